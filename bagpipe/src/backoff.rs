@@ -0,0 +1,170 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Backing off between retries of operations that failed because of
+//! contention.
+//!
+//! The `try...` methods fail transiently when they race with other
+//! threads, and the `push` and `pop` methods retry them until they
+//! succeed. Retrying right away is the fastest option as long as every
+//! thread has a core to itself. Under oversubscription (more threads
+//! than cores), it is the worst: the thread whose operation is in the
+//! way may have been descheduled, and the retrying threads burn their
+//! time slices while it waits to run again.
+//!
+//! A `Backoff` retries in three phases:
+//!
+//! - Spin: wait for 1, 2, 4, ... spin-loop hints, for `spin_limit`
+//!   rounds.
+//! - Yield: call `thread::yield_now` for `YIELD_ROUNDS` rounds, which
+//!   lets a descheduled thread run if it is waiting for this core.
+//! - Park: sleep for 1, 2, 4, ... microseconds, up to `max_park_us`.
+//!   The failed operations do not belong to a thread that could wake the
+//!   sleeper up when it is done, so parking is timed rather than waiting
+//!   for a notification. With a `max_park_us` of 0, a `Backoff` keeps
+//!   yielding instead.
+//!
+//! The limits are process-wide, and can be changed at any time with
+//! `set_spin_limit` and `set_max_park_us`.
+
+use std::cmp;
+use std::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::time::Duration;
+
+/// The default number of spinning rounds.
+pub const DEFAULT_SPIN_LIMIT: usize = 6;
+/// The number of rounds spent yielding between spinning and parking.
+pub const YIELD_ROUNDS: usize = 4;
+/// The default limit on the time spent parked per round, in microseconds.
+pub const DEFAULT_MAX_PARK_US: usize = 128;
+
+/// The spin phase doubles at most this many times, so that a large spin
+/// limit means spinning for a long time rather than overflowing.
+const MAX_SHIFT: usize = 16;
+
+// These hold the setting plus one, so that 0 (their initial value) means
+// the default.
+static SPIN_LIMIT: AtomicUsize = ATOMIC_USIZE_INIT;
+static MAX_PARK_US: AtomicUsize = ATOMIC_USIZE_INIT;
+
+static PARKS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn load_setting(setting: &AtomicUsize, default: usize) -> usize {
+    match setting.load(Ordering::Relaxed) {
+        0 => default,
+        n => n - 1,
+    }
+}
+
+/// The number of spinning rounds before a `Backoff` starts yielding.
+pub fn spin_limit() -> usize {
+    load_setting(&SPIN_LIMIT, DEFAULT_SPIN_LIMIT)
+}
+
+/// Set the number of spinning rounds. `usize::MAX` makes retries spin
+/// forever, as they did before backing off was introduced.
+pub fn set_spin_limit(rounds: usize) {
+    SPIN_LIMIT.store(rounds.saturating_add(1), Ordering::Relaxed);
+}
+
+/// The longest a `Backoff` sleeps per round, in microseconds, or 0 if it
+/// never parks.
+pub fn max_park_us() -> usize {
+    load_setting(&MAX_PARK_US, DEFAULT_MAX_PARK_US)
+}
+
+/// Set the longest a `Backoff` sleeps per round, in microseconds. 0
+/// disables parking.
+pub fn set_max_park_us(us: usize) {
+    MAX_PARK_US.store(us.saturating_add(1), Ordering::Relaxed);
+}
+
+/// The number of times a thread has parked, since the start of the
+/// process.
+pub fn parks() -> usize {
+    PARKS.load(Ordering::Relaxed)
+}
+
+/// What a `Backoff` does in a given round.
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    /// Spin for this many spin-loop hints.
+    Spin(usize),
+    Yield,
+    /// Sleep for this many microseconds.
+    Park(usize),
+}
+
+fn action(round: usize, spin_limit: usize, max_park_us: usize) -> Action {
+    if round < spin_limit {
+        Action::Spin(1 << cmp::min(round, MAX_SHIFT))
+    } else if round - spin_limit < YIELD_ROUNDS || max_park_us == 0 {
+        Action::Yield
+    } else {
+        let shift = cmp::min(round - spin_limit - YIELD_ROUNDS, MAX_SHIFT);
+        Action::Park(cmp::min(1 << shift, max_park_us))
+    }
+}
+
+/// The state of a sequence of retries. See the module documentation.
+pub struct Backoff {
+    round: usize,
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff { round: 0 }
+    }
+
+    /// Wait before the next retry.
+    pub fn snooze(&mut self) {
+        match action(self.round, spin_limit(), max_park_us()) {
+            Action::Spin(n) => {
+                for _ in 0..n {
+                    spin_loop_hint();
+                }
+            }
+            Action::Yield => thread::yield_now(),
+            Action::Park(us) => {
+                PARKS.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(Duration::new(
+                    (us / 1_000_000) as u64,
+                    (us % 1_000_000) as u32 * 1000,
+                ));
+            }
+        }
+        self.round = self.round.saturating_add(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phases() {
+        assert_eq!(action(0, 2, 4), Action::Spin(1));
+        assert_eq!(action(1, 2, 4), Action::Spin(2));
+        for round in 2..2 + YIELD_ROUNDS {
+            assert_eq!(action(round, 2, 4), Action::Yield);
+        }
+        assert_eq!(action(2 + YIELD_ROUNDS, 2, 4), Action::Park(1));
+        assert_eq!(action(3 + YIELD_ROUNDS, 2, 4), Action::Park(2));
+        assert_eq!(action(100, 2, 4), Action::Park(4));
+        assert_eq!(action(100, 2, 0), Action::Yield);
+        // Spinning forever does not overflow the shift.
+        assert_eq!(action(100, ::std::usize::MAX, 4), Action::Spin(1 << MAX_SHIFT));
+    }
+
+    #[test]
+    fn settings() {
+        assert_eq!(load_setting(&ATOMIC_USIZE_INIT, 7), 7);
+        assert_eq!(load_setting(&AtomicUsize::new(1), 7), 0);
+        assert_eq!(load_setting(&AtomicUsize::new(4), 7), 3);
+    }
+}
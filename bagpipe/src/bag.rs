@@ -9,6 +9,7 @@
 //! data-structures.
 use super::crossbeam::sync::{TreiberStack, SegQueue, MsQueue};
 use super::crossbeam::mem::epoch;
+use super::backoff::Backoff;
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
@@ -47,7 +48,8 @@ pub trait SharedWeakBag {
     /// A push operation that will not fail.
     ///
     /// The default implementation of `push` simply calls `try_push`
-    /// in a loop. until it succeeds. Depending on the underlying
+    /// in a loop until it succeeds, backing off between attempts (see
+    /// the `backoff` module). Depending on the underlying
     /// data-structure this may loop infinitely under some
     /// circumstances.
     ///
@@ -56,8 +58,10 @@ pub trait SharedWeakBag {
     fn push(&self, it: Self::Item) {
         let _g = epoch::pin();
         let mut cur_item = it;
+        let mut backoff = Backoff::new();
         while let Err(old_item) = self.try_push(cur_item) {
-            cur_item = old_item
+            cur_item = old_item;
+            backoff.snooze();
         }
     }
 
@@ -66,11 +70,15 @@ pub trait SharedWeakBag {
     /// Same caveats apply to those of `push`.
     fn pop(&self) -> Option<Self::Item> {
         let _g = epoch::pin();
+        let mut backoff = Backoff::new();
         loop {
             return match self.try_pop() {
                 Ok(it) => Some(it),
                 Err(PopStatus::Empty) => None,
-                Err(PopStatus::TransientFailure) => continue,
+                Err(PopStatus::TransientFailure) => {
+                    backoff.snooze();
+                    continue;
+                }
             };
         }
     }
@@ -94,17 +102,23 @@ pub trait WeakBag: Clone {
     fn push_mut(&mut self, it: Self::Item) {
         let _g = epoch::pin();
         let mut cur_item = it;
+        let mut backoff = Backoff::new();
         while let Err(old_item) = self.try_push_mut(cur_item) {
-            cur_item = old_item
+            cur_item = old_item;
+            backoff.snooze();
         }
     }
     fn pop_mut(&mut self) -> Option<Self::Item> {
         let _g = epoch::pin();
+        let mut backoff = Backoff::new();
         loop {
             return match self.try_pop_mut() {
                 Ok(it) => Some(it),
                 Err(PopStatus::Empty) => None,
-                Err(PopStatus::TransientFailure) => continue,
+                Err(PopStatus::TransientFailure) => {
+                    backoff.snooze();
+                    continue;
+                }
             };
         }
     }
@@ -42,7 +42,9 @@
 //! The API currently supports `try...` versions of methods,
 //! allowing data-structures to signal lack of progress due to high
 //! contention. It also provides `push` and `pop` methods that will loop
-//! until they succeed (or do something more intelligent).
+//! until they succeed, backing off between attempts so that they degrade
+//! gracefully when there are more threads than cores (see the `backoff`
+//! module).
 //!
 //! # Guarantees
 //!
@@ -70,11 +72,13 @@ use std::marker::PhantomData;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, AtomicIsize, Ordering, fence};
 use bag::{WeakBag, SharedWeakBag, RevocableWeakBag, Revocable, PopResult, PopStatus};
+use backoff::Backoff;
 use crossbeam::mem::CachePadded;
 use std::mem;
 
 pub mod queue;
 pub mod bag;
+pub mod backoff;
 
 #[cfg(feature = "prime_schedules")]
 mod primes;
@@ -340,6 +344,7 @@ impl<B: SharedWeakBag, Clean: BagCleanup<Item = B::Item>> WeakBag for BagPipe<B,
         let mut n_iters = 0;
         for item in iter {
             let mut it = item;
+            let mut backoff = Backoff::new();
             loop {
                 cur_index &= p_len - 1;
                 let res = unsafe { self.pipes.pipes.get_unchecked(cur_index).try_push(it) };
@@ -351,6 +356,7 @@ impl<B: SharedWeakBag, Clean: BagCleanup<Item = B::Item>> WeakBag for BagPipe<B,
                     }
                     Err(old_item) => {
                         it = old_item;
+                        backoff.snooze();
                     }
                 }
             }
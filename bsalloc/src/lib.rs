@@ -16,7 +16,8 @@ extern crate alloc_fmt;
 extern crate lazy_static;
 extern crate mmap_alloc;
 mod bsalloc;
-use bsalloc::{BsAlloc, ALLOC};
+pub use bsalloc::BsAlloc;
+use bsalloc::ALLOC;
 use self::alloc::allocator::{Alloc, AllocErr, Layout};
 use core::cmp;
 use core::ptr;
@@ -0,0 +1,39 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Helpers shared by the example applications. See tests/examples.rs for how they are built and
+// run.
+
+#ifndef ELFC_EXAMPLES_COMMON_H
+#define ELFC_EXAMPLES_COMMON_H
+
+#include <stdio.h>
+#include <stdlib.h>
+
+// Defined by elfc. The reference is weak so that the examples also link without elfc, in which
+// case the symbol is NULL.
+extern size_t elfmalloc_trim(size_t level) __attribute__((weak));
+
+#define CHECK(cond)                                                          \
+    do {                                                                     \
+        if (!(cond)) {                                                       \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, \
+                    #cond);                                                  \
+            exit(1);                                                         \
+        }                                                                    \
+    } while (0)
+
+// Exit with status 2 unless the allocator is elfc, whether it was linked in or preloaded, so that
+// the harness does not silently test the system allocator.
+static inline void require_elfc(void) {
+    if (elfmalloc_trim == NULL) {
+        fprintf(stderr, "elfc is not loaded; run with LD_PRELOAD or link against libelfc.a\n");
+        exit(2);
+    }
+}
+
+#endif
@@ -0,0 +1,174 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// A multithreaded benchmark of a chained hash table with string keys. Threads created with
+// pthread_create (which elfmalloc has never seen before their first allocation) insert and remove
+// random keys concurrently, so most entries are freed by a different thread than the one that
+// allocated them. Threads are started in several waves, each of which exits before the next one
+// starts, which exercises the cleanup of thread caches at thread exit. Prints the throughput of
+// each wave.
+
+#define _GNU_SOURCE
+#include <pthread.h>
+#include <stdint.h>
+#include <string.h>
+#include <time.h>
+
+#include "common.h"
+
+#define THREADS 8
+#define WAVES 4
+#define OPS 100000
+#define KEYS 65536
+#define BUCKETS 16384
+#define STRIPES 64
+
+struct entry {
+    struct entry *next;
+    char *key;
+    char *value;
+    size_t value_len;
+};
+
+static struct entry *buckets[BUCKETS];
+static pthread_mutex_t locks[STRIPES];
+static long live[STRIPES];
+
+static uint64_t hash(const char *s) {
+    uint64_t h = 0xcbf29ce484222325ULL;
+    for (; *s != '\0'; s++) {
+        h = (h ^ (unsigned char)*s) * 0x100000001b3ULL;
+    }
+    return h;
+}
+
+static uint64_t next(uint64_t *state) {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    return *state;
+}
+
+// The byte that the value of the entry with key `key` is filled with.
+static char fill(const char *key) {
+    return 'a' + hash(key) % 26;
+}
+
+// Check that an entry has not been corrupted. Checking every byte of the value would dominate the
+// running time, so only its ends and middle are checked.
+static void check_entry(const struct entry *e) {
+    char c = fill(e->key);
+    CHECK(e->value[0] == c);
+    CHECK(e->value[e->value_len / 2] == c);
+    CHECK(e->value[e->value_len - 1] == c);
+}
+
+static struct entry *new_entry(const char *key, size_t value_len) {
+    struct entry *e = malloc(sizeof(*e));
+    CHECK(e != NULL);
+    e->key = strdup(key);
+    CHECK(e->key != NULL);
+    e->value = malloc(value_len);
+    CHECK(e->value != NULL);
+    e->value_len = value_len;
+    memset(e->value, fill(key), value_len);
+    return e;
+}
+
+static void free_entry(struct entry *e) {
+    check_entry(e);
+    free(e->key);
+    free(e->value);
+    free(e);
+}
+
+static void *worker(void *arg) {
+    uint64_t state = (uintptr_t)arg * 0x9e3779b97f4a7c15ULL + 1;
+    char key[32];
+    for (int i = 0; i < OPS; i++) {
+        snprintf(key, sizeof(key), "key-%llu", (unsigned long long)(next(&state) % KEYS));
+        uint64_t h = hash(key);
+        size_t b = h % BUCKETS;
+        size_t s = b % STRIPES;
+        // Allocate outside of the lock, as a real program would.
+        struct entry *fresh = new_entry(key, 1 + next(&state) % 512);
+
+        pthread_mutex_lock(&locks[s]);
+        struct entry **slot = &buckets[b];
+        while (*slot != NULL && strcmp((*slot)->key, key) != 0) {
+            slot = &(*slot)->next;
+        }
+        struct entry *old = *slot;
+        if (old == NULL) {
+            fresh->next = NULL;
+            *slot = fresh;
+            fresh = NULL;
+            live[s]++;
+        } else if (next(&state) % 2 == 0) {
+            // Replace the value, growing or shrinking it in place if possible.
+            size_t len = 1 + next(&state) % 4096;
+            char *value = realloc(old->value, len);
+            CHECK(value != NULL);
+            if (len > old->value_len) {
+                memset(value + old->value_len, fill(old->key), len - old->value_len);
+            }
+            old->value = value;
+            old->value_len = len;
+            old = NULL;
+        } else {
+            *slot = old->next;
+            live[s]--;
+        }
+        pthread_mutex_unlock(&locks[s]);
+
+        if (fresh != NULL) {
+            free_entry(fresh);
+        }
+        if (old != NULL) {
+            free_entry(old);
+        }
+    }
+    return NULL;
+}
+
+int main(void) {
+    require_elfc();
+
+    for (int i = 0; i < STRIPES; i++) {
+        pthread_mutex_init(&locks[i], NULL);
+    }
+    for (int wave = 0; wave < WAVES; wave++) {
+        pthread_t threads[THREADS];
+        struct timespec start, end;
+        clock_gettime(CLOCK_MONOTONIC, &start);
+        for (uintptr_t i = 0; i < THREADS; i++) {
+            CHECK(pthread_create(&threads[i], NULL, worker, (void *)(wave * THREADS + i)) == 0);
+        }
+        for (int i = 0; i < THREADS; i++) {
+            CHECK(pthread_join(threads[i], NULL) == 0);
+        }
+        clock_gettime(CLOCK_MONOTONIC, &end);
+        double secs = (end.tv_sec - start.tv_sec) + (end.tv_nsec - start.tv_nsec) / 1e9;
+        printf("wave %d: %.2f Mops/s\n", wave, THREADS * OPS / secs / 1e6);
+    }
+
+    long expected = 0, found = 0;
+    for (int i = 0; i < STRIPES; i++) {
+        expected += live[i];
+    }
+    for (int b = 0; b < BUCKETS; b++) {
+        while (buckets[b] != NULL) {
+            struct entry *e = buckets[b];
+            CHECK(hash(e->key) % BUCKETS == (uint64_t)b);
+            buckets[b] = e->next;
+            free_entry(e);
+            found++;
+        }
+    }
+    CHECK(found == expected);
+    return 0;
+}
@@ -0,0 +1,87 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Inserts rows with payloads of random sizes into an in-memory SQLite database, reads them back,
+// and deletes them again, several times over. SQLite allocates its page cache, statements, and
+// values with malloc, realloc, and free, with a mix of sizes that is typical of real programs.
+// Since every round frees what it allocated, the resident set should stay flat across rounds.
+
+#include <sqlite3.h>
+#include <string.h>
+
+#include "common.h"
+
+#define ROUNDS 8
+#define ROWS 50000
+#define MAX_PAYLOAD 2048
+
+static unsigned long long rng_state = 0x9e3779b97f4a7c15ULL;
+
+static unsigned int rng(void) {
+    rng_state ^= rng_state << 13;
+    rng_state ^= rng_state >> 7;
+    rng_state ^= rng_state << 17;
+    return (unsigned int)rng_state;
+}
+
+static void exec(sqlite3 *db, const char *sql) {
+    char *err = NULL;
+    if (sqlite3_exec(db, sql, NULL, NULL, &err) != SQLITE_OK) {
+        fprintf(stderr, "%s: %s\n", sql, err);
+        exit(1);
+    }
+}
+
+static sqlite3_int64 query_int(sqlite3 *db, const char *sql) {
+    sqlite3_stmt *stmt;
+    CHECK(sqlite3_prepare_v2(db, sql, -1, &stmt, NULL) == SQLITE_OK);
+    CHECK(sqlite3_step(stmt) == SQLITE_ROW);
+    sqlite3_int64 res = sqlite3_column_int64(stmt, 0);
+    sqlite3_finalize(stmt);
+    return res;
+}
+
+int main(void) {
+    require_elfc();
+
+    static char payload[MAX_PAYLOAD];
+    sqlite3 *db;
+    CHECK(sqlite3_open(":memory:", &db) == SQLITE_OK);
+    exec(db, "CREATE TABLE t (id INTEGER PRIMARY KEY, tag INTEGER, payload TEXT)");
+    exec(db, "CREATE INDEX t_tag ON t (tag)");
+
+    sqlite3_stmt *insert;
+    CHECK(sqlite3_prepare_v2(db, "INSERT INTO t (tag, payload) VALUES (?, ?)", -1, &insert,
+                             NULL) == SQLITE_OK);
+    for (int round = 0; round < ROUNDS; round++) {
+        sqlite3_int64 bytes = 0;
+        exec(db, "BEGIN");
+        for (int i = 0; i < ROWS; i++) {
+            int len = 1 + rng() % (MAX_PAYLOAD - 1);
+            memset(payload, 'a' + i % 26, len);
+            bytes += len;
+            CHECK(sqlite3_bind_int(insert, 1, rng() % 1000) == SQLITE_OK);
+            // SQLITE_TRANSIENT makes SQLite copy the payload into memory of its own.
+            CHECK(sqlite3_bind_text(insert, 2, payload, len, SQLITE_TRANSIENT) == SQLITE_OK);
+            CHECK(sqlite3_step(insert) == SQLITE_DONE);
+            sqlite3_reset(insert);
+        }
+        exec(db, "COMMIT");
+
+        CHECK(query_int(db, "SELECT count(*) FROM t") == ROWS);
+        CHECK(query_int(db, "SELECT sum(length(payload)) FROM t") == bytes);
+        // Sorting by the indexed column makes SQLite build and free temporary structures.
+        CHECK(query_int(db, "SELECT count(*) FROM (SELECT payload FROM t ORDER BY tag, id)") ==
+              ROWS);
+
+        exec(db, "DELETE FROM t");
+        CHECK(query_int(db, "SELECT count(*) FROM t") == 0);
+    }
+    sqlite3_finalize(insert);
+    CHECK(sqlite3_close(db) == SQLITE_OK);
+    return 0;
+}
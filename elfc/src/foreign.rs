@@ -0,0 +1,75 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Forwarding of pointers that were not allocated by elfmalloc.
+//!
+//! On Linux, the `free` and `realloc` functions of the next library in the symbol search order
+//! (for a preloaded library, usually libc) are looked up with `dlsym(RTLD_NEXT, ...)` the first
+//! time a foreign pointer is seen, so nothing needs to be initialized before `main`. Whatever
+//! `realloc` returns belongs to that allocator too, and is forwarded again when it is freed.
+//!
+//! If the lookup fails, on other platforms, or with the `strict` feature, foreign pointers are
+//! handled as they would be with elfmalloc alone: `realloc` aborts the process, and `free` reports
+//! a violation to elfmalloc's `integrity` module, which aborts by default.
+
+use elfmalloc::ownership;
+use libc::{c_void, size_t};
+#[cfg(all(target_os = "linux", not(feature = "strict")))]
+use std::mem;
+#[cfg(all(target_os = "linux", not(feature = "strict")))]
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+/// Should `p` be handled by this module rather than by elfmalloc?
+#[inline]
+pub fn is_foreign(p: *mut c_void) -> bool {
+    // In strict mode, elfmalloc performs the check itself.
+    cfg!(all(target_os = "linux", not(feature = "strict"))) && !p.is_null() &&
+        !ownership::owns(p as *mut u8)
+}
+
+#[cfg(all(target_os = "linux", not(feature = "strict")))]
+static NEXT_FREE: AtomicUsize = ATOMIC_USIZE_INIT;
+#[cfg(all(target_os = "linux", not(feature = "strict")))]
+static NEXT_REALLOC: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Look up `name` (which must be NUL-terminated) in the libraries after this one, caching the
+/// result in `slot`. Returns 0 if there is no such symbol.
+#[cfg(all(target_os = "linux", not(feature = "strict")))]
+unsafe fn next(slot: &AtomicUsize, name: &[u8]) -> usize {
+    let mut f = slot.load(Ordering::Acquire);
+    if f == 0 {
+        f = ::libc::dlsym(::libc::RTLD_NEXT, name.as_ptr() as *const ::libc::c_char) as usize;
+        slot.store(f, Ordering::Release);
+    }
+    f
+}
+
+#[cold]
+pub unsafe fn free(p: *mut c_void) {
+    #[cfg(all(target_os = "linux", not(feature = "strict")))]
+    {
+        let f = next(&NEXT_FREE, b"free\0");
+        if f != 0 {
+            let f: unsafe extern "C" fn(*mut c_void) = mem::transmute(f);
+            return f(p);
+        }
+    }
+    ownership::foreign_free(p as *mut u8)
+}
+
+#[cold]
+pub unsafe fn realloc(p: *mut c_void, new_size: size_t) -> *mut c_void {
+    #[cfg(all(target_os = "linux", not(feature = "strict")))]
+    {
+        let f = next(&NEXT_REALLOC, b"realloc\0");
+        if f != 0 {
+            let f: unsafe extern "C" fn(*mut c_void, size_t) -> *mut c_void = mem::transmute(f);
+            return f(p, new_size);
+        }
+    }
+    ownership::foreign_pointer("realloc", p as *mut u8)
+}
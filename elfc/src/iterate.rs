@@ -0,0 +1,119 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! bionic's heap iteration interface: `malloc_iterate`, `malloc_disable`, and `malloc_enable`.
+//!
+//! Android's memory tooling (libmemunreachable, which debuggerd and the memory profilers build
+//! on) finds leaks by disabling the allocator, suspending the process's threads, and then
+//! iterating over every allocation. `malloc_iterate` is backed by elfmalloc's `walk` module.
+//!
+//! `malloc_disable` closes a gate that every allocation function passes through, and waits for
+//! the threads that are already inside to leave. Threads that call into the allocator while it is
+//! disabled wait until `malloc_enable` is called. The thread that disabled the allocator must not
+//! allocate either. Keeping track of the threads inside the allocator costs an atomic increment
+//! and decrement on every call, which is why this module is behind the `malloc-iterate` feature.
+//!
+//! elfmalloc's background thread, which frees memory on behalf of exiting threads, does not pass
+//! through the gate, so a walk can still race with it.
+//!
+//! bionic defines these functions itself, so they are weak symbols with the `weak-symbols`
+//! feature.
+
+use elfmalloc::walk::{self, WalkMode};
+use libc::{c_int, c_void, size_t, uintptr_t};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread;
+
+/// Set in `GATE` while the allocator is disabled.
+#[cfg(target_pointer_width = "32")]
+const CLOSED: usize = 1 << 31;
+#[cfg(target_pointer_width = "64")]
+const CLOSED: usize = 1 << 63;
+
+/// The number of threads inside the allocator, plus `CLOSED` while it is disabled.
+static GATE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Proof that the current thread is inside the allocator. Dropping it leaves.
+pub struct Guard;
+
+impl Drop for Guard {
+    #[inline]
+    fn drop(&mut self) {
+        GATE.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Enter the allocator, waiting for it to be enabled if it is disabled.
+#[inline]
+pub fn enter() -> Guard {
+    loop {
+        if GATE.fetch_add(1, Ordering::Acquire) & CLOSED == 0 {
+            return Guard;
+        }
+        GATE.fetch_sub(1, Ordering::Relaxed);
+        while GATE.load(Ordering::Relaxed) & CLOSED != 0 {
+            thread::yield_now();
+        }
+    }
+}
+
+/// Disable the allocator: wait for all threads to leave it, and block any further calls until
+/// `malloc_enable` is called.
+#[no_mangle]
+#[cfg_attr(feature = "weak-symbols", linkage = "weak")]
+pub extern "C" fn malloc_disable() {
+    loop {
+        let gate = GATE.load(Ordering::Relaxed);
+        if gate & CLOSED == 0 &&
+            GATE.compare_exchange_weak(gate, gate | CLOSED, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        {
+            break;
+        }
+        // Another thread has disabled the allocator.
+        thread::yield_now();
+    }
+    while GATE.load(Ordering::Acquire) & !CLOSED != 0 {
+        thread::yield_now();
+    }
+}
+
+/// Enable the allocator after a call to `malloc_disable`.
+#[no_mangle]
+#[cfg_attr(feature = "weak-symbols", linkage = "weak")]
+pub extern "C" fn malloc_enable() {
+    GATE.fetch_and(!CLOSED, Ordering::Release);
+}
+
+/// Call `callback` with the address and usable size of every allocation that starts in
+/// `[base, base + size)`, along with `arg`.
+///
+/// The allocator should be disabled with `malloc_disable`. If it is not, the results are only
+/// approximate (see `WalkMode::BestEffort`). `callback` must not call into the allocator. Returns
+/// 0 on success and -1 if `callback` is null.
+#[no_mangle]
+#[cfg_attr(feature = "weak-symbols", linkage = "weak")]
+pub unsafe extern "C" fn malloc_iterate(
+    base: uintptr_t,
+    size: size_t,
+    callback: Option<unsafe extern "C" fn(uintptr_t, size_t, *mut c_void)>,
+    arg: *mut c_void,
+) -> c_int {
+    let callback = match callback {
+        Some(callback) => callback,
+        None => return -1,
+    };
+    let mode = if GATE.load(Ordering::Acquire) & CLOSED != 0 {
+        WalkMode::StopTheWorld
+    } else {
+        WalkMode::BestEffort
+    };
+    for (p, info) in walk::allocations_in(mode, base, size) {
+        callback(p as uintptr_t, info.usable_size, arg);
+    }
+    0
+}
@@ -5,20 +5,153 @@
 // the MIT license (the LICENSE-MIT file) at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+#![feature(alloc)]
+#![feature(allocator_api)]
+#![cfg_attr(feature = "weak-symbols", feature(linkage))]
 #![cfg_attr(feature = "logging", feature(link_args))]
 #![cfg_attr(all(feature = "logging", target_os = "linux"), link_args = "-Wl,-init,init_log")]
 // On Mac, the C ABI prefixes all symbols with _.
 // Source: https://users.rust-lang.org/t/ld-preload-init-function-in-rust/12865/6
 #![cfg_attr(all(feature = "logging", target_os = "macos"), link_args = "-Wl,-init,_init_log")]
 
+extern crate alloc;
+#[cfg(target_os = "linux")]
+extern crate alloc_fmt;
 extern crate elfmalloc;
 #[cfg(feature = "logging")]
 extern crate env_logger;
+extern crate libc;
 #[macro_use]
 extern crate malloc_bind;
+
+mod foreign;
+#[cfg(feature = "malloc-iterate")]
+mod iterate;
+#[cfg(target_os = "linux")]
+mod trace;
+#[cfg(feature = "weak-symbols")]
+mod weak;
+
+use alloc::allocator::{Alloc, AllocErr, Layout};
 use elfmalloc::alloc_impl::ElfMallocGlobal;
+use malloc_bind::{LayoutFinder, Malloc};
+use libc::{c_void, size_t};
+#[cfg(target_os = "linux")]
+use trace::Op;
+
+/// The allocator behind elfc's exports.
+///
+/// This is `ElfMallocGlobal`, except that pointers passed to `free` and `realloc` which were not
+/// allocated by elfmalloc are forwarded to the allocator that elfc was interposed on, rather than
+/// aborting the process. Such pointers show up when elfc is loaded with `LD_PRELOAD` into a
+/// process that allocated memory before the library was loaded, or that contains libraries
+/// statically linked against another allocator. With the `strict` feature, they abort the process
+/// instead. See the `foreign` module and elfmalloc's `ownership` module for details.
+///
+/// On Linux, calls are recorded in the call log of the `trace` module when `ELFC_DEBUG` is set.
+pub struct Elfc;
+
+unsafe impl<'a> Alloc for &'a Elfc {
+    unsafe fn alloc(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        #[cfg(feature = "malloc-iterate")]
+        let _guard = iterate::enter();
+        let res = (&ElfMallocGlobal).alloc(l.clone());
+        #[cfg(target_os = "linux")]
+        trace::record(Op::Alloc, 0, l.size(), l.align(), result(&res));
+        res
+    }
+
+    unsafe fn dealloc(&mut self, p: *mut u8, l: Layout) {
+        #[cfg(feature = "malloc-iterate")]
+        let _guard = iterate::enter();
+        #[cfg(target_os = "linux")]
+        trace::record(Op::Dealloc, p as usize, l.size(), l.align(), 0);
+        (&ElfMallocGlobal).dealloc(p, l)
+    }
+
+    unsafe fn realloc(&mut self, p: *mut u8, l1: Layout, l2: Layout) -> Result<*mut u8, AllocErr> {
+        #[cfg(feature = "malloc-iterate")]
+        let _guard = iterate::enter();
+        #[cfg(target_os = "linux")]
+        let (size, align) = (l2.size(), l2.align());
+        let res = (&ElfMallocGlobal).realloc(p, l1, l2);
+        #[cfg(target_os = "linux")]
+        trace::record(Op::AllocRealloc, p as usize, size, align, result(&res));
+        res
+    }
+}
+
+/// The address returned by a call to `Alloc`, or 0 if it failed, for the call log.
+#[cfg(target_os = "linux")]
+fn result(res: &Result<*mut u8, AllocErr>) -> usize {
+    res.as_ref().map(|&p| p as usize).unwrap_or(0)
+}
+
+unsafe impl LayoutFinder for Elfc {
+    unsafe fn get_layout(&self, p: *mut u8) -> Layout {
+        ElfMallocGlobal.get_layout(p)
+    }
+}
 
-define_malloc!(ElfMallocGlobal, ElfMallocGlobal);
+unsafe impl Malloc for Elfc {
+    unsafe fn c_malloc(&self, size: size_t) -> *mut c_void {
+        #[cfg(feature = "malloc-iterate")]
+        let _guard = iterate::enter();
+        let res = ElfMallocGlobal.c_malloc(size);
+        #[cfg(target_os = "linux")]
+        trace::record(Op::Malloc, 0, size, 0, res as usize);
+        res
+    }
+
+    unsafe fn c_free(&self, p: *mut c_void) {
+        #[cfg(target_os = "linux")]
+        trace::record(Op::Free, p as usize, 0, 0, 0);
+        if foreign::is_foreign(p) {
+            return foreign::free(p);
+        }
+        #[cfg(feature = "malloc-iterate")]
+        let _guard = iterate::enter();
+        ElfMallocGlobal.c_free(p)
+    }
+
+    unsafe fn c_realloc(&self, p: *mut c_void, new_size: size_t) -> *mut c_void {
+        let res = if foreign::is_foreign(p) {
+            foreign::realloc(p, new_size)
+        } else {
+            #[cfg(feature = "malloc-iterate")]
+            let _guard = iterate::enter();
+            ElfMallocGlobal.c_realloc(p, new_size)
+        };
+        #[cfg(target_os = "linux")]
+        trace::record(Op::Realloc, p as usize, new_size, 0, res as usize);
+        res
+    }
+}
+
+// With the `weak-symbols` feature, the `weak` module defines the C allocation API instead.
+#[cfg(not(feature = "weak-symbols"))]
+define_malloc!(Elfc, Elfc);
+
+/// Return unused memory to the operating system.
+///
+/// This has the same signature as glibc's `malloc_trim`. `pad` is ignored, and trimming is
+/// performed at the most aggressive level. Returns 1 if any memory was released and 0 otherwise.
+///
+/// glibc defines `malloc_trim` alongside `malloc`, so it is weak with the `weak-symbols` feature.
+/// `elfmalloc_trim` is always available.
+#[no_mangle]
+#[cfg_attr(feature = "weak-symbols", linkage = "weak")]
+pub extern "C" fn malloc_trim(_pad: usize) -> i32 {
+    if unsafe { elfmalloc::trim(2) } > 0 { 1 } else { 0 }
+}
+
+/// Return unused memory to the operating system using an explicit trim level.
+///
+/// See `elfmalloc::trim` for a description of the levels. Returns the number of bytes released.
+#[no_mangle]
+pub extern "C" fn elfmalloc_trim(level: usize) -> usize {
+    unsafe { elfmalloc::trim(level) }
+}
 
 #[cfg(feature = "logging")]
 #[no_mangle]
@@ -0,0 +1,325 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A log of recent allocator calls, enabled with `ELFC_DEBUG=1`.
+//!
+//! When the `ELFC_DEBUG` environment variable is set to anything but `0`, every call to the
+//! allocator is recorded in a ring of the last `ENTRIES` calls: the function, the calling thread
+//! (its `pthread_self`), the arguments and the result. The ring is printed to stderr when the
+//! process receives `SIGUSR2`, and when it is about to die of `SIGSEGV`, `SIGBUS`, `SIGILL`,
+//! `SIGFPE` or `SIGABRT` (elfmalloc's aborts on heap corruption raise `SIGILL` or `SIGABRT`), so
+//! that the calls that led up to a crash in a preloaded application can be seen without a
+//! debugger.
+//!
+//! `malloc`, `free` and `realloc` are recorded as such. The other functions of the C API
+//! (`calloc`, `posix_memalign`, `aligned_alloc`, etc) are recorded as the `alloc`, `dealloc` and
+//! `realloc` calls they make, with the size and alignment they ask for. Frees are recorded before
+//! the object is freed and allocations after they return, so a call that crashes inside `malloc`
+//! is not in the ring, while one that crashes inside `free` is.
+//!
+//! The environment is read on the first call made after libc has set it up; calls before that are
+//! not recorded. The ring is then mapped and the signal handlers are installed. Without
+//! `ELFC_DEBUG`, each call costs a load of a flag.
+//!
+//! # Concurrency and signal safety
+//!
+//! Threads claim entries by incrementing a counter, so recording takes no locks. Each entry has a
+//! sequence number, which is cleared while the entry is written and set to the call's number
+//! afterwards; entries that are being written, or were overwritten while they were printed, are
+//! left out of the dump. The handlers do not allocate: they format with `core::fmt` straight to
+//! stderr, and only call `write`, `sigaction` and `raise`. `SIGUSR2` is passed on to the handler
+//! installed before ours, if there was one. For the fatal signals, the previous handler is called
+//! if there was one; otherwise the default action is restored and the signal is raised again, so
+//! the process dies of it as it would have without this module.
+//!
+//! This module is only available on Linux.
+
+use alloc_fmt::FDWriter;
+use libc::{self, c_char, c_int, c_void, siginfo_t};
+use std::fmt::{self, Write};
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT,
+                        ATOMIC_USIZE_INIT};
+
+/// The number of calls kept in the ring.
+pub const ENTRIES: usize = 1024;
+
+/// The signals that make the ring be printed before the process dies.
+const FATAL_SIGNALS: [c_int; 5] = [
+    libc::SIGSEGV,
+    libc::SIGBUS,
+    libc::SIGILL,
+    libc::SIGFPE,
+    libc::SIGABRT,
+];
+
+/// An allocator function, as recorded in the ring.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Malloc = 1,
+    Free,
+    Realloc,
+    /// `Alloc::alloc`, for the other allocation functions of the C API.
+    Alloc,
+    /// `Alloc::dealloc`.
+    Dealloc,
+    /// `Alloc::realloc`.
+    AllocRealloc,
+}
+
+impl Op {
+    fn from_usize(n: usize) -> Option<Op> {
+        match n {
+            1 => Some(Op::Malloc),
+            2 => Some(Op::Free),
+            3 => Some(Op::Realloc),
+            4 => Some(Op::Alloc),
+            5 => Some(Op::Dealloc),
+            6 => Some(Op::AllocRealloc),
+            _ => None,
+        }
+    }
+}
+
+/// A recorded call. `seq` is the call's number plus one once the entry is written, and 0 while it
+/// is being written.
+struct Entry {
+    seq: AtomicUsize,
+    op: AtomicUsize,
+    thread: AtomicUsize,
+    ptr: AtomicUsize,
+    size: AtomicUsize,
+    align: AtomicUsize,
+    result: AtomicUsize,
+}
+
+const UNKNOWN: usize = 0;
+const STARTING: usize = 1;
+const OFF: usize = 2;
+const ON: usize = 3;
+
+/// Whether calls are recorded: `UNKNOWN` until the environment has been read.
+static STATE: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The ring, an array of `ENTRIES` entries mapped when `ELFC_DEBUG` is found to be set.
+static RING: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The number of calls recorded so far.
+static NEXT: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Set while a fatal signal is being reported, so that only one thread prints the ring.
+static DYING: AtomicBool = ATOMIC_BOOL_INIT;
+/// The actions that were installed for `SIGUSR2` and the fatal signals before ours. Written
+/// before ours are installed.
+static mut PREV_USR2: Option<libc::sigaction> = None;
+static mut PREV_FATAL: [Option<libc::sigaction>; 5] = [None; 5];
+
+extern "C" {
+    static environ: *const *const c_char;
+}
+
+/// Is the call log enabled? The first call after libc has set up the environment reads it.
+#[inline]
+pub fn enabled() -> bool {
+    match STATE.load(Ordering::Acquire) {
+        ON => true,
+        UNKNOWN => start(),
+        _ => false,
+    }
+}
+
+/// Record a call to `op` with `ptr`, `size` and `align` as arguments, which returned `result`.
+/// Arguments that `op` does not take are ignored.
+#[inline]
+pub fn record(op: Op, ptr: usize, size: usize, align: usize, result: usize) {
+    if enabled() {
+        unsafe { push(op, ptr, size, align, result) }
+    }
+}
+
+#[cold]
+fn start() -> bool {
+    unsafe {
+        if environ.is_null() ||
+            STATE
+                .compare_exchange(UNKNOWN, STARTING, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return false;
+        }
+        let var = libc::getenv(b"ELFC_DEBUG\0".as_ptr() as *const c_char);
+        if var.is_null() || *var == 0 || (*var == b'0' as c_char && *var.offset(1) == 0) {
+            STATE.store(OFF, Ordering::Release);
+            return false;
+        }
+        let ring = libc::mmap(
+            ptr::null_mut(),
+            ENTRIES * mem::size_of::<Entry>(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if ring == libc::MAP_FAILED {
+            let _ = FDWriter(2).write_str("elfc: could not map the call log for ELFC_DEBUG\n");
+            STATE.store(OFF, Ordering::Release);
+            return false;
+        }
+        RING.store(ring as usize, Ordering::Relaxed);
+        install();
+        STATE.store(ON, Ordering::Release);
+        true
+    }
+}
+
+unsafe fn entry(n: usize) -> &'static Entry {
+    &*(RING.load(Ordering::Relaxed) as *const Entry).offset((n % ENTRIES) as isize)
+}
+
+unsafe fn push(op: Op, ptr: usize, size: usize, align: usize, result: usize) {
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    let e = entry(n);
+    e.seq.store(0, Ordering::Relaxed);
+    fence(Ordering::Release);
+    e.op.store(op as usize, Ordering::Relaxed);
+    e.thread.store(libc::pthread_self() as usize, Ordering::Relaxed);
+    e.ptr.store(ptr, Ordering::Relaxed);
+    e.size.store(size, Ordering::Relaxed);
+    e.align.store(align, Ordering::Relaxed);
+    e.result.store(result, Ordering::Relaxed);
+    e.seq.store(n + 1, Ordering::Release);
+}
+
+/// Print the recorded calls to stderr, oldest first. This is safe to call from a signal handler.
+pub fn dump() {
+    if STATE.load(Ordering::Acquire) != ON {
+        return;
+    }
+    let _ = unsafe { write_calls(&mut FDWriter(2)) };
+}
+
+unsafe fn write_calls<W: Write>(w: &mut W) -> fmt::Result {
+    let next = NEXT.load(Ordering::Acquire);
+    let first = next.saturating_sub(ENTRIES);
+    writeln!(
+        w,
+        "elfc: the last {} of {} allocator calls, oldest first:",
+        next - first,
+        next
+    )?;
+    for n in first..next {
+        let e = entry(n);
+        if e.seq.load(Ordering::Acquire) != n + 1 {
+            continue;
+        }
+        let op = e.op.load(Ordering::Relaxed);
+        let thread = e.thread.load(Ordering::Relaxed);
+        let ptr = e.ptr.load(Ordering::Relaxed);
+        let size = e.size.load(Ordering::Relaxed);
+        let align = e.align.load(Ordering::Relaxed);
+        let result = e.result.load(Ordering::Relaxed);
+        fence(Ordering::Acquire);
+        if e.seq.load(Ordering::Relaxed) != n + 1 {
+            // Overwritten while it was read.
+            continue;
+        }
+        write!(w, "  #{} thread {:#x}: ", n, thread)?;
+        match Op::from_usize(op) {
+            Some(Op::Malloc) => writeln!(w, "malloc({}) = {:#x}", size, result),
+            Some(Op::Free) => writeln!(w, "free({:#x})", ptr),
+            Some(Op::Realloc) => writeln!(w, "realloc({:#x}, {}) = {:#x}", ptr, size, result),
+            Some(Op::Alloc) => writeln!(w, "alloc({}, align {}) = {:#x}", size, align, result),
+            Some(Op::Dealloc) => writeln!(w, "dealloc({:#x}, {}, align {})", ptr, size, align),
+            Some(Op::AllocRealloc) => writeln!(
+                w,
+                "realloc({:#x}, {}, align {}) = {:#x}",
+                ptr,
+                size,
+                align,
+                result
+            ),
+            None => writeln!(w, "unknown call {}", op),
+        }?;
+    }
+    Ok(())
+}
+
+unsafe fn install() {
+    let mut action: libc::sigaction = mem::zeroed();
+    action.sa_sigaction = handle_usr2 as usize;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK | libc::SA_RESTART;
+    libc::sigemptyset(&mut action.sa_mask);
+    PREV_USR2 = swap_action(libc::SIGUSR2, &action);
+    action.sa_sigaction = handle_fatal as usize;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+    for (i, &sig) in FATAL_SIGNALS.iter().enumerate() {
+        PREV_FATAL[i] = swap_action(sig, &action);
+    }
+}
+
+/// Install `action` for `sig`, returning the action it replaced, or `None` if it failed.
+unsafe fn swap_action(sig: c_int, action: &libc::sigaction) -> Option<libc::sigaction> {
+    let mut prev: libc::sigaction = mem::zeroed();
+    if libc::sigaction(sig, action, &mut prev) == 0 {
+        Some(prev)
+    } else {
+        None
+    }
+}
+
+extern "C" fn handle_usr2(sig: c_int, info: *mut siginfo_t, ctx: *mut c_void) {
+    unsafe {
+        let errno = *libc::__errno_location();
+        dump();
+        if let Some(ref prev) = PREV_USR2 {
+            call(prev, sig, info, ctx);
+        }
+        *libc::__errno_location() = errno;
+    }
+}
+
+extern "C" fn handle_fatal(sig: c_int, info: *mut siginfo_t, ctx: *mut c_void) {
+    unsafe {
+        if !DYING.swap(true, Ordering::AcqRel) {
+            let _ = writeln!(FDWriter(2), "elfc: caught signal {}", sig);
+            dump();
+        }
+        let prev = FATAL_SIGNALS
+            .iter()
+            .position(|&s| s == sig)
+            .and_then(|i| PREV_FATAL[i]);
+        if let Some(ref prev) = prev {
+            if call(prev, sig, info, ctx) {
+                return;
+            }
+        }
+        // Die of the signal as we would have without the handler. It is blocked until we return.
+        let mut dfl: libc::sigaction = mem::zeroed();
+        dfl.sa_sigaction = libc::SIG_DFL;
+        libc::sigaction(sig, &dfl, ptr::null_mut());
+        libc::raise(sig);
+    }
+}
+
+/// Call the handler of `action`, if it has one. Returns whether it did.
+unsafe fn call(
+    action: &libc::sigaction,
+    sig: c_int,
+    info: *mut siginfo_t,
+    ctx: *mut c_void,
+) -> bool {
+    if action.sa_sigaction == libc::SIG_DFL || action.sa_sigaction == libc::SIG_IGN {
+        return false;
+    }
+    if action.sa_flags & libc::SA_SIGINFO != 0 {
+        let f: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) =
+            mem::transmute(action.sa_sigaction);
+        f(sig, info, ctx);
+    } else {
+        let f: extern "C" fn(c_int) = mem::transmute(action.sa_sigaction);
+        f(sig);
+    }
+    true
+}
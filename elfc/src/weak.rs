@@ -0,0 +1,73 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Weak definitions of the C allocation API, for linking elfc statically.
+//!
+//! By default, elfc defines `malloc`, `free`, and the rest of the C allocation API as strong
+//! symbols, which is what loading it with `LD_PRELOAD` needs. When `libelfc.a` is linked into a
+//! binary that also pulls in libc's allocator (for instance because the object file in `libc.a`
+//! that defines `malloc` also defines some other function that the binary uses), the linker
+//! fails with duplicate definitions instead.
+//!
+//! With the `weak-symbols` feature, each function is defined twice: as a strong symbol with an
+//! `elfc_` prefix (`elfc_malloc`, `elfc_free`, and so on), and as a weak symbol with the usual
+//! name that calls the prefixed one. If nothing else defines `malloc`, the weak definitions are
+//! used and the binary allocates with elfmalloc. If libc's allocator is linked in, its definitions
+//! take precedence without an error, and elfmalloc remains available through the prefixed names.
+//! libc's allocation functions are defined in a single object file, so they replace elfc's as a
+//! set rather than one by one.
+
+use malloc_bind::Malloc;
+use libc::{c_void, size_t};
+use super::Elfc;
+
+#[cfg(windows)]
+compile_error!("the weak-symbols feature is only supported on ELF and Mach-O targets");
+
+static HEAP: Elfc = Elfc;
+
+macro_rules! define_weak {
+    ($($(#[$attr:meta])* fn $name:ident / $prefixed:ident($($arg:ident: $ty:ty),*) -> $ret:ty
+       => $method:ident;)*) => {
+        $(
+            $(#[$attr])*
+            #[no_mangle]
+            pub extern "C" fn $prefixed($($arg: $ty),*) -> $ret {
+                unsafe { HEAP.$method($($arg),*) }
+            }
+
+            $(#[$attr])*
+            #[no_mangle]
+            #[linkage = "weak"]
+            pub extern "C" fn $name($($arg: $ty),*) -> $ret {
+                $prefixed($($arg),*)
+            }
+        )*
+    };
+}
+
+define_weak! {
+    fn malloc / elfc_malloc(size: size_t) -> *mut c_void => c_malloc;
+    fn free / elfc_free(ptr: *mut c_void) -> () => c_free;
+    #[cfg(target_os = "linux")]
+    fn cfree / elfc_cfree(ptr: *mut c_void) -> () => c_cfree;
+    fn calloc / elfc_calloc(nmemb: size_t, size: size_t) -> *mut c_void => c_calloc;
+    fn valloc / elfc_valloc(size: size_t) -> *mut c_void => c_valloc;
+    #[cfg(target_os = "linux")]
+    fn pvalloc / elfc_pvalloc(size: size_t) -> *mut c_void => c_pvalloc;
+    fn realloc / elfc_realloc(ptr: *mut c_void, size: size_t) -> *mut c_void => c_realloc;
+    #[cfg(target_os = "macos")]
+    fn reallocf / elfc_reallocf(ptr: *mut c_void, size: size_t) -> *mut c_void => c_reallocf;
+    fn posix_memalign / elfc_posix_memalign(memptr: *mut *mut c_void,
+                                            alignment: size_t,
+                                            size: size_t) -> i32 => c_posix_memalign;
+    #[cfg(target_os = "linux")]
+    fn memalign / elfc_memalign(alignment: size_t, size: size_t) -> *mut c_void => c_memalign;
+    #[cfg(target_os = "linux")]
+    fn aligned_alloc / elfc_aligned_alloc(alignment: size_t, size: size_t) -> *mut c_void
+        => c_aligned_alloc;
+}
@@ -0,0 +1,25 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Makes a few allocator calls with recognizable sizes and, if given the argument "signal", asks
+// elfc to print its call log by raising SIGUSR2. This is meant to be run with elfc loaded via
+// LD_PRELOAD; see call_log.rs.
+
+#include <signal.h>
+#include <stdlib.h>
+#include <string.h>
+
+int main(int argc, char **argv) {
+    char *p = malloc(1234);
+    memset(p, 1, 1234);
+    p = realloc(p, 5678);
+    free(p);
+    if (argc > 1 && strcmp(argv[1], "signal") == 0) {
+        raise(SIGUSR2);
+    }
+    return 0;
+}
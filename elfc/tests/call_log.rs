@@ -0,0 +1,69 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Builds `call_log.c` with the system C compiler and checks the call log that elfc prints on
+//! `SIGUSR2` with `ELFC_DEBUG=1`.
+
+#![cfg(target_os = "linux")]
+
+use std::env;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// The directory containing `libelfc.so`.
+///
+/// Integration tests are built to `target/<profile>/deps`, and Cargo places the cdylib in
+/// `target/<profile>`.
+fn target_dir() -> PathBuf {
+    let mut dir = env::current_exe().unwrap();
+    dir.pop();
+    if dir.ends_with("deps") {
+        dir.pop();
+    }
+    dir
+}
+
+fn run(debug: &str, args: &[&str]) -> Output {
+    let dir = target_dir();
+    let lib = dir.join("libelfc.so");
+    assert!(lib.exists(), "{} does not exist", lib.display());
+    let exe = dir.join("elfc-call-log");
+    Command::new(&exe)
+        .args(args)
+        .env("LD_PRELOAD", &lib)
+        .env("ELFC_DEBUG", debug)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn call_log() {
+    let src = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("call_log.c");
+    let exe = target_dir().join("elfc-call-log");
+    let cc = env::var("CC").unwrap_or_else(|_| String::from("cc"));
+    let status = Command::new(cc)
+        .args(&["-std=gnu11", "-Wall", "-O1", "-fno-builtin", "-o"])
+        .arg(&exe)
+        .arg(&src)
+        .status()
+        .expect("could not run the C compiler");
+    assert!(status.success(), "failed to compile {}", src.display());
+
+    let output = run("1", &["signal"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "SIGUSR2 was not handled:\n{}", stderr);
+    assert!(stderr.contains("allocator calls, oldest first:"), "{}", stderr);
+    assert!(stderr.contains(": malloc(1234) = 0x"), "{}", stderr);
+    assert!(stderr.contains(", 5678) = 0x"), "{}", stderr);
+    let malloc = stderr.find(": malloc(1234)").unwrap();
+    assert!(stderr[malloc..].contains(": free(0x"), "{}", stderr);
+
+    // Without the log, nothing is printed.
+    let output = run("0", &[]);
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty(), "{}", String::from_utf8_lossy(&output.stderr));
+}
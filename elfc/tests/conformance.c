@@ -0,0 +1,277 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Checks the return values and errno behavior of the C allocation API against POSIX (and the
+// Linux manpages, where POSIX leaves things unspecified). This is meant to be run with elfc
+// loaded via LD_PRELOAD; see conformance.rs.
+
+#define _GNU_SOURCE
+#include <dlfcn.h>
+#include <errno.h>
+#include <malloc.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+// A value that no allocation function should ever set errno to.
+#define SENTINEL EBADF
+
+static int failures = 0;
+
+#define CHECK(cond)                                                          \
+    do {                                                                     \
+        if (!(cond)) {                                                       \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, \
+                    #cond);                                                  \
+            failures++;                                                      \
+        }                                                                    \
+    } while (0)
+
+// Sizes are read through volatile variables so that the compiler cannot constant-fold calls with
+// impossible sizes.
+static volatile size_t huge = SIZE_MAX;
+static volatile size_t half = SIZE_MAX / 2 + 1;
+
+static int aligned(void *p, size_t align) {
+    return ((uintptr_t)p & (align - 1)) == 0;
+}
+
+static void test_malloc(void) {
+    errno = SENTINEL;
+    void *p = malloc(100);
+    CHECK(p != NULL);
+    CHECK(aligned(p, sizeof(void *)));
+    CHECK(errno == SENTINEL);
+    memset(p, 0xff, 100);
+    free(p);
+    CHECK(errno == SENTINEL);
+
+    // malloc(0) may return either NULL or a pointer that can be passed to free, but it does not
+    // fail.
+    p = malloc(0);
+    CHECK(errno == SENTINEL);
+    free(p);
+
+    p = malloc(huge);
+    CHECK(p == NULL);
+    CHECK(errno == ENOMEM);
+
+    errno = SENTINEL;
+    free(NULL);
+    CHECK(errno == SENTINEL);
+}
+
+// Objects of at least _Alignof(max_align_t) bytes must be aligned for any fundamental type. SSE
+// code relies on this for 16-byte objects. Many objects of each size are allocated so that
+// objects in the middle of a slab are checked as well.
+static void test_fundamental_alignment(void) {
+    const size_t align = _Alignof(max_align_t);
+    void *ptrs[64];
+    for (size_t size = align; size <= 4096; size += 8) {
+        for (int i = 0; i < 64; i++) {
+            ptrs[i] = malloc(size);
+            CHECK(ptrs[i] != NULL);
+            if (!aligned(ptrs[i], align)) {
+                fprintf(stderr, "malloc(%zu) returned %p\n", size, ptrs[i]);
+                failures++;
+            }
+        }
+        for (int i = 0; i < 64; i++) {
+            free(ptrs[i]);
+        }
+    }
+    void *p = calloc(3, 16);
+    CHECK(aligned(p, align));
+    p = realloc(p, 80);
+    CHECK(aligned(p, align));
+    free(p);
+}
+
+static void test_calloc(void) {
+    errno = SENTINEL;
+    unsigned char *p = calloc(10, 100);
+    CHECK(p != NULL);
+    CHECK(errno == SENTINEL);
+    for (int i = 0; i < 1000; i++) {
+        if (p[i] != 0) {
+            CHECK(p[i] == 0);
+            break;
+        }
+    }
+    free(p);
+
+    p = calloc(half, 2);
+    CHECK(p == NULL);
+    CHECK(errno == ENOMEM);
+
+    errno = SENTINEL;
+    p = calloc(2, half);
+    CHECK(p == NULL);
+    CHECK(errno == ENOMEM);
+}
+
+static void test_realloc(void) {
+    errno = SENTINEL;
+    char *p = realloc(NULL, 16);
+    CHECK(p != NULL);
+    CHECK(errno == SENTINEL);
+    strcpy(p, "conformance");
+
+    char *q = realloc(p, 1 << 20);
+    CHECK(q != NULL);
+    CHECK(errno == SENTINEL);
+    CHECK(strcmp(q, "conformance") == 0);
+    p = q;
+
+    // A failed realloc leaves the original allocation untouched.
+    q = realloc(p, huge);
+    CHECK(q == NULL);
+    CHECK(errno == ENOMEM);
+    CHECK(strcmp(p, "conformance") == 0);
+
+    errno = SENTINEL;
+    q = realloc(p, 8);
+    CHECK(q != NULL);
+    CHECK(errno == SENTINEL);
+    CHECK(memcmp(q, "conforma", 8) == 0);
+    free(q);
+    CHECK(errno == SENTINEL);
+}
+
+static void test_posix_memalign(void) {
+    void *p = NULL;
+
+    // posix_memalign reports errors through its return value and does not set errno.
+    errno = SENTINEL;
+    CHECK(posix_memalign(&p, 0, 16) == EINVAL);
+    CHECK(posix_memalign(&p, 3, 16) == EINVAL);
+    CHECK(posix_memalign(&p, sizeof(void *) / 2, 16) == EINVAL);
+    CHECK(posix_memalign(&p, 3 * sizeof(void *), 16) == EINVAL);
+    CHECK(errno == SENTINEL);
+
+    CHECK(posix_memalign(&p, sizeof(void *), 16) == 0);
+    CHECK(p != NULL);
+    CHECK(aligned(p, sizeof(void *)));
+    free(p);
+
+    CHECK(posix_memalign(&p, 4096, 100) == 0);
+    CHECK(p != NULL);
+    CHECK(aligned(p, 4096));
+    free(p);
+
+    CHECK(posix_memalign(&p, 64, huge) == ENOMEM);
+    CHECK(errno == SENTINEL);
+}
+
+static void test_memalign(void) {
+    errno = SENTINEL;
+    void *p = memalign(256, 100);
+    CHECK(p != NULL);
+    CHECK(aligned(p, 256));
+    CHECK(errno == SENTINEL);
+    free(p);
+
+    p = memalign(3, 100);
+    CHECK(p == NULL);
+    CHECK(errno == EINVAL);
+
+    errno = SENTINEL;
+    p = memalign(256, huge);
+    CHECK(p == NULL);
+    CHECK(errno == ENOMEM);
+}
+
+static void test_aligned_alloc(void) {
+    errno = SENTINEL;
+    void *p = aligned_alloc(64, 128);
+    CHECK(p != NULL);
+    CHECK(aligned(p, 64));
+    CHECK(errno == SENTINEL);
+    free(p);
+
+    p = aligned_alloc(64, 100);
+    CHECK(p == NULL);
+    CHECK(errno == EINVAL);
+
+    errno = SENTINEL;
+    p = aligned_alloc(48, 96);
+    CHECK(p == NULL);
+    CHECK(errno == EINVAL);
+}
+
+static void test_valloc(void) {
+    size_t pagesize = (size_t)sysconf(_SC_PAGESIZE);
+
+    errno = SENTINEL;
+    void *p = valloc(100);
+    CHECK(p != NULL);
+    CHECK(aligned(p, pagesize));
+    CHECK(errno == SENTINEL);
+    free(p);
+
+    p = pvalloc(pagesize + 1);
+    CHECK(p != NULL);
+    CHECK(aligned(p, pagesize));
+    CHECK(errno == SENTINEL);
+    free(p);
+
+    p = valloc(huge);
+    CHECK(p == NULL);
+    CHECK(errno == ENOMEM);
+}
+
+// Pointers allocated by libc's own malloc (e.g., before elfc was loaded, or by a library
+// statically linked against it) must be forwarded to libc rather than crash elfc.
+static void test_foreign(void) {
+    void *libc = dlopen("libc.so.6", RTLD_LAZY | RTLD_NOLOAD);
+    if (libc == NULL) {
+        return;
+    }
+    void *(*libc_malloc)(size_t) = (void *(*)(size_t))dlsym(libc, "malloc");
+    CHECK(libc_malloc != NULL && libc_malloc != malloc);
+    if (libc_malloc == NULL || libc_malloc == malloc) {
+        return;
+    }
+
+    free(libc_malloc(100));
+
+    char *p = libc_malloc(100);
+    CHECK(p != NULL);
+    memset(p, 'a', 100);
+    p = realloc(p, 100000);
+    CHECK(p != NULL);
+    CHECK(p[0] == 'a' && p[99] == 'a');
+    free(p);
+    dlclose(libc);
+}
+
+int main(void) {
+    // Make sure that we are testing elfc rather than the system allocator.
+    if (dlsym(RTLD_DEFAULT, "elfmalloc_trim") == NULL) {
+        fprintf(stderr, "elfc is not loaded; run with LD_PRELOAD\n");
+        return 2;
+    }
+
+    test_malloc();
+    test_fundamental_alignment();
+    test_calloc();
+    test_realloc();
+    test_posix_memalign();
+    test_memalign();
+    test_aligned_alloc();
+    test_valloc();
+    test_foreign();
+
+    if (failures > 0) {
+        fprintf(stderr, "%d checks failed\n", failures);
+        return 1;
+    }
+    return 0;
+}
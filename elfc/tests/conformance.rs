@@ -0,0 +1,56 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Builds `conformance.c` with the system C compiler and runs it with elfc loaded via
+//! `LD_PRELOAD`.
+
+#![cfg(target_os = "linux")]
+
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The directory containing `libelfc.so`.
+///
+/// Integration tests are built to `target/<profile>/deps`, and Cargo places the cdylib in
+/// `target/<profile>`.
+fn target_dir() -> PathBuf {
+    let mut dir = env::current_exe().unwrap();
+    dir.pop();
+    if dir.ends_with("deps") {
+        dir.pop();
+    }
+    dir
+}
+
+#[test]
+fn c_conformance() {
+    let dir = target_dir();
+    let lib = dir.join("libelfc.so");
+    assert!(lib.exists(), "{} does not exist", lib.display());
+
+    let src = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("conformance.c");
+    let exe = dir.join("elfc-conformance");
+    let cc = env::var("CC").unwrap_or_else(|_| String::from("cc"));
+    // -fno-builtin keeps the compiler from making assumptions about the allocation functions
+    // (e.g., that malloc(SIZE_MAX) returns NULL without calling it).
+    let status = Command::new(cc)
+        .args(&["-std=gnu11", "-Wall", "-O1", "-fno-builtin", "-o"])
+        .arg(&exe)
+        .arg(&src)
+        .arg("-ldl")
+        .status()
+        .expect("could not run the C compiler");
+    assert!(status.success(), "failed to compile {}", src.display());
+
+    let output = Command::new(&exe).env("LD_PRELOAD", &lib).output().unwrap();
+    assert!(
+        output.status.success(),
+        "conformance checks failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
@@ -0,0 +1,180 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Builds the example applications in `examples/` with the system C compiler, runs them against
+//! elfc, and checks that they succeed without exceeding their peak resident set size.
+//!
+//! Each example is run with `libelfc.so` loaded via `LD_PRELOAD` and, with the `weak-symbols`
+//! feature, linked statically against `libelfc.a`. The examples check their own results and exit
+//! with a nonzero status if something is wrong, including if elfc is not the allocator in use.
+//! Unlike the unit tests, they exercise symbol interposition and allocation from threads that
+//! were not created by Rust.
+//!
+//! Examples that depend on a library that is not installed (such as SQLite) are skipped with a
+//! message on standard error.
+
+#![cfg(target_os = "linux")]
+
+extern crate libc;
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+struct Example {
+    /// The name of the source file in `examples/`, without the `.c` extension.
+    name: &'static str,
+    /// Libraries to link against, other than elfc.
+    libs: &'static [&'static str],
+    /// A program that only compiles if the libraries in `libs` are installed, or `None` if they
+    /// are always present.
+    probe: Option<&'static str>,
+    /// The largest acceptable peak resident set size, in MiB. The examples free what they
+    /// allocate as they go, so exceeding this points at memory that elfmalloc fails to reuse.
+    max_rss_mb: usize,
+}
+
+const EXAMPLES: &[Example] = &[
+    Example {
+        name: "sqlite_insert",
+        libs: &["-lsqlite3"],
+        probe: Some(
+            "#include <sqlite3.h>\nint main(void) { return sqlite3_libversion_number() == 0; }\n",
+        ),
+        max_rss_mb: 512,
+    },
+    Example {
+        name: "hash_table",
+        libs: &["-lpthread"],
+        probe: None,
+        max_rss_mb: 256,
+    },
+];
+
+/// The libraries that Rust's standard library depends on, for linking against `libelfc.a`.
+#[cfg(feature = "weak-symbols")]
+const STD_LIBS: &[&str] = &["-lpthread", "-ldl", "-lm", "-lrt", "-lutil"];
+
+/// The directory containing `libelfc.so` and `libelfc.a`. See `target_dir` in `conformance.rs`.
+fn target_dir() -> PathBuf {
+    let mut dir = env::current_exe().unwrap();
+    dir.pop();
+    if dir.ends_with("deps") {
+        dir.pop();
+    }
+    dir
+}
+
+/// Compile and link `src` into `exe` with the system C compiler, passing `args` after the source
+/// file. Returns `false` if the compiler fails.
+fn compile(src: &Path, exe: &Path, args: &[&str], quiet: bool) -> bool {
+    let cc = env::var("CC").unwrap_or_else(|_| String::from("cc"));
+    let mut cmd = Command::new(cc);
+    // -fno-builtin keeps the compiler from making assumptions about the allocation functions.
+    cmd.args(&["-std=gnu11", "-Wall", "-O1", "-fno-builtin", "-o"])
+        .arg(exe)
+        .arg(src)
+        .args(args);
+    if quiet {
+        cmd.stdout(Stdio::null()).stderr(Stdio::null());
+    }
+    cmd.status().expect("could not run the C compiler").success()
+}
+
+/// Whether the libraries that `example` needs are installed. `mode` keeps the tests, which run in
+/// parallel, from using the same file names.
+fn available(example: &Example, dir: &Path, mode: &str) -> bool {
+    let probe = match example.probe {
+        Some(probe) => probe,
+        None => return true,
+    };
+    let exe = dir.join(format!("elfc-probe-{}-{}", example.name, mode));
+    let src = exe.with_extension("c");
+    fs::File::create(&src)
+        .and_then(|mut f| f.write_all(probe.as_bytes()))
+        .unwrap();
+    let ok = compile(&src, &exe, example.libs, true);
+    let _ = fs::remove_file(&src);
+    ok
+}
+
+/// Run `cmd` to completion, and return whether it succeeded, its peak resident set size in KiB,
+/// and its standard output and error.
+fn run(cmd: &mut Command) -> (bool, usize, String) {
+    let mut child = cmd.stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // std's wait does not report resource usage, so reap the child with wait4 instead. The
+    // examples print little enough for it to fit in the pipe buffers.
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { mem::zeroed() };
+    let pid = unsafe { libc::wait4(child.id() as libc::pid_t, &mut status, 0, &mut usage) };
+    assert_eq!(pid, child.id() as libc::pid_t, "wait4 failed");
+    let mut output = String::new();
+    child.stdout.take().unwrap().read_to_string(&mut output).unwrap();
+    child.stderr.take().unwrap().read_to_string(&mut output).unwrap();
+    let success = libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0;
+    (success, usage.ru_maxrss as usize, output)
+}
+
+/// Build every available example with `link_args`, run it with `env` set, and panic with a
+/// description of every failure.
+fn run_examples(mode: &str, link_args: &[&str], env: &[(&str, &Path)]) {
+    let dir = target_dir();
+    let examples = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("examples");
+    let mut failures = Vec::new();
+    for example in EXAMPLES {
+        if !available(example, &dir, mode) {
+            eprintln!("skipping example {}: its libraries are not installed", example.name);
+            continue;
+        }
+        let src = examples.join(format!("{}.c", example.name));
+        let exe = dir.join(format!("elfc-example-{}-{}", example.name, mode));
+        let mut args = link_args.to_vec();
+        args.extend_from_slice(example.libs);
+        assert!(compile(&src, &exe, &args, false), "failed to build {}", src.display());
+
+        let mut cmd = Command::new(&exe);
+        for &(k, v) in env {
+            cmd.env(k, v);
+        }
+        let (success, rss_kb, output) = run(&mut cmd);
+        if !success {
+            failures.push(format!("{} ({}) failed:\n{}", example.name, mode, output));
+        } else if rss_kb > example.max_rss_mb << 10 {
+            failures.push(format!(
+                "{} ({}) used {} MiB, more than its limit of {} MiB",
+                example.name,
+                mode,
+                rss_kb >> 10,
+                example.max_rss_mb
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn examples_preloaded() {
+    let lib = target_dir().join("libelfc.so");
+    assert!(lib.exists(), "{} does not exist", lib.display());
+    run_examples("preload", &[], &[("LD_PRELOAD", &lib)]);
+}
+
+#[cfg(feature = "weak-symbols")]
+#[test]
+fn examples_static() {
+    let lib = target_dir().join("libelfc.a");
+    assert!(lib.exists(), "{} does not exist", lib.display());
+    let mut args = vec![lib.to_str().unwrap()];
+    args.extend_from_slice(STD_LIBS);
+    run_examples("static", &args, &[]);
+}
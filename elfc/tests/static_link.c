@@ -0,0 +1,36 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Checks that a binary linked statically against libelfc.a built with the weak-symbols feature
+// allocates with elfmalloc through both the standard and the prefixed names. See static_link.rs.
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+void *elfc_malloc(size_t size);
+void elfc_free(void *ptr);
+
+int main(void) {
+    // If free were libc's, it would reject memory allocated by elfmalloc, and vice versa.
+    for (size_t size = 8; size <= (4 << 20); size *= 2) {
+        char *p = elfc_malloc(size);
+        char *q = malloc(size);
+        if (p == NULL || q == NULL) {
+            fprintf(stderr, "allocation of %zu bytes failed\n", size);
+            return 1;
+        }
+        memset(p, 1, size);
+        memset(q, 2, size);
+        free(p);
+        elfc_free(q);
+    }
+    char *r = realloc(NULL, 100);
+    r = realloc(r, 100000);
+    elfc_free(r);
+    return 0;
+}
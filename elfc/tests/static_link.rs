@@ -0,0 +1,52 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Links `static_link.c` against `libelfc.a` with the system C compiler and runs it.
+
+#![cfg(all(target_os = "linux", feature = "weak-symbols"))]
+
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The directory containing `libelfc.a`. See `target_dir` in `conformance.rs`.
+fn target_dir() -> PathBuf {
+    let mut dir = env::current_exe().unwrap();
+    dir.pop();
+    if dir.ends_with("deps") {
+        dir.pop();
+    }
+    dir
+}
+
+#[test]
+fn static_link() {
+    let dir = target_dir();
+    let lib = dir.join("libelfc.a");
+    assert!(lib.exists(), "{} does not exist", lib.display());
+
+    let src = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("static_link.c");
+    let exe = dir.join("elfc-static-link");
+    let cc = env::var("CC").unwrap_or_else(|_| String::from("cc"));
+    let status = Command::new(cc)
+        .args(&["-std=gnu11", "-Wall", "-O1", "-fno-builtin", "-o"])
+        .arg(&exe)
+        .arg(&src)
+        .arg(&lib)
+        // The libraries that Rust's standard library depends on.
+        .args(&["-lpthread", "-ldl", "-lm", "-lrt", "-lutil"])
+        .status()
+        .expect("could not run the C compiler");
+    assert!(status.success(), "failed to link {} against {}", src.display(), lib.display());
+
+    let output = Command::new(&exe).output().unwrap();
+    assert!(
+        output.status.success(),
+        "statically linked binary failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
@@ -0,0 +1,38 @@
+#!/bin/bash
+
+# Copyright 2017 the authors. See the 'Copyright and license' section of the
+# README.md file at the top-level directory of this repository.
+#
+# Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+# the MIT license (the LICENSE-MIT file) at your option. This file may not be
+# copied, modified, or distributed except according to those terms.
+
+# Check that the thread cache's allocation fast path still compiles to a handful of
+# instructions: a bounds check, a pointer pop, and a jump to the out-of-line slow path.
+#
+# This needs cargo-asm (`cargo install cargo-asm`). Without it, the check is skipped, so that CI
+# and contributors don't need it installed.
+#
+# Usage: ./asm_check.sh [max instructions]
+
+set -e
+
+MAX_INSTRUCTIONS="${1:-16}"
+PROBE="elfmalloc::frontends::cache_pop_probe"
+
+if ! cargo asm --version >/dev/null 2>&1; then
+  echo "cargo-asm is not installed; skipping the fast path check" >&2
+  exit 0
+fi
+
+ASM=$(cargo asm --release --lib "$PROBE")
+# Count instructions: skip blank lines, directives, comments, and labels (including the symbol
+# name, which cargo-asm prints as a label).
+COUNT=$(echo "$ASM" | grep -vE '^\s*($|[.#;]|\S+:\s*$)' | wc -l)
+
+echo "$ASM"
+echo "$PROBE: $COUNT instructions (at most $MAX_INSTRUCTIONS allowed)"
+if [ "$COUNT" -gt "$MAX_INSTRUCTIONS" ]; then
+  echo "the thread cache fast path has grown; see cache_pop_probe in src/frontends.rs" >&2
+  exit 1
+fi
@@ -0,0 +1,147 @@
+# Copyright 2017 the authors. See the 'Copyright and license' section of the
+# README.md file at the top-level directory of this repository.
+#
+# Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+# the MIT license (the LICENSE-MIT file) at your option. This file may not be
+# copied, modified, or distributed except according to those terms.
+
+"""Debugger-independent parts of the elfmalloc debugger scripts.
+
+This module decodes elfmalloc's ownership table from the ELF_DBG_DESCRIPTOR
+static exported with the debug-support feature (see the debug_support module),
+so that it works on core dumps as well as on live processes. The gdb and lldb
+scripts supply a Memory object that reads the process's memory.
+"""
+
+import struct
+
+DESCRIPTOR_SYMBOL = "ELF_DBG_DESCRIPTOR"
+DESCRIPTOR_MAGIC = 0x676264666C65
+DESCRIPTOR_VERSION = 1
+
+KIND_SMALL_SLAG = 1
+KIND_BIG_SLAG = 2
+KIND_LARGE = 3
+
+HEAPS = {0: "thread", 1: "shared", 2: "large"}
+
+
+class Memory(object):
+    """Reads words from a process or core dump. Subclasses implement read."""
+
+    def __init__(self, pointer_size, little_endian):
+        self.pointer_size = pointer_size
+        order = "<" if little_endian else ">"
+        self.word_format = order + ("Q" if pointer_size == 8 else "I")
+        self.u32_format = order + "I"
+        self.u64_format = order + "Q"
+
+    def read(self, addr, size):
+        """Return size bytes of memory at addr as a bytes object."""
+        raise NotImplementedError
+
+    def words(self, addr, count):
+        fmt = self.word_format[0] + self.word_format[1] * count
+        return struct.unpack(fmt, self.read(addr, self.pointer_size * count))
+
+    def word(self, addr):
+        return self.words(addr, 1)[0]
+
+    def u32(self, addr):
+        return struct.unpack(self.u32_format, self.read(addr, 4))[0]
+
+
+class Table(object):
+    """The ownership table, as described by the descriptor at addr."""
+
+    def __init__(self, memory, addr):
+        self.memory = memory
+        magic = struct.unpack(memory.u64_format, memory.read(addr, 8))[0]
+        if magic != DESCRIPTOR_MAGIC:
+            raise ValueError("%s at %#x has the wrong magic number" % (DESCRIPTOR_SYMBOL, addr))
+        version = memory.u32(addr + 8)
+        if version != DESCRIPTOR_VERSION:
+            raise ValueError("unsupported %s version %d (expected %d)" %
+                             (DESCRIPTOR_SYMBOL, version, DESCRIPTOR_VERSION))
+        self.granule_shift = memory.u32(addr + 12)
+        self.leaf_shift = memory.u32(addr + 16)
+        self.address_bits = memory.u32(addr + 20)
+        self.root_slot = memory.word(addr + 24)
+        self.large_object_offset = memory.word(addr + 24 + memory.pointer_size)
+
+    def root(self):
+        return self.memory.word(self.root_slot)
+
+    def entry(self, addr):
+        """The table entry for the granule containing addr, or 0 if there is none."""
+        if addr >> self.address_bits:
+            return 0
+        root = self.root()
+        if root == 0:
+            return 0
+        size = self.memory.pointer_size
+        leaf = self.memory.word(root + (addr >> self.leaf_shift) * size)
+        if leaf == 0:
+            return 0
+        index = (addr & ((1 << self.leaf_shift) - 1)) >> self.granule_shift
+        return self.memory.word(leaf + index * size)
+
+    def describe(self, addr):
+        """Describe the elfmalloc memory containing addr, without reading its metadata."""
+        entry = self.entry(addr)
+        kind = entry & 3
+        if kind == 0:
+            return "%#x is not in memory that belongs to elfmalloc" % addr
+        if kind == KIND_LARGE:
+            granule = addr & ~((1 << self.granule_shift) - 1)
+            base = granule - ((entry >> 2) << self.granule_shift)
+            start = base + self.large_object_offset
+            if addr < start:
+                return "%#x is in the header of the large object at %#x" % (addr, start)
+            return "%#x is %d bytes into the large object at %#x" % (addr, addr - start, start)
+        slag_size = 1 << (entry >> 2)
+        slag = addr & ~(slag_size - 1)
+        name = "small" if kind == KIND_SMALL_SLAG else "big"
+        return "%#x is %d bytes into the %s Slag at %#x (%d bytes)" % (
+            addr, addr - slag, name, slag, slag_size)
+
+    def census(self):
+        """Count the memory that elfmalloc has mapped, like debug_support::census."""
+        counts = {"small_slag_bytes": 0, "big_slag_bytes": 0, "large_objects": 0,
+                  "large_bytes": 0}
+        root = self.root()
+        if root == 0:
+            return counts
+        granule = 1 << self.granule_shift
+        leaves = self.memory.words(root, 1 << (self.address_bits - self.leaf_shift))
+        for leaf in leaves:
+            if leaf == 0:
+                continue
+            for entry in self.memory.words(leaf, 1 << (self.leaf_shift - self.granule_shift)):
+                kind = entry & 3
+                if kind == KIND_SMALL_SLAG:
+                    counts["small_slag_bytes"] += granule
+                elif kind == KIND_BIG_SLAG:
+                    counts["big_slag_bytes"] += granule
+                elif kind == KIND_LARGE:
+                    if entry >> 2 == 0:
+                        counts["large_objects"] += 1
+                    counts["large_bytes"] += granule
+        return counts
+
+
+def format_info(start, usable_size, size_class, heap):
+    """Format the fields of a debug_support::ObjectInfo."""
+    return "object at %#x (usable size %d, size class %d, %s heap)" % (
+        start, usable_size, size_class, HEAPS.get(heap, "unknown"))
+
+
+def format_object(ptr, start, usable_size, size_class, heap):
+    """Format the fields of a debug_support::ObjectInfo describing ptr."""
+    return "%#x is %d bytes into the %s" % (
+        ptr, ptr - start, format_info(start, usable_size, size_class, heap))
+
+
+def format_census(counts):
+    return ("%(small_slag_bytes)d bytes of small Slags, %(big_slag_bytes)d bytes of big Slags, "
+            "%(large_objects)d large objects in %(large_bytes)d bytes" % counts)
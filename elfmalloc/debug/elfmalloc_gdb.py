@@ -0,0 +1,153 @@
+# Copyright 2017 the authors. See the 'Copyright and license' section of the
+# README.md file at the top-level directory of this repository.
+#
+# Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+# the MIT license (the LICENSE-MIT file) at your option. This file may not be
+# copied, modified, or distributed except according to those terms.
+
+"""gdb support for processes using elfmalloc built with the debug-support feature.
+
+Load it with `source path/to/elfmalloc_gdb.py`. This adds two commands:
+
+  elf-lookup EXPR  Describe the object that the pointer EXPR points into. In a
+                   live process this calls elf_dbg_lookup; in a core dump, it
+                   reads the ownership table instead.
+  elf-stats        Count the memory that elfmalloc has mapped.
+
+and pretty-printers for the ObjectInfo, HeapCensus and Descriptor types of
+elfmalloc's debug_support module.
+"""
+
+import os
+import sys
+
+import gdb
+
+sys.path.insert(0, os.path.dirname(os.path.abspath(__file__)))
+import elfmalloc_debug  # noqa: E402
+
+
+class GdbMemory(elfmalloc_debug.Memory):
+    def __init__(self):
+        pointer_size = gdb.lookup_type("void").pointer().sizeof
+        little_endian = "little" in gdb.execute("show endian", to_string=True)
+        super(GdbMemory, self).__init__(pointer_size, little_endian)
+
+    def read(self, addr, size):
+        return bytes(gdb.selected_inferior().read_memory(addr, size))
+
+
+class CLanguage(object):
+    """Evaluate expressions as C while in scope, whatever the language of the current frame."""
+
+    def __enter__(self):
+        self.saved = gdb.parameter("language")
+        gdb.execute("set language c", to_string=True)
+
+    def __exit__(self, *args):
+        gdb.execute("set language %s" % self.saved, to_string=True)
+
+
+def eval_address(expr):
+    with CLanguage():
+        return int(gdb.parse_and_eval("(unsigned long)(%s)" % expr))
+
+
+def table():
+    try:
+        addr = eval_address("&%s" % elfmalloc_debug.DESCRIPTOR_SYMBOL)
+    except gdb.error:
+        raise gdb.GdbError("%s not found; was elfmalloc built with the debug-support feature?" %
+                           elfmalloc_debug.DESCRIPTOR_SYMBOL)
+    try:
+        return elfmalloc_debug.Table(GdbMemory(), addr)
+    except ValueError as e:
+        raise gdb.GdbError(str(e))
+
+
+def live_lookup(memory, ptr):
+    """Call elf_dbg_lookup, returning its description of ptr, or None if it cannot be called."""
+    try:
+        info = eval_address("((unsigned long (*)(void *))elf_dbg_lookup)((void *)%d)" % ptr)
+    except gdb.error:
+        # There is no process to call functions in, e.g. because this is a core dump.
+        return None
+    if info == 0:
+        return "%#x is not in an elfmalloc object" % ptr
+    start, usable_size, size_class = memory.words(info, 3)
+    heap = memory.u32(info + 3 * memory.pointer_size)
+    return elfmalloc_debug.format_object(ptr, start, usable_size, size_class, heap)
+
+
+class LookupCommand(gdb.Command):
+    """Describe the elfmalloc object that a pointer points into: elf-lookup EXPR"""
+
+    def __init__(self):
+        super(LookupCommand, self).__init__("elf-lookup", gdb.COMMAND_DATA, gdb.COMPLETE_EXPRESSION)
+
+    def invoke(self, arg, from_tty):
+        if not arg:
+            raise gdb.GdbError("usage: elf-lookup EXPR")
+        ptr = int(gdb.parse_and_eval(arg).cast(gdb.lookup_type("long").unsigned()))
+        t = table()
+        description = live_lookup(t.memory, ptr)
+        if description is None:
+            description = t.describe(ptr)
+        gdb.write(description + "\n")
+
+
+class StatsCommand(gdb.Command):
+    """Count the memory that elfmalloc has mapped: elf-stats"""
+
+    def __init__(self):
+        super(StatsCommand, self).__init__("elf-stats", gdb.COMMAND_DATA)
+
+    def invoke(self, arg, from_tty):
+        gdb.write(elfmalloc_debug.format_census(table().census()) + "\n")
+
+
+class ObjectInfoPrinter(object):
+    def __init__(self, val):
+        self.val = val
+
+    def to_string(self):
+        v = self.val
+        return elfmalloc_debug.format_info(int(v["start"]), int(v["usable_size"]),
+                                           int(v["size_class"]), int(v["heap"]))
+
+
+class HeapCensusPrinter(object):
+    def __init__(self, val):
+        self.val = val
+
+    def to_string(self):
+        fields = ("small_slag_bytes", "big_slag_bytes", "large_objects", "large_bytes")
+        return elfmalloc_debug.format_census(dict((f, int(self.val[f])) for f in fields))
+
+
+class DescriptorPrinter(object):
+    def __init__(self, val):
+        self.val = val
+
+    def to_string(self):
+        v = self.val
+        return "elfmalloc ownership table v%d (granule 2^%d, leaf 2^%d, %d address bits)" % (
+            int(v["version"]), int(v["granule_shift"]), int(v["leaf_shift"]),
+            int(v["address_bits"]))
+
+
+PRINTERS = {
+    "elfmalloc::debug_support::ObjectInfo": ObjectInfoPrinter,
+    "elfmalloc::debug_support::HeapCensus": HeapCensusPrinter,
+    "elfmalloc::debug_support::Descriptor": DescriptorPrinter,
+}
+
+
+def lookup_printer(val):
+    printer = PRINTERS.get(str(val.type.strip_typedefs().unqualified()))
+    return printer(val) if printer is not None else None
+
+
+LookupCommand()
+StatsCommand()
+gdb.pretty_printers.append(lookup_printer)
@@ -0,0 +1,135 @@
+# Copyright 2017 the authors. See the 'Copyright and license' section of the
+# README.md file at the top-level directory of this repository.
+#
+# Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+# the MIT license (the LICENSE-MIT file) at your option. This file may not be
+# copied, modified, or distributed except according to those terms.
+
+"""lldb support for processes using elfmalloc built with the debug-support feature.
+
+Load it with `command script import path/to/elfmalloc_lldb.py`. This adds the
+same commands as elfmalloc_gdb.py:
+
+  elf-lookup EXPR  Describe the object that the pointer EXPR points into. In a
+                   live process this calls elf_dbg_lookup; in a core dump, it
+                   reads the ownership table instead.
+  elf-stats        Count the memory that elfmalloc has mapped.
+
+and summaries for the ObjectInfo, HeapCensus and Descriptor types of
+elfmalloc's debug_support module.
+"""
+
+import lldb
+
+import elfmalloc_debug
+
+
+class LldbMemory(elfmalloc_debug.Memory):
+    def __init__(self, target):
+        self.process = target.GetProcess()
+        little_endian = target.GetByteOrder() == lldb.eByteOrderLittle
+        super(LldbMemory, self).__init__(target.GetAddressByteSize(), little_endian)
+
+    def read(self, addr, size):
+        error = lldb.SBError()
+        data = self.process.ReadMemory(addr, size, error)
+        if error.Fail():
+            raise ValueError("cannot read %d bytes at %#x: %s" % (size, addr, error.GetCString()))
+        return data
+
+
+def table(target):
+    symbols = target.FindSymbols(elfmalloc_debug.DESCRIPTOR_SYMBOL)
+    if symbols.GetSize() == 0:
+        raise ValueError("%s not found; was elfmalloc built with the debug-support feature?" %
+                         elfmalloc_debug.DESCRIPTOR_SYMBOL)
+    addr = symbols.GetContextAtIndex(0).GetSymbol().GetStartAddress().GetLoadAddress(target)
+    return elfmalloc_debug.Table(LldbMemory(target), addr)
+
+
+def evaluate(frame, expr):
+    options = lldb.SBExpressionOptions()
+    options.SetLanguage(lldb.eLanguageTypeC)
+    return frame.EvaluateExpression(expr, options)
+
+
+def live_lookup(frame, memory, ptr):
+    """Call elf_dbg_lookup, returning its description of ptr, or None if it cannot be called."""
+    if not frame.IsValid():
+        return None
+    value = evaluate(frame, "((unsigned long (*)(void *))elf_dbg_lookup)((void *)%d)" % ptr)
+    if value.GetError().Fail():
+        # There is no process to call functions in, e.g. because this is a core dump.
+        return None
+    info = value.GetValueAsUnsigned()
+    if info == 0:
+        return "%#x is not in an elfmalloc object" % ptr
+    start, usable_size, size_class = memory.words(info, 3)
+    heap = memory.u32(info + 3 * memory.pointer_size)
+    return elfmalloc_debug.format_object(ptr, start, usable_size, size_class, heap)
+
+
+def elf_lookup(debugger, command, result, internal_dict):
+    """Describe the elfmalloc object that a pointer points into: elf-lookup EXPR"""
+    if not command:
+        result.SetError("usage: elf-lookup EXPR")
+        return
+    target = debugger.GetSelectedTarget()
+    frame = target.GetProcess().GetSelectedThread().GetSelectedFrame()
+    if frame.IsValid():
+        value = frame.EvaluateExpression(command)
+    else:
+        value = target.EvaluateExpression(command)
+    if value.GetError().Fail():
+        result.SetError(value.GetError().GetCString())
+        return
+    ptr = value.GetValueAsUnsigned()
+    try:
+        t = table(target)
+        description = live_lookup(frame, t.memory, ptr)
+        if description is None:
+            description = t.describe(ptr)
+    except ValueError as e:
+        result.SetError(str(e))
+        return
+    result.AppendMessage(description)
+
+
+def elf_stats(debugger, command, result, internal_dict):
+    """Count the memory that elfmalloc has mapped: elf-stats"""
+    try:
+        counts = table(debugger.GetSelectedTarget()).census()
+    except ValueError as e:
+        result.SetError(str(e))
+        return
+    result.AppendMessage(elfmalloc_debug.format_census(counts))
+
+
+def field(valobj, name):
+    return valobj.GetChildMemberWithName(name).GetValueAsUnsigned()
+
+
+def object_info_summary(valobj, internal_dict):
+    return elfmalloc_debug.format_info(field(valobj, "start"), field(valobj, "usable_size"),
+                                       field(valobj, "size_class"), field(valobj, "heap"))
+
+
+def heap_census_summary(valobj, internal_dict):
+    fields = ("small_slag_bytes", "big_slag_bytes", "large_objects", "large_bytes")
+    return elfmalloc_debug.format_census(dict((f, field(valobj, f)) for f in fields))
+
+
+def descriptor_summary(valobj, internal_dict):
+    return "elfmalloc ownership table v%d (granule 2^%d, leaf 2^%d, %d address bits)" % (
+        field(valobj, "version"), field(valobj, "granule_shift"), field(valobj, "leaf_shift"),
+        field(valobj, "address_bits"))
+
+
+def __lldb_init_module(debugger, internal_dict):
+    debugger.HandleCommand("command script add -f elfmalloc_lldb.elf_lookup elf-lookup")
+    debugger.HandleCommand("command script add -f elfmalloc_lldb.elf_stats elf-stats")
+    for name, summary in (("ObjectInfo", "object_info_summary"),
+                          ("HeapCensus", "heap_census_summary"),
+                          ("Descriptor", "descriptor_summary")):
+        debugger.HandleCommand("type summary add -F elfmalloc_lldb.%s "
+                               "elfmalloc::debug_support::%s" % (summary, name))
@@ -0,0 +1,173 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Guards against allocation on hot paths.
+//!
+//! Code that must not allocate (a real-time audio callback, the inner loop of a parser, an
+//! iterator adapter that is documented to be allocation-free) can be checked by running it, in a
+//! test or in production, while an `AllocGuard` is alive:
+//!
+//! ```rust,ignore
+//! let sum = assert_no_alloc(|| values.iter().map(|v| v * 2).sum::<u64>());
+//! ```
+//!
+//! While a guard is alive, every allocation on the current thread through elfmalloc's thread
+//! caches - the global allocator, `DynamicAllocator` and the `rust_alloc` handles - is reported
+//! according to the guard's `GuardMode`: it panics, aborts the process, or is merely counted (see
+//! `AllocGuard::allocations`). Frees are allowed. Guards nest; the innermost one decides.
+//!
+//! A guard has no effect on other threads, so work that a guarded thread hands to a thread pool
+//! is not checked. Allocations from other allocators, or that go straight to `mmap`, are not
+//! seen either.
+//!
+//! `GuardMode::Panic` must only be used in Rust code that allocates through elfmalloc's Rust
+//! interfaces, since unwinding out of `malloc` is undefined behavior; for the C API, use
+//! `GuardMode::Abort`. The guard is disabled while the panic is raised, so that the panic machinery
+//! can allocate.
+//!
+//! Checking for a guard costs a thread-local load on every allocation, which is why this module is
+//! behind the `alloc-guard` feature.
+
+use std::marker::PhantomData;
+
+/// What happens when memory is allocated while an `AllocGuard` is alive.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GuardMode {
+    /// Panic, naming the size of the allocation.
+    Panic,
+    /// Print a message to standard error and abort the process.
+    Abort,
+    /// Count the allocation and carry on.
+    Count,
+}
+
+/// The mode of the innermost guard on this thread, or `None`.
+#[thread_local]
+static mut MODE: Option<GuardMode> = None;
+
+/// The number of allocations made on this thread while a guard was alive.
+#[thread_local]
+static mut COUNT: usize = 0;
+
+/// A guard that reports allocations on the current thread until it is dropped. See the module
+/// documentation.
+///
+/// The raw pointer makes this type neither `Send` nor `Sync`: it must be dropped on the thread
+/// that created it.
+pub struct AllocGuard {
+    prev: Option<GuardMode>,
+    start: usize,
+    _marker: PhantomData<*mut u8>,
+}
+
+impl AllocGuard {
+    pub fn new(mode: GuardMode) -> AllocGuard {
+        unsafe {
+            let prev = MODE;
+            MODE = Some(mode);
+            AllocGuard {
+                prev: prev,
+                start: COUNT,
+                _marker: PhantomData,
+            }
+        }
+    }
+
+    /// The number of allocations made on this thread since this guard was created. This is only
+    /// ever nonzero in `GuardMode::Count`, or with an inner guard in that mode.
+    pub fn allocations(&self) -> usize {
+        unsafe { COUNT - self.start }
+    }
+}
+
+impl Drop for AllocGuard {
+    fn drop(&mut self) {
+        unsafe { MODE = self.prev };
+    }
+}
+
+/// Run `f`, panicking if it allocates through elfmalloc on the current thread.
+pub fn assert_no_alloc<R, F: FnOnce() -> R>(f: F) -> R {
+    let _guard = AllocGuard::new(GuardMode::Panic);
+    f()
+}
+
+/// Run `f`, and return its result along with the number of allocations it made through elfmalloc
+/// on the current thread.
+pub fn count_allocs<R, F: FnOnce() -> R>(f: F) -> (R, usize) {
+    let guard = AllocGuard::new(GuardMode::Count);
+    let res = f();
+    (res, guard.allocations())
+}
+
+/// Report an allocation of `bytes` bytes if a guard is alive. Called at the start of every
+/// allocation through a thread cache, before any allocator state is touched.
+#[inline(always)]
+pub fn check(bytes: usize) {
+    unsafe {
+        if let Some(mode) = MODE {
+            violation(mode, bytes);
+        }
+    }
+}
+
+#[cold]
+#[inline(never)]
+unsafe fn violation(mode: GuardMode, bytes: usize) {
+    COUNT += 1;
+    match mode {
+        GuardMode::Count => {}
+        GuardMode::Panic => {
+            // Formatting the message and unwinding allocate. The guard restores the mode when it
+            // is dropped during unwinding.
+            MODE = None;
+            panic!("allocated {} bytes while an AllocGuard was alive", bytes);
+        }
+        GuardMode::Abort => {
+            alloc_eprintln!("elfmalloc: allocated {} bytes while an AllocGuard was alive", bytes);
+            ::std::process::abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::general::global;
+    use std::panic;
+
+    #[test]
+    fn count_and_nest() {
+        unsafe {
+            let (p, n) = count_allocs(|| global::alloc(64));
+            alloc_assert_eq!(n, 1);
+            let (_, n) = count_allocs(|| {
+                global::free(p);
+                let q = global::alloc(1 << 20);
+                // An inner guard in `Count` mode is also seen by the outer one.
+                let inner = AllocGuard::new(GuardMode::Count);
+                let r = global::alloc(16);
+                alloc_assert_eq!(inner.allocations(), 1);
+                drop(inner);
+                global::free(r);
+                global::free(q);
+            });
+            alloc_assert_eq!(n, 2);
+            alloc_assert_eq!(assert_no_alloc(|| 2 + 2), 4);
+        }
+    }
+
+    #[test]
+    fn panic_on_alloc() {
+        // Set up the thread's heap first, so that the panic does not interrupt its initialization.
+        unsafe { global::free(global::alloc(64)) };
+        let res = panic::catch_unwind(|| assert_no_alloc(|| unsafe { global::alloc(64) }));
+        alloc_assert!(res.is_err());
+        // The guard is gone, so allocation works again.
+        unsafe { global::free(global::alloc(64)) };
+    }
+}
@@ -12,6 +12,12 @@
 //! at the call site.
 //!
 //! This module also implements additional traits from the `malloc-bind` crate.
+//!
+//! `ElfMallocGlobal` overrides `Alloc::oom`, so when it is the global allocator, running out of
+//! memory prints the failed request, the size class it was for, and what elfmalloc knows about the
+//! heap: the bytes allocated and the limit (with the `quota` feature), the peak resident set size
+//! (with `quota` on Linux), and the bytes mapped and unmapped (with `slow-path-stats`). The report
+//! is written with `alloc-fmt`, so it does not allocate.
 
 extern crate alloc;
 #[cfg(feature = "c-api")]
@@ -22,6 +28,17 @@ use self::alloc::allocator::{Alloc, AllocErr, Layout};
 #[cfg(feature = "c-api")]
 use self::malloc_bind::{LayoutFinder, Malloc, MIN_ALIGN};
 use super::general::global;
+use super::error::ElfAllocError;
+use super::utils::mmap;
+#[cfg(feature = "quota")]
+use super::quota;
+#[cfg(feature = "slow-path-stats")]
+use super::stats::slow_path_stats;
+#[cfg(all(feature = "c-api", feature = "ownership"))]
+use super::ownership;
+#[cfg(feature = "sites")]
+use super::sites::SiteId;
+use std::cmp;
 use std::mem;
 #[cfg(feature = "c-api")]
 use std::intrinsics::unlikely;
@@ -31,18 +48,108 @@ use self::libc::{size_t, c_void};
 
 /// A zero-sized type used for implementing `Alloc` and `LayoutFinder` for the global instance of
 /// elfmalloc.
+///
+/// The global instance is created the first time memory is allocated, so an `ElfMallocGlobal` can
+/// be used as the global allocator without any setup:
+///
+/// ```rust,ignore
+/// #[global_allocator]
+/// static ALLOC: ElfMallocGlobal = ElfMallocGlobal::new();
+/// ```
 pub struct ElfMallocGlobal;
 
+/// The size of the object that must be allocated to satisfy `l`.
+fn alloc_size(l: &Layout) -> usize {
+    // All objects are only guaranteed to be word-aligned except for powers of two. Powers of two
+    // up to 1MiB are aligned to their size. Past that size, only page-alignment is guaranteed.
+    if l.align() <= mem::size_of::<usize>() {
+        l.size()
+    } else {
+        cmp::max(l.size(), l.align()).next_power_of_two()
+    }
+}
+
+impl ElfMallocGlobal {
+    pub const fn new() -> ElfMallocGlobal {
+        ElfMallocGlobal
+    }
+
+    /// Allocate an object for `l` attributed to the allocation site `site`.
+    ///
+    /// `site` is usually obtained with the `alloc_site!` macro. See the `sites` module for
+    /// details.
+    #[cfg(feature = "sites")]
+    pub unsafe fn alloc_with_site(&self, l: Layout, site: SiteId) -> Result<*mut u8, AllocErr> {
+        check_layout(&l)?;
+        let p = global::alloc_with_site(alloc_size(&l), site);
+        Ok(exhausted_if_null(p, &l)?)
+    }
+
+    /// Allocate an object for `l`, reporting why allocation failed if it does. `Alloc::alloc` is
+    /// this with the error converted to an `AllocErr`; see the `error` module.
+    pub unsafe fn try_alloc(&self, l: Layout) -> Result<*mut u8, ElfAllocError> {
+        check_layout(&l)?;
+        exhausted_if_null(global::alloc(alloc_size(&l)), &l)
+    }
+
+    /// Resize `p` to fit `l`, like `Alloc::realloc`, reporting why reallocation failed if it does.
+    /// `p` is left alone if it does.
+    pub unsafe fn try_realloc(&self, p: *mut u8, l: Layout) -> Result<*mut u8, ElfAllocError> {
+        check_layout(&l)?;
+        exhausted_if_null(global::aligned_realloc(p, l.size(), l.align()), &l)
+    }
+}
+
+/// `alloc_size` picks a size class that is aligned as `l` requires, except that large objects are
+/// only aligned to the page size.
+fn check_layout(l: &Layout) -> Result<(), ElfAllocError> {
+    if l.align() <= mmap::page_size() || global::size_class(alloc_size(l)).is_some() {
+        Ok(())
+    } else {
+        Err(ElfAllocError::InvalidLayout {
+            size: l.size(),
+            align: l.align(),
+        })
+    }
+}
+
+/// Allocation only fails if a limit set with the `quota` feature would be exceeded, in which case
+/// a null pointer is returned.
+#[inline]
+fn exhausted_if_null(p: *mut u8, l: &Layout) -> Result<*mut u8, ElfAllocError> {
+    if p.is_null() {
+        Err(ElfAllocError::QuotaExceeded {
+            size: l.size(),
+            align: l.align(),
+        })
+    } else {
+        Ok(p)
+    }
+}
+
+#[cfg(feature = "c-api")]
+#[cold]
+unsafe fn set_enomem() {
+    #[cfg(target_os = "linux")]
+    {
+        *self::libc::__errno_location() = self::libc::ENOMEM;
+    }
+    #[cfg(target_os = "macos")]
+    {
+        *self::libc::__error() = self::libc::ENOMEM;
+    }
+}
+
 unsafe impl<'a> Alloc for &'a ElfMallocGlobal {
     unsafe fn alloc(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
-        // All objects are only guaranteed to be word-aligned except for powers of two. Powers of
-        // two up to 1MiB are aligned to their size. Past that size, only page-alignment is
-        // guaranteed.
-        if l.size().is_power_of_two() || l.align() <= mem::size_of::<usize>() {
-            Ok(global::alloc(l.size()))
-        } else {
-            Ok(global::alloc(l.size().next_power_of_two()))
-        }
+        (**self).try_alloc(l).map_err(AllocErr::from)
+    }
+
+    /// With the `zero-on-free` feature, objects are zeroed when they are freed, so this is the
+    /// same as `alloc` (see the `zero` module). `calloc` goes through here.
+    #[cfg(feature = "zero-on-free")]
+    unsafe fn alloc_zeroed(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        self.alloc(l)
     }
 
     unsafe fn dealloc(&mut self, p: *mut u8, _l: Layout) {
@@ -50,7 +157,69 @@ unsafe impl<'a> Alloc for &'a ElfMallocGlobal {
     }
 
     unsafe fn realloc(&mut self, p: *mut u8, _l1: Layout, l2: Layout) -> Result<*mut u8, AllocErr> {
-        Ok(global::aligned_realloc(p, l2.size(), l2.align()))
+        (**self).try_realloc(p, l2).map_err(AllocErr::from)
+    }
+
+    /// Describe the failed allocation and the state of the heap on standard error, and abort.
+    ///
+    /// When `ElfMallocGlobal` is the global allocator, this is what runs when a collection fails
+    /// to allocate, instead of the standard library's generic message.
+    fn oom(&mut self, err: AllocErr) -> ! {
+        report_oom(&err);
+        ::std::process::abort()
+    }
+}
+
+/// Print what is known about an allocation failure to standard error, without allocating.
+#[cold]
+fn report_oom(err: &AllocErr) {
+    match *err {
+        AllocErr::Exhausted { ref request } => {
+            let size = alloc_size(request);
+            match global::size_class(size) {
+                Some(class) => alloc_eprintln!(
+                    "elfmalloc: out of memory allocating {} bytes (alignment {}) from the {}-byte \
+                     size class",
+                    request.size(),
+                    request.align(),
+                    class
+                ),
+                None => alloc_eprintln!(
+                    "elfmalloc: out of memory allocating a large object of {} bytes (alignment {})",
+                    request.size(),
+                    request.align()
+                ),
+            }
+        }
+        AllocErr::Unsupported { details } => {
+            alloc_eprintln!("elfmalloc: unsupported allocation request: {}", details)
+        }
+    }
+    #[cfg(feature = "quota")]
+    {
+        match quota::global_limit() {
+            Some(limit) => alloc_eprintln!(
+                "elfmalloc: {} bytes allocated of a {}-byte limit",
+                quota::global_allocated(),
+                limit
+            ),
+            None => alloc_eprintln!("elfmalloc: {} bytes allocated", quota::global_allocated()),
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(peak) = quota::peak_resident() {
+                alloc_eprintln!("elfmalloc: peak resident set size {} bytes", peak);
+            }
+        }
+    }
+    #[cfg(feature = "slow-path-stats")]
+    {
+        let stats = slow_path_stats();
+        alloc_eprintln!(
+            "elfmalloc: {} bytes mapped, {} bytes unmapped",
+            stats.mapped_bytes,
+            stats.unmapped_bytes
+        );
     }
 }
 
@@ -58,6 +227,10 @@ unsafe impl<'a> Alloc for &'a ElfMallocGlobal {
 unsafe impl Malloc for ElfMallocGlobal {
     unsafe fn c_malloc(&self, size: size_t) -> *mut c_void {
         let p = global::alloc(size as usize) as *mut c_void;
+        if unlikely(p.is_null()) {
+            set_enomem();
+            return p;
+        }
         alloc_debug_assert_eq!((p as usize) % MIN_ALIGN,
                          0,
                          "object does not have the required alignment of {}: {:?}",
@@ -74,6 +247,12 @@ unsafe impl Malloc for ElfMallocGlobal {
         if unlikely(p.is_null()) {
             return;
         }
+        #[cfg(feature = "ownership")]
+        {
+            if unlikely(!ownership::owns(p as *mut u8)) {
+                return ownership::foreign_free(p as *mut u8);
+            }
+        }
         global::free(p as *mut u8)
     }
     unsafe fn c_realloc(&self, p: *mut c_void, new_size: size_t) -> *mut c_void {
@@ -82,7 +261,17 @@ unsafe impl Malloc for ElfMallocGlobal {
                          "object does not have the required alignment of {}: {:?}",
                          MIN_ALIGN,
                          p);
-        global::realloc(p as *mut u8, new_size as usize) as *mut c_void
+        #[cfg(feature = "ownership")]
+        {
+            if unlikely(!p.is_null() && !ownership::owns(p as *mut u8)) {
+                ownership::foreign_pointer("realloc", p as *mut u8);
+            }
+        }
+        let res = global::realloc(p as *mut u8, new_size as usize) as *mut c_void;
+        if unlikely(res.is_null() && new_size != 0) {
+            set_enomem();
+        }
+        res
     }
 }
 
@@ -93,6 +282,12 @@ unsafe impl LayoutFinder for ElfMallocGlobal {
         // value, e.g.  Layout::from_size_align(8, 8).unwrap()
         // But, seeing as that (and `Alloc`'s default impl's internals) may change, we are going to
         // err on the side of caution for the time being
+        #[cfg(feature = "ownership")]
+        {
+            if unlikely(!ownership::owns(p)) {
+                ownership::foreign_pointer("size lookup", p);
+            }
+        }
         let (size, align) = global::get_layout(p);
         Layout::from_size_align(size, align).unwrap()
     }
@@ -0,0 +1,79 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! AddressSanitizer annotations.
+//!
+//! AddressSanitizer only knows about the memory handed out by the system allocator; to it, all of
+//! the memory in a `Slag` looks like one big valid object. With the `asan` feature enabled, we
+//! tell it which bytes are actually in use through its manual poisoning interface:
+//!
+//! - Freed small and medium objects are poisoned until they are allocated again, so that
+//!   use-after-free and double-free bugs are reported.
+//! - The bytes between the requested size of an object and the size of its size class are
+//!   poisoned, and serve as a redzone that catches (small) buffer overflows.
+//! - Pages are unpoisoned when they are returned to a page cache, as they may be reused for a
+//!   different size class (or to store metadata) later.
+//!
+//! Large objects are not annotated: they are unmapped when freed, so accesses to them fault
+//! anyway.
+//!
+//! The interface is provided by the ASan runtime, so the feature requires building with
+//! `RUSTFLAGS="-Z sanitizer=address"`.
+
+extern "C" {
+    fn __asan_poison_memory_region(addr: *const u8, size: usize);
+    fn __asan_unpoison_memory_region(addr: *const u8, size: usize);
+    #[cfg(test)]
+    fn __asan_address_is_poisoned(addr: *const u8) -> i32;
+}
+
+/// Mark `[p, p + len)` as inaccessible.
+#[inline]
+pub unsafe fn poison(p: *mut u8, len: usize) {
+    __asan_poison_memory_region(p, len);
+}
+
+/// Mark `[p, p + len)` as accessible.
+#[inline]
+pub unsafe fn unpoison(p: *mut u8, len: usize) {
+    __asan_unpoison_memory_region(p, len);
+}
+
+/// Annotate a freshly allocated object of `size` bytes from a size class of `object_size` bytes.
+#[inline]
+pub unsafe fn on_alloc(p: *mut u8, size: usize, object_size: usize) {
+    unpoison(p, size);
+    if object_size > size {
+        poison(p.offset(size as isize), object_size - size);
+    }
+}
+
+/// Annotate an object of `object_size` bytes that is about to be freed.
+#[inline]
+pub unsafe fn on_free(p: *mut u8, object_size: usize) {
+    poison(p, object_size);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::general::DynamicAllocator;
+
+    #[test]
+    fn poisoned_after_free() {
+        let mut alloc = DynamicAllocator::new();
+        unsafe {
+            let p = alloc.alloc(20);
+            alloc_assert_eq!(__asan_address_is_poisoned(p), 0);
+            alloc_assert_eq!(__asan_address_is_poisoned(p.offset(19)), 0);
+            // The rest of the 24-byte size class is a redzone.
+            alloc_assert!(__asan_address_is_poisoned(p.offset(20)) != 0);
+            alloc.free(p);
+            alloc_assert!(__asan_address_is_poisoned(p) != 0);
+        }
+    }
+}
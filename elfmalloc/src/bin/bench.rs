@@ -11,18 +11,19 @@ extern crate alloc;
 extern crate elfmalloc;
 extern crate num_cpus;
 use std::marker;
-use alloc::heap;
 use std::mem;
 use std::thread;
 use std::time;
 use std::ptr::write_volatile;
 
 use elfmalloc::slag::{AllocBuilder, LocalAllocator, MagazineAllocator};
-use elfmalloc::general::global;
 use elfmalloc::general::DynamicAllocator;
 use std::sync::{Arc, Barrier};
 use std::sync::atomic::{AtomicPtr, Ordering};
 
+mod common;
+use common::{Allocator, Table};
+
 type BenchItem = [usize; 2];
 
 const PAGE_SIZE: usize = 32 << 10;
@@ -60,29 +61,28 @@ impl<T: 'static> AllocLike for MagazineAllocator<T> {
     fn kill(&mut self) {}
 }
 
-struct ElfGlobal<T>(marker::PhantomData<T>);
-impl<T> Clone for ElfGlobal<T> {
+/// One of the allocators in `common::Allocator`, allocating objects of type `T`.
+struct Backend<T>(Allocator, marker::PhantomData<T>);
+impl<T> Clone for Backend<T> {
     fn clone(&self) -> Self {
-        ElfGlobal(marker::PhantomData)
+        Backend(self.0, marker::PhantomData)
     }
 }
-unsafe impl<T> Send for ElfGlobal<T> {}
+unsafe impl<T> Send for Backend<T> {}
 
-impl<T: 'static> AllocLike for ElfGlobal<T> {
+impl<T: 'static> AllocLike for Backend<T> {
     type Item = T;
     fn create() -> Self {
-        ElfGlobal(marker::PhantomData)
+        Backend(Allocator::Elf, marker::PhantomData)
     }
 
     unsafe fn allocate(&mut self) -> *mut T {
-        global::alloc(mem::size_of::<T>()) as *mut T
+        self.0.allocate(mem::size_of::<T>()) as *mut T
     }
 
     unsafe fn deallocate(&mut self, item: *mut T) {
-        global::free(item as *mut u8)
+        self.0.deallocate(item as *mut u8, mem::size_of::<T>())
     }
-
-    fn kill(&mut self) {}
 }
 
 struct ElfClone<T>(DynamicAllocator, marker::PhantomData<T>);
@@ -130,38 +130,6 @@ impl<T: 'static> AllocLike for LocalAllocator<T> {
     fn kill(&mut self) {}
 }
 
-struct DefaultMalloc<T>(marker::PhantomData<T>);
-
-unsafe impl<T> Send for DefaultMalloc<T> {}
-
-impl<T> Clone for DefaultMalloc<T> {
-    fn clone(&self) -> Self {
-        DefaultMalloc(marker::PhantomData)
-    }
-}
-
-impl<T> AllocLike for DefaultMalloc<T> {
-    type Item = T;
-    fn create() -> Self {
-        DefaultMalloc(marker::PhantomData)
-    }
-    unsafe fn allocate(&mut self) -> *mut T {
-        use heap::{Alloc, Layout};
-        heap::Heap
-            .alloc(Layout::from_size_align(mem::size_of::<T>(), 8).unwrap())
-            .unwrap() as *mut T
-    }
-
-    unsafe fn deallocate(&mut self, item: *mut T) {
-        use heap::{Alloc, Layout};
-        heap::Heap.dealloc(
-            item as *mut u8,
-            Layout::from_size_align(mem::size_of::<T>(), 8).unwrap(),
-        );
-    }
-}
-
-
 macro_rules! time_block {
     ($block:expr) => {
         {
@@ -187,10 +155,10 @@ macro_rules! time_block_once {
 }
 
 fn bench_alloc_free_pairs<A: AllocLike<Item = BenchItem> + 'static>(
+    mut a: A,
     nthreads: usize,
     per_thread: usize,
-) {
-    let mut a = A::create();
+) -> f64 {
     let b = Arc::new(Barrier::new(nthreads + 1));
     let mut threads = Vec::new();
     for _ in 0..nthreads {
@@ -214,20 +182,17 @@ fn bench_alloc_free_pairs<A: AllocLike<Item = BenchItem> + 'static>(
         total += i.join().unwrap();
     }
 
+    a.kill();
     // why nthreads * nthreads? Total is actually n_threads * mean time, so we need an extra
     // nthreads factor to not over-count the time it takes to perform the workload.
-    println!(
-        "{} Mops/s",
-        ((nthreads * nthreads * per_thread * 2 * 1_000) as f64) / (total as f64)
-    );
-    a.kill();
+    ((nthreads * nthreads * per_thread * 2 * 1_000) as f64) / (total as f64)
 }
 
 fn bench_alloc_free_pairs_buffered<A: AllocLike<Item = BenchItem> + 'static>(
+    mut a: A,
     nthreads: usize,
     per_thread: usize,
-) {
-    let mut a = A::create();
+) -> f64 {
     let b = Arc::new(Barrier::new(nthreads + 1));
     let mut threads = Vec::new();
     for _ in 0..nthreads {
@@ -258,17 +223,17 @@ fn bench_alloc_free_pairs_buffered<A: AllocLike<Item = BenchItem> + 'static>(
         total += i.join().unwrap();
     }
 
+    a.kill();
     // why nthreads * nthreads? Total is actually n_threads * mean time, so we need an extra
     // nthreads factor to not over-count the time it takes to perform the workload.
-    println!(
-        "{} Mops/s",
-        ((nthreads * nthreads * per_thread * 2 * 1_000) as f64) / (total as f64)
-    );
-    a.kill();
+    ((nthreads * nthreads * per_thread * 2 * 1_000) as f64) / (total as f64)
 }
 
-fn bench_prod_cons<A: AllocLike<Item = BenchItem> + 'static>(nthreads: usize, per_thread: usize) {
-    let mut a = A::create();
+fn bench_prod_cons<A: AllocLike<Item = BenchItem> + 'static>(
+    mut a: A,
+    nthreads: usize,
+    per_thread: usize,
+) -> f64 {
     let b = Arc::new(Barrier::new(nthreads + 1));
     let mut v_base = Vec::new();
     for _ in 0..nthreads {
@@ -308,15 +273,15 @@ fn bench_prod_cons<A: AllocLike<Item = BenchItem> + 'static>(nthreads: usize, pe
     for i in threads {
         total += i.join().unwrap();
     }
-    println!(
-        "{} Mops/s",
-        ((nthreads * nthreads * per_thread * 1_000) as f64) / (total as f64)
-    );
     a.kill();
+    ((nthreads * nthreads * per_thread * 1_000) as f64) / (total as f64)
 }
 
-fn bench_alloc_free<A: AllocLike<Item = BenchItem> + 'static>(nthreads: usize, per_thread: usize) {
-    let mut a = A::create();
+fn bench_alloc_free<A: AllocLike<Item = BenchItem> + 'static>(
+    mut a: A,
+    nthreads: usize,
+    per_thread: usize,
+) -> f64 {
     let b = Arc::new(Barrier::new(nthreads + 1));
     let mut threads = Vec::new();
     for _ in 0..nthreads {
@@ -343,15 +308,15 @@ fn bench_alloc_free<A: AllocLike<Item = BenchItem> + 'static>(nthreads: usize, p
     for i in threads {
         total += i.join().unwrap();
     }
-    println!(
-        "{} Mops/s",
-        ((nthreads * nthreads * per_thread * 2 * 1_000) as f64) / (total as f64)
-    );
-    a.kill()
+    a.kill();
+    ((nthreads * nthreads * per_thread * 2 * 1_000) as f64) / (total as f64)
 }
 
-fn bench_alloc<A: AllocLike<Item = BenchItem> + 'static>(nthreads: usize, per_thread: usize) {
-    let mut a = A::create();
+fn bench_alloc<A: AllocLike<Item = BenchItem> + 'static>(
+    mut a: A,
+    nthreads: usize,
+    per_thread: usize,
+) -> f64 {
     let b = Arc::new(Barrier::new(nthreads + 1));
     let mut threads = Vec::new();
     for _ in 0..nthreads {
@@ -379,15 +344,15 @@ fn bench_alloc<A: AllocLike<Item = BenchItem> + 'static>(nthreads: usize, per_th
     for i in threads {
         total += i.join().unwrap();
     }
-    println!(
-        "{} Mops/s",
-        ((nthreads * nthreads * per_thread * 1_000) as f64) / (total as f64)
-    );
-    a.kill()
+    a.kill();
+    ((nthreads * nthreads * per_thread * 1_000) as f64) / (total as f64)
 }
 
-fn bench_free<A: AllocLike<Item = BenchItem> + 'static>(nthreads: usize, per_thread: usize) {
-    let mut a = A::create();
+fn bench_free<A: AllocLike<Item = BenchItem> + 'static>(
+    mut a: A,
+    nthreads: usize,
+    per_thread: usize,
+) -> f64 {
     let b = Arc::new(Barrier::new(nthreads + 1));
     let mut threads = Vec::new();
     for _ in 0..nthreads {
@@ -416,41 +381,53 @@ fn bench_free<A: AllocLike<Item = BenchItem> + 'static>(nthreads: usize, per_thr
     for i in threads {
         total += i.join().unwrap();
     }
-    println!(
-        "{} Mops/s",
-        ((nthreads * nthreads * per_thread * 1_000) as f64) / (total as f64)
-    );
-    a.kill()
+    a.kill();
+    ((nthreads * nthreads * per_thread * 1_000) as f64) / (total as f64)
+}
+
+/// The columns of the result tables: the allocators in `Allocator::all()`, followed by
+/// elfmalloc's other frontends.
+fn columns() -> Vec<String> {
+    let mut columns: Vec<String> = Allocator::all()
+        .iter()
+        .map(|a| a.name().to_string())
+        .collect();
+    columns.push("elf clone".to_string());
+    columns.push("elf local".to_string());
+    columns.push("elf magazine".to_string());
+    columns
 }
 
+/// Run a benchmark against each column, returning throughput in Mops/s.
 macro_rules! run_bench_inner {
     ($bench:tt, $nthreads:expr, $iters:expr) => {
-        let iters = $iters;
-        let nthreads = $nthreads;
-        println!("global malloc");
-        $bench::<DefaultMalloc<BenchItem>>(nthreads, iters);
-        println!("global slag allocator");
-        $bench::<ElfGlobal<BenchItem>>(nthreads, iters);
-        println!("clone-based slag allocator");
-        $bench::<ElfClone<BenchItem>>(nthreads, iters);
-        println!("slag allocator");
-        $bench::<LocalAllocator<BenchItem>>(nthreads, iters);
-        println!("slagazine allocator");
-        $bench::<MagazineAllocator<BenchItem>>(nthreads, iters);
+        {
+            let iters = $iters;
+            let nthreads = $nthreads;
+            let mut res = Vec::new();
+            for alloc in Allocator::all() {
+                res.push($bench(Backend::<BenchItem>(alloc, marker::PhantomData), nthreads, iters));
+            }
+            res.push($bench(ElfClone::<BenchItem>::create(), nthreads, iters));
+            res.push($bench(LocalAllocator::<BenchItem>::create(), nthreads, iters));
+            res.push($bench(MagazineAllocator::<BenchItem>::create(), nthreads, iters));
+            res.iter().map(|mops| format!("{:.2}", mops)).collect()
+        }
     };
 }
 
 macro_rules! run_bench {
     (both $desc:expr, $bench:tt, $nthreads:expr, $iters:expr) => {
-        println!("\n{} - {}", $desc, "single-threaded");
-        run_bench_inner!($bench, 1, $iters);
-        println!("\n{} - {} threads", $desc, $nthreads);
-        run_bench_inner!($bench, $nthreads, $iters);
+        let mut table = Table::with_columns(format!("{} (Mops/s)", $desc), columns());
+        table.row("single-threaded", run_bench_inner!($bench, 1, $iters));
+        table.row(format!("{} threads", $nthreads), run_bench_inner!($bench, $nthreads, $iters));
+        table.print();
     };
 
     (threads $desc:expr, $bench:tt, $nthreads:expr, $iters:expr) => {
-        println!("\n{} - {} threads", $desc, $nthreads);
-        run_bench_inner!($bench, $nthreads, $iters);
+        let mut table = Table::with_columns(format!("{} (Mops/s)", $desc), columns());
+        table.row(format!("{} threads", $nthreads), run_bench_inner!($bench, $nthreads, $iters));
+        table.print();
     };
 }
 
@@ -461,6 +438,8 @@ fn main() {
         "allocating {} bytes per thread",
         ITERS * mem::size_of::<BenchItem>()
     );
+    // Compare runs with ELFMALLOC_CONF=randomize:true to measure the cost of randomized placement.
+    println!("randomized placement: {}", elfmalloc::conf::randomize());
 
     run_bench!(both "alloc/free pairs", bench_alloc_free_pairs, nthreads, ITERS);
     run_bench!(both "buffered alloc/free pairs", bench_alloc_free_pairs_buffered, nthreads, ITERS);
@@ -0,0 +1,121 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A benchmark of the shared backend under contention, with more threads than cores.
+//!
+//! Usage: `bench_contention [max threads]`. By default, up to 256 threads are used, and at least
+//! 64 are always used, whatever the number of CPUs.
+//!
+//! Each thread allocates small objects and swaps them into a shared array of slots, freeing
+//! whatever it takes out, which was usually allocated by another thread. These remote frees, and
+//! the `Slag`s and pages they return to the shared backend, make every thread contend on the
+//! backend's `BagPipe`s. The table shows the throughput with retries that only spin, as the
+//! backend's retries did before they backed off, and with the default backoff (see the `backoff`
+//! module of the `bagpipe` crate), which yields and then parks. With `--features
+//! contention-stats`, elfmalloc's contention counters are printed as well.
+
+#![feature(alloc)]
+#![feature(allocator_api)]
+extern crate alloc;
+extern crate bagpipe;
+extern crate elfmalloc;
+extern crate num_cpus;
+
+use bagpipe::backoff;
+use elfmalloc::general::global;
+use std::cmp;
+use std::env;
+use std::ptr;
+use std::sync::{Arc, Barrier};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::thread;
+use std::time;
+
+mod common;
+use common::Table;
+
+/// The number of objects each thread allocates.
+const OBJECTS: usize = 1 << 18;
+/// The number of shared slots objects are swapped through.
+const SLOTS: usize = 1 << 12;
+const SIZES: &[usize] = &[16, 64, 256, 1024];
+
+/// Run the workload on `threads` threads, and return the throughput in millions of objects per
+/// second.
+fn run(threads: usize) -> f64 {
+    let slots: Arc<Vec<AtomicPtr<u8>>> =
+        Arc::new((0..SLOTS).map(|_| AtomicPtr::new(ptr::null_mut())).collect());
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let slots = slots.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                // A cheap per-thread sequence of slots, so that threads do not move in lockstep.
+                let mut ix = t.wrapping_mul(0x9e37_79b9);
+                for i in 0..OBJECTS {
+                    let p = unsafe { global::alloc(SIZES[i % SIZES.len()]) };
+                    ix = ix.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    let old = slots[(ix >> 8) % SLOTS].swap(p, Ordering::AcqRel);
+                    if !old.is_null() {
+                        unsafe { global::free(old) };
+                    }
+                }
+                barrier.wait();
+            })
+        })
+        .collect();
+    barrier.wait();
+    let start = time::Instant::now();
+    barrier.wait();
+    let dur = start.elapsed();
+    for h in handles {
+        h.join().unwrap();
+    }
+    for slot in slots.iter() {
+        let p = slot.swap(ptr::null_mut(), Ordering::Relaxed);
+        if !p.is_null() {
+            unsafe { global::free(p) };
+        }
+    }
+    let secs = dur.as_secs() as f64 + f64::from(dur.subsec_nanos()) / 1e9;
+    (threads * OBJECTS) as f64 / secs / 1e6
+}
+
+fn main() {
+    let max_threads = env::args()
+        .nth(1)
+        .map(|s| s.parse().expect("max threads must be a number"))
+        .unwrap_or(256);
+    let mut counts = vec![num_cpus::get(), 64, 128, 256, max_threads];
+    counts.retain(|&n| n <= cmp::max(max_threads, 64));
+    counts.sort();
+    counts.dedup();
+
+    let mut table = Table::with_columns(
+        format!("Mobjects/s, {} CPUs", num_cpus::get()),
+        vec!["spin".to_string(), "backoff".to_string()],
+    );
+    for threads in counts {
+        backoff::set_spin_limit(::std::usize::MAX);
+        let spin = run(threads);
+        backoff::set_spin_limit(backoff::DEFAULT_SPIN_LIMIT);
+        let parks = backoff::parks();
+        let backed_off = run(threads);
+        table.row(
+            format!("{} threads", threads),
+            vec![
+                format!("{:.2}", spin),
+                format!("{:.2} ({} parks)", backed_off, backoff::parks() - parks),
+            ],
+        );
+    }
+    table.print();
+    #[cfg(feature = "contention-stats")]
+    println!("{:?}", elfmalloc::contention_stats());
+}
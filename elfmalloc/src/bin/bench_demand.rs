@@ -0,0 +1,104 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A benchmark comparing eager commit with commit on demand (see the `demand` module).
+//!
+//! Usage: `cargo run --release --bin bench_demand --features demand-commit` (Linux only).
+//!
+//! For each density, it commits `REGION` bytes of a `Region` in `CHUNK`-byte chunks and writes to
+//! one page out of every `density`, once with eager commit and once in demand mode. It reports the
+//! best time out of several rounds per page written, and how much the system-wide commit charge
+//! (`Committed_AS` in `/proc/meminfo`) grew while the memory was committed. The commit charge is
+//! shared with every other process, so run this on an otherwise idle machine.
+
+#![feature(alloc)]
+#![feature(allocator_api)]
+extern crate alloc;
+extern crate elfmalloc;
+
+use elfmalloc::conf;
+use elfmalloc::demand;
+use std::fs::File;
+use std::io::Read;
+use std::time;
+
+mod common;
+use common::Table;
+
+const REGION: usize = 1 << 30;
+const CHUNK: usize = 1 << 20;
+const PAGE: usize = 4096;
+const DENSITIES: &[usize] = &[1, 4, 16, 64, 256];
+const ROUNDS: usize = 5;
+
+/// The system-wide commit charge, in KiB.
+fn committed_kib() -> usize {
+    let mut meminfo = String::new();
+    File::open("/proc/meminfo")
+        .and_then(|mut f| f.read_to_string(&mut meminfo))
+        .expect("could not read /proc/meminfo");
+    meminfo
+        .lines()
+        .find(|l| l.starts_with("Committed_AS:"))
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|kib| kib.parse().ok())
+        .expect("no Committed_AS in /proc/meminfo")
+}
+
+/// The best time per page written in nanoseconds, and the growth of the commit charge in MiB.
+fn run(density: usize, on_demand: bool) -> (f64, f64) {
+    conf::set_demand_commit(on_demand);
+    let pages = REGION / PAGE / density;
+    let mut best = ::std::f64::MAX;
+    let mut charge = 0;
+    for _ in 0..ROUNDS {
+        let region = elfmalloc::reserve(REGION).expect("could not reserve address space");
+        let before = committed_kib();
+        let start = time::Instant::now();
+        for _ in 0..REGION / CHUNK {
+            let chunk = region.commit(CHUNK).expect("could not commit");
+            let mut offset = 0;
+            while offset < CHUNK {
+                unsafe { *chunk.offset(offset as isize) = 1 };
+                offset += density * PAGE;
+            }
+        }
+        let dur = start.elapsed();
+        charge = committed_kib().saturating_sub(before);
+        let nanos = dur.as_secs() * 1_000_000_000 + u64::from(dur.subsec_nanos());
+        best = best.min(nanos as f64 / pages as f64);
+    }
+    (best, charge as f64 / 1024.0)
+}
+
+fn main() {
+    let mut table = Table::with_columns(
+        format!("{}MiB committed in {}KiB chunks", REGION >> 20, CHUNK >> 10),
+        vec![
+            "eager (ns/page)".to_string(),
+            "demand (ns/page)".to_string(),
+            "eager (MiB)".to_string(),
+            "demand (MiB)".to_string(),
+        ],
+    );
+    let faults = demand::faults();
+    for &density in DENSITIES {
+        let (eager_time, eager_charge) = run(density, false);
+        let (demand_time, demand_charge) = run(density, true);
+        table.row(
+            format!("1 page in {}", density),
+            vec![
+                format!("{:.1}", eager_time),
+                format!("{:.1}", demand_time),
+                format!("{:.1}", eager_charge),
+                format!("{:.1}", demand_charge),
+            ],
+        );
+    }
+    table.print();
+    println!("{} faults handled", demand::faults() - faults);
+}
@@ -0,0 +1,153 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A benchmark of false sharing: threads that write to different words of the same cache line
+//! slow each other down as much as if they were writing to the same word.
+//!
+//! Usage: `bench_false_sharing [max threads]`. By default, up to one thread per CPU is used.
+//!
+//! The first table shows the effect in isolation: each thread increments a counter of its own,
+//! with the counters either packed next to each other or padded to a cache line each, as
+//! elfmalloc's shared counters are. The second table measures elfmalloc's fast path under the
+//! same thread counts: each thread allocates and frees small objects independently, so throughput
+//! should scale with the number of threads. Building with features that maintain process-wide
+//! state on the allocation path (`quota`, `contention-stats`, `slow-path-stats`) shows whether
+//! that state is laid out so that threads do not contend on it.
+
+#![feature(alloc)]
+#![feature(allocator_api)]
+#![feature(attr_literals)]
+#![feature(repr_align)]
+extern crate alloc;
+extern crate elfmalloc;
+extern crate num_cpus;
+
+use std::env;
+use std::ptr::write_volatile;
+use std::sync::{Arc, Barrier};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time;
+
+mod common;
+use common::{Allocator, Table};
+
+/// The number of increments each thread makes to its counter.
+const INCREMENTS: usize = 1 << 24;
+/// The number of objects each thread allocates and frees.
+const OBJECTS: usize = 1 << 22;
+/// The number of objects a thread holds at once.
+const BATCH: usize = 64;
+const OBJECT_SIZE: usize = 32;
+
+/// A counter padded to a cache line, like `utils::CachePadded`, which is private to elfmalloc.
+#[repr(align(64))]
+#[derive(Default)]
+struct Padded(AtomicUsize);
+
+/// Run `f(i)` on threads `0..threads` at once, and return the elapsed time in seconds.
+fn run_threads<F: Fn(usize) + Send + Sync + 'static>(threads: usize, f: F) -> f64 {
+    let f = Arc::new(f);
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let handles: Vec<_> = (0..threads)
+        .map(|i| {
+            let f = f.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                f(i);
+                barrier.wait();
+            })
+        })
+        .collect();
+    barrier.wait();
+    let start = time::Instant::now();
+    barrier.wait();
+    let dur = start.elapsed();
+    for h in handles {
+        h.join().unwrap();
+    }
+    dur.as_secs() as f64 + f64::from(dur.subsec_nanos()) / 1e9
+}
+
+/// Throughput of `threads` threads incrementing packed counters, in millions per second.
+fn packed(threads: usize) -> f64 {
+    let ctrs: Arc<Vec<AtomicUsize>> = Arc::new((0..threads).map(|_| AtomicUsize::new(0)).collect());
+    let secs = run_threads(threads, move |i| for _ in 0..INCREMENTS {
+        ctrs[i].fetch_add(1, Ordering::Relaxed);
+    });
+    (threads * INCREMENTS) as f64 / secs / 1e6
+}
+
+/// Throughput of `threads` threads incrementing padded counters, in millions per second.
+fn padded(threads: usize) -> f64 {
+    let ctrs: Arc<Vec<Padded>> = Arc::new((0..threads).map(|_| Padded::default()).collect());
+    let secs = run_threads(threads, move |i| for _ in 0..INCREMENTS {
+        ctrs[i].0.fetch_add(1, Ordering::Relaxed);
+    });
+    (threads * INCREMENTS) as f64 / secs / 1e6
+}
+
+/// Throughput of `threads` threads allocating and freeing objects with `alloc`, in millions of
+/// objects per second.
+fn alloc_free(alloc: Allocator, threads: usize) -> f64 {
+    let secs = run_threads(threads, move |_| {
+        let mut batch = [0 as *mut u8; BATCH];
+        for _ in 0..OBJECTS / BATCH {
+            unsafe {
+                for p in batch.iter_mut() {
+                    *p = alloc.allocate(OBJECT_SIZE);
+                    write_volatile(*p, 1);
+                }
+                for &p in batch.iter() {
+                    alloc.deallocate(p, OBJECT_SIZE);
+                }
+            }
+        }
+    });
+    (threads * OBJECTS) as f64 / secs / 1e6
+}
+
+fn main() {
+    let max_threads = env::args()
+        .nth(1)
+        .map(|a| a.parse().expect("the thread count must be a number"))
+        .unwrap_or_else(num_cpus::get);
+    let mut thread_counts = Vec::new();
+    let mut t = 1;
+    while t < max_threads {
+        thread_counts.push(t);
+        t *= 2;
+    }
+    thread_counts.push(max_threads);
+
+    let mut counters = Table::with_columns(
+        "counter increments (M/s)",
+        vec!["packed".to_string(), "padded".to_string()],
+    );
+    let mut allocs = Table::new(format!(
+        "alloc/free throughput ({}B objects, Mobj/s)",
+        OBJECT_SIZE
+    ));
+    for &threads in &thread_counts {
+        let label = format!("{} threads", threads);
+        counters.row(
+            label.clone(),
+            vec![
+                format!("{:.1}", packed(threads)),
+                format!("{:.1}", padded(threads)),
+            ],
+        );
+        let cells = Allocator::all()
+            .into_iter()
+            .map(|a| format!("{:.1}", alloc_free(a, threads)))
+            .collect();
+        allocs.row(label, cells);
+    }
+    counters.print();
+    allocs.print();
+}
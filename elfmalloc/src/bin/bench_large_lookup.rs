@@ -0,0 +1,145 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A benchmark of metadata lookups of small objects while other threads allocate and free large
+//! objects.
+//!
+//! Usage: `bench_large_lookup [lookup threads]`. By default, half of the CPUs run lookups.
+//!
+//! The lookup threads call `global::lookup` on pointers into small objects allocated up front,
+//! which reads the `Slag` header (and with the `ownership` feature, the ownership table) that
+//! `free` and `realloc` read. Alongside them, 0, 1, 2, ... large threads allocate, touch and free
+//! objects of `LARGE` bytes through the global allocator as fast as they can, up to the remaining
+//! CPUs. The table shows the throughput of both.
+//!
+//! The large path keeps an object's metadata in the header page of its own mapping and only
+//! writes lock-free structures otherwise (see the `large_alloc` module in `general` and the
+//! `ownership` module), so the criterion is that lookup throughput with large threads running
+//! stays within noise of the row with none. What large threads do share with the rest of the
+//! process is the kernel's lock on the address space, taken by `mmap` and `munmap`, and the TLB
+//! shootdowns `munmap` sends to the other cores; a drop in lookup throughput that grows with the
+//! number of large threads points there rather than at elfmalloc. Building with `large-cache`
+//! takes most of these system calls off the large path.
+
+#![feature(alloc)]
+#![feature(allocator_api)]
+extern crate alloc;
+extern crate elfmalloc;
+extern crate num_cpus;
+
+use elfmalloc::general::global;
+use std::cmp;
+use std::env;
+use std::ptr;
+use std::sync::{Arc, Barrier};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time;
+
+mod common;
+use common::Table;
+
+/// The number of lookups each lookup thread makes.
+const LOOKUPS: usize = 1 << 24;
+/// The number of small objects each lookup thread looks up, round-robin.
+const OBJECTS: usize = 1 << 12;
+const SMALL: usize = 64;
+const LARGE: usize = 1 << 20;
+
+/// Run `lookups` lookup threads alongside `large` large threads until the lookup threads are done,
+/// and return the throughput of each in millions of operations per second.
+fn run(lookups: usize, large: usize) -> (f64, f64) {
+    let start = Arc::new(Barrier::new(lookups + large + 1));
+    let lookups_done = Arc::new(Barrier::new(lookups + 1));
+    let done = Arc::new(AtomicBool::new(false));
+    let lookup_threads: Vec<_> = (0..lookups)
+        .map(|_| {
+            let start = start.clone();
+            let lookups_done = lookups_done.clone();
+            thread::spawn(move || {
+                let objects: Vec<usize> = (0..OBJECTS)
+                    .map(|_| unsafe {
+                        let p = global::alloc(SMALL);
+                        ptr::write_bytes(p, 1, SMALL);
+                        p as usize
+                    })
+                    .collect();
+                start.wait();
+                let mut found = 0;
+                for i in 0..LOOKUPS {
+                    // An interior pointer, so that the object's start has to be computed.
+                    let p = (objects[i % OBJECTS] + SMALL / 2) as *mut u8;
+                    found += unsafe { global::lookup(p) }.is_some() as usize;
+                }
+                lookups_done.wait();
+                assert_eq!(found, LOOKUPS);
+                for p in objects {
+                    unsafe { global::free(p as *mut u8) };
+                }
+            })
+        })
+        .collect();
+    let large_threads: Vec<_> = (0..large)
+        .map(|_| {
+            let start = start.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut ops = 0;
+                start.wait();
+                while !done.load(Ordering::Relaxed) {
+                    unsafe {
+                        let p = global::alloc(LARGE);
+                        // Touch the first and last pages, as a caller filling the object would.
+                        *p = 1;
+                        *p.offset(LARGE as isize - 1) = 1;
+                        global::free(p);
+                    }
+                    ops += 1;
+                }
+                ops
+            })
+        })
+        .collect();
+    start.wait();
+    let begin = time::Instant::now();
+    lookups_done.wait();
+    let dur = begin.elapsed();
+    done.store(true, Ordering::Relaxed);
+    for h in lookup_threads {
+        h.join().unwrap();
+    }
+    let large_ops: usize = large_threads.into_iter().map(|h| h.join().unwrap()).sum();
+    let secs = dur.as_secs() as f64 + f64::from(dur.subsec_nanos()) / 1e9;
+    (
+        (lookups * LOOKUPS) as f64 / secs / 1e6,
+        large_ops as f64 / secs / 1e6,
+    )
+}
+
+fn main() {
+    let cpus = num_cpus::get();
+    let lookups = env::args()
+        .nth(1)
+        .map(|s| s.parse().expect("lookup threads must be a number"))
+        .unwrap_or_else(|| cmp::max(cpus / 2, 1));
+    let max_large = cmp::max(cpus.saturating_sub(lookups), 1);
+
+    let mut table = Table::with_columns(
+        format!("Mops/s, {} lookup threads, {} CPUs", lookups, cpus),
+        vec!["lookups".to_string(), "large alloc/free".to_string()],
+    );
+    let mut large = 0;
+    while large <= max_large {
+        let (lookup_rate, large_rate) = run(lookups, large);
+        table.row(
+            format!("{} large threads", large),
+            vec![format!("{:.2}", lookup_rate), format!("{:.3}", large_rate)],
+        );
+        large = if large == 0 { 1 } else { large * 2 };
+    }
+    table.print();
+}
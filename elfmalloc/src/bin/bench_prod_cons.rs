@@ -0,0 +1,227 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A producer-consumer benchmark: producer threads allocate objects and hand them to consumer
+//! threads, which free them. Every free is thus a remote free, which is the case that allocators
+//! with thread-local caches tend to handle worst.
+//!
+//! Usage: `bench_prod_cons [producers] [consumers] [object sizes...]`. By default, half of the
+//! CPUs produce, the other half consume, and a range of small and medium sizes is measured.
+//!
+//! Objects are handed over in batches through channels. For each run we report throughput
+//! (objects allocated and freed per second) and the number of objects that were in flight between
+//! producers and consumers when a consumer received a batch, followed by a table comparing the
+//! throughput of all allocators. Building with the `contention-stats` feature additionally reports
+//! how remote frees reached elfmalloc's backend.
+
+#![feature(alloc)]
+#![feature(allocator_api)]
+extern crate alloc;
+extern crate elfmalloc;
+extern crate num_cpus;
+
+use std::cmp;
+use std::env;
+use std::mem;
+use std::ptr::write_volatile;
+use std::sync::{Arc, Barrier};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time;
+
+mod common;
+use common::{Allocator, Table};
+
+/// The number of objects each producer allocates. This must be a multiple of `BATCH`.
+const OBJECTS_PER_PRODUCER: usize = 1 << 20;
+/// The number of objects handed to a consumer at once.
+const BATCH: usize = 64;
+/// Producers wait for consumers to catch up once this many objects are in flight, so that memory
+/// usage stays bounded when freeing is slower than allocating.
+const MAX_IN_FLIGHT: usize = 1 << 16;
+
+/// A batch of objects on its way to a consumer. Pointers are sent as `usize`s because raw
+/// pointers are not `Send`.
+type Batch = Vec<usize>;
+
+/// Statistics about the objects in flight, sampled whenever a consumer receives a batch.
+#[derive(Default)]
+struct Depth {
+    samples: usize,
+    total: usize,
+    max: usize,
+}
+
+impl Depth {
+    fn record(&mut self, depth: usize) {
+        self.samples += 1;
+        self.total += depth;
+        self.max = cmp::max(self.max, depth);
+    }
+
+    fn merge(&mut self, other: Depth) {
+        self.samples += other.samples;
+        self.total += other.total;
+        self.max = cmp::max(self.max, other.max);
+    }
+
+    fn mean(&self) -> f64 {
+        if self.samples == 0 {
+            0.0
+        } else {
+            self.total as f64 / self.samples as f64
+        }
+    }
+}
+
+fn producer(
+    alloc: Allocator,
+    size: usize,
+    consumers: Vec<Sender<Batch>>,
+    in_flight: Arc<AtomicUsize>,
+    barrier: Arc<Barrier>,
+) {
+    barrier.wait();
+    let mut next = 0;
+    let mut batch = Vec::with_capacity(BATCH);
+    for i in 0..OBJECTS_PER_PRODUCER {
+        unsafe {
+            let p = alloc.allocate(size);
+            write_volatile(p as *mut usize, i);
+            batch.push(p as usize);
+        }
+        if batch.len() == BATCH {
+            while in_flight.load(Ordering::Relaxed) >= MAX_IN_FLIGHT {
+                thread::yield_now();
+            }
+            in_flight.fetch_add(BATCH, Ordering::Relaxed);
+            let full = mem::replace(&mut batch, Vec::with_capacity(BATCH));
+            consumers[next].send(full).unwrap();
+            next = (next + 1) % consumers.len();
+        }
+    }
+}
+
+/// Run the benchmark and return its throughput in millions of objects per second.
+fn run(alloc: Allocator, producers: usize, consumers: usize, size: usize) -> f64 {
+    let barrier = Arc::new(Barrier::new(producers + consumers + 1));
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let mut senders = Vec::new();
+    let mut consumer_threads = Vec::new();
+    for _ in 0..consumers {
+        let (send, recv) = channel::<Batch>();
+        senders.push(send);
+        let in_flight = in_flight.clone();
+        let barrier = barrier.clone();
+        consumer_threads.push(thread::spawn(move || {
+            barrier.wait();
+            let mut depth = Depth::default();
+            // The loop ends once all producers have exited and dropped their senders.
+            for batch in recv.iter() {
+                depth.record(in_flight.load(Ordering::Relaxed));
+                for p in &batch {
+                    unsafe { alloc.deallocate(*p as *mut u8, size) };
+                }
+                in_flight.fetch_sub(batch.len(), Ordering::Relaxed);
+            }
+            depth
+        }));
+    }
+    let producer_threads: Vec<_> = (0..producers)
+        .map(|_| {
+            let senders = senders.clone();
+            let in_flight = in_flight.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || producer(alloc, size, senders, in_flight, barrier))
+        })
+        .collect();
+    // Consumers exit once the producers' senders are gone, so ours must not outlive them.
+    mem::drop(senders);
+
+    #[cfg(feature = "contention-stats")]
+    elfmalloc::reset_contention_stats();
+    barrier.wait();
+    let start = time::Instant::now();
+    for t in producer_threads {
+        t.join().expect("producers should exit successfully");
+    }
+    let mut depth = Depth::default();
+    for t in consumer_threads {
+        depth.merge(t.join().expect("consumers should exit successfully"));
+    }
+    let dur = start.elapsed();
+    let nanos = dur.as_secs() * 1_000_000_000 + u64::from(dur.subsec_nanos());
+    let objects = producers * OBJECTS_PER_PRODUCER;
+    let throughput = (objects as f64 * 1_000.0) / (nanos as f64);
+
+    println!(
+        "{:10} {:3}p/{:<3}c {:6} B {:8.2} Mobj/s  in flight: mean {:8.1} max {:6}",
+        alloc.name(),
+        producers,
+        consumers,
+        size,
+        throughput,
+        depth.mean(),
+        depth.max
+    );
+    #[cfg(feature = "contention-stats")]
+    {
+        if alloc == Allocator::Elf {
+            let stats = elfmalloc::contention_stats();
+            println!(
+                "    remote frees: {} single, {} batched; {} drains of {:.1} objects on average",
+                stats.remote_frees,
+                stats.bulk_remote_frees,
+                stats.drains,
+                if stats.drains == 0 {
+                    0.0
+                } else {
+                    stats.drained_objects as f64 / stats.drains as f64
+                }
+            );
+        }
+    }
+    throughput
+}
+
+fn parse(arg: &str) -> usize {
+    arg.parse()
+        .unwrap_or_else(|_| panic!("expected a number, got {:?}", arg))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let half = cmp::max(num_cpus::get() / 2, 1);
+    let producers = args.get(0).map(|a| parse(a)).unwrap_or(half);
+    let consumers = args.get(1).map(|a| parse(a)).unwrap_or(half);
+    let sizes: Vec<usize> = if args.len() > 2 {
+        args[2..].iter().map(|a| parse(a)).collect()
+    } else {
+        vec![16, 64, 256, 1024, 4096, 16 << 10]
+    };
+    assert!(producers > 0 && consumers > 0, "need at least one producer and consumer");
+    println!(
+        "{} objects per producer, handed over in batches of {}",
+        OBJECTS_PER_PRODUCER,
+        BATCH
+    );
+    let mut table = Table::new(format!(
+        "throughput (Mobj/s), {} producers, {} consumers",
+        producers,
+        consumers
+    ));
+    for &size in &sizes {
+        assert!(size >= 8, "objects must be able to hold a word");
+        let cells = Allocator::all()
+            .into_iter()
+            .map(|alloc| format!("{:.2}", run(alloc, producers, consumers, size)))
+            .collect();
+        table.row(format!("{} B", size), cells);
+    }
+    table.print();
+}
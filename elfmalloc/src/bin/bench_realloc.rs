@@ -0,0 +1,153 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A benchmark of common `realloc` patterns: buffers that double when full, strings that grow a
+//! byte at a time, buffers that grow a page at a time, and buffers that are shrunk by halves.
+//!
+//! Each pattern resizes a single object from its start size to its end size, writing to the last
+//! byte after every step, and is repeated a number of times. We report the mean time per
+//! `realloc` for each allocator. Building with the `realloc-stats` feature additionally reports
+//! how elfmalloc satisfied the calls: in place, by moving pages, or by copying.
+
+#![feature(alloc)]
+#![feature(allocator_api)]
+extern crate alloc;
+extern crate elfmalloc;
+
+use std::ptr::write_volatile;
+use std::time;
+
+mod common;
+use common::{Allocator, Table};
+
+struct Pattern {
+    name: &'static str,
+    start: usize,
+    end: usize,
+    /// The size after `size`.
+    next: fn(usize) -> usize,
+    rounds: usize,
+}
+
+fn double(size: usize) -> usize {
+    size * 2
+}
+
+fn halve(size: usize) -> usize {
+    size / 2
+}
+
+fn plus_one(size: usize) -> usize {
+    size + 1
+}
+
+fn plus_page(size: usize) -> usize {
+    size + 4096
+}
+
+const PATTERNS: &[Pattern] = &[
+    Pattern {
+        name: "doubling, 16B to 1MiB",
+        start: 16,
+        end: 1 << 20,
+        next: double,
+        rounds: 10_000,
+    },
+    Pattern {
+        name: "doubling, 16B to 64MiB",
+        start: 16,
+        end: 64 << 20,
+        next: double,
+        rounds: 100,
+    },
+    Pattern {
+        name: "+1B, 16B to 64KiB",
+        start: 16,
+        end: 64 << 10,
+        next: plus_one,
+        rounds: 20,
+    },
+    Pattern {
+        name: "+4KiB, 4KiB to 16MiB",
+        start: 4 << 10,
+        end: 16 << 20,
+        next: plus_page,
+        rounds: 5,
+    },
+    Pattern {
+        name: "halving, 1MiB to 16B",
+        start: 1 << 20,
+        end: 16,
+        next: halve,
+        rounds: 10_000,
+    },
+];
+
+/// Run `pattern` and return the mean time per `realloc` in nanoseconds.
+fn run(alloc: Allocator, pattern: &Pattern) -> f64 {
+    let growing = pattern.end > pattern.start;
+    let mut reallocs = 0;
+    let start = time::Instant::now();
+    for _ in 0..pattern.rounds {
+        unsafe {
+            let mut size = pattern.start;
+            let mut p = alloc.allocate(size);
+            write_volatile(p, 1);
+            while (growing && size < pattern.end) || (!growing && size > pattern.end) {
+                let new_size = (pattern.next)(size);
+                p = alloc.reallocate(p, size, new_size);
+                size = new_size;
+                write_volatile(p.offset(size as isize - 1), 1);
+                reallocs += 1;
+            }
+            alloc.deallocate(p, size);
+        }
+    }
+    let dur = start.elapsed();
+    let nanos = dur.as_secs() * 1_000_000_000 + u64::from(dur.subsec_nanos());
+    nanos as f64 / reallocs as f64
+}
+
+fn main() {
+    let mut table = Table::new("time per realloc (ns)");
+    #[cfg(feature = "realloc-stats")]
+    let mut stats = Table::with_columns(
+        "elfmalloc realloc outcomes",
+        ["in place", "pages moved", "copied", "MiB copied"]
+            .iter()
+            .map(|c| c.to_string())
+            .collect(),
+    );
+    for pattern in PATTERNS {
+        let mut cells = Vec::new();
+        for alloc in Allocator::all() {
+            #[cfg(feature = "realloc-stats")]
+            elfmalloc::reset_realloc_stats();
+            cells.push(format!("{:.1}", run(alloc, pattern)));
+            #[cfg(feature = "realloc-stats")]
+            {
+                if alloc == Allocator::Elf {
+                    let s = elfmalloc::realloc_stats();
+                    let pct = |n: usize| format!("{:.1}%", n as f64 * 100.0 / s.total() as f64);
+                    stats.row(
+                        pattern.name,
+                        vec![
+                            pct(s.in_place()),
+                            pct(s.remapped_moved),
+                            pct(s.copied),
+                            format!("{:.1}", s.copied_bytes as f64 / (1 << 20) as f64),
+                        ],
+                    );
+                }
+            }
+        }
+        table.row(pattern.name, cells);
+    }
+    table.print();
+    #[cfg(feature = "realloc-stats")]
+    stats.print();
+}
@@ -0,0 +1,212 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A sawtooth workload that logs resident memory over time, for evaluating how quickly memory is
+//! returned to the operating system.
+//!
+//! Each cycle allocates objects of random sizes until a target amount of memory is live, frees
+//! most of them in random order, and then idles for a while. The resident set size of the process
+//! is sampled throughout and written as CSV:
+//!
+//! ```text
+//! allocator,cycle,phase,elapsed_ms,live_bytes,rss_bytes
+//! ```
+//!
+//! Usage: `bench_rss [output.csv]` (default `rss.csv`). Since RSS is a property of the whole
+//! process, each allocator in `common::Allocator` is measured in a child process of its own. A
+//! table of the peak RSS and the RSS after the last idle phase of each allocator is printed at the
+//! end. This requires Linux, as RSS is read from `/proc/self/statm`.
+
+#![feature(alloc)]
+#![feature(allocator_api)]
+extern crate alloc;
+extern crate elfmalloc;
+extern crate libc;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process::Command;
+use std::thread;
+use std::time;
+
+mod common;
+use common::{Allocator, Table};
+
+/// The number of allocate/free cycles.
+const CYCLES: usize = 8;
+/// The number of bytes that are live at the end of each allocation phase.
+const PEAK_BYTES: usize = 512 << 20;
+/// The fraction of the live objects that is freed in each free phase, in percent.
+const FREE_PERCENT: u64 = 90;
+/// The largest object size.
+const MAX_SIZE: u64 = 4096;
+/// RSS is sampled every `SAMPLE_OPS` allocations or frees.
+const SAMPLE_OPS: usize = 1 << 14;
+/// The length of the idle phase, and the interval at which RSS is sampled during it.
+const IDLE_MS: u64 = 2_000;
+const IDLE_SAMPLE_MS: u64 = 100;
+
+/// The resident set size of this process in bytes.
+fn rss_bytes() -> usize {
+    let mut statm = String::new();
+    File::open("/proc/self/statm")
+        .and_then(|mut f| f.read_to_string(&mut statm))
+        .expect("failed to read /proc/self/statm");
+    // The second field is the number of resident pages.
+    let pages: usize = statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|f| f.parse().ok())
+        .expect("malformed /proc/self/statm");
+    pages * unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// xorshift64*, so that all allocators see the same sequence of sizes and frees.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+struct Log<'a, W: Write + 'a> {
+    out: &'a mut W,
+    name: &'static str,
+    start: time::Instant,
+}
+
+impl<'a, W: Write> Log<'a, W> {
+    fn sample(&mut self, cycle: usize, phase: &str, live_bytes: usize) {
+        let elapsed = self.start.elapsed();
+        let ms = elapsed.as_secs() * 1_000 + u64::from(elapsed.subsec_nanos()) / 1_000_000;
+        writeln!(
+            self.out,
+            "{},{},{},{},{},{}",
+            self.name,
+            cycle,
+            phase,
+            ms,
+            live_bytes,
+            rss_bytes()
+        ).unwrap();
+    }
+}
+
+fn run<W: Write>(alloc: Allocator, out: &mut W) {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let mut log = Log {
+        out: out,
+        name: alloc.name(),
+        start: time::Instant::now(),
+    };
+    let mut live: Vec<(*mut u8, usize)> = Vec::new();
+    let mut live_bytes = 0;
+    log.sample(0, "start", live_bytes);
+    for cycle in 0..CYCLES {
+        let mut ops = 0;
+        while live_bytes < PEAK_BYTES {
+            let size = (rng.next() % MAX_SIZE + 1) as usize;
+            unsafe {
+                let p = alloc.allocate(size);
+                // Touch every page of the object so that it counts towards RSS.
+                let mut off = 0;
+                while off < size {
+                    *p.offset(off as isize) = 1;
+                    off += 4096;
+                }
+                live.push((p, size));
+            }
+            live_bytes += size;
+            ops += 1;
+            if ops % SAMPLE_OPS == 0 {
+                log.sample(cycle, "alloc", live_bytes);
+            }
+        }
+        log.sample(cycle, "alloc", live_bytes);
+
+        let to_free = live.len() * FREE_PERCENT as usize / 100;
+        for i in 0..to_free {
+            // Free a random live object by swapping it to the end.
+            let ix = (rng.next() % live.len() as u64) as usize;
+            let (p, size) = live.swap_remove(ix);
+            unsafe { alloc.deallocate(p, size) };
+            live_bytes -= size;
+            if (i + 1) % SAMPLE_OPS == 0 {
+                log.sample(cycle, "free", live_bytes);
+            }
+        }
+        log.sample(cycle, "free", live_bytes);
+
+        let mut idle = 0;
+        while idle < IDLE_MS {
+            thread::sleep(time::Duration::from_millis(IDLE_SAMPLE_MS));
+            idle += IDLE_SAMPLE_MS;
+            log.sample(cycle, "idle", live_bytes);
+        }
+    }
+    for (p, size) in live {
+        unsafe { alloc.deallocate(p, size) };
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    // Each allocator is run in a child process, which writes its samples to stdout.
+    if args.len() == 2 && args[0] == "--run" {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        match Allocator::from_name(&args[1]) {
+            Some(alloc) => run(alloc, &mut out),
+            None => panic!("unknown allocator {:?}", args[1]),
+        }
+        return;
+    }
+
+    let path = args.get(0).map(|s| s.as_str()).unwrap_or("rss.csv");
+    let mut csv = File::create(path).expect("failed to create output file");
+    writeln!(csv, "allocator,cycle,phase,elapsed_ms,live_bytes,rss_bytes").unwrap();
+    let exe = env::current_exe().expect("failed to find the benchmark executable");
+    let mut table = Table::with_columns(
+        "resident memory (MiB)",
+        vec!["peak".to_string(), "after idle".to_string()],
+    );
+    for alloc in Allocator::all() {
+        let name = alloc.name();
+        println!("running {}", name);
+        let output = Command::new(&exe)
+            .arg("--run")
+            .arg(name)
+            .output()
+            .expect("failed to run benchmark process");
+        io::stderr().write_all(&output.stderr).unwrap();
+        assert!(output.status.success(), "{} run failed", name);
+        csv.write_all(&output.stdout).unwrap();
+
+        // The last field of each sample is the RSS, and the last sample is taken at the end of
+        // the last idle phase.
+        let samples = String::from_utf8_lossy(&output.stdout);
+        let rss: Vec<usize> = samples
+            .lines()
+            .filter_map(|l| l.rsplit(',').next().and_then(|f| f.parse().ok()))
+            .collect();
+        let mib = |bytes: usize| format!("{:.1}", bytes as f64 / (1 << 20) as f64);
+        table.row(
+            name,
+            vec![
+                mib(rss.iter().cloned().max().unwrap_or(0)),
+                mib(rss.last().cloned().unwrap_or(0)),
+            ],
+        );
+    }
+    println!("wrote {}", path);
+    table.print();
+}
@@ -0,0 +1,102 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A benchmark comparing a sealable `IsolatedHeap` with an ordinary one.
+//!
+//! Usage: `bench_sealable [max threads]`. By default, up to one thread per CPU is used.
+//!
+//! Each thread allocates and frees small objects through a handle of its own, and every 64th
+//! object is a large one, so that threads with large objects in flight run alongside threads
+//! allocating small ones. The table shows the throughput of an ordinary heap and of a sealable one,
+//! which records the mappings it makes and uncommits pages rather than unmapping them. Neither
+//! happens on the fast path, so the sealable heap should keep up with the ordinary one at any
+//! number of threads.
+
+#![feature(alloc)]
+#![feature(allocator_api)]
+extern crate alloc;
+extern crate elfmalloc;
+extern crate num_cpus;
+
+use alloc::allocator::{Alloc, Layout};
+use elfmalloc::rust_alloc::{ElfMallocBuilder, IsolatedHeap};
+use std::env;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time;
+
+mod common;
+use common::Table;
+
+/// The number of objects each thread allocates.
+const OBJECTS: usize = 1 << 20;
+/// The number of objects a thread holds at once.
+const BATCH: usize = 64;
+const SMALL: usize = 64;
+const LARGE: usize = 4 << 20;
+
+/// Run the workload on `threads` threads with handles to `heap`, and return the throughput in
+/// millions of objects per second.
+fn run(heap: &IsolatedHeap, threads: usize) -> f64 {
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let mut handle = heap.handle();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                let small = Layout::from_size_align(SMALL, 8).unwrap();
+                let large = Layout::from_size_align(LARGE, 8).unwrap();
+                let mut batch = Vec::with_capacity(BATCH);
+                barrier.wait();
+                for _ in 0..OBJECTS / BATCH {
+                    for i in 0..BATCH {
+                        let l = if i == 0 { large.clone() } else { small.clone() };
+                        batch.push((unsafe { handle.alloc(l.clone()) }.unwrap(), l));
+                    }
+                    for (p, l) in batch.drain(..) {
+                        unsafe { handle.dealloc(p, l) };
+                    }
+                }
+                barrier.wait();
+            })
+        })
+        .collect();
+    barrier.wait();
+    let start = time::Instant::now();
+    barrier.wait();
+    let dur = start.elapsed();
+    for h in handles {
+        h.join().unwrap();
+    }
+    let secs = dur.as_secs() as f64 + f64::from(dur.subsec_nanos()) / 1e9;
+    (threads * OBJECTS) as f64 / secs / 1e6
+}
+
+fn main() {
+    let max_threads = env::args()
+        .nth(1)
+        .map(|s| s.parse().expect("max threads must be a number"))
+        .unwrap_or_else(num_cpus::get);
+    let plain = ElfMallocBuilder::default().build_heap();
+    let sealable = ElfMallocBuilder::default().build_sealable_heap();
+    let mut table = Table::with_columns(
+        "Mobjects/s".to_string(),
+        vec!["heap".to_string(), "sealable heap".to_string()],
+    );
+    let mut threads = 1;
+    while threads <= max_threads {
+        table.row(
+            format!("{} threads", threads),
+            vec![
+                format!("{:.2}", run(&plain, threads)),
+                format!("{:.2}", run(&sealable, threads)),
+            ],
+        );
+        threads *= 2;
+    }
+    table.print();
+}
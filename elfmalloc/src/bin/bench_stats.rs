@@ -0,0 +1,115 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A benchmark of the cost of the `stats` feature on elfmalloc's fast path.
+//!
+//! Usage: `bench_stats [threads]`. Run it once as is and once built with `--features stats`:
+//!
+//! ```text
+//! cargo run --release --bin bench_stats
+//! cargo run --release --bin bench_stats --features stats
+//! ```
+//!
+//! Each run allocates and frees batches of small objects of several sizes through the global
+//! allocator, on one thread and then on `threads` threads (by default, one per CPU), and reports
+//! the best time per allocation and free out of several rounds. Without the feature, the
+//! counting code is compiled out entirely, so the first run measures the allocator as it would be
+//! without the statistics subsystem; the difference between the two runs is the cost of turning
+//! it on. With the feature, the counts are printed as well, as a check that they were recorded.
+
+#![feature(alloc)]
+#![feature(allocator_api)]
+extern crate alloc;
+extern crate elfmalloc;
+extern crate num_cpus;
+
+use std::env;
+use std::ptr::write_volatile;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time;
+
+mod common;
+use common::{Allocator, Table};
+
+const SIZES: &[usize] = &[16, 64, 256, 2048];
+/// The number of objects each thread allocates and frees per round.
+const OBJECTS: usize = 1 << 22;
+/// The number of objects a thread holds at once.
+const BATCH: usize = 64;
+const ROUNDS: usize = 5;
+
+/// The best time per allocation and free of `size`-byte objects, in nanoseconds, with `threads`
+/// threads running at once.
+fn run(size: usize, threads: usize) -> f64 {
+    let mut best = ::std::f64::MAX;
+    for _ in 0..ROUNDS {
+        let barrier = Arc::new(Barrier::new(threads + 1));
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let alloc = Allocator::Elf;
+                    let mut batch = [0 as *mut u8; BATCH];
+                    // Set up the thread's heap before the clock starts.
+                    unsafe { alloc.deallocate(alloc.allocate(size), size) };
+                    barrier.wait();
+                    for _ in 0..OBJECTS / BATCH {
+                        unsafe {
+                            for p in batch.iter_mut() {
+                                *p = alloc.allocate(size);
+                                write_volatile(*p, 1);
+                            }
+                            for &p in batch.iter() {
+                                alloc.deallocate(p, size);
+                            }
+                        }
+                    }
+                    barrier.wait();
+                })
+            })
+            .collect();
+        barrier.wait();
+        let start = time::Instant::now();
+        barrier.wait();
+        let dur = start.elapsed();
+        for h in handles {
+            h.join().unwrap();
+        }
+        let nanos = dur.as_secs() * 1_000_000_000 + u64::from(dur.subsec_nanos());
+        best = best.min(nanos as f64 / OBJECTS as f64);
+    }
+    best
+}
+
+fn main() {
+    let threads = env::args()
+        .nth(1)
+        .map(|a| a.parse().expect("the thread count must be a number"))
+        .unwrap_or_else(num_cpus::get);
+    let enabled = if cfg!(feature = "stats") {
+        "enabled"
+    } else {
+        "disabled"
+    };
+    let mut table = Table::with_columns(
+        format!("alloc + free (ns), heap statistics {}", enabled),
+        vec!["1 thread".to_string(), format!("{} threads", threads)],
+    );
+    for &size in SIZES {
+        table.row(
+            format!("{}B", size),
+            vec![
+                format!("{:.2}", run(size, 1)),
+                format!("{:.2}", run(size, threads)),
+            ],
+        );
+    }
+    table.print();
+    #[cfg(feature = "stats")]
+    println!("{:?}", elfmalloc::heap_stats());
+}
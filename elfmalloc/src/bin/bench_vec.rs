@@ -15,7 +15,6 @@ extern crate num_cpus;
 extern crate smallvec;
 extern crate test;
 use test::stats::Stats;
-use elfmalloc::rust_alloc::SharedAlloc;
 use elfmalloc::vec_alloc::AVec;
 use alloc::heap::Heap;
 use smallvec::VecLike;
@@ -24,6 +23,9 @@ use std::thread;
 use std::time;
 use std::sync::{Arc, Barrier};
 
+mod common;
+use common::{Allocator, Selected};
+
 /// A basic resumable timer type used for benchmark time measurements.
 struct Timer {
     start: time::Instant,
@@ -139,7 +141,8 @@ macro_rules! create_bench {
     };
 }
 
-/// Group a number of benchmarks created using `create_bench` for three `Vec`-like types.
+/// Group a number of benchmarks created using `create_bench` for `Vec`, and for `AVec` with the
+/// default heap and with each allocator in `Allocator::all()`.
 macro_rules! bench_group {
     ($name:ident, $param:tt ::: $pty:ty = $pval:expr, $iters:expr, $fn:tt) => {
         fn $name() {
@@ -172,22 +175,25 @@ macro_rules! bench_group {
                           $iters,
                           $fn::<AVec<_, Heap>>($param, _t));
             create_bench!(v3,
-                          format!("{}_avec_elf", stringify!($name)),
+                          format!("{}_avec_{}", stringify!($name), Allocator::selected().name()),
                           $param ::: $pty = $pval,
                           _t,
                           1,
                           $iters,
-                          $fn::<AVec<_, SharedAlloc>>($param, _t));
+                          $fn::<AVec<_, Selected>>($param, _t));
             create_bench!(v3n,
-                          format!("{}_avec_elf", stringify!($name)),
+                          format!("{}_avec_{}", stringify!($name), Allocator::selected().name()),
                           $param ::: $pty = $pval,
                           _t,
                           num_cpus::get(),
                           $iters,
-                          $fn::<AVec<_, SharedAlloc>>($param, _t));
+                          $fn::<AVec<_, Selected>>($param, _t));
 
-            v3();
-            v3n();
+            for alloc in Allocator::all() {
+                alloc.select();
+                v3();
+                v3n();
+            }
             v1();
             v1n();
             v2();
@@ -196,27 +202,51 @@ macro_rules! bench_group {
     };
 }
 
+/// A `Vec`-like type that the benchmarks can create. `AVec` only implements `Default` for some
+/// allocators, so this can't just be `Default`.
+trait BenchVec<T>: VecLike<T> {
+    fn create() -> Self;
+}
+
+impl<T> BenchVec<T> for Vec<T> {
+    fn create() -> Self {
+        Vec::new()
+    }
+}
+
+impl<T> BenchVec<T> for AVec<T, Heap> {
+    fn create() -> Self {
+        AVec::new()
+    }
+}
+
+impl<T> BenchVec<T> for AVec<T, Selected> {
+    fn create() -> Self {
+        AVec::new_in(Selected)
+    }
+}
+
 #[inline(never)]
 fn push_noinline<T, V: VecLike<T>>(v: &mut V, t: T) {
     v.push(t)
 }
 
 /// A benchmark testing smaller-sized allocations.
-fn do_push<V: VecLike<usize> + Default>(ops: usize, _timer: &mut Timer) -> V {
-    let mut v = V::default();
+fn do_push<V: BenchVec<usize>>(ops: usize, _timer: &mut Timer) -> V {
+    let mut v = V::create();
     for _ in 0..8 {
         for i in 0..ops {
             push_noinline(&mut v, i);
         }
-        v = V::default();
+        v = V::create();
     }
     v
 }
 
 /// A benchmark stressing medium-sized allocations.
-fn do_push_medium<V: VecLike<usize> + Default>(ops: usize, timer: &mut Timer) -> V {
+fn do_push_medium<V: BenchVec<usize>>(ops: usize, timer: &mut Timer) -> V {
     timer.stop();
-    let mut v = V::default();
+    let mut v = V::create();
     v.extend(1..(1 << 14));
     timer.resume();
     for i in 0..ops {
@@ -226,8 +256,8 @@ fn do_push_medium<V: VecLike<usize> + Default>(ops: usize, timer: &mut Timer) ->
 }
 
 /// A benchmark stressing large-sized allocations.
-fn do_push_large<V: VecLike<[usize; 1024]> + Default>(ops: usize, _timer: &mut Timer) -> V {
-    let mut v = V::default();
+fn do_push_large<V: BenchVec<[usize; 1024]>>(ops: usize, _timer: &mut Timer) -> V {
+    let mut v = V::create();
     for i in 0..ops {
         // noinline may cause overflow issues
         v.push([i; 1024]);
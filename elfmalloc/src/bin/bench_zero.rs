@@ -0,0 +1,84 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A benchmark of the cost of the `zero-on-free` feature.
+//!
+//! Usage: `bench_zero`. Run it once as is and once built with `--features zero-on-free`:
+//!
+//! ```text
+//! cargo run --release --bin bench_zero
+//! cargo run --release --bin bench_zero --features zero-on-free
+//! ```
+//!
+//! For objects of several sizes, from a small size class to a large object, it reports the best
+//! time out of several rounds to allocate an object with `malloc`, write all of it, and free it,
+//! and the same with `calloc`. The feature makes the first slower, since `free` zeroes the object,
+//! and the second faster, since `calloc` no longer has to.
+
+#![feature(alloc)]
+#![feature(allocator_api)]
+extern crate alloc;
+extern crate elfmalloc;
+
+use alloc::allocator::{Alloc, Layout};
+use elfmalloc::alloc_impl::ElfMallocGlobal;
+use elfmalloc::general::global;
+use std::ptr::write_bytes;
+use std::time;
+
+mod common;
+use common::Table;
+
+const SIZES: &[usize] = &[64, 1 << 10, 16 << 10, 256 << 10, 4 << 20];
+/// The number of bytes allocated per round, spread over objects of the size being measured.
+const BYTES: usize = 1 << 30;
+const ROUNDS: usize = 5;
+
+/// The best time per object, in nanoseconds, to allocate `size`-byte objects with `alloc`, fill
+/// them, and free them.
+fn run<F: Fn(usize) -> *mut u8>(size: usize, alloc: F) -> f64 {
+    let objects = BYTES / size;
+    let mut best = ::std::f64::MAX;
+    for _ in 0..ROUNDS {
+        let start = time::Instant::now();
+        for _ in 0..objects {
+            unsafe {
+                let p = alloc(size);
+                write_bytes(p, 1, size);
+                global::free(p);
+            }
+        }
+        let dur = start.elapsed();
+        let nanos = dur.as_secs() * 1_000_000_000 + u64::from(dur.subsec_nanos());
+        best = best.min(nanos as f64 / objects as f64);
+    }
+    best
+}
+
+fn main() {
+    let enabled = if cfg!(feature = "zero-on-free") {
+        "enabled"
+    } else {
+        "disabled"
+    };
+    let mut table = Table::with_columns(
+        format!("alloc + fill + free (ns), zero-on-free {}", enabled),
+        vec!["malloc".to_string(), "calloc".to_string()],
+    );
+    for &size in SIZES {
+        let malloc = run(size, |size| unsafe { global::alloc(size) });
+        let calloc = run(size, |size| unsafe {
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            (&ElfMallocGlobal).alloc_zeroed(layout).unwrap()
+        });
+        table.row(
+            format!("{}B", size),
+            vec![format!("{:.1}", malloc), format!("{:.1}", calloc)],
+        );
+    }
+    table.print();
+}
@@ -0,0 +1,272 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Code shared by the benchmark binaries: the set of allocators to compare, and a table for
+//! reporting results side by side.
+//!
+//! Every benchmark runs against each `Allocator` in `Allocator::all()`. elfmalloc and the system
+//! allocator (libc's `malloc`) are always included; jemalloc and mimalloc are added by the
+//! `bench-jemalloc` and `bench-mimalloc` features. Not every binary uses every item here.
+#![allow(dead_code)]
+
+extern crate libc;
+#[cfg(feature = "bench-jemalloc")]
+extern crate jemallocator;
+#[cfg(feature = "bench-mimalloc")]
+extern crate libmimalloc_sys;
+
+use alloc::allocator::{Alloc, AllocErr, Layout};
+use elfmalloc::general::global;
+use elfmalloc::rust_alloc::SharedAlloc;
+
+use std::cmp;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+/// The alignment guaranteed by `malloc` on the platforms we benchmark on.
+const MALLOC_ALIGN: usize = 16;
+
+/// An allocator that benchmarks can be run against.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Allocator {
+    /// elfmalloc's global allocator.
+    Elf,
+    /// libc's `malloc`.
+    System,
+    #[cfg(feature = "bench-jemalloc")]
+    Jemalloc,
+    #[cfg(feature = "bench-mimalloc")]
+    Mimalloc,
+}
+
+impl Allocator {
+    /// All allocators enabled in this build, elfmalloc first.
+    pub fn all() -> Vec<Allocator> {
+        #[allow(unused_mut)]
+        let mut all = vec![Allocator::Elf, Allocator::System];
+        #[cfg(feature = "bench-jemalloc")]
+        all.push(Allocator::Jemalloc);
+        #[cfg(feature = "bench-mimalloc")]
+        all.push(Allocator::Mimalloc);
+        all
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Allocator::Elf => "elfmalloc",
+            Allocator::System => "system",
+            #[cfg(feature = "bench-jemalloc")]
+            Allocator::Jemalloc => "jemalloc",
+            #[cfg(feature = "bench-mimalloc")]
+            Allocator::Mimalloc => "mimalloc",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Allocator> {
+        Allocator::all().into_iter().find(|a| a.name() == name)
+    }
+
+    /// Allocate `size` bytes with at least word alignment.
+    #[inline]
+    pub unsafe fn allocate(self, size: usize) -> *mut u8 {
+        match self {
+            Allocator::Elf => global::alloc(size),
+            mut a => {
+                let layout = Layout::from_size_align(size, MALLOC_ALIGN).unwrap();
+                match a.alloc(layout) {
+                    Ok(p) => p,
+                    Err(e) => a.oom(e),
+                }
+            }
+        }
+    }
+
+    /// Free an object of `size` bytes returned by `allocate`.
+    #[inline]
+    pub unsafe fn deallocate(self, p: *mut u8, size: usize) {
+        match self {
+            Allocator::Elf => global::free(p),
+            mut a => {
+                let layout = Layout::from_size_align(size, MALLOC_ALIGN).unwrap();
+                a.dealloc(p, layout)
+            }
+        }
+    }
+
+    /// Resize an object of `old_size` bytes returned by `allocate` to `new_size` bytes.
+    #[inline]
+    pub unsafe fn reallocate(self, p: *mut u8, old_size: usize, new_size: usize) -> *mut u8 {
+        match self {
+            Allocator::Elf => global::realloc(p, new_size),
+            mut a => {
+                let old = Layout::from_size_align(old_size, MALLOC_ALIGN).unwrap();
+                let new = Layout::from_size_align(new_size, MALLOC_ALIGN).unwrap();
+                match a.realloc(p, old, new) {
+                    Ok(p) => p,
+                    Err(e) => a.oom(e),
+                }
+            }
+        }
+    }
+
+    /// Make this the allocator that `Selected` allocates from.
+    pub fn select(self) {
+        let idx = Allocator::all().iter().position(|&a| a == self).unwrap();
+        SELECTED.store(idx, Ordering::Relaxed);
+    }
+
+    pub fn selected() -> Allocator {
+        Allocator::all()[SELECTED.load(Ordering::Relaxed)]
+    }
+}
+
+unsafe impl Alloc for Allocator {
+    unsafe fn alloc(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        match *self {
+            Allocator::Elf => SharedAlloc.alloc(l),
+            Allocator::System => {
+                let p = if l.align() <= MALLOC_ALIGN {
+                    libc::malloc(l.size())
+                } else {
+                    let mut p = ::std::ptr::null_mut();
+                    if libc::posix_memalign(&mut p, l.align(), l.size()) != 0 {
+                        p = ::std::ptr::null_mut();
+                    }
+                    p
+                };
+                if p.is_null() {
+                    Err(AllocErr::Exhausted { request: l })
+                } else {
+                    Ok(p as *mut u8)
+                }
+            }
+            #[cfg(feature = "bench-jemalloc")]
+            Allocator::Jemalloc => jemallocator::Jemalloc.alloc(l),
+            #[cfg(feature = "bench-mimalloc")]
+            Allocator::Mimalloc => {
+                let p = libmimalloc_sys::mi_malloc_aligned(l.size(), l.align()) as *mut u8;
+                if p.is_null() {
+                    Err(AllocErr::Exhausted { request: l })
+                } else {
+                    Ok(p)
+                }
+            }
+        }
+    }
+
+    unsafe fn dealloc(&mut self, p: *mut u8, l: Layout) {
+        match *self {
+            Allocator::Elf => SharedAlloc.dealloc(p, l),
+            Allocator::System => libc::free(p as *mut libc::c_void),
+            #[cfg(feature = "bench-jemalloc")]
+            Allocator::Jemalloc => jemallocator::Jemalloc.dealloc(p, l),
+            #[cfg(feature = "bench-mimalloc")]
+            Allocator::Mimalloc => libmimalloc_sys::mi_free(p as *mut _),
+        }
+    }
+
+    // The default implementation allocates, copies, and frees, which would hide the allocators'
+    // own realloc strategies.
+    unsafe fn realloc(&mut self, p: *mut u8, old: Layout, new: Layout) -> Result<*mut u8, AllocErr> {
+        let res = match *self {
+            Allocator::Elf => return SharedAlloc.realloc(p, old, new),
+            Allocator::System if new.align() <= MALLOC_ALIGN => {
+                libc::realloc(p as *mut libc::c_void, new.size()) as *mut u8
+            }
+            Allocator::System => {
+                let res = self.alloc(new.clone())?;
+                ::std::ptr::copy_nonoverlapping(p, res, cmp::min(old.size(), new.size()));
+                self.dealloc(p, old);
+                res
+            }
+            #[cfg(feature = "bench-jemalloc")]
+            Allocator::Jemalloc => return jemallocator::Jemalloc.realloc(p, old, new),
+            #[cfg(feature = "bench-mimalloc")]
+            Allocator::Mimalloc => {
+                libmimalloc_sys::mi_realloc_aligned(p as *mut _, new.size(), new.align()) as *mut u8
+            }
+        };
+        if res.is_null() {
+            Err(AllocErr::Exhausted { request: new })
+        } else {
+            Ok(res)
+        }
+    }
+}
+
+/// The index in `Allocator::all()` of the allocator used by `Selected`.
+static SELECTED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A ZST that allocates from the allocator chosen with `Allocator::select`, for benchmarking
+/// collections that construct their allocator with `Default`.
+#[derive(Copy, Clone, Default)]
+pub struct Selected;
+
+unsafe impl Alloc for Selected {
+    unsafe fn alloc(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        Allocator::selected().alloc(l)
+    }
+
+    unsafe fn dealloc(&mut self, p: *mut u8, l: Layout) {
+        Allocator::selected().dealloc(p, l)
+    }
+}
+
+/// A table of results with a row per benchmark configuration and a column per allocator.
+pub struct Table {
+    title: String,
+    columns: Vec<String>,
+    rows: Vec<(String, Vec<String>)>,
+}
+
+impl Table {
+    /// Create a table with a column for each allocator in `Allocator::all()`.
+    pub fn new<S: Into<String>>(title: S) -> Table {
+        let columns = Allocator::all().iter().map(|a| a.name().to_string()).collect();
+        Table::with_columns(title, columns)
+    }
+
+    pub fn with_columns<S: Into<String>>(title: S, columns: Vec<String>) -> Table {
+        Table {
+            title: title.into(),
+            columns: columns,
+            rows: Vec::new(),
+        }
+    }
+
+    /// Add a row. `cells` must have one entry per column.
+    pub fn row<S: Into<String>>(&mut self, label: S, cells: Vec<String>) {
+        assert_eq!(cells.len(), self.columns.len(), "wrong number of cells");
+        self.rows.push((label.into(), cells));
+    }
+
+    pub fn print(&self) {
+        let label_width = self.rows.iter().map(|r| r.0.len()).max().unwrap_or(0);
+        let widths: Vec<usize> = self.columns
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                self.rows
+                    .iter()
+                    .map(|r| r.1[i].len())
+                    .fold(c.len(), cmp::max)
+            })
+            .collect();
+        println!("\n{}", self.title);
+        let mut header = format!("{:1$}", "", label_width);
+        for (c, w) in self.columns.iter().zip(&widths) {
+            header.push_str(&format!("  {:>1$}", c, w));
+        }
+        println!("{}", header);
+        for &(ref label, ref cells) in &self.rows {
+            let mut line = format!("{:1$}", label, label_width);
+            for (c, w) in cells.iter().zip(&widths) {
+                line.push_str(&format!("  {:>1$}", c, w));
+            }
+            println!("{}", line);
+        }
+    }
+}
@@ -0,0 +1,725 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! An `Alloc`-parametric B-tree map.
+//!
+//! `ABTreeMap` complements the containers in `vec_alloc` with a node-based one. A B-tree
+//! allocates many objects of only two sizes (leaf and internal nodes), frees them as the tree
+//! shrinks, and follows pointers between them on every lookup, which is the workload that
+//! elfmalloc's size classes and per-thread caches are designed for.
+//!
+//! The tree is a classic B-tree of minimum degree `B`: every node other than the root holds
+//! between `B - 1` and `2 * B - 1` entries, and all leaves are at the same depth. Insertion splits
+//! full nodes on the way down, and removal refills nodes with `B - 1` entries on the way down (by
+//! borrowing an entry from a sibling or merging with it), so neither ever has to walk back up.
+//! Leaves do not have room for edges, so they are smaller than internal nodes.
+
+use super::alloc::allocator::{Alloc, Layout};
+use super::alloc::heap::Heap;
+use super::rust_alloc::{self, DynamicAlloc, SharedAlloc};
+
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+
+/// The minimum degree of the tree.
+const B: usize = 6;
+/// The maximum number of entries in a node.
+const CAPACITY: usize = 2 * B - 1;
+
+/// A node without edges. Internal nodes start with a `Leaf`, so a pointer to either kind of node
+/// is a `*mut Leaf`; the height of the node tells which kind it is.
+///
+/// Nodes are allocated and initialized field by field; only the first `len` keys and values are
+/// initialized.
+#[repr(C)]
+struct Leaf<K, V> {
+    len: usize,
+    keys: [K; CAPACITY],
+    vals: [V; CAPACITY],
+}
+
+#[repr(C)]
+struct Internal<K, V> {
+    data: Leaf<K, V>,
+    edges: [*mut Leaf<K, V>; CAPACITY + 1],
+}
+
+type NodePtr<K, V> = *mut Leaf<K, V>;
+
+unsafe fn len<K, V>(n: NodePtr<K, V>) -> usize {
+    (*n).len
+}
+
+unsafe fn key<K, V>(n: NodePtr<K, V>, i: usize) -> *mut K {
+    (&mut (*n).keys as *mut [K; CAPACITY] as *mut K).offset(i as isize)
+}
+
+unsafe fn val<K, V>(n: NodePtr<K, V>, i: usize) -> *mut V {
+    (&mut (*n).vals as *mut [V; CAPACITY] as *mut V).offset(i as isize)
+}
+
+/// The `i`th edge of `n`, which must be an internal node.
+unsafe fn edge<K, V>(n: NodePtr<K, V>, i: usize) -> *mut NodePtr<K, V> {
+    let n = n as *mut Internal<K, V>;
+    (&mut (*n).edges as *mut [NodePtr<K, V>; CAPACITY + 1] as *mut NodePtr<K, V>)
+        .offset(i as isize)
+}
+
+/// Move the `n` elements starting at `from` to start at `to`. The ranges may overlap.
+unsafe fn shift<T>(base: *mut T, from: usize, to: usize, n: usize) {
+    ptr::copy(base.offset(from as isize), base.offset(to as isize), n);
+}
+
+/// Insert `k` and `v` at index `i` of `n`, which must not be full, shifting the entries after it.
+unsafe fn insert_kv<K, V>(n: NodePtr<K, V>, i: usize, k: K, v: V) {
+    let len = len(n);
+    shift(key(n, 0), i, i + 1, len - i);
+    shift(val(n, 0), i, i + 1, len - i);
+    ptr::write(key(n, i), k);
+    ptr::write(val(n, i), v);
+    (*n).len = len + 1;
+}
+
+/// Remove the entry at index `i` of `n`, shifting the entries after it.
+unsafe fn remove_kv<K, V>(n: NodePtr<K, V>, i: usize) -> (K, V) {
+    let len = len(n);
+    let kv = (ptr::read(key(n, i)), ptr::read(val(n, i)));
+    shift(key(n, 0), i + 1, i, len - i - 1);
+    shift(val(n, 0), i + 1, i, len - i - 1);
+    (*n).len = len - 1;
+    kv
+}
+
+/// The index of the first key in `n` that is not less than `k`, and whether it is equal to `k`.
+unsafe fn search<K, V, Q: ?Sized + Ord>(n: NodePtr<K, V>, k: &Q) -> (usize, bool)
+where
+    K: Borrow<Q>,
+{
+    for i in 0..len(n) {
+        match k.cmp((*key(n, i)).borrow()) {
+            Ordering::Greater => {}
+            Ordering::Equal => return (i, true),
+            Ordering::Less => return (i, false),
+        }
+    }
+    (len(n), false)
+}
+
+/// Which entry `ABTreeMap::remove_from` removes.
+enum Target<'a, Q: 'a + ?Sized> {
+    Key(&'a Q),
+    Min,
+    Max,
+}
+
+/// An ordered map whose nodes are allocated from an `Alloc`. See the module documentation.
+pub struct ABTreeMap<K, V, A: Alloc> {
+    /// The root, or null if the map is empty.
+    root: NodePtr<K, V>,
+    /// The height of the root; leaves have height 0.
+    height: usize,
+    len: usize,
+    alloc: A,
+    _marker: PhantomData<(K, V)>,
+}
+
+unsafe impl<K: Send, V: Send, A: Alloc + Send> Send for ABTreeMap<K, V, A> {}
+unsafe impl<K: Sync, V: Sync, A: Alloc + Sync> Sync for ABTreeMap<K, V, A> {}
+
+impl<K: Ord, V> Default for ABTreeMap<K, V, DynamicAlloc> {
+    fn default() -> Self {
+        Self::new_in(rust_alloc::new_owned_handle())
+    }
+}
+
+impl<K: Ord, V> Default for ABTreeMap<K, V, SharedAlloc> {
+    fn default() -> Self {
+        Self::new_in(SharedAlloc)
+    }
+}
+
+impl<K: Ord, V> Default for ABTreeMap<K, V, Heap> {
+    fn default() -> Self {
+        Self::new_in(Heap)
+    }
+}
+
+impl<K: Ord, V, A: Alloc> ABTreeMap<K, V, A> {
+    /// Create an empty map that allocates its nodes from `alloc`. No memory is allocated until
+    /// the first insertion.
+    pub fn new_in(alloc: A) -> Self {
+        ABTreeMap {
+            root: ptr::null_mut(),
+            height: 0,
+            len: 0,
+            alloc: alloc,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn layout(height: usize) -> Layout {
+        if height == 0 {
+            Layout::new::<Leaf<K, V>>()
+        } else {
+            Layout::new::<Internal<K, V>>()
+        }
+    }
+
+    /// Allocate an empty node of height `height`.
+    fn new_node(&mut self, height: usize) -> NodePtr<K, V> {
+        unsafe {
+            let n = match self.alloc.alloc(Self::layout(height)) {
+                Ok(ptr) => ptr as NodePtr<K, V>,
+                Err(e) => self.alloc.oom(e),
+            };
+            (*n).len = 0;
+            n
+        }
+    }
+
+    unsafe fn free_node(&mut self, n: NodePtr<K, V>, height: usize) {
+        self.alloc.dealloc(n as *mut u8, Self::layout(height));
+    }
+
+    pub fn get<Q: ?Sized + Ord>(&self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
+        unsafe { self.find(k).map(|v| &*v) }
+    }
+
+    pub fn get_mut<Q: ?Sized + Ord>(&mut self, k: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+    {
+        unsafe { self.find(k).map(|v| &mut *v) }
+    }
+
+    pub fn contains_key<Q: ?Sized + Ord>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        self.find(k).is_some()
+    }
+
+    fn find<Q: ?Sized + Ord>(&self, k: &Q) -> Option<*mut V>
+    where
+        K: Borrow<Q>,
+    {
+        if self.root.is_null() {
+            return None;
+        }
+        let (mut n, mut height) = (self.root, self.height);
+        unsafe {
+            loop {
+                match search(n, k) {
+                    (i, true) => return Some(val(n, i)),
+                    (_, false) if height == 0 => return None,
+                    (i, false) => {
+                        n = *edge(n, i);
+                        height -= 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Insert `v` under `k`, returning the value previously stored under `k`, if any.
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        if self.root.is_null() {
+            self.root = self.new_node(0);
+            self.height = 0;
+        }
+        unsafe {
+            if len(self.root) == CAPACITY {
+                let old_height = self.height;
+                let root = self.new_node(old_height + 1);
+                *edge(root, 0) = self.root;
+                self.split_child(root, 0, old_height);
+                self.root = root;
+                self.height = old_height + 1;
+            }
+            let (mut n, mut height) = (self.root, self.height);
+            loop {
+                let (mut i, found) = search(n, &k);
+                if found {
+                    return Some(mem::replace(&mut *val(n, i), v));
+                }
+                if height == 0 {
+                    insert_kv(n, i, k, v);
+                    self.len += 1;
+                    return None;
+                }
+                if len(*edge(n, i)) == CAPACITY {
+                    self.split_child(n, i, height - 1);
+                    match k.cmp(&*key(n, i)) {
+                        Ordering::Equal => return Some(mem::replace(&mut *val(n, i), v)),
+                        Ordering::Greater => i += 1,
+                        Ordering::Less => {}
+                    }
+                }
+                n = *edge(n, i);
+                height -= 1;
+            }
+        }
+    }
+
+    /// Split the full `i`th child of `parent`, whose height is `height`, around its median entry,
+    /// which moves up into `parent`. `parent` must not be full.
+    unsafe fn split_child(&mut self, parent: NodePtr<K, V>, i: usize, height: usize) {
+        let left = *edge(parent, i);
+        let right = self.new_node(height);
+        ptr::copy_nonoverlapping(key(left, B), key(right, 0), B - 1);
+        ptr::copy_nonoverlapping(val(left, B), val(right, 0), B - 1);
+        if height > 0 {
+            ptr::copy_nonoverlapping(edge(left, B), edge(right, 0), B);
+        }
+        (*right).len = B - 1;
+        (*left).len = B;
+        let (k, v) = remove_kv(left, B - 1);
+        let plen = len(parent);
+        shift(edge(parent, 0), i + 1, i + 2, plen - i);
+        *edge(parent, i + 1) = right;
+        insert_kv(parent, i, k, v);
+    }
+
+    /// Remove the entry under `k`, returning its value, if there is one.
+    pub fn remove<Q: ?Sized + Ord>(&mut self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        self.remove_entry(Target::Key(k)).map(|(_, v)| v)
+    }
+
+    /// Remove and return the entry with the smallest key, if the map is not empty.
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        self.remove_entry(Target::Min::<K>)
+    }
+
+    /// Remove and return the entry with the largest key, if the map is not empty.
+    pub fn pop_last(&mut self) -> Option<(K, V)> {
+        self.remove_entry(Target::Max::<K>)
+    }
+
+    fn remove_entry<Q: ?Sized + Ord>(&mut self, target: Target<Q>) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        if self.root.is_null() {
+            return None;
+        }
+        unsafe {
+            let (root, height) = (self.root, self.height);
+            let res = self.remove_from(root, height, target);
+            if res.is_some() {
+                self.len -= 1;
+            }
+            if len(root) == 0 {
+                // The root's last entry moved into a merged child, or the map is now empty.
+                self.root = if height == 0 {
+                    ptr::null_mut()
+                } else {
+                    *edge(root, 0)
+                };
+                self.free_node(root, height);
+                self.height = height.saturating_sub(1);
+            }
+            res
+        }
+    }
+
+    /// Remove `target` from the subtree rooted at `n`, whose height is `height`. Unless `n` is the
+    /// root, it must have at least `B` entries, so that it can lose one.
+    unsafe fn remove_from<Q: ?Sized + Ord>(
+        &mut self,
+        mut n: NodePtr<K, V>,
+        mut height: usize,
+        target: Target<Q>,
+    ) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        loop {
+            let (i, found) = match target {
+                Target::Key(k) => search(n, k),
+                Target::Min => (0, height == 0 && len(n) > 0),
+                Target::Max => {
+                    let len = len(n);
+                    if height == 0 && len > 0 {
+                        (len - 1, true)
+                    } else {
+                        (len, false)
+                    }
+                }
+            };
+            if found && height == 0 {
+                return Some(remove_kv(n, i));
+            }
+            if found {
+                // Replace the entry with its predecessor or successor from a child that can
+                // spare an entry, or merge the children around it and remove it from the merged
+                // child.
+                let (left, right) = (*edge(n, i), *edge(n, i + 1));
+                if len(left) >= B {
+                    let (k, v) = self.remove_from(left, height - 1, Target::Max::<Q>).unwrap();
+                    return Some((
+                        mem::replace(&mut *key(n, i), k),
+                        mem::replace(&mut *val(n, i), v),
+                    ));
+                }
+                if len(right) >= B {
+                    let (k, v) = self.remove_from(right, height - 1, Target::Min::<Q>).unwrap();
+                    return Some((
+                        mem::replace(&mut *key(n, i), k),
+                        mem::replace(&mut *val(n, i), v),
+                    ));
+                }
+                self.merge(n, i, height - 1);
+                n = left;
+                height -= 1;
+                continue;
+            }
+            if height == 0 {
+                return None;
+            }
+            let i = self.fill(n, i, height - 1);
+            n = *edge(n, i);
+            height -= 1;
+        }
+    }
+
+    /// Make sure that the `i`th child of `n`, whose height is `height`, has at least `B`
+    /// entries, by moving an entry from a sibling through `n` or merging it with a sibling.
+    /// Returns the index of the child holding its former entries.
+    unsafe fn fill(&mut self, n: NodePtr<K, V>, i: usize, height: usize) -> usize {
+        let child = *edge(n, i);
+        let clen = len(child);
+        if clen >= B {
+            return i;
+        }
+        if i > 0 && len(*edge(n, i - 1)) >= B {
+            // Rotate an entry from the left sibling through `n`.
+            let left = *edge(n, i - 1);
+            let llen = len(left);
+            let (k, v) = remove_kv(left, llen - 1);
+            let k = mem::replace(&mut *key(n, i - 1), k);
+            let v = mem::replace(&mut *val(n, i - 1), v);
+            if height > 0 {
+                shift(edge(child, 0), 0, 1, clen + 1);
+                *edge(child, 0) = *edge(left, llen);
+            }
+            insert_kv(child, 0, k, v);
+            return i;
+        }
+        if i < len(n) && len(*edge(n, i + 1)) >= B {
+            // Rotate an entry from the right sibling through `n`.
+            let right = *edge(n, i + 1);
+            let first_edge = if height > 0 { *edge(right, 0) } else { ptr::null_mut() };
+            let (k, v) = remove_kv(right, 0);
+            if height > 0 {
+                shift(edge(right, 0), 1, 0, len(right) + 1);
+            }
+            let k = mem::replace(&mut *key(n, i), k);
+            let v = mem::replace(&mut *val(n, i), v);
+            insert_kv(child, clen, k, v);
+            if height > 0 {
+                *edge(child, clen + 1) = first_edge;
+            }
+            return i;
+        }
+        if i < len(n) {
+            self.merge(n, i, height);
+            i
+        } else {
+            self.merge(n, i - 1, height);
+            i - 1
+        }
+    }
+
+    /// Merge the `i + 1`th child of `n`, whose height is `height`, and the `i`th entry of `n`
+    /// into the `i`th child. The children must have `B - 1` entries each.
+    unsafe fn merge(&mut self, n: NodePtr<K, V>, i: usize, height: usize) {
+        let (left, right) = (*edge(n, i), *edge(n, i + 1));
+        let (llen, rlen) = (len(left), len(right));
+        let (k, v) = remove_kv(n, i);
+        shift(edge(n, 0), i + 2, i + 1, len(n) - i);
+        insert_kv(left, llen, k, v);
+        ptr::copy_nonoverlapping(key(right, 0), key(left, llen + 1), rlen);
+        ptr::copy_nonoverlapping(val(right, 0), val(left, llen + 1), rlen);
+        if height > 0 {
+            ptr::copy_nonoverlapping(edge(right, 0), edge(left, llen + 1), rlen + 1);
+        }
+        (*left).len = llen + 1 + rlen;
+        self.free_node(right, height);
+    }
+
+    /// An iterator over the entries of the map, in order of their keys.
+    pub fn iter(&self) -> Iter<K, V> {
+        let mut iter = Iter {
+            stack: Vec::new(),
+            remaining: self.len,
+            _marker: PhantomData,
+        };
+        if !self.root.is_null() {
+            unsafe { iter.descend(self.root, self.height) };
+        }
+        iter
+    }
+
+    /// Remove every entry, freeing every node.
+    pub fn clear(&mut self) {
+        if !self.root.is_null() {
+            let (root, height) = (self.root, self.height);
+            unsafe { self.free_subtree(root, height) };
+            self.root = ptr::null_mut();
+            self.len = 0;
+        }
+    }
+
+    unsafe fn free_subtree(&mut self, n: NodePtr<K, V>, height: usize) {
+        for i in 0..len(n) {
+            ptr::drop_in_place(key(n, i));
+            ptr::drop_in_place(val(n, i));
+        }
+        if height > 0 {
+            for i in 0..len(n) + 1 {
+                self.free_subtree(*edge(n, i), height - 1);
+            }
+        }
+        self.free_node(n, height);
+    }
+}
+
+impl<K, V, A: Alloc> Drop for ABTreeMap<K, V, A> {
+    fn drop(&mut self) {
+        // `clear` needs `K: Ord`, which `Drop` cannot require, so free the nodes here.
+        unsafe fn free<K, V, A: Alloc>(alloc: &mut A, n: NodePtr<K, V>, height: usize) {
+            for i in 0..len(n) {
+                ptr::drop_in_place(key(n, i));
+                ptr::drop_in_place(val(n, i));
+            }
+            let layout = if height == 0 {
+                Layout::new::<Leaf<K, V>>()
+            } else {
+                for i in 0..len(n) + 1 {
+                    free(alloc, *edge(n, i), height - 1);
+                }
+                Layout::new::<Internal<K, V>>()
+            };
+            alloc.dealloc(n as *mut u8, layout);
+        }
+        if !self.root.is_null() {
+            unsafe { free(&mut self.alloc, self.root, self.height) };
+        }
+    }
+}
+
+impl<K: Ord + fmt::Debug, V: fmt::Debug, A: Alloc> fmt::Debug for ABTreeMap<K, V, A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<K: Ord, V, A: Alloc> Extend<(K, V)> for ABTreeMap<K, V, A> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iterable: I) {
+        for (k, v) in iterable {
+            self.insert(k, v);
+        }
+    }
+}
+
+/// An in-order iterator over the entries of an `ABTreeMap`, created by `ABTreeMap::iter`.
+pub struct Iter<'a, K: 'a, V: 'a> {
+    /// The path to the next entry: each node, its height, and the index of the next entry in it.
+    stack: Vec<(NodePtr<K, V>, usize, usize)>,
+    remaining: usize,
+    _marker: PhantomData<&'a (K, V)>,
+}
+
+impl<'a, K: 'a, V: 'a> Iter<'a, K, V> {
+    /// Push `n` and the leftmost path below it.
+    unsafe fn descend(&mut self, mut n: NodePtr<K, V>, mut height: usize) {
+        loop {
+            self.stack.push((n, height, 0));
+            if height == 0 {
+                return;
+            }
+            n = *edge(n, 0);
+            height -= 1;
+        }
+    }
+}
+
+impl<'a, K: 'a, V: 'a> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        unsafe {
+            loop {
+                let (n, height, i) = match self.stack.last_mut() {
+                    Some(top) => {
+                        let cur = *top;
+                        top.2 += 1;
+                        cur
+                    }
+                    None => return None,
+                };
+                if i == len(n) {
+                    self.stack.pop();
+                    continue;
+                }
+                if height > 0 {
+                    self.descend(*edge(n, i + 1), height - 1);
+                }
+                self.remaining -= 1;
+                return Some((&*key(n, i), &*val(n, i)));
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+    use self::test::Bencher;
+
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+
+    type Map<K, V> = ABTreeMap<K, V, SharedAlloc>;
+
+    #[test]
+    fn matches_std() {
+        let mut map = Map::default();
+        let mut expected = BTreeMap::new();
+        let mut x = 1usize;
+        // Enough keys for a tree several levels deep, with a mix of insertions, replacements and
+        // removals, so that every rotation and merge happens.
+        for round in 0..20_000 {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let k = (x >> 8) % 4096;
+            if round % 3 == 2 {
+                alloc_assert_eq!(map.remove(&k), expected.remove(&k));
+            } else {
+                alloc_assert_eq!(map.insert(k, round), expected.insert(k, round));
+            }
+            alloc_assert_eq!(map.len(), expected.len());
+        }
+        alloc_assert!(map.iter().eq(expected.iter()));
+        alloc_assert_eq!(map.iter().size_hint().0, map.len());
+        for k in 0..4096 {
+            alloc_assert_eq!(map.get(&k), expected.get(&k));
+        }
+        if let Some(v) = map.get_mut(&expected.keys().next().cloned().unwrap()) {
+            *v = !0;
+        }
+        alloc_assert_eq!(map.iter().next().map(|(_, &v)| v), Some(!0));
+        // Drain from both ends.
+        while !map.is_empty() {
+            let first = map.pop_first().unwrap();
+            alloc_assert!(map.iter().all(|(&k, _)| k > first.0));
+            if let Some(last) = map.pop_last() {
+                alloc_assert!(map.iter().all(|(&k, _)| k < last.0));
+            }
+        }
+        alloc_assert_eq!(map.remove(&0), None);
+        map.insert(1, 1);
+        alloc_assert_eq!(map.pop_last(), Some((1, 1)));
+    }
+
+    #[test]
+    fn drops() {
+        let rc = Rc::new(());
+        {
+            let mut map = Map::default();
+            for i in 0..1000 {
+                map.insert(i, rc.clone());
+            }
+            for i in 0..500 {
+                drop(map.remove(&(i * 2)));
+            }
+            alloc_assert_eq!(Rc::strong_count(&rc), 501);
+            map.clear();
+            alloc_assert_eq!(Rc::strong_count(&rc), 1);
+            map.extend((0..100).map(|i| (i, rc.clone())));
+        }
+        alloc_assert_eq!(Rc::strong_count(&rc), 1);
+    }
+
+    /// Insert `1 << 14` pseudo-random keys, look each of them up, and remove them all.
+    fn bench_map<M, I, G, R>(b: &mut Bencher, new: fn() -> M, insert: I, get: G, remove: R)
+    where
+        I: Fn(&mut M, usize),
+        G: Fn(&M, usize) -> bool,
+        R: Fn(&mut M, usize),
+    {
+        b.iter(|| {
+            let mut map = new();
+            let keys = (0..(1usize << 14)).map(|i| i.wrapping_mul(0x9e37_79b9) & 0xffff_ffff);
+            for k in keys.clone() {
+                insert(&mut map, k);
+            }
+            for k in keys.clone() {
+                alloc_assert!(get(&map, k));
+            }
+            for k in keys {
+                remove(&mut map, k);
+            }
+            test::black_box(map)
+        });
+    }
+
+    #[bench]
+    fn bench_abtreemap_elf(b: &mut Bencher) {
+        bench_map(
+            b,
+            ABTreeMap::<usize, usize, DynamicAlloc>::default,
+            |m, k| drop(m.insert(k, k)),
+            |m, k| m.contains_key(&k),
+            |m, k| drop(m.remove(&k)),
+        );
+    }
+
+    #[bench]
+    fn bench_abtreemap_heap(b: &mut Bencher) {
+        bench_map(
+            b,
+            ABTreeMap::<usize, usize, Heap>::default,
+            |m, k| drop(m.insert(k, k)),
+            |m, k| m.contains_key(&k),
+            |m, k| drop(m.remove(&k)),
+        );
+    }
+
+    #[bench]
+    fn bench_std_btreemap(b: &mut Bencher) {
+        bench_map(
+            b,
+            BTreeMap::<usize, usize>::new,
+            |m, k| drop(m.insert(k, k)),
+            |m, k| m.contains_key(&k),
+            |m, k| drop(m.remove(&k)),
+        );
+    }
+}
@@ -0,0 +1,285 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A buddy allocator for power-of-two blocks of pages.
+//!
+//! This is an alternative to the `PageSource` for medium-sized objects in the `rust_alloc`
+//! module. Where a `PageSource` hands out an entire (large) page for every object regardless of
+//! its size, a `BuddySource` splits its memory into power-of-two blocks that are only as large as
+//! the request, and merges freed blocks with their buddies in O(log n) steps.
+//!
+//! Memory is acquired from a `MemorySource` in *arenas* that are aligned to their size, which
+//! lets us find the arena (and thus its metadata) of any block by masking off low bits. Each arena
+//! is twice the size of the largest block. Its first block is reserved for a table holding one
+//! byte per minimum-size block; the entry for a block's first slot records whether the block is
+//! free and, if so, its order. Free blocks are kept in intrusive doubly-linked lists, one per
+//! order.
+//!
+//! All state is protected by a single lock. This is acceptable because the `PageFrontend`s in
+//! front of a `BuddySource` cache blocks, so the lock is only taken when those caches miss or
+//! overflow.
+use super::sources::MemorySource;
+use super::utils::{mmap, with_addr};
+use super::utils::mmap::MapName;
+
+use std::cmp;
+use std::mem;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+
+/// Set in a table entry if the block starting at that slot is free.
+const FREE: u8 = 1 << 7;
+
+/// The header written at the start of every free block.
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
+struct BuddyHeap<M: MemorySource> {
+    source: M,
+    /// log2 of the smallest block size.
+    min_order: usize,
+    /// log2 of the largest block size. Arenas are of size `1 << (max_order + 1)`.
+    max_order: usize,
+    /// Blocks of at least this size are uncommitted (save for their first page) when freed.
+    cutoff_bytes: usize,
+    /// The head of the free list for each order.
+    free: [*mut FreeBlock; 64],
+    /// The name given to fresh arenas.
+    name: MapName,
+}
+
+unsafe impl<M: MemorySource> Send for BuddyHeap<M> {}
+
+/// A shared, thread-safe buddy allocator. Clones refer to the same underlying heap.
+#[derive(Clone)]
+pub struct BuddySource<M: MemorySource> {
+    heap: Arc<Mutex<BuddyHeap<M>>>,
+    max_size: usize,
+}
+
+impl<M: MemorySource> BuddySource<M> {
+    /// Create a new `BuddySource` handing out blocks between `min_size` and `max_size` bytes.
+    ///
+    /// Both sizes are rounded up to powers of two; `min_size` is at least a system page. Blocks
+    /// of size at least `cutoff_bytes` have their memory returned to the operating system when
+    /// they are freed.
+    pub fn new(min_size: usize, max_size: usize, cutoff_bytes: usize) -> BuddySource<M> {
+        BuddySource::with_source(min_size, max_size, cutoff_bytes, M::new)
+    }
+
+    /// Create a new `BuddySource` like `new`, whose arenas are carved from `source(arena_size)`.
+    pub fn with_source<F: FnOnce(usize) -> M>(
+        min_size: usize,
+        max_size: usize,
+        cutoff_bytes: usize,
+        source: F,
+    ) -> BuddySource<M> {
+        let min_size = cmp::max(min_size, mmap::page_size()).next_power_of_two();
+        let max_size = cmp::max(min_size, max_size.next_power_of_two());
+        let min_order = min_size.trailing_zeros() as usize;
+        let max_order = max_size.trailing_zeros() as usize;
+        alloc_assert!(max_order < 63);
+        BuddySource {
+            heap: Arc::new(Mutex::new(BuddyHeap {
+                source: source(max_size * 2),
+                min_order: min_order,
+                max_order: max_order,
+                cutoff_bytes: cutoff_bytes,
+                free: [ptr::null_mut(); 64],
+                name: MapName::new(None, "medium"),
+            })),
+            max_size: max_size,
+        }
+    }
+
+    /// Name the mappings of fresh arenas `name` (see `mmap::name`) rather than
+    /// `elfmalloc:medium`. This applies to all clones of this `BuddySource`.
+    pub fn set_name(&self, name: MapName) {
+        self.heap.lock().unwrap().name = name;
+    }
+
+    /// The size of the largest block that can be allocated.
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Whether blocks handed out by this `BuddySource` may be unmapped individually. See
+    /// `MemorySource::unmaps_pages`.
+    pub fn unmaps_pages(&self) -> bool {
+        self.heap.lock().unwrap().source.unmaps_pages()
+    }
+
+    /// Allocate a block of at least `size` bytes, aligned to its size.
+    pub unsafe fn alloc(&self, size: usize) -> Option<*mut u8> {
+        self.heap.lock().unwrap().alloc(size)
+    }
+
+    /// Free a block previously returned by `alloc(size)`.
+    pub unsafe fn free(&self, p: *mut u8, size: usize) {
+        self.heap.lock().unwrap().free(p, size)
+    }
+}
+
+impl<M: MemorySource> BuddyHeap<M> {
+    fn arena_size(&self) -> usize {
+        1 << (self.max_order + 1)
+    }
+
+    fn order_of(&self, size: usize) -> usize {
+        cmp::max(self.min_order, size.next_power_of_two().trailing_zeros() as usize)
+    }
+
+    /// Get the table entry for the block starting at `p`.
+    unsafe fn entry(&self, p: *mut u8) -> *mut u8 {
+        let arena = (p as usize) & !(self.arena_size() - 1);
+        let slot = ((p as usize) - arena) >> self.min_order;
+        (arena as *mut u8).offset(slot as isize)
+    }
+
+    unsafe fn push(&mut self, p: *mut u8, order: usize) {
+        let block = p as *mut FreeBlock;
+        let head = self.free[order];
+        ptr::write(
+            block,
+            FreeBlock {
+                next: head,
+                prev: ptr::null_mut(),
+            },
+        );
+        if !head.is_null() {
+            (*head).prev = block;
+        }
+        self.free[order] = block;
+        *self.entry(p) = FREE | order as u8;
+    }
+
+    unsafe fn remove(&mut self, p: *mut u8, order: usize) {
+        let block = p as *mut FreeBlock;
+        let FreeBlock { next, prev } = ptr::read(block);
+        if prev.is_null() {
+            self.free[order] = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        *self.entry(p) = 0;
+    }
+
+    /// Map a new arena and add its blocks to the free lists.
+    unsafe fn refresh(&mut self) -> bool {
+        let arena = match self.source.carve(1) {
+            Some(arena) => arena,
+            None => return false,
+        };
+        alloc_debug_assert_eq!(arena as usize % self.arena_size(), 0);
+        mmap::name(arena, self.arena_size(), &self.name);
+        // The table needs one byte per minimum-size block; it occupies the first block(s).
+        let table_bytes = self.arena_size() >> self.min_order;
+        let table_order = self.order_of(table_bytes);
+        alloc_assert!(table_order <= self.max_order, "buddy table does not fit in an arena");
+        for order in table_order..(self.max_order + 1) {
+            self.push(arena.offset(1 << order), order);
+        }
+        true
+    }
+
+    unsafe fn alloc(&mut self, size: usize) -> Option<*mut u8> {
+        let order = self.order_of(size);
+        if order > self.max_order {
+            return None;
+        }
+        let mut from = order;
+        while from <= self.max_order && self.free[from].is_null() {
+            from += 1;
+        }
+        if from > self.max_order {
+            if !self.refresh() {
+                return None;
+            }
+            return self.alloc(size);
+        }
+        let block = self.free[from] as *mut u8;
+        self.remove(block, from);
+        while from > order {
+            from -= 1;
+            self.push(block.offset(1 << from), from);
+        }
+        Some(block)
+    }
+
+    unsafe fn free(&mut self, p: *mut u8, size: usize) {
+        let mut order = self.order_of(size);
+        let mut block = p as usize;
+        let arena = block & !(self.arena_size() - 1);
+        while order < self.max_order {
+            let buddy = arena + ((block - arena) ^ (1 << order));
+            if *self.entry(with_addr(p, buddy)) != FREE | order as u8 {
+                break;
+            }
+            self.remove(with_addr(p, buddy), order);
+            block = cmp::min(block, buddy);
+            order += 1;
+        }
+        let len = 1 << order;
+        let page_size = mmap::page_size();
+        if len >= self.cutoff_bytes && len > page_size {
+            mmap::uncommit(with_addr(p, block + page_size), len - page_size);
+        }
+        alloc_debug_assert!(len >= mem::size_of::<FreeBlock>());
+        self.push(with_addr(p, block), order);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    use super::*;
+    use super::super::sources::MmapSource;
+
+    #[test]
+    fn buddy_split_and_merge() {
+        let _ = env_logger::init();
+        let buddy = BuddySource::<MmapSource>::new(16 << 10, 1 << 20, 256 << 10);
+        unsafe {
+            let sizes = [16 << 10, 20 << 10, 64 << 10, 1 << 20, 16 << 10, 200 << 10];
+            let blocks: Vec<*mut u8> = sizes
+                .iter()
+                .map(|&size| {
+                    let p = buddy.alloc(size).unwrap();
+                    alloc_assert_eq!(p as usize % size.next_power_of_two(), 0);
+                    ptr::write_bytes(p, 0xFF, size);
+                    p
+                })
+                .collect();
+            for (i, &p) in blocks.iter().enumerate() {
+                for (j, &q) in blocks.iter().enumerate().filter(|&(j, _)| j != i) {
+                    let (lo, hi) = (p as usize, p as usize + sizes[i]);
+                    alloc_assert!(
+                        (q as usize) < lo || (q as usize) >= hi,
+                        "blocks {} and {} overlap",
+                        i,
+                        j
+                    );
+                }
+            }
+            for (&p, &size) in blocks.iter().zip(sizes.iter()) {
+                buddy.free(p, size);
+            }
+            // Everything has been merged back, so every largest block is available again.
+            let a = buddy.alloc(1 << 20).unwrap();
+            let b = buddy.alloc(1 << 20).unwrap();
+            alloc_assert!(a != b);
+            buddy.free(a, 1 << 20);
+            buddy.free(b, 1 << 20);
+        }
+        alloc_assert!(buddy.alloc(2 << 20).is_none());
+    }
+}
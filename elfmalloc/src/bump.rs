@@ -0,0 +1,310 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A bump (linear) allocator.
+//!
+//! `BumpAlloc` hands out memory by advancing a pointer through fixed-size chunks obtained from a
+//! `PageSource`, the same page cache used by the `rust_alloc` module. Deallocation is a no-op,
+//! except that freeing the most recent allocation makes its memory available again. All memory is
+//! reclaimed at once with `reset`, which makes this a good fit for request-scoped workloads and a
+//! useful baseline for benchmarks.
+//!
+//! Each chunk begins with a pointer to the previously allocated chunk, so the allocator needs no
+//! metadata beyond a handful of pointers. Objects that do not fit in a chunk are mapped directly
+//! and kept on a separate list; these are unmapped by `reset`.
+
+use super::alloc::allocator::{Alloc, AllocErr, Layout};
+use super::slag::PageSource;
+use super::sources::MmapSource;
+use super::utils::{likely, mmap, with_addr};
+use super::utils::mmap::MapName;
+#[cfg(feature = "valgrind")]
+use super::valgrind;
+
+use std::cmp;
+use std::mem;
+use std::ptr;
+
+/// A bump allocator. See the module documentation for details.
+pub struct BumpAlloc {
+    source: PageSource<MmapSource>,
+    /// The next free byte in the current chunk.
+    cur: *mut u8,
+    /// The end of the current chunk.
+    end: *mut u8,
+    /// The current chunk. Its first word points to the previous chunk.
+    chunk: *mut u8,
+    /// The most recent large allocation. Its header holds the mapping's size and a pointer to the
+    /// previous large allocation.
+    large: *mut LargeHeader,
+    /// The identity of this allocator's Valgrind mempool. The allocation only serves to provide a
+    /// unique address that stays put when the `BumpAlloc` is moved.
+    #[cfg(feature = "valgrind")]
+    pool: Box<u8>,
+}
+
+unsafe impl Send for BumpAlloc {}
+
+struct LargeHeader {
+    next: *mut LargeHeader,
+    map_size: usize,
+}
+
+pub struct BumpAllocBuilder {
+    chunk_size: usize,
+    cached_chunks: usize,
+}
+
+impl Default for BumpAllocBuilder {
+    fn default() -> BumpAllocBuilder {
+        BumpAllocBuilder {
+            chunk_size: 256 << 10,
+            cached_chunks: 64,
+        }
+    }
+}
+
+impl BumpAllocBuilder {
+    /// The size of the chunks from which objects are allocated. Rounded up to a power of two.
+    pub fn chunk_size(&mut self, chunk_size: usize) -> &mut BumpAllocBuilder {
+        self.chunk_size = cmp::max(mmap::page_size(), chunk_size).next_power_of_two();
+        self
+    }
+
+    /// The number of chunks freed by `reset` that are kept around for reuse rather than unmapped.
+    pub fn cached_chunks(&mut self, cached_chunks: usize) -> &mut BumpAllocBuilder {
+        self.cached_chunks = cached_chunks;
+        self
+    }
+
+    pub fn build(&self) -> BumpAlloc {
+        // Chunks are never partially uncommitted: they are about to be reused, and the first
+        // thing a new chunk does is write to its first page anyway.
+        let mut source = PageSource::new(!0, self.cached_chunks, 1, self.chunk_size);
+        source.set_name(MapName::new(None, "bump"));
+        let mut res = BumpAlloc {
+            source: source,
+            cur: ptr::null_mut(),
+            end: ptr::null_mut(),
+            chunk: ptr::null_mut(),
+            large: ptr::null_mut(),
+            #[cfg(feature = "valgrind")]
+            pool: Box::new(0),
+        };
+        #[cfg(feature = "valgrind")]
+        unsafe {
+            valgrind::create_mempool(&*res.pool)
+        };
+        let mapped = unsafe { res.new_chunk() };
+        alloc_assert!(mapped, "[BumpAlloc::build] mmap failed");
+        res
+    }
+}
+
+impl BumpAlloc {
+    pub fn new() -> BumpAlloc {
+        BumpAllocBuilder::default().build()
+    }
+
+    fn chunk_size(&self) -> usize {
+        self.source.page_size()
+    }
+
+    /// Start allocating from a fresh chunk. Returns `false` if no memory was available.
+    unsafe fn new_chunk(&mut self) -> bool {
+        match self.source.alloc() {
+            Some(chunk) => {
+                // The chunk may have been handed out by a previous incarnation of the mempool.
+                #[cfg(feature = "valgrind")]
+                {
+                    let word = mem::size_of::<*mut u8>();
+                    valgrind::make_mem_undefined(chunk, word);
+                    valgrind::make_mem_noaccess(
+                        chunk.offset(word as isize),
+                        self.chunk_size() - word,
+                    );
+                }
+                ptr::write(chunk as *mut *mut u8, self.chunk);
+                self.chunk = chunk;
+                self.cur = chunk.offset(mem::size_of::<*mut u8>() as isize);
+                self.end = chunk.offset(self.chunk_size() as isize);
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[inline(always)]
+    fn bump(&mut self, l: &Layout) -> Option<*mut u8> {
+        let start = (self.cur as usize + l.align() - 1) & !(l.align() - 1);
+        let end = start.wrapping_add(l.size());
+        if likely(end <= self.end as usize && end >= start) {
+            let res = with_addr(self.cur, start);
+            self.cur = with_addr(self.cur, end);
+            Some(res)
+        } else {
+            None
+        }
+    }
+
+    #[cold]
+    unsafe fn alloc_slow(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        let payload = self.chunk_size() - mem::size_of::<*mut u8>();
+        if l.size() + l.align() > payload {
+            return self.alloc_large(l);
+        }
+        if !self.new_chunk() {
+            return Err(AllocErr::Exhausted { request: l });
+        }
+        Ok(self.bump(&l).expect("fresh chunk too small"))
+    }
+
+    unsafe fn alloc_large(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        let page_size = mmap::page_size();
+        if l.align() > page_size {
+            return Err(AllocErr::Unsupported {
+                details: "BumpAlloc does not support alignments larger than a page",
+            });
+        }
+        // The header gets a page to itself so that the object is page-aligned.
+        let map_size = page_size + ((l.size() + page_size - 1) & !(page_size - 1));
+        match mmap::fallible_map(map_size) {
+            Some(mem) => {
+                mmap::name(mem, map_size, &MapName::new(None, "bump"));
+                let header = mem as *mut LargeHeader;
+                ptr::write(
+                    header,
+                    LargeHeader {
+                        next: self.large,
+                        map_size: map_size,
+                    },
+                );
+                self.large = header;
+                Ok(mem.offset(page_size as isize))
+            }
+            None => Err(AllocErr::Exhausted { request: l }),
+        }
+    }
+
+    /// Free every object allocated so far.
+    ///
+    /// All chunks but the current one are returned to the `PageSource`, and all large objects are
+    /// unmapped. Pointers obtained before the call must not be used afterwards.
+    pub fn reset(&mut self) {
+        unsafe {
+            self.release_large();
+            let chunk_size = self.chunk_size();
+            let mut prev = ptr::read(self.chunk as *mut *mut u8);
+            while !prev.is_null() {
+                let next = ptr::read(prev as *mut *mut u8);
+                self.source.free(prev, chunk_size);
+                prev = next;
+            }
+            ptr::write(self.chunk as *mut *mut u8, ptr::null_mut());
+            self.cur = self.chunk.offset(mem::size_of::<*mut u8>() as isize);
+            #[cfg(feature = "valgrind")]
+            {
+                valgrind::destroy_mempool(&*self.pool);
+                valgrind::create_mempool(&*self.pool);
+                valgrind::make_mem_noaccess(self.cur, self.end as usize - self.cur as usize);
+            }
+        }
+    }
+
+    unsafe fn release_large(&mut self) {
+        while !self.large.is_null() {
+            let LargeHeader { next, map_size } = ptr::read(self.large);
+            mmap::unmap(self.large as *mut u8, map_size);
+            self.large = next;
+        }
+    }
+}
+
+impl Drop for BumpAlloc {
+    fn drop(&mut self) {
+        unsafe {
+            #[cfg(feature = "valgrind")]
+            valgrind::destroy_mempool(&*self.pool);
+            self.release_large();
+            let chunk_size = self.chunk_size();
+            let mut chunk = self.chunk;
+            while !chunk.is_null() {
+                let next = ptr::read(chunk as *mut *mut u8);
+                mmap::unmap(chunk, chunk_size);
+                chunk = next;
+            }
+        }
+    }
+}
+
+unsafe impl Alloc for BumpAlloc {
+    #[inline(always)]
+    unsafe fn alloc(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        #[cfg(feature = "valgrind")]
+        let size = l.size();
+        let res = match self.bump(&l) {
+            Some(p) => Ok(p),
+            None => self.alloc_slow(l),
+        };
+        #[cfg(feature = "valgrind")]
+        {
+            if let Ok(&p) = res.as_ref() {
+                valgrind::mempool_alloc(&*self.pool, p, size);
+            }
+        }
+        res
+    }
+
+    #[inline(always)]
+    unsafe fn dealloc(&mut self, item: *mut u8, l: Layout) {
+        #[cfg(feature = "valgrind")]
+        valgrind::mempool_free(&*self.pool, item);
+        // Only the most recent allocation can be reclaimed before `reset`.
+        if item.offset(l.size() as isize) == self.cur {
+            self.cur = item;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    use super::*;
+
+    #[test]
+    fn bump_reset() {
+        let _ = env_logger::init();
+        let mut ba = BumpAllocBuilder::default().chunk_size(64 << 10).build();
+        let word = Layout::new::<usize>();
+        unsafe {
+            let first = ba.alloc(word.clone()).unwrap();
+            let second = ba.alloc(word.clone()).unwrap();
+            alloc_assert_eq!(second as usize, first as usize + mem::size_of::<usize>());
+            ba.dealloc(second, word.clone());
+            alloc_assert_eq!(ba.alloc(word.clone()).unwrap(), second);
+
+            let aligned = ba.alloc(Layout::from_size_align(100, 256).unwrap()).unwrap();
+            alloc_assert_eq!(aligned as usize % 256, 0);
+
+            for _ in 0..2 {
+                let ptrs: Vec<*mut usize> = (0..(64 << 10))
+                    .map(|i| {
+                        let p = ba.alloc(word.clone()).unwrap() as *mut usize;
+                        ptr::write(p, i);
+                        p
+                    })
+                    .collect();
+                let large = ba.alloc(Layout::from_size_align(1 << 20, 8).unwrap()).unwrap();
+                ptr::write_bytes(large, 0xFF, 1 << 20);
+                for (i, p) in ptrs.into_iter().enumerate() {
+                    alloc_assert_eq!(*p, i);
+                }
+                ba.reset();
+            }
+        }
+    }
+}
@@ -0,0 +1,413 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Runtime configuration through the `ELFMALLOC_CONF` environment variable.
+//!
+//! `ELFMALLOC_CONF` holds a comma-separated list of `key:value` pairs, in the manner of
+//! jemalloc's `MALLOC_CONF`. For example:
+//!
+//! ```text
+//! ELFMALLOC_CONF=randomize:true,quarantine_size:1m
+//! ```
+//!
+//! The following keys are recognized:
+//!
+//! - `randomize` (`true` or `false`, default `false`): randomize where objects are placed. Within
+//!   a slab, each allocation picks a random free slot among those the allocating thread has
+//!   claimed, rather than the lowest one; and the slabs carved out of freshly mapped memory are
+//!   handed out in a random order rather than by increasing address. This makes it harder to
+//!   groom the heap into a layout where an overflowing or dangling object is adjacent to a
+//!   chosen victim. The random numbers come from a fast non-cryptographic generator.
+//! - `quarantine_size` (a size, with an optional `k`, `m` or `g` suffix): the byte budget of each
+//!   quarantine (see the `quarantine` module). Only recognized with the `quarantine` feature.
+//! - `prefault` (`true` or `false`, default `false`): fault in each page of the heap when it
+//!   is first handed to a size class, rather than when objects in it are first written. This
+//!   moves page faults out of the code that uses the objects, which matters for latency-critical
+//!   programs. See also `global::prefault`.
+//! - `on_violation` (`abort` or `continue`, default `abort`): what to do when misuse of the heap
+//!   is detected (see the `integrity` module).
+//! - `defer_unmap` (`true` or `false`, default `false`): hand full batches of freed large object
+//!   mappings to the background thread to be unmapped, rather than unmapping them on the thread
+//!   that frees them (see the `unmap_batch` module). Only recognized with the `batch-unmap`
+//!   feature.
+//! - `shrink_threshold` (a size, default `64k`): when `realloc` shrinks a large object by at least
+//!   this many bytes, the pages past its new end are uncommitted so that they no longer count
+//!   towards the resident set size. 0 disables this.
+//! - `cache_decay_ms` (a number of milliseconds, default 10000): how long a thread's cache for a
+//!   size class may go unused before its objects are freed back to their slabs. 0 disables decay.
+//!   Only recognized with the `cache-decay` feature (see the `decay` module).
+//! - `name_mappings` (`true` or `false`, default `true`): name the memory mappings of the heap
+//!   after what they hold (`elfmalloc:small`, `elfmalloc:medium` and `elfmalloc:large`, or
+//!   `elfmalloc:<heap>:small` and so on for a named `IsolatedHeap`), so that they show up as e.g.
+//!   `[anon:elfmalloc:small]` in `/proc/<pid>/maps`. This needs Linux 5.17 or later; elsewhere,
+//!   the option has no effect.
+//! - `backoff_spin_limit` (a number, default 6) and `backoff_max_park_us` (a number of
+//!   microseconds, default 128): how operations on the shared backend that fail because of
+//!   contention are retried. A thread spins for `backoff_spin_limit` rounds of exponentially
+//!   growing length, then yields a few times, then sleeps for exponentially longer up to
+//!   `backoff_max_park_us` per round, so that retries do not starve the threads they are waiting
+//!   for when there are more threads than cores. A `backoff_max_park_us` of 0 disables sleeping.
+//!   See the `backoff` module of the `bagpipe` crate.
+//! - `demand_commit` (`true` or `false`, default `false`): commit the memory of `Region`s when it
+//!   is first touched, from a `SIGSEGV` handler, rather than when it is handed out by
+//!   `Region::commit`. Experimental; only recognized with the `demand-commit` feature (see the
+//!   `demand` module).
+//! - `dump_path` (a path prefix, unset by default): on receiving `dump_signal`, write the
+//!   statistics and the heap profile to files whose names start with this (see the `dump`
+//!   module). Only recognized on Linux.
+//! - `dump_signal` (`usr1` or `usr2`, default `usr2`): the signal that triggers a dump when
+//!   `dump_path` is set. Only recognized on Linux.
+//!
+//! The defaults given for `randomize`, `quarantine_size`, `shrink_threshold` and `cache_decay_ms`
+//! are those of the default build; the `profile-*` features change them (see the `profile`
+//! module).
+//!
+//! Unknown keys and malformed values are reported on standard error and otherwise ignored.
+//!
+//! The variable is read once, the first time the allocator needs one of the settings. Reading it
+//! does not allocate, so it is safe to do from within `malloc`. Allocations made by other threads
+//! while the variable is being read use the default settings.
+#[cfg(not(miri))]
+extern crate libc;
+
+use std::str;
+use super::integrity::{self, ViolationPolicy};
+use super::profile::PROFILE;
+use super::bagpipe::backoff;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+
+const UNINIT: usize = 0;
+const PARSING: usize = 1;
+const READY: usize = 2;
+
+static STATE: AtomicUsize = ATOMIC_USIZE_INIT;
+static RANDOMIZE: AtomicBool = ATOMIC_BOOL_INIT;
+static PREFAULT: AtomicBool = ATOMIC_BOOL_INIT;
+#[cfg(feature = "batch-unmap")]
+static DEFER_UNMAP: AtomicBool = ATOMIC_BOOL_INIT;
+static SHRINK_THRESHOLD: AtomicUsize = ATOMIC_USIZE_INIT;
+static NAME_MAPPINGS: AtomicBool = ATOMIC_BOOL_INIT;
+#[cfg(feature = "demand-commit")]
+static DEMAND_COMMIT: AtomicBool = ATOMIC_BOOL_INIT;
+#[cfg(feature = "cache-decay")]
+static CACHE_DECAY_MS: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The address and length of the `dump_path` value within `ELFMALLOC_CONF`, or 0 if it is unset.
+#[cfg(target_os = "linux")]
+static DUMP_PATH_PTR: AtomicUsize = ATOMIC_USIZE_INIT;
+#[cfg(target_os = "linux")]
+static DUMP_PATH_LEN: AtomicUsize = ATOMIC_USIZE_INIT;
+#[cfg(target_os = "linux")]
+static DUMP_SIGNAL: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Is randomized placement enabled?
+#[inline]
+pub fn randomize() -> bool {
+    init();
+    RANDOMIZE.load(Ordering::Relaxed)
+}
+
+/// Enable or disable randomized placement, overriding `ELFMALLOC_CONF`.
+///
+/// Only slabs and slots chosen after the call are affected.
+pub fn set_randomize(enabled: bool) {
+    init();
+    RANDOMIZE.store(enabled, Ordering::Relaxed);
+}
+
+/// Are pages faulted in as soon as they are handed out?
+///
+/// This is read when the global allocator is created, so changing it afterwards has no effect.
+#[inline]
+pub fn prefault() -> bool {
+    init();
+    PREFAULT.load(Ordering::Relaxed)
+}
+
+/// Are batches of large object mappings unmapped by the background thread?
+#[cfg(feature = "batch-unmap")]
+#[inline]
+pub fn defer_unmap() -> bool {
+    init();
+    DEFER_UNMAP.load(Ordering::Relaxed)
+}
+
+/// Enable or disable deferred unmapping, overriding `ELFMALLOC_CONF`.
+///
+/// Mappings that are already in a thread's batch are unmapped according to the setting in effect
+/// when the batch fills up.
+#[cfg(feature = "batch-unmap")]
+pub fn set_defer_unmap(enabled: bool) {
+    init();
+    DEFER_UNMAP.store(enabled, Ordering::Relaxed);
+}
+
+/// The number of bytes that `realloc` must free at the end of a large object for the pages to be
+/// uncommitted, or 0 if they never are.
+#[inline]
+pub fn shrink_threshold() -> usize {
+    init();
+    SHRINK_THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// Set the shrink threshold for large objects, overriding `ELFMALLOC_CONF`. 0 disables
+/// uncommitting pages on `realloc`.
+pub fn set_shrink_threshold(bytes: usize) {
+    init();
+    SHRINK_THRESHOLD.store(bytes, Ordering::Relaxed);
+}
+
+/// Are the heap's mappings named after what they hold?
+#[inline]
+pub fn name_mappings() -> bool {
+    init();
+    NAME_MAPPINGS.load(Ordering::Relaxed)
+}
+
+/// Enable or disable naming mappings, overriding `ELFMALLOC_CONF`. Mappings that have already
+/// been named keep their names.
+pub fn set_name_mappings(enabled: bool) {
+    init();
+    NAME_MAPPINGS.store(enabled, Ordering::Relaxed);
+}
+
+/// Are new `Region`s committed on demand?
+#[cfg(feature = "demand-commit")]
+#[inline]
+pub fn demand_commit() -> bool {
+    init();
+    DEMAND_COMMIT.load(Ordering::Relaxed)
+}
+
+/// Enable or disable commit on demand, overriding `ELFMALLOC_CONF`. Only `Region`s reserved after
+/// the call are affected.
+#[cfg(feature = "demand-commit")]
+pub fn set_demand_commit(enabled: bool) {
+    init();
+    DEMAND_COMMIT.store(enabled, Ordering::Relaxed);
+}
+
+/// How long, in milliseconds, a thread cache may go unused before it is freed, or 0 if caches
+/// never decay.
+#[cfg(feature = "cache-decay")]
+#[inline]
+pub fn cache_decay_ms() -> usize {
+    init();
+    CACHE_DECAY_MS.load(Ordering::Relaxed)
+}
+
+/// Set the decay interval of thread caches, overriding `ELFMALLOC_CONF`. 0 disables decay.
+///
+/// Threads pick up the new interval at their next check, which may take up to a quarter of the
+/// old interval.
+#[cfg(feature = "cache-decay")]
+pub fn set_cache_decay_ms(ms: usize) {
+    init();
+    CACHE_DECAY_MS.store(ms, Ordering::Relaxed);
+}
+
+/// The prefix of the names of dump files, if dumps are enabled.
+#[cfg(target_os = "linux")]
+pub fn dump_path() -> Option<&'static [u8]> {
+    init();
+    let ptr = DUMP_PATH_PTR.load(Ordering::Relaxed);
+    if ptr == 0 {
+        return None;
+    }
+    let len = DUMP_PATH_LEN.load(Ordering::Relaxed);
+    Some(unsafe { ::std::slice::from_raw_parts(ptr as *const u8, len) })
+}
+
+/// The signal that triggers a dump: 1 for `SIGUSR1` or 2 for `SIGUSR2`.
+#[cfg(target_os = "linux")]
+pub fn dump_signal() -> usize {
+    init();
+    DUMP_SIGNAL.load(Ordering::Relaxed)
+}
+
+#[inline]
+fn init() {
+    if STATE.load(Ordering::Acquire) == READY {
+        return;
+    }
+    if STATE
+        .compare_exchange(UNINIT, PARSING, Ordering::Acquire, Ordering::Relaxed)
+        .is_ok()
+    {
+        RANDOMIZE.store(PROFILE.randomize, Ordering::Relaxed);
+        SHRINK_THRESHOLD.store(PROFILE.shrink_threshold, Ordering::Relaxed);
+        NAME_MAPPINGS.store(true, Ordering::Relaxed);
+        #[cfg(feature = "cache-decay")]
+        CACHE_DECAY_MS.store(PROFILE.cache_decay_ms, Ordering::Relaxed);
+        #[cfg(target_os = "linux")]
+        DUMP_SIGNAL.store(2, Ordering::Relaxed);
+        if let Some(conf) = env_conf() {
+            parse(conf, apply);
+        }
+        STATE.store(READY, Ordering::Release);
+    }
+}
+
+/// The contents of `ELFMALLOC_CONF`, if it is set.
+#[cfg(not(miri))]
+fn env_conf() -> Option<&'static [u8]> {
+    use std::ffi::CStr;
+    unsafe {
+        let val = libc::getenv(b"ELFMALLOC_CONF\0".as_ptr() as *const libc::c_char);
+        if val.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(val).to_bytes())
+        }
+    }
+}
+
+/// Miri cannot call `getenv`, so the defaults are always used.
+#[cfg(miri)]
+fn env_conf() -> Option<&'static [u8]> {
+    None
+}
+
+fn apply(key: &[u8], val: &[u8]) {
+    let ok = match key {
+        b"randomize" => parse_bool(val).map(|b| RANDOMIZE.store(b, Ordering::Relaxed)),
+        b"prefault" => parse_bool(val).map(|b| PREFAULT.store(b, Ordering::Relaxed)),
+        b"on_violation" => match val {
+            b"abort" => Some(ViolationPolicy::Abort),
+            b"continue" => Some(ViolationPolicy::Continue),
+            _ => None,
+        }.map(integrity::set_violation_policy),
+        b"name_mappings" => parse_bool(val).map(|b| NAME_MAPPINGS.store(b, Ordering::Relaxed)),
+        b"backoff_spin_limit" => parse_usize(val).map(backoff::set_spin_limit),
+        b"backoff_max_park_us" => parse_usize(val).map(backoff::set_max_park_us),
+        b"shrink_threshold" => {
+            parse_size(val).map(|n| SHRINK_THRESHOLD.store(n, Ordering::Relaxed))
+        }
+        #[cfg(feature = "quarantine")]
+        b"quarantine_size" => parse_size(val).map(super::quarantine::set_quarantine_size),
+        #[cfg(feature = "batch-unmap")]
+        b"defer_unmap" => parse_bool(val).map(|b| DEFER_UNMAP.store(b, Ordering::Relaxed)),
+        #[cfg(feature = "demand-commit")]
+        b"demand_commit" => parse_bool(val).map(|b| DEMAND_COMMIT.store(b, Ordering::Relaxed)),
+        #[cfg(feature = "cache-decay")]
+        b"cache_decay_ms" => {
+            parse_usize(val).map(|ms| CACHE_DECAY_MS.store(ms, Ordering::Relaxed))
+        }
+        // The value points into the environment, which lives as long as the process.
+        #[cfg(target_os = "linux")]
+        b"dump_path" => if val.is_empty() {
+            None
+        } else {
+            DUMP_PATH_LEN.store(val.len(), Ordering::Relaxed);
+            Some(DUMP_PATH_PTR.store(val.as_ptr() as usize, Ordering::Relaxed))
+        },
+        #[cfg(target_os = "linux")]
+        b"dump_signal" => match val {
+            b"usr1" => Some(1),
+            b"usr2" => Some(2),
+            _ => None,
+        }.map(|sig| DUMP_SIGNAL.store(sig, Ordering::Relaxed)),
+        _ => {
+            alloc_eprintln!(
+                "elfmalloc: unknown ELFMALLOC_CONF option: {}",
+                str::from_utf8(key).unwrap_or("?")
+            );
+            return;
+        }
+    };
+    if ok.is_none() {
+        alloc_eprintln!(
+            "elfmalloc: invalid value for ELFMALLOC_CONF option {}: {}",
+            str::from_utf8(key).unwrap_or("?"),
+            str::from_utf8(val).unwrap_or("?")
+        );
+    }
+}
+
+/// Call `f` with each `key:value` pair in `conf`. Empty entries are skipped, and an entry without
+/// a `:` has an empty value.
+fn parse<F: FnMut(&[u8], &[u8])>(conf: &[u8], mut f: F) {
+    for entry in conf.split(|&b| b == b',') {
+        if entry.is_empty() {
+            continue;
+        }
+        match entry.iter().position(|&b| b == b':') {
+            Some(i) => f(&entry[..i], &entry[i + 1..]),
+            None => f(entry, &[]),
+        }
+    }
+}
+
+fn parse_bool(val: &[u8]) -> Option<bool> {
+    match val {
+        b"true" | b"1" => Some(true),
+        b"false" | b"0" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_size(val: &[u8]) -> Option<usize> {
+    let (digits, shift) = match val.last() {
+        Some(&b'k') | Some(&b'K') => (&val[..val.len() - 1], 10),
+        Some(&b'm') | Some(&b'M') => (&val[..val.len() - 1], 20),
+        Some(&b'g') | Some(&b'G') => (&val[..val.len() - 1], 30),
+        _ => (val, 0),
+    };
+    parse_usize(digits).and_then(|n| n.checked_mul(1 << shift))
+}
+
+fn parse_usize(digits: &[u8]) -> Option<usize> {
+    if digits.is_empty() {
+        return None;
+    }
+    let mut n: usize = 0;
+    for &d in digits {
+        if d < b'0' || d > b'9' {
+            return None;
+        }
+        n = match n.checked_mul(10).and_then(|n| n.checked_add((d - b'0') as usize)) {
+            Some(n) => n,
+            None => return None,
+        };
+    }
+    Some(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_entries() {
+        let mut entries = Vec::new();
+        parse(b"randomize:true,,quarantine_size:1m,flag", |k, v| {
+            entries.push((k.to_vec(), v.to_vec()))
+        });
+        alloc_assert_eq!(
+            entries,
+            vec![
+                (b"randomize".to_vec(), b"true".to_vec()),
+                (b"quarantine_size".to_vec(), b"1m".to_vec()),
+                (b"flag".to_vec(), Vec::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_values() {
+        alloc_assert_eq!(parse_bool(b"true"), Some(true));
+        alloc_assert_eq!(parse_bool(b"0"), Some(false));
+        alloc_assert_eq!(parse_bool(b"yes"), None);
+        alloc_assert_eq!(parse_size(b"4096"), Some(4096));
+        alloc_assert_eq!(parse_size(b"256k"), Some(256 << 10));
+        alloc_assert_eq!(parse_size(b"2G"), Some(2 << 30));
+        alloc_assert_eq!(parse_size(b"k"), None);
+        alloc_assert_eq!(parse_size(b"1.5m"), None);
+        alloc_assert_eq!(parse_size(b"99999999999999999999999"), None);
+        alloc_assert_eq!(parse_usize(b"10000"), Some(10000));
+        alloc_assert_eq!(parse_usize(b"10k"), None);
+    }
+}
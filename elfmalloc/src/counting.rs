@@ -0,0 +1,240 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! An allocator wrapper that records how it is used.
+//!
+//! `CountingAlloc` wraps any `Alloc` - elfmalloc's handles, the system heap (`Heap`), or another
+//! wrapper - and counts the allocations, reallocations and frees made through it, the live and
+//! peak live bytes, and a histogram of allocation sizes. Wrapping the system allocator and an
+//! elfmalloc handle in the same way makes it easy to check that a workload makes the same requests
+//! of both, and to compare their behavior on it.
+//!
+//! Bytes are counted as requested by the `Layout`, not as rounded up by the inner allocator, so
+//! the numbers are the same whichever allocator is wrapped. The counters are plain fields of the
+//! wrapper; `stats` takes a snapshot and `reset` starts over, so that e.g. one phase of a program
+//! can be measured on its own.
+
+use std::cmp;
+use super::alloc::allocator::{Alloc, AllocErr, Layout};
+
+/// The number of buckets in `CountingStats::sizes`.
+pub const SIZE_BUCKETS: usize = 32;
+
+/// The bucket of `CountingStats::sizes` that counts allocations of `size` bytes: 0 for sizes 0
+/// and 1, and `i` for sizes in `(2^(i-1), 2^i]`, except that the last bucket also counts all
+/// larger sizes.
+pub fn size_bucket(size: usize) -> usize {
+    if size <= 1 {
+        return 0;
+    }
+    let bits = (0usize.count_zeros() - (size - 1).leading_zeros()) as usize;
+    cmp::min(bits, SIZE_BUCKETS - 1)
+}
+
+/// What a `CountingAlloc` has recorded since it was created or last reset.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CountingStats {
+    /// Successful calls to `alloc` and `alloc_zeroed`.
+    pub allocs: usize,
+    /// Calls to `alloc`, `alloc_zeroed` and `realloc` that failed.
+    pub failed: usize,
+    /// Successful calls to `realloc`.
+    pub reallocs: usize,
+    /// Calls to `dealloc`.
+    pub frees: usize,
+    /// The sum of the sizes of successful allocations, including the new sizes of reallocations.
+    pub bytes_allocated: usize,
+    pub live_bytes: usize,
+    /// The highest value of `live_bytes`.
+    pub peak_live_bytes: usize,
+    pub live_objects: usize,
+    /// The number of successful allocations and reallocations by size (see `size_bucket`).
+    pub sizes: [usize; SIZE_BUCKETS],
+}
+
+/// An `Alloc` that forwards to `A` and counts what passes through. See the module documentation.
+pub struct CountingAlloc<A: Alloc> {
+    inner: A,
+    stats: CountingStats,
+}
+
+impl<A: Alloc> CountingAlloc<A> {
+    pub fn new(inner: A) -> CountingAlloc<A> {
+        CountingAlloc {
+            inner: inner,
+            stats: CountingStats::default(),
+        }
+    }
+
+    /// A snapshot of the counters.
+    pub fn stats(&self) -> CountingStats {
+        self.stats
+    }
+
+    /// Zero the counters. The live byte and object counts are kept, since the objects are still
+    /// live, and the peak starts over from the current live bytes.
+    pub fn reset(&mut self) {
+        let (live_bytes, live_objects) = (self.stats.live_bytes, self.stats.live_objects);
+        self.stats = CountingStats {
+            live_bytes: live_bytes,
+            peak_live_bytes: live_bytes,
+            live_objects: live_objects,
+            ..CountingStats::default()
+        };
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut A {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> A {
+        self.inner
+    }
+
+    fn record_alloc(&mut self, size: usize) {
+        let s = &mut self.stats;
+        s.bytes_allocated += size;
+        s.live_bytes += size;
+        s.peak_live_bytes = cmp::max(s.peak_live_bytes, s.live_bytes);
+        s.sizes[size_bucket(size)] += 1;
+    }
+
+    fn record<T>(&mut self, res: &Result<T, AllocErr>, size: usize) -> bool {
+        if res.is_ok() {
+            self.record_alloc(size);
+            true
+        } else {
+            self.stats.failed += 1;
+            false
+        }
+    }
+}
+
+unsafe impl<A: Alloc> Alloc for CountingAlloc<A> {
+    unsafe fn alloc(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        let size = l.size();
+        let res = self.inner.alloc(l);
+        if self.record(&res, size) {
+            self.stats.allocs += 1;
+            self.stats.live_objects += 1;
+        }
+        res
+    }
+
+    unsafe fn alloc_zeroed(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        let size = l.size();
+        let res = self.inner.alloc_zeroed(l);
+        if self.record(&res, size) {
+            self.stats.allocs += 1;
+            self.stats.live_objects += 1;
+        }
+        res
+    }
+
+    unsafe fn dealloc(&mut self, p: *mut u8, l: Layout) {
+        self.stats.frees += 1;
+        self.stats.live_bytes -= l.size();
+        self.stats.live_objects -= 1;
+        self.inner.dealloc(p, l)
+    }
+
+    unsafe fn realloc(
+        &mut self,
+        p: *mut u8,
+        old_l: Layout,
+        new_l: Layout,
+    ) -> Result<*mut u8, AllocErr> {
+        let (old_size, new_size) = (old_l.size(), new_l.size());
+        let res = self.inner.realloc(p, old_l, new_l);
+        if res.is_ok() {
+            // Free the old size first, so that shrinking does not raise the peak.
+            self.stats.live_bytes -= old_size;
+        }
+        if self.record(&res, new_size) {
+            self.stats.reallocs += 1;
+        }
+        res
+    }
+
+    fn usable_size(&self, l: &Layout) -> (usize, usize) {
+        self.inner.usable_size(l)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::heap::Heap;
+    use rust_alloc::SharedAlloc;
+
+    #[test]
+    fn buckets() {
+        let expected = [(0, 0), (1, 0), (2, 1), (3, 2), (4, 2), (5, 3), (4096, 12), (4097, 13)];
+        for &(size, bucket) in &expected {
+            alloc_assert_eq!(size_bucket(size), bucket, "size {}", size);
+        }
+        alloc_assert_eq!(size_bucket(!0), SIZE_BUCKETS - 1);
+    }
+
+    fn small() -> Layout {
+        Layout::from_size_align(24, 8).unwrap()
+    }
+
+    /// Run the same sequence of operations against `a`, returning the one object left live.
+    fn workload<A: Alloc>(a: &mut CountingAlloc<A>) -> *mut u8 {
+        let small = small();
+        unsafe {
+            let ptrs: Vec<_> = (0..10).map(|_| a.alloc(small.clone()).unwrap()).collect();
+            let big = a.alloc_zeroed(Layout::from_size_align(1 << 20, 8).unwrap())
+                .unwrap();
+            let big = a.realloc(
+                big,
+                Layout::from_size_align(1 << 20, 8).unwrap(),
+                Layout::from_size_align(1 << 10, 8).unwrap(),
+            ).unwrap();
+            a.dealloc(big, Layout::from_size_align(1 << 10, 8).unwrap());
+            for &p in &ptrs[1..] {
+                a.dealloc(p, small.clone());
+            }
+            ptrs[0]
+        }
+    }
+
+    #[test]
+    fn same_counts_for_any_allocator() {
+        let mut sys = CountingAlloc::new(Heap);
+        let mut elf = CountingAlloc::new(SharedAlloc);
+        let sys_obj = workload(&mut sys);
+        let elf_obj = workload(&mut elf);
+        let stats = elf.stats();
+        alloc_assert_eq!(sys.stats(), stats);
+        alloc_assert_eq!(stats.allocs, 11);
+        alloc_assert_eq!(stats.reallocs, 1);
+        alloc_assert_eq!(stats.frees, 10);
+        alloc_assert_eq!(stats.live_objects, 1);
+        alloc_assert_eq!(stats.live_bytes, 24);
+        alloc_assert_eq!(stats.peak_live_bytes, 10 * 24 + (1 << 20));
+        alloc_assert_eq!(stats.bytes_allocated, 10 * 24 + (1 << 20) + (1 << 10));
+        alloc_assert_eq!(stats.sizes[size_bucket(24)], 10);
+        alloc_assert_eq!(stats.sizes[20], 1);
+        alloc_assert_eq!(stats.sizes[10], 1);
+
+        elf.reset();
+        alloc_assert_eq!(elf.stats().allocs, 0);
+        alloc_assert_eq!(elf.stats().live_bytes, 24);
+        alloc_assert_eq!(elf.stats().peak_live_bytes, 24);
+        unsafe {
+            sys.dealloc(sys_obj, small());
+            elf.dealloc(elf_obj, small());
+        }
+        alloc_assert_eq!(elf.stats().live_objects, 0);
+    }
+}
@@ -0,0 +1,307 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Support for inspecting elfmalloc from a debugger.
+//!
+//! This module exports two kinds of symbols with unmangled names:
+//!
+//! - Functions with the C ABI that a debugger can call in a live process: `elf_dbg_lookup`
+//!   describes the object that a pointer points into, and `elf_dbg_stats` counts the memory that
+//!   elfmalloc has mapped. `elf_dbg_describe` and `elf_dbg_print_stats` print the same to stderr.
+//! - `ELF_DBG_DESCRIPTOR`, a static that tells a debugger where the `ownership` table is and how
+//!   its entries are laid out. Scripts can then classify pointers in a core dump, where no code
+//!   can be run.
+//!
+//! The functions are meant to be called while the process is stopped at an arbitrary point,
+//! possibly in the middle of an allocation on another thread. They do not allocate, take no
+//! locks, and never wait for other threads. Memory is found through the `ownership` table, and
+//! elfmalloc's metadata is only read for memory that the table says belongs to elfmalloc, so a
+//! wild pointer is reported as not found rather than faulting. Metadata that a stopped thread was
+//! in the middle of changing may still be inconsistent; like `global::lookup`, the results are a
+//! snapshot.
+//!
+//! `elf_dbg_lookup` and `elf_dbg_stats` return pointers to static buffers, which are overwritten
+//! by the next call. This keeps them callable from debuggers that cannot allocate memory in the
+//! process, but makes them unsuitable for use by the program itself, which should use
+//! `lookup_object` and `census` instead.
+//!
+//! The Python scripts in the `debug` directory of this crate build on these symbols:
+//! `elfmalloc_gdb.py` for gdb (`source elfmalloc_gdb.py`) and `elfmalloc_lldb.py` for lldb
+//! (`command script import elfmalloc_lldb.py`). Both add an `elf-lookup` command, which calls
+//! `elf_dbg_lookup` in a live process and falls back to reading the table in a core dump, an
+//! `elf-stats` command, which reads the table in either case, and pretty-printers for the types
+//! below. The layout of `ObjectInfo`, `HeapCensus` and `Descriptor` is part of the interface:
+//! fields are only ever appended, and `DESCRIPTOR_VERSION` is bumped when they are.
+
+extern crate libc;
+
+use std::fmt::Write;
+use std::ptr;
+use std::sync::atomic::AtomicUsize;
+use self::libc::{c_int, c_void};
+use alloc_fmt::FDWriter;
+use super::alloc_type::AllocType;
+use super::general::{global, OwningHeap};
+use super::ownership::{self, Granule, ADDRESS_BITS, GRANULE_SHIFT, LEAF_SHIFT};
+
+/// The value of `Descriptor::magic`, "elfdbg" in ASCII.
+pub const DESCRIPTOR_MAGIC: u64 = 0x6762_6466_6c65;
+/// The version of the layout of `Descriptor`, `ObjectInfo`, `HeapCensus` and the entries of the
+/// `ownership` table.
+pub const DESCRIPTOR_VERSION: u32 = 1;
+
+/// Values of `ObjectInfo::heap`, which mirror `OwningHeap`.
+pub const HEAP_THREAD: u32 = 0;
+pub const HEAP_SHARED: u32 = 1;
+pub const HEAP_LARGE: u32 = 2;
+
+/// A description of the `ownership` table, for debuggers reading a core dump.
+///
+/// The table's root is an array of `1 << (address_bits - leaf_shift)` leaf addresses, and each
+/// leaf is an array of `1 << (leaf_shift - granule_shift)` words. `*root` is 0 until the root is
+/// mapped, and leaf addresses are 0 until the leaf is mapped. A word describes one granule: the
+/// low two bits are 0 if the granule does not belong to elfmalloc, 1 for small `Slag`s, 2 for big
+/// `Slag`s and 3 for large objects. The remaining bits hold the base-2 log of the `Slag` size for
+/// `Slag`s, which are aligned to their size, and the index of the granule within the object's
+/// mapping for large objects. A large object starts `large_object_offset` bytes into its mapping.
+#[repr(C)]
+pub struct Descriptor {
+    pub magic: u64,
+    pub version: u32,
+    pub granule_shift: u32,
+    pub leaf_shift: u32,
+    pub address_bits: u32,
+    /// The word holding the address of the root of the table.
+    pub root: &'static AtomicUsize,
+    pub large_object_offset: usize,
+}
+
+#[no_mangle]
+pub static ELF_DBG_DESCRIPTOR: Descriptor = Descriptor {
+    magic: DESCRIPTOR_MAGIC,
+    version: DESCRIPTOR_VERSION,
+    granule_shift: GRANULE_SHIFT as u32,
+    leaf_shift: LEAF_SHIFT as u32,
+    address_bits: ADDRESS_BITS as u32,
+    root: &ownership::ROOT,
+    large_object_offset: global::LARGE_OBJECT_OFFSET,
+};
+
+/// The object that a pointer points into, as reported by `elf_dbg_lookup`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ObjectInfo {
+    /// The address of the start of the object.
+    pub start: usize,
+    /// The number of bytes, counting from `start`, that may be used.
+    pub usable_size: usize,
+    /// The object size of the object's size class, or 0 for large objects.
+    pub size_class: usize,
+    /// `HEAP_THREAD`, `HEAP_SHARED` or `HEAP_LARGE`.
+    pub heap: u32,
+}
+
+/// The memory that elfmalloc has mapped, as reported by `elf_dbg_stats`.
+///
+/// Memory is counted in granules of the `ownership` table, so `Slag`s of the object-specific
+/// allocators in `frontends` that are smaller than a granule are rounded up.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeapCensus {
+    /// Bytes of memory holding small `Slag`s.
+    pub small_slag_bytes: usize,
+    /// Bytes of memory holding big `Slag`s.
+    pub big_slag_bytes: usize,
+    /// The number of large objects.
+    pub large_objects: usize,
+    /// Bytes of memory mapped for large objects, including their headers.
+    pub large_bytes: usize,
+}
+
+const NO_OBJECT: ObjectInfo = ObjectInfo {
+    start: 0,
+    usable_size: 0,
+    size_class: 0,
+    heap: 0,
+};
+const NO_CENSUS: HeapCensus = HeapCensus {
+    small_slag_bytes: 0,
+    big_slag_bytes: 0,
+    large_objects: 0,
+    large_bytes: 0,
+};
+
+static mut LOOKUP_RESULT: ObjectInfo = NO_OBJECT;
+static mut STATS_RESULT: HeapCensus = NO_CENSUS;
+
+/// Look up the object that `ptr` points into. `ptr` can be any pointer; `None` is returned if it
+/// does not point into an object managed by elfmalloc. See `global::lookup`.
+pub unsafe fn lookup_object(ptr: *mut u8) -> Option<ObjectInfo> {
+    let info = match global::lookup(ptr) {
+        Some(info) => info,
+        None => return None,
+    };
+    global::object_start(ptr).map(|start| {
+        ObjectInfo {
+            start: start as usize,
+            usable_size: info.usable_size,
+            size_class: info.size_class.unwrap_or(0),
+            heap: match info.owning_heap {
+                OwningHeap::Thread => HEAP_THREAD,
+                OwningHeap::Shared => HEAP_SHARED,
+                OwningHeap::Large => HEAP_LARGE,
+            },
+        }
+    })
+}
+
+/// Count the memory that elfmalloc has mapped, from the `ownership` table alone.
+pub fn census() -> HeapCensus {
+    let granule = 1 << GRANULE_SHIFT;
+    let mut census = NO_CENSUS;
+    let mut next = 0;
+    while let Some((addr, g)) = ownership::next_owned(next) {
+        match g {
+            Granule::Slags { ty: AllocType::SmallSlag, .. } => census.small_slag_bytes += granule,
+            Granule::Slags { .. } => census.big_slag_bytes += granule,
+            Granule::Large { index } => {
+                if index == 0 {
+                    census.large_objects += 1;
+                }
+                census.large_bytes += granule;
+            }
+        }
+        next = addr + granule;
+    }
+    census
+}
+
+/// Describe the object that `ptr` points into. Returns a pointer to a static buffer, which the
+/// next call overwrites, or null if `ptr` does not point into an object managed by elfmalloc.
+#[no_mangle]
+pub unsafe extern "C" fn elf_dbg_lookup(ptr: *mut c_void) -> *const ObjectInfo {
+    match lookup_object(ptr as *mut u8) {
+        Some(info) => {
+            LOOKUP_RESULT = info;
+            &LOOKUP_RESULT as *const ObjectInfo
+        }
+        None => ptr::null(),
+    }
+}
+
+/// Count the memory that elfmalloc has mapped. Returns a pointer to a static buffer, which the
+/// next call overwrites.
+#[no_mangle]
+pub unsafe extern "C" fn elf_dbg_stats() -> *const HeapCensus {
+    STATS_RESULT = census();
+    &STATS_RESULT
+}
+
+/// Print a description of the object that `ptr` points into to stderr. Returns 1 if there is
+/// one, and 0 otherwise.
+#[no_mangle]
+pub unsafe extern "C" fn elf_dbg_describe(ptr: *mut c_void) -> c_int {
+    let mut w = FDWriter(2);
+    match lookup_object(ptr as *mut u8) {
+        Some(info) => {
+            let heap = match info.heap {
+                HEAP_THREAD => "thread",
+                HEAP_SHARED => "shared",
+                _ => "large",
+            };
+            let _ = writeln!(
+                w,
+                "elfmalloc: {:?} is {} bytes into the object at {:#x} (usable size {}, size \
+                 class {}, {} heap)",
+                ptr,
+                ptr as usize - info.start,
+                info.start,
+                info.usable_size,
+                info.size_class,
+                heap
+            );
+            1
+        }
+        None => {
+            let _ = writeln!(w, "elfmalloc: {:?} is not in an elfmalloc object", ptr);
+            0
+        }
+    }
+}
+
+/// Print the memory that elfmalloc has mapped to stderr.
+#[no_mangle]
+pub extern "C" fn elf_dbg_print_stats() {
+    let c = census();
+    let _ = writeln!(
+        FDWriter(2),
+        "elfmalloc: {} bytes of small Slags, {} bytes of big Slags, {} large objects in {} bytes",
+        c.small_slag_bytes,
+        c.big_slag_bytes,
+        c.large_objects,
+        c.large_bytes
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    /// Decode the table entry for `p` the way the debugger scripts do, using only the descriptor.
+    unsafe fn decode_like_script(p: usize) -> usize {
+        let d = &ELF_DBG_DESCRIPTOR;
+        let root = d.root.load(Ordering::Acquire) as *const usize;
+        if root.is_null() || p >> d.address_bits != 0 {
+            return 0;
+        }
+        let leaf = *root.offset((p >> d.leaf_shift) as isize) as *const usize;
+        if leaf.is_null() {
+            return 0;
+        }
+        *leaf.offset(((p & ((1 << d.leaf_shift) - 1)) >> d.granule_shift) as isize)
+    }
+
+    #[test]
+    fn lookup_and_descriptor() {
+        unsafe {
+            let small = global::alloc(40);
+            let large = global::alloc(4 << 20);
+            let info = lookup_object(small.offset(8)).unwrap();
+            alloc_assert_eq!(info.start, small as usize);
+            alloc_assert!(info.usable_size >= 40);
+            alloc_assert_eq!(Some(info.size_class), global::size_class(40));
+            alloc_assert!(info.heap == HEAP_THREAD || info.heap == HEAP_SHARED);
+            let info = &*elf_dbg_lookup(large.offset(100) as *mut c_void);
+            alloc_assert_eq!(
+                (info.start, info.size_class, info.heap),
+                (large as usize, 0, HEAP_LARGE)
+            );
+            let local = 0usize;
+            alloc_assert!(elf_dbg_lookup(&local as *const usize as *mut c_void).is_null());
+
+            alloc_assert_eq!(ELF_DBG_DESCRIPTOR.magic, DESCRIPTOR_MAGIC);
+            alloc_assert_eq!(decode_like_script(small as usize) & 3, 1);
+            alloc_assert_eq!(decode_like_script(large as usize) & 3, 3);
+            let base = large as usize - ELF_DBG_DESCRIPTOR.large_object_offset;
+            let inner = large as usize + (1 << 20);
+            let index = decode_like_script(inner) >> 2;
+            alloc_assert_eq!(
+                base + (index << GRANULE_SHIFT),
+                inner & !((1 << GRANULE_SHIFT) - 1)
+            );
+            alloc_assert_eq!(decode_like_script(&local as *const usize as usize), 0);
+
+            let c = *elf_dbg_stats();
+            alloc_assert!(c.small_slag_bytes > 0);
+            alloc_assert!(c.large_objects >= 1);
+            alloc_assert!(c.large_bytes >= 4 << 20);
+            global::free(small);
+            global::free(large);
+        }
+    }
+}
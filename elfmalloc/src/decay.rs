@@ -0,0 +1,142 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Time-based decay of thread caches (`cache-decay` feature).
+//!
+//! A thread cache otherwise only shrinks when it overflows or is flushed explicitly (`trim`,
+//! `on_park`), so a thread that frees a burst of objects of one size class and then moves on to
+//! other work keeps them cached indefinitely. With this feature, each frontend records when it was
+//! last used, and a size class whose cache has not been used for the decay interval has its
+//! cached objects freed back to their `Slag`s, as with `trim(1)`.
+//!
+//! The check is made opportunistically by the thread itself, since no other thread can safely
+//! touch its caches: once every `CHECK_OPS` allocations, the thread reads the clock, and if a
+//! quarter of the interval has passed since its last check, it goes over its size classes. The
+//! fast path only counts allocations and marks the frontend as used, and time stamps are only
+//! taken during checks, so a cache is freed between one and one and a quarter intervals after its
+//! last use (given that the thread keeps allocating). A thread that stops allocating altogether
+//! should call `on_park` instead.
+//!
+//! The interval is set with the `cache_decay_ms` option of `ELFMALLOC_CONF` (10 seconds by
+//! default; 0 disables decay) or with `conf::set_cache_decay_ms`.
+
+#[cfg(not(miri))]
+extern crate libc;
+
+use super::conf;
+use super::utils::likely;
+
+/// The number of allocations on a handle between reads of the clock.
+pub const CHECK_OPS: usize = 1024;
+
+/// Whether a frontend was used since the last check, and the time of the last check at which it
+/// was.
+pub struct LastUse {
+    used: bool,
+    at: u64,
+}
+
+impl Default for LastUse {
+    /// A new frontend counts as used, so that it is stamped at the next check.
+    fn default() -> LastUse {
+        LastUse { used: true, at: 0 }
+    }
+}
+
+impl LastUse {
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    pub fn touch(&mut self) {
+        self.used = true;
+    }
+
+    /// When the frontend was last used, as of a check at `now`.
+    pub fn get(&mut self, now: u64) -> u64 {
+        if self.used {
+            self.used = false;
+            self.at = now;
+        }
+        self.at
+    }
+}
+
+/// Decides when a handle checks its caches for decay.
+#[derive(Default)]
+pub struct Clock {
+    ops: usize,
+    last_check: u64,
+}
+
+impl Clock {
+    /// Count an allocation. Returns the current time and the decay interval if the caches should
+    /// be checked now.
+    #[inline]
+    pub fn tick(&mut self) -> Option<(u64, u64)> {
+        self.ops += 1;
+        if unsafe { likely(self.ops < CHECK_OPS) } {
+            return None;
+        }
+        self.check()
+    }
+
+    #[cold]
+    fn check(&mut self) -> Option<(u64, u64)> {
+        self.ops = 0;
+        let interval = conf::cache_decay_ms() as u64;
+        if interval == 0 {
+            return None;
+        }
+        let now = now_ms();
+        if now - self.last_check < interval / 4 {
+            return None;
+        }
+        self.last_check = now;
+        Some((now, interval))
+    }
+}
+
+/// Milliseconds on the monotonic clock.
+#[cfg(not(miri))]
+pub fn now_ms() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    ts.tv_sec as u64 * 1000 + ts.tv_nsec as u64 / 1_000_000
+}
+
+/// Miri cannot read the clock, so time stands still and caches never decay.
+#[cfg(miri)]
+pub fn now_ms() -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_use() {
+        let mut l = LastUse::default();
+        alloc_assert_eq!(l.get(5), 5);
+        alloc_assert_eq!(l.get(9), 5);
+        l.touch();
+        alloc_assert_eq!(l.get(12), 12);
+        alloc_assert_eq!(l.get(20), 12);
+    }
+
+    #[test]
+    fn clock_checks_every_check_ops() {
+        let mut c = Clock::default();
+        let checks = (0..3 * CHECK_OPS).filter(|_| c.tick().is_some()).count();
+        // Only the first check passes, unless a quarter of the interval passes during the loop.
+        alloc_assert!(checks >= 1 && checks <= 3);
+    }
+}
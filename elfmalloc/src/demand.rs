@@ -0,0 +1,261 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Committing memory on demand from a `SIGSEGV` handler. **Experimental.**
+//!
+//! With the `demand-commit` feature, a `Region` (see `reserve`) reserves its address space with
+//! no access permissions, so that it is not charged against the commit limit. By default, `commit`
+//! then makes the requested pages accessible with `mprotect`, which charges all of them up front,
+//! whether or not they are ever touched. With the `demand_commit` option (see the `conf` module),
+//! `commit` only moves the end of the committed prefix, and the first access to each page faults;
+//! a `SIGSEGV` handler makes the faulting page accessible and returns, and the access is retried.
+//! Only the pages that are actually touched are charged, and `Region::uncommit` returns pages to
+//! the inaccessible state, so that they are charged again only if they are touched again.
+//!
+//! This exists to measure whether fault-based commit beats eager commit on sparse workloads, where
+//! much of what is committed is never touched (see the `bench_demand` benchmark). It is not meant
+//! for production use:
+//!
+//! - Each first touch of a page costs a signal delivery and an `mprotect` call on top of the page
+//!   fault, which is much more than the fault alone.
+//! - If the commit limit has been reached, the handler's `mprotect` fails and the process dies
+//!   with `SIGSEGV`, where eager commit would have returned `None` from `Region::commit`.
+//! - The handler is process-wide, and has to coexist with any other `SIGSEGV` handler.
+//!
+//! # Signal handler hygiene
+//!
+//! The handler is installed once, when the first `Region` is created in demand mode, and is never
+//! removed, since another thread may be running it. It runs on the alternate signal stack if
+//! there is one (`SA_ONSTACK`), so that it coexists with the stack overflow detection of Rust's
+//! standard library. It does not allocate, take locks or call anything but `mprotect` and
+//! `sigaction`, which are system calls on Linux. The page size is read before it is installed, and
+//! the handler saves and restores `errno`.
+//!
+//! Regions are registered in a fixed-size table of atomics, which the handler scans. Only faults
+//! caused by missing permissions (`SEGV_ACCERR`) at an address in the committed prefix of a
+//! registered region are handled. Everything else, including accesses past the committed prefix,
+//! is passed on to the handler that was installed before, or, if there was none, makes the
+//! handler restore the default action and return, so that the access faults again and kills the
+//! process as it would have without this module. A `Region` is removed from the table before its
+//! address space is unmapped. If the table is full, new regions fall back to eager commit.
+//!
+//! This module is only available on Linux.
+
+extern crate libc;
+extern crate mmap_alloc;
+
+use std::mem;
+use std::ptr;
+use std::sync::{Once, ONCE_INIT};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use self::libc::{c_int, c_void, siginfo_t};
+use self::mmap_alloc::MapAllocBuilder;
+use super::alloc::allocator::{Alloc, Layout};
+use super::utils::mmap;
+
+/// The number of regions that can be committed on demand at the same time.
+pub const MAX_REGIONS: usize = 64;
+
+// not exported by the libc crate
+const SEGV_ACCERR: c_int = 2;
+
+/// A registered region. `start` is 0 for a free slot and 1 for a slot that is being claimed. The
+/// handler commits pages in `[start, end)`.
+struct Slot {
+    start: AtomicUsize,
+    end: AtomicUsize,
+}
+
+lazy_static! {
+    // An array of atomics cannot be written as a constant, hence lazy_static. The table is
+    // initialized before the handler is installed, so the handler only ever takes the fast path
+    // of the lazy_static, which is an atomic load.
+    static ref SLOTS: [Slot; MAX_REGIONS] = unsafe { mem::zeroed() };
+}
+
+static INSTALL: Once = ONCE_INIT;
+static PAGE_SIZE: AtomicUsize = ATOMIC_USIZE_INIT;
+static FAULTS: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The action that was installed for `SIGSEGV` before ours. Written once, before ours is
+/// installed.
+static mut PREV: Option<libc::sigaction> = None;
+
+/// The number of faults that the handler has committed a page for.
+pub fn faults() -> usize {
+    FAULTS.load(Ordering::Relaxed)
+}
+
+fn builder() -> MapAllocBuilder {
+    MapAllocBuilder::default().reserve_only(true)
+}
+
+/// Reserve `len` bytes of address space with no access permissions.
+pub fn reserve(len: usize) -> Option<*mut u8> {
+    unsafe {
+        builder()
+            .build()
+            .alloc(Layout::from_size_align(len, mmap::page_size()).unwrap())
+            .ok()
+    }
+}
+
+/// Make `[p, p + len)` accessible right away. Returns `false` if the commit limit was reached.
+pub unsafe fn commit(p: *mut u8, len: usize) -> bool {
+    builder().build().commit_reserved(p, len).is_ok()
+}
+
+/// Release the physical memory backing `[p, p + len)` and make it inaccessible again.
+pub unsafe fn decommit(p: *mut u8, len: usize) {
+    builder()
+        .build()
+        .uncommit(p, Layout::from_size_align(len, mmap::page_size()).unwrap())
+}
+
+/// Register the region starting at `base` for commit on demand, with an empty committed prefix.
+/// Returns the index of its slot, or `None` if the table is full.
+pub fn register(base: *mut u8) -> Option<usize> {
+    install();
+    for (i, slot) in SLOTS.iter().enumerate() {
+        if slot.start
+            .compare_exchange(0, 1, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            slot.end.store(base as usize, Ordering::Relaxed);
+            slot.start.store(base as usize, Ordering::Release);
+            return Some(i);
+        }
+    }
+    None
+}
+
+/// Move the end of the committed prefix of the region in slot `slot` forward to `end`, if it is
+/// not past it already.
+pub fn extend(slot: usize, end: *mut u8) {
+    let end = end as usize;
+    let cur = &SLOTS[slot].end;
+    let mut old = cur.load(Ordering::Relaxed);
+    while old < end {
+        match cur.compare_exchange_weak(old, end, Ordering::Release, Ordering::Relaxed) {
+            Ok(_) => return,
+            Err(actual) => old = actual,
+        }
+    }
+}
+
+/// Set the end of the committed prefix of the region in slot `slot` back to `end`.
+pub fn truncate(slot: usize, end: *mut u8) {
+    SLOTS[slot].end.store(end as usize, Ordering::Release);
+}
+
+/// Free slot `slot`. This must happen before the region is unmapped, so that the handler does not
+/// commit pages of a mapping that later reuses its addresses.
+pub fn unregister(slot: usize) {
+    SLOTS[slot].start.store(0, Ordering::Release);
+}
+
+fn install() {
+    INSTALL.call_once(|| unsafe {
+        PAGE_SIZE.store(mmap::page_size(), Ordering::Relaxed);
+        ::lazy_static::initialize(&SLOTS);
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handle as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut prev: libc::sigaction = mem::zeroed();
+        let ret = libc::sigaction(libc::SIGSEGV, ptr::null(), &mut prev);
+        alloc_assert_eq!(ret, 0, "sigaction failed");
+        PREV = Some(prev);
+        let ret = libc::sigaction(libc::SIGSEGV, &action, ptr::null_mut());
+        alloc_assert_eq!(ret, 0, "sigaction failed");
+    });
+}
+
+extern "C" fn handle(sig: c_int, info: *mut siginfo_t, ctx: *mut c_void) {
+    unsafe {
+        let errno = *libc::__errno_location();
+        let handled = (*info).si_code == SEGV_ACCERR && commit_fault((*info).si_addr() as usize);
+        *libc::__errno_location() = errno;
+        if !handled {
+            chain(sig, info, ctx);
+        }
+    }
+}
+
+/// Commit the page containing `addr` if it is in the committed prefix of a registered region.
+unsafe fn commit_fault(addr: usize) -> bool {
+    for slot in SLOTS.iter() {
+        let start = slot.start.load(Ordering::Acquire);
+        if start <= 1 || addr < start || addr >= slot.end.load(Ordering::Acquire) {
+            continue;
+        }
+        let page_size = PAGE_SIZE.load(Ordering::Relaxed);
+        let page = addr & !(page_size - 1);
+        if libc::mprotect(
+            page as *mut c_void,
+            page_size,
+            libc::PROT_READ | libc::PROT_WRITE,
+        ) != 0
+        {
+            // Most likely the commit limit. Let the fault kill the process.
+            return false;
+        }
+        FAULTS.fetch_add(1, Ordering::Relaxed);
+        return true;
+    }
+    false
+}
+
+/// Pass a fault that is not ours on to the previous handler.
+unsafe fn chain(sig: c_int, info: *mut siginfo_t, ctx: *mut c_void) {
+    if let Some(ref prev) = PREV {
+        if prev.sa_flags & libc::SA_SIGINFO != 0 {
+            let f: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) =
+                mem::transmute(prev.sa_sigaction);
+            return f(sig, info, ctx);
+        }
+        if prev.sa_sigaction != libc::SIG_DFL && prev.sa_sigaction != libc::SIG_IGN {
+            let f: extern "C" fn(c_int) = mem::transmute(prev.sa_sigaction);
+            return f(sig);
+        }
+    }
+    // There was no handler (ignoring SIGSEGV would make the access fault forever). Restore the
+    // default action; returning retries the access, which then kills the process.
+    let mut dfl: libc::sigaction = mem::zeroed();
+    dfl.sa_sigaction = libc::SIG_DFL;
+    libc::sigaction(sig, &dfl, ptr::null_mut());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commit_on_fault() {
+        let page_size = mmap::page_size();
+        let base = reserve(8 * page_size).unwrap();
+        let slot = register(base).unwrap();
+        unsafe {
+            extend(slot, base.offset(4 * page_size as isize));
+            let before = faults();
+            *base.offset(page_size as isize + 1) = 1;
+            alloc_assert_eq!(*base.offset(page_size as isize + 1), 1);
+            // Only the touched page was committed; the next one faults too.
+            *base.offset(2 * page_size as isize) = 2;
+            alloc_assert!(faults() >= before + 2);
+
+            // Decommitted pages read as zeros and are committed again on the next access.
+            decommit(base.offset(page_size as isize), page_size);
+            alloc_assert_eq!(*base.offset(page_size as isize + 1), 0);
+
+            alloc_assert!(commit(base.offset(4 * page_size as isize), page_size));
+            *base.offset(4 * page_size as isize) = 3;
+            unregister(slot);
+            decommit(base, 8 * page_size);
+            mmap::unmap(base, 8 * page_size);
+        }
+    }
+}
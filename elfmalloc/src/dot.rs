@@ -0,0 +1,216 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Graphviz export of the `Slag`s of a size class, for debugging the allocator.
+//!
+//! `write_size_class` describes every `Slag` of one size class as a graph in the DOT language,
+//! which can be rendered with e.g. `dot -Tsvg`:
+//!
+//! - Each `Slag` is a node labeled with its address and how many of its objects are in use
+//!   (i.e., not marked as available in its bit-set), and shaded by that fill level. `Slag`s that
+//!   are claimed by a thread are drawn with a thick border.
+//! - The current thread's cache for the size class is a node with an edge to every `Slag` that
+//!   it holds objects of, labeled with the number of objects, and a bold edge to the `Slag` it
+//!   allocates from.
+//!
+//! This makes it easy to see, for example, that a size class is spread over many nearly-empty
+//! `Slag`s, or that a thread's cache is holding on to objects that would let a `Slag` be
+//! returned to the page cache. `Slag`s are found through the `ownership` table, like in the `walk`
+//! module, so `Slag`s of the heaps in `rust_alloc` and the object-specific allocators in
+//! `frontends` are included if their objects have the same size. Other threads may use the
+//! allocator while the graph is written, so it is only a snapshot.
+//!
+//! Nothing is allocated while the graph is generated: the `Slag`s are visited in place, and the
+//! output is written piece by piece to a `fmt::Write`. `dump_size_class` writes it straight to a
+//! file descriptor, which is safe to call from a debugger or from within the allocator itself.
+
+use std::cmp;
+use std::fmt::{self, Write};
+use alloc_fmt::FDWriter;
+use super::general::global;
+use super::ownership::{self, Granule, GRANULE_SHIFT};
+use super::slag::{Metadata, Slag};
+
+/// Write the graph of the `Slag`s of the size class of `size` bytes to `w`. See the module
+/// documentation.
+///
+/// This is unsafe because it reads elfmalloc's metadata, which may be corrupt if the heap is.
+pub unsafe fn write_size_class<W: Write>(w: &mut W, size: usize) -> fmt::Result {
+    writeln!(w, "digraph elfmalloc {{")?;
+    writeln!(w, "  node [shape=box, style=filled, colorscheme=blues9];")?;
+    let class = match global::size_class(size) {
+        Some(class) => class,
+        None => {
+            writeln!(
+                w,
+                "  label=\"{} bytes is a large object, which has no Slag\";",
+                size
+            )?;
+            return writeln!(w, "}}");
+        }
+    };
+
+    let mut cache_total = 0;
+    let current = global::cached_objects(size, |_| cache_total += 1);
+    if current.is_some() {
+        writeln!(
+            w,
+            "  cache [shape=ellipse, fillcolor=white, label=\"thread cache\\n{} objects\"];",
+            cache_total
+        )?;
+    }
+
+    let (mut n_slags, mut objects, mut used) = (0, 0, 0);
+    let mut res = Ok(());
+    for_each_slag(class, |slag, slag_size, meta| {
+        if res.is_err() {
+            return;
+        }
+        n_slags += 1;
+        objects += meta.n_objects;
+        let (claimed, available) = (*slag).rc.load();
+        let in_use = meta.n_objects - cmp::min(available, meta.n_objects);
+        used += in_use;
+        res = write_slag(
+            w,
+            slag,
+            slag_size,
+            size,
+            meta.n_objects,
+            in_use,
+            claimed,
+            current,
+        );
+    });
+    res?;
+
+    writeln!(
+        w,
+        "  label=\"size class {} B: {} Slags, {} of {} objects in use\";",
+        class,
+        n_slags,
+        used,
+        objects
+    )?;
+    writeln!(w, "}}")
+}
+
+/// Write the node for `slag` and the edges from the current thread's cache to it. `size` is an
+/// object size in the `Slag`'s size class, and `current` is the `Slag` the cache allocates from.
+unsafe fn write_slag<W: Write>(
+    w: &mut W,
+    slag: *mut Slag,
+    slag_size: usize,
+    size: usize,
+    n_objects: usize,
+    in_use: usize,
+    claimed: bool,
+    current: Option<*mut Slag>,
+) -> fmt::Result {
+    // Shades 2 to 9 of the color scheme, so that even empty Slags stand out from the background.
+    let shade = 2 + in_use * 7 / cmp::max(n_objects, 1);
+    write!(
+        w,
+        "  s{:x} [label=\"{:#x}\\n{}/{} in use ({}%)\", ",
+        slag as usize,
+        slag as usize,
+        in_use,
+        n_objects,
+        in_use * 100 / cmp::max(n_objects, 1)
+    )?;
+    writeln!(
+        w,
+        "fillcolor={}, fontcolor={}, penwidth={}];",
+        shade,
+        if shade > 5 { "white" } else { "black" },
+        if claimed { 3 } else { 1 }
+    )?;
+
+    // Walking the cache again for every Slag is quadratic, but needs no memory to keep counts in.
+    let (start, end) = (slag as usize, slag as usize + slag_size);
+    let mut cached = 0;
+    global::cached_objects(size, |obj| if obj as usize >= start && (obj as usize) < end {
+        cached += 1;
+    });
+    if cached > 0 {
+        writeln!(
+            w,
+            "  cache -> s{:x} [label=\"{} cached\"];",
+            slag as usize,
+            cached
+        )?;
+    }
+    if current == Some(slag) {
+        writeln!(
+            w,
+            "  cache -> s{:x} [label=\"allocating\", style=bold];",
+            slag as usize
+        )?;
+    }
+    Ok(())
+}
+
+/// Call `f` with every initialized `Slag` in elfmalloc's memory whose objects are `class` bytes,
+/// along with its size and metadata.
+unsafe fn for_each_slag<F: FnMut(*mut Slag, usize, &Metadata)>(class: usize, mut f: F) {
+    let mut next = 0;
+    while let Some((addr, g)) = ownership::next_owned(next) {
+        match g {
+            Granule::Slags { ty, slag_size } => {
+                let addr = cmp::max(addr, next);
+                let slag = Slag::find(addr as *mut u8, slag_size);
+                next = slag as usize + slag_size;
+                // The Slag may never have been initialized, or have been used for the other type.
+                if let Some(meta) = (*slag).try_metadata() {
+                    if meta.ty == ty && meta.object_size == class {
+                        f(slag, slag_size, meta);
+                    }
+                }
+            }
+            Granule::Large { .. } => next = addr + (1 << GRANULE_SHIFT),
+        }
+    }
+}
+
+/// Write the graph of the `Slag`s of the size class of `size` bytes to the file descriptor `fd`.
+/// See `write_size_class`.
+pub unsafe fn dump_size_class(fd: i32, size: usize) {
+    let _ = write_size_class(&mut FDWriter(fd), size);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dot_shows_slags_and_cache() {
+        unsafe {
+            let objs: Vec<_> = (0..32).map(|_| global::alloc(48)).collect();
+            let slag = match ownership::granule(objs[0]) {
+                Some(Granule::Slags { slag_size, .. }) => Slag::find(objs[0], slag_size),
+                g => panic!("unexpected granule {:?}", g),
+            };
+            for &obj in &objs[16..] {
+                global::free(obj);
+            }
+            let mut out = String::new();
+            write_size_class(&mut out, 48).unwrap();
+            alloc_assert!(out.starts_with("digraph"));
+            let node = format!("s{:x} [label=\"{:#x}", slag as usize, slag as usize);
+            alloc_assert!(out.contains(&node));
+            alloc_assert!(out.contains(&format!("cache -> s{:x}", slag as usize)));
+            alloc_assert!(out.trim_right().ends_with('}'));
+            for &obj in &objs[..16] {
+                global::free(obj);
+            }
+
+            out.clear();
+            write_size_class(&mut out, 16 << 20).unwrap();
+            alloc_assert!(out.contains("large object"));
+        }
+    }
+}
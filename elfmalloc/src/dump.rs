@@ -0,0 +1,327 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Dumping the statistics and the heap profile to files when the process receives a signal.
+//!
+//! Production binaries cannot be rebuilt to call `heap_stats` or `sites::write_massif` at the
+//! right moment. With the `dump_path` option of `ELFMALLOC_CONF` (see the `conf` module), sending
+//! the process `SIGUSR2` (or `SIGUSR1`, with `dump_signal:usr1`) writes a dump instead:
+//!
+//! ```text
+//! ELFMALLOC_CONF=dump_path:/tmp/myapp ./myapp &
+//! kill -USR2 $!
+//! ```
+//!
+//! Each dump `n` (counting from 0) of process `pid` writes `<dump_path>.<pid>.<n>.stats`, with
+//! the profile the allocator was built with and each of the statistics compiled in (`heap_stats`,
+//! `slow_path_stats`, `contention_stats`, `realloc_stats`, `large_cache_stats` and the latency
+//! histograms, depending on the features), and, with the `sites` feature, the current heap profile
+//! by allocation site as `<dump_path>.<pid>.<n>.massif`, in the format of `sites::write_massif`.
+//! Errors are reported on standard error.
+//!
+//! Formatting the statistics allocates, which must not happen in a signal handler, so the handler
+//! only writes a byte to a pipe. A thread of its own, started the first time a thread uses the
+//! global allocator, reads the pipe and writes the dumps. Signals that arrive while a dump is
+//! pending are merged with it. The handler is installed at the same time, saves `errno`, and
+//! passes the signal on to the handler that was installed before it, if there was one. `dump`
+//! writes a dump directly.
+//!
+//! A child created with `fork` inherits the handler, but neither the dump thread nor a pipe of its
+//! own. A `pthread_atfork` handler therefore closes the inherited pipe in the child, so that its
+//! signals do not make the parent write dumps, and creates a new pipe and dump thread. The child's
+//! dumps are named after its pid and counted from 0.
+//!
+//! This module is only available on Linux.
+
+extern crate libc;
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread;
+use self::libc::{c_int, c_void, siginfo_t};
+use super::conf;
+use super::profile::PROFILE;
+
+/// The read and write ends of the pipe from the handler to the dump thread, once it is started.
+static READ_FD: AtomicUsize = ATOMIC_USIZE_INIT;
+static WRITE_FD: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The number of dumps written so far.
+static DUMPS: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Whether the pipe and the dump thread have been started in this process.
+static STARTED: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The action that was installed for the dump signal before ours. Written before ours is
+/// installed.
+static mut PREV: Option<libc::sigaction> = None;
+/// The path passed to `start_with`, for restarting the dump thread in a child. Written before the
+/// `pthread_atfork` handler is registered.
+static mut PATH: Option<&'static [u8]> = None;
+
+/// Install the signal handler and start the dump thread, if the `dump_path` option is set. Only
+/// the first call has an effect.
+pub fn start() {
+    if let Some(path) = conf::dump_path() {
+        let sig = if conf::dump_signal() == 1 {
+            libc::SIGUSR1
+        } else {
+            libc::SIGUSR2
+        };
+        start_with(path, sig);
+    }
+}
+
+fn start_with(path: &'static [u8], sig: c_int) {
+    static INSTALLED: AtomicUsize = ATOMIC_USIZE_INIT;
+    if INSTALLED.swap(1, Ordering::Relaxed) != 0 {
+        return;
+    }
+    if !start_thread(path) {
+        return;
+    }
+    unsafe {
+        PATH = Some(path);
+        libc::pthread_atfork(None, None, Some(after_fork_child));
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handle as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK | libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut prev: libc::sigaction = mem::zeroed();
+        if libc::sigaction(sig, ptr::null(), &mut prev) != 0 {
+            alloc_eprintln!("elfmalloc: sigaction failed for dump_signal");
+            return;
+        }
+        PREV = Some(prev);
+        libc::sigaction(sig, &action, ptr::null_mut());
+    }
+}
+
+/// Create the pipe and start the dump thread, unless they have already been started in this
+/// process. Returns whether they are running.
+fn start_thread(path: &'static [u8]) -> bool {
+    if STARTED.swap(1, Ordering::Relaxed) != 0 {
+        return true;
+    }
+    unsafe {
+        let mut fds = [0 as c_int; 2];
+        if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) != 0 {
+            alloc_eprintln!("elfmalloc: could not create the pipe for dump_path");
+            return false;
+        }
+        // Only the handler's end is non-blocking: a full pipe already has a dump pending.
+        let flags = libc::fcntl(fds[0], libc::F_GETFL);
+        libc::fcntl(fds[0], libc::F_SETFL, flags & !libc::O_NONBLOCK);
+        READ_FD.store(fds[0] as usize, Ordering::Relaxed);
+        WRITE_FD.store(fds[1] as usize, Ordering::Relaxed);
+    }
+    let spawned = thread::Builder::new()
+        .name(String::from("elfmalloc-dump"))
+        .spawn(move || wait(path));
+    if spawned.is_err() {
+        alloc_eprintln!("elfmalloc: could not start the thread for dump_path");
+        return false;
+    }
+    true
+}
+
+/// The `pthread_atfork` child handler: replace the pipe shared with the parent and the dump thread,
+/// which did not survive the fork, with ones of the child's own.
+extern "C" fn after_fork_child() {
+    let read_fd = READ_FD.swap(0, Ordering::Relaxed);
+    let write_fd = WRITE_FD.swap(0, Ordering::Relaxed);
+    unsafe {
+        if write_fd != 0 {
+            libc::close(read_fd as c_int);
+            libc::close(write_fd as c_int);
+        }
+    }
+    DUMPS.store(0, Ordering::Relaxed);
+    STARTED.store(0, Ordering::Relaxed);
+    if let Some(path) = unsafe { PATH } {
+        start_thread(path);
+    }
+}
+
+/// The dump thread: write a dump for every byte read from the pipe.
+fn wait(path: &'static [u8]) {
+    let fd = READ_FD.load(Ordering::Relaxed) as c_int;
+    let mut buf = [0u8; 64];
+    loop {
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+        if n < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+            continue;
+        }
+        if n <= 0 {
+            return;
+        }
+        if let Err(e) = dump_to(path) {
+            alloc_eprintln!("elfmalloc: could not write dump: {}", e);
+        }
+    }
+}
+
+extern "C" fn handle(sig: c_int, info: *mut siginfo_t, ctx: *mut c_void) {
+    unsafe {
+        let errno = *libc::__errno_location();
+        // The pipe is 0 while it is being replaced after a fork.
+        let fd = WRITE_FD.load(Ordering::Relaxed) as c_int;
+        if fd != 0 {
+            libc::write(fd, b"d".as_ptr() as *const c_void, 1);
+        }
+        chain(sig, info, ctx);
+        *libc::__errno_location() = errno;
+    }
+}
+
+/// Pass the signal on to the previous handler, if there was one.
+unsafe fn chain(sig: c_int, info: *mut siginfo_t, ctx: *mut c_void) {
+    if let Some(ref prev) = PREV {
+        if prev.sa_flags & libc::SA_SIGINFO != 0 {
+            let f: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) =
+                mem::transmute(prev.sa_sigaction);
+            f(sig, info, ctx);
+        } else if prev.sa_sigaction != libc::SIG_DFL && prev.sa_sigaction != libc::SIG_IGN {
+            let f: extern "C" fn(c_int) = mem::transmute(prev.sa_sigaction);
+            f(sig);
+        }
+    }
+}
+
+/// Write a dump now, to the files named after the `dump_path` option. Returns an error if the
+/// option is not set.
+pub fn dump() -> io::Result<()> {
+    match conf::dump_path() {
+        Some(path) => dump_to(path),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, "dump_path is not set")),
+    }
+}
+
+fn dump_to(path: &[u8]) -> io::Result<()> {
+    let n = DUMPS.fetch_add(1, Ordering::Relaxed);
+    let prefix = file_prefix(path, unsafe { libc::getpid() } as u32, n);
+    let stats = File::create(OsStr::from_bytes(&suffixed(&prefix, b"stats")))?;
+    let mut stats = BufWriter::new(stats);
+    write_stats(&mut stats, n)?;
+    stats.flush()?;
+    #[cfg(feature = "sites")]
+    {
+        let massif = File::create(OsStr::from_bytes(&suffixed(&prefix, b"massif")))?;
+        let mut massif = BufWriter::new(massif);
+        let cmd = format!("elfmalloc dump {} of process {}", n, unsafe { libc::getpid() });
+        super::sites::write_massif(&mut massif, &cmd)?;
+        massif.flush()?;
+    }
+    Ok(())
+}
+
+/// `<path>.<pid>.<n>`, the names of the files of dump `n` without their extensions.
+fn file_prefix(path: &[u8], pid: u32, n: usize) -> Vec<u8> {
+    let mut name = path.to_vec();
+    let _ = write!(name, ".{}.{}", pid, n);
+    name
+}
+
+fn suffixed(prefix: &[u8], ext: &[u8]) -> Vec<u8> {
+    let mut name = prefix.to_vec();
+    name.push(b'.');
+    name.extend_from_slice(ext);
+    name
+}
+
+/// Write the statistics compiled into this build to `w`. See the module documentation.
+pub fn write_stats<W: Write>(w: &mut W, n: usize) -> io::Result<()> {
+    writeln!(w, "elfmalloc dump {} of process {}", n, unsafe { libc::getpid() })?;
+    writeln!(w, "profile: {}", PROFILE.name)?;
+    #[cfg(feature = "stats")]
+    writeln!(w, "heap_stats: {:#?}", super::stats::heap_stats())?;
+    #[cfg(feature = "slow-path-stats")]
+    writeln!(w, "slow_path_stats: {:#?}", super::stats::slow_path_stats())?;
+    #[cfg(feature = "contention-stats")]
+    writeln!(w, "contention_stats: {:#?}", super::stats::contention_stats())?;
+    #[cfg(feature = "realloc-stats")]
+    writeln!(w, "realloc_stats: {:#?}", super::stats::realloc_stats())?;
+    #[cfg(feature = "large-cache")]
+    writeln!(w, "large_cache_stats: {:#?}", super::large_cache::large_cache_stats())?;
+    #[cfg(feature = "latency-stats")]
+    {
+        writeln!(w, "latency_stats:")?;
+        super::stats::dump_latency_stats(w)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Read;
+    use std::time::Duration;
+
+    #[test]
+    fn names() {
+        let prefix = file_prefix(b"/tmp/app", 42, 3);
+        alloc_assert_eq!(prefix, b"/tmp/app.42.3".to_vec());
+        alloc_assert_eq!(suffixed(&prefix, b"stats"), b"/tmp/app.42.3.stats".to_vec());
+    }
+
+    #[test]
+    fn stats_name_profile() {
+        let mut out = Vec::new();
+        write_stats(&mut out, 7).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        alloc_assert!(out.starts_with("elfmalloc dump 7 of process "));
+        alloc_assert!(out.contains(&format!("profile: {}\n", PROFILE.name)));
+    }
+
+    /// Wait for dump `n` of process `pid` to be written, and remove its files.
+    fn wait_for_dump(path: &[u8], pid: libc::pid_t, n: usize) -> bool {
+        let prefix = file_prefix(path, pid as u32, n);
+        let stats = suffixed(&prefix, b"stats");
+        let stats = OsStr::from_bytes(&stats);
+        for _ in 0..500 {
+            let mut contents = String::new();
+            if let Ok(mut file) = File::open(stats) {
+                file.read_to_string(&mut contents).unwrap();
+            }
+            if contents.contains("profile: ") {
+                let _ = fs::remove_file(stats);
+                let _ = fs::remove_file(OsStr::from_bytes(&suffixed(&prefix, b"massif")));
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn signal_writes_dump() {
+        let path = format!("/tmp/elfmalloc-dump-test-{}", unsafe { libc::getpid() });
+        let path: &'static [u8] = unsafe { &*Box::into_raw(path.into_bytes().into_boxed_slice()) };
+        start_with(path, libc::SIGUSR1);
+        unsafe { libc::raise(libc::SIGUSR1) };
+        alloc_assert!(wait_for_dump(path, unsafe { libc::getpid() }, 0));
+
+        // A child writes its own dumps, and does not make the parent write one.
+        unsafe {
+            let child = libc::fork();
+            if child == 0 {
+                libc::raise(libc::SIGUSR1);
+                let ok = wait_for_dump(path, libc::getpid(), 0);
+                libc::_exit(if ok { 0 } else { 1 });
+            }
+            alloc_assert!(child > 0);
+            let mut status = 0;
+            alloc_assert_eq!(libc::waitpid(child, &mut status, 0), child);
+            // The child exited normally with status 0.
+            alloc_assert_eq!(status, 0);
+        }
+        alloc_assert_eq!(DUMPS.load(Ordering::Relaxed), 1);
+    }
+}
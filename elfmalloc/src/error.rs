@@ -0,0 +1,200 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Structured errors for the fallible allocation APIs.
+//!
+//! `AllocErr` only tells a caller that an allocation failed, but what it should do about it
+//! depends on why: a quota may be lifted by freeing memory, the kernel may be able to commit
+//! memory once caches have been purged (e.g. with `trim`), but an exhausted address space or a
+//! layout that the allocator cannot satisfy will not go away by retrying. The `try_alloc` methods
+//! of `ElfMallocGlobal` and `rust_alloc::ElfMalloc` (and `ElfMallocGlobal::try_realloc`) report an
+//! `ElfAllocError` instead, which can be converted into an `AllocErr` with `From`; their `Alloc`
+//! implementations do just that.
+//!
+//! Small and medium objects of the global allocator are served from address space that is
+//! reserved up front, and the global allocator still aborts if that runs out or a large object
+//! cannot be mapped. Its `try_` methods report quota and layout errors.
+
+#[cfg(all(unix, not(miri)))]
+extern crate libc;
+
+use std::fmt;
+use super::alloc::allocator::{AllocErr, Layout};
+
+/// Why an allocation failed. Each variant records the size and alignment of the request.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ElfAllocError {
+    /// There was no address space left to map the request. Retrying is unlikely to help unless
+    /// large mappings are released.
+    AddressSpace { size: usize, align: usize },
+    /// The address space was available, but the kernel refused to commit memory for it (`ENOMEM`
+    /// under strict overcommit accounting, or a resource limit). Purging caches (`trim`) and
+    /// freeing memory may let a retry succeed.
+    Commit { size: usize, align: usize },
+    /// The allocator cannot satisfy the layout, e.g. because the alignment is larger than the
+    /// alignment it guarantees for objects of that size. Retrying will not help.
+    InvalidLayout { size: usize, align: usize },
+    /// The allocation would exceed a limit set with the `quota` feature or on an
+    /// `IsolatedHeap`. A retry succeeds once enough memory has been freed or the limit raised.
+    QuotaExceeded { size: usize, align: usize },
+}
+
+impl ElfAllocError {
+    /// The size of the failed request.
+    pub fn size(&self) -> usize {
+        match *self {
+            ElfAllocError::AddressSpace { size, .. } |
+            ElfAllocError::Commit { size, .. } |
+            ElfAllocError::InvalidLayout { size, .. } |
+            ElfAllocError::QuotaExceeded { size, .. } => size,
+        }
+    }
+
+    /// The alignment of the failed request.
+    pub fn align(&self) -> usize {
+        match *self {
+            ElfAllocError::AddressSpace { align, .. } |
+            ElfAllocError::Commit { align, .. } |
+            ElfAllocError::InvalidLayout { align, .. } |
+            ElfAllocError::QuotaExceeded { align, .. } => align,
+        }
+    }
+
+    /// Could the same request succeed later, once memory has been freed or purged?
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            ElfAllocError::Commit { .. } | ElfAllocError::QuotaExceeded { .. } => true,
+            ElfAllocError::AddressSpace { .. } | ElfAllocError::InvalidLayout { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for ElfAllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match *self {
+            ElfAllocError::AddressSpace { .. } => "out of address space",
+            ElfAllocError::Commit { .. } => "the kernel could not commit memory",
+            ElfAllocError::InvalidLayout { .. } => "unsupported layout",
+            ElfAllocError::QuotaExceeded { .. } => "allocation limit exceeded",
+        };
+        write!(
+            f,
+            "{} allocating {} bytes (alignment {})",
+            reason,
+            self.size(),
+            self.align()
+        )
+    }
+}
+
+impl From<ElfAllocError> for AllocErr {
+    fn from(err: ElfAllocError) -> AllocErr {
+        match err {
+            ElfAllocError::InvalidLayout { .. } => AllocErr::Unsupported {
+                details: "elfmalloc cannot satisfy the layout's alignment",
+            },
+            _ => match Layout::from_size_align(err.size(), err.align()) {
+                Some(request) => AllocErr::Exhausted { request: request },
+                None => AllocErr::Unsupported {
+                    details: "invalid layout",
+                },
+            },
+        }
+    }
+}
+
+/// Classify the failure to map `size` bytes for a request with alignment `align`.
+///
+/// Both running out of address space and strict overcommit accounting make `mmap` fail with
+/// `ENOMEM`, so this tries to reserve the same amount of address space without committing it. If
+/// that succeeds, it was the commit that failed.
+#[doc(hidden)]
+#[cold]
+pub fn map_failure(size: usize, align: usize) -> ElfAllocError {
+    if can_reserve(size) {
+        ElfAllocError::Commit {
+            size: size,
+            align: align,
+        }
+    } else {
+        ElfAllocError::AddressSpace {
+            size: size,
+            align: align,
+        }
+    }
+}
+
+#[cfg(all(unix, not(miri)))]
+fn can_reserve(size: usize) -> bool {
+    use self::libc::{c_void, mmap, munmap, MAP_ANON, MAP_FAILED, MAP_NORESERVE, MAP_PRIVATE,
+                     PROT_NONE};
+    unsafe {
+        let p = mmap(
+            ::std::ptr::null_mut(),
+            size,
+            PROT_NONE,
+            MAP_PRIVATE | MAP_ANON | MAP_NORESERVE,
+            -1,
+            0,
+        );
+        if p == MAP_FAILED {
+            return false;
+        }
+        munmap(p as *mut c_void, size);
+        true
+    }
+}
+
+/// Without `mmap`, there is no way to tell, so the address space is assumed to be exhausted.
+#[cfg(not(all(unix, not(miri))))]
+fn can_reserve(_size: usize) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_to_alloc_err() {
+        let quota = ElfAllocError::QuotaExceeded {
+            size: 100,
+            align: 8,
+        };
+        alloc_assert!(quota.is_transient());
+        alloc_assert_eq!(
+            AllocErr::from(quota),
+            AllocErr::Exhausted { request: Layout::from_size_align(100, 8).unwrap() }
+        );
+        let layout = ElfAllocError::InvalidLayout {
+            size: 4 << 20,
+            align: 1 << 30,
+        };
+        alloc_assert!(!layout.is_transient());
+        alloc_assert!(AllocErr::from(layout).is_request_unsupported());
+    }
+
+    #[test]
+    fn classify_map_failure() {
+        // Reserving a small range always succeeds, so failing to map it must have been a commit
+        // failure. No address space can hold a range larger than half of it.
+        alloc_assert_eq!(
+            map_failure(1 << 20, 4096),
+            ElfAllocError::Commit {
+                size: 1 << 20,
+                align: 4096,
+            }
+        );
+        alloc_assert_eq!(
+            map_failure(!0 >> 1, 4096),
+            ElfAllocError::AddressSpace {
+                size: !0 >> 1,
+                align: 4096,
+            }
+        );
+    }
+}
@@ -0,0 +1,252 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! An allocator wrapper that fails allocations on purpose, for testing.
+//!
+//! Code that handles allocation failure is rarely run: on a system with overcommit, allocations
+//! almost never fail, and when they do, it is hard to make them fail at the point under test.
+//! `FaultInjectAlloc` wraps any `Alloc` and fails the allocations it is told to, forwarding the
+//! rest:
+//!
+//! - `fail_nth` fails one allocation, chosen by its index, which makes it possible to run a test
+//!   once for every allocation it makes and check that each failure is handled.
+//! - `fail_fraction` fails a random fraction of allocations. `set_seed` makes the sequence of
+//!   failures reproducible.
+//! - `fail_above` fails every allocation larger than a given size.
+//!
+//! The conditions can be combined; an allocation fails if any of them says so. `alloc`,
+//! `alloc_zeroed` and `realloc` all count as allocations, and failures are reported as
+//! `AllocErr::Exhausted`, as a real out-of-memory condition would be. A `realloc` that fails
+//! leaves the original object alone.
+//!
+//! Together with the fallible `AVec::try_reserve` and `AVec::try_push`, this lets the out-of-memory
+//! paths of a container's users be tested:
+//!
+//! ```rust,ignore
+//! let mut a = FaultInjectAlloc::new(SharedAlloc);
+//! a.fail_above(1 << 10);
+//! let mut v = AVec::<u64, _>::new_in(a);
+//! while v.try_push(0).is_ok() {}
+//! ```
+
+use super::alloc::allocator::{Alloc, AllocErr, Layout};
+use super::utils::random;
+
+/// An `Alloc` that forwards to `A` but fails selected allocations. See the module documentation.
+pub struct FaultInjectAlloc<A: Alloc> {
+    inner: A,
+    nth: Option<usize>,
+    fraction: f64,
+    max_size: usize,
+    rng: u64,
+    allocations: usize,
+    failures: usize,
+}
+
+impl<A: Alloc> FaultInjectAlloc<A> {
+    /// Wrap `inner`, initially without failing any allocations.
+    pub fn new(inner: A) -> FaultInjectAlloc<A> {
+        FaultInjectAlloc {
+            inner: inner,
+            nth: None,
+            fraction: 0.0,
+            max_size: !0,
+            rng: random::seed(),
+            allocations: 0,
+            failures: 0,
+        }
+    }
+
+    /// Fail the allocation with index `n`, counting from 0 (as `Iterator::nth` does) across all
+    /// allocations made through this allocator, including those made before the call.
+    pub fn fail_nth(&mut self, n: usize) -> &mut FaultInjectAlloc<A> {
+        self.nth = Some(n);
+        self
+    }
+
+    /// Fail each allocation with probability `fraction`, which must be between 0 and 1.
+    pub fn fail_fraction(&mut self, fraction: f64) -> &mut FaultInjectAlloc<A> {
+        alloc_assert!(
+            fraction >= 0.0 && fraction <= 1.0,
+            "fraction {} is not between 0 and 1",
+            fraction
+        );
+        self.fraction = fraction;
+        self
+    }
+
+    /// Fail every allocation of more than `size` bytes.
+    pub fn fail_above(&mut self, size: usize) -> &mut FaultInjectAlloc<A> {
+        self.max_size = size;
+        self
+    }
+
+    /// Seed the generator that picks the allocations failed by `fail_fraction`, so that a run
+    /// can be reproduced. Otherwise, it is seeded differently for every allocator.
+    pub fn set_seed(&mut self, seed: u64) -> &mut FaultInjectAlloc<A> {
+        // xorshift never leaves the all-zero state.
+        self.rng = if seed == 0 { 1 } else { seed };
+        self
+    }
+
+    /// Stop failing allocations.
+    pub fn clear_faults(&mut self) -> &mut FaultInjectAlloc<A> {
+        self.nth = None;
+        self.fraction = 0.0;
+        self.max_size = !0;
+        self
+    }
+
+    /// The number of allocations made through this allocator, including failed ones.
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
+
+    /// The number of allocations that were failed on purpose.
+    pub fn failures(&self) -> usize {
+        self.failures
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut A {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> A {
+        self.inner
+    }
+
+    /// Count an allocation of `l`, returning an error if it should fail.
+    fn check(&mut self, l: &Layout) -> Result<(), AllocErr> {
+        let ix = self.allocations;
+        self.allocations += 1;
+        let mut fail = self.nth == Some(ix) || l.size() > self.max_size;
+        if self.fraction > 0.0 {
+            // The top 53 bits make a uniformly distributed f64 in [0, 1).
+            let r = (random::next(&mut self.rng) >> 11) as f64 / (1u64 << 53) as f64;
+            fail |= r < self.fraction;
+        }
+        if fail {
+            self.failures += 1;
+            Err(AllocErr::Exhausted { request: l.clone() })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+unsafe impl<A: Alloc> Alloc for FaultInjectAlloc<A> {
+    unsafe fn alloc(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        self.check(&l)?;
+        self.inner.alloc(l)
+    }
+
+    unsafe fn alloc_zeroed(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        self.check(&l)?;
+        self.inner.alloc_zeroed(l)
+    }
+
+    unsafe fn dealloc(&mut self, p: *mut u8, l: Layout) {
+        self.inner.dealloc(p, l)
+    }
+
+    unsafe fn realloc(
+        &mut self,
+        p: *mut u8,
+        old_l: Layout,
+        new_l: Layout,
+    ) -> Result<*mut u8, AllocErr> {
+        self.check(&new_l)?;
+        self.inner.realloc(p, old_l, new_l)
+    }
+
+    fn usable_size(&self, l: &Layout) -> (usize, usize) {
+        self.inner.usable_size(l)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_alloc::SharedAlloc;
+    use vec_alloc::AVec;
+
+    fn small() -> Layout {
+        Layout::from_size_align(64, 8).unwrap()
+    }
+
+    #[test]
+    fn fail_nth_and_above() {
+        let mut a = FaultInjectAlloc::new(SharedAlloc);
+        a.fail_nth(2);
+        unsafe {
+            let results: Vec<_> = (0..5).map(|_| a.alloc(small())).collect();
+            for (i, r) in results.iter().enumerate() {
+                alloc_assert_eq!(r.is_err(), i == 2);
+            }
+            alloc_assert_eq!(
+                results[2],
+                Err(AllocErr::Exhausted { request: small() })
+            );
+            for p in results.into_iter().filter_map(Result::ok) {
+                a.dealloc(p, small());
+            }
+
+            a.clear_faults().fail_above(4096);
+            let big = Layout::from_size_align(4097, 8).unwrap();
+            let p = a.alloc(Layout::from_size_align(4096, 8).unwrap()).unwrap();
+            alloc_assert!(a.alloc(big.clone()).is_err());
+            alloc_assert!(a.alloc_zeroed(big.clone()).is_err());
+            alloc_assert!(a.realloc(p, Layout::from_size_align(4096, 8).unwrap(), big).is_err());
+            // The object survives the failed realloc.
+            a.dealloc(p, Layout::from_size_align(4096, 8).unwrap());
+        }
+        alloc_assert_eq!(a.allocations(), 9);
+        alloc_assert_eq!(a.failures(), 4);
+    }
+
+    #[test]
+    fn fail_fraction() {
+        let run = |fraction, seed| {
+            let mut a = FaultInjectAlloc::new(SharedAlloc);
+            a.fail_fraction(fraction).set_seed(seed);
+            unsafe {
+                (0..1000)
+                    .map(|_| match a.alloc(small()) {
+                        Ok(p) => {
+                            a.dealloc(p, small());
+                            true
+                        }
+                        Err(_) => false,
+                    })
+                    .collect::<Vec<_>>()
+            }
+        };
+        alloc_assert!(run(0.0, 1).iter().all(|&ok| ok));
+        alloc_assert!(run(1.0, 1).iter().all(|&ok| !ok));
+        let half = run(0.5, 42);
+        let failed = half.iter().filter(|&&ok| !ok).count();
+        alloc_assert!(failed > 400 && failed < 600, "{} of 1000 failed", failed);
+        alloc_assert_eq!(half, run(0.5, 42));
+    }
+
+    #[test]
+    fn avec_oom() {
+        let mut a = FaultInjectAlloc::new(SharedAlloc);
+        a.fail_above(1 << 10);
+        let mut v = AVec::<u64, _>::new_in(a);
+        let mut n = 0;
+        while v.try_push(n).is_ok() {
+            n += 1;
+        }
+        alloc_assert!(n > 0 && n * 8 <= 1 << 10);
+        alloc_assert_eq!(&*v, &(0..n).collect::<Vec<_>>()[..]);
+    }
+}
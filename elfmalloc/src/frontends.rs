@@ -14,8 +14,13 @@
 //! latter task is implemented in the `general` module.
 use super::slag::*;
 use super::sources::MmapSource;
-use super::utils::{likely, OwnedArray, LazyInitializable, mmap};
+use super::utils::{likely, CachePadded, OwnedArray, LazyInitializable, mmap};
 use super::alloc_type::AllocType;
+use super::profile::{BuildConfig, Config};
+#[cfg(feature = "contention-stats")]
+use super::stats::contention;
+#[cfg(feature = "cache-decay")]
+use super::decay::LastUse;
 use std::marker::PhantomData;
 use std::mem;
 use std::ptr;
@@ -24,6 +29,37 @@ use std::cmp;
 pub trait Frontend: LazyInitializable + Clone {
     unsafe fn alloc(&mut self) -> *mut u8;
     unsafe fn free(&mut self, item: *mut u8);
+
+    /// Release memory held by this frontend.
+    ///
+    /// At `level` 1 and above, all cached objects are freed back to their `Slag`s. At `level` 2
+    /// and above, free pages of the available `Slag`s for this size class are uncommitted as
+    /// well. Returns the number of bytes uncommitted.
+    unsafe fn trim(&mut self, level: usize) -> usize;
+
+    /// Give up as much of the cache as possible because the owning thread is going to sleep.
+    ///
+    /// By default, cached objects are freed back to their `Slag`s, as with `trim(1)`.
+    unsafe fn park(&mut self) {
+        self.trim(1);
+    }
+
+    /// Prepare for allocation after `park`. By default this does nothing, and the cache is
+    /// refilled on demand.
+    unsafe fn unpark(&mut self) {}
+
+    /// The `Slag` currently owned by this frontend.
+    fn current_slag(&self) -> *mut Slag;
+
+    /// Call `f` with every object held in this frontend's caches, i.e. freed to it or taken from
+    /// a `Slag` by it and not handed out since. This is for debugging tools, and must not
+    /// allocate.
+    fn for_each_cached<F: FnMut(*mut u8)>(&self, f: F);
+
+    /// When this frontend was last used, in the milliseconds of `decay::now_ms`, as of a check at
+    /// `now`. See the `decay` module.
+    #[cfg(feature = "cache-decay")]
+    fn last_used(&mut self, now: u64) -> u64;
 }
 
 /// A `LocalCache` provides thread-local data on top of a `SlagAllocator`.
@@ -38,6 +74,8 @@ pub struct LocalCache<CA: CoarseAllocator> {
     alloc: SlagAllocator<CA>,
     vals: PtrStack,
     iter: AllocIter,
+    #[cfg(feature = "cache-decay")]
+    last_use: LastUse,
 }
 
 impl<CA: CoarseAllocator> Drop for LocalCache<CA> {
@@ -70,6 +108,8 @@ impl<CA: CoarseAllocator> LocalCache<CA> {
                 alloc: alloc,
                 vals: stack,
                 iter: iter,
+                #[cfg(feature = "cache-decay")]
+                last_use: LastUse::default(),
             }
         }
     }
@@ -77,6 +117,8 @@ impl<CA: CoarseAllocator> LocalCache<CA> {
 
 impl<CA: CoarseAllocator> Frontend for LocalCache<CA> {
     unsafe fn free(&mut self, it: *mut u8) {
+        #[cfg(feature = "cache-decay")]
+        self.last_use.touch();
         if self.alloc.contains(it) {
             self.vals.push(it);
         } else {
@@ -85,14 +127,38 @@ impl<CA: CoarseAllocator> Frontend for LocalCache<CA> {
     }
 
     unsafe fn alloc(&mut self) -> *mut u8 {
+        #[cfg(feature = "cache-decay")]
+        self.last_use.touch();
         self.vals
             .pop()
             .or_else(|| self.iter.next())
-            .unwrap_or_else(|| {
-                let next_iter = self.alloc.refresh();
-                self.iter = next_iter;
-                self.iter.next().expect("New iterator should have values")
-            })
+            .unwrap_or_else(|| self.alloc.refill(&mut self.iter))
+    }
+
+    unsafe fn trim(&mut self, level: usize) -> usize {
+        if level >= 1 {
+            while let Some(item) = self.vals.pop() {
+                self.alloc.free(item)
+            }
+        }
+        if level >= 2 {
+            self.alloc.trim_available()
+        } else {
+            0
+        }
+    }
+
+    fn current_slag(&self) -> *mut Slag {
+        self.alloc.slag
+    }
+
+    fn for_each_cached<F: FnMut(*mut u8)>(&self, f: F) {
+        self.vals.for_each(f)
+    }
+
+    #[cfg(feature = "cache-decay")]
+    fn last_used(&mut self, now: u64) -> u64 {
+        self.last_use.get(now)
     }
 }
 
@@ -111,19 +177,27 @@ pub struct MagazineCache<CA: CoarseAllocator> {
     iter: AllocIter,
     alloc: SlagAllocator<CA>,
     coalescer: Coalescer,
+    #[cfg(feature = "cache-decay")]
+    last_use: LastUse,
 }
 
+/// The parameters of a thread cache: its size class's metadata, the eager decommit threshold, the
+/// magazine size for medium objects (see `Config::MAGAZINE_OBJECTS`), and the shared pools of pages
+/// and `Slag`s.
+pub type CacheParams<CA> = (*mut Metadata, usize, usize, CA, RevocablePipe<Slag>);
+
 impl<CA: CoarseAllocator> LazyInitializable for MagazineCache<CA> {
-    type Params = (*mut Metadata, usize, CA, RevocablePipe<Slag>);
-    fn init(&(meta, decommit, ref page_alloc, ref avail): &Self::Params) -> Self {
+    type Params = CacheParams<CA>;
+    fn init(&(meta, decommit, objects, ref page_alloc, ref avail): &Self::Params) -> Self {
         let salloc = SlagAllocator::partial_new(meta, decommit, page_alloc.clone(), avail.clone());
-        Self::new(salloc)
+        Self::with_magazine_objects(salloc, objects)
     }
 }
 
 impl<CA: CoarseAllocator> LazyInitializable for LocalCache<CA> {
-    type Params = (*mut Metadata, usize, CA, RevocablePipe<Slag>);
-    fn init(&(meta, decommit, ref page_alloc, ref avail): &Self::Params) -> Self {
+    type Params = CacheParams<CA>;
+    // A `LocalCache` holds at most a `Slag`'s worth of objects, so the magazine size is unused.
+    fn init(&(meta, decommit, _, ref page_alloc, ref avail): &Self::Params) -> Self {
         let salloc = SlagAllocator::partial_new(meta, decommit, page_alloc.clone(), avail.clone());
         Self::new(salloc)
     }
@@ -152,6 +226,18 @@ impl<CA: CoarseAllocator> Clone for MagazineCache<CA> {
     }
 }
 
+/// The number of objects of `object_size` bytes in a magazine, given the `objects` of
+/// `Config::MAGAZINE_OBJECTS`.
+fn magazine_size(object_size: usize, objects: usize) -> usize {
+    const CUTOFF: usize = 32 << 10;
+    let magazine_size = match object_size {
+        0...512 => objects * 128,
+        513...CUTOFF => objects,
+        _ => 1,
+    };
+    cmp::max(1, magazine_size)
+}
+
 impl<CA: CoarseAllocator> MagazineCache<CA> {
     pub fn new_sized(mut alloc: SlagAllocator<CA>, magazine_size: usize) -> Self {
         alloc_assert!(magazine_size > 0);
@@ -164,46 +250,54 @@ impl<CA: CoarseAllocator> MagazineCache<CA> {
             iter: iter,
             alloc: alloc,
             coalescer: buckets,
+            #[cfg(feature = "cache-decay")]
+            last_use: LastUse::default(),
         }
     }
 
     pub fn new(alloc: SlagAllocator<CA>) -> Self {
-        use std::cmp;
+        Self::with_magazine_objects(alloc, BuildConfig::MAGAZINE_OBJECTS)
+    }
+
+    /// Create a cache whose magazine size is derived from `objects` as described for
+    /// `Config::MAGAZINE_OBJECTS`.
+    pub fn with_magazine_objects(alloc: SlagAllocator<CA>, objects: usize) -> Self {
         let object_size = unsafe { (*alloc.m).object_size };
-        const CUTOFF: usize = 32 << 10;
-        let magazine_size = match object_size {
-            0...512 => 1 << 16,
-            513...CUTOFF => 512 << 10 / object_size,
-            _ => 1 << 20 / object_size,
-        };
-        Self::new_sized(alloc, cmp::max(1, magazine_size))
+        Self::new_sized(alloc, magazine_size(object_size, objects))
     }
 
     /// Allocate memory from the current owned `Slag`.
     ///
     /// This amounts to getting memory from the current alloc iterator. If the iterator is
     /// exhausted, a new `Slag` is acquired.
+    ///
+    /// This is kept out of line so that `alloc` inlines to a bounds check and a pop.
+    #[cold]
+    #[inline(never)]
     unsafe fn slag_alloc(&mut self) -> *mut u8 {
-        for _ in 0..2 {
-            match self.iter.next() {
-                Some(ptr) => return ptr,
-                None => self.iter = self.alloc.refresh(),
-            }
+        match self.iter.next() {
+            Some(ptr) => ptr,
+            None => self.alloc.refill(&mut self.iter),
         }
-        panic!(
-            "New slag is empty {:?} {:?}",
-            self.alloc.slag,
-            (*self.alloc.slag).rc.load()
-        )
     }
 
     /// Perform the bulk-level frees for the `Coalescer`.
     unsafe fn return_memory(&mut self) {
         alloc_debug_assert_eq!(self.s.top as usize, self.stack_size);
         let new_top = self.stack_size / 2;
+        self.return_from(new_top);
+    }
+
+    /// Free all objects in the stack above `new_top`, batching frees with the `Coalescer`.
+    unsafe fn return_from(&mut self, new_top: usize) {
+        let top = self.s.top;
+        #[cfg(feature = "contention-stats")]
+        let timer = contention::Timer::start(&contention::DRAINS);
+        contention_event!(DRAINED_OBJECTS, top - new_top);
+        slow_path_event!(DRAINS);
         let meta = &*self.alloc.m;
         // iterate over the stack and attempt to add them to the coalescer.
-        for i in new_top..self.stack_size {
+        for i in new_top..top {
             let item = *self.s.data.get(i);
             if !self.coalescer.insert(item, meta) {
                 // there was a "hash collision", so we simply free `item` directly
@@ -221,11 +315,17 @@ impl<CA: CoarseAllocator> MagazineCache<CA> {
             ptr::write(cell, RemoteFreeCell::default());
         }
         self.coalescer.1.top = 0;
+        #[cfg(feature = "contention-stats")]
+        timer.finish(&contention::SAMPLED_DRAINS, &contention::DRAIN_NANOS);
     }
 }
 
 impl<CA: CoarseAllocator> Frontend for MagazineCache<CA> {
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
     unsafe fn alloc(&mut self) -> *mut u8 {
+        #[cfg(feature = "cache-decay")]
+        self.last_use.touch();
         if let Some(ptr) = self.s.pop() {
             trace_event!(cache_alloc);
             ptr
@@ -237,6 +337,8 @@ impl<CA: CoarseAllocator> Frontend for MagazineCache<CA> {
 
     unsafe fn free(&mut self, item: *mut u8) {
         trace_event!(local_free);
+        #[cfg(feature = "cache-decay")]
+        self.last_use.touch();
         if likely(self.s.top < self.stack_size) {
             self.s.push(item);
             return;
@@ -244,6 +346,30 @@ impl<CA: CoarseAllocator> Frontend for MagazineCache<CA> {
         self.return_memory();
         self.s.push(item);
     }
+
+    unsafe fn trim(&mut self, level: usize) -> usize {
+        if level >= 1 {
+            self.return_from(0);
+        }
+        if level >= 2 {
+            self.alloc.trim_available()
+        } else {
+            0
+        }
+    }
+
+    fn current_slag(&self) -> *mut Slag {
+        self.alloc.slag
+    }
+
+    fn for_each_cached<F: FnMut(*mut u8)>(&self, f: F) {
+        self.s.for_each(f)
+    }
+
+    #[cfg(feature = "cache-decay")]
+    fn last_used(&mut self, now: u64) -> u64 {
+        self.last_use.get(now)
+    }
 }
 
 /// A set data-structure used to batch remote free operations.
@@ -352,17 +478,21 @@ impl PtrStack {
         }
     }
 
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
     unsafe fn push(&mut self, item: *mut u8) {
         *self.data.get(self.top) = item;
         self.top += 1;
     }
 
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
     unsafe fn pop(&mut self) -> Option<*mut u8> {
-        if self.empty() {
-            None
-        } else {
+        if likely(!self.empty()) {
             self.top -= 1;
             Some(*self.data.get(self.top))
+        } else {
+            None
         }
     }
 
@@ -370,6 +500,12 @@ impl PtrStack {
     fn empty(&self) -> bool {
         self.top == 0
     }
+
+    fn for_each<F: FnMut(*mut u8)>(&self, mut f: F) {
+        for i in 0..self.top {
+            f(unsafe { *self.data.get(i) });
+        }
+    }
 }
 
 pub use self::magazine::{Depot, DepotCache};
@@ -405,11 +541,20 @@ mod magazine {
     //! The main difference here is that we do not allow magazines to grow dynamically. This is
     //! because we implement the `Depot` using a `BagPipe`; as a result, the `Depot` should not be
     //! a source of contention except in the case where magazines are quite small.
+    //!
+    //! ## Sharding
+    //! On machines with many cores, a single set of `BagPipe`s still has every core exchanging
+    //! magazines through the same cache lines. The `Depot` is therefore split into shards, one per
+    //! group of `CPUS_PER_SHARD` CPUs, and each thread uses the shard of the CPU it is running on
+    //! (see `utils::cpu`). Magazines are returned to the current shard; a thread that finds no
+    //! full magazine in its own shard looks in the others before falling back to the underlying
+    //! `Frontend`, so that magazines freed on one core group are not stranded there.
 
     use super::*;
     use super::super::bagpipe::bag::WeakBag;
     use super::super::bagpipe::{BagPipe, BagCleanup};
     use super::super::bagpipe::queue::FAAQueueLowLevel;
+    use super::super::utils::cpu;
 
     /// A Custom destructor for Magazines in a `BagPipe`.
     #[derive(Copy, Clone, Default)]
@@ -499,14 +644,31 @@ mod magazine {
         }
     }
 
+    /// The number of CPUs that share a shard of a `Depot`.
+    pub const CPUS_PER_SHARD: usize = 4;
+    const MAX_SHARDS: usize = 64;
+
+    /// The `Magazine`s of one group of CPUs. Shards are padded to a cache line so that threads
+    /// on different core groups do not write to the same line.
+    struct DepotShard {
+        empty: MagPipe,
+        full: MagPipe,
+    }
+
     /// A global cache for empty and full `Magazine`s.
+    ///
+    /// Clones of a `Depot` share its shards, which are never freed.
     #[derive(Clone)]
     pub struct Depot {
+        /// The maximum number of `Magazine`s of each kind in a shard.
         max_size: isize,
-        empty: MagPipe,
-        full: MagPipe,
+        shards: *mut CachePadded<DepotShard>,
+        n_shards: usize,
     }
 
+    unsafe impl Send for Depot {}
+    unsafe impl Sync for Depot {}
+
     impl Default for Depot {
         fn default() -> Depot {
             Depot::new()
@@ -528,18 +690,50 @@ mod magazine {
     }
 
     impl Depot {
-        fn new_size(max_size: usize, empty: usize, full: usize) -> Depot {
+        fn new_size(max_size: usize, n_shards: usize, empty: usize, full: usize) -> Depot {
             alloc_assert!(max_size < (isize::max_value() as usize));
+            alloc_assert!(n_shards > 0);
+            let shards = mmap::map(mem::size_of::<CachePadded<DepotShard>>() * n_shards) as
+                *mut CachePadded<DepotShard>;
+            for i in 0..n_shards {
+                unsafe {
+                    ptr::write(
+                        shards.offset(i as isize),
+                        CachePadded(DepotShard {
+                            empty: MagPipe::new_size(empty),
+                            full: MagPipe::new_size(full),
+                        }),
+                    );
+                }
+            }
             Depot {
                 max_size: max_size as isize,
-                empty: MagPipe::new_size(empty),
-                full: MagPipe::new_size(full),
+                shards: shards,
+                n_shards: n_shards,
             }
         }
 
         fn new() -> Depot {
             use super::super::num_cpus;
-            Depot::new_size(1 << 20, num_cpus::get(), num_cpus::get())
+            let cpus = num_cpus::get();
+            let n_shards = cmp::min(MAX_SHARDS, (cpus + CPUS_PER_SHARD - 1) / CPUS_PER_SHARD);
+            let per_shard = cmp::min(cpus, CPUS_PER_SHARD);
+            Depot::new_size(1 << 20, cmp::max(1, n_shards), per_shard, per_shard)
+        }
+
+        /// The shard with index `i`.
+        fn shard(&self, i: usize) -> &mut DepotShard {
+            alloc_debug_assert!(i < self.n_shards);
+            unsafe { &mut (*self.shards.offset(i as isize)).0 }
+        }
+
+        /// The index of the current CPU's shard.
+        fn home(&self) -> usize {
+            if self.n_shards == 1 {
+                0
+            } else {
+                cpu::current() / CPUS_PER_SHARD % self.n_shards
+            }
         }
 
         /// Return an empty `Magazine` to the `Depot`.
@@ -547,10 +741,11 @@ mod magazine {
         /// If the `Depot` is at capacity, the `Magazine`'s memory is unmapped.
         unsafe fn free_empty(&mut self, m: *mut Magazine) {
             alloc_debug_assert_eq!((*m).top, 0);
-            if self.empty.size_guess() >= self.max_size {
+            let shard = self.shard(self.home());
+            if shard.empty.size_guess() >= self.max_size {
                 Magazine::destroy(m);
             } else {
-                self.empty.push_mut(m)
+                shard.empty.push_mut(m)
             }
         }
 
@@ -562,28 +757,35 @@ mod magazine {
             unsafe {
                 alloc_debug_assert_eq!((*m).top, (*m).cap)
             };
-            if self.full.size_guess() >= self.max_size {
+            let shard = self.shard(self.home());
+            if shard.full.size_guess() >= self.max_size {
                 false
             } else {
-                self.full.push_mut(m);
+                shard.full.push_mut(m);
                 true
             }
         }
 
-        /// Allocate a full `Magazine` from the `Depot` if one is present.
+        /// Allocate a full `Magazine` from the `Depot` if one is present, preferring the current
+        /// CPU's shard.
         fn alloc_full(&mut self) -> Option<*mut Magazine> {
-            self.full.pop_mut().and_then(|r| {
-                unsafe {
-                    alloc_debug_assert_eq!((*r).top, (*r).cap)
-                };
-                Some(r)
-            })
+            let home = self.home();
+            for i in 0..self.n_shards {
+                let shard = self.shard((home + i) % self.n_shards);
+                if let Some(r) = shard.full.pop_mut() {
+                    unsafe {
+                        alloc_debug_assert_eq!((*r).top, (*r).cap)
+                    };
+                    return Some(r);
+                }
+            }
+            None
         }
 
         /// Allocate a full `Magazine` from the `Depot`, constructing a new one if none are
         /// present.
         fn alloc_empty(&mut self) -> *mut Magazine {
-            let res = self.empty.pop_mut().unwrap_or_else(|| {
+            let res = self.shard(self.home()).empty.pop_mut().unwrap_or_else(|| {
                 unsafe {
                     Magazine::default()
                 }
@@ -627,6 +829,8 @@ mod magazine {
         depot: Depot,
         m1: *mut Magazine,
         m2: *mut Magazine,
+        #[cfg(feature = "cache-decay")]
+        last_use: LastUse,
     }
 
     impl<FE: Frontend> LazyInitializable for DepotCache<FE> {
@@ -645,12 +849,29 @@ mod magazine {
                 depot: depot,
                 m1: m1,
                 m2: m2,
+                #[cfg(feature = "cache-decay")]
+                last_use: LastUse::default(),
             }
         }
+
+        /// Empty `m` for `park`, returning the `Magazine` to use in its place.
+        unsafe fn park_magazine(&mut self, m: *mut Magazine) -> *mut Magazine {
+            if (*m).top == (*m).cap {
+                if let Some(empty) = self.depot.swap_full(m) {
+                    return empty;
+                }
+            }
+            while let Some(p) = (*m).pop() {
+                self.backing.free(p);
+            }
+            m
+        }
     }
 
     impl<FE: Frontend> Frontend for DepotCache<FE> {
         unsafe fn alloc(&mut self) -> *mut u8 {
+            #[cfg(feature = "cache-decay")]
+            self.last_use.touch();
             if let Some(p) = (*self.m1).pop() {
                 return p;
             }
@@ -672,6 +893,8 @@ mod magazine {
         }
 
         unsafe fn free(&mut self, item: *mut u8) {
+            #[cfg(feature = "cache-decay")]
+            self.last_use.touch();
             if (*self.m1).push(item) {
                 return;
             }
@@ -691,6 +914,58 @@ mod magazine {
             let _r = (*self.m1).push(item);
             alloc_debug_assert!(_r);
         }
+
+        unsafe fn trim(&mut self, level: usize) -> usize {
+            if level >= 1 {
+                for m in &[self.m1, self.m2] {
+                    let m_raw: *mut Magazine = *m;
+                    while let Some(p) = (*m_raw).pop() {
+                        self.backing.free(p);
+                    }
+                }
+            }
+            self.backing.trim(level)
+        }
+
+        /// Donate full `Magazine`s to the `Depot`, where other threads can pick them up, and free
+        /// the objects in the others.
+        unsafe fn park(&mut self) {
+            let (m1, m2) = (self.m1, self.m2);
+            self.m1 = self.park_magazine(m1);
+            self.m2 = self.park_magazine(m2);
+            self.backing.park()
+        }
+
+        /// Reclaim a full `Magazine` from the `Depot`, if there is one.
+        unsafe fn unpark(&mut self) {
+            if (*self.m1).top == 0 {
+                if let Some(m) = self.depot.swap_empty(self.m1) {
+                    self.m1 = m;
+                }
+            }
+            self.backing.unpark()
+        }
+
+        fn current_slag(&self) -> *mut Slag {
+            self.backing.current_slag()
+        }
+
+        fn for_each_cached<F: FnMut(*mut u8)>(&self, mut f: F) {
+            for &m in &[self.m1, self.m2] {
+                let m = unsafe { &*m };
+                for i in 0..m.top {
+                    f(unsafe { *(m.base as *mut *mut u8).offset(i as isize) });
+                }
+            }
+            self.backing.for_each_cached(f)
+        }
+
+        /// Objects can come and go through the `Magazine`s without touching the backing
+        /// `Frontend`, so a `DepotCache` keeps track of its own use.
+        #[cfg(feature = "cache-decay")]
+        fn last_used(&mut self, now: u64) -> u64 {
+            self.last_use.get(now)
+        }
     }
 
     #[cfg(test)]
@@ -713,6 +988,24 @@ mod magazine {
                 Magazine::destroy(m);
             }
         }
+
+        #[test]
+        fn depot_shards() {
+            unsafe {
+                let mut depot = Depot::new_size(4, 4, 4, 4);
+                let m = Magazine::default();
+                while (*m).push(8 as *mut u8) {}
+                // A full magazine in another shard is found before giving up.
+                let other = (depot.home() + 1) % depot.n_shards;
+                depot.shard(other).full.push_mut(m);
+                alloc_assert_eq!(depot.alloc_full(), Some(m));
+                alloc_assert_eq!(depot.alloc_full(), None);
+                alloc_assert!(depot.free_full(m));
+                alloc_assert_eq!(depot.alloc_full(), Some(m));
+                (*m).top = 0;
+                depot.free_empty(m);
+            }
+        }
     }
 }
 
@@ -829,10 +1122,24 @@ macro_rules! typed_wrapper {
 typed_wrapper!(LocalAllocator, LocalCache);
 typed_wrapper!(MagazineAllocator, MagazineCache);
 
+/// The thread cache's allocation fast path, instantiated with the default page source so that
+/// its generated code can be inspected. `asm_check.sh` fails if it grows beyond a handful of
+/// instructions; it is not meant to be called.
+#[doc(hidden)]
+#[inline(never)]
+pub unsafe fn cache_pop_probe(cache: &mut MagazineCache<PageAlloc<MmapSource>>) -> *mut u8 {
+    cache.alloc()
+}
+
 #[cfg(test)]
 mod tests {
     extern crate env_logger;
+    extern crate object_alloc;
+    extern crate object_alloc_test;
     use super::*;
+    use self::object_alloc::{Exhausted, ObjectAlloc};
+    use self::object_alloc_test::corruption::{ConcurrentTestBuilder, CorruptionTesterDefault};
+    use self::object_alloc_test::types::Byte64;
     use std::thread;
     use std::ptr::write_volatile;
     use std::collections::HashSet;
@@ -960,4 +1267,71 @@ mod tests {
             t.join().expect("threads should exit successfully");
         }
     }
+
+    /// Adapts the typed allocators to `ObjectAlloc` for `object_alloc_test`'s corruption tests.
+    /// Unlike a slab allocator, elfmalloc does not cache constructed objects, so objects are
+    /// constructed on every allocation and dropped on every free.
+    macro_rules! test_object_alloc {
+        ($name:ident, $wrapped:ident) => {
+            struct $name<T>($wrapped<T>);
+
+            impl<T> Clone for $name<T> {
+                fn clone(&self) -> Self {
+                    $name(self.0.clone())
+                }
+            }
+
+            unsafe impl<T: Default> ObjectAlloc<T> for $name<T> {
+                unsafe fn alloc(&mut self) -> Result<*mut T, Exhausted> {
+                    let item = self.0.alloc();
+                    ptr::write(item, T::default());
+                    Ok(item)
+                }
+
+                unsafe fn dealloc(&mut self, item: *mut T) {
+                    ptr::drop_in_place(item);
+                    self.0.free(item)
+                }
+            }
+        };
+    }
+
+    test_object_alloc!(TestMagazineAllocator, MagazineAllocator);
+    test_object_alloc!(TestLocalAllocator, LocalAllocator);
+
+    type Tester = CorruptionTesterDefault<Byte64>;
+
+    #[test]
+    fn concurrent_corruption_magazine() {
+        let _ = env_logger::init();
+        let oa = AllocBuilder::<Tester>::default()
+            .page_size(4096)
+            .build_magazine();
+        ConcurrentTestBuilder::new(TestMagazineAllocator(oa)).test();
+    }
+
+    #[test]
+    fn concurrent_corruption_local() {
+        let _ = env_logger::init();
+        let oa = AllocBuilder::<Tester>::default()
+            .page_size(4096)
+            .build_local();
+        ConcurrentTestBuilder::new(TestLocalAllocator(oa)).test();
+    }
+
+    #[test]
+    fn magazine_sizes() {
+        use super::super::profile::DefaultConfig;
+        // The default build keeps the magazine sizes it had before `Config` existed.
+        let objects = DefaultConfig::MAGAZINE_OBJECTS;
+        alloc_assert_eq!(magazine_size(16, objects), 1 << 16);
+        alloc_assert_eq!(magazine_size(512, objects), 1 << 16);
+        alloc_assert_eq!(magazine_size(513, objects), 512);
+        alloc_assert_eq!(magazine_size(1024, objects), 512);
+        alloc_assert_eq!(magazine_size(8 << 10, objects), 512);
+        alloc_assert_eq!(magazine_size(32 << 10, objects), 512);
+        alloc_assert_eq!(magazine_size((32 << 10) + 1, objects), 1);
+        alloc_assert_eq!(magazine_size(64 << 10, objects), 1);
+        alloc_assert_eq!(magazine_size(4 << 20, objects), 1);
+    }
 }
@@ -43,6 +43,7 @@
 //! object-specific allocators.
 
 use std::cmp;
+use std::marker::PhantomData;
 use std::ptr;
 use std::mem;
 
@@ -53,8 +54,47 @@ use super::slag::{compute_metadata, CoarseAllocator, DirtyFn, Metadata, PageAllo
                   Slag, PageCleanup};
 #[allow(unused_imports)]
 use super::frontends::{MagazineCache, LocalCache, DepotCache, Depot, Frontend};
-use super::utils::{mmap, Lazy, TypedArray, likely};
+use super::utils::{mmap, map_addr, Lazy, StaticCell, TypedArray, likely, unlikely};
 use super::alloc_type::AllocType;
+use super::conf;
+use super::integrity::{self, Violation};
+use super::profile::{BuildConfig, Config, Profile};
+#[cfg(feature = "tags")]
+use super::tags::{self, Label, Tag, LABELS};
+#[cfg(feature = "sites")]
+use super::sites::SiteId;
+#[cfg(feature = "site-pools")]
+use super::site_pools;
+#[cfg(feature = "leak-report")]
+use super::leaks;
+#[cfg(feature = "asan")]
+use super::asan;
+#[cfg(feature = "valgrind")]
+use super::valgrind;
+#[cfg(feature = "tsan")]
+use super::tsan;
+#[cfg(feature = "msan")]
+use super::msan;
+#[cfg(feature = "mte")]
+use super::mte;
+#[cfg(feature = "zero-on-free")]
+use super::zero;
+#[cfg(feature = "quota")]
+use super::quota;
+#[cfg(feature = "quarantine")]
+use super::quarantine::Quarantine;
+#[cfg(feature = "latency-stats")]
+use super::stats::{latency, LatencyOp};
+#[cfg(feature = "stats")]
+use super::stats::heap;
+#[cfg(feature = "cache-decay")]
+use super::decay;
+#[cfg(feature = "alloc-guard")]
+use super::alloc_guard;
+#[cfg(feature = "batch-unmap")]
+use super::unmap_batch::RangeBatch;
+#[cfg(feature = "latency-stats")]
+use std::time::Instant;
 
 type Source = MmapSource;
 
@@ -62,10 +102,19 @@ pub mod global {
     //! A global malloc-style interface to interact with a `DynamicAllocator`. All of these
     //! structures are lazily initailized.
     //!
-    //! One could be forgiven for thinking that this could work by simply using a global
-    //! `lazy_static`-managed instance of a `DynamicAllocator` and then using thread-local storage
-    //! (TLS) to store handles to this global instance. While this is essentially the architecture
-    //! we use, a number of hacks have been added to ensure correctness.
+    //! One could be forgiven for thinking that this could work by simply using a global instance
+    //! of a `DynamicAllocator` and then using thread-local storage (TLS) to store handles to this
+    //! global instance. While this is essentially the architecture we use, a number of hacks have
+    //! been added to ensure correctness.
+    //!
+    //! ## Static Initialization
+    //!
+    //! The global instance is a plain `static` that starts out empty and is built by the first
+    //! thread to allocate (see `utils::StaticCell`). The instance itself is placed in memory mapped
+    //! for the purpose rather than in a `Box`, so creating it does not go through any other
+    //! allocator. This means there is no constructor to run before `main`, and allocations made by
+    //! the dynamic loader or by other libraries' initializers (which elfc has to serve) work no
+    //! matter when they happen.
     //!
     //! ## TLS Destructors
     //!
@@ -91,13 +140,29 @@ pub mod global {
     #[allow(unused_imports)]
     use super::{CoarseAllocator, DynamicAllocator, DirtyFn, ElfMalloc, MemorySource, ObjectAlloc,
                 PageAlloc, TieredSizeClasses, TypedArray, AllocType, get_type, Source, AllocMap};
+    #[cfg(feature = "tags")]
+    use super::{Label, Tag};
+    #[cfg(feature = "sites")]
+    use super::SiteId;
+    use super::StaticCell;
+    use super::AllocationInfo;
+    #[cfg(feature = "ownership")]
+    use super::super::ownership::{self, Granule};
+    #[cfg(feature = "ownership")]
+    use super::Slag;
     #[cfg(feature = "nightly")]
+    #[cfg(feature = "mte")]
+    use super::mte;
     use super::likely;
+    #[cfg(feature = "batch-unmap")]
+    use super::RangeBatch;
     use std::ptr;
     use std::cell::UnsafeCell;
     use std::mem;
+    #[cfg(not(feature = "nightly"))]
+    use std::marker::PhantomData;
     #[allow(unused_imports)]
-    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
     use std::sync::mpsc::{channel, Sender};
     use std::sync::Mutex;
     use std::thread;
@@ -215,11 +280,14 @@ pub mod global {
     }
     unsafe impl Send for GlobalAllocator {}
 
+    type GlobalHeap = ElfMalloc<PA, TieredSizeClasses<ObjectAlloc<PA>>>;
+
     /// The type of the global instance of the allocator.
     ///
-    /// This is used to create handles for TLS-stored `GlobalAllocator`s.
+    /// This is used to create handles for TLS-stored `GlobalAllocator`s. It can be constructed in
+    /// a constant expression; the heap itself is created on first use.
     struct GlobalAllocProvider {
-        inner: Option<ElfMalloc<PA, TieredSizeClasses<ObjectAlloc<PA>>>>,
+        heap: StaticCell<GlobalHeap>,
     }
 
     // We need sync to have the global allocator reference live for new threads to clone. This is
@@ -227,8 +295,14 @@ pub mod global {
     // methods.
     unsafe impl Sync for GlobalAllocProvider {}
     impl GlobalAllocProvider {
-        fn new() -> GlobalAllocProvider {
-            GlobalAllocProvider { inner: Some(ElfMalloc::new()) }
+        #[cfg(feature = "nightly")]
+        const fn new() -> GlobalAllocProvider {
+            GlobalAllocProvider { heap: StaticCell::new() }
+        }
+
+        #[inline]
+        fn get(&self) -> &GlobalHeap {
+            self.heap.get_or_init(ElfMalloc::new)
         }
     }
 
@@ -241,6 +315,8 @@ pub mod global {
         Ptr(*mut u8),
         #[allow(dead_code)]
         Slag(*mut u8),
+        #[cfg(feature = "batch-unmap")]
+        Unmap(RangeBatch),
     }
 
     unsafe impl Send for Husk {}
@@ -288,6 +364,8 @@ pub mod global {
     }
 
     pub unsafe fn get_layout(item: *mut u8) -> (usize /* size */, usize /* alignment */) {
+        #[cfg(feature = "mte")]
+        let item = mte::untag(item);
         let m_block = match get_type(item) {
             // TODO(ezrosent): this duplicates some work..
             AllocType::SmallSlag | AllocType::Large => {
@@ -314,20 +392,142 @@ pub mod global {
         super::elfmalloc_get_layout(m_block, item)
     }
 
-    fn new_handle() -> GlobalAllocator {
-        GlobalAllocator {
-            inner: Some(ELF_HEAP.inner.as_ref().expect("heap uninitialized").clone()),
+    /// The offset of a large object from the start of its mapping, which begins with the object's
+    /// header.
+    pub const LARGE_OBJECT_OFFSET: usize = super::ELFMALLOC_PAGE_SIZE;
+
+    /// The size of the object that `alloc(size)` returns, or `None` if it would be a large
+    /// object, which gets a mapping of its own.
+    ///
+    /// This does not allocate, and can be called before any memory has been allocated.
+    pub fn size_class(size: usize) -> Option<usize> {
+        ELF_HEAP.get().class_size(size)
+    }
+
+    /// Look up the object that `item` points into.
+    ///
+    /// This is meant for tools built on top of elfmalloc, such as debuggers and heap scanners.
+    ///
+    /// Without the `ownership` feature, `item` must point into memory allocated by the global
+    /// allocator, as its metadata is read without checking, and large objects are only recognized
+    /// by their start address. With the `ownership` feature, `item` can be any pointer, and
+    /// `None` is returned if it does not point into an object managed by elfmalloc (including the
+    /// object-specific allocators in `frontends` and the heaps in `rust_alloc`). In both cases,
+    /// `None` is returned for pointers into elfmalloc's memory that do not point into an object,
+    /// such as `Slag` headers.
+    ///
+    /// The result is a snapshot: the object may be freed, and its `Slag` may change hands, at any
+    /// time. In particular, `lookup` does not report whether the object is live.
+    pub unsafe fn lookup(item: *mut u8) -> Option<AllocationInfo> {
+        find_object(item).map(|(_, info)| info)
+    }
+
+    /// Find the start of the object that `item` points into, under the same conditions as
+    /// `lookup`.
+    ///
+    /// Conservative garbage collectors can use this to map the words they scan to the objects
+    /// that those words may point to.
+    pub unsafe fn object_start(item: *mut u8) -> Option<*mut u8> {
+        find_object(item).map(|(start, _)| start)
+    }
+
+    /// Call `f` with every object of the size class of `size` that is cached by the current
+    /// thread, and return the `Slag` that the thread allocates that class from. Returns `None`
+    /// without calling `f` for large sizes, and if the thread has not used the size class yet.
+    /// This does not allocate; it is used by the `dot` module.
+    #[cfg(feature = "ownership")]
+    #[doc(hidden)]
+    pub fn cached_objects<F: FnMut(*mut u8)>(size: usize, f: F) -> Option<*mut Slag> {
+        let with_heap = |h: &UnsafeCell<GlobalAllocator>| unsafe {
+            (*h.get()).inner.as_mut().and_then(
+                |heap| heap.cached_objects(size, f),
+            )
+        };
+        #[cfg(feature = "nightly")]
+        {
+            LOCAL_ELF_HEAP.try_with(with_heap).unwrap_or(None)
+        }
+        #[cfg(not(feature = "nightly"))]
+        {
+            LOCAL_ELF_HEAP.with(with_heap)
         }
     }
 
-    impl Drop for GlobalAllocProvider {
-        fn drop(&mut self) {
-            mem::forget(self.inner.take());
+    unsafe fn find_object(item: *mut u8) -> Option<(*mut u8, AllocationInfo)> {
+        #[cfg(feature = "mte")]
+        let item = mte::untag(item);
+        if item.is_null() {
+            return None;
+        }
+        #[cfg(feature = "ownership")]
+        {
+            match ownership::granule(item) {
+                Some(Granule::Slags { ty, slag_size }) => super::find_in_slag(slag_size, ty, item),
+                Some(Granule::Large { index }) => {
+                    let granule = item as usize & !((1 << ownership::GRANULE_SHIFT) - 1);
+                    super::large_alloc::find(granule - (index << ownership::GRANULE_SHIFT), item)
+                }
+                None => None,
+            }
+        }
+        #[cfg(not(feature = "ownership"))]
+        {
+            let ty = get_type(item);
+            let slag_size = match ty {
+                AllocType::SmallSlag => {
+                    LOCAL_ELF_HEAP.with(|h| {
+                        (*h.get())
+                            .inner
+                            .as_ref()
+                            .unwrap()
+                            .small_pages
+                            .backing_memory()
+                            .page_size()
+                    })
+                }
+                AllocType::BigSlag => {
+                    LOCAL_ELF_HEAP.with(|h| {
+                        (*h.get())
+                            .inner
+                            .as_ref()
+                            .unwrap()
+                            .large_pages
+                            .backing_memory()
+                            .page_size()
+                    })
+                }
+                AllocType::Large => {
+                    return super::large_alloc::lookup(item).map(|size| {
+                        (item, AllocationInfo::large(size))
+                    })
+                }
+            };
+            super::find_in_slag(slag_size, ty, item)
+        }
+    }
+
+    fn new_handle() -> GlobalAllocator {
+        // Allocations made while the dump thread is spawned go to `large_alloc`, since this
+        // thread's handle is still being initialized.
+        #[cfg(target_os = "linux")]
+        super::super::dump::start();
+        GlobalAllocator {
+            inner: Some(ELF_HEAP.get().clone()),
         }
     }
 
+    #[cfg(feature = "nightly")]
+    static ELF_HEAP: GlobalAllocProvider = GlobalAllocProvider::new();
+    // `const fn` is not available on stable Rust.
+    #[cfg(not(feature = "nightly"))]
+    static ELF_HEAP: GlobalAllocProvider = GlobalAllocProvider {
+        heap: StaticCell {
+            addr: ATOMIC_USIZE_INIT,
+            marker: PhantomData,
+        },
+    };
+
     lazy_static! {
-        static ref ELF_HEAP: GlobalAllocProvider = GlobalAllocProvider::new();
         static ref DESTRUCTOR_CHAN: Mutex<Sender<Husk>> = {
             // Background thread code: block on a channel waiting for memory reclamation messages
             // (Husks).
@@ -341,6 +541,10 @@ pub mod global {
                             Husk::Array(alloc) => mem::drop(DynamicAllocator(alloc)),
                             Husk::Ptr(p) => local_alloc.inner.as_mut().unwrap().free(p),
                             Husk::Slag(s) => dirty_slag(s),
+                            #[cfg(feature = "batch-unmap")]
+                            Husk::Unmap(mut batch) => {
+                                batch.flush(|base, len| super::mmap::unmap(base, len));
+                            }
                         }
                         continue
                     }
@@ -352,11 +556,9 @@ pub mod global {
         };
     }
 
-    lazy_static!{
-        // only used on stable nightly or targets where thread-local is not supported
-        #[allow(unused_variables)]
-        pub static ref INITIALIZING: AtomicUsize = AtomicUsize::new(0);
-    }
+    // only used on stable nightly or targets where thread-local is not supported
+    #[allow(dead_code)]
+    pub static INITIALIZING: AtomicUsize = ATOMIC_USIZE_INIT;
 
     thread_local! {
         static LOCAL_DESTRUCTOR_CHAN: Sender<Husk> =
@@ -400,6 +602,41 @@ pub mod global {
         }
     }
 
+    /// Allocate an object of `size` bytes tagged with `tag`.
+    ///
+    /// See the `tags` module for details.
+    #[cfg(feature = "tags")]
+    pub unsafe fn alloc_tagged(size: usize, tag: Tag) -> *mut u8 {
+        alloc_labeled(size, Label::Tag, tag)
+    }
+
+    /// Allocate an object of `size` bytes attributed to the allocation site `site`.
+    ///
+    /// `site` is usually obtained with the `alloc_site!` macro. See the `sites` module for
+    /// details.
+    #[cfg(feature = "sites")]
+    pub unsafe fn alloc_with_site(size: usize, site: SiteId) -> *mut u8 {
+        alloc_labeled(size, Label::Site, site)
+    }
+
+    #[cfg(feature = "tags")]
+    unsafe fn alloc_labeled(size: usize, label: Label, val: u32) -> *mut u8 {
+        #[cfg(feature = "nightly")]
+        #[cfg(target_thread_local)]
+        {
+            if likely(!PTR.is_null()) {
+                return (*PTR).alloc_labeled(size, label, val);
+            }
+        }
+        alloc_assert!(!is_initializing(), "alloc_labeled can't be called recursively");
+        init_begin();
+        let res = LOCAL_ELF_HEAP.with(|h| {
+            (*h.get()).inner.as_mut().unwrap().alloc_labeled(size, label, val)
+        });
+        init_end();
+        res
+    }
+
     pub unsafe fn realloc(item: *mut u8, new_size: usize) -> *mut u8 {
         aligned_realloc(item, new_size, mem::size_of::<usize>())
     }
@@ -424,7 +661,140 @@ pub mod global {
         res
     }
 
+    /// Return unused memory to the operating system, in the manner of glibc's `malloc_trim`.
+    ///
+    /// The `level` argument controls how aggressive trimming is:
+    ///
+    /// * At level 0, only cached pages backing completely empty `Slag`s are uncommitted (and,
+    ///   with the `large-cache` feature, cached large object mappings are unmapped).
+    /// * At level 1, the current thread's caches are also flushed back to their pages before
+    ///   trimming. Caches belonging to other threads are left alone.
+    /// * At level 2 and above, the pages of partially-empty `Slag`s that only contain free objects
+    ///   are also uncommitted.
+    ///
+    /// Returns the number of bytes that were uncommitted.
+    pub unsafe fn trim(level: usize) -> usize {
+        #[cfg(feature = "nightly")]
+        {
+            if likely(!PTR.is_null()) {
+                return (*PTR).trim(level);
+            }
+        }
+        alloc_assert!(!is_initializing(), "trim can't be called recursively");
+        init_begin();
+        let res = LOCAL_ELF_HEAP.with(|h| (*h.get()).inner.as_mut().unwrap().trim(level));
+        init_end();
+        res
+    }
+
+    /// Ask the kernel to back the current thread's hot `Slag`s with transparent huge pages.
+    ///
+    /// The `Slag`s currently being allocated from by this thread are the ones most likely to be
+    /// accessed soon, so collapsing the regions containing them into huge pages reduces TLB
+    /// pressure. This relies on `MADV_COLLAPSE`, which is only supported on Linux 6.1 and later,
+    /// and it will commit any uncommitted memory in those regions. Returns the number of bytes
+    /// for which the request succeeded.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn defrag() -> usize {
+        #[cfg(feature = "nightly")]
+        {
+            if likely(!PTR.is_null()) {
+                return (*PTR).defrag();
+            }
+        }
+        alloc_assert!(!is_initializing(), "defrag can't be called recursively");
+        init_begin();
+        let res = LOCAL_ELF_HEAP.with(|h| (*h.get()).inner.as_mut().unwrap().defrag());
+        init_end();
+        res
+    }
+
+    /// Fault in memory ahead of time, so that it does not take page faults when it is first used.
+    ///
+    /// This is meant for programs with a latency-critical phase (e.g. handling requests) that can
+    /// afford to pay for page faults during initialization but not afterwards. At least `bytes`
+    /// bytes of pages for small objects and as many for medium objects are faulted in and cached
+    /// in the shared page caches, from which new `Slag`s take pages before any others. Returns the
+    /// number of bytes faulted in. The pages are uncommitted again by `trim`. Large objects, which
+    /// have mappings of their own, are not affected.
+    ///
+    /// To fault in every page as it is first used instead, set the `prefault` option in
+    /// `ELFMALLOC_CONF` (see the `conf` module).
+    pub unsafe fn prefault(bytes: usize) -> usize {
+        #[cfg(feature = "nightly")]
+        {
+            if likely(!PTR.is_null()) {
+                return (*PTR).prefault(bytes);
+            }
+        }
+        alloc_assert!(!is_initializing(), "prefault can't be called recursively");
+        init_begin();
+        let res = LOCAL_ELF_HEAP.with(|h| (*h.get()).inner.as_mut().unwrap().prefault(bytes));
+        init_end();
+        res
+    }
+
+    /// Hand the current thread's cached objects back to the shared backend, because the thread
+    /// is about to be idle for a long time.
+    ///
+    /// Thread pools with bursty load otherwise strand the caches of their sleeping workers. A pool
+    /// should call this when a worker parks after running out of work, and `on_unpark` when it
+    /// wakes up. Unlike `trim`, this does not uncommit any memory: the objects are meant to be
+    /// reused by the threads that are still running. With the `magazine_layer` feature, full
+    /// magazines are donated to the depot whole; otherwise cached objects are freed back to their
+    /// `Slag`s. The thread's caches are refilled as it allocates again.
+    pub unsafe fn on_park() {
+        #[cfg(feature = "nightly")]
+        {
+            if likely(!PTR.is_null()) {
+                return (*PTR).park();
+            }
+        }
+        alloc_assert!(!is_initializing(), "on_park can't be called recursively");
+        init_begin();
+        LOCAL_ELF_HEAP.with(|h| (*h.get()).inner.as_mut().unwrap().park());
+        init_end();
+    }
+
+    /// Prepare the current thread for allocation after `on_park`.
+    ///
+    /// With the `magazine_layer` feature, this reclaims a full magazine from the depot for each
+    /// size class whose cache is empty, if there is one; otherwise it does nothing.
+    pub unsafe fn on_unpark() {
+        #[cfg(feature = "nightly")]
+        {
+            if likely(!PTR.is_null()) {
+                return (*PTR).unpark();
+            }
+        }
+        alloc_assert!(!is_initializing(), "on_unpark can't be called recursively");
+        init_begin();
+        LOCAL_ELF_HEAP.with(|h| (*h.get()).inner.as_mut().unwrap().unpark());
+        init_end();
+    }
+
+    /// Unmap the mappings in `batch` on the background thread.
+    ///
+    /// Without the `nightly` feature, or if the current thread is exiting and its channel to the
+    /// background thread is gone, the mappings are unmapped right away.
+    #[cfg(feature = "batch-unmap")]
+    pub unsafe fn defer_unmap(mut batch: RangeBatch) {
+        #[cfg(feature = "nightly")]
+        {
+            let sent = LOCAL_DESTRUCTOR_CHAN
+                .try_with(|chan| chan.send(Husk::Unmap(batch.take())).is_ok())
+                .unwrap_or(false);
+            if sent {
+                return;
+            }
+        }
+        batch.flush(|base, len| super::mmap::unmap(base, len));
+    }
+
     pub unsafe fn free(item: *mut u8) {
+        // The fallback path below looks up the type of `item`.
+        #[cfg(feature = "mte")]
+        let item = mte::untag(item);
         #[cfg(feature = "nightly")]
         {
             #[cfg(target_thread_local)]
@@ -598,6 +968,25 @@ impl<T> AllocMap<T> for TieredSizeClasses<T> {
     }
 }
 
+impl<T> TieredSizeClasses<T> {
+    /// The size of the class that `get_raw(n)` returns, for `n <= max_key()`.
+    fn class_size(&self, n: usize) -> usize {
+        #[cfg(any(not(feature = "c-api"),
+                    not(any(target_os = "macos",
+                                all(windows, target_pointer_width = "64")))))]
+        {
+            if n <= 8 {
+                return 8;
+            }
+        }
+        if n <= self.small_objs.max_key() {
+            cmp::max(round_up(n), self.small_objs.starting_size)
+        } else {
+            cmp::max(n.next_power_of_two(), self.medium_objs.starting_size)
+        }
+    }
+}
+
 // Once this can be a type parameter, it should be.
 pub const MULTIPLE: usize = 16;
 
@@ -685,15 +1074,61 @@ impl<T: Clone> Clone for PowersOfTwo<T> {
 
 impl Drop for DynamicAllocator {
     fn drop(&mut self) {
-        self.0.allocs.foreach(|x| unsafe { ptr::drop_in_place(x) });
-        unsafe {
-            self.0.allocs.medium_objs.classes.destroy();
-            self.0.allocs.small_objs.classes.destroy();
-            #[cfg(any(not(feature = "c-api"),
-                        not(any(target_os = "macos",
-                                    all(windows, target_pointer_width = "64")))))]
-            ptr::write(&mut self.0.allocs.word_objs, None);
+        unsafe { self.0.destroy() }
+    }
+}
+
+impl<M: MemorySource, D: DirtyFn, C: Config>
+    ElfMalloc<PageAlloc<M, D>, TieredSizeClasses<ObjectAlloc<PageAlloc<M, D>>>, C> {
+    /// The size of the objects allocated for requests of `bytes` bytes, or `None` for large
+    /// objects.
+    fn class_size(&self, bytes: usize) -> Option<usize> {
+        if bytes <= C::MAX_SMALL_SIZE {
+            Some(self.allocs.class_size(bytes))
+        } else {
+            None
+        }
+    }
+}
+
+/// `AllocMap`s whose classes are stored in a `TieredSizeClasses`.
+trait Tiered<T> {
+    fn tiered(&mut self) -> &mut TieredSizeClasses<T>;
+}
+
+impl<T> Tiered<T> for TieredSizeClasses<T> {
+    fn tiered(&mut self) -> &mut TieredSizeClasses<T> {
+        self
+    }
+}
+
+impl<M: MemorySource, D: DirtyFn, AM, C: Config> ElfMalloc<PageAlloc<M, D>, AM, C>
+where
+    AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key = usize> + Tiered<ObjectAlloc<PageAlloc<M, D>>>,
+{
+    /// Flush this handle's caches, and drop and unmap its size classes. The handle must not be
+    /// used afterwards.
+    unsafe fn destroy(&mut self) {
+        heap_event!(self.heap_stats, retire);
+        #[cfg(feature = "site-pools")]
+        {
+            self.site_pools.retire();
+            while let Some(item) = self.site_pools.take() {
+                self.release(item);
+            }
         }
+        #[cfg(feature = "quarantine")]
+        self.flush_quarantine();
+        #[cfg(feature = "batch-unmap")]
+        self.flush_unmaps(false);
+        self.allocs.foreach(|x| ptr::drop_in_place(x));
+        let classes = self.allocs.tiered();
+        classes.medium_objs.classes.destroy();
+        classes.small_objs.classes.destroy();
+        #[cfg(any(not(feature = "c-api"),
+                    not(any(target_os = "macos",
+                                all(windows, target_pointer_width = "64")))))]
+        ptr::write(&mut classes.word_objs, None);
     }
 }
 
@@ -763,6 +1198,14 @@ impl DynamicAllocator {
     pub fn new() -> Self {
         DynamicAllocator(ElfMalloc::new())
     }
+
+    /// Create a heap with the parameters of `profile` rather than those of the build's profile
+    /// (see the `profile` module). Runtime options and features are shared by all heaps, so only
+    /// the parameters of the heap itself are taken from `profile`. Those fixed at compile time,
+    /// such as the largest size class, are those of `BuildConfig`.
+    pub fn with_profile(profile: &Profile) -> Self {
+        DynamicAllocator(ElfMalloc::with_profile(profile))
+    }
     pub unsafe fn alloc(&mut self, size: usize) -> *mut u8 {
         self.0.alloc(size)
     }
@@ -770,6 +1213,22 @@ impl DynamicAllocator {
         self.0.free(item)
     }
 
+    /// Allocate an object of `size` bytes tagged with `tag`.
+    ///
+    /// See the `tags` module for details.
+    #[cfg(feature = "tags")]
+    pub unsafe fn alloc_tagged(&mut self, size: usize, tag: Tag) -> *mut u8 {
+        self.0.alloc_labeled(size, Label::Tag, tag)
+    }
+
+    /// Allocate an object of `size` bytes attributed to the allocation site `site`.
+    ///
+    /// See the `sites` module for details.
+    #[cfg(feature = "sites")]
+    pub unsafe fn alloc_with_site(&mut self, size: usize, site: SiteId) -> *mut u8 {
+        self.0.alloc_labeled(size, Label::Site, site)
+    }
+
     pub unsafe fn realloc(&mut self, item: *mut u8, new_size: usize) -> *mut u8 {
         self.0.realloc(item, new_size, mem::size_of::<usize>())
     }
@@ -782,80 +1241,333 @@ impl DynamicAllocator {
     ) -> *mut u8 {
         self.0.realloc(item, new_size, new_alignment)
     }
-}
 
+    /// Return unused memory to the operating system.
+    ///
+    /// See `global::trim` for the meaning of `level`. Only the caches of this handle are flushed.
+    pub unsafe fn trim(&mut self, level: usize) -> usize {
+        self.0.trim(level)
+    }
 
-// Frontends are currently feature-gated in the following fashion:
+    /// Ask the kernel to back this handle's hot `Slag`s with transparent huge pages.
+    ///
+    /// See `global::defrag` for details.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn defrag(&mut self) -> usize {
+        self.0.defrag()
+    }
 
-#[cfg(not(feature = "local_cache"))]
-type Inner<CA> = MagazineCache<CA>;
-#[cfg(feature = "local_cache")]
-type Inner<CA> = LocalCache<CA>;
+    /// Fault in at least `bytes` bytes of both small and large pages ahead of time.
+    ///
+    /// See `global::prefault` for details.
+    pub unsafe fn prefault(&mut self, bytes: usize) -> usize {
+        self.0.prefault(bytes)
+    }
 
-#[cfg(not(feature = "magazine_layer"))]
-pub type ObjectAlloc<CA> = Lazy<Inner<CA>>;
-#[cfg(feature = "magazine_layer")]
-pub type ObjectAlloc<CA> = Lazy<DepotCache<Inner<CA>>>;
+    /// Hand this handle's cached objects back to the shared backend before a long idle period.
+    ///
+    /// See `global::on_park` for details.
+    pub unsafe fn park(&mut self) {
+        self.0.park()
+    }
 
+    /// Prepare this handle for allocation after `park`.
+    pub unsafe fn unpark(&mut self) {
+        self.0.unpark()
+    }
+}
 
-/// A Dynamic memory allocator, parmetrized on a particular `ObjectAlloc`, `CourseAllocator` and
-/// `AllocMap`.
-///
-/// `ElfMalloc` encapsulates the logic of constructing and selecting object classes, as well as
-/// delgating to the `large_alloc` module for large allocations. Most of the logic occurs in its
-/// type parameters.
-struct ElfMalloc<CA: CoarseAllocator, AM: AllocMap<ObjectAlloc<CA>>> {
-    /// A cache of pages for all small allocations.
-    small_pages: CA,
-    /// A cache of pages for all medium allocations.
-    large_pages: CA,
-    /// An `AllocMap` of size classes of individual fixed-size object allocator.
-    allocs: AM,
-    /// The maximum size of a "non-large" object. Objects larger than `max_size` are allocated
-    /// directly with mmap.
-    max_size: usize,
+/// A size class that is known at compile time. See `SpecializedAllocator`.
+pub trait SizeClass {
+    /// The size of the objects in the class, in bytes. This must be one of the allocator's size
+    /// classes, and larger than 8 bytes.
+    const SIZE: usize;
+}
 
-    start_from: usize,
-    n_classes: usize,
+/// The `SizeClass` of unused `SpecializedAllocator` parameters. It matches no allocation.
+pub enum NoClass {}
+
+impl SizeClass for NoClass {
+    const SIZE: usize = 0;
 }
 
-impl Default for DynamicAllocator {
-    fn default() -> Self {
-        Self::new()
-    }
+/// An `AllocMap` that looks up the class of `C` without consulting `AM`.
+///
+/// The class itself is still owned by `AM`; `Hot` caches a pointer to it. That is only sound
+/// because the `AllocMap`s in this module keep every class larger than 8 bytes in a `TypedArray`,
+/// whose contents do not move when the map does.
+struct Hot<T, C, AM> {
+    inner: AM,
+    /// The class of `C`, or null for `NoClass`.
+    hot: *mut T,
+    _marker: PhantomData<C>,
 }
 
-// TODO(ezrosent): move this to a type parameter when const generics are in.
-const ELFMALLOC_PAGE_SIZE: usize = 2 << 20;
-const ELFMALLOC_SMALL_PAGE_SIZE: usize = 256 << 10;
-const ELFMALLOC_SMALL_CUTOFF: usize = ELFMALLOC_SMALL_PAGE_SIZE / 4;
+impl<T, C: SizeClass, AM: AllocMap<T, Key = usize>> AllocMap<T> for Hot<T, C, AM> {
+    type Key = usize;
+    fn init_conserve<F: FnMut(usize) -> T>(start: usize, n_classes: usize, f: F) -> (F, Self) {
+        let (f, inner) = AM::init_conserve(start, n_classes, f);
+        let hot = if C::SIZE == 0 {
+            ptr::null_mut()
+        } else {
+            alloc_assert!(
+                C::SIZE > 8 && C::SIZE <= inner.max_key(),
+                "cannot specialize size class {}",
+                C::SIZE
+            );
+            // Every size that `get_raw` sends to the hot class has to belong to it.
+            let hot = unsafe { inner.get_raw(C::SIZE) };
+            let smallest = cmp::max(C::SIZE, MULTIPLE + 8) - MULTIPLE + 1;
+            alloc_assert!(
+                unsafe { inner.get_raw(smallest) } == hot,
+                "{} is not a size class",
+                C::SIZE
+            );
+            hot
+        };
+        (
+            f,
+            Hot {
+                inner: inner,
+                hot: hot,
+                _marker: PhantomData,
+            },
+        )
+    }
 
-impl<M: MemorySource, D: DirtyFn>
-    ElfMalloc<PageAlloc<M, D>, TieredSizeClasses<ObjectAlloc<PageAlloc<M, D>>>> {
-    fn new() -> Self {
-        let pa_large = PageAlloc::new(ELFMALLOC_PAGE_SIZE, 1 << 20, 8, AllocType::BigSlag);
-        // The small pages are allocated in groups where the first page is aligned to
-        // ELFMALLOC_PAGE_SIZE; this page will be stamped with AllocType::SmallSlag, allowing type
-        // lookups to work as expected.
-        let pa_small = PageAlloc::new_aligned(
-            ELFMALLOC_SMALL_PAGE_SIZE,
-            1 << 20,
-            8,
-            ELFMALLOC_PAGE_SIZE,
-            AllocType::SmallSlag,
-        );
-        Self::new_internal(0.6, pa_small, pa_large, 8, 25)
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    unsafe fn get_raw(&self, n: usize) -> *mut T {
+        // After monomorphization, these are comparisons against constants.
+        if n <= C::SIZE && n + MULTIPLE > C::SIZE && n > 8 {
+            self.hot
+        } else {
+            self.inner.get_raw(n)
+        }
+    }
+
+    #[inline]
+    fn max_key(&self) -> usize {
+        self.inner.max_key()
+    }
+
+    fn foreach<F: Fn(*mut T)>(&self, f: F) {
+        // The hot class is one of `inner`'s.
+        self.inner.foreach(f)
     }
 }
 
-#[inline(always)]
-unsafe fn round_to_page<T>(item: *mut T) -> *mut T {
-    ((item as usize) & !(ELFMALLOC_PAGE_SIZE - 1)) as *mut T
+impl<T, C, AM: Tiered<T>> Tiered<T> for Hot<T, C, AM> {
+    fn tiered(&mut self) -> &mut TieredSizeClasses<T> {
+        self.inner.tiered()
+    }
 }
 
-/// We ensure that for every pointer returned from a call to `alloc`, rounding that pointer down to
-/// a 2MiB boundary yields the location of an `AllocType`. This is enforced separately in the
-/// `PageAlloc` code, the `large_alloc` code, and the `Slag` code.
+type Classes = TieredSizeClasses<ObjectAlloc<PageAlloc<Source>>>;
+type HotClasses<C, AM> = Hot<ObjectAlloc<PageAlloc<Source>>, C, AM>;
+
+/// A `DynamicAllocator` with fast paths for up to four size classes that are chosen at compile
+/// time.
+///
+/// Finding the class of an allocation in a `DynamicAllocator` takes a few branches and some
+/// arithmetic on the requested size. A `SpecializedAllocator` first compares the size against
+/// each of its `SizeClass` parameters in turn, using constants compiled into the code, and only
+/// falls back to the general lookup if none of them match. Frees are dispatched the same way.
+/// This pays off when a handful of sizes dominate an allocation-heavy workload; the classes
+/// should be listed from most to least common, since every miss costs two comparisons per class.
+///
+/// ```rust,ignore
+/// struct Node;
+/// impl SizeClass for Node {
+///     const SIZE: usize = 48;
+/// }
+/// let mut elf = SpecializedAllocator::<Node>::new();
+/// let node = elf.alloc(40);
+/// ```
+///
+/// As with `DynamicAllocator`, objects must be freed through a clone of the handle that allocated
+/// them. Const generics would allow the classes to be plain numbers, and any number of them.
+pub struct SpecializedAllocator<A = NoClass, B = NoClass, C = NoClass, D = NoClass>(
+    ElfMalloc<PageAlloc<Source>, HotClasses<A, HotClasses<B, HotClasses<C, HotClasses<D, Classes>>>>>
+)
+where
+    A: SizeClass,
+    B: SizeClass,
+    C: SizeClass,
+    D: SizeClass;
+
+unsafe impl<A: SizeClass, B: SizeClass, C: SizeClass, D: SizeClass> Send
+    for SpecializedAllocator<A, B, C, D> {
+}
+
+impl<A: SizeClass, B: SizeClass, C: SizeClass, D: SizeClass> SpecializedAllocator<A, B, C, D> {
+    pub fn new() -> Self {
+        SpecializedAllocator(ElfMalloc::new())
+    }
+    pub unsafe fn alloc(&mut self, size: usize) -> *mut u8 {
+        self.0.alloc(size)
+    }
+    pub unsafe fn free(&mut self, item: *mut u8) {
+        self.0.free(item)
+    }
+    pub unsafe fn realloc(&mut self, item: *mut u8, new_size: usize) -> *mut u8 {
+        self.0.realloc(item, new_size, mem::size_of::<usize>())
+    }
+}
+
+impl<A: SizeClass, B: SizeClass, C: SizeClass, D: SizeClass> Clone
+    for SpecializedAllocator<A, B, C, D> {
+    fn clone(&self) -> Self {
+        SpecializedAllocator(self.0.clone())
+    }
+}
+
+impl<A: SizeClass, B: SizeClass, C: SizeClass, D: SizeClass> Default
+    for SpecializedAllocator<A, B, C, D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: SizeClass, B: SizeClass, C: SizeClass, D: SizeClass> Drop
+    for SpecializedAllocator<A, B, C, D> {
+    fn drop(&mut self) {
+        unsafe { self.0.destroy() }
+    }
+}
+
+
+// Frontends are currently feature-gated in the following fashion:
+
+#[cfg(not(feature = "local_cache"))]
+type Inner<CA> = MagazineCache<CA>;
+#[cfg(feature = "local_cache")]
+type Inner<CA> = LocalCache<CA>;
+
+#[cfg(not(feature = "magazine_layer"))]
+pub type ObjectAlloc<CA> = Lazy<Inner<CA>>;
+#[cfg(feature = "magazine_layer")]
+pub type ObjectAlloc<CA> = Lazy<DepotCache<Inner<CA>>>;
+
+
+/// A Dynamic memory allocator, parmetrized on a particular `ObjectAlloc`, `CourseAllocator` and
+/// `AllocMap`.
+///
+/// `ElfMalloc` encapsulates the logic of constructing and selecting object classes, as well as
+/// delgating to the `large_alloc` module for large allocations. Most of the logic occurs in its
+/// type parameters. The sizes that are allocated from size classes, and the sizes of the caches,
+/// are fixed by the `Config` `C` (see the `profile` module).
+struct ElfMalloc<CA: CoarseAllocator, AM: AllocMap<ObjectAlloc<CA>>, C: Config = BuildConfig> {
+    /// A cache of pages for all small allocations.
+    small_pages: CA,
+    /// A cache of pages for all medium allocations.
+    large_pages: CA,
+    /// An `AllocMap` of size classes of individual fixed-size object allocator. Its `max_key` is
+    /// `C::MAX_SMALL_SIZE`, the maximum size of a "non-large" object. Larger objects are
+    /// allocated directly with mmap.
+    allocs: AM,
+
+    start_from: usize,
+    n_classes: usize,
+    /// Objects freed through this handle which have not been returned to `allocs` yet.
+    #[cfg(feature = "quarantine")]
+    quarantine: Quarantine,
+    /// Picks the operations on this handle whose latency is recorded.
+    #[cfg(feature = "latency-stats")]
+    latency_sampler: latency::Sampler,
+    /// Operations on this handle which have not been added to `heap_stats` yet.
+    #[cfg(feature = "stats")]
+    heap_stats: heap::Local,
+    /// Pools of freed objects for promoted allocation sites, and the sampler that finds them.
+    #[cfg(feature = "site-pools")]
+    site_pools: site_pools::Local,
+    /// Picks the allocations with a site that are recorded for the leak report.
+    #[cfg(feature = "leak-report")]
+    leak_sampler: leaks::Sampler,
+    /// Decides when this handle's caches are checked for decay.
+    #[cfg(feature = "cache-decay")]
+    decay: decay::Clock,
+    /// Mappings of large objects freed through this handle which have not been unmapped yet.
+    #[cfg(feature = "batch-unmap")]
+    unmap_batch: RangeBatch,
+    config: PhantomData<C>,
+}
+
+impl Default for DynamicAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// TODO(ezrosent): move this to a type parameter when const generics are in.
+const ELFMALLOC_PAGE_SIZE: usize = 2 << 20;
+const ELFMALLOC_SMALL_PAGE_SIZE: usize = 256 << 10;
+const ELFMALLOC_SMALL_CUTOFF: usize = ELFMALLOC_SMALL_PAGE_SIZE / 4;
+
+impl<M, D, AM, C> ElfMalloc<PageAlloc<M, D>, AM, C>
+where
+    M: MemorySource,
+    D: DirtyFn,
+    AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key = usize>,
+    C: Config,
+{
+    fn new() -> Self {
+        Self::with_profile(&C::PROFILE)
+    }
+
+    fn with_profile(profile: &Profile) -> Self {
+        let mut pa_large = PageAlloc::new(
+            ELFMALLOC_PAGE_SIZE,
+            profile.target_overhead,
+            profile.pipe_size,
+            AllocType::BigSlag,
+        );
+        // The small pages are allocated in groups where the first page is aligned to
+        // ELFMALLOC_PAGE_SIZE; this page will be stamped with AllocType::SmallSlag, allowing type
+        // lookups to work as expected.
+        let pa_small = PageAlloc::new_aligned(
+            ELFMALLOC_SMALL_PAGE_SIZE,
+            profile.target_overhead,
+            profile.pipe_size,
+            ELFMALLOC_PAGE_SIZE,
+            AllocType::SmallSlag,
+        );
+        pa_large.set_prefault(conf::prefault());
+        pa_small.set_prefault(conf::prefault());
+        let n_classes = tiered_n_classes(8, C::MAX_SMALL_SIZE);
+        Self::new_internal(profile.cutoff_factor, pa_small, pa_large, 8, n_classes)
+    }
+}
+
+/// The number of classes that `TieredSizeClasses` needs, starting from `start`, for its largest
+/// class to be `max_size`, which must be a power of two.
+fn tiered_n_classes(start: usize, max_size: usize) -> usize {
+    // Each class added either doubles the largest class or leaves it as it is, so counting up
+    // finds every power of two above the first largest class.
+    let mut n_classes = 2;
+    loop {
+        let n_small = cmp::min(
+            (ELFMALLOC_SMALL_CUTOFF / MULTIPLE) - (start / MULTIPLE),
+            n_classes / 2,
+        );
+        let small_max = n_small * MULTIPLE + round_up(start) - MULTIPLE;
+        let max_key = (small_max + 1).next_power_of_two() << (n_classes - n_small - 1);
+        if max_key >= max_size {
+            alloc_assert_eq!(max_key, max_size, "unreachable maximum size class");
+            return n_classes;
+        }
+        n_classes += 1;
+    }
+}
+
+#[inline(always)]
+unsafe fn round_to_page<T>(item: *mut T) -> *mut T {
+    map_addr(item, |addr| addr & !(ELFMALLOC_PAGE_SIZE - 1))
+}
+
+/// We ensure that for every pointer returned from a call to `alloc`, rounding that pointer down to
+/// a 2MiB boundary yields the location of an `AllocType`. This is enforced separately in the
+/// `PageAlloc` code, the `large_alloc` code, and the `Slag` code.
 ///
 /// All of this allows us to run elfmalloc with a full malloc-style interface without resorting to
 /// any sort of global ownership check on the underlying `MemorySource`. This method thus breaks
@@ -865,8 +1577,13 @@ unsafe fn get_type(item: *mut u8) -> AllocType {
     *round_to_page(item.offset(-1) as *mut AllocType)
 }
 
-impl<M: MemorySource, D: DirtyFn, AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key = usize>> Clone
-    for ElfMalloc<PageAlloc<M, D>, AM> {
+impl<M, D, AM, C> Clone for ElfMalloc<PageAlloc<M, D>, AM, C>
+where
+    M: MemorySource,
+    D: DirtyFn,
+    AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key = usize>,
+    C: Config,
+{
     fn clone(&self) -> Self {
         let new_map = AM::init(self.start_from, self.n_classes, |size: usize| unsafe {
             self.allocs.get(size).clone()
@@ -875,9 +1592,23 @@ impl<M: MemorySource, D: DirtyFn, AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key
             small_pages: self.small_pages.clone(),
             large_pages: self.large_pages.clone(),
             allocs: new_map,
-            max_size: self.max_size,
             start_from: self.start_from,
             n_classes: self.n_classes,
+            #[cfg(feature = "quarantine")]
+            quarantine: Quarantine::new(),
+            #[cfg(feature = "latency-stats")]
+            latency_sampler: latency::Sampler::new(),
+            #[cfg(feature = "stats")]
+            heap_stats: heap::Local::default(),
+            #[cfg(feature = "site-pools")]
+            site_pools: site_pools::Local::new(),
+            #[cfg(feature = "leak-report")]
+            leak_sampler: leaks::Sampler::new(),
+            #[cfg(feature = "cache-decay")]
+            decay: decay::Clock::default(),
+            #[cfg(feature = "batch-unmap")]
+            unmap_batch: RangeBatch::new(),
+            config: PhantomData,
         }
     }
 }
@@ -900,8 +1631,81 @@ unsafe fn elfmalloc_get_layout<M: MemorySource>(m_block: &M, item: *mut u8) -> (
     }
 }
 
-impl<M: MemorySource, D: DirtyFn, AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key = usize>>
-    ElfMalloc<PageAlloc<M, D>, AM> {
+/// Information about an object allocated by the global allocator, as returned by
+/// `global::lookup`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AllocationInfo {
+    /// The object size of the object's size class, or `None` for large objects, which are mapped
+    /// individually.
+    pub size_class: Option<usize>,
+    /// The number of bytes, counting from the start of the object, that may be used.
+    pub usable_size: usize,
+    pub owning_heap: OwningHeap,
+}
+
+/// The part of the heap that an object belongs to.
+///
+/// See the `slag` module for the life cycle of a `Slag`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OwningHeap {
+    /// The object is in a `Slag` that is owned by a thread, which allocates from it.
+    Thread,
+    /// The object is in a `Slag` that no thread allocates from, and which is waiting for frees or
+    /// available for reuse.
+    Shared,
+    /// The object is a large object with its own mapping.
+    Large,
+}
+
+impl AllocationInfo {
+    fn large(size: usize) -> AllocationInfo {
+        AllocationInfo {
+            size_class: None,
+            usable_size: size,
+            owning_heap: OwningHeap::Large,
+        }
+    }
+}
+
+/// Find the object containing `item`, which points into a `Slag` of type `ty` and size
+/// `slag_size`. Returns the start of the object along with information about it.
+unsafe fn find_in_slag(
+    slag_size: usize,
+    ty: AllocType,
+    item: *mut u8,
+) -> Option<(*mut u8, AllocationInfo)> {
+    let slag = Slag::find(item, slag_size);
+    // The page may never have been used as a `Slag`, or have been used for the other type.
+    let meta = match (*slag).try_metadata() {
+        Some(meta) if meta.ty == ty => meta,
+        _ => return None,
+    };
+    let objects = slag as usize + meta.objects_offset as usize;
+    if (item as usize) < objects || item as usize >= objects + meta.n_objects * meta.object_size {
+        return None;
+    }
+    let start = objects + (item as usize - objects) / meta.object_size * meta.object_size;
+    Some((
+        start as *mut u8,
+        AllocationInfo {
+            size_class: Some(meta.object_size),
+            usable_size: meta.object_size,
+            owning_heap: if (*slag).is_owned() {
+                OwningHeap::Thread
+            } else {
+                OwningHeap::Shared
+            },
+        },
+    ))
+}
+
+impl<M, D, AM, C> ElfMalloc<PageAlloc<M, D>, AM, C>
+where
+    M: MemorySource,
+    D: DirtyFn,
+    AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key = usize>,
+    C: Config,
+{
     fn new_internal(
         // usable_size: usize,
         cutoff_factor: f64,
@@ -911,6 +1715,8 @@ impl<M: MemorySource, D: DirtyFn, AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key
         n_classes: usize,
     ) -> Self {
         use self::mmap::map;
+        // The metadata is only read once it is initialized here. It gets a mapping of its own so
+        // that it never shares a cache line with data that is written on the fast path.
         let mut meta_pointer = map(mem::size_of::<Metadata>() * n_classes) as *mut Metadata;
         let small_page_size = pa_small.backing_memory().page_size();
         let am = AM::init(start_from, n_classes, |size: usize| {
@@ -938,13 +1744,17 @@ impl<M: MemorySource, D: DirtyFn, AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key
                     ),
                 );
             }
-            let clean = PageCleanup::new(pa.backing_memory().page_size());
+            let clean = {
+                let source = pa.backing_memory();
+                PageCleanup::for_source(source.page_size(), source)
+            };
             // TODO(ezrosent); new_size(8) is a good default, but a better one would take
             // num_cpus::get() into account when picking this size, as in principle this will run
             // into scaling limits at some point.
             let params = (
                 m_ptr,
-                1 << 20,
+                C::DECOMMIT_THRESHOLD,
+                C::MAGAZINE_OBJECTS,
                 pa,
                 RevocablePipe::new_size_cleanup(16, clean),
             );
@@ -957,14 +1767,28 @@ impl<M: MemorySource, D: DirtyFn, AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key
                 ObjectAlloc::new((params, Depot::default()))
             }
         });
-        let max_size = am.max_key();
+        alloc_assert_eq!(am.max_key(), C::MAX_SMALL_SIZE);
         ElfMalloc {
             small_pages: pa_small.clone(),
             large_pages: pa_large.clone(),
             allocs: am,
-            max_size: max_size,
             start_from: start_from,
             n_classes: n_classes,
+            #[cfg(feature = "quarantine")]
+            quarantine: Quarantine::new(),
+            #[cfg(feature = "latency-stats")]
+            latency_sampler: latency::Sampler::new(),
+            #[cfg(feature = "stats")]
+            heap_stats: heap::Local::default(),
+            #[cfg(feature = "site-pools")]
+            site_pools: site_pools::Local::new(),
+            #[cfg(feature = "leak-report")]
+            leak_sampler: leaks::Sampler::new(),
+            #[cfg(feature = "cache-decay")]
+            decay: decay::Clock::default(),
+            #[cfg(feature = "batch-unmap")]
+            unmap_batch: RangeBatch::new(),
+            config: PhantomData,
         }
     }
 
@@ -994,14 +1818,161 @@ impl<M: MemorySource, D: DirtyFn, AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key
         }
     }
 
+    /// Call `f` with every object that this handle caches for the size class of `bytes`, and
+    /// return the `Slag` it allocates that class from. Returns `None` without calling `f` for
+    /// large sizes and size classes this handle has not used yet.
+    #[cfg(any(feature = "ownership", all(test, feature = "cache-decay")))]
+    fn cached_objects<F: FnMut(*mut u8)>(&mut self, bytes: usize, f: F) -> Option<*mut Slag> {
+        if bytes > C::MAX_SMALL_SIZE {
+            return None;
+        }
+        let cache = unsafe { self.allocs.get_mut(bytes) };
+        cache.get_mut_initialized().map(|cache| {
+            cache.for_each_cached(f);
+            cache.current_slag()
+        })
+    }
+
     unsafe fn alloc(&mut self, bytes: usize) -> *mut u8 {
-        if likely(bytes <= self.max_size) {
-            self.allocs.get_mut(bytes).alloc()
+        #[cfg(feature = "alloc-guard")]
+        alloc_guard::check(bytes);
+        heap_event!(self.heap_stats, alloc, bytes);
+        #[cfg(feature = "latency-stats")]
+        {
+            if self.latency_sampler.sample() {
+                let start = Instant::now();
+                let item = self.alloc_untimed(bytes);
+                if !item.is_null() {
+                    latency::record(LatencyOp::Alloc, self.size_class(item), start);
+                }
+                return item;
+            }
+        }
+        self.alloc_untimed(bytes)
+    }
+
+    #[inline]
+    unsafe fn alloc_untimed(&mut self, bytes: usize) -> *mut u8 {
+        if likely(bytes <= C::MAX_SMALL_SIZE) {
+            let item = self.allocs.get_mut(bytes).alloc();
+            #[cfg(feature = "cache-decay")]
+            {
+                if let Some((now, interval)) = self.decay.tick() {
+                    self.decay_caches(now, interval);
+                }
+            }
+            #[cfg(feature = "quota")]
+            {
+                if !quota::charge(self.object_size(item)) {
+                    self.allocs.get_mut(bytes).free(item);
+                    return ptr::null_mut();
+                }
+            }
+            #[cfg(feature = "asan")]
+            asan::on_alloc(item, bytes, self.object_size(item));
+            #[cfg(feature = "valgrind")]
+            valgrind::malloclike_block(item, bytes, false);
+            #[cfg(feature = "tsan")]
+            tsan::acquire(item);
+            #[cfg(feature = "msan")]
+            msan::unpoison(item, bytes);
+            #[cfg(feature = "mte")]
+            let item = mte::tag(item, self.object_size(item));
+            item
         } else {
             large_alloc::alloc(bytes)
         }
     }
 
+    /// The size of the size class of `item`, or `None` if it is a large object.
+    #[cfg(feature = "latency-stats")]
+    unsafe fn size_class(&self, item: *mut u8) -> Option<usize> {
+        #[cfg(feature = "mte")]
+        let item = mte::untag(item);
+        self.get_page_size(item)
+            .map(|page_size| (*Slag::find(item, page_size)).get_metadata().object_size)
+    }
+
+    /// The size of the size class of `item`, which must not be a large object.
+    #[cfg(any(feature = "asan", feature = "quota", feature = "mte"))]
+    unsafe fn object_size(&self, item: *mut u8) -> usize {
+        let page_size = self.get_page_size(item).expect("large object has no size class");
+        (*Slag::find(item, page_size)).get_metadata().object_size
+    }
+
+    #[cfg(feature = "tags")]
+    unsafe fn alloc_labeled(&mut self, bytes: usize, label: Label, val: u32) -> *mut u8 {
+        #[cfg(feature = "site-pools")]
+        {
+            if label == Label::Site {
+                if let Some(item) = self.site_pools.alloc(val, bytes) {
+                    heap_event!(self.heap_stats, alloc, bytes);
+                    self.set_label(item, label, val);
+                    #[cfg(feature = "leak-report")]
+                    self.sample_leak(item, label, val, bytes);
+                    return item;
+                }
+            }
+        }
+        let item = self.alloc(bytes);
+        // `alloc` fails when a quota is exceeded or, with `alloc-guard`, when memory runs out.
+        if item.is_null() {
+            return item;
+        }
+        self.set_label(item, label, val);
+        #[cfg(feature = "leak-report")]
+        self.sample_leak(item, label, val, bytes);
+        #[cfg(feature = "site-pools")]
+        {
+            if label == Label::Site && val != 0 && self.site_pools.sample() {
+                if let Some(page_size) = self.get_page_size(item) {
+                    let object_size = (*Slag::find(item, page_size)).get_metadata().object_size;
+                    self.site_pools.record(val, bytes, object_size);
+                }
+            }
+        }
+        item
+    }
+
+    /// Record a sample of the allocations made with a site in the leak report.
+    #[cfg(feature = "leak-report")]
+    unsafe fn sample_leak(&mut self, item: *mut u8, label: Label, val: u32, bytes: usize) {
+        if label == Label::Site && val != 0 && !item.is_null() && self.leak_sampler.sample() {
+            #[cfg(feature = "mte")]
+            let item = mte::untag(item);
+            leaks::record(item, val, bytes);
+        }
+    }
+
+    /// Get the `label` of `item`.
+    #[cfg(feature = "tags")]
+    unsafe fn get_label(&self, item: *mut u8, label: Label) -> u32 {
+        #[cfg(feature = "mte")]
+        let item = mte::untag(item);
+        match self.get_page_size(item) {
+            Some(page_size) => (*Slag::find(item, page_size)).get_label(item, label),
+            None => large_alloc::get_label(item, label),
+        }
+    }
+
+    /// Set the `label` of `item`, which must currently be unset, and account for its size.
+    #[cfg(feature = "tags")]
+    unsafe fn set_label(&mut self, item: *mut u8, label: Label, val: u32) {
+        if val == 0 {
+            return;
+        }
+        #[cfg(feature = "mte")]
+        let item = mte::untag(item);
+        match self.get_page_size(item) {
+            Some(page_size) => {
+                let slag = &*Slag::find(item, page_size);
+                slag.set_label(item, label, val);
+                tags::account_label(label, val, slag.get_metadata().object_size);
+            }
+            None => large_alloc::set_label(item, label, val),
+        }
+    }
+
     unsafe fn realloc(
         &mut self,
         item: *mut u8,
@@ -1020,15 +1991,83 @@ impl<M: MemorySource, D: DirtyFn, AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key
             self.free(item);
             return ptr::null_mut();
         }
-        let (old_size, old_alignment) = global::get_layout(item);
+        // `item` keeps its tag for accessing the object, but metadata lookups need the untagged
+        // address.
+        #[cfg(feature = "mte")]
+        let untagged = mte::untag(item);
+        #[cfg(not(feature = "mte"))]
+        let untagged = item;
+        let (old_size, old_alignment) = global::get_layout(untagged);
         if old_alignment >= new_alignment && old_size >= new_size {
+            #[cfg(feature = "asan")]
+            {
+                if self.get_page_size(untagged).is_some() {
+                    asan::on_alloc(item, new_size, old_size);
+                }
+            }
+            #[cfg(feature = "valgrind")]
+            {
+                if self.get_page_size(untagged).is_some() {
+                    valgrind::resize_in_place(item, old_size, new_size);
+                }
+            }
+            if self.get_page_size(untagged).is_none() && large_alloc::shrink(untagged, new_size) {
+                realloc_event!(SHRUNK);
+            } else {
+                realloc_event!(FIT);
+            }
             return item;
         }
         if new_alignment > mem::size_of::<usize>() {
             new_size = new_size.next_power_of_two();
         }
+        // Moving the pages of a large allocation preserves its (page) alignment.
+        #[cfg(all(target_os = "linux", not(miri)))]
+        {
+            if old_alignment >= new_alignment && new_size > C::MAX_SMALL_SIZE &&
+                self.get_page_size(untagged).is_none()
+            {
+                if let Some(new_mem) = large_alloc::realloc(untagged, new_size) {
+                    return new_mem;
+                }
+            }
+        }
         let new_mem = self.alloc(new_size);
+        if new_mem.is_null() {
+            return new_mem;
+        }
+        #[cfg(feature = "tags")]
+        for &label in &LABELS {
+            let val = self.get_label(item, label);
+            self.set_label(new_mem, label, val);
+            // Carry over a sampled object's time of allocation; freeing `item` below is then a
+            // no-op for the leak report.
+            #[cfg(feature = "leak-report")]
+            {
+                if label == Label::Site && val != 0 {
+                    #[cfg(feature = "mte")]
+                    let new_mem = mte::untag(new_mem);
+                    leaks::moved(untagged, new_mem, new_size);
+                }
+            }
+        }
+        // We do not know how much of the old object was requested, so its redzone is copied as
+        // well.
+        #[cfg(feature = "asan")]
+        {
+            if self.get_page_size(untagged).is_some() {
+                asan::unpoison(item, old_size);
+            }
+        }
+        #[cfg(feature = "valgrind")]
+        {
+            if self.get_page_size(untagged).is_some() {
+                valgrind::make_mem_defined(item, old_size);
+            }
+        }
         ptr::copy_nonoverlapping(item, new_mem, ::std::cmp::min(old_size, new_size));
+        realloc_event!(COPIED);
+        realloc_event!(COPIED_BYTES, ::std::cmp::min(old_size, new_size));
         self.free(item);
         #[cfg(debug_assertions)]
         {
@@ -1038,14 +2077,218 @@ impl<M: MemorySource, D: DirtyFn, AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key
         new_mem
     }
 
+    unsafe fn trim(&mut self, level: usize) -> usize {
+        use std::cell::Cell;
+        let released = Cell::new(0);
+        #[cfg(feature = "quarantine")]
+        {
+            if level >= 1 {
+                self.flush_quarantine();
+            }
+        }
+        if level >= 1 {
+            // Flushing caches can leave `Slag`s completely free, so this has to happen before the
+            // page caches are trimmed below.
+            self.allocs.foreach(|x| unsafe {
+                if let Some(alloc) = (*x).get_mut_initialized() {
+                    released.set(released.get() + alloc.trim(level));
+                }
+            });
+        }
+        #[cfg(feature = "batch-unmap")]
+        released.set(released.get() + self.flush_unmaps(false));
+        #[cfg(feature = "large-cache")]
+        released.set(released.get() + super::large_cache::flush());
+        released.get() + self.small_pages.trim() + self.large_pages.trim()
+    }
+
+    /// Free the cached objects of the size classes that have not been used for `interval`
+    /// milliseconds as of `now`. See the `decay` module.
+    #[cfg(feature = "cache-decay")]
+    #[cold]
+    unsafe fn decay_caches(&mut self, now: u64, interval: u64) {
+        self.allocs.foreach(|x| unsafe {
+            if let Some(alloc) = (*x).get_mut_initialized() {
+                if now - alloc.last_used(now) >= interval {
+                    slow_path_event!(CACHE_DECAYS);
+                    alloc.trim(1);
+                }
+            }
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn defrag(&mut self) -> usize {
+        use std::cell::Cell;
+        let collapsed = Cell::new(0);
+        let last = Cell::new(ptr::null_mut());
+        self.allocs.foreach(|x| unsafe {
+            if let Some(alloc) = (*x).get_mut_initialized() {
+                // Both small and large pages are carved out of regions aligned to
+                // ELFMALLOC_PAGE_SIZE, which is the size of a huge page on x86-64.
+                let region = round_to_page(alloc.current_slag() as *mut u8);
+                if region != last.get() && mmap::collapse(region, ELFMALLOC_PAGE_SIZE) {
+                    collapsed.set(collapsed.get() + ELFMALLOC_PAGE_SIZE);
+                }
+                last.set(region);
+            }
+        });
+        collapsed.get()
+    }
+
     unsafe fn free(&mut self, item: *mut u8) {
+        heap_event!(self.heap_stats, free);
+        #[cfg(feature = "latency-stats")]
+        {
+            if self.latency_sampler.sample() {
+                // The Slag may be reused for another size class once the object is freed.
+                let class = self.size_class(item);
+                let start = Instant::now();
+                self.free_untimed(item);
+                latency::record(LatencyOp::Free, class, start);
+                return;
+            }
+        }
+        self.free_untimed(item)
+    }
+
+    #[inline]
+    unsafe fn free_untimed(&mut self, item: *mut u8) {
+        #[cfg(feature = "mte")]
+        let item = {
+            let item = mte::untag(item);
+            if self.get_page_size(item).is_some() {
+                mte::retire(item, self.object_size(item));
+            }
+            item
+        };
+        #[cfg(feature = "quarantine")]
+        {
+            if let Some(page_size) = self.get_page_size(item) {
+                let size = (*Slag::find(item, page_size)).get_metadata().object_size;
+                if self.quarantine.push(item, size) {
+                    while let Some(old) = self.quarantine.evict() {
+                        self.release(old);
+                    }
+                    return;
+                }
+            }
+        }
+        self.release(item)
+    }
+
+    unsafe fn prefault(&mut self, bytes: usize) -> usize {
+        self.small_pages.prefault(bytes) + self.large_pages.prefault(bytes)
+    }
+
+    unsafe fn park(&mut self) {
+        #[cfg(feature = "quarantine")]
+        self.flush_quarantine();
+        #[cfg(feature = "batch-unmap")]
+        self.flush_unmaps(true);
+        self.allocs.foreach(|x| unsafe {
+            if let Some(alloc) = (*x).get_mut_initialized() {
+                alloc.park();
+            }
+        });
+    }
+
+    unsafe fn unpark(&mut self) {
+        self.allocs.foreach(|x| unsafe {
+            if let Some(alloc) = (*x).get_mut_initialized() {
+                alloc.unpark();
+            }
+        });
+    }
+
+    /// Return all objects in the quarantine to their size classes.
+    #[cfg(feature = "quarantine")]
+    unsafe fn flush_quarantine(&mut self) {
+        while let Some(item) = self.quarantine.pop() {
+            self.release(item);
+        }
+    }
+
+    /// Unmap the mappings in this handle's batch, returning the number of bytes in it. If `defer`
+    /// is set and deferred unmapping is enabled, the batch is handed to the background thread
+    /// instead, and 0 is returned.
+    #[cfg(feature = "batch-unmap")]
+    unsafe fn flush_unmaps(&mut self, defer: bool) -> usize {
+        if self.unmap_batch.is_empty() {
+            return 0;
+        }
+        let mut batch = self.unmap_batch.take();
+        if defer && conf::defer_unmap() {
+            global::defer_unmap(batch);
+            0
+        } else {
+            batch.flush(|base, len| mmap::unmap(base, len))
+        }
+    }
+
+    /// Free `item` without going through the quarantine.
+    unsafe fn release(&mut self, item: *mut u8) {
         match self.get_page_size(item) {
             Some(page_size) => {
                 let slag = &*Slag::find(item, page_size);
+                if unlikely(slag.try_metadata().is_none()) {
+                    // The page was never initialized as a `Slag`, so `item` was never handed out.
+                    integrity::report(Violation::ForeignFree { ptr: item });
+                    return;
+                }
+                #[cfg(feature = "site-pools")]
+                let pool = match slag.get_label(item, Label::Tag) {
+                    0 => {
+                        let site = slag.get_label(item, Label::Site);
+                        self.site_pools.pool_for(site, slag.get_metadata().object_size)
+                    }
+                    _ => None,
+                };
+                #[cfg(feature = "tags")]
+                for &label in &LABELS {
+                    let val = slag.get_label(item, label);
+                    if val != 0 {
+                        slag.set_label(item, label, 0);
+                        tags::unaccount_label(label, val, slag.get_metadata().object_size);
+                        #[cfg(feature = "leak-report")]
+                        {
+                            if label == Label::Site {
+                                leaks::forget(item);
+                            }
+                        }
+                    }
+                }
+                #[cfg(feature = "site-pools")]
+                {
+                    if let Some(i) = pool {
+                        self.site_pools.push(i, item);
+                        return;
+                    }
+                }
+                #[cfg(feature = "quota")]
+                quota::uncharge(slag.get_metadata().object_size);
+                // Before ASan poisons the object.
+                #[cfg(feature = "zero-on-free")]
+                zero::zero(item, slag.get_metadata().object_size);
+                #[cfg(feature = "asan")]
+                asan::on_free(item, slag.get_metadata().object_size);
+                #[cfg(feature = "valgrind")]
+                valgrind::freelike_block(item);
+                #[cfg(feature = "tsan")]
+                tsan::release(item);
                 self.allocs.get_mut(slag.get_metadata().object_size).free(
                     item,
                 )
             }
+            #[cfg(feature = "batch-unmap")]
+            None => {
+                if let Some((base, len)) = large_alloc::release(item) {
+                    if self.unmap_batch.push(base, len) {
+                        self.flush_unmaps(true);
+                    }
+                }
+            }
+            #[cfg(not(feature = "batch-unmap"))]
             None => large_alloc::free(item),
         };
     }
@@ -1057,15 +2300,44 @@ mod large_alloc {
     //!
     //! Large allocations are implemented by mapping a region of memory of the indicated size, with
     //! an additional page of padding to store the size information.
+    //!
+    //! # Locking
+    //!
+    //! There is no index of large objects, and no lock on this path. Everything `free`, `realloc`
+    //! and `lookup` need to know about a large object is read from the header page in front of it,
+    //! which only the object's owner writes. The other structures a large allocation or free
+    //! touches are lock-free: the ownership table (see the `ownership` module) is an atomic radix
+    //! table, the large object cache (`large-cache`) claims and releases its slots with
+    //! compare-and-swap, and `batch-unmap` batches are per handle. The only lock nearby is the
+    //! channel to the background thread, taken once per batch handed off with `defer_unmap`, which
+    //! small-object lookups never take.
+    //!
+    //! What large allocations do contend on is the kernel's lock on the address space, which
+    //! `mmap`, `munmap` and `mremap` take for writing and page faults take for reading. A small
+    //! lookup only waits on it if it faults. The `bench_large_lookup` benchmark measures small
+    //! lookups with large allocations running on other threads.
+    #[cfg(target_os = "linux")]
+    extern crate libc;
     #[cfg(test)]
     use std::collections::HashMap;
     #[cfg(test)]
     use std::cell::RefCell;
-    use std::cmp;
     use std::ptr;
     use super::super::sources::{MemorySource, MmapSource};
     use super::{ELFMALLOC_PAGE_SIZE, ELFMALLOC_SMALL_CUTOFF, round_to_page};
     use super::super::alloc_type::AllocType;
+    #[cfg(feature = "tags")]
+    use super::super::tags::{self, Label, LABELS, N_LABELS};
+    #[cfg(feature = "quota")]
+    use super::super::quota;
+    #[cfg(feature = "ownership")]
+    use super::super::ownership;
+    #[cfg(feature = "ownership")]
+    use super::AllocationInfo;
+    #[cfg(feature = "large-cache")]
+    use super::super::large_cache;
+    #[cfg(feature = "zero-on-free")]
+    use super::super::zero;
 
     // For debugging, we keep around a thread-local map of pointers to lengths. This helps us
     // scrutinize if various header data is getting propagated correctly.
@@ -1073,9 +2345,8 @@ mod large_alloc {
     thread_local! {
         pub static SEEN_PTRS: RefCell<HashMap<*mut u8, usize>> = RefCell::new(HashMap::new());
     }
-    use super::mmap::unmap;
-    #[cfg(debug_assertions)]
-    use super::mmap::page_size;
+    use super::mmap::{name, page_size, uncommit, unmap, MapName};
+    use super::super::conf;
 
     #[repr(C)]
     #[derive(Copy, Clone)]
@@ -1083,16 +2354,48 @@ mod large_alloc {
         ty: AllocType,
         base: *mut u8,
         region_size: usize,
+        #[cfg(feature = "tags")]
+        labels: [u32; N_LABELS],
+    }
+
+    /// The size of the mapping for a large object whose region (the object and its header page)
+    /// is `region_size` bytes long.
+    fn mapped_size(region_size: usize) -> usize {
+        let mapped = (region_size + ELFMALLOC_SMALL_CUTOFF - 1) & !(ELFMALLOC_SMALL_CUTOFF - 1);
+        // Mappings are rounded to the cache's size classes so that they can be reused for objects
+        // of slightly different sizes.
+        #[cfg(feature = "large-cache")]
+        let mapped = large_cache::round(mapped);
+        mapped
     }
 
     pub unsafe fn alloc(size: usize) -> *mut u8 {
+        #[cfg(feature = "quota")]
+        {
+            if !quota::charge(size) {
+                return ptr::null_mut();
+            }
+        }
         // TODO(ezrosent) round up to page size
         let region_size = size + ELFMALLOC_PAGE_SIZE;
-        // We need a pointer aligned to the SMALL_CUTOFF, so we use an `MmapSource` to map the
-        // memory. See the comment in get_page_size.
-        let src = MmapSource::new(ELFMALLOC_SMALL_CUTOFF);
-        let n_pages = region_size / ELFMALLOC_SMALL_CUTOFF + cmp::min(1, region_size % ELFMALLOC_SMALL_CUTOFF);
-        let mem = src.carve(n_pages).expect("[lage_alloc::alloc] mmap failed");
+        let mapped = mapped_size(region_size);
+        #[cfg(feature = "large-cache")]
+        let cached = large_cache::take(mapped);
+        #[cfg(not(feature = "large-cache"))]
+        let cached = None;
+        let mem = cached.unwrap_or_else(|| {
+            // We need a pointer aligned to the SMALL_CUTOFF, so we use an `MmapSource` to map the
+            // memory. See the comment in get_page_size.
+            let src = MmapSource::new(ELFMALLOC_SMALL_CUTOFF);
+            let mem = src.carve(mapped / ELFMALLOC_SMALL_CUTOFF)
+                .expect("[lage_alloc::alloc] mmap failed");
+            // Mappings taken from the cache keep their name, and `realloc` moves the name along
+            // with the pages.
+            name(mem, mapped, &MapName::new(None, "large"));
+            mem
+        });
+        #[cfg(feature = "ownership")]
+        ownership::register_large(mem, mapped);
         let res = mem.offset(ELFMALLOC_PAGE_SIZE as isize);
         let addr = get_commitment_mut(res);
         ptr::write(
@@ -1101,6 +2404,8 @@ mod large_alloc {
                 ty: AllocType::Large,
                 base: mem,
                 region_size: region_size,
+                #[cfg(feature = "tags")]
+                labels: [0; N_LABELS],
             },
         );
 
@@ -1115,8 +2420,26 @@ mod large_alloc {
     }
 
     pub unsafe fn free(item: *mut u8) {
+        if let Some((base, mapped)) = release(item) {
+            unmap(base, mapped);
+        }
+    }
+
+    /// Retire the large allocation `item` without unmapping it. Returns the mapping that the
+    /// caller has to unmap, unless it was kept in the large object cache.
+    pub unsafe fn release(item: *mut u8) -> Option<(*mut u8, usize)> {
         let (size, base_ptr) = get_commitment(item);
         trace!("size={}, base_ptr={:?}", size, base_ptr);
+        #[cfg(feature = "tags")]
+        for &label in &LABELS {
+            tags::unaccount_label(label, get_label(item, label), size - ELFMALLOC_PAGE_SIZE);
+        }
+        #[cfg(feature = "leak-report")]
+        {
+            if get_label(item, Label::Site) != 0 {
+                super::super::leaks::forget(item);
+            }
+        }
         // begin extra debugging information:
         #[cfg(debug_assertions)]
         {
@@ -1136,7 +2459,162 @@ mod large_alloc {
             });
         }
         // end extra debugging information
-        unmap(base_ptr, size);
+        // Only the object has to be zeroed: the rest of the mapping is never written, so if the
+        // mapping is cached, the next object placed in it starts out zeroed as well.
+        #[cfg(feature = "zero-on-free")]
+        zero::zero(item, size - ELFMALLOC_PAGE_SIZE);
+        #[cfg(feature = "quota")]
+        quota::uncharge(size - ELFMALLOC_PAGE_SIZE);
+        let mapped = mapped_size(size);
+        // Unregister before unmapping, as the range may be reused by another mapping right away.
+        #[cfg(feature = "ownership")]
+        ownership::unregister(base_ptr, mapped);
+        #[cfg(feature = "large-cache")]
+        {
+            // `lookup` must not mistake the start of a cached mapping for a live object.
+            (*get_commitment_mut(item)).base = ptr::null_mut();
+            if large_cache::put(base_ptr, mapped) {
+                return None;
+            }
+        }
+        Some((base_ptr, mapped))
+    }
+
+    /// Resize the large allocation `item` so that it can hold `new_size` bytes.
+    ///
+    /// Rather than copying the contents of `item`, the underlying pages are moved to their new
+    /// location using `mremap`. The mapping is first grown or shrunk in place if possible; if not,
+    /// a suitably aligned region is reserved and the pages are moved on top of it with
+    /// `MREMAP_FIXED`. This preserves the alignment invariants described in `get_page_size`.
+    ///
+    /// If `None` is returned, `item` was not modified and is still valid.
+    #[cfg(all(target_os = "linux", not(miri)))]
+    pub unsafe fn realloc(item: *mut u8, new_size: usize) -> Option<*mut u8> {
+        use self::libc::{c_void, mremap, MAP_FAILED, MREMAP_FIXED, MREMAP_MAYMOVE};
+        let (region_size, base) = get_commitment(item);
+        #[cfg(feature = "tags")]
+        let labels = (*get_commitment_mut(item)).labels;
+        let new_region_size = new_size + ELFMALLOC_PAGE_SIZE;
+        let old_mapped = mapped_size(region_size);
+        let new_mapped = mapped_size(new_region_size);
+        #[cfg(feature = "quota")]
+        let old_size = region_size - ELFMALLOC_PAGE_SIZE;
+        #[cfg(feature = "quota")]
+        {
+            if new_size > old_size && !quota::charge(new_size - old_size) {
+                return None;
+            }
+        }
+        let new_base = if old_mapped == new_mapped ||
+            mremap(base as *mut c_void, old_mapped, new_mapped, 0) != MAP_FAILED
+        {
+            base
+        } else {
+            let src = MmapSource::new(ELFMALLOC_SMALL_CUTOFF);
+            let dest = match src.carve(new_mapped / ELFMALLOC_SMALL_CUTOFF) {
+                Some(dest) => dest,
+                None => {
+                    #[cfg(feature = "quota")]
+                    quota::uncharge(new_size.saturating_sub(old_size));
+                    return None;
+                }
+            };
+            let res = mremap(
+                base as *mut c_void,
+                old_mapped,
+                new_mapped,
+                MREMAP_MAYMOVE | MREMAP_FIXED,
+                dest as *mut c_void,
+            );
+            if res == MAP_FAILED {
+                unmap(dest, new_mapped);
+                #[cfg(feature = "quota")]
+                quota::uncharge(new_size.saturating_sub(old_size));
+                return None;
+            }
+            alloc_debug_assert_eq!(res as *mut u8, dest);
+            dest
+        };
+        #[cfg(feature = "ownership")]
+        {
+            ownership::unregister(base, old_mapped);
+            ownership::register_large(new_base, new_mapped);
+        }
+        if new_base == base {
+            realloc_event!(REMAPPED_IN_PLACE);
+        } else {
+            realloc_event!(REMAPPED_MOVED);
+        }
+        let res = new_base.offset(ELFMALLOC_PAGE_SIZE as isize);
+        // The header's offset from the base depends on the base's alignment, so it has to be
+        // rewritten even if the contents of the padding page were moved along with everything else.
+        ptr::write(
+            get_commitment_mut(res),
+            AllocInfo {
+                ty: AllocType::Large,
+                base: new_base,
+                region_size: new_region_size,
+                #[cfg(feature = "tags")]
+                labels: labels,
+            },
+        );
+        #[cfg(feature = "tags")]
+        for &label in &LABELS {
+            let val = labels[label as usize];
+            tags::unaccount_label(label, val, region_size - ELFMALLOC_PAGE_SIZE);
+            tags::account_label(label, val, new_size);
+        }
+        #[cfg(feature = "leak-report")]
+        {
+            if labels[Label::Site as usize] != 0 {
+                super::super::leaks::moved(item, res, new_size);
+            }
+        }
+        #[cfg(feature = "quota")]
+        quota::uncharge(old_size.saturating_sub(new_size));
+        #[cfg(test)]
+        SEEN_PTRS.with(|hm| {
+            let mut hmap = hm.borrow_mut();
+            hmap.remove(&base);
+            hmap.insert(new_base, new_region_size);
+        });
+        Some(res)
+    }
+
+    /// Uncommit the pages of the large allocation `item` past its first `new_size` bytes, if that
+    /// releases at least `conf::shrink_threshold()` bytes. Returns whether it did.
+    ///
+    /// The mapping and the header are left alone, so the object keeps its usable size, and it can
+    /// grow back into the uncommitted pages (which read as zeros) without moving. Quotas and tags
+    /// still account for the object at its usable size.
+    pub unsafe fn shrink(item: *mut u8, new_size: usize) -> bool {
+        let threshold = conf::shrink_threshold();
+        if threshold == 0 {
+            return false;
+        }
+        let (region_size, base) = get_commitment(item);
+        let page = page_size();
+        let start = (item as usize + new_size + page - 1) & !(page - 1);
+        // Mappings taken from the large object cache may have been touched past the object.
+        let end = base as usize + mapped_size(region_size);
+        if end <= start || end - start < threshold {
+            return false;
+        }
+        uncommit(start as *mut u8, end - start);
+        true
+    }
+
+    #[cfg(feature = "tags")]
+    pub unsafe fn get_label(item: *mut u8, label: Label) -> u32 {
+        (*get_commitment_mut(item)).labels[label as usize]
+    }
+
+    /// Set the `label` of `item`, which must currently be unset.
+    #[cfg(feature = "tags")]
+    pub unsafe fn set_label(item: *mut u8, label: Label, val: u32) {
+        alloc_debug_assert_eq!(get_label(item, label), 0);
+        (*get_commitment_mut(item)).labels[label as usize] = val;
+        tags::account_label(label, val, get_size(item));
     }
 
     pub unsafe fn get_size(item: *mut u8) -> usize {
@@ -1144,6 +2622,35 @@ mod large_alloc {
         size - ELFMALLOC_PAGE_SIZE
     }
 
+    /// Find the large object containing `item`, which points into the large object mapping that
+    /// starts at `base`. Returns the start of the object along with information about it.
+    #[cfg(feature = "ownership")]
+    pub unsafe fn find(base: usize, item: *mut u8) -> Option<(*mut u8, AllocationInfo)> {
+        let start = (base + ELFMALLOC_PAGE_SIZE) as *mut u8;
+        if item < start {
+            return None;
+        }
+        // The metadata of the object lies between `base` and `start`, so this reads the mapping.
+        match lookup(start) {
+            Some(size) if (item as usize) < start as usize + size => {
+                Some((start, AllocationInfo::large(size)))
+            }
+            _ => None,
+        }
+    }
+
+    /// The size of `item` if it is the start of a large object, or `None` otherwise.
+    pub unsafe fn lookup(item: *mut u8) -> Option<usize> {
+        let info = &*get_commitment_mut(item);
+        // Check the base first: if `item` is not the start of a large object, `info` is just bytes
+        // of some object, which need not be a valid `AllocType`.
+        if info.base as usize + ELFMALLOC_PAGE_SIZE == item as usize && info.ty == AllocType::Large {
+            Some(info.region_size - ELFMALLOC_PAGE_SIZE)
+        } else {
+            None
+        }
+    }
+
     unsafe fn get_commitment(item: *mut u8) -> (usize, *mut u8) {
         let meta_addr = get_commitment_mut(item);
         let base_ptr = (*meta_addr).base;
@@ -1159,6 +2666,8 @@ mod large_alloc {
 #[cfg(test)]
 mod tests {
     extern crate env_logger;
+    extern crate test;
+    use self::test::Bencher;
     use super::*;
     use std::ptr::{write_bytes, write_volatile};
 
@@ -1185,6 +2694,120 @@ mod tests {
         });
     }
 
+    #[test]
+    fn config_sets_size_classes() {
+        use super::super::profile::SmallConfig;
+        alloc_assert_eq!(tiered_n_classes(8, 1 << 20), 25);
+        alloc_assert_eq!(tiered_n_classes(8, 256 << 10), 21);
+        let mut small = ElfMalloc::<PageAlloc<Source>, Classes, SmallConfig>::new();
+        alloc_assert_eq!(small.class_size(256 << 10), Some(256 << 10));
+        alloc_assert_eq!(small.class_size((256 << 10) + 1), None);
+        unsafe {
+            let item = small.alloc(300 << 10);
+            alloc_assert_eq!(get_type(item), AllocType::Large);
+            small.free(item);
+            small.destroy();
+        }
+    }
+
+    #[test]
+    fn allocation_lookup() {
+        unsafe {
+            for &size in &[8, 24, 512, 100 << 10] {
+                let obj = global::alloc(size);
+                let info = global::lookup(obj).expect("lookup should find the object");
+                let class = info.size_class.expect("object should be in a size class");
+                alloc_assert!(class >= size);
+                alloc_assert_eq!(info.usable_size, class);
+                // The object was just allocated from this thread's Slag.
+                alloc_assert_eq!(info.owning_heap, OwningHeap::Thread);
+                alloc_assert_eq!(global::lookup(obj.offset(size as isize - 1)), Some(info));
+                alloc_assert_eq!(global::object_start(obj.offset(size as isize - 1)), Some(obj));
+                global::free(obj);
+            }
+            let obj = global::alloc(4 << 20);
+            alloc_assert_eq!(
+                global::lookup(obj),
+                Some(AllocationInfo {
+                    size_class: None,
+                    usable_size: 4 << 20,
+                    owning_heap: OwningHeap::Large,
+                })
+            );
+            alloc_assert_eq!(global::object_start(obj), Some(obj));
+            global::free(obj);
+            alloc_assert_eq!(global::lookup(ptr::null_mut()), None);
+        }
+    }
+
+    #[test]
+    fn min_object_alignment() {
+        use super::super::slag::MIN_OBJECT_ALIGN;
+        // Allocate several objects of each size so that objects past the start of a Slag are
+        // checked too.
+        let mut ptrs = Vec::new();
+        for size in (2..512).map(|i| i * 8).chain(vec![(64 << 10) + 8, (2 << 20) + 8]) {
+            for _ in 0..8 {
+                unsafe {
+                    let obj = global::alloc(size);
+                    alloc_assert_eq!((obj as usize) % MIN_OBJECT_ALIGN,
+                                     0,
+                                     "object of size {} is misaligned: {:?}",
+                                     size,
+                                     obj);
+                    ptrs.push(obj);
+                }
+            }
+            for obj in ptrs.drain(..) {
+                unsafe { global::free(obj) };
+            }
+        }
+    }
+
+    /// Check that `size_class` (the equivalent of `nallocx`), the usable size reported by
+    /// `get_layout` and `lookup`, and the position of the object in its `Slag` all agree, for every
+    /// size that is served from a size class and for random large sizes. A mismatch would let a
+    /// caller write past the end of its slot without any immediate symptom.
+    #[test]
+    fn size_class_differential() {
+        use super::super::utils::random;
+        let probe = DynamicAllocator::new();
+        let max_small = BuildConfig::MAX_SMALL_SIZE;
+        unsafe {
+            for size in 1..(max_small + 1) {
+                let item = global::alloc(size);
+                let class = global::size_class(size).expect("small size without a size class");
+                alloc_assert!(class >= size, "size {} in class {}", size, class);
+                alloc_assert_eq!(global::get_layout(item).0, class, "size {}", size);
+                let info = global::lookup(item).unwrap();
+                alloc_assert_eq!(info.size_class, Some(class), "size {}", size);
+                alloc_assert_eq!(info.usable_size, class, "size {}", size);
+                let page_size = probe.0.get_page_size(item).unwrap();
+                let slag = Slag::find(item, page_size);
+                let meta = (*slag).get_metadata();
+                alloc_assert_eq!(meta.object_size, class, "size {}", size);
+                let offset = item as usize - slag as usize - meta.objects_offset as usize;
+                alloc_assert_eq!(offset % class, 0, "size {} at {:?}", size, item);
+                alloc_assert!(offset / class < meta.n_objects, "size {}", size);
+                write_volatile(item.offset(size as isize - 1), 1);
+                global::free(item);
+            }
+
+            let mut rng = random::seed();
+            for _ in 0..64 {
+                let size = max_small + 1 + random::next(&mut rng) as usize % (64 << 20);
+                let item = global::alloc(size);
+                alloc_assert_eq!(global::size_class(size), None, "size {}", size);
+                alloc_assert!(global::get_layout(item).0 >= size, "size {}", size);
+                let info = global::lookup(item).unwrap();
+                alloc_assert_eq!(info.size_class, None, "size {}", size);
+                alloc_assert!(info.usable_size >= size, "size {}", size);
+                write_volatile(item.offset(size as isize - 1), 1);
+                global::free(item);
+            }
+        }
+    }
+
     #[test]
     fn general_alloc_basic_global_single_threaded() {
         let _ = env_logger::init();
@@ -1211,6 +2834,69 @@ mod tests {
         }
     }
 
+    struct Class16;
+    impl SizeClass for Class16 {
+        const SIZE: usize = 16;
+    }
+
+    struct Class48;
+    impl SizeClass for Class48 {
+        const SIZE: usize = 48;
+    }
+
+    struct Class512;
+    impl SizeClass for Class512 {
+        const SIZE: usize = 512;
+    }
+
+    #[test]
+    fn specialized_size_classes() {
+        let mut elf = SpecializedAllocator::<Class48, Class16, Class512>::new();
+        let mut clone = elf.clone();
+        let mut da = DynamicAllocator::new();
+        for size in 1..2048 {
+            unsafe {
+                let item = elf.alloc(size);
+                let reference = da.alloc(size);
+                write_volatile(item, 10);
+                // The hot classes are the same classes that the general lookup finds.
+                alloc_assert_eq!(global::get_layout(item), global::get_layout(reference));
+                da.free(reference);
+                clone.free(item);
+            }
+        }
+    }
+
+    // A `SpecializedAllocator` only differs in how it finds a size class, so the benchmark has to
+    // be dominated by the fast path: objects are pushed and popped from a single thread's cache.
+    #[bench]
+    fn bench_push_pop_dynamic(b: &mut Bencher) {
+        let mut elf = DynamicAllocator::new();
+        let mut ptrs = Vec::with_capacity(1 << 12);
+        b.iter(|| unsafe {
+            for _ in 0..(1 << 12) {
+                ptrs.push(elf.alloc(48));
+            }
+            for p in ptrs.drain(..) {
+                elf.free(p);
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_push_pop_specialized(b: &mut Bencher) {
+        let mut elf = SpecializedAllocator::<Class48>::new();
+        let mut ptrs = Vec::with_capacity(1 << 12);
+        b.iter(|| unsafe {
+            for _ in 0..(1 << 12) {
+                ptrs.push(elf.alloc(48));
+            }
+            for p in ptrs.drain(..) {
+                elf.free(p);
+            }
+        });
+    }
+
     #[test]
     fn general_alloc_basic_global_many_threads() {
         let _ = env_logger::init();
@@ -1353,6 +3039,349 @@ mod tests {
         }
     }
 
+    #[test]
+    fn realloc_large() {
+        let _ = env_logger::init();
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            let mut size = 4 << 20;
+            let mut item = da.alloc(size);
+            write_bytes(item, 0xAB, size);
+            for &new_size in &[16 << 20, 5 << 20, 64 << 20] {
+                item = da.realloc(item, new_size);
+                for i in 0..::std::cmp::min(size, new_size) {
+                    alloc_assert_eq!(*item.offset(i as isize), 0xAB);
+                }
+                write_bytes(item, 0xAB, new_size);
+                size = new_size;
+            }
+            da.free(item);
+        }
+    }
+
+    #[test]
+    fn realloc_shrink_uncommits() {
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            let item = da.alloc(8 << 20);
+            write_bytes(item, 0xAB, 8 << 20);
+            alloc_assert_eq!(da.realloc(item, 1 << 20), item);
+            alloc_assert_eq!(*item.offset((1 << 20) - 1), 0xAB);
+            // The object keeps its usable size, but the pages past its new end are uncommitted,
+            // which makes them read as zeros on Linux.
+            alloc_assert_eq!(global::get_layout(item).0, 8 << 20);
+            #[cfg(target_os = "linux")]
+            alloc_assert_eq!(*item.offset(4 << 20), 0);
+            alloc_assert_eq!(da.realloc(item, 8 << 20), item);
+            write_bytes(item, 0xAB, 8 << 20);
+            da.free(item);
+        }
+    }
+
+    #[cfg(feature = "tags")]
+    #[test]
+    fn tagged_alloc() {
+        let _ = env_logger::init();
+        const TAG: Tag = 0xE1F;
+        let bytes_for_tag = || {
+            tags::bytes_by_tag()
+                .into_iter()
+                .find(|&(t, _)| t == TAG)
+                .map(|(_, bytes)| bytes)
+                .unwrap_or(0)
+        };
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            let small: Vec<*mut u8> = (0..1024).map(|_| da.alloc_tagged(24, TAG)).collect();
+            let large = da.alloc_tagged(4 << 20, TAG);
+            alloc_assert!(bytes_for_tag() >= 1024 * 24 + (4 << 20));
+            let large = da.realloc(large, 8 << 20);
+            alloc_assert!(bytes_for_tag() >= 1024 * 24 + (8 << 20));
+            da.free(large);
+            for p in small {
+                da.free(p);
+            }
+        }
+        alloc_assert_eq!(bytes_for_tag(), 0);
+    }
+
+    #[cfg(feature = "sites")]
+    #[test]
+    fn site_alloc() {
+        use super::super::sites;
+        let _ = env_logger::init();
+        let ids = [alloc_site!(), alloc_site!()];
+        alloc_assert!(ids[0] != ids[1]);
+        let stats_for = |site| {
+            sites::site_stats()
+                .into_iter()
+                .find(|s| s.id == site)
+                .unwrap()
+        };
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            let small: Vec<*mut u8> = (0..128).map(|_| da.alloc_with_site(24, ids[0])).collect();
+            let large = da.alloc_with_site(4 << 20, ids[1]);
+            let stats = stats_for(ids[0]);
+            alloc_assert_eq!(stats.file, file!());
+            alloc_assert_eq!(stats.live_objects, 128);
+            alloc_assert_eq!(stats.total_allocs, 128);
+            alloc_assert!(stats.live_bytes >= 128 * 24);
+            alloc_assert!(stats_for(ids[1]).live_bytes >= 4 << 20);
+            da.free(large);
+            for p in small {
+                da.free(p);
+            }
+        }
+        let stats = stats_for(ids[0]);
+        alloc_assert_eq!((stats.live_bytes, stats.live_objects), (0, 0));
+        alloc_assert_eq!(stats.total_allocs, 128);
+    }
+
+    #[cfg(feature = "site-pools")]
+    #[test]
+    fn site_pool_promotion() {
+        use super::super::{site_pools, sites};
+        let _ = env_logger::init();
+        let site = alloc_site!();
+        let mut da = DynamicAllocator::new();
+        let rounds = site_pools::sample_period() * site_pools::promotion_threshold();
+        unsafe {
+            for _ in 0..rounds {
+                let p = da.alloc_with_site(40, site);
+                da.free(p);
+            }
+            let promoted = site_pools::promoted();
+            let pair = promoted.iter().find(|p| p.site == site).unwrap();
+            alloc_assert_eq!((pair.file, pair.size), (file!(), 40));
+            alloc_assert!(pair.object_size >= 40);
+            // The object comes back from the handle's pool, with its site.
+            let p = da.alloc_with_site(40, site);
+            da.free(p);
+            alloc_assert_eq!(da.alloc_with_site(40, site), p);
+            let stats = sites::site_stats().into_iter().find(|s| s.id == site).unwrap();
+            alloc_assert_eq!(stats.live_objects, 1);
+            da.free(p);
+            let stats = sites::site_stats().into_iter().find(|s| s.id == site).unwrap();
+            alloc_assert_eq!(stats.live_objects, 0);
+        }
+        mem::drop(da);
+        let pair = site_pools::promoted().into_iter().find(|p| p.site == site).unwrap();
+        alloc_assert!(pair.hits >= 1);
+    }
+
+    #[cfg(feature = "leak-report")]
+    #[test]
+    fn leak_report_samples() {
+        use std::time::Duration;
+        use super::super::leaks;
+        let _ = env_logger::init();
+        leaks::set_sample_period(1);
+        let site = alloc_site!();
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            let small: Vec<*mut u8> = (0..16).map(|_| da.alloc_with_site(24, site)).collect();
+            let large = da.alloc_with_site(1 << 20, site);
+            let large = da.realloc(large, 4 << 20);
+            let group = leaks::probable_leaks(Duration::from_millis(0))
+                .into_iter()
+                .find(|g| g.site == site)
+                .unwrap();
+            alloc_assert_eq!((group.file, group.objects), (file!(), 17));
+            alloc_assert_eq!(group.bytes, 16 * 24 + (4 << 20));
+            da.free(large);
+            for p in small {
+                da.free(p);
+            }
+        }
+        leaks::set_sample_period(0);
+        let leaks = leaks::probable_leaks(Duration::from_millis(0));
+        alloc_assert!(leaks.iter().all(|g| g.site != site));
+    }
+
+    #[test]
+    fn trim_levels() {
+        let _ = env_logger::init();
+        let mut released = [0; 3];
+        for level in 0..3 {
+            // Each level gets a heap of its own, so that the amounts released can be compared.
+            let mut da = DynamicAllocator::new();
+            unsafe {
+                let ptrs: Vec<*mut u8> = (0..(1 << 16)).map(|_| da.alloc(64)).collect();
+                // free every other object so that some slags are left partially full
+                for p in ptrs.iter().step_by(2) {
+                    da.free(*p);
+                }
+                released[level] += da.trim(level);
+                for p in ptrs.iter().skip(1).step_by(2) {
+                    write_bytes(*p, 0xFF, 64);
+                    da.free(*p);
+                }
+                released[level] += da.trim(level);
+                let item = da.alloc(64);
+                write_bytes(item, 0xFF, 64);
+                da.free(item);
+            }
+        }
+        // With every object freed, flushing the caches leaves whole `Slag`s free for level 2 to
+        // uncommit; level 0 only uncommits the pages that were already free.
+        alloc_assert!(released[2] > 0, "{:?}", released);
+        alloc_assert!(released[0] <= released[1], "{:?}", released);
+        alloc_assert!(released[1] <= released[2], "{:?}", released);
+    }
+
+    // With `local_cache`, only objects of the current `Slag` are cached, and that `Slag` is never
+    // released.
+    #[cfg(not(feature = "local_cache"))]
+    #[test]
+    fn trim_level_1_flushes_caches() {
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            // Enough objects to fill a few `Slag`s, and few enough that the thread cache holds all
+            // of them once they are freed, under every profile.
+            let ptrs: Vec<*mut u8> = (0..(1 << 13)).map(|_| da.alloc(64)).collect();
+            for p in &ptrs {
+                write_bytes(*p, 0xFF, 64);
+                da.free(*p);
+            }
+            let level0 = da.trim(0);
+            let level1 = da.trim(1);
+            alloc_assert!(level1 > level0, "level 0: {}, level 1: {}", level0, level1);
+        }
+    }
+
+    #[test]
+    fn prefault_pages() {
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            alloc_assert!(da.prefault(1 << 20) >= 2 << 20);
+            let item = da.alloc(64);
+            write_bytes(item, 0xFF, 64);
+            da.free(item);
+            alloc_assert!(global::prefault(0) == 0);
+            alloc_assert!(global::prefault(1) > 0);
+        }
+    }
+
+    #[test]
+    fn park_and_unpark() {
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            for _ in 0..2 {
+                let ptrs: Vec<*mut u8> = (0..(1 << 14)).map(|_| da.alloc(64)).collect();
+                for p in ptrs {
+                    da.free(p);
+                }
+                da.park();
+                da.unpark();
+                let item = da.alloc(64);
+                write_bytes(item, 0xFF, 64);
+                da.free(item);
+            }
+            let item = global::alloc(64);
+            global::free(item);
+            global::on_park();
+            global::on_unpark();
+            let item = global::alloc(64);
+            write_bytes(item, 0xFF, 64);
+            global::free(item);
+        }
+    }
+
+    #[cfg(feature = "batch-unmap")]
+    #[test]
+    fn batched_unmaps() {
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            for &defer in &[false, true] {
+                super::conf::set_defer_unmap(defer);
+                // Enough frees to fill a batch more than once, with some left over.
+                let ptrs: Vec<*mut u8> = (0..40).map(|_| da.alloc(4 << 20)).collect();
+                for &p in &ptrs {
+                    write_bytes(p, 0xFF, 4 << 20);
+                }
+                for p in ptrs {
+                    da.free(p);
+                }
+                da.trim(0);
+                let item = da.alloc(4 << 20);
+                write_bytes(item, 0xFF, 4 << 20);
+                da.free(item);
+            }
+            super::conf::set_defer_unmap(false);
+        }
+    }
+
+    #[cfg(feature = "cache-decay")]
+    #[test]
+    fn idle_caches_decay() {
+        use std::{thread, time};
+        use super::super::decay::CHECK_OPS;
+        fn cached(da: &mut DynamicAllocator, size: usize) -> usize {
+            let mut n = 0;
+            da.0.cached_objects(size, |_| n += 1);
+            n
+        }
+        // Allocate and free enough objects of another size class to make the handle check its
+        // caches.
+        unsafe fn check(da: &mut DynamicAllocator) {
+            for _ in 0..CHECK_OPS {
+                let item = da.alloc(1024);
+                da.free(item);
+            }
+        }
+
+        super::conf::set_cache_decay_ms(50);
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            let ptrs: Vec<*mut u8> = (0..1000).map(|_| da.alloc(64)).collect();
+            for p in ptrs {
+                da.free(p);
+            }
+            alloc_assert!(cached(&mut da, 64) >= 1000);
+            // The first check only notes that the 64-byte cache was used.
+            check(&mut da);
+            alloc_assert!(cached(&mut da, 64) >= 1000);
+            thread::sleep(time::Duration::from_millis(100));
+            check(&mut da);
+        }
+        super::conf::set_cache_decay_ms(10_000);
+        alloc_assert_eq!(cached(&mut da, 64), 0);
+        alloc_assert!(cached(&mut da, 1024) > 0);
+    }
+
+    #[cfg(feature = "zero-on-free")]
+    #[test]
+    fn freed_objects_are_zeroed() {
+        use super::super::alloc::allocator::{Alloc, Layout};
+        use super::super::alloc_impl::ElfMallocGlobal;
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            for &size in &[64, 4096, 1 << 20, 4 << 20] {
+                let item = da.alloc(size);
+                write_bytes(item, 0xFF, size);
+                da.free(item);
+                // Small objects are reused right away, and large ones come from the large object
+                // cache or a fresh mapping.
+                let item = da.alloc(size);
+                for i in 0..size {
+                    alloc_assert_eq!(*item.offset(i as isize), 0, "size {} byte {}", size, i);
+                }
+                da.free(item);
+            }
+
+            let layout = Layout::from_size_align(256, 8).unwrap();
+            let item = global::alloc(256);
+            write_bytes(item, 0xFF, 256);
+            global::free(item);
+            let item = (&ElfMallocGlobal).alloc_zeroed(layout.clone()).unwrap();
+            for i in 0..256 {
+                alloc_assert_eq!(*item.offset(i), 0);
+            }
+            (&ElfMallocGlobal).dealloc(item, layout);
+        }
+    }
+
     #[test]
     fn all_sizes_one_thread() {
         let _ = env_logger::init();
@@ -0,0 +1,218 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! What to do when the allocator detects that the heap has been misused.
+//!
+//! Some features check for heap misuse: the quarantine detects objects written to after they were
+//! freed (`quarantine` feature), and ownership checks detect `free`s of pointers that elfmalloc
+//! never handed out (`ownership` feature). In every build, the bit-set and reference count of a
+//! `Slag` are checked against each other as objects are freed to it and as it is refilled from,
+//! which catches most objects freed twice. By default, such a violation aborts the process, which
+//! is what hardened deployments want: the heap can no longer be trusted. Before aborting, a
+//! report of the memory around the offending pointer is printed to stderr (see `triage`).
+//!
+//! Long-running services sometimes prefer to keep going in a degraded state instead. With
+//! `ViolationPolicy::Continue`, the allocator isolates the affected memory and carries on:
+//!
+//! - An object written to after it was freed is leaked, so that its slot in the `Slag` is never
+//!   handed out again.
+//! - A foreign pointer passed to `free` is ignored.
+//! - A corrupt `Slag` is poisoned: it is never allocated from or made available again, and its
+//!   memory is leaked. Objects in it that are still in use stay valid.
+//!
+//! Either way, each violation is counted (see `violation_stats`) and passed to the hook installed
+//! with `set_violation_hook`, if any, before the policy is applied, so that it can be logged or
+//! exported as a metric. The hook must not allocate.
+//!
+//! The policy can be set at startup with the `on_violation` option of `ELFMALLOC_CONF` (`abort`
+//! or `continue`; see the `conf` module), and changed at any time with `set_violation_policy`.
+//! Violations that leave the allocator unable to return a meaningful result, such as calling
+//! `realloc` on a foreign pointer, always abort.
+
+use std::fmt;
+use std::mem;
+#[cfg(test)]
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use super::triage;
+
+/// A detected misuse of the heap.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// The object at `object` was modified at byte `offset` while it was in the quarantine.
+    WriteAfterFree { object: *mut u8, offset: usize },
+    /// `free` was called with `ptr`, which does not belong to elfmalloc.
+    ForeignFree { ptr: *mut u8 },
+    /// The bit-set and reference count of the `Slag` at `slag` disagree, as found when freeing
+    /// `object` to it, or when refilling from it if `object` is null.
+    CorruptSlag { slag: *mut u8, object: *mut u8 },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::WriteAfterFree { object, offset } => write!(
+                f,
+                "object {:?} was modified at offset {} after it was freed",
+                object,
+                offset
+            ),
+            Violation::ForeignFree { ptr } => write!(
+                f,
+                "free of pointer {:?}, which was not allocated by elfmalloc",
+                ptr
+            ),
+            Violation::CorruptSlag { slag, object } if object.is_null() => write!(
+                f,
+                "Slag {:?} is corrupt: its reference count exceeds its free objects",
+                slag
+            ),
+            Violation::CorruptSlag { slag, object } => write!(
+                f,
+                "Slag {:?} is corrupt: object {:?} was freed while it was already free",
+                slag,
+                object
+            ),
+        }
+    }
+}
+
+/// What the allocator does after reporting a `Violation`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ViolationPolicy {
+    /// Abort the process.
+    Abort,
+    /// Isolate the affected memory and continue. See the module documentation.
+    Continue,
+}
+
+/// 0 for `Abort`, 1 for `Continue`.
+static POLICY: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The violation hook, as a `fn(&Violation)`, or 0 if there is none.
+static HOOK: AtomicUsize = ATOMIC_USIZE_INIT;
+
+static WRITES_AFTER_FREE: AtomicUsize = ATOMIC_USIZE_INIT;
+static FOREIGN_FREES: AtomicUsize = ATOMIC_USIZE_INIT;
+static CORRUPT_SLAGS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[cfg(test)]
+lazy_static! {
+    static ref POLICY_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Keep other tests from changing the policy, or relying on it being the default, while the guard
+/// is held. A test that changes the policy must restore the default before dropping the guard.
+#[cfg(test)]
+pub fn lock_policy() -> MutexGuard<'static, ()> {
+    POLICY_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Set the policy for subsequent violations.
+pub fn set_violation_policy(policy: ViolationPolicy) {
+    POLICY.store(
+        match policy {
+            ViolationPolicy::Abort => 0,
+            ViolationPolicy::Continue => 1,
+        },
+        Ordering::Relaxed,
+    );
+}
+
+/// The current policy.
+pub fn violation_policy() -> ViolationPolicy {
+    match POLICY.load(Ordering::Relaxed) {
+        0 => ViolationPolicy::Abort,
+        _ => ViolationPolicy::Continue,
+    }
+}
+
+/// Install a function to be called with every violation, or remove it.
+pub fn set_violation_hook(hook: Option<fn(&Violation)>) {
+    HOOK.store(
+        match hook {
+            Some(f) => f as usize,
+            None => 0,
+        },
+        Ordering::Release,
+    );
+}
+
+/// The number of violations of each kind detected so far.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ViolationStats {
+    pub writes_after_free: usize,
+    pub foreign_frees: usize,
+    pub corrupt_slags: usize,
+}
+
+pub fn violation_stats() -> ViolationStats {
+    ViolationStats {
+        writes_after_free: WRITES_AFTER_FREE.load(Ordering::Relaxed),
+        foreign_frees: FOREIGN_FREES.load(Ordering::Relaxed),
+        corrupt_slags: CORRUPT_SLAGS.load(Ordering::Relaxed),
+    }
+}
+
+/// Report `violation`, and abort unless the policy is `Continue`. If this returns, the caller
+/// must isolate the affected memory as described in the module documentation.
+///
+/// Before aborting, a report of the memory around the offending pointer is printed to stderr
+/// (see the `triage` module).
+#[cold]
+pub fn report(violation: Violation) {
+    notify(&violation);
+    if violation_policy() == ViolationPolicy::Abort {
+        triage::print(&violation);
+    }
+    alloc_assert!(
+        violation_policy() == ViolationPolicy::Continue,
+        "{}",
+        violation
+    );
+}
+
+/// Count `violation` and pass it to the hook.
+fn notify(violation: &Violation) {
+    let ctr = match *violation {
+        Violation::WriteAfterFree { .. } => &WRITES_AFTER_FREE,
+        Violation::ForeignFree { .. } => &FOREIGN_FREES,
+        Violation::CorruptSlag { .. } => &CORRUPT_SLAGS,
+    };
+    ctr.fetch_add(1, Ordering::Relaxed);
+    let hook = HOOK.load(Ordering::Acquire);
+    if hook != 0 {
+        let hook: fn(&Violation) = unsafe { mem::transmute(hook) };
+        hook(violation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SEEN: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    fn count(v: &Violation) {
+        if let Violation::ForeignFree { ptr } = *v {
+            SEEN.store(ptr as usize, Ordering::Relaxed);
+        }
+    }
+
+    // Reporting a violation under the default policy aborts, so this only tests what happens
+    // before the policy is applied.
+    #[test]
+    fn notify_calls_hook() {
+        let _policy = lock_policy();
+        alloc_assert_eq!(violation_policy(), ViolationPolicy::Abort);
+        set_violation_hook(Some(count));
+        let before = violation_stats().foreign_frees;
+        notify(&Violation::ForeignFree { ptr: 8 as *mut u8 });
+        set_violation_hook(None);
+        alloc_assert_eq!(SEEN.load(Ordering::Relaxed), 8);
+        alloc_assert!(violation_stats().foreign_frees > before);
+    }
+}
@@ -0,0 +1,312 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A cache of the mappings of recently freed large objects.
+//!
+//! Large objects get a mapping of their own, which is unmapped when they are freed. Workloads that
+//! repeatedly allocate and free buffers of a few MiB therefore spend most of their time in `mmap`
+//! and `munmap` (and in page faults on the fresh mappings). With the `large-cache` feature, freed
+//! mappings are kept in this cache instead, and handed out again to large allocations of the same
+//! size class.
+//!
+//! Mapping sizes are rounded up to one of eight size classes per power of two, so that objects of
+//! similar sizes can share mappings; this wastes at most 12.5% of virtual memory, and no physical
+//! memory beyond what the object touches. Each size class holds up to `SLOTS` mappings, and the
+//! cache as a whole holds at most `set_large_cache_limit` bytes (64MiB by default). Mappings decay
+//! out of the cache: one that has not been reused within roughly `DECAY_PERIOD` frees of other
+//! large objects is unmapped. `trim` empties the cache. `large_cache_stats` reports the hit rate,
+//! which is what the size of the cache should be tuned against.
+//!
+//! Cached mappings are not considered to be owned by elfmalloc, and the contents of a reused
+//! mapping are not cleared.
+
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use super::utils::{mmap, CachePadded};
+
+/// The number of cached mappings per size class.
+pub const SLOTS: usize = 4;
+/// The number of frees of large objects after which an unused mapping is unmapped.
+pub const DECAY_PERIOD: usize = 256;
+/// Size classes are only used for mappings larger than `1 << MIN_SHIFT` bytes; every large object
+/// mapping is, since it includes a 2MiB header page.
+const MIN_SHIFT: usize = 21;
+/// The number of powers of two covered by the size classes. Larger mappings are not cached.
+const OCTAVES: usize = 6;
+const CLASSES_PER_OCTAVE: usize = 8;
+const BUCKETS: usize = OCTAVES * CLASSES_PER_OCTAVE;
+const DEFAULT_LIMIT: usize = 64 << 20;
+
+struct Bucket {
+    /// The addresses of the cached mappings, or 0 for empty slots.
+    slots: [AtomicUsize; SLOTS],
+    /// The value of `CLOCK` when each slot was filled.
+    stamps: [AtomicUsize; SLOTS],
+}
+
+type Table = [Bucket; BUCKETS];
+
+// `TABLE` and `LIMIT` are read-mostly; the other globals are written by every call to `take` or
+// `put` from any thread, so each of them has a cache line of its own.
+
+/// The address of the table, or 0 if it has not been mapped yet.
+static TABLE: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The number of calls to `put`, which is the unit of time for decay.
+static CLOCK: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+/// The number of bytes in cached mappings.
+static BYTES: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+/// The maximum value of `BYTES`, plus 1 so that the default can be 0.
+static LIMIT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+static HITS: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+static MISSES: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+static CACHED: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+static REJECTED: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+static DECAYED: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+static TRIMMED: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+
+fn table() -> &'static Table {
+    let mut addr = TABLE.load(Ordering::Acquire);
+    if addr == 0 {
+        let bytes = mem::size_of::<Table>();
+        let fresh = mmap::map(bytes) as usize;
+        addr = match TABLE.compare_exchange(0, fresh, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => fresh,
+            Err(winner) => {
+                unsafe { mmap::unmap(fresh as *mut u8, bytes) };
+                winner
+            }
+        };
+    }
+    unsafe { &*(addr as *const Table) }
+}
+
+fn limit() -> usize {
+    match LIMIT.load(Ordering::Relaxed) {
+        0 => DEFAULT_LIMIT,
+        n => n - 1,
+    }
+}
+
+/// `floor(log2(n))`, for `n > 0`.
+fn log2(n: usize) -> usize {
+    mem::size_of::<usize>() * 8 - 1 - n.leading_zeros() as usize
+}
+
+/// Round the size of a large object mapping up to its size class.
+pub fn round(bytes: usize) -> usize {
+    if bytes <= 1 << MIN_SHIFT {
+        return bytes;
+    }
+    let step = 1 << (log2(bytes - 1) - 3);
+    (bytes + step - 1) & !(step - 1)
+}
+
+/// The bucket for mappings of `bytes` bytes, if they can be cached.
+fn bucket(bytes: usize) -> Option<&'static Bucket> {
+    if bytes <= 1 << MIN_SHIFT || round(bytes) != bytes {
+        return None;
+    }
+    let shift = log2(bytes - 1);
+    let ix = (shift - MIN_SHIFT) * CLASSES_PER_OCTAVE + (bytes >> (shift - 3)) - 9;
+    if ix < BUCKETS {
+        Some(&table()[ix])
+    } else {
+        None
+    }
+}
+
+/// Take a cached mapping of `bytes` bytes, which must have been rounded with `round`.
+pub fn take(bytes: usize) -> Option<*mut u8> {
+    let bucket = match bucket(bytes) {
+        Some(bucket) => bucket,
+        None => return None,
+    };
+    for slot in &bucket.slots {
+        if slot.load(Ordering::Relaxed) == 0 {
+            continue;
+        }
+        let base = slot.swap(0, Ordering::Acquire);
+        if base != 0 {
+            BYTES.fetch_sub(bytes, Ordering::Relaxed);
+            HITS.fetch_add(1, Ordering::Relaxed);
+            return Some(base as *mut u8);
+        }
+    }
+    MISSES.fetch_add(1, Ordering::Relaxed);
+    None
+}
+
+/// Offer the mapping of `bytes` bytes at `base` to the cache. If this returns `false`, the
+/// mapping was not cached and the caller has to unmap it.
+pub unsafe fn put(base: *mut u8, bytes: usize) -> bool {
+    let now = CLOCK.fetch_add(1, Ordering::Relaxed);
+    decay(&table()[now % BUCKETS], now);
+    let bucket = match bucket(bytes) {
+        Some(bucket) => bucket,
+        None => return false,
+    };
+    if BYTES.fetch_add(bytes, Ordering::Relaxed) + bytes > limit() {
+        BYTES.fetch_sub(bytes, Ordering::Relaxed);
+        REJECTED.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    for (slot, stamp) in bucket.slots.iter().zip(bucket.stamps.iter()) {
+        if slot.load(Ordering::Relaxed) == 0 {
+            stamp.store(now, Ordering::Relaxed);
+            if slot.compare_exchange(0, base as usize, Ordering::Release, Ordering::Relaxed)
+                .is_ok()
+            {
+                CACHED.fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+        }
+    }
+    BYTES.fetch_sub(bytes, Ordering::Relaxed);
+    REJECTED.fetch_add(1, Ordering::Relaxed);
+    false
+}
+
+/// The size of the mappings in `bucket`.
+fn bucket_bytes(bucket: &Bucket) -> usize {
+    let ix = (bucket as *const Bucket as usize - table().as_ptr() as usize) /
+        mem::size_of::<Bucket>();
+    let shift = MIN_SHIFT + ix / CLASSES_PER_OCTAVE;
+    (ix % CLASSES_PER_OCTAVE + 9) << (shift - 3)
+}
+
+/// Unmap the mapping in `slot`, if it still holds `base`, and return its size.
+unsafe fn evict(bucket: &Bucket, slot: &AtomicUsize, base: usize) -> usize {
+    if base == 0 || slot.compare_exchange(base, 0, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        return 0;
+    }
+    let bytes = bucket_bytes(bucket);
+    BYTES.fetch_sub(bytes, Ordering::Relaxed);
+    mmap::unmap(base as *mut u8, bytes);
+    bytes
+}
+
+/// Unmap the mappings in `bucket` that were cached more than `DECAY_PERIOD` puts before `now`.
+unsafe fn decay(bucket: &Bucket, now: usize) {
+    for (slot, stamp) in bucket.slots.iter().zip(bucket.stamps.iter()) {
+        let base = slot.load(Ordering::Relaxed);
+        if base != 0 && now.wrapping_sub(stamp.load(Ordering::Relaxed)) > DECAY_PERIOD &&
+            evict(bucket, slot, base) != 0
+        {
+            DECAYED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Unmap every cached mapping, returning the number of bytes unmapped.
+pub unsafe fn flush() -> usize {
+    if TABLE.load(Ordering::Acquire) == 0 {
+        return 0;
+    }
+    let mut released = 0;
+    for bucket in table().iter() {
+        for slot in &bucket.slots {
+            let bytes = evict(bucket, slot, slot.load(Ordering::Relaxed));
+            if bytes != 0 {
+                TRIMMED.fetch_add(1, Ordering::Relaxed);
+                released += bytes;
+            }
+        }
+    }
+    released
+}
+
+/// Set the maximum number of bytes of mappings that the large object cache holds. 0 disables the
+/// cache. Mappings that are already cached are not unmapped until they decay or `trim` is called.
+pub fn set_large_cache_limit(bytes: usize) {
+    LIMIT.store(bytes.saturating_add(1), Ordering::Relaxed);
+}
+
+/// Counters for the large object cache. See the module documentation.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LargeCacheStats {
+    /// Large allocations that reused a cached mapping.
+    pub hits: usize,
+    /// Large allocations that could have used a cached mapping, but found none.
+    pub misses: usize,
+    /// Mappings that were cached when their object was freed.
+    pub cached: usize,
+    /// Mappings that were unmapped when their object was freed, because the cache was full.
+    pub rejected: usize,
+    /// Cached mappings that were unmapped because they were not reused in time.
+    pub decayed: usize,
+    /// Cached mappings that were unmapped by `trim`.
+    pub trimmed: usize,
+    /// The number of bytes currently cached.
+    pub bytes: usize,
+}
+
+impl LargeCacheStats {
+    /// The proportion of large allocations that reused a cached mapping, if there were any.
+    pub fn hit_rate(&self) -> Option<f64> {
+        match self.hits + self.misses {
+            0 => None,
+            total => Some(self.hits as f64 / total as f64),
+        }
+    }
+}
+
+/// Get the large object cache's counters.
+///
+/// Counters are read one at a time, so the result is not an atomic snapshot.
+pub fn large_cache_stats() -> LargeCacheStats {
+    let load = |ctr: &AtomicUsize| ctr.load(Ordering::Relaxed);
+    LargeCacheStats {
+        hits: load(&HITS),
+        misses: load(&MISSES),
+        cached: load(&CACHED),
+        rejected: load(&REJECTED),
+        decayed: load(&DECAYED),
+        trimmed: load(&TRIMMED),
+        bytes: load(&BYTES),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::general::global;
+    use std::ptr::write_bytes;
+
+    #[test]
+    fn size_classes() {
+        let mut prev = 1 << MIN_SHIFT;
+        for ix in 0..BUCKETS {
+            let bytes = bucket_bytes(&table()[ix]);
+            alloc_assert!(bytes > prev);
+            alloc_assert_eq!(round(bytes), bytes);
+            alloc_assert_eq!(round(prev + 1), bytes);
+            alloc_assert_eq!(
+                bucket(bytes).map(|b| b as *const Bucket),
+                Some(&table()[ix] as *const Bucket)
+            );
+            prev = bytes;
+        }
+        alloc_assert!(bucket(round(prev + 1)).is_none());
+    }
+
+    #[test]
+    fn reuse_large_mappings() {
+        let before = large_cache_stats();
+        unsafe {
+            for _ in 0..16 {
+                let p = global::alloc(1 << 20);
+                write_bytes(p, 0xFF, 1 << 20);
+                global::free(p);
+            }
+        }
+        let after = large_cache_stats();
+        alloc_assert!(after.cached > before.cached);
+        // Other tests may take the cached mappings, but not every time.
+        alloc_assert!(after.hits > before.hits);
+    }
+}
@@ -0,0 +1,408 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Age-based leak heuristics for allocations with a site (`leak-report` feature).
+//!
+//! A slow leak shows up in `site_stats` as a site whose live bytes keep growing, but a site that
+//! legitimately holds a lot of memory looks much the same. What tells them apart is age: objects
+//! that were allocated long ago and are still live are the likely leaks. With this feature, each
+//! handle samples one in every `sample_period` allocations made with an explicit site (see the
+//! `sites` module), and records the object's address, site, size and time of allocation in a
+//! global table. Freeing a sampled object removes it from the table.
+//!
+//! `probable_leaks` groups the sampled objects that have been live for at least a given time by
+//! site, and `write_report` prints them, largest first. Since only a sample of the objects is
+//! recorded, the counts are of sampled objects; multiplied by the sample period, they estimate
+//! the totals.
+//!
+//! Sampling is off until `set_sample_period` is called. Timestamps come from the coarse monotonic
+//! clock (`CLOCK_MONOTONIC_COARSE` on Linux), which the kernel keeps cached so that reading it
+//! takes no system call, with a resolution of a few milliseconds. The table has room for
+//! `MAX_SAMPLES` objects; once a part of it is full, further samples that land there are counted
+//! by `dropped_samples` and otherwise ignored.
+
+extern crate libc;
+
+use std::cmp;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::Duration;
+use super::sites::{self, SiteId, NO_SITE};
+use super::utils::TypedArray;
+
+/// The number of sampled objects the table can hold.
+pub const MAX_SAMPLES: usize = 1 << 16;
+
+/// How far from its hash slot an object may be stored.
+const MAX_PROBE: usize = 64;
+
+/// How often a `Sampler` checks whether sampling has been enabled while it is disabled.
+const RECHECK_PERIOD: usize = 4096;
+
+const EMPTY: usize = 0;
+const TOMBSTONE: usize = 1;
+
+/// Sample one in this many allocations with a site; 0 disables sampling.
+static SAMPLE_PERIOD: AtomicUsize = ATOMIC_USIZE_INIT;
+
+static DROPPED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The number of allocations with a site per sample on each handle, or 0 if sampling is off.
+pub fn sample_period() -> usize {
+    SAMPLE_PERIOD.load(Ordering::Relaxed)
+}
+
+/// Sample one in every `period` allocations with a site; 0 stops sampling. Objects that were
+/// already sampled stay in the table until they are freed. Handles pick up a new period after
+/// their next sample, or within `RECHECK_PERIOD` allocations if sampling was off.
+pub fn set_sample_period(period: usize) {
+    SAMPLE_PERIOD.store(period, Ordering::Relaxed);
+}
+
+/// The number of samples that were dropped because the table was full.
+pub fn dropped_samples() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Decides which of a handle's allocations with a site are sampled.
+pub struct Sampler {
+    /// The number of allocations until the next sample.
+    countdown: usize,
+}
+
+impl Sampler {
+    pub fn new() -> Sampler {
+        Sampler { countdown: 1 }
+    }
+
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    pub fn sample(&mut self) -> bool {
+        self.countdown -= 1;
+        if self.countdown != 0 {
+            return false;
+        }
+        self.next_period()
+    }
+
+    #[cold]
+    fn next_period(&mut self) -> bool {
+        match SAMPLE_PERIOD.load(Ordering::Relaxed) {
+            0 => {
+                self.countdown = RECHECK_PERIOD;
+                false
+            }
+            period => {
+                self.countdown = period;
+                true
+            }
+        }
+    }
+}
+
+/// Milliseconds on the coarse monotonic clock.
+pub fn coarse_now_ms() -> u64 {
+    #[cfg(target_os = "linux")]
+    const CLOCK: libc::clockid_t = libc::CLOCK_MONOTONIC_COARSE;
+    #[cfg(not(target_os = "linux"))]
+    const CLOCK: libc::clockid_t = libc::CLOCK_MONOTONIC;
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(CLOCK, &mut ts);
+    }
+    ts.tv_sec as u64 * 1000 + ts.tv_nsec as u64 / 1_000_000
+}
+
+/// A sampled object. `addr` is claimed with a compare-and-swap, after which the other fields are
+/// written; `born` is written last and cleared first, so an entry whose `born` is not zero is
+/// complete (unless it is being replaced concurrently, which a report can live with).
+struct Slot {
+    addr: AtomicUsize,
+    site: AtomicUsize,
+    size: AtomicUsize,
+    /// The time of allocation in milliseconds, plus one.
+    born: AtomicUsize,
+}
+
+/// An open-addressing table of sampled objects, keyed by address. Inserting claims an empty or
+/// deleted slot within `MAX_PROBE` slots of the address's hash, and removing replaces the address
+/// with a tombstone, so both are lock-free and bounded.
+struct Table {
+    slots: TypedArray<Slot>,
+    /// The number of objects in the table, so that frees need not search it when it is empty.
+    live: AtomicUsize,
+}
+
+impl Table {
+    /// A table of `n` slots, which must be a power of two.
+    fn new(n: usize) -> Table {
+        alloc_debug_assert!(n.is_power_of_two());
+        Table {
+            slots: TypedArray::new(n),
+            live: AtomicUsize::new(0),
+        }
+    }
+
+    fn slot(&self, start: usize, i: usize) -> &Slot {
+        unsafe { &*self.slots.get((start + i) & (self.slots.len() - 1)) }
+    }
+
+    fn start(&self, addr: usize) -> usize {
+        let h = (addr >> 4).wrapping_mul(0x9e37_79b9_7f4a_7c15u64 as usize);
+        h >> (::std::mem::size_of::<usize>() * 8 - self.slots.len().trailing_zeros() as usize)
+    }
+
+    /// Record the object at `addr`. Returns false if there was no room for it.
+    fn insert(&self, addr: usize, site: SiteId, size: usize, now_ms: u64) -> bool {
+        let start = self.start(addr);
+        for i in 0..MAX_PROBE {
+            let slot = self.slot(start, i);
+            let cur = slot.addr.load(Ordering::Relaxed);
+            if (cur == EMPTY || cur == TOMBSTONE) &&
+                slot.addr
+                    .compare_exchange(cur, addr, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+            {
+                slot.site.store(site as usize, Ordering::Relaxed);
+                slot.size.store(size, Ordering::Relaxed);
+                slot.born.store(now_ms as usize + 1, Ordering::Release);
+                self.live.fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Remove the object at `addr`, if it is in the table, returning its site, size and time of
+    /// allocation.
+    fn remove(&self, addr: usize) -> Option<(SiteId, usize, u64)> {
+        if self.live.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        let start = self.start(addr);
+        for i in 0..MAX_PROBE {
+            let slot = self.slot(start, i);
+            if slot.addr.load(Ordering::Acquire) == addr {
+                let entry = (
+                    slot.site.load(Ordering::Relaxed) as SiteId,
+                    slot.size.load(Ordering::Relaxed),
+                    slot.born.swap(0, Ordering::Relaxed).saturating_sub(1) as u64,
+                );
+                slot.addr.store(TOMBSTONE, Ordering::Release);
+                self.live.fetch_sub(1, Ordering::Relaxed);
+                return Some(entry);
+            }
+        }
+        None
+    }
+
+    /// Group the objects that were allocated at least `min_age_ms` before `now_ms` by site.
+    fn groups(&self, now_ms: u64, min_age_ms: u64) -> Vec<LeakGroup> {
+        let mut groups: Vec<LeakGroup> = Vec::new();
+        for i in 0..self.slots.len() {
+            let slot = self.slot(0, i);
+            let born = slot.born.load(Ordering::Acquire);
+            if born == 0 || slot.addr.load(Ordering::Relaxed) <= TOMBSTONE {
+                continue;
+            }
+            let age = now_ms.saturating_sub(born as u64 - 1);
+            if age < min_age_ms {
+                continue;
+            }
+            let site = slot.site.load(Ordering::Relaxed) as SiteId;
+            let size = slot.size.load(Ordering::Relaxed);
+            if let Some(g) = groups.iter_mut().find(|g| g.site == site) {
+                g.objects += 1;
+                g.bytes += size;
+                g.oldest_ms = cmp::max(g.oldest_ms, age);
+                continue;
+            }
+            let (file, line, column) = sites::location(site);
+            groups.push(LeakGroup {
+                site: site,
+                file: file,
+                line: line,
+                column: column,
+                objects: 1,
+                bytes: size,
+                oldest_ms: age,
+            });
+        }
+        groups.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+        groups
+    }
+}
+
+lazy_static! {
+    static ref SAMPLES: Table = Table::new(MAX_SAMPLES);
+}
+
+/// Record that `item`, an object of `size` bytes, was just allocated at `site`. Called for
+/// sampled allocations.
+#[cold]
+pub fn record(item: *mut u8, site: SiteId, size: usize) {
+    if site != NO_SITE && !SAMPLES.insert(item as usize, site, size, coarse_now_ms()) {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Record that `item`, an object allocated with a site, is being freed.
+#[inline]
+pub fn forget(item: *mut u8) {
+    SAMPLES.remove(item as usize);
+}
+
+/// Record that `old` was moved to `new` by `realloc`, keeping its time of allocation.
+pub fn moved(old: *mut u8, new: *mut u8, size: usize) {
+    if old == new {
+        return;
+    }
+    if let Some((site, _, born)) = SAMPLES.remove(old as usize) {
+        if !SAMPLES.insert(new as usize, site, size, born) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The sampled objects of one site that have been live for at least the requested time.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LeakGroup {
+    pub site: SiteId,
+    /// The location of the site, as in `SiteStats`.
+    pub file: &'static str,
+    pub line: u32,
+    pub column: u32,
+    /// The number of sampled objects from the site that are old enough.
+    pub objects: usize,
+    /// Their total requested size.
+    pub bytes: usize,
+    /// The age of the oldest of them, in milliseconds.
+    pub oldest_ms: u64,
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_nanos()) / 1_000_000
+}
+
+/// Group the sampled objects that have been live for at least `min_age` by site, largest first.
+///
+/// The table is read one slot at a time, so objects sampled or freed concurrently may or may not
+/// be counted.
+pub fn probable_leaks(min_age: Duration) -> Vec<LeakGroup> {
+    SAMPLES.groups(coarse_now_ms(), millis(min_age))
+}
+
+/// Write `probable_leaks(min_age)` to `w`, one line per site, with each site's current totals
+/// from `site_stats` for comparison.
+pub fn write_report<W: Write>(w: &mut W, min_age: Duration) -> io::Result<()> {
+    write_groups(w, min_age, probable_leaks(min_age), &sites::site_stats())
+}
+
+fn write_groups<W: Write>(
+    w: &mut W,
+    min_age: Duration,
+    groups: Vec<LeakGroup>,
+    stats: &[sites::SiteStats],
+) -> io::Result<()> {
+    writeln!(
+        w,
+        "probable leaks: sampled objects live for at least {}ms (1 in {} sampled, {} dropped)",
+        millis(min_age),
+        sample_period(),
+        dropped_samples()
+    )?;
+    for g in &groups {
+        let live = match stats.iter().find(|s| s.id == g.site) {
+            Some(s) => format!(" ({} bytes in {} objects live)", s.live_bytes, s.live_objects),
+            None => String::new(),
+        };
+        writeln!(
+            w,
+            "{} bytes in {} objects, oldest {}ms, at {}:{}:{}{}",
+            g.bytes,
+            g.objects,
+            g.oldest_ms,
+            g.file,
+            g.line,
+            g.column,
+            live
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_groups_by_site_and_age() {
+        let table = Table::new(1 << 10);
+        // Site 1 has two old objects and a young one, site 2 one old object.
+        alloc_assert!(table.insert(0x1000, 1, 64, 100));
+        alloc_assert!(table.insert(0x2000, 1, 32, 400));
+        alloc_assert!(table.insert(0x3000, 2, 512, 200));
+        alloc_assert!(table.insert(0x4000, 1, 16, 950));
+        alloc_assert_eq!(table.remove(0x5000), None);
+        let groups = table.groups(1000, 500);
+        alloc_assert_eq!(
+            groups.iter().map(|g| (g.site, g.objects, g.bytes, g.oldest_ms)).collect::<Vec<_>>(),
+            vec![(2, 1, 512, 800), (1, 2, 96, 900)]
+        );
+        alloc_assert_eq!(table.remove(0x3000), Some((2, 512, 200)));
+        alloc_assert_eq!(table.groups(1000, 500).len(), 1);
+        alloc_assert_eq!(table.groups(1000, 0)[0].objects, 3);
+
+        // Objects that hash to the same slot are stored nearby, up to `MAX_PROBE` of them.
+        let colliding: Vec<usize> = (1..)
+            .map(|i| i << 4)
+            .filter(|&a| table.start(a) == table.start(0x10))
+            .take(MAX_PROBE + 1)
+            .collect();
+        let inserted = colliding.iter().filter(|&&a| table.insert(a, 3, 8, 0)).count();
+        alloc_assert!(inserted <= MAX_PROBE && inserted >= MAX_PROBE - 3);
+        for &a in &colliding[..inserted] {
+            alloc_assert_eq!(table.remove(a), Some((3, 8, 0)));
+        }
+        unsafe { table.slots.destroy() };
+    }
+
+    #[test]
+    fn report_format() {
+        let group = LeakGroup {
+            site: 7,
+            file: "src/foo.rs",
+            line: 10,
+            column: 5,
+            objects: 3,
+            bytes: 96,
+            oldest_ms: 1500,
+        };
+        let stats = sites::SiteStats {
+            id: 7,
+            file: "src/foo.rs",
+            line: 10,
+            column: 5,
+            live_bytes: 4096,
+            live_objects: 100,
+            total_allocs: 200,
+        };
+        let mut out = Vec::new();
+        write_groups(&mut out, Duration::from_secs(1), vec![group], &[stats]).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        alloc_assert_eq!(
+            out.lines().nth(1),
+            Some(
+                "96 bytes in 3 objects, oldest 1500ms, at src/foo.rs:10:5 \
+                 (4096 bytes in 100 objects live)",
+            )
+        );
+    }
+}
@@ -7,13 +7,16 @@
 
 #![feature(alloc)]
 #![feature(allocator_api)]
-#![cfg_attr(test, feature(test))]
+#![feature(attr_literals)]
+#![feature(repr_align)]
+#![cfg_attr(test, feature(test, iterator_step_by))]
 #![cfg_attr(feature = "nightly", feature(thread_local_state))]
 #![cfg_attr(feature = "nightly", feature(thread_local))]
 #![cfg_attr(feature = "nightly", feature(const_fn))]
 #![cfg_attr(feature = "nightly", feature(cfg_target_thread_local))]
 #![cfg_attr(feature = "nightly", feature(core_intrinsics))]
 #![cfg_attr(feature = "nightly", feature(const_ptr_null_mut))]
+#![cfg_attr(any(feature = "valgrind", feature = "mte"), feature(asm))]
 extern crate alloc;
 extern crate bagpipe;
 extern crate num_cpus;
@@ -22,26 +25,128 @@ extern crate num_cpus;
 extern crate alloc_fmt;
 // Linking in `bsalloc` causes it to be used as the global heap allocator. That is important when
 // using this as a basis for a `malloc` library, but it becomes a hindrance when using this crate
-// as a specialized allocator library.
-#[cfg(not(feature = "use_default_allocator"))]
+// as a specialized allocator library. Under Miri, memory mappings are emulated using the global
+// heap (see `utils::mmap`), so it has to be the default one.
+#[cfg(not(any(feature = "use_default_allocator", miri)))]
 extern crate bsalloc;
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "async")]
+extern crate futures;
 
+#[macro_use]
+mod stats;
 mod sources;
 mod alloc_type;
 mod utils;
-#[macro_use]
-mod stats;
 mod slag;
+#[cfg(feature = "nightly")]
+mod buddy;
+#[cfg(feature = "asan")]
+mod asan;
+#[cfg(feature = "valgrind")]
+mod valgrind;
+#[cfg(feature = "tsan")]
+mod tsan;
+#[cfg(feature = "msan")]
+mod msan;
+#[cfg(feature = "mte")]
+mod mte;
+#[cfg(feature = "zero-on-free")]
+mod zero;
+#[cfg(feature = "sites")]
+#[macro_use]
+pub mod sites;
+#[cfg(feature = "site-pools")]
+pub mod site_pools;
+#[cfg(feature = "leak-report")]
+pub mod leaks;
+pub mod conf;
+#[cfg(target_os = "linux")]
+pub mod dump;
+pub mod profile;
+pub mod integrity;
+mod triage;
+pub mod error;
 pub mod frontends;
 pub mod general;
+pub mod mspace;
+pub mod offset;
+#[cfg(feature = "quota")]
+pub mod quota;
+#[cfg(feature = "demand-commit")]
+pub mod demand;
+#[cfg(feature = "quarantine")]
+pub mod quarantine;
+#[cfg(feature = "ownership")]
+pub mod ownership;
+#[cfg(feature = "ownership")]
+pub mod walk;
+#[cfg(feature = "ownership")]
+pub mod dot;
+#[cfg(feature = "debug-support")]
+pub mod debug_support;
+#[cfg(feature = "large-cache")]
+mod large_cache;
+#[cfg(feature = "cache-decay")]
+mod decay;
+// Only `PageAlloc::trim` uses batches without the `batch-unmap` feature.
+#[cfg_attr(not(feature = "batch-unmap"), allow(dead_code))]
+mod unmap_batch;
+
+#[cfg(feature = "tags")]
+pub mod tags;
+#[cfg(all(feature = "thp-stats", target_os = "linux"))]
+pub mod thp;
+// Locked memory comes from `mmap_alloc`, which only supports locking on these platforms.
+#[cfg(all(any(target_os = "linux", target_os = "macos"), not(miri)))]
+pub mod locked;
 
 #[cfg(feature = "nightly")]
 pub mod alloc_impl;
 #[cfg(feature = "nightly")]
 pub mod rust_alloc;
+#[cfg(all(feature = "nightly", target_os = "linux"))]
+pub mod persistent;
 #[cfg(feature = "nightly")]
 pub mod vec_alloc;
+#[cfg(feature = "nightly")]
+pub mod btree_alloc;
+#[cfg(feature = "nightly")]
+pub mod fault;
+#[cfg(feature = "nightly")]
+pub mod counting;
+#[cfg(feature = "nightly")]
+pub mod split;
+#[cfg(feature = "nightly")]
+pub mod bump;
+#[cfg(feature = "async")]
+pub mod task_alloc;
+#[cfg(feature = "alloc-guard")]
+pub mod alloc_guard;
+
+pub use error::ElfAllocError;
+pub use general::{AllocationInfo, OwningHeap};
+pub use general::global::{lookup, on_park, on_unpark, prefault, trim};
+pub use sources::{reserve, Region};
+#[cfg(feature = "quota")]
+pub use quota::{peak_allocated, reset_peaks, set_global_limit, set_thread_limit,
+                thread_allocated, thread_peak_allocated};
+#[cfg(all(feature = "quota", target_os = "linux"))]
+pub use quota::peak_resident;
+#[cfg(feature = "contention-stats")]
+pub use stats::{contention_stats, reset_contention_stats, set_contention_sample_period,
+                ContentionStats};
+#[cfg(feature = "realloc-stats")]
+pub use stats::{realloc_stats, reset_realloc_stats, ReallocStats};
+#[cfg(feature = "large-cache")]
+pub use large_cache::{large_cache_stats, set_large_cache_limit, LargeCacheStats};
+#[cfg(feature = "slow-path-stats")]
+pub use stats::{reset_slow_path_stats, slow_path_stats, SlowPathStats};
+#[cfg(feature = "stats")]
+pub use stats::{heap_stats, reset_heap_stats, HeapStats};
+#[cfg(feature = "latency-stats")]
+pub use stats::{dump_latency_stats, latency_stats, reset_latency_stats, set_latency_sample_period,
+                LatencyHistogram, LatencyOp};
@@ -0,0 +1,211 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A small pool of memory that is locked into RAM, for secrets such as cryptographic keys.
+//!
+//! Objects allocated from elfmalloc's heap may be written to swap, may end up in a core dump, and
+//! keep their contents after they are freed until the memory is reused. `LockedPool` serves small
+//! objects from a single region that is mapped with `mmap_alloc`'s `locked` option, so it is
+//! locked with `mlock` and (on Linux) excluded from core dumps, and it overwrites every object with
+//! zeros when it is freed. Objects are returned zeroed.
+//!
+//! Locked memory is scarce (see `RLIMIT_MEMLOCK`), so the pool is small and fixed in size, and only
+//! objects of up to `MAX_OBJECT` bytes are supported. Objects are rounded up to a power of two of
+//! at least 16 bytes, are 16-byte aligned, and are kept on a free list per size; a pool never
+//! returns its memory to the operating system until it is dropped. When the region cannot be
+//! locked because the limit has been reached, the pool still works, but `is_locked` returns false,
+//! so that a program can refuse to store secrets in it.
+//!
+//! `alloc` and `free` use a process-wide pool of `POOL_SIZE` bytes, which is created on first use.
+
+extern crate mmap_alloc;
+
+use std::cmp;
+use std::ptr;
+use std::sync::Mutex;
+use self::mmap_alloc::{MapAlloc, MapAllocBuilder};
+use super::alloc::allocator::{Alloc, Layout};
+use super::utils::mmap;
+
+/// The size of the process-wide pool.
+pub const POOL_SIZE: usize = 64 << 10;
+
+/// The largest object a `LockedPool` can allocate.
+pub const MAX_OBJECT: usize = 2 << 10;
+
+const MIN_OBJECT_SHIFT: usize = 4;
+const N_CLASSES: usize = 8;
+
+/// The size class of objects of `size` bytes, which must be at most `MAX_OBJECT`.
+fn class(size: usize) -> usize {
+    let size = cmp::max(size, 1 << MIN_OBJECT_SHIFT).next_power_of_two();
+    size.trailing_zeros() as usize - MIN_OBJECT_SHIFT
+}
+
+fn class_size(class: usize) -> usize {
+    1 << (class + MIN_OBJECT_SHIFT)
+}
+
+struct State {
+    /// The offset of the part of the region that has not been handed out yet.
+    next: usize,
+    /// The first free object of each size class, or 0. Each free object starts with the address
+    /// of the next one.
+    free: [usize; N_CLASSES],
+}
+
+/// A fixed-size pool of locked memory. See the module documentation.
+pub struct LockedPool {
+    alloc: MapAlloc,
+    region: usize,
+    size: usize,
+    state: Mutex<State>,
+}
+
+impl LockedPool {
+    /// Map and lock a pool of `size` bytes, rounded up to a multiple of the page size.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the memory cannot be mapped.
+    pub fn new(size: usize) -> LockedPool {
+        let page_size = mmap::page_size();
+        let size = (cmp::max(size, 1) + page_size - 1) & !(page_size - 1);
+        let alloc = MapAllocBuilder::default().locked(true).build();
+        let layout = Layout::from_size_align(size, page_size).unwrap();
+        let region = unsafe { (&alloc).alloc(layout) }.expect("cannot map locked pool") as usize;
+        LockedPool {
+            alloc: alloc,
+            region: region,
+            size: size,
+            state: Mutex::new(State {
+                next: 0,
+                free: [0; N_CLASSES],
+            }),
+        }
+    }
+
+    /// Is the pool's memory locked? This is false if the limit on locked memory was reached when
+    /// the pool was created.
+    pub fn is_locked(&self) -> bool {
+        self.alloc.lock_failures() == 0
+    }
+
+    /// Allocate `size` bytes of zeroed memory, aligned to 16 bytes. Returns `None` if `size` is
+    /// larger than `MAX_OBJECT` or the pool has run out.
+    pub fn alloc(&self, size: usize) -> Option<*mut u8> {
+        if size > MAX_OBJECT {
+            return None;
+        }
+        let c = class(size);
+        let mut state = self.state.lock().unwrap();
+        let obj = state.free[c];
+        if obj != 0 {
+            unsafe {
+                state.free[c] = ptr::read(obj as *const usize);
+                // The rest of the object was zeroed when it was freed.
+                ptr::write(obj as *mut usize, 0);
+            }
+            return Some(obj as *mut u8);
+        }
+        if state.next + class_size(c) > self.size {
+            return None;
+        }
+        let obj = self.region + state.next;
+        state.next += class_size(c);
+        Some(obj as *mut u8)
+    }
+
+    /// Zero and free an object allocated by `alloc(size)`.
+    pub unsafe fn free(&self, p: *mut u8, size: usize) {
+        alloc_assert!(
+            p as usize >= self.region && (p as usize) < self.region + self.size,
+            "{:?} was not allocated from this locked pool",
+            p
+        );
+        let c = class(size);
+        // Volatile writes, so that the compiler cannot drop them as dead stores.
+        let words = p as *mut usize;
+        for i in 0..class_size(c) / ::std::mem::size_of::<usize>() {
+            ptr::write_volatile(words.offset(i as isize), 0);
+        }
+        let mut state = self.state.lock().unwrap();
+        ptr::write(words, state.free[c]);
+        state.free[c] = p as usize;
+    }
+}
+
+impl Drop for LockedPool {
+    fn drop(&mut self) {
+        unsafe {
+            // MapAlloc zeroes locked memory before unmapping it.
+            let layout = Layout::from_size_align(self.size, mmap::page_size()).unwrap();
+            (&self.alloc).dealloc(self.region as *mut u8, layout);
+        }
+    }
+}
+
+lazy_static! {
+    static ref POOL: LockedPool = LockedPool::new(POOL_SIZE);
+}
+
+/// Allocate `size` bytes of zeroed memory from the process-wide locked pool. See
+/// `LockedPool::alloc`.
+pub fn alloc(size: usize) -> Option<*mut u8> {
+    POOL.alloc(size)
+}
+
+/// Zero and free an object allocated from the process-wide locked pool.
+pub unsafe fn free(p: *mut u8, size: usize) {
+    POOL.free(p, size)
+}
+
+/// Is the process-wide locked pool's memory locked? See `LockedPool::is_locked`.
+pub fn is_locked() -> bool {
+    POOL.is_locked()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classes() {
+        alloc_assert_eq!(class(0), 0);
+        alloc_assert_eq!(class(16), 0);
+        alloc_assert_eq!(class(17), 1);
+        alloc_assert_eq!(class(MAX_OBJECT), N_CLASSES - 1);
+        alloc_assert_eq!(class_size(N_CLASSES - 1), MAX_OBJECT);
+    }
+
+    #[test]
+    fn alloc_zero_and_reuse() {
+        let pool = LockedPool::new(4096);
+        unsafe {
+            let p = pool.alloc(32).unwrap();
+            alloc_assert_eq!(p as usize % 16, 0);
+            ptr::write_bytes(p, 0xab, 32);
+            pool.free(p, 32);
+            // The object is reused, and has been zeroed.
+            let q = pool.alloc(20).unwrap();
+            alloc_assert_eq!(q, p);
+            for i in 0..32 {
+                alloc_assert_eq!(*q.offset(i), 0);
+            }
+            pool.free(q, 20);
+        }
+
+        alloc_assert!(pool.alloc(MAX_OBJECT + 1).is_none());
+        // The pool is fixed in size; the 32-byte object above took the first 32 bytes.
+        alloc_assert!(pool.alloc(MAX_OBJECT).is_some());
+        alloc_assert!(pool.alloc(MAX_OBJECT).is_none());
+
+        let p = alloc(100).unwrap();
+        unsafe { free(p, 100) };
+        let _ = is_locked();
+    }
+}
@@ -0,0 +1,27 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! MemorySanitizer annotations.
+//!
+//! Parts of the allocator are typically not instrumented (e.g. `bagpipe`, or code built without
+//! the sanitizer), and MemorySanitizer considers memory written by uninstrumented code to be
+//! uninitialized. Objects whose memory was last touched by such code are then reported as
+//! uninitialized as soon as the application reads data it has written to them itself. With the
+//! `msan` feature enabled, objects are unpoisoned when they are allocated.
+//!
+//! The interface is provided by the MSan runtime, so the feature requires building with
+//! `RUSTFLAGS="-Z sanitizer=memory"`.
+
+extern "C" {
+    fn __msan_unpoison(addr: *const u8, size: usize);
+}
+
+/// Mark `[p, p + size)` as initialized.
+#[inline]
+pub unsafe fn unpoison(p: *mut u8, size: usize) {
+    __msan_unpoison(p, size);
+}
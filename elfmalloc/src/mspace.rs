@@ -0,0 +1,466 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A general-purpose heap inside a caller-provided buffer, in the manner of dlmalloc's mspaces.
+//!
+//! `Heap::from_buffer` turns a slice of memory into a heap that supports allocation of arbitrary
+//! sizes and alignments, freeing, and resizing. Nothing is ever mapped or unmapped: the heap and
+//! all of its metadata live inside the buffer, and allocation fails once the buffer is full. This
+//! makes it suitable for environments without an operating system heap, and for tests that want
+//! deterministic placement and a hard bound on memory use. For the same reason, the heap cannot
+//! return free memory to the operating system (it only coalesces it for reuse).
+//!
+//! The implementation is a classic boundary-tag allocator. The buffer starts with a header
+//! holding the free lists, followed by a sequence of *chunks*, each of which starts with its own
+//! size and (if the previous chunk is free) the size of the previous chunk. Freed chunks are
+//! merged with free neighbours right away and kept in one of 64 doubly-linked bins: exact-size
+//! bins for small chunks and one bin per power of two above that. Allocation takes the first
+//! chunk that fits from the smallest suitable bin and splits off the remainder.
+//!
+//! A `Heap` is not thread-safe; wrap it in a `Mutex` to share it. The code only depends on `core`
+//! functionality.
+use super::alloc::allocator::{Alloc, AllocErr, Layout};
+
+use std::cmp;
+use std::mem;
+use std::ptr;
+
+const WORD: usize = mem::size_of::<usize>();
+/// The alignment of every chunk and every payload.
+const ALIGN: usize = 2 * WORD;
+/// The size of a chunk's header, which precedes its payload.
+const HEADER: usize = 2 * WORD;
+/// The size of the smallest chunk: a header and the two free list pointers.
+const MIN_CHUNK: usize = 4 * WORD;
+const NBINS: usize = 64;
+/// The number of exact-size bins.
+const SMALL_BINS: usize = 32;
+
+/// Set in a chunk's `size` if the chunk is in use.
+const INUSE: usize = 1;
+/// Set in a chunk's `size` if the preceding chunk is in use.
+const PREV_INUSE: usize = 2;
+const FLAGS: usize = INUSE | PREV_INUSE;
+
+#[repr(C)]
+struct Chunk {
+    /// The size of the preceding chunk; only valid if it is free.
+    prev_size: usize,
+    /// The size of this chunk, including its header, or-ed with `FLAGS`.
+    size: usize,
+    // The following fields are only present in free chunks.
+    next: *mut Chunk,
+    prev: *mut Chunk,
+}
+
+/// The header at the start of the buffer.
+#[repr(C)]
+struct State {
+    bins: [*mut Chunk; NBINS],
+    /// Bit `i` is set if `bins[i]` is non-empty.
+    binmap: u64,
+    /// The chunk following the last real chunk. It is permanently in use and has size 0, so that
+    /// merging never runs past the end of the buffer.
+    fencepost: *mut Chunk,
+    /// The total size of all chunks.
+    capacity: usize,
+    /// The total size of the chunks in use.
+    used: usize,
+}
+
+/// A heap inside a fixed buffer. See the module documentation for details.
+pub struct Heap {
+    state: *mut State,
+}
+
+unsafe impl Send for Heap {}
+
+#[inline]
+fn round_up(n: usize, align: usize) -> usize {
+    (n + align - 1) & !(align - 1)
+}
+
+#[inline]
+unsafe fn size_of(c: *mut Chunk) -> usize {
+    (*c).size & !FLAGS
+}
+
+#[inline]
+unsafe fn next_chunk(c: *mut Chunk) -> *mut Chunk {
+    (c as *mut u8).offset(size_of(c) as isize) as *mut Chunk
+}
+
+#[inline]
+unsafe fn payload(c: *mut Chunk) -> *mut u8 {
+    (c as *mut u8).offset(HEADER as isize)
+}
+
+#[inline]
+unsafe fn chunk_of(p: *mut u8) -> *mut Chunk {
+    p.offset(-(HEADER as isize)) as *mut Chunk
+}
+
+/// The bin holding free chunks of `size` bytes.
+fn bin_index(size: usize) -> usize {
+    let q = size / ALIGN;
+    if q < SMALL_BINS {
+        return q;
+    }
+    let log = |n: usize| mem::size_of::<usize>() * 8 - 1 - n.leading_zeros() as usize;
+    cmp::min(
+        SMALL_BINS + log(size) - log(SMALL_BINS * ALIGN),
+        NBINS - 1,
+    )
+}
+
+/// The chunk size needed for an object of `size` bytes, or `None` on overflow.
+fn chunk_size(size: usize) -> Option<usize> {
+    size.checked_add(HEADER + ALIGN - 1)
+        .map(|n| cmp::max(n & !(ALIGN - 1), MIN_CHUNK))
+}
+
+impl Heap {
+    /// Create a heap occupying `buf`.
+    ///
+    /// A few hundred bytes at the start of the buffer hold the heap's free lists; the rest is
+    /// available for objects, minus a two-word header per object.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is too small to hold the heap's metadata and a single object.
+    pub fn from_buffer(buf: &'static mut [u8]) -> Heap {
+        unsafe {
+            let start = buf.as_mut_ptr() as usize;
+            let end = start + buf.len();
+            let state = round_up(start, ALIGN);
+            let first = round_up(state + mem::size_of::<State>(), ALIGN);
+            // The fencepost only needs its header.
+            let fencepost = end.saturating_sub(HEADER) & !(ALIGN - 1);
+            alloc_assert!(
+                fencepost >= first + MIN_CHUNK,
+                "a buffer of {} bytes is too small for a heap",
+                buf.len()
+            );
+            let state = state as *mut State;
+            ptr::write(
+                state,
+                State {
+                    bins: [ptr::null_mut(); NBINS],
+                    binmap: 0,
+                    fencepost: fencepost as *mut Chunk,
+                    capacity: fencepost - first,
+                    used: 0,
+                },
+            );
+            let heap = Heap { state: state };
+            let first = first as *mut Chunk;
+            (*first).size = (fencepost - first as usize) | PREV_INUSE;
+            let fencepost = fencepost as *mut Chunk;
+            (*fencepost).prev_size = size_of(first);
+            (*fencepost).size = INUSE;
+            heap.insert(first);
+            heap
+        }
+    }
+
+    /// The number of bytes available for objects and their headers.
+    pub fn capacity(&self) -> usize {
+        unsafe { (*self.state).capacity }
+    }
+
+    /// The number of bytes in use by objects and their headers.
+    pub fn used(&self) -> usize {
+        unsafe { (*self.state).used }
+    }
+
+    /// Does `p` point into this heap's buffer?
+    pub fn contains(&self, p: *mut u8) -> bool {
+        let p = p as usize;
+        p >= self.state as usize && p < unsafe { (*self.state).fencepost as usize }
+    }
+
+    /// The number of bytes that can be used at `p`, which must have been allocated from this
+    /// heap and not freed.
+    pub unsafe fn usable_size(&self, p: *mut u8) -> usize {
+        alloc_debug_assert!(self.contains(p));
+        size_of(chunk_of(p)) - HEADER
+    }
+
+    unsafe fn insert(&self, c: *mut Chunk) {
+        let state = &mut *self.state;
+        let i = bin_index(size_of(c));
+        let head = state.bins[i];
+        (*c).next = head;
+        (*c).prev = ptr::null_mut();
+        if !head.is_null() {
+            (*head).prev = c;
+        }
+        state.bins[i] = c;
+        state.binmap |= 1 << i;
+    }
+
+    unsafe fn unlink(&self, c: *mut Chunk) {
+        let state = &mut *self.state;
+        let i = bin_index(size_of(c));
+        if (*c).prev.is_null() {
+            alloc_debug_assert_eq!(state.bins[i], c);
+            state.bins[i] = (*c).next;
+            if state.bins[i].is_null() {
+                state.binmap &= !(1 << i);
+            }
+        } else {
+            (*(*c).prev).next = (*c).next;
+        }
+        if !(*c).next.is_null() {
+            (*(*c).next).prev = (*c).prev;
+        }
+    }
+
+    /// Find and unlink a free chunk of at least `size` bytes.
+    unsafe fn take(&self, size: usize) -> Option<*mut Chunk> {
+        let state = &*self.state;
+        let i = bin_index(size);
+        // Chunks in the exact-size bins all fit; chunks in the other bins may not.
+        let mut c = state.bins[i];
+        while !c.is_null() {
+            if size_of(c) >= size {
+                self.unlink(c);
+                return Some(c);
+            }
+            c = (*c).next;
+        }
+        // Every chunk in a later bin is larger than `size`.
+        let later = if i + 1 < NBINS {
+            state.binmap & !((1 << (i + 1)) - 1)
+        } else {
+            0
+        };
+        if later == 0 {
+            return None;
+        }
+        let c = state.bins[later.trailing_zeros() as usize];
+        self.unlink(c);
+        Some(c)
+    }
+
+    /// Mark the unlinked free chunk `c` as in use, returning any space beyond `size` bytes to the
+    /// free lists.
+    unsafe fn carve(&self, c: *mut Chunk, size: usize) {
+        let total = size_of(c);
+        let prev_flag = (*c).size & PREV_INUSE;
+        alloc_debug_assert!(total >= size);
+        if total - size >= MIN_CHUNK {
+            (*c).size = size | prev_flag | INUSE;
+            let rem = next_chunk(c);
+            (*rem).size = (total - size) | PREV_INUSE;
+            (*next_chunk(rem)).prev_size = total - size;
+            self.insert(rem);
+        } else {
+            (*c).size = total | prev_flag | INUSE;
+            (*next_chunk(c)).size |= PREV_INUSE;
+        }
+        (*self.state).used += size_of(c);
+    }
+
+    /// Free the in-use chunk `c`, merging it with its free neighbours.
+    unsafe fn release(&self, mut c: *mut Chunk) {
+        let mut size = size_of(c);
+        (*self.state).used -= size;
+        let mut prev_flag = (*c).size & PREV_INUSE;
+        let next = next_chunk(c);
+        if (*next).size & INUSE == 0 {
+            self.unlink(next);
+            size += size_of(next);
+        }
+        if prev_flag == 0 {
+            let prev = (c as *mut u8).offset(-((*c).prev_size as isize)) as *mut Chunk;
+            self.unlink(prev);
+            size += size_of(prev);
+            c = prev;
+            // Two free chunks are never adjacent, so the chunk before `prev` is in use.
+            prev_flag = PREV_INUSE;
+        }
+        (*c).size = size | prev_flag;
+        let next = next_chunk(c);
+        (*next).prev_size = size;
+        (*next).size &= !PREV_INUSE;
+        self.insert(c);
+    }
+
+    /// Shrink the in-use chunk `c` to `size` bytes if that frees enough space for a new chunk.
+    unsafe fn shrink(&self, c: *mut Chunk, size: usize) {
+        let total = size_of(c);
+        if total - size < MIN_CHUNK {
+            return;
+        }
+        (*c).size = size | ((*c).size & FLAGS);
+        let rem = next_chunk(c);
+        (*rem).size = (total - size) | PREV_INUSE | INUSE;
+        // `release` subtracts the remainder from `used`, which included all of `total`.
+        self.release(rem);
+    }
+}
+
+unsafe impl Alloc for Heap {
+    unsafe fn alloc(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        let size = match chunk_size(l.size()) {
+            Some(size) if size <= self.capacity() => size,
+            _ => return Err(AllocErr::Exhausted { request: l }),
+        };
+        if l.align() <= ALIGN {
+            return match self.take(size) {
+                Some(c) => {
+                    self.carve(c, size);
+                    Ok(payload(c))
+                }
+                None => Err(AllocErr::Exhausted { request: l }),
+            };
+        }
+        // Take a chunk large enough that an aligned payload can be found in it with room for a
+        // free chunk before it, if there is any space before it at all.
+        let c = match size.checked_add(2 * l.align()).and_then(|n| self.take(n)) {
+            Some(c) => c,
+            None => return Err(AllocErr::Exhausted { request: l }),
+        };
+        let mut p = round_up(payload(c) as usize, l.align());
+        let mut lead = p - payload(c) as usize;
+        if lead != 0 && lead < MIN_CHUNK {
+            p += l.align();
+            lead += l.align();
+        }
+        let c = if lead == 0 {
+            c
+        } else {
+            let total = size_of(c);
+            let aligned = chunk_of(p as *mut u8);
+            (*aligned).prev_size = lead;
+            (*aligned).size = total - lead;
+            (*c).size = lead | ((*c).size & PREV_INUSE);
+            self.insert(c);
+            aligned
+        };
+        self.carve(c, size);
+        Ok(payload(c))
+    }
+
+    unsafe fn dealloc(&mut self, p: *mut u8, _l: Layout) {
+        alloc_debug_assert!(self.contains(p));
+        self.release(chunk_of(p));
+    }
+
+    unsafe fn realloc(&mut self, p: *mut u8, l: Layout, new_l: Layout) -> Result<*mut u8, AllocErr> {
+        let c = chunk_of(p);
+        let size = match chunk_size(new_l.size()) {
+            Some(size) => size,
+            None => return Err(AllocErr::Exhausted { request: new_l }),
+        };
+        if p as usize % new_l.align() == 0 {
+            if size <= size_of(c) {
+                self.shrink(c, size);
+                return Ok(p);
+            }
+            let next = next_chunk(c);
+            if (*next).size & INUSE == 0 && size_of(c) + size_of(next) >= size {
+                self.unlink(next);
+                let grown = size_of(next);
+                (*c).size += grown;
+                (*next_chunk(c)).size |= PREV_INUSE;
+                (*self.state).used += grown;
+                self.shrink(c, size);
+                return Ok(p);
+            }
+        }
+        let new = self.alloc(new_l.clone())?;
+        ptr::copy_nonoverlapping(p, new, cmp::min(l.size(), new_l.size()));
+        self.dealloc(p, l);
+        Ok(new)
+    }
+
+    fn usable_size(&self, l: &Layout) -> (usize, usize) {
+        (l.size(), chunk_size(l.size()).map_or(l.size(), |n| n - HEADER))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(len: usize) -> &'static mut [u8] {
+        unsafe { &mut *Box::into_raw(vec![0u8; len].into_boxed_slice()) }
+    }
+
+    #[test]
+    fn alloc_free_merge() {
+        let mut heap = Heap::from_buffer(buffer(64 << 10));
+        let capacity = heap.capacity();
+        let l = Layout::from_size_align(100, 8).unwrap();
+        unsafe {
+            let mut ptrs = Vec::new();
+            while let Ok(p) = heap.alloc(l.clone()) {
+                alloc_assert!(heap.contains(p));
+                alloc_assert_eq!(p as usize % ALIGN, 0);
+                ptr::write_bytes(p, ptrs.len() as u8, 100);
+                ptrs.push(p);
+            }
+            alloc_assert!(ptrs.len() > 400);
+            for (i, &p) in ptrs.iter().enumerate() {
+                alloc_assert_eq!(*p.offset(99), i as u8);
+            }
+            // Free every other object, then the rest; everything must merge back into one chunk.
+            for parity in 0..2 {
+                for (i, &p) in ptrs.iter().enumerate() {
+                    if i % 2 == parity {
+                        heap.dealloc(p, l.clone());
+                    }
+                }
+            }
+            alloc_assert_eq!(heap.used(), 0);
+            let all = Layout::from_size_align(capacity - HEADER, 8).unwrap();
+            let p = heap.alloc(all.clone()).unwrap();
+            heap.dealloc(p, all);
+        }
+    }
+
+    #[test]
+    fn aligned_and_realloc() {
+        let mut heap = Heap::from_buffer(buffer(1 << 20));
+        unsafe {
+            for &align in &[32, 64, 4096, 1 << 16] {
+                let l = Layout::from_size_align(24, align).unwrap();
+                let p = heap.alloc(l.clone()).unwrap();
+                alloc_assert_eq!(p as usize % align, 0);
+                heap.dealloc(p, l);
+            }
+            alloc_assert_eq!(heap.used(), 0);
+
+            let l = Layout::from_size_align(16, 8).unwrap();
+            let p = heap.alloc(l.clone()).unwrap();
+            ptr::write_bytes(p, 7, 16);
+            // Nothing follows `p`, so it can grow in place.
+            let big = Layout::from_size_align(4096, 8).unwrap();
+            alloc_assert_eq!(heap.realloc(p, l.clone(), big.clone()), Ok(p));
+            alloc_assert!(heap.usable_size(p) >= 4096);
+            let q = heap.alloc(l.clone()).unwrap();
+            alloc_assert_eq!(heap.realloc(p, big.clone(), l.clone()), Ok(p));
+            let r = heap.realloc(p, l.clone(), big.clone()).unwrap();
+            alloc_assert_eq!(*r.offset(15), 7);
+            heap.dealloc(q, l);
+            heap.dealloc(r, big);
+            alloc_assert_eq!(heap.used(), 0);
+        }
+    }
+
+    #[test]
+    fn exhaustion() {
+        let mut heap = Heap::from_buffer(buffer(4096));
+        let l = Layout::from_size_align(8192, 8).unwrap();
+        unsafe {
+            alloc_assert!(heap.alloc(l).is_err());
+            alloc_assert!(heap.alloc(Layout::from_size_align(!0 >> 1, 8).unwrap()).is_err());
+        }
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Arm Memory Tagging Extension (MTE) support.
+//!
+//! With MTE, every 16-byte granule of memory carries a 4-bit tag, and pointers carry a tag in
+//! bits 56-59. A load or store through a pointer whose tag does not match the tag of the memory it
+//! accesses faults. With the `mte` feature enabled, and if the hardware and kernel support it, we
+//! use this to catch memory errors in the application:
+//!
+//! - Pages obtained from a `MemorySource` are mapped with `PROT_MTE`.
+//! - Small and medium objects are tagged with a random non-zero tag when they are handed out, and
+//!   the returned pointer carries the same tag.
+//! - Freed objects are retagged with tag 0.
+//!
+//! All memory owned by the allocator (free objects, `Slag` headers, bit sets) thus has tag 0, and
+//! is accessed through untagged pointers; pointers received from the application are untagged as
+//! soon as they enter the allocator. Stale pointers to freed objects no longer match their tag,
+//! and overflows into a neighboring object fault unless it happens to have the same tag (1 in 15).
+//!
+//! Objects smaller than a granule (the 8-byte size class) share their granule with a neighbor and
+//! are not tagged. Large objects are not tagged either: they are unmapped when freed, so accesses
+//! to them fault anyway.
+//!
+//! Support is detected at runtime through `HWCAP2_MTE`, the first time it is needed; without it,
+//! all of this is a no-op. Tag checks are then enabled in synchronous mode for the calling thread,
+//! and are inherited by the threads it creates afterwards. Threads created before elfmalloc
+//! allocates its first object do not check tags.
+
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+extern crate libc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use super::utils::map_addr;
+
+/// The number of bytes covered by a single tag.
+pub const GRANULE: usize = 16;
+
+/// The bits of a pointer that hold its tag, along with the rest of the top byte, which is ignored
+/// by the hardware when dereferencing it.
+const TAG_MASK: usize = 0xff << 56;
+
+const UNKNOWN: usize = 0;
+const DISABLED: usize = 1;
+const ENABLED: usize = 2;
+
+static STATE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Is memory tagging supported and enabled?
+#[inline]
+pub fn enabled() -> bool {
+    match STATE.load(Ordering::Relaxed) {
+        UNKNOWN => init(),
+        state => state == ENABLED,
+    }
+}
+
+#[cold]
+fn init() -> bool {
+    let enabled = unsafe { sys::enable() };
+    STATE.store(if enabled { ENABLED } else { DISABLED }, Ordering::Relaxed);
+    enabled
+}
+
+/// Strip the tag from `p`.
+#[inline]
+pub fn untag(p: *mut u8) -> *mut u8 {
+    map_addr(p, |addr| addr & !TAG_MASK)
+}
+
+/// Allow `[p, p + len)`, which must be page-aligned, to be tagged.
+pub unsafe fn enable_pages(p: *mut u8, len: usize) {
+    if enabled() {
+        sys::protect_mte(p, len);
+    }
+}
+
+/// Tag the object of `size` bytes at `p`, which must be untagged, with a random tag, and return a
+/// pointer to it that carries that tag.
+#[inline]
+pub unsafe fn tag(p: *mut u8, size: usize) -> *mut u8 {
+    if size % GRANULE != 0 || !enabled() {
+        return p;
+    }
+    let tagged = sys::random_tag(p);
+    set_tag(tagged, size);
+    tagged
+}
+
+/// Reset the tag of the object of `size` bytes at `p`, which must be untagged, to 0.
+#[inline]
+pub unsafe fn retire(p: *mut u8, size: usize) {
+    if size % GRANULE != 0 || !enabled() {
+        return;
+    }
+    set_tag(p, size);
+}
+
+/// Set the tag of every granule in `[p, p + size)` to the tag of `p`.
+unsafe fn set_tag(p: *mut u8, size: usize) {
+    let mut offset = 0;
+    while offset < size {
+        sys::store_tag(p.offset(offset as isize));
+        offset += GRANULE;
+    }
+}
+
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+mod sys {
+    use super::libc;
+
+    // not exported by the libc crate
+    const AT_HWCAP2: libc::c_ulong = 26;
+    const HWCAP2_MTE: libc::c_ulong = 1 << 18;
+    const PR_SET_TAGGED_ADDR_CTRL: libc::c_int = 55;
+    const PR_TAGGED_ADDR_ENABLE: libc::c_ulong = 1;
+    const PR_MTE_TCF_SYNC: libc::c_ulong = 1 << 1;
+    const PR_MTE_TAG_SHIFT: libc::c_ulong = 3;
+    const PROT_MTE: libc::c_int = 0x20;
+
+    /// Enable tagged addresses and synchronous tag checks for this thread, if MTE is supported.
+    pub unsafe fn enable() -> bool {
+        if libc::getauxval(AT_HWCAP2) & HWCAP2_MTE == 0 {
+            return false;
+        }
+        // `irg` may generate any tag but 0, which is reserved for memory owned by the allocator.
+        let ctrl = PR_TAGGED_ADDR_ENABLE | PR_MTE_TCF_SYNC | (0xfffe << PR_MTE_TAG_SHIFT);
+        libc::prctl(PR_SET_TAGGED_ADDR_CTRL, ctrl, 0, 0, 0) == 0
+    }
+
+    pub unsafe fn protect_mte(p: *mut u8, len: usize) {
+        let ret = libc::mprotect(
+            p as *mut libc::c_void,
+            len,
+            libc::PROT_READ | libc::PROT_WRITE | PROT_MTE,
+        );
+        alloc_assert_eq!(ret, 0, "mprotect(PROT_MTE) failed");
+    }
+
+    /// Return `p` with a random tag inserted.
+    #[inline(always)]
+    pub unsafe fn random_tag(p: *mut u8) -> *mut u8 {
+        let res;
+        asm!(".arch_extension memtag
+              irg $0, $1"
+             : "=r"(res)
+             : "r"(p)
+             :
+             : "volatile");
+        res
+    }
+
+    /// Set the tag of the granule at `p` to the tag of `p`.
+    #[inline(always)]
+    pub unsafe fn store_tag(p: *mut u8) {
+        asm!(".arch_extension memtag
+              stg $0, [$0]"
+             :
+             : "r"(p)
+             : "memory"
+             : "volatile");
+    }
+}
+
+#[cfg(not(all(target_arch = "aarch64", target_os = "linux")))]
+mod sys {
+    pub unsafe fn enable() -> bool {
+        false
+    }
+
+    pub unsafe fn protect_mte(_p: *mut u8, _len: usize) {}
+
+    pub unsafe fn random_tag(p: *mut u8) -> *mut u8 {
+        p
+    }
+
+    pub unsafe fn store_tag(_p: *mut u8) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::general::DynamicAllocator;
+    use std::ptr::write_volatile;
+
+    #[test]
+    fn untag_pointer() {
+        let p = 0x0a00_7fff_1234_5670usize as *mut u8;
+        alloc_assert_eq!(untag(p) as usize, 0x7fff_1234_5670);
+        alloc_assert_eq!(untag(untag(p)), untag(p));
+    }
+
+    #[test]
+    fn tagged_objects() {
+        let mut alloc = DynamicAllocator::new();
+        unsafe {
+            let p = alloc.alloc(48);
+            write_volatile(p.offset(47), 1);
+            if enabled() {
+                alloc_assert!(p != untag(p), "object is not tagged: {:?}", p);
+            } else {
+                alloc_assert_eq!(p, untag(p));
+            }
+            alloc.free(p);
+            // Tiny objects share a granule and are never tagged.
+            let p = alloc.alloc(8);
+            alloc_assert_eq!(p, untag(p));
+            alloc.free(p);
+        }
+    }
+}
@@ -0,0 +1,447 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Pointers that stay valid when memory is mapped at a different address.
+//!
+//! Data structures that live in a mapping shared between processes (or saved to disk and mapped
+//! again later) cannot contain ordinary pointers: the mapping will usually be at a different
+//! address in each process. An `OffsetPtr<T>` instead stores the distance of its target from the
+//! *base* of the mapping, and is resolved against whichever base the mapping currently has.
+//!
+//! Offsets are relative to a base rather than to the pointer itself (as C++'s
+//! `boost::offset_ptr` does) because Rust moves values with a plain memory copy, which would
+//! silently invalidate a self-relative pointer.
+//!
+//! `OffsetSlice<T>` is the corresponding variant of a slice, and `OffsetVec<T>` of a `Vec`. The
+//! base is usually that of a `Region`, and `Region::base` can be passed wherever a base is
+//! expected.
+//!
+//! The containers do not own an allocator: the operations that allocate or free take one, along
+//! with the current base. Any `Alloc` whose memory lies in the mapping will do, such as an
+//! `mspace::Heap` created in a `Region`'s committed memory. The containers have no destructors and
+//! only hold `Copy` elements, so that nothing in a mapping ever needs to be dropped.
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+use std::slice;
+
+use super::alloc::allocator::{Alloc, AllocErr, Layout};
+use super::sources::Region;
+
+/// The offset representing a null pointer.
+const NULL_OFFSET: usize = !0;
+
+/// A pointer to a `T`, stored as an offset from the base of a mapping.
+#[repr(C)]
+pub struct OffsetPtr<T> {
+    offset: usize,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for OffsetPtr<T> {
+    fn clone(&self) -> OffsetPtr<T> {
+        *self
+    }
+}
+
+impl<T> Copy for OffsetPtr<T> {}
+
+impl<T> PartialEq for OffsetPtr<T> {
+    fn eq(&self, other: &OffsetPtr<T>) -> bool {
+        self.offset == other.offset
+    }
+}
+
+impl<T> Eq for OffsetPtr<T> {}
+
+impl<T> ::std::fmt::Debug for OffsetPtr<T> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        if self.is_null() {
+            write!(f, "OffsetPtr(null)")
+        } else {
+            write!(f, "OffsetPtr(+{:#x})", self.offset)
+        }
+    }
+}
+
+impl<T> Default for OffsetPtr<T> {
+    fn default() -> OffsetPtr<T> {
+        OffsetPtr::null()
+    }
+}
+
+impl<T> OffsetPtr<T> {
+    /// A null `OffsetPtr`. It resolves to a null pointer against any base.
+    pub fn null() -> OffsetPtr<T> {
+        OffsetPtr::from_offset(NULL_OFFSET)
+    }
+
+    /// Construct an `OffsetPtr` from an offset in bytes.
+    pub fn from_offset(offset: usize) -> OffsetPtr<T> {
+        OffsetPtr {
+            offset: offset,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Construct an `OffsetPtr` to `p`, which must lie in the mapping starting at `base` (or be
+    /// null).
+    pub fn new(p: *mut T, base: *mut u8) -> OffsetPtr<T> {
+        if p.is_null() {
+            return OffsetPtr::null();
+        }
+        alloc_debug_assert!(p as usize >= base as usize);
+        OffsetPtr::from_offset(p as usize - base as usize)
+    }
+
+    /// Construct an `OffsetPtr` to `p`, which must lie in `region` (or be null).
+    pub fn in_region(p: *mut T, region: &Region) -> OffsetPtr<T> {
+        alloc_debug_assert!(p.is_null() || region.contains(p as *mut u8));
+        OffsetPtr::new(p, region.base())
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.offset == NULL_OFFSET
+    }
+
+    /// The offset of the target from the base of its mapping.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Get the address of the target in the mapping starting at `base`.
+    pub fn resolve(&self, base: *mut u8) -> *mut T {
+        if self.is_null() {
+            return ptr::null_mut();
+        }
+        base.wrapping_offset(self.offset as isize) as *mut T
+    }
+}
+
+/// A slice of `T`s, stored as an `OffsetPtr` to its first element and a length.
+#[repr(C)]
+pub struct OffsetSlice<T> {
+    start: OffsetPtr<T>,
+    len: usize,
+}
+
+impl<T> Clone for OffsetSlice<T> {
+    fn clone(&self) -> OffsetSlice<T> {
+        *self
+    }
+}
+
+impl<T> Copy for OffsetSlice<T> {}
+
+impl<T> Default for OffsetSlice<T> {
+    fn default() -> OffsetSlice<T> {
+        OffsetSlice {
+            start: OffsetPtr::null(),
+            len: 0,
+        }
+    }
+}
+
+impl<T> OffsetSlice<T> {
+    /// Construct an `OffsetSlice` referring to `s`, which must lie in the mapping starting at
+    /// `base`.
+    pub fn new(s: &[T], base: *mut u8) -> OffsetSlice<T> {
+        OffsetSlice {
+            start: OffsetPtr::new(s.as_ptr() as *mut T, base),
+            len: s.len(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get the slice in the mapping starting at `base`.
+    ///
+    /// # Safety
+    ///
+    /// The mapping at `base` must contain the slice's elements, and they must not be mutated for
+    /// the lifetime of the returned slice.
+    pub unsafe fn resolve<'a>(&self, base: *mut u8) -> &'a [T] {
+        if self.len == 0 {
+            return &[];
+        }
+        slice::from_raw_parts(self.start.resolve(base), self.len)
+    }
+
+    /// Get the mutable slice in the mapping starting at `base`.
+    ///
+    /// # Safety
+    ///
+    /// The mapping at `base` must contain the slice's elements, and they must not be accessed
+    /// through any other reference for the lifetime of the returned slice.
+    pub unsafe fn resolve_mut<'a>(&self, base: *mut u8) -> &'a mut [T] {
+        if self.len == 0 {
+            return &mut [];
+        }
+        slice::from_raw_parts_mut(self.start.resolve(base), self.len)
+    }
+}
+
+/// A growable vector of `T`s in a mapping, stored as an `OffsetPtr` to its buffer, a length and
+/// a capacity. `T` must not be zero-sized.
+///
+/// See the module documentation for how the buffer is allocated. Growing the vector moves its
+/// buffer, so slices obtained from it must not be used across a `push`.
+#[repr(C)]
+pub struct OffsetVec<T> {
+    buf: OffsetPtr<T>,
+    len: usize,
+    cap: usize,
+}
+
+impl<T> Clone for OffsetVec<T> {
+    fn clone(&self) -> OffsetVec<T> {
+        *self
+    }
+}
+
+impl<T> Copy for OffsetVec<T> {}
+
+impl<T> Default for OffsetVec<T> {
+    fn default() -> OffsetVec<T> {
+        OffsetVec {
+            buf: OffsetPtr::null(),
+            len: 0,
+            cap: 0,
+        }
+    }
+}
+
+/// The layout of a buffer of `cap` `T`s, or `None` on overflow.
+fn array_layout<T>(cap: usize) -> Option<Layout> {
+    mem::size_of::<T>()
+        .checked_mul(cap)
+        .and_then(|size| Layout::from_size_align(size, mem::align_of::<T>()))
+}
+
+impl<T: Copy> OffsetVec<T> {
+    /// An empty vector, which has no buffer yet.
+    pub fn new() -> OffsetVec<T> {
+        OffsetVec::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of elements that fit in the current buffer.
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// Append `val`, growing the buffer with `a` if it is full.
+    ///
+    /// # Safety
+    ///
+    /// The vector must be in the mapping starting at `base`, and its buffer must have been
+    /// allocated with `a`, which must allocate from the same mapping.
+    pub unsafe fn push<A: Alloc>(
+        &mut self,
+        val: T,
+        a: &mut A,
+        base: *mut u8,
+    ) -> Result<(), AllocErr> {
+        if self.len == self.cap {
+            self.grow(a, base)?;
+        }
+        ptr::write(self.buf.resolve(base).offset(self.len as isize), val);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove the last element and return it, or `None` if the vector is empty.
+    ///
+    /// # Safety
+    ///
+    /// The vector's buffer must be in the mapping starting at `base`.
+    pub unsafe fn pop(&mut self, base: *mut u8) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(*self.buf.resolve(base).offset(self.len as isize))
+    }
+
+    /// Get the elements in the mapping starting at `base`.
+    ///
+    /// # Safety
+    ///
+    /// As for `OffsetSlice::resolve`.
+    pub unsafe fn as_slice<'a>(&self, base: *mut u8) -> &'a [T] {
+        self.as_offset_slice().resolve(base)
+    }
+
+    /// Get the elements in the mapping starting at `base` mutably.
+    ///
+    /// # Safety
+    ///
+    /// As for `OffsetSlice::resolve_mut`.
+    pub unsafe fn as_mut_slice<'a>(&self, base: *mut u8) -> &'a mut [T] {
+        self.as_offset_slice().resolve_mut(base)
+    }
+
+    /// The elements as an `OffsetSlice`, which stays valid until the vector grows or is freed.
+    pub fn as_offset_slice(&self) -> OffsetSlice<T> {
+        OffsetSlice {
+            start: self.buf,
+            len: self.len,
+        }
+    }
+
+    /// Free the buffer with `a`, leaving the vector empty.
+    ///
+    /// # Safety
+    ///
+    /// As for `push`.
+    pub unsafe fn free<A: Alloc>(&mut self, a: &mut A, base: *mut u8) {
+        if self.cap != 0 {
+            let l = array_layout::<T>(self.cap).unwrap();
+            a.dealloc(self.buf.resolve(base) as *mut u8, l);
+        }
+        *self = OffsetVec::new();
+    }
+
+    unsafe fn grow<A: Alloc>(&mut self, a: &mut A, base: *mut u8) -> Result<(), AllocErr> {
+        alloc_assert!(mem::size_of::<T>() != 0, "OffsetVec of a zero-sized type");
+        let cap = if self.cap == 0 { 4 } else { self.cap * 2 };
+        let l = match array_layout::<T>(cap) {
+            Some(l) => l,
+            None => {
+                return Err(AllocErr::Unsupported {
+                    details: "OffsetVec capacity overflow",
+                })
+            }
+        };
+        let buf = if self.cap == 0 {
+            a.alloc(l)?
+        } else {
+            let old = array_layout::<T>(self.cap).unwrap();
+            a.realloc(self.buf.resolve(base) as *mut u8, old, l)?
+        };
+        self.buf = OffsetPtr::new(buf as *mut T, base);
+        self.cap = cap;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::mspace::Heap;
+    use super::super::sources::reserve;
+
+    struct Node {
+        val: usize,
+        next: OffsetPtr<Node>,
+        name: OffsetSlice<u8>,
+    }
+
+    #[test]
+    fn offset_list_survives_remapping() {
+        let src = reserve(1 << 20).unwrap();
+        let dst = reserve(1 << 20).unwrap();
+        let n_nodes = 100;
+        unsafe {
+            let nodes = src.commit(n_nodes * mem::size_of::<Node>()).unwrap() as *mut Node;
+            let names = src.commit(n_nodes).unwrap();
+            let mut head = OffsetPtr::<Node>::null();
+            for i in 0..n_nodes {
+                let name = names.offset(i as isize);
+                *name = i as u8;
+                let node = nodes.offset(i as isize);
+                ptr::write(
+                    node,
+                    Node {
+                        val: i,
+                        next: head,
+                        name: OffsetSlice::new(slice::from_raw_parts(name, 1), src.base()),
+                    },
+                );
+                head = OffsetPtr::in_region(node, &src);
+            }
+
+            // "Map" the data at another address, and destroy the original.
+            ptr::copy_nonoverlapping(
+                src.base(),
+                dst.commit(src.committed()).unwrap(),
+                src.committed(),
+            );
+            ptr::write_bytes(src.base(), 0, src.committed());
+
+            let mut cur = head.resolve(dst.base());
+            let mut expected = n_nodes;
+            while !cur.is_null() {
+                expected -= 1;
+                alloc_assert!(dst.contains(cur as *mut u8));
+                alloc_assert_eq!((*cur).val, expected);
+                alloc_assert_eq!((*cur).name.resolve(dst.base()), &[expected as u8][..]);
+                cur = (*cur).next.resolve(dst.base());
+            }
+            alloc_assert_eq!(expected, 0);
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    struct Entry {
+        key: usize,
+        name: OffsetSlice<u8>,
+    }
+
+    #[test]
+    fn offset_vec_survives_remapping() {
+        let src = reserve(1 << 20).unwrap();
+        let dst = reserve(1 << 20).unwrap();
+        let n_entries = 1000;
+        unsafe {
+            let base = src.commit(src.len()).unwrap();
+            let mut heap = Heap::from_buffer(slice::from_raw_parts_mut(base, src.len()));
+            // The vector itself lives in the mapping too, as the entry point to its contents.
+            let vec = heap.alloc(Layout::new::<OffsetVec<Entry>>()).unwrap();
+            let vec = vec as *mut OffsetVec<Entry>;
+            ptr::write(vec, OffsetVec::new());
+            let root = OffsetPtr::in_region(vec, &src);
+            for i in 0..n_entries {
+                let name = heap.alloc(Layout::from_size_align(2, 1).unwrap()).unwrap();
+                *name = i as u8;
+                *name.offset(1) = (i >> 8) as u8;
+                let entry = Entry {
+                    key: i,
+                    name: OffsetSlice::new(slice::from_raw_parts(name, 2), base),
+                };
+                (*vec).push(entry, &mut heap, base).unwrap();
+            }
+            alloc_assert!((*vec).capacity() >= n_entries);
+            let last = (*vec).pop(base).unwrap();
+            alloc_assert_eq!(last.key, n_entries - 1);
+
+            // "Map" the data at another address, and destroy the original.
+            ptr::copy_nonoverlapping(src.base(), dst.commit(dst.len()).unwrap(), src.len());
+            ptr::write_bytes(src.base(), 0, src.len());
+
+            let vec = root.resolve(dst.base());
+            alloc_assert_eq!((*vec).len(), n_entries - 1);
+            for (i, entry) in (*vec).as_slice(dst.base()).iter().enumerate() {
+                alloc_assert_eq!(entry.key, i);
+                alloc_assert_eq!(entry.name.resolve(dst.base()), &[i as u8, (i >> 8) as u8][..]);
+            }
+        }
+    }
+}
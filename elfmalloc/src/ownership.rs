@@ -0,0 +1,334 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A map of the address ranges that belong to elfmalloc.
+//!
+//! elfmalloc finds the metadata of an object by rounding its address down and reading an
+//! `AllocType` from the start of the enclosing page (see `general::get_type`). When it is handed a
+//! pointer it did not allocate - because the process also uses another allocator, or because a
+//! library captured a pointer before elfmalloc was interposed - that read lands in some other
+//! allocator's memory or in unmapped memory, and `free` silently corrupts the heap or crashes far
+//! from the bug.
+//!
+//! With the `ownership` feature, elfmalloc records the kind of every 64KiB granule of address
+//! space it maps in a two-level radix table. The C API functions (`free`, `realloc`, and functions
+//! that look up an object's size) check pointers against the table before touching them. A
+//! pointer outside of elfmalloc's memory is passed to the hook installed with
+//! `set_foreign_free_hook`, if there is one. Otherwise, a foreign pointer passed to `free` is
+//! reported as a violation to the `integrity` module, which aborts the process by default, and
+//! one passed to any other function aborts the process with a message naming the pointer ("strict
+//! mode").
+//!
+//! Every mapping made by the general-purpose allocator is aligned to, and a multiple of, the
+//! granule size, so the table is exact for it. Memory for the object-specific allocators in
+//! `frontends` may use smaller pages, in which case the whole granule is considered owned. The
+//! table does not record size classes, since pages move between size classes; once a pointer is
+//! known to be owned, its size class is read from the `Slag` header as usual. It does record the
+//! size of the `Slag`s in a granule, and for large objects, the position of the granule in the
+//! object's mapping. With these, any pointer can be traced back to the start of its `Slag` or
+//! mapping without reading elfmalloc's memory, which is what `general::global::lookup` and the
+//! `walk` module rely on.
+//!
+//! The table's leaves cover 1GiB of address space each and are mapped on first use, so the
+//! memory cost is a few pages per GiB of heap.
+//!
+//! The table takes no locks. Lookups are two atomic loads. Registering and unregistering a range
+//! store to its entries, and a missing leaf (or root) is installed with a compare-and-swap, the
+//! loser unmapping its copy. Large objects are registered and unregistered on every allocation
+//! and free, so this keeps lookups of small objects on other threads from ever waiting on the
+//! large path.
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use super::alloc_type::AllocType;
+use super::integrity::{self, Violation};
+use super::utils::mmap;
+
+/// The base-2 log of the granularity of the table.
+pub const GRANULE_SHIFT: usize = 16;
+/// The base-2 log of the address space covered by a leaf.
+pub const LEAF_SHIFT: usize = 30;
+const LEAF_ENTRIES: usize = 1 << (LEAF_SHIFT - GRANULE_SHIFT);
+/// Only the low `ADDRESS_BITS` bits of a user-space pointer can be non-zero.
+#[cfg(target_pointer_width = "64")]
+pub const ADDRESS_BITS: usize = 48;
+#[cfg(target_pointer_width = "32")]
+pub const ADDRESS_BITS: usize = 32;
+const ROOT_ENTRIES: usize = 1 << (ADDRESS_BITS - LEAF_SHIFT);
+
+/// The entries of a leaf are 0 for memory that does not belong to elfmalloc, and otherwise
+/// describe a `Granule` (see `encode`).
+type Leaf = [AtomicUsize; LEAF_ENTRIES];
+
+/// The address of the root of the table, an array of `ROOT_ENTRIES` leaf addresses, or 0 if it
+/// has not been mapped yet. Leaf addresses are likewise 0 until the leaf is mapped.
+///
+/// This is only public so that `debug_support` can point debuggers at it.
+#[doc(hidden)]
+pub static ROOT: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The foreign free hook, as an `unsafe fn(*mut u8)`, or 0 if there is none.
+static FOREIGN_FREE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// What the table records about a granule of elfmalloc memory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Granule {
+    /// The granule holds `Slag`s of type `ty` (`SmallSlag` or `BigSlag`) that are `slag_size`
+    /// bytes long and aligned to their size.
+    Slags { ty: AllocType, slag_size: usize },
+    /// The granule is the `index`th granule of the mapping of a large object.
+    Large { index: usize },
+}
+
+impl Granule {
+    pub fn ty(&self) -> AllocType {
+        match *self {
+            Granule::Slags { ty, .. } => ty,
+            Granule::Large { .. } => AllocType::Large,
+        }
+    }
+}
+
+/// The low two bits of an entry identify its `AllocType`. The remaining bits hold the base-2 log
+/// of the `Slag` size for `Slag`s, and the index of the granule for large objects.
+///
+/// The debugger scripts decode entries themselves to inspect core dumps (see `debug_support`), so
+/// changing the encoding requires bumping `debug_support::DESCRIPTOR_VERSION`.
+fn encode(g: Granule) -> usize {
+    match g {
+        Granule::Slags { ty: AllocType::SmallSlag, slag_size } => {
+            1 | (slag_size.trailing_zeros() as usize) << 2
+        }
+        Granule::Slags { ty: AllocType::BigSlag, slag_size } => {
+            2 | (slag_size.trailing_zeros() as usize) << 2
+        }
+        Granule::Slags { ty: AllocType::Large, .. } => {
+            alloc_assert!(false, "large objects are not stored in Slags");
+            unreachable!()
+        }
+        Granule::Large { index } => 3 | index << 2,
+    }
+}
+
+fn decode(val: usize) -> Option<Granule> {
+    match val & 3 {
+        1 => Some(Granule::Slags {
+            ty: AllocType::SmallSlag,
+            slag_size: 1 << (val >> 2),
+        }),
+        2 => Some(Granule::Slags {
+            ty: AllocType::BigSlag,
+            slag_size: 1 << (val >> 2),
+        }),
+        3 => Some(Granule::Large { index: val >> 2 }),
+        _ => None,
+    }
+}
+
+/// Map `bytes` zeroed bytes and store their address in `slot`, unless another thread beat us to
+/// it. Returns the address stored in `slot`.
+fn install(slot: &AtomicUsize, bytes: usize) -> usize {
+    let fresh = mmap::map(bytes) as usize;
+    match slot.compare_exchange(0, fresh, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => fresh,
+        Err(winner) => {
+            unsafe { mmap::unmap(fresh as *mut u8, bytes) };
+            winner
+        }
+    }
+}
+
+/// Get the entry for the granule containing `addr`, creating its leaf if `create` is true.
+#[inline]
+fn entry(addr: usize, create: bool) -> Option<&'static AtomicUsize> {
+    if (addr as u64) >> ADDRESS_BITS != 0 {
+        return None;
+    }
+    let mut root = ROOT.load(Ordering::Acquire);
+    if root == 0 {
+        if !create {
+            return None;
+        }
+        root = install(&ROOT, ROOT_ENTRIES * mem::size_of::<AtomicUsize>());
+    }
+    let slot = unsafe { &*(root as *const AtomicUsize).offset((addr >> LEAF_SHIFT) as isize) };
+    let mut leaf = slot.load(Ordering::Acquire);
+    if leaf == 0 {
+        if !create {
+            return None;
+        }
+        leaf = install(slot, mem::size_of::<Leaf>());
+    }
+    let ix = (addr & ((1 << LEAF_SHIFT) - 1)) >> GRANULE_SHIFT;
+    Some(unsafe { &(*(leaf as *const Leaf))[ix] })
+}
+
+/// Set the entries of the granules overlapping `[base, base + len)`, calling `val` with the index
+/// of each granule in the range.
+fn set_range<F: Fn(usize) -> usize>(base: *mut u8, len: usize, val: F) {
+    let granule = 1 << GRANULE_SHIFT;
+    let start = base as usize & !(granule - 1);
+    let end = (base as usize + len + granule - 1) & !(granule - 1);
+    let mut addr = start;
+    while addr < end {
+        let v = val((addr - start) >> GRANULE_SHIFT);
+        // Clearing never needs to create a leaf: if there is none, the range was never
+        // registered.
+        if let Some(e) = entry(addr, v != 0) {
+            e.store(v, Ordering::Release);
+        }
+        addr += granule;
+    }
+}
+
+/// Record that `[base, base + len)` belongs to elfmalloc and holds `Slag`s of type `ty` and size
+/// `slag_size`.
+pub fn register_slags(base: *mut u8, len: usize, ty: AllocType, slag_size: usize) {
+    alloc_debug_assert!(slag_size.is_power_of_two());
+    let val = encode(Granule::Slags {
+        ty: ty,
+        slag_size: slag_size,
+    });
+    set_range(base, len, |_| val)
+}
+
+/// Record that `[base, base + len)` belongs to elfmalloc and is the mapping of a large object.
+/// `base` must be aligned to the granule size.
+pub fn register_large(base: *mut u8, len: usize) {
+    alloc_debug_assert_eq!(base as usize & ((1 << GRANULE_SHIFT) - 1), 0);
+    set_range(base, len, |index| encode(Granule::Large { index: index }))
+}
+
+/// Record that `[base, base + len)` no longer belongs to elfmalloc (e.g., because it has been
+/// unmapped).
+pub fn unregister(base: *mut u8, len: usize) {
+    set_range(base, len, |_| 0)
+}
+
+/// What the table records about the granule containing `p`, or `None` if it does not belong to
+/// elfmalloc.
+#[inline]
+pub fn granule(p: *mut u8) -> Option<Granule> {
+    // Pointers to tagged objects carry the tag in their top byte.
+    #[cfg(feature = "mte")]
+    let p = super::mte::untag(p);
+    entry(p as usize, false).and_then(|e| decode(e.load(Ordering::Acquire)))
+}
+
+/// The type of the elfmalloc memory containing `p`, or `None` if it does not belong to elfmalloc.
+#[inline]
+pub fn owner(p: *mut u8) -> Option<AllocType> {
+    granule(p).map(|g| g.ty())
+}
+
+/// Find the first granule at or after the one containing `addr` that belongs to elfmalloc, and
+/// return its address along with what the table records about it.
+pub fn next_owned(addr: usize) -> Option<(usize, Granule)> {
+    let root = ROOT.load(Ordering::Acquire);
+    if root == 0 {
+        return None;
+    }
+    let mut addr = addr & !((1 << GRANULE_SHIFT) - 1);
+    while (addr as u64) >> ADDRESS_BITS == 0 {
+        let slot = unsafe { &*(root as *const AtomicUsize).offset((addr >> LEAF_SHIFT) as isize) };
+        let leaf = slot.load(Ordering::Acquire);
+        if leaf == 0 {
+            // Skip to the start of the next leaf.
+            addr = (addr | ((1 << LEAF_SHIFT) - 1)) + 1;
+            continue;
+        }
+        let ix = (addr & ((1 << LEAF_SHIFT) - 1)) >> GRANULE_SHIFT;
+        if let Some(g) = decode(unsafe { &(*(leaf as *const Leaf))[ix] }.load(Ordering::Acquire)) {
+            return Some((addr, g));
+        }
+        addr += 1 << GRANULE_SHIFT;
+    }
+    None
+}
+
+/// Does `p` point into memory that belongs to elfmalloc?
+#[inline]
+pub fn owns(p: *mut u8) -> bool {
+    owner(p).is_some()
+}
+
+/// Install a function to be called with pointers passed to `free` that do not belong to
+/// elfmalloc, or remove it to report them as violations to the `integrity` module instead.
+///
+/// This is meant for processes in which elfmalloc coexists with another allocator, e.g. when it
+/// is interposed with `LD_PRELOAD`: the hook can forward the pointer to that allocator.
+pub fn set_foreign_free_hook(hook: Option<unsafe fn(*mut u8)>) {
+    FOREIGN_FREE.store(
+        match hook {
+            Some(f) => f as usize,
+            None => 0,
+        },
+        Ordering::Release,
+    );
+}
+
+/// Handle a call to `free` with a pointer that does not belong to elfmalloc.
+#[cold]
+pub unsafe fn foreign_free(p: *mut u8) {
+    let hook = FOREIGN_FREE.load(Ordering::Acquire);
+    if hook == 0 {
+        // If the process carries on, there is nothing to free.
+        integrity::report(Violation::ForeignFree { ptr: p });
+        return;
+    }
+    let hook: unsafe fn(*mut u8) = mem::transmute(hook);
+    hook(p)
+}
+
+/// Abort because `p`, which was passed to `func`, does not belong to elfmalloc.
+#[cold]
+pub fn foreign_pointer(func: &str, p: *mut u8) -> ! {
+    alloc_assert!(
+        false,
+        "{} of pointer {:?}, which was not allocated by elfmalloc",
+        func,
+        p
+    );
+    unreachable!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::general::global;
+
+    #[test]
+    fn heap_pointers_are_owned() {
+        unsafe {
+            for &size in &[8, 1 << 10, 100 << 10, 4 << 20] {
+                let p = global::alloc(size);
+                alloc_assert!(owns(p), "size {} not owned", size);
+                alloc_assert!(owns(p.offset(size as isize - 1)));
+                global::free(p);
+            }
+        }
+        let x = 0usize;
+        alloc_assert!(!owns(&x as *const usize as *mut u8));
+        let b = Box::new(0u64);
+        alloc_assert_eq!(owner(&*b as *const u64 as *mut u8), None);
+    }
+
+    #[test]
+    fn lookup_foreign_pointers() {
+        unsafe {
+            let x = 0usize;
+            alloc_assert_eq!(global::lookup(&x as *const usize as *mut u8), None);
+            let b = Box::new(0u64);
+            alloc_assert_eq!(global::lookup(&*b as *const u64 as *mut u8), None);
+            let p = global::alloc(4 << 20);
+            alloc_assert!(global::lookup(p).is_some());
+            // Interior pointers to large objects are recognized, but not pointers to their header.
+            alloc_assert_eq!(global::object_start(p.offset(1)), Some(p));
+            alloc_assert_eq!(global::object_start(p.offset(3 << 20)), Some(p));
+            alloc_assert_eq!(global::object_start(p.offset(-1)), None);
+            global::free(p);
+        }
+    }
+}
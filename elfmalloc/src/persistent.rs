@@ -0,0 +1,369 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Saving a heap to a file and mapping it back in later.
+//!
+//! A heap built with `ElfMallocBuilder::build_sealable_heap` records every mapping it makes in a
+//! `MapRecord`: the pages of its objects along with their `Slag` headers and bitsets, the pages it
+//! has cached, its size class metadata and its large objects. `IsolatedHeap::persist` writes an
+//! *image* of the heap - a header followed by the contents of each of these mappings - and
+//! `HeapImage::open` maps each mapping back in from the file, at the address it was saved from.
+//! Since nothing moves, pointers between objects in the image stay valid, and the *root* pointer
+//! saved in the header is the entry point to its contents. The mappings are private and read-only,
+//! and are read from the file lazily as their pages are touched, so opening even a large image is
+//! fast. This suits data that is built once and loaded on every startup, such as caches.
+//!
+//! An image is a snapshot of the heap's memory, not of the allocator: the handles' caches and the
+//! `BagPipe`s that track free pages live outside of the recorded mappings. A `HeapImage` therefore
+//! cannot be allocated from or freed into; like a sealed heap, it is read-only, and it is unmapped
+//! when dropped.
+//!
+//! # Format
+//!
+//! The file starts with a header recording a format version, and the byte order, pointer width
+//! and page size of the machine that wrote it, followed by the base address and length of each
+//! mapping. The contents of the mappings follow, each starting at an offset that is a multiple of
+//! the page size so that it can be mapped from the file directly. `open` refuses images written by
+//! another version or on a different kind of machine, as well as images whose address ranges are
+//! already in use in the process (e.g., because address space layout randomization has put a
+//! library there). Images are meant to be reopened by the same binary that wrote them, whose
+//! layout of the objects in the heap they capture.
+//!
+//! This module is only available on Linux.
+
+extern crate libc;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use super::sources::MapRecord;
+use super::utils::mmap;
+
+const MAGIC: [u8; 8] = *b"elfheap\0";
+const VERSION: u32 = 1;
+/// Reads as a different value on a machine with the opposite byte order.
+const BYTE_ORDER_MARK: u32 = 0x0102_0304;
+
+/// The header at the start of every image. It is followed by `maps` `(base, len)` pairs of `u64`s.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Header {
+    magic: [u8; 8],
+    version: u32,
+    byte_order: u32,
+    pointer_width: u32,
+    page_size: u32,
+    /// The number of mappings in the image.
+    maps: u64,
+    /// The address of the root object.
+    root: u64,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn round_up(n: usize, page_size: usize) -> usize {
+    (n + page_size - 1) & !(page_size - 1)
+}
+
+/// The offset in an image of the contents of its first mapping.
+fn data_offset(maps: usize, page_size: usize) -> usize {
+    round_up(
+        mem::size_of::<Header>() + maps * mem::size_of::<(u64, u64)>(),
+        page_size,
+    )
+}
+
+unsafe fn as_bytes<T>(t: &T) -> &[u8] {
+    slice::from_raw_parts(t as *const T as *const u8, mem::size_of::<T>())
+}
+
+/// Write an image of the mappings in `record` to `path`, with `root` as its root. Returns the
+/// number of bytes of memory saved.
+///
+/// # Safety
+///
+/// The memory of the mappings must not be modified concurrently with this call.
+pub unsafe fn write_image(record: &MapRecord, root: *const u8, path: &Path) -> io::Result<usize> {
+    let page_size = mmap::page_size();
+    let mut maps = Vec::new();
+    record.for_each(|p, len| maps.push((p as u64, round_up(len, page_size) as u64)));
+    let header = Header {
+        magic: MAGIC,
+        version: VERSION,
+        byte_order: BYTE_ORDER_MARK,
+        pointer_width: (mem::size_of::<usize>() * 8) as u32,
+        page_size: page_size as u32,
+        maps: maps.len() as u64,
+        root: root as u64,
+    };
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(as_bytes(&header))?;
+    for map in &maps {
+        w.write_all(as_bytes(map))?;
+    }
+    let table_end = mem::size_of::<Header>() + maps.len() * mem::size_of::<(u64, u64)>();
+    let padding = vec![0u8; data_offset(maps.len(), page_size) - table_end];
+    w.write_all(&padding)?;
+    let mut saved = 0;
+    for &(base, len) in &maps {
+        w.write_all(slice::from_raw_parts(base as usize as *const u8, len as usize))?;
+        saved += len as usize;
+    }
+    w.flush()?;
+    Ok(saved)
+}
+
+/// A heap image mapped back into memory. See the module documentation for details.
+pub struct HeapImage {
+    /// The image's mappings, which are unmapped when the record is dropped.
+    record: MapRecord,
+    root: *mut u8,
+}
+
+unsafe impl Send for HeapImage {}
+unsafe impl Sync for HeapImage {}
+
+impl HeapImage {
+    /// Map the image at `path` back into memory, at the addresses from which it was saved.
+    ///
+    /// Returns an error with kind `InvalidData` if the file is not an image written by this
+    /// version of elfmalloc on the same kind of machine, and one with kind `AddrInUse` if part of
+    /// the address space the image needs is already mapped.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<HeapImage> {
+        let mut file = File::open(path)?;
+        let mut buf = [0u8; 40];
+        alloc_debug_assert_eq!(buf.len(), mem::size_of::<Header>());
+        file.read_exact(&mut buf)?;
+        let header: Header = unsafe { ptr::read_unaligned(buf.as_ptr() as *const Header) };
+        if header.magic != MAGIC {
+            return Err(invalid_data("not an elfmalloc heap image"));
+        }
+        if header.byte_order == BYTE_ORDER_MARK.swap_bytes() {
+            return Err(invalid_data("heap image was written with the other byte order"));
+        }
+        if header.byte_order != BYTE_ORDER_MARK || header.version != VERSION {
+            return Err(invalid_data("unsupported heap image version"));
+        }
+        if header.pointer_width as usize != mem::size_of::<usize>() * 8 {
+            return Err(invalid_data("heap image was written with another pointer width"));
+        }
+        let page_size = mmap::page_size();
+        if header.page_size as usize != page_size {
+            return Err(invalid_data("heap image was written with another page size"));
+        }
+
+        let file_len = file.metadata()?.len();
+        let entry_size = mem::size_of::<(u64, u64)>();
+        if header.maps > (file_len - buf.len() as u64) / entry_size as u64 {
+            return Err(invalid_data("heap image is truncated"));
+        }
+        let nmaps = header.maps as usize;
+        let mut table = vec![0u8; nmaps * entry_size];
+        file.read_exact(&mut table)?;
+        let entries = table.as_ptr() as *const (u64, u64);
+        let maps: Vec<(usize, usize)> = (0..nmaps)
+            .map(|i| {
+                let (base, len) = unsafe { ptr::read_unaligned(entries.offset(i as isize)) };
+                (base as usize, len as usize)
+            })
+            .collect();
+        let mut end = data_offset(nmaps, page_size) as u64;
+        for &(base, len) in &maps {
+            if base % page_size != 0 || len % page_size != 0 {
+                return Err(invalid_data("heap image has a misaligned mapping"));
+            }
+            end += len as u64;
+        }
+        if file_len < end {
+            return Err(invalid_data("heap image is truncated"));
+        }
+
+        // Everything is mapped before the record is created: growing it maps memory, which could
+        // land where a later mapping of the image has to go.
+        let mut offset = data_offset(nmaps, page_size);
+        for (i, &(base, len)) in maps.iter().enumerate() {
+            if let Err(e) = unsafe { map_at(&file, base, len, offset) } {
+                for &(base, len) in &maps[..i] {
+                    unsafe { mmap::unmap(base as *mut u8, len) };
+                }
+                return Err(e);
+            }
+            offset += len;
+        }
+        let record = MapRecord::new();
+        for &(base, len) in &maps {
+            record.add(base as *mut u8, len);
+        }
+        Ok(HeapImage {
+            record: record,
+            root: header.root as usize as *mut u8,
+        })
+    }
+
+    /// The root pointer passed to `IsolatedHeap::persist` when the image was saved.
+    pub fn root(&self) -> *mut u8 {
+        self.root
+    }
+
+    /// The number of bytes of memory in the image.
+    pub fn mapped_bytes(&self) -> usize {
+        self.record.mapped_bytes()
+    }
+}
+
+/// Map `len` bytes of `file` starting at `offset` at `base`, privately and read-only.
+unsafe fn map_at(file: &File, base: usize, len: usize, offset: usize) -> io::Result<()> {
+    // Not exported by the libc crate. Kernels before Linux 4.17 ignore it, and treat `base` as a
+    // hint instead of failing when the range is in use; the check below catches both.
+    const MAP_FIXED_NOREPLACE: libc::c_int = 0x100000;
+    let p = libc::mmap(
+        base as *mut libc::c_void,
+        len,
+        libc::PROT_READ,
+        libc::MAP_PRIVATE | MAP_FIXED_NOREPLACE,
+        file.as_raw_fd(),
+        offset as libc::off_t,
+    );
+    if p == libc::MAP_FAILED {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EEXIST) {
+            return Err(address_in_use());
+        }
+        return Err(err);
+    }
+    if p as usize != base {
+        libc::munmap(p, len);
+        return Err(address_in_use());
+    }
+    Ok(())
+}
+
+fn address_in_use() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AddrInUse,
+        "the address range of the heap image is in use",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::alloc::allocator::{Alloc, Layout};
+    use super::super::rust_alloc::{ElfMallocBuilder, HeapHandle};
+    use std::fs;
+
+    /// A singly-linked list node.
+    struct Node {
+        value: usize,
+        next: *mut Node,
+    }
+
+    unsafe fn push(h: &mut HeapHandle, head: *mut Node, value: usize) -> *mut Node {
+        let node = h.alloc(Layout::new::<Node>()).unwrap() as *mut Node;
+        ptr::write(node, Node { value: value, next: head });
+        node
+    }
+
+    #[test]
+    fn persist_and_open() {
+        let path = format!("/tmp/elfmalloc-image-test-{}", unsafe { libc::getpid() });
+        let heap = ElfMallocBuilder::default().build_sealable_heap();
+        let mut h = heap.handle();
+        let saved = unsafe {
+            let mut head = ptr::null_mut();
+            for i in 0..1000 {
+                head = push(&mut h, head, i);
+            }
+            // A large object, which is a mapping of its own.
+            let big = h.alloc(Layout::from_size_align(1 << 20, 8).unwrap()).unwrap();
+            ptr::write_bytes(big, 0xAB, 1 << 20);
+            (*head).next = push(&mut h, (*head).next, big as usize);
+            heap.persist(&path, head as *const u8).unwrap()
+        };
+        alloc_assert!(saved >= (1 << 20));
+
+        // The heap's memory is still mapped in this process.
+        match HeapImage::open(&path) {
+            Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => {}
+            _ => panic!("opened a heap image over the live heap"),
+        }
+
+        // Other threads may map memory where the heap was once it is gone, so reopen the image in
+        // a child, which has only one thread.
+        unsafe {
+            let child = libc::fork();
+            if child == 0 {
+                mem::drop(h);
+                mem::drop(heap);
+                let ok = match HeapImage::open(&path) {
+                    Ok(image) => {
+                        let mut values = Vec::new();
+                        let mut node = image.root() as *const Node;
+                        while !node.is_null() {
+                            values.push((*node).value);
+                            node = (*node).next;
+                        }
+                        let big = values[1] as *const u8;
+                        image.mapped_bytes() == saved && values.len() == 1001 &&
+                            values[0] == 999 && values[1000] == 0 &&
+                            *big == 0xAB && *big.offset((1 << 20) - 1) == 0xAB
+                    }
+                    Err(_) => false,
+                };
+                libc::_exit(if ok { 0 } else { 1 });
+            }
+            alloc_assert!(child > 0);
+            let mut status = 0;
+            alloc_assert_eq!(libc::waitpid(child, &mut status, 0), child);
+            // The child exited normally with status 0.
+            alloc_assert_eq!(status, 0);
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn open_checks_header() {
+        let path = format!("/tmp/elfmalloc-image-header-test-{}", unsafe { libc::getpid() });
+        let mut header = Header {
+            magic: MAGIC,
+            version: VERSION,
+            byte_order: BYTE_ORDER_MARK.swap_bytes(),
+            pointer_width: (mem::size_of::<usize>() * 8) as u32,
+            page_size: mmap::page_size() as u32,
+            maps: 0,
+            root: 0,
+        };
+        let check = |header: &Header, msg: &str| {
+            File::create(&path)
+                .unwrap()
+                .write_all(unsafe { as_bytes(header) })
+                .unwrap();
+            match HeapImage::open(&path) {
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                    alloc_assert_eq!(e.to_string(), msg)
+                }
+                _ => panic!("opened an invalid heap image"),
+            }
+        };
+        check(&header, "heap image was written with the other byte order");
+        header.byte_order = BYTE_ORDER_MARK;
+        header.version = VERSION + 1;
+        check(&header, "unsupported heap image version");
+        header.version = VERSION;
+        header.maps = 1;
+        check(&header, "heap image is truncated");
+        header.magic = *b"notheap\0";
+        check(&header, "not an elfmalloc heap image");
+        let _ = fs::remove_file(&path);
+    }
+}
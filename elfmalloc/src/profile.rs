@@ -0,0 +1,243 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Build-time configuration profiles.
+//!
+//! elfmalloc has many knobs: cargo features that compile in checks and statistics,
+//! `ELFMALLOC_CONF` options (see the `conf` module), and parameters of the heap itself. A
+//! `Profile` is a coherent set of values for the knobs that are not features, and each of the
+//! `profile-fast`, `profile-small`, `profile-hardened` and `profile-debug` features selects one
+//! of the profiles below as `PROFILE`, along with the features that go with it:
+//!
+//! - `FAST` keeps more empty pages around and caches freed large objects (`large-cache`).
+//! - `SMALL` returns memory sooner: it keeps fewer empty pages, reuses partially empty `Slag`s
+//!   sooner, and frees unused thread caches after a second (`cache-decay`).
+//! - `HARDENED` randomizes object placement, checks freed objects in a quarantine, checks
+//!   pointers passed to `free` (`ownership`), and zeroes freed objects (`zero-on-free`).
+//! - `DEBUG` has a large quarantine and turns on the statistics, the per-site leak report and the
+//!   debugger helpers (`stats`, `slow-path-stats`, `leak-report`, `debug-support`).
+//!
+//! Without a profile feature, `PROFILE` is `DEFAULT`. If several are enabled (cargo features are
+//! additive, so this can happen through dependencies), the first of `debug`, `hardened`, `small`
+//! and `fast` wins; the features of all of them are enabled regardless.
+//!
+//! `ELFMALLOC_CONF` options still override the profile's defaults at runtime. The parameters of
+//! the heap apply to the global allocator and to `DynamicAllocator::new`;
+//! `DynamicAllocator::with_profile` creates a heap with the parameters of another profile.
+//!
+//! # Configs
+//!
+//! A few parameters are read on every allocation, or fix the layout of the heap, and are better
+//! known at compile time: the largest size served from size classes, the number of objects in the
+//! thread caches' magazines and the size above which the pages of an empty `Slag` are returned to
+//! the operating system eagerly. These are the associated constants of a `Config`, which the heap
+//! takes as a type parameter, so that for example the check that picks between size classes and
+//! large objects compares against a constant. Each profile has a `Config`, whose `PROFILE` is the
+//! profile, and `BuildConfig` is the one selected by the profile features. The heaps created by
+//! `DynamicAllocator::with_profile` have the constants of `BuildConfig` as well.
+
+/// A set of values for elfmalloc's knobs. See the module documentation.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Profile {
+    pub name: &'static str,
+    /// The fraction of a `Slag`'s objects that must be free before it is made available to other
+    /// threads again.
+    pub cutoff_factor: f64,
+    /// The number of bytes of empty pages that each page allocator keeps before it starts
+    /// returning them to the operating system.
+    pub target_overhead: usize,
+    /// The number of queues in the shared pools of pages and `Slag`s.
+    pub pipe_size: usize,
+    /// The default of the `randomize` option.
+    pub randomize: bool,
+    /// The default of the `shrink_threshold` option.
+    pub shrink_threshold: usize,
+    /// The default of the `cache_decay_ms` option, used with the `cache-decay` feature.
+    pub cache_decay_ms: usize,
+    /// The default of the `quarantine_size` option, used with the `quarantine` feature.
+    pub quarantine_size: usize,
+}
+
+/// The profile used without a profile feature.
+pub const DEFAULT: Profile = Profile {
+    name: "default",
+    cutoff_factor: 0.6,
+    target_overhead: 1 << 20,
+    pipe_size: 8,
+    randomize: false,
+    shrink_threshold: 64 << 10,
+    cache_decay_ms: 10_000,
+    quarantine_size: 256 << 10,
+};
+
+/// Throughput over memory usage.
+pub const FAST: Profile = Profile {
+    name: "fast",
+    target_overhead: 8 << 20,
+    shrink_threshold: 1 << 20,
+    cache_decay_ms: 60_000,
+    ..DEFAULT
+};
+
+/// Memory usage over throughput.
+pub const SMALL: Profile = Profile {
+    name: "small",
+    cutoff_factor: 0.4,
+    target_overhead: 256 << 10,
+    pipe_size: 4,
+    shrink_threshold: 16 << 10,
+    cache_decay_ms: 1_000,
+    ..DEFAULT
+};
+
+/// For programs exposed to untrusted input.
+pub const HARDENED: Profile = Profile {
+    name: "hardened",
+    randomize: true,
+    quarantine_size: 1 << 20,
+    ..DEFAULT
+};
+
+/// For finding bugs in the program, or in elfmalloc.
+pub const DEBUG: Profile = Profile {
+    name: "debug",
+    quarantine_size: 4 << 20,
+    ..DEFAULT
+};
+
+/// The profile selected by the profile features.
+#[cfg(feature = "profile-debug")]
+pub const PROFILE: Profile = DEBUG;
+#[cfg(all(feature = "profile-hardened", not(feature = "profile-debug")))]
+pub const PROFILE: Profile = HARDENED;
+#[cfg(all(feature = "profile-small",
+          not(any(feature = "profile-debug", feature = "profile-hardened"))))]
+pub const PROFILE: Profile = SMALL;
+#[cfg(all(feature = "profile-fast",
+          not(any(feature = "profile-debug", feature = "profile-hardened",
+                  feature = "profile-small"))))]
+pub const PROFILE: Profile = FAST;
+#[cfg(not(any(feature = "profile-debug", feature = "profile-hardened",
+              feature = "profile-small", feature = "profile-fast")))]
+pub const PROFILE: Profile = DEFAULT;
+
+/// Compile-time parameters of a heap, and the profile that goes with them. See the module
+/// documentation.
+pub trait Config: 'static {
+    /// The profile with the rest of the heap's parameters.
+    const PROFILE: Profile;
+    /// The largest size that is allocated from a size class. Larger objects get a mapping of their
+    /// own. This must be a power of two between 64KiB and 1MiB: objects of the largest class must
+    /// fit in the 2MiB pages of big `Slag`s.
+    const MAX_SMALL_SIZE: usize;
+    /// The number of objects of 513 bytes to 32KiB in a thread cache's magazine. Objects of up to
+    /// 512 bytes are cheap to cache and get 128 times as many, and larger objects get a magazine
+    /// of one.
+    const MAGAZINE_OBJECTS: usize;
+    /// The `Slag` size at or above which the pages of an empty `Slag` are returned to the
+    /// operating system when it is freed, rather than kept for reuse.
+    const DECOMMIT_THRESHOLD: usize;
+}
+
+/// The `Config` of `DEFAULT`.
+pub enum DefaultConfig {}
+/// The `Config` of `FAST`, which never decommits `Slag`s eagerly and has larger magazines.
+pub enum FastConfig {}
+/// The `Config` of `SMALL`, which maps objects over 256KiB individually, has smaller magazines
+/// and decommits both sizes of `Slag`.
+pub enum SmallConfig {}
+/// The `Config` of `HARDENED`.
+pub enum HardenedConfig {}
+/// The `Config` of `DEBUG`.
+pub enum DebugConfig {}
+
+impl Config for DefaultConfig {
+    const PROFILE: Profile = DEFAULT;
+    const MAX_SMALL_SIZE: usize = 1 << 20;
+    const MAGAZINE_OBJECTS: usize = 512;
+    const DECOMMIT_THRESHOLD: usize = 1 << 20;
+}
+
+impl Config for FastConfig {
+    const PROFILE: Profile = FAST;
+    const MAX_SMALL_SIZE: usize = 1 << 20;
+    const MAGAZINE_OBJECTS: usize = 1024;
+    const DECOMMIT_THRESHOLD: usize = ::std::usize::MAX;
+}
+
+impl Config for SmallConfig {
+    const PROFILE: Profile = SMALL;
+    const MAX_SMALL_SIZE: usize = 256 << 10;
+    const MAGAZINE_OBJECTS: usize = 128;
+    const DECOMMIT_THRESHOLD: usize = 256 << 10;
+}
+
+impl Config for HardenedConfig {
+    const PROFILE: Profile = HARDENED;
+    const MAX_SMALL_SIZE: usize = 1 << 20;
+    const MAGAZINE_OBJECTS: usize = 512;
+    const DECOMMIT_THRESHOLD: usize = 1 << 20;
+}
+
+impl Config for DebugConfig {
+    const PROFILE: Profile = DEBUG;
+    const MAX_SMALL_SIZE: usize = 1 << 20;
+    const MAGAZINE_OBJECTS: usize = 512;
+    const DECOMMIT_THRESHOLD: usize = 1 << 20;
+}
+
+/// The `Config` selected by the profile features, whose `PROFILE` is `PROFILE`.
+#[cfg(feature = "profile-debug")]
+pub type BuildConfig = DebugConfig;
+#[cfg(all(feature = "profile-hardened", not(feature = "profile-debug")))]
+pub type BuildConfig = HardenedConfig;
+#[cfg(all(feature = "profile-small",
+          not(any(feature = "profile-debug", feature = "profile-hardened"))))]
+pub type BuildConfig = SmallConfig;
+#[cfg(all(feature = "profile-fast",
+          not(any(feature = "profile-debug", feature = "profile-hardened",
+                  feature = "profile-small"))))]
+pub type BuildConfig = FastConfig;
+#[cfg(not(any(feature = "profile-debug", feature = "profile-hardened",
+              feature = "profile-small", feature = "profile-fast")))]
+pub type BuildConfig = DefaultConfig;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_are_sane() {
+        for p in &[DEFAULT, FAST, SMALL, HARDENED, DEBUG] {
+            alloc_assert!(p.cutoff_factor > 0.0 && p.cutoff_factor < 1.0, "{}", p.name);
+            alloc_assert!(p.pipe_size.is_power_of_two(), "{}", p.name);
+            alloc_assert!(p.target_overhead > 0, "{}", p.name);
+        }
+        alloc_assert!(SMALL.target_overhead < DEFAULT.target_overhead);
+        alloc_assert!(FAST.target_overhead > DEFAULT.target_overhead);
+        alloc_assert!(HARDENED.randomize);
+        alloc_assert!(DEBUG.quarantine_size > HARDENED.quarantine_size);
+    }
+
+    fn check_config<C: Config>() {
+        let name = C::PROFILE.name;
+        alloc_assert!(C::MAX_SMALL_SIZE.is_power_of_two(), "{}", name);
+        alloc_assert!(C::MAX_SMALL_SIZE >= 64 << 10 && C::MAX_SMALL_SIZE <= 1 << 20, "{}", name);
+        alloc_assert!(C::MAGAZINE_OBJECTS > 0, "{}", name);
+        alloc_assert!(C::DECOMMIT_THRESHOLD > 0, "{}", name);
+    }
+
+    #[test]
+    fn configs_are_sane() {
+        check_config::<DefaultConfig>();
+        check_config::<FastConfig>();
+        check_config::<SmallConfig>();
+        check_config::<HardenedConfig>();
+        check_config::<DebugConfig>();
+        alloc_assert_eq!(BuildConfig::PROFILE, PROFILE);
+    }
+}
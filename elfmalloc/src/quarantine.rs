@@ -0,0 +1,152 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Delayed reuse of freed objects.
+//!
+//! Allocators hand out recently-freed memory first, as it is likely to be in cache. This also
+//! makes use-after-free bugs easy to exploit: an attacker who can trigger a free can usually get
+//! an object of their choosing allocated in its place right away. With the `quarantine` feature
+//! enabled, each handle instead holds on to the objects freed through it in a FIFO, and only
+//! frees them for real once the FIFO exceeds a byte budget.
+//!
+//! Objects are filled with `JUNK` when they enter the quarantine, so that stale reads through
+//! dangling pointers see garbage rather than the old contents. When an object leaves the
+//! quarantine, we check that it still only contains `JUNK`. If it was written to in the meantime,
+//! the violation is reported to the `integrity` module, which aborts by default; if the process
+//! carries on, the object is leaked.
+//!
+//! Only small and medium objects are quarantined. Large objects are unmapped when they are freed,
+//! so accesses to them fault anyway.
+//!
+//! The budget is process-wide. It can be set at startup with the `quarantine_size` option of
+//! `ELFMALLOC_CONF` (see the `conf` module), and changed at any time with `set_quarantine_size`.
+//! Each handle (i.e., each thread, for the global allocator) has a quarantine of that size, so the
+//! memory overhead grows with the number of threads. Calling `trim` empties the quarantines of
+//! the handles it is called on.
+use std::collections::VecDeque;
+use super::integrity::{self, Violation};
+use super::profile::PROFILE;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The byte that quarantined objects are filled with.
+pub const JUNK: u8 = 0x5a;
+
+/// The default budget of each quarantine, which depends on the build's profile.
+pub const DEFAULT_QUARANTINE_SIZE: usize = PROFILE.quarantine_size;
+
+static QUARANTINE_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_QUARANTINE_SIZE);
+
+/// Set the number of bytes that each handle keeps in quarantine; 0 disables the quarantine.
+///
+/// Quarantines that are over the new budget shrink the next time an object is freed through
+/// them.
+pub fn set_quarantine_size(bytes: usize) {
+    QUARANTINE_SIZE.store(bytes, Ordering::Relaxed);
+}
+
+/// The number of bytes that each handle keeps in quarantine.
+pub fn quarantine_size() -> usize {
+    QUARANTINE_SIZE.load(Ordering::Relaxed)
+}
+
+/// A FIFO of freed objects.
+#[derive(Default)]
+pub struct Quarantine {
+    objects: VecDeque<(*mut u8, usize)>,
+    bytes: usize,
+}
+
+impl Quarantine {
+    pub fn new() -> Quarantine {
+        Quarantine::default()
+    }
+
+    /// Add `item`, an object of `size` bytes, to the quarantine.
+    ///
+    /// Returns `false` if the object does not fit, in which case it should be freed right away.
+    /// Objects pushed out of the quarantine must then be retrieved with `evict`.
+    pub unsafe fn push(&mut self, item: *mut u8, size: usize) -> bool {
+        if size > quarantine_size() {
+            return false;
+        }
+        ptr::write_bytes(item, JUNK, size);
+        self.objects.push_back((item, size));
+        self.bytes += size;
+        true
+    }
+
+    /// Remove the oldest object if the quarantine is over budget.
+    pub unsafe fn evict(&mut self) -> Option<*mut u8> {
+        if self.bytes <= quarantine_size() {
+            return None;
+        }
+        self.pop()
+    }
+
+    /// Remove the oldest object that was not written to while it was in the quarantine.
+    pub unsafe fn pop(&mut self) -> Option<*mut u8> {
+        while let Some((item, size)) = self.objects.pop_front() {
+            self.bytes -= size;
+            if check_junk(item, size) {
+                return Some(item);
+            }
+        }
+        None
+    }
+}
+
+/// Check that the `size` bytes at `item` are all `JUNK`, reporting a violation if they are not.
+unsafe fn check_junk(item: *mut u8, size: usize) -> bool {
+    for i in 0..size {
+        if *item.offset(i as isize) != JUNK {
+            integrity::report(Violation::WriteAfterFree {
+                object: item,
+                offset: i,
+            });
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::general::DynamicAllocator;
+
+    #[test]
+    fn freed_objects_are_not_reused_immediately() {
+        let mut alloc = DynamicAllocator::new();
+        unsafe {
+            let p = alloc.alloc(64);
+            ptr::write_bytes(p, 1, 64);
+            alloc.free(p);
+            // The contents are destroyed.
+            alloc_assert_eq!(*p, JUNK);
+            // The object does not come back until the quarantine has cycled.
+            let n = quarantine_size() / 64;
+            let ptrs: Vec<_> = (0..n / 2).map(|_| alloc.alloc(64)).collect();
+            alloc_assert!(ptrs.iter().all(|&q| q != p));
+            for q in ptrs {
+                alloc.free(q);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn write_after_free_is_detected() {
+        let mut q = Quarantine::new();
+        let mut buf = [0u8; 32];
+        unsafe {
+            alloc_assert!(q.push(buf.as_mut_ptr(), 32));
+            buf[7] = 0;
+            q.pop();
+        }
+    }
+}
@@ -0,0 +1,420 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A process-wide limit on allocated memory.
+//!
+//! With the `quota` feature enabled, every object handed out by the allocator is charged against
+//! a global counter, and the counter is checked against a limit set with `set_global_limit`. An
+//! allocation that would exceed the limit fails - `malloc` returns `NULL` and the `Alloc`
+//! implementations return `AllocErr::Exhausted` - rather than growing the process until the
+//! kernel's OOM killer steps in.
+//!
+//! Objects are charged their usable size (the size of their size class, or the size of the
+//! mapping for large objects), so the counter includes internal fragmentation but not memory
+//! cached by the allocator, nor metadata.
+//!
+//! Before failing, the allocator calls the hook installed with `set_oom_hook`, if any. The hook is
+//! passed the size of the failing request, and can release memory (e.g., by dropping caches of
+//! its own) and return `true` to have the allocation retried, or return `false` to let it fail. A
+//! hook that keeps returning `true` without freeing anything will make the allocation loop
+//! forever. The hook must not allocate.
+//!
+//! An `IsolatedHeap` can also be given a limit of its own with `IsolatedHeap::set_limit`, which
+//! does not require this feature.
+//!
+//! # Per-thread accounting
+//!
+//! Each thread also keeps a count of the bytes it has allocated minus the bytes it has freed,
+//! which can be read with `thread_allocated` and limited with `set_thread_limit`. Objects are
+//! credited to the thread that frees them, so a thread that frees objects allocated elsewhere
+//! (e.g., the consumer of a queue) can have a negative count. The counters are plain thread-local
+//! variables, so they cost no more than the global counter to maintain.
+//!
+//! # Peak usage
+//!
+//! The high-water marks of the global and per-thread counters are kept as well, and can be read
+//! with `peak_allocated` and `thread_peak_allocated`. On Linux, `peak_resident` reports the peak
+//! resident set size of the process as tracked by the kernel, which also includes memory cached
+//! by the allocator, its metadata, and memory not allocated through elfmalloc at all. A benchmark
+//! or a capacity planning tool can call `reset_peaks` at the start of each phase it measures to
+//! restart all of them from the current values. `IsolatedHeap`s keep a peak of their own (see
+//! `HeapStats::peak_live_bytes`).
+#[cfg(target_os = "linux")]
+extern crate libc;
+
+use super::utils::CachePadded;
+use std::cell::Cell;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+/// A byte counter with an optional limit.
+struct Quota {
+    /// The limit in bytes, or 0 if there is none. This is read by every allocation but rarely
+    /// written, so it is kept out of the cache line of the counters.
+    limit: CachePadded<AtomicUsize>,
+    allocated: AtomicUsize,
+    /// The maximum value of `allocated` since the last reset.
+    peak: AtomicUsize,
+}
+
+impl Quota {
+    #[inline]
+    fn charge(&self, bytes: usize) -> bool {
+        loop {
+            let limit = self.limit.load(Ordering::Relaxed);
+            let prev = self.allocated.fetch_add(bytes, Ordering::Relaxed);
+            if limit == 0 || prev + bytes <= limit {
+                self.raise_peak(prev + bytes);
+                return true;
+            }
+            self.allocated.fetch_sub(bytes, Ordering::Relaxed);
+            if !oom(bytes) {
+                return false;
+            }
+        }
+    }
+
+    #[inline]
+    fn uncharge(&self, bytes: usize) {
+        self.allocated.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn raise_peak(&self, allocated: usize) {
+        let mut peak = self.peak.load(Ordering::Relaxed);
+        while allocated > peak {
+            match self.peak.compare_exchange_weak(
+                peak,
+                allocated,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(cur) => peak = cur,
+            }
+        }
+    }
+}
+
+thread_local! {
+    /// The current thread's allocated bytes, its limit (or 0 if there is none), and the peak of
+    /// its allocated bytes since the last reset.
+    static THREAD: (Cell<isize>, Cell<usize>, Cell<isize>) =
+        (Cell::new(0), Cell::new(0), Cell::new(0));
+}
+
+static GLOBAL: Quota = Quota {
+    limit: CachePadded(ATOMIC_USIZE_INIT),
+    allocated: ATOMIC_USIZE_INIT,
+    peak: ATOMIC_USIZE_INIT,
+};
+/// The OOM hook, as a `fn(usize) -> bool`, or 0 if there is none.
+static HOOK: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Limit the number of bytes allocated by the process to `bytes`; 0 removes the limit.
+///
+/// Objects which are already allocated are not affected, even if they exceed the new limit.
+pub fn set_global_limit(bytes: usize) {
+    GLOBAL.limit.store(bytes, Ordering::Relaxed);
+}
+
+/// The current limit, if any.
+pub fn global_limit() -> Option<usize> {
+    match GLOBAL.limit.load(Ordering::Relaxed) {
+        0 => None,
+        limit => Some(limit),
+    }
+}
+
+/// The number of bytes currently charged against the limit.
+pub fn global_allocated() -> usize {
+    GLOBAL.allocated.load(Ordering::Relaxed)
+}
+
+/// Install a hook to be called when an allocation would exceed the limit, or remove it.
+///
+/// See the module documentation for details.
+pub fn set_oom_hook(hook: Option<fn(usize) -> bool>) {
+    HOOK.store(
+        match hook {
+            Some(f) => f as usize,
+            None => 0,
+        },
+        Ordering::Release,
+    );
+}
+
+/// The number of bytes allocated by the current thread, minus the number of bytes it has freed.
+pub fn thread_allocated() -> isize {
+    THREAD.try_with(|t| t.0.get()).unwrap_or(0)
+}
+
+/// Limit the value of `thread_allocated` for the current thread to `bytes`; 0 removes the limit.
+///
+/// Allocations on the current thread that would exceed the limit fail as if they exceeded the
+/// global limit.
+pub fn set_thread_limit(bytes: usize) {
+    let _ = THREAD.try_with(|t| t.1.set(bytes));
+}
+
+/// The current thread's limit, if any.
+pub fn thread_limit() -> Option<usize> {
+    match THREAD.try_with(|t| t.1.get()).unwrap_or(0) {
+        0 => None,
+        limit => Some(limit),
+    }
+}
+
+/// The highest value of `global_allocated` since the last call to `reset_peaks`.
+pub fn peak_allocated() -> usize {
+    GLOBAL.peak.load(Ordering::Relaxed)
+}
+
+/// The highest value of `thread_allocated` on the current thread since the last call to
+/// `reset_peaks` on this thread.
+pub fn thread_peak_allocated() -> isize {
+    THREAD.try_with(|t| t.2.get()).unwrap_or(0)
+}
+
+/// Restart `peak_allocated`, the current thread's `thread_peak_allocated` and, on Linux,
+/// `peak_resident` from their current values.
+///
+/// Other threads' peaks are not affected.
+pub fn reset_peaks() {
+    GLOBAL.peak.store(GLOBAL.allocated.load(Ordering::Relaxed), Ordering::Relaxed);
+    let _ = THREAD.try_with(|t| t.2.set(t.0.get()));
+    #[cfg(target_os = "linux")]
+    reset_peak_resident();
+}
+
+/// The peak resident set size of the process in bytes, as reported by the `VmHWM` line of
+/// `/proc/self/status`, or `None` if it cannot be read.
+#[cfg(target_os = "linux")]
+pub fn peak_resident() -> Option<usize> {
+    // Read the file into a buffer on the stack, as this may be called from within the allocator.
+    let mut buf = [0u8; 8192];
+    let len = unsafe {
+        let fd = libc::open(b"/proc/self/status\0".as_ptr() as *const libc::c_char, libc::O_RDONLY);
+        if fd < 0 {
+            return None;
+        }
+        let mut len = 0;
+        while len < buf.len() {
+            let n = libc::read(
+                fd,
+                buf[len..].as_mut_ptr() as *mut libc::c_void,
+                buf.len() - len,
+            );
+            if n <= 0 {
+                break;
+            }
+            len += n as usize;
+        }
+        libc::close(fd);
+        len
+    };
+    for line in buf[..len].split(|&b| b == b'\n') {
+        if !line.starts_with(b"VmHWM:") {
+            continue;
+        }
+        let mut kib: usize = 0;
+        for &d in line[6..].iter().filter(|&&d| d >= b'0' && d <= b'9') {
+            kib = kib * 10 + (d - b'0') as usize;
+        }
+        return Some(kib << 10);
+    }
+    None
+}
+
+/// Reset the kernel's peak resident set size to the current one. This is supported since Linux
+/// 4.0; on older kernels it does nothing.
+#[cfg(target_os = "linux")]
+fn reset_peak_resident() {
+    unsafe {
+        let fd = libc::open(
+            b"/proc/self/clear_refs\0".as_ptr() as *const libc::c_char,
+            libc::O_WRONLY,
+        );
+        if fd >= 0 {
+            libc::write(fd, b"5".as_ptr() as *const libc::c_void, 1);
+            libc::close(fd);
+        }
+    }
+}
+
+/// Charge `bytes` against the current thread's limit, if it has one.
+#[inline]
+fn charge_thread(bytes: usize) -> bool {
+    // During thread teardown, the thread's counters are gone and allocations go unaccounted.
+    THREAD
+        .try_with(|t| loop {
+            let allocated = t.0.get() + bytes as isize;
+            let limit = t.1.get();
+            if limit == 0 || allocated <= limit as isize {
+                t.0.set(allocated);
+                if allocated > t.2.get() {
+                    t.2.set(allocated);
+                }
+                return true;
+            }
+            if !oom(bytes) {
+                return false;
+            }
+        })
+        .unwrap_or(true)
+}
+
+/// Charge `bytes` against the global limit and the current thread's limit, returning `false` if
+/// that would exceed either of them.
+#[inline]
+pub fn charge(bytes: usize) -> bool {
+    if !GLOBAL.charge(bytes) {
+        return false;
+    }
+    if !charge_thread(bytes) {
+        GLOBAL.uncharge(bytes);
+        return false;
+    }
+    true
+}
+
+/// Return `bytes` previously charged with `charge`, possibly on another thread.
+#[inline]
+pub fn uncharge(bytes: usize) {
+    GLOBAL.uncharge(bytes);
+    let _ = THREAD.try_with(|t| t.0.set(t.0.get() - bytes as isize));
+}
+
+#[cold]
+fn oom(bytes: usize) -> bool {
+    let hook = HOOK.load(Ordering::Acquire);
+    if hook == 0 {
+        return false;
+    }
+    let hook: fn(usize) -> bool = unsafe { mem::transmute(hook) };
+    hook(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Setting the global limit would make allocations in concurrently running tests fail, so we
+    // test a private `Quota` instead. The allocation paths are exercised by the per-heap limit
+    // tests in `rust_alloc`.
+    #[test]
+    fn charge_respects_limit() {
+        let quota = Quota {
+            limit: AtomicUsize::new(100),
+            allocated: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        };
+        alloc_assert!(quota.charge(60));
+        alloc_assert!(quota.charge(40));
+        alloc_assert!(!quota.charge(1));
+        alloc_assert_eq!(quota.allocated.load(Ordering::Relaxed), 100);
+        quota.uncharge(50);
+        alloc_assert!(quota.charge(50));
+        quota.limit.store(0, Ordering::Relaxed);
+        alloc_assert!(quota.charge(1 << 40));
+    }
+
+    #[test]
+    fn peaks() {
+        let quota = Quota {
+            limit: AtomicUsize::new(0),
+            allocated: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        };
+        alloc_assert!(quota.charge(60));
+        quota.uncharge(60);
+        alloc_assert!(quota.charge(40));
+        alloc_assert_eq!(quota.peak.load(Ordering::Relaxed), 60);
+
+        use super::super::general::DynamicAllocator;
+        use std::thread;
+        thread::spawn(|| unsafe {
+            let mut alloc = DynamicAllocator::new();
+            reset_peaks();
+            let base = thread_allocated();
+            let ptrs: Vec<_> = (0..16).map(|_| alloc.alloc(1 << 10)).collect();
+            for p in ptrs {
+                alloc.free(p);
+            }
+            alloc_assert!(thread_peak_allocated() >= base + (16 << 10));
+            alloc_assert!(peak_allocated() >= 16 << 10);
+            reset_peaks();
+            alloc_assert_eq!(thread_peak_allocated(), thread_allocated());
+        }).join()
+            .unwrap();
+        #[cfg(target_os = "linux")]
+        alloc_assert!(peak_resident().unwrap() > 0);
+    }
+
+    #[test]
+    fn thread_limit_is_enforced() {
+        use super::super::general::DynamicAllocator;
+        use std::thread;
+
+        // Run on a fresh thread so that the limit does not affect other tests.
+        thread::spawn(|| unsafe {
+            let mut alloc = DynamicAllocator::new();
+            let base = thread_allocated();
+            let p = alloc.alloc(1 << 10);
+            alloc_assert_eq!(thread_allocated(), base + (1 << 10));
+            alloc.free(p);
+            alloc_assert_eq!(thread_allocated(), base);
+
+            set_thread_limit((base + (64 << 10)) as usize);
+            alloc_assert_eq!(thread_limit(), Some((base + (64 << 10)) as usize));
+            let mut ptrs = Vec::with_capacity(128);
+            loop {
+                let p = alloc.alloc(1 << 10);
+                if p.is_null() {
+                    break;
+                }
+                ptrs.push(p);
+                alloc_assert!(ptrs.len() <= 64, "thread limit was not enforced");
+            }
+            alloc_assert!(!ptrs.is_empty());
+            set_thread_limit(0);
+            for p in ptrs {
+                alloc.free(p);
+            }
+            alloc_assert_eq!(thread_allocated(), base);
+        }).join()
+            .unwrap();
+    }
+
+    #[cfg(feature = "sites")]
+    #[test]
+    fn site_alloc_over_limit_fails() {
+        use super::super::general::DynamicAllocator;
+        use std::thread;
+
+        let site = alloc_site!();
+        thread::spawn(move || unsafe {
+            let mut alloc = DynamicAllocator::new();
+            set_thread_limit((thread_allocated() + (64 << 10)) as usize);
+            let mut ptrs = Vec::with_capacity(128);
+            loop {
+                let p = alloc.alloc_with_site(1 << 10, site);
+                if p.is_null() {
+                    break;
+                }
+                ptrs.push(p);
+                alloc_assert!(ptrs.len() <= 64, "thread limit was not enforced");
+            }
+            alloc_assert!(!ptrs.is_empty());
+            set_thread_limit(0);
+            for p in ptrs {
+                alloc.free(p);
+            }
+        }).join()
+            .unwrap();
+    }
+}
@@ -71,91 +71,279 @@
 //! this because we can use the size passed in at the call site to determine the allocator to which
 //! a given object belongs. This is also the trick that allows us to handle medium objects
 //! specially: in the other system, they would need their own `Creek`.
+//!
+//! The heaps of owning handles and sealable `IsolatedHeap`s use a `TrackedSource` instead, which
+//! records each mapping in a `MapRecord` so that all of the heap's memory can be unmapped or
+//! protected at once. Their pages are never unmapped individually, only uncommitted.
+//!
+//! # Locking
+//!
+//! The allocation and free paths take no locks, with these exceptions, none of which a small
+//! allocation ever waits on:
+//!
+//! - `BuddySource` (medium objects with `MediumBackend::Buddy`) is protected by a lock, taken only
+//!   when the per-thread caches in front of it miss or overflow.
+//! - A `MapRecord` is protected by a lock, taken when a heap that keeps one maps more memory or
+//!   maps or unmaps a large object. The record is sorted by address, so the lock is only held for
+//!   a binary search and a `memmove`.
+//! - The channels to the background thread are behind locks, taken when a thread exits or hands
+//!   off a batch of unmaps.
+//! - `HandlePool` and `LockedPool` lock their own state, and are separate from the heap.
 extern crate num_cpus;
 
 use super::alloc::allocator::{Alloc, AllocErr, Layout};
-use super::general::{Multiples, PowersOfTwo, ObjectAlloc, MULTIPLE, AllocMap};
-use super::slag::{PageAlloc, Metadata, RevocablePipe, compute_metadata, SlagPipe, PageCleanup};
+use super::general::{PowersOfTwo, ObjectAlloc, MULTIPLE, AllocMap};
+use super::slag::{PageAlloc, PageSource, Metadata, RevocablePipe, compute_metadata, SlagPipe,
+                  PageCleanup};
 #[allow(unused_imports)]
 use super::frontends::{Depot, Frontend};
-use super::utils::{mmap, Lazy, LazyInitializable};
+use super::utils::{mmap, CachePadded, Lazy, LazyInitializable, TypedArray};
+use super::utils::mmap::MapName;
 use super::sources::MemorySource;
 use super::bagpipe::bag::WeakBag;
-use super::sources::MmapSource;
+use super::sources::{MapRecord, MmapSource, TrackedSource};
 use super::alloc_type::AllocType;
+use super::profile::{BuildConfig, Config};
+use super::buddy::BuddySource;
+use super::error::{self, ElfAllocError};
+#[cfg(feature = "asan")]
+use super::asan;
+#[cfg(feature = "valgrind")]
+use super::valgrind;
+#[cfg(feature = "tsan")]
+use super::tsan;
+#[cfg(feature = "msan")]
+use super::msan;
+#[cfg(feature = "mte")]
+use super::mte;
+#[cfg(feature = "zero-on-free")]
+use super::zero;
+#[cfg(feature = "quota")]
+use super::quota;
+#[cfg(feature = "alloc-guard")]
+use super::alloc_guard;
 
 use std::cmp;
-use std::mem;
+use std::mem::{self, ManuallyDrop};
 use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-/// A shared concurrent data-structure for caching large objects.
+/// The page-level backend used for medium objects.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MediumBackend {
+    /// Every medium object occupies its own page of a shared `PageSource`. Pages beyond the
+    /// configured cutoff are partially uncommitted when they are cached.
+    Pages,
+    /// Medium objects are carved out of larger blocks by a buddy allocator, so each object only
+    /// uses the next power of two of its size.
+    Buddy,
+}
+
+/// How the sizes of small objects are rounded up to size classes.
 ///
-/// A single `PageSource` is used as a backing store for several `PageFrontend`s, each with a
-/// separate object size less than or equal to the page size of `M`. This structure allows for
-/// unused pages freed from one size class to be used to service allocations in another size class.
+/// Finer-grained classes waste less memory inside each object, but spread a workload's objects
+/// over more classes, each with its own partially-filled `Slag`s. Which of these costs dominates
+/// depends on the distribution of object sizes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SizeClassStrategy {
+    /// Multiples of 16 bytes: 16, 32, 48, 64, ... This is the default.
+    Quantum,
+    /// Powers of two: 16, 32, 64, 128, ...
+    PowersOfTwo,
+    /// Four classes per doubling, in the manner of jemalloc: multiples of 16 bytes up to 64, and
+    /// then four evenly-spaced classes up to each power of two: 80, 96, 112, 128, 160, 192, ...
+    Geometric,
+}
+
+impl SizeClassStrategy {
+    /// The index of the class serving objects of `size` bytes.
+    fn class_index(self, size: usize) -> usize {
+        let size = cmp::max(size, 1);
+        match self {
+            SizeClassStrategy::Quantum => (size - 1) / MULTIPLE,
+            SizeClassStrategy::PowersOfTwo => {
+                (cmp::max(size, MULTIPLE).next_power_of_two().trailing_zeros() -
+                     MULTIPLE.trailing_zeros()) as usize
+            }
+            SizeClassStrategy::Geometric => {
+                if size <= 4 * MULTIPLE {
+                    return (size - 1) / MULTIPLE;
+                }
+                // The classes above 2^k and up to 2^(k+1) are 2^(k-2) apart.
+                let m = size - 1;
+                let k = mem::size_of::<usize>() * 8 - 1 - m.leading_zeros() as usize;
+                let step = 1 << (k - 2);
+                4 + (k - 6) * 4 + (m - (1 << k)) / step
+            }
+        }
+    }
+
+    /// The object size of the class with index `ix`.
+    fn class_size(self, ix: usize) -> usize {
+        match self {
+            SizeClassStrategy::Quantum => (ix + 1) * MULTIPLE,
+            SizeClassStrategy::PowersOfTwo => MULTIPLE << ix,
+            SizeClassStrategy::Geometric => {
+                if ix < 4 {
+                    return (ix + 1) * MULTIPLE;
+                }
+                let k = 6 + (ix - 4) / 4;
+                (1 << k) + ((ix - 4) % 4 + 1) * (1 << (k - 2))
+            }
+        }
+    }
+}
+
+/// The object size of the tiny size class.
+pub const TINY_SIZE: usize = 8;
+
+/// The mapping from object sizes to small size classes: the classes of a `SizeClassStrategy`,
+/// optionally preceded by a class of `TINY_SIZE` bytes.
 ///
-/// The `PageSource` can be configured to decommit a portion of memory reclaimed from object
-/// classes whose size exceeds a certain threshold.
-#[derive(Clone)]
-struct PageSource<M: MemorySource> {
-    cutoff_bytes: usize,
-    target_size: usize,
-    pages: SlagPipe<u8>,
-    source: M,
+/// Like all small objects, tiny objects have no header: a `Slag` only spends one bit of its bitset
+/// per object, so 8-byte objects cost 8 bytes plus about 1.6% of overhead rather than being
+/// rounded up to 16 bytes.
+#[derive(Copy, Clone)]
+struct ClassMap {
+    strategy: SizeClassStrategy,
+    tiny: bool,
 }
 
-impl<M: MemorySource> PageSource<M> {
-    fn new(
-        cutoff_bytes: usize,
-        target_size: usize,
-        pipe_size: usize,
-        page_size: usize,
-    ) -> PageSource<M> {
-        let m = M::new(page_size);
-        PageSource {
-            cutoff_bytes: cutoff_bytes,
-            target_size: target_size,
-            pages: SlagPipe::new_size_cleanup(pipe_size, PageCleanup::new(m.page_size())),
-            source: m,
+impl ClassMap {
+    /// The index of the class serving objects of `size` bytes.
+    #[inline(always)]
+    fn index(self, size: usize) -> usize {
+        if !self.tiny {
+            self.strategy.class_index(size)
+        } else if size <= TINY_SIZE {
+            0
+        } else {
+            1 + self.strategy.class_index(size)
         }
     }
 
-    unsafe fn free(&mut self, p: *mut u8, old_size: usize) {
-        if self.pages.size_guess() >= self.target_size as isize {
-            mmap::unmap(p, self.source.page_size());
-            return;
+    /// The object size of the class with index `ix`.
+    #[inline(always)]
+    fn size_at(self, ix: usize) -> usize {
+        if !self.tiny {
+            self.strategy.class_size(ix)
+        } else if ix == 0 {
+            TINY_SIZE
+        } else {
+            self.strategy.class_size(ix - 1)
         }
-        if old_size >= self.cutoff_bytes {
-            mmap::uncommit(
-                p.offset(self.cutoff_bytes as isize),
-                self.source.page_size() - self.cutoff_bytes,
-            );
+    }
+}
+
+/// The small size classes of an `ElfMalloc`, laid out according to a `ClassMap`.
+struct SizeClasses<T> {
+    map: ClassMap,
+    max_size: usize,
+    classes: TypedArray<T>,
+}
+
+impl<T> SizeClasses<T> {
+    /// Create a class for every size up to `max_size`, which must be a class size, by calling `f`
+    /// with the size of each class.
+    fn init<F: FnMut(usize) -> T>(map: ClassMap, max_size: usize, mut f: F) -> Self {
+        let n_classes = map.index(max_size) + 1;
+        alloc_debug_assert_eq!(map.size_at(n_classes - 1), max_size);
+        let res = SizeClasses {
+            map: map,
+            max_size: max_size,
+            classes: TypedArray::new(n_classes),
+        };
+        for (ix, p) in res.classes.iter().enumerate() {
+            unsafe { ptr::write(p, f(map.size_at(ix))) };
         }
-        self.pages.push_mut(p);
+        res
     }
 
-    unsafe fn alloc(&mut self) -> Option<*mut u8> {
-        self.pages.pop_mut().or_else(|| {
-            let npages = 4;
-            self.source.carve(npages).and_then(|pages| {
-                for i in 1..npages {
-                    let offset = (i * self.source.page_size()) as isize;
-                    self.pages.push_mut(pages.offset(offset));
-                }
-                Some(pages)
-            })
+    /// The object size of the class serving objects of `size` bytes.
+    #[inline(always)]
+    fn class_size(&self, size: usize) -> usize {
+        self.map.size_at(self.map.index(size))
+    }
+
+    #[inline(always)]
+    unsafe fn get_mut(&mut self, size: usize) -> &mut T {
+        alloc_debug_assert!(size <= self.max_size);
+        &mut *self.classes.get(self.map.index(size))
+    }
+
+    #[inline]
+    fn max_key(&self) -> usize {
+        self.max_size
+    }
+
+    fn foreach<F: Fn(*mut T)>(&self, f: F) {
+        for class in self.classes.iter() {
+            f(class)
+        }
+    }
+}
+
+impl<T: Clone> Clone for SizeClasses<T> {
+    fn clone(&self) -> Self {
+        let mut classes = self.classes.iter();
+        SizeClasses::init(self.map, self.max_size, |_| unsafe {
+            (*classes.next().unwrap()).clone()
         })
     }
 }
 
+/// The source of memory for `PageFrontend`s.
+#[derive(Clone)]
+enum MediumSource<M: MemorySource> {
+    Pages(PageSource<M>),
+    Buddy(BuddySource<M>),
+}
+
+impl<M: MemorySource> MediumSource<M> {
+    fn max_size(&self) -> usize {
+        match *self {
+            MediumSource::Pages(ref p) => p.page_size(),
+            MediumSource::Buddy(ref b) => b.max_size(),
+        }
+    }
+
+    unsafe fn alloc(&mut self, size: usize) -> Option<*mut u8> {
+        match *self {
+            MediumSource::Pages(ref mut p) => p.alloc(),
+            MediumSource::Buddy(ref b) => b.alloc(size),
+        }
+    }
+
+    unsafe fn free(&mut self, item: *mut u8, size: usize) {
+        match *self {
+            MediumSource::Pages(ref mut p) => p.free(item, size),
+            MediumSource::Buddy(ref b) => b.free(item, size),
+        }
+    }
+
+    fn set_name(&mut self, name: MapName) {
+        match *self {
+            MediumSource::Pages(ref mut p) => p.set_name(name),
+            MediumSource::Buddy(ref b) => b.set_name(name),
+        }
+    }
+
+    fn unmaps_pages(&self) -> bool {
+        match *self {
+            MediumSource::Pages(ref p) => p.unmaps_pages(),
+            MediumSource::Buddy(ref b) => b.unmaps_pages(),
+        }
+    }
+}
 
 /// An allocator used for allocating large objects.
 ///
 /// A `PageFrontend` is a thin wrapper around a `BagPipe`, where additional memory is acquired from
-/// a `PageSource`.
+/// a `MediumSource`.
 #[derive(Clone)]
 struct PageFrontend<M: MemorySource> {
-    parent: PageSource<M>,
+    parent: MediumSource<M>,
     pages: SlagPipe<u8>,
     local_size: usize,
     max_overhead: usize,
@@ -166,20 +354,22 @@ impl<M: MemorySource> PageFrontend<M> {
         size: usize,
         max_overhead: usize,
         pipe_size: usize,
-        parent: PageSource<M>,
+        parent: MediumSource<M>,
     ) -> PageFrontend<M> {
-        alloc_debug_assert!(size <= parent.source.page_size());
+        alloc_debug_assert!(size <= parent.max_size());
         alloc_debug_assert!(size.is_power_of_two());
+        let cleanup = PageCleanup::unmapping(size, parent.unmaps_pages());
         PageFrontend {
             parent: parent,
-            pages: SlagPipe::new_size_cleanup(pipe_size, PageCleanup::new(size)),
+            pages: SlagPipe::new_size_cleanup(pipe_size, cleanup),
             local_size: size,
             max_overhead: max_overhead,
         }
     }
 
     unsafe fn alloc(&mut self) -> Option<*mut u8> {
-        self.pages.pop_mut().or_else(|| self.parent.alloc())
+        let size = self.local_size;
+        self.pages.pop_mut().or_else(|| self.parent.alloc(size))
     }
 
     unsafe fn free(&mut self, item: *mut u8) {
@@ -194,7 +384,7 @@ impl<M: MemorySource> PageFrontend<M> {
 }
 
 impl<M: MemorySource + Clone> LazyInitializable for PageFrontend<M> {
-    type Params = (usize, usize, usize, PageSource<M>);
+    type Params = (usize, usize, usize, MediumSource<M>);
     fn init(&(size, max_overhead, pipe_size, ref parent): &Self::Params) -> Self {
         PageFrontend::new(size, max_overhead, pipe_size, parent.clone())
     }
@@ -204,8 +394,27 @@ impl<M: MemorySource + Clone> LazyInitializable for PageFrontend<M> {
 /// handle larger allocations.
 #[derive(Clone)]
 pub struct ElfMalloc<M: MemorySource> {
-    small: Multiples<ObjectAlloc<PageAlloc<M>>>,
+    small: SizeClasses<ObjectAlloc<PageAlloc<M>>>,
     large: PowersOfTwo<Lazy<PageFrontend<M>>>,
+    /// The name given to the mappings of large objects.
+    large_name: MapName,
+    /// The record of all of the heap's mappings, if it keeps one (see `TrackedSource`).
+    record: Option<Arc<MapRecord>>,
+    /// The bytes charged to the quota for the live objects of a heap with a record, which are
+    /// uncharged when the heap is released.
+    #[cfg(feature = "quota")]
+    charged: Option<Arc<HeapCharge>>,
+}
+
+/// The quota charged for the live objects of a heap, given back when it is dropped.
+#[cfg(feature = "quota")]
+struct HeapCharge(AtomicUsize);
+
+#[cfg(feature = "quota")]
+impl Drop for HeapCharge {
+    fn drop(&mut self) {
+        quota::uncharge(self.0.load(Ordering::Relaxed));
+    }
 }
 
 /// Following the structure of the `general` module, we keep the underlying `ElfMalloc` struct with
@@ -213,8 +422,52 @@ pub struct ElfMalloc<M: MemorySource> {
 /// thread local storage.
 ///
 /// `OwnedElfMalloc` is the standard version, where the destructor reclaims any cached memory.
-#[derive(Clone)]
-pub struct OwnedElfMalloc<M: MemorySource>(pub ElfMalloc<M>);
+///
+/// An owning `OwnedElfMalloc` (see `owning`) instead allocates from a heap of its own, whose
+/// pages, `Slag`s and metadata are all released at once by `free_all` or when the handle is
+/// dropped. This turns the handle into a lightweight region: freeing its objects costs a handful of
+/// `munmap` calls, however many of them there are, and nothing is tracked per object.
+pub struct OwnedElfMalloc<M: MemorySource>(pub ElfMalloc<M>, Option<ElfMallocBuilder>);
+
+impl<M: MemorySource> Clone for OwnedElfMalloc<M> {
+    /// Clones share the underlying allocator. The heap of an owning handle is only released once
+    /// each of its clones has been dropped or has called `free_all`.
+    fn clone(&self) -> Self {
+        OwnedElfMalloc(self.0.clone(), self.1.clone())
+    }
+}
+
+impl<M: MemorySource> OwnedElfMalloc<M> {
+    pub fn new(inner: ElfMalloc<M>) -> OwnedElfMalloc<M> {
+        OwnedElfMalloc(inner, None)
+    }
+
+    /// Does this handle allocate from a heap of its own?
+    pub fn is_owning(&self) -> bool {
+        self.1.is_some()
+    }
+}
+
+impl OwnedElfMalloc<TrackedSource> {
+    /// Create a handle in owning mode, allocating from a new heap configured by `builder`.
+    pub fn owning(builder: &ElfMallocBuilder) -> OwnedElfMalloc<TrackedSource> {
+        OwnedElfMalloc(builder.build_tracked(), Some(builder.clone()))
+    }
+
+    /// Free every object allocated through this handle, if it is in owning mode, by releasing its
+    /// heap. The handle then starts over with a new heap.
+    ///
+    /// # Safety
+    ///
+    /// None of the freed objects may be used after this call.
+    pub unsafe fn free_all(&mut self) {
+        let fresh = match self.1 {
+            Some(ref builder) => builder.build_tracked(),
+            None => return,
+        };
+        mem::drop(OwnedElfMalloc::new(mem::replace(&mut self.0, fresh)));
+    }
+}
 
 
 impl<M: MemorySource> ::std::ops::Deref for OwnedElfMalloc<M> {
@@ -244,9 +497,9 @@ impl<M: MemorySource> ElfMalloc<M> {
 
 impl<M: MemorySource> Drop for OwnedElfMalloc<M> {
     fn drop(&mut self) {
-        unsafe {
-            self.0.destroy();
-        }
+        // If this is the last handle to a heap with a record, dropping the record after this
+        // unmaps all of the heap's memory.
+        unsafe { self.0.destroy() }
     }
 }
 
@@ -265,47 +518,46 @@ macro_rules! case_analyze {
     }
 }
 
+#[cold]
+fn invalid_layout(l: &Layout) -> ElfAllocError {
+    ElfAllocError::InvalidLayout {
+        size: l.size(),
+        align: l.align(),
+    }
+}
+
+/// The size used to look up the small size class that serves `l`.
+///
+/// Objects in power-of-two size classes are aligned to their size, so over-aligned objects are
+/// allocated from the power-of-two class that is at least as large as both their size and their
+/// alignment.
+#[inline(always)]
+fn small_key(l: &Layout) -> usize {
+    if l.align() > mem::size_of::<usize>() {
+        cmp::max(l.size(), l.align()).next_power_of_two()
+    } else {
+        l.size()
+    }
+}
+
 unsafe impl<M: MemorySource> Alloc for ElfMalloc<M> {
     #[inline(always)]
     unsafe fn alloc(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
-        trace!("alloc({:?})", l);
-        case_analyze!(
-            self,
-            l,
-            small Ok(
-                self.small
-                    .get_mut(if l.align() > mem::size_of::<usize>() {
-                        l.size().next_power_of_two()
-                    } else {
-                        l.size()
-                    })
-                    .alloc(),
-            );
-            medium match self.large.get_mut(l.size()).alloc() {
-                Some(p) => Ok(p),
-                None => Err(AllocErr::Exhausted { request: l }),
-            };
-            large match mmap::fallible_map(l.size()) {
-                Some(p) => Ok(p),
-                None => Err(AllocErr::Exhausted { request: l }),
-            };
-        )
+        self.try_alloc(l).map_err(AllocErr::from)
     }
 
     #[inline(always)]
     unsafe fn dealloc(&mut self, item: *mut u8, l: Layout) {
         trace!("dealloc({:?}, {:?})", item, l);
-        case_analyze!(
-            self,
-            l,
-            small self.small.get_mut(if l.align() > mem::size_of::<usize>() {
-                        l.size().next_power_of_two()
-                    } else {
-                        // round up to nearest MULTIPLE
-                        (l.size() + (MULTIPLE - 1)) & !(MULTIPLE - 1)
-                    }).free(item);
-            medium self.large.get_mut(l.size()).free(item);
-            large mmap::unmap(item, l.size());)
+        #[cfg(feature = "quota")]
+        {
+            let bytes = self.usable_size(&l).1;
+            quota::uncharge(bytes);
+            if let Some(ref charged) = self.charged {
+                charged.0.fetch_sub(bytes, Ordering::Relaxed);
+            }
+        }
+        self.dealloc_uncharged(item, l)
     }
 
     #[inline(always)]
@@ -316,17 +568,154 @@ unsafe impl<M: MemorySource> Alloc for ElfMalloc<M> {
             case_analyze!(
             self,
             l,
-            small if l.align() > mem::size_of::<usize>() {
-                l.size().next_power_of_two()
-            } else {
-                l.size()
-            };
+            small self.small.class_size(small_key(l));
             medium l.size().next_power_of_two();
             large l.size();),
         )
     }
 }
 
+impl<M: MemorySource> ElfMalloc<M> {
+    /// Set up this handle's caches for objects of each of `sizes` bytes, so that the first
+    /// allocations of those sizes do not have to.
+    ///
+    /// A handle's caches are otherwise created the first time they are used. Warming the cache of
+    /// a small size class takes a `Slag` for it, so this should be limited to the sizes that the
+    /// handle will actually allocate.
+    pub fn warm(&mut self, sizes: &[usize]) {
+        for &size in sizes {
+            unsafe {
+                if size <= self.small.max_key() {
+                    let _ = &mut **self.small.get_mut(size);
+                } else if size <= self.large.max_key() {
+                    let _ = &mut **self.large.get_mut(size);
+                }
+            }
+        }
+    }
+
+    /// Set up this handle's caches for every small size class. See `warm`.
+    pub fn warm_small(&mut self) {
+        for class in self.small.classes.iter() {
+            unsafe {
+                let _ = &mut **class;
+            }
+        }
+    }
+
+    /// Allocate an object for `l`, reporting why allocation failed if it does. `Alloc::alloc` is
+    /// this with the error converted to an `AllocErr`; see the `error` module.
+    #[inline(always)]
+    pub unsafe fn try_alloc(&mut self, l: Layout) -> Result<*mut u8, ElfAllocError> {
+        trace!("alloc({:?})", l);
+        #[cfg(feature = "alloc-guard")]
+        alloc_guard::check(l.size());
+        #[cfg(feature = "quota")]
+        {
+            let bytes = self.usable_size(&l).1;
+            if !quota::charge(bytes) {
+                return Err(ElfAllocError::QuotaExceeded {
+                    size: l.size(),
+                    align: l.align(),
+                });
+            }
+            let res = self.alloc_uncharged(l);
+            if res.is_err() {
+                quota::uncharge(bytes);
+            } else if let Some(ref charged) = self.charged {
+                charged.0.fetch_add(bytes, Ordering::Relaxed);
+            }
+            res
+        }
+        #[cfg(not(feature = "quota"))]
+        {
+            self.alloc_uncharged(l)
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn alloc_uncharged(&mut self, l: Layout) -> Result<*mut u8, ElfAllocError> {
+        case_analyze!(
+            self,
+            l,
+            small {
+                let item = self.small.get_mut(small_key(&l)).alloc();
+                #[cfg(feature = "asan")]
+                asan::on_alloc(item, l.size(), self.small.class_size(small_key(&l)));
+                #[cfg(feature = "valgrind")]
+                valgrind::malloclike_block(item, l.size(), false);
+                #[cfg(feature = "tsan")]
+                tsan::acquire(item);
+                #[cfg(feature = "msan")]
+                msan::unpoison(item, l.size());
+                #[cfg(feature = "mte")]
+                let item = mte::tag(item, self.small.class_size(small_key(&l)));
+                Ok(item)
+            };
+            // Medium objects are aligned to their power-of-two size class, and large objects to
+            // the page size.
+            medium if l.align() > l.size().next_power_of_two() {
+                Err(invalid_layout(&l))
+            } else {
+                match self.large.get_mut(l.size()).alloc() {
+                    Some(p) => Ok(p),
+                    None => Err(error::map_failure(l.size(), l.align())),
+                }
+            };
+            large if l.align() > mmap::page_size() {
+                Err(invalid_layout(&l))
+            } else {
+                let mapped = match self.record {
+                    Some(ref record) => record.map(l.size()),
+                    None => mmap::fallible_map(l.size()),
+                };
+                match mapped {
+                    Some(p) => {
+                        mmap::name(p, l.size(), &self.large_name);
+                        Ok(p)
+                    }
+                    None => Err(error::map_failure(l.size(), l.align())),
+                }
+            };
+        )
+    }
+
+    #[inline(always)]
+    unsafe fn dealloc_uncharged(&mut self, item: *mut u8, l: Layout) {
+        case_analyze!(
+            self,
+            l,
+            small {
+                #[cfg(feature = "mte")]
+                let item = mte::untag(item);
+                #[cfg(feature = "mte")]
+                mte::retire(item, self.small.class_size(small_key(&l)));
+                #[cfg(feature = "zero-on-free")]
+                zero::zero(item, self.small.class_size(small_key(&l)));
+                #[cfg(feature = "asan")]
+                asan::on_free(item, self.small.class_size(small_key(&l)));
+                #[cfg(feature = "valgrind")]
+                valgrind::freelike_block(item);
+                #[cfg(feature = "tsan")]
+                tsan::release(item);
+                self.small.get_mut(small_key(&l)).free(item)
+            };
+            medium {
+                #[cfg(feature = "zero-on-free")]
+                zero::zero(item, l.size().next_power_of_two());
+                self.large.get_mut(l.size()).free(item)
+            };
+            large {
+                #[cfg(feature = "zero-on-free")]
+                zero::zero(item, l.size());
+                match self.record {
+                    Some(ref record) => record.unmap(item, l.size()),
+                    None => mmap::unmap(item, l.size()),
+                }
+            };)
+    }
+}
+
 unsafe impl<M: MemorySource> Alloc for OwnedElfMalloc<M> {
     unsafe fn alloc(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
         self.0.alloc(l)
@@ -341,6 +730,7 @@ unsafe impl<M: MemorySource> Alloc for OwnedElfMalloc<M> {
     }
 }
 
+#[derive(Clone)]
 pub struct ElfMallocBuilder {
     page_size: usize,
     target_pa_size: usize,
@@ -352,6 +742,11 @@ pub struct ElfMallocBuilder {
     large_obj_cutoff: usize,
     large_obj_target_size: usize,
     target_pipe_overhead: usize,
+    medium_backend: MediumBackend,
+    size_classes: SizeClassStrategy,
+    tiny_class: bool,
+    prefault: bool,
+    name: Option<String>,
 }
 
 impl Default for ElfMallocBuilder {
@@ -367,6 +762,11 @@ impl Default for ElfMallocBuilder {
             large_obj_cutoff: 1 << 20,
             large_obj_target_size: 1 << 12,
             target_pipe_overhead: 16 << 20,
+            medium_backend: MediumBackend::Pages,
+            size_classes: SizeClassStrategy::Quantum,
+            tiny_class: true,
+            prefault: false,
+            name: None,
         }
     }
 }
@@ -396,14 +796,83 @@ impl ElfMallocBuilder {
         self.large_pipe_size = large_pipe_size;
         self
     }
+    pub fn medium_backend(&mut self, medium_backend: MediumBackend) -> &mut ElfMallocBuilder {
+        self.medium_backend = medium_backend;
+        self
+    }
+    pub fn size_classes(&mut self, size_classes: SizeClassStrategy) -> &mut ElfMallocBuilder {
+        self.size_classes = size_classes;
+        self
+    }
+    /// Whether to have a size class for objects of up to `TINY_SIZE` bytes (the default), rather
+    /// than rounding them up to the smallest class of the size class strategy.
+    pub fn tiny_class(&mut self, tiny_class: bool) -> &mut ElfMallocBuilder {
+        self.tiny_class = tiny_class;
+        self
+    }
+
+    /// Fault in each page for small objects when it is first handed to a size class, so that
+    /// objects are not subject to page faults when they are first written. Pages for medium
+    /// objects are not affected.
+    pub fn prefault(&mut self, prefault: bool) -> &mut ElfMallocBuilder {
+        self.prefault = prefault;
+        self
+    }
+
+    /// Name the heap, so that its memory can be told apart from that of other heaps and the rest
+    /// of the process. Its mappings are named `elfmalloc:<name>:small`, `elfmalloc:<name>:medium`
+    /// and `elfmalloc:<name>:large` rather than `elfmalloc:small` and so on (see the
+    /// `name_mappings` option in the `conf` module), and `IsolatedHeap::name` returns it.
+    pub fn name(&mut self, name: &str) -> &mut ElfMallocBuilder {
+        self.name = Some(name.to_string());
+        self
+    }
 
     pub fn build<M: MemorySource>(&self) -> ElfMalloc<M> {
-        let pa = PageAlloc::<M>::new(self.page_size, self.target_pa_size, self.large_pipe_size, AllocType::SmallSlag);
-        let n_small_classes = (self.page_size / 4) / MULTIPLE;
-        alloc_assert!(n_small_classes > 0);
-        let mut meta_pointers = mmap::map(mem::size_of::<Metadata>() * n_small_classes) as
-            *mut Metadata;
-        let small_classes = Multiples::init(MULTIPLE, n_small_classes, |size: usize| {
+        self.build_from(M::new, None)
+    }
+
+    /// Build an allocator whose mappings are all recorded, so that they can be found, or released
+    /// at once when the last handle to the allocator is dropped.
+    fn build_tracked(&self) -> ElfMalloc<TrackedSource> {
+        let record = Arc::new(MapRecord::new());
+        self.build_from(
+            |page_size| TrackedSource::with_record(page_size, record.clone()),
+            Some(record.clone()),
+        )
+    }
+
+    /// Build an allocator whose memory sources are created by `source(page_size)`, and whose
+    /// metadata and large objects are mapped through `record` if there is one.
+    fn build_from<M, F>(&self, source: F, record: Option<Arc<MapRecord>>) -> ElfMalloc<M>
+    where
+        M: MemorySource,
+        F: Fn(usize) -> M,
+    {
+        let heap_name = self.name.as_ref().map(|name| &name[..]);
+        let small_source = source(self.page_size);
+        let small_cleanup = PageCleanup::for_source(self.page_size, &small_source);
+        let mut pa = PageAlloc::with_source(
+            small_source,
+            self.target_pa_size,
+            self.large_pipe_size,
+            AllocType::SmallSlag,
+        );
+        pa.set_prefault(self.prefault);
+        pa.set_name(MapName::new(heap_name, "small"));
+        let max_small_size = self.page_size / 4;
+        alloc_assert!(max_small_size >= MULTIPLE);
+        let class_map = ClassMap {
+            strategy: self.size_classes,
+            tiny: self.tiny_class,
+        };
+        let n_small_classes = class_map.index(max_small_size) + 1;
+        let meta_bytes = mem::size_of::<Metadata>() * n_small_classes;
+        let mut meta_pointers = match record {
+            Some(ref record) => record.map(meta_bytes).expect("mmap should not fail"),
+            None => mmap::map(meta_bytes),
+        } as *mut Metadata;
+        let small_classes = SizeClasses::init(class_map, max_small_size, |size: usize| {
             let meta = meta_pointers;
             unsafe {
                 meta_pointers = meta_pointers.offset(1);
@@ -422,8 +891,9 @@ impl ElfMallocBuilder {
             let params = (
                 meta,
                 usize::max_value(), /* no eager decommit */
+                BuildConfig::MAGAZINE_OBJECTS,
                 pa.clone(),
-                RevocablePipe::new_size_cleanup(self.small_pipe_size, PageCleanup::new(self.page_size)),
+                RevocablePipe::new_size_cleanup(self.small_pipe_size, small_cleanup),
             );
             #[cfg(not(feature = "magazine_layer"))]
             {
@@ -437,12 +907,21 @@ impl ElfMallocBuilder {
         let next_size_class = (small_classes.max_key() + 1).next_power_of_two();
         let max_size = self.max_object_size.next_power_of_two();
         let n_classes = max_size.trailing_zeros() - next_size_class.trailing_zeros();
-        let p_source = PageSource::<M>::new(
-            self.large_obj_cutoff,
-            self.large_obj_target_size,
-            self.large_pipe_size,
-            max_size,
-        );
+        let mut p_source = match self.medium_backend {
+            MediumBackend::Pages => MediumSource::Pages(PageSource::with_source(
+                self.large_obj_cutoff,
+                self.large_obj_target_size,
+                self.large_pipe_size,
+                source(max_size),
+            )),
+            MediumBackend::Buddy => MediumSource::Buddy(BuddySource::with_source(
+                next_size_class,
+                max_size,
+                self.large_obj_cutoff,
+                |arena_size| source(arena_size),
+            )),
+        };
+        p_source.set_name(MapName::new(heap_name, "medium"));
         let large_classes = PowersOfTwo::init(next_size_class, n_classes as usize, |size: usize| {
             let target_size: usize = cmp::max(1, self.target_pipe_overhead / size);
             Lazy::<PageFrontend<M>>::new(
@@ -453,15 +932,470 @@ impl ElfMallocBuilder {
         ElfMalloc {
             small: small_classes,
             large: large_classes,
+            large_name: MapName::new(heap_name, "large"),
+            #[cfg(feature = "quota")]
+            charged: record.as_ref().map(|_| Arc::new(HeapCharge(AtomicUsize::new(0)))),
+            record: record,
         }
     }
 
     pub fn build_owned<M: MemorySource>(&self) -> OwnedElfMalloc<M> {
-        OwnedElfMalloc(self.build())
+        OwnedElfMalloc::new(self.build())
+    }
+
+    /// Build an `IsolatedHeap` with this configuration.
+    pub fn build_heap(&self) -> IsolatedHeap {
+        IsolatedHeap::new(self.build_owned(), self.name.clone())
+    }
+
+    /// Build an `IsolatedHeap` with this configuration that can be sealed.
+    ///
+    /// The heap records each mapping it makes so that `seal` can find the memory to protect.
+    /// Mappings are only made on slow paths, so this does not slow down allocation. Pages the heap
+    /// no longer needs are uncommitted rather than unmapped, and all of its memory is unmapped when
+    /// the heap and all of its handles have been dropped, unless it was sealed.
+    pub fn build_sealable_heap(&self) -> IsolatedHeap {
+        IsolatedHeap::new(OwnedElfMalloc::new(self.build_tracked()), self.name.clone())
     }
 }
 
-pub use self::global::{DynamicAlloc, SharedAlloc, new_owned_handle};
+/// A heap that shares no state with any other heap in the process.
+///
+/// Every allocator returned by `ElfMallocBuilder::build` has its own pages, page caches and size
+/// class metadata; large objects are mapped and unmapped directly and need no shared state at all.
+/// An `IsolatedHeap` packages such an allocator with its own configuration and usage statistics,
+/// and hands out `HeapHandle`s that allocate from it. This allows, e.g., each plugin or sandbox in
+/// a process to be given a heap of its own whose usage can be measured and bounded separately.
+///
+/// Memory cached by a heap's handles is returned to that heap (and only that heap) when they are
+/// dropped. Objects must be freed through a handle to the heap from which they were allocated.
+///
+/// # Sealing
+///
+/// A heap built with `ElfMallocBuilder::build_sealable_heap` can be *sealed* once it has been
+/// populated: all of its memory, including the `Slag` headers and size class metadata alongside
+/// its objects, is made read-only, and any further allocation from the heap fails. This suits
+/// data structures that are built once and then shared, such as configuration snapshots or
+/// interning tables; accidental writes to them fault instead of going unnoticed. A sealed heap
+/// can never be unsealed, and its memory is never reclaimed.
+///
+/// Such a heap can also be saved to a file with `persist`, and mapped back in later, read-only and
+/// at the same addresses, with `persistent::HeapImage::open`.
+///
+/// # Partitions
+///
+/// A heap can be divided into named partitions, created with `partition`, to account for (and
+/// bound) the memory used by different parts of a program separately while they share the heap's
+/// pages. Each partition has its own limit and statistics, and its handles have their own caches,
+/// so objects of one partition are never handed out from a cache filled by another. An allocation
+/// from a partition's handle counts towards both the partition and the heap, and fails if it
+/// would exceed either limit.
+pub struct IsolatedHeap {
+    proto: OwnedElfMalloc<TrackedSource>,
+    counters: Arc<HeapCounters>,
+    name: Option<String>,
+    partitions: Mutex<Vec<Partition>>,
+}
+
+// Handles are created from `proto` by cloning it, which only reads from it.
+unsafe impl Sync for IsolatedHeap {}
+
+#[derive(Default)]
+struct HeapCounters {
+    live_bytes: AtomicUsize,
+    /// The maximum value of `live_bytes` since the last call to `reset_peak`.
+    peak_bytes: AtomicUsize,
+    // `limit` and `sealed` are read by every allocation but rarely written, so they are kept
+    // out of the cache lines of the counters.
+    /// The maximum value of `live_bytes`, or 0 if there is none.
+    limit: CachePadded<AtomicUsize>,
+    live_objects: AtomicUsize,
+    total_allocs: AtomicUsize,
+    sealed: CachePadded<AtomicBool>,
+}
+
+impl HeapCounters {
+    /// Count `bytes` more live bytes, unless that would exceed the limit.
+    fn charge(&self, bytes: usize) -> bool {
+        let limit = self.limit.load(Ordering::Relaxed);
+        let prev = self.live_bytes.fetch_add(bytes, Ordering::Relaxed);
+        if limit != 0 && prev + bytes > limit {
+            self.live_bytes.fetch_sub(bytes, Ordering::Relaxed);
+            return false;
+        }
+        let mut peak = self.peak_bytes.load(Ordering::Relaxed);
+        while prev + bytes > peak {
+            match self.peak_bytes.compare_exchange_weak(
+                peak,
+                prev + bytes,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(cur) => peak = cur,
+            }
+        }
+        true
+    }
+
+    /// Undo a successful `charge` of `bytes`.
+    fn uncharge(&self, bytes: usize) {
+        self.live_bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Count an object as allocated, after a successful `charge` of its size.
+    fn allocated(&self) {
+        self.live_objects.fetch_add(1, Ordering::Relaxed);
+        self.total_allocs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an object of `bytes` bytes as freed.
+    fn freed(&self, bytes: usize) {
+        self.uncharge(bytes);
+        self.live_objects.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn set_limit(&self, bytes: usize) {
+        self.limit.store(bytes, Ordering::Relaxed);
+    }
+
+    fn limit(&self) -> Option<usize> {
+        match self.limit.load(Ordering::Relaxed) {
+            0 => None,
+            limit => Some(limit),
+        }
+    }
+
+    fn reset_peak(&self) {
+        self.peak_bytes
+            .store(self.live_bytes.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> HeapStats {
+        HeapStats {
+            live_bytes: self.live_bytes.load(Ordering::Relaxed),
+            peak_live_bytes: self.peak_bytes.load(Ordering::Relaxed),
+            live_objects: self.live_objects.load(Ordering::Relaxed),
+            total_allocs: self.total_allocs.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Usage statistics for an `IsolatedHeap`, or for one of its partitions.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Live bytes, including internal fragmentation.
+    pub live_bytes: usize,
+    /// The highest value of `live_bytes` since the heap was created or `reset_peak` was called.
+    pub peak_live_bytes: usize,
+    pub live_objects: usize,
+    /// The number of objects ever allocated from the heap.
+    pub total_allocs: usize,
+}
+
+impl IsolatedHeap {
+    /// Create a heap with the default configuration.
+    pub fn new_default() -> IsolatedHeap {
+        ElfMallocBuilder::default().build_heap()
+    }
+
+    fn new(proto: OwnedElfMalloc<TrackedSource>, name: Option<String>) -> IsolatedHeap {
+        IsolatedHeap {
+            proto: proto,
+            counters: Arc::new(HeapCounters::default()),
+            name: name,
+            partitions: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The name given to the heap with `ElfMallocBuilder::name`, if any.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|name| &name[..])
+    }
+
+    /// Create a new handle to this heap.
+    pub fn handle(&self) -> HeapHandle {
+        HeapHandle {
+            inner: ManuallyDrop::new(OwnedElfMalloc::new(self.proto.0.clone())),
+            counters: self.counters.clone(),
+            partition: None,
+        }
+    }
+
+    /// Get the partition of this heap called `name`, creating it if there is none.
+    ///
+    /// Partitions are never removed; an empty partition costs a few hundred bytes.
+    pub fn partition(&self, name: &str) -> Partition {
+        let mut partitions = self.partitions.lock().unwrap();
+        if let Some(p) = partitions.iter().find(|p| p.name() == name) {
+            return p.clone();
+        }
+        let p = Partition(Arc::new(PartitionInner {
+            name: name.to_string(),
+            proto: OwnedElfMalloc::new(self.proto.0.clone()),
+            heap: self.counters.clone(),
+            counters: Arc::new(HeapCounters::default()),
+        }));
+        partitions.push(p.clone());
+        p
+    }
+
+    /// The partitions of this heap, in the order they were created.
+    pub fn partitions(&self) -> Vec<Partition> {
+        self.partitions.lock().unwrap().clone()
+    }
+
+    /// Limit the live bytes of this heap to `bytes`; 0 removes the limit.
+    ///
+    /// Allocations from the heap's handles that would exceed the limit fail with
+    /// `AllocErr::Exhausted`. Live bytes are counted as in `stats`, including internal
+    /// fragmentation. Objects which are already allocated are not affected.
+    pub fn set_limit(&self, bytes: usize) {
+        self.counters.set_limit(bytes)
+    }
+
+    /// The current limit, if any.
+    pub fn limit(&self) -> Option<usize> {
+        self.counters.limit()
+    }
+
+    /// Has this heap been sealed?
+    pub fn is_sealed(&self) -> bool {
+        self.counters.sealed.load(Ordering::Acquire)
+    }
+
+    /// Make all of this heap's memory read-only, and reject any further allocations from it.
+    /// Returns the number of bytes that were protected.
+    ///
+    /// This covers every mapping the heap has made: the pages of its objects along with their
+    /// `Slag` headers and bitsets, pages it has cached, its size class metadata and its large
+    /// objects. Subsequent calls to `alloc` on the heap's handles fail, and calls to `dealloc`
+    /// panic. The memory of a sealed heap is never unmapped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the heap was not built with `ElfMallocBuilder::build_sealable_heap`.
+    ///
+    /// # Safety
+    ///
+    /// No handle to this heap may be allocating or freeing concurrently with this call.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn seal(&self) -> usize {
+        let record = self.proto
+            .0
+            .record
+            .as_ref()
+            .expect("seal called on a heap that is not sealable");
+        if self.counters.sealed.swap(true, Ordering::AcqRel) {
+            return 0;
+        }
+        // Handles to a sealed heap are never dropped, but the heap itself may have no handles
+        // left; its memory must stay mapped either way.
+        mem::forget(record.clone());
+        let page_size = mmap::page_size();
+        let mut protected = 0;
+        record.for_each(|p, len| {
+            // Large objects are mapped with their exact size.
+            let len = (len + page_size - 1) & !(page_size - 1);
+            mmap::protect_read_only(p, len);
+            protected += len;
+        });
+        protected
+    }
+
+    /// Write an image of all of this heap's memory to `path`, with `root` as the entry point to
+    /// its contents, so that it can be mapped back in with `persistent::HeapImage::open`. Returns
+    /// the number of bytes of memory saved. See the `persistent` module for details.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the heap was not built with `ElfMallocBuilder::build_sealable_heap`.
+    ///
+    /// # Safety
+    ///
+    /// No handle to this heap may be allocating, freeing or writing to objects concurrently with
+    /// this call.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn persist<P>(&self, path: P, root: *const u8) -> ::std::io::Result<usize>
+    where
+        P: AsRef<::std::path::Path>,
+    {
+        let record = self.proto
+            .0
+            .record
+            .as_ref()
+            .expect("persist called on a heap that is not sealable");
+        super::persistent::write_image(record, root, path.as_ref())
+    }
+
+    /// Restart `HeapStats::peak_live_bytes` from the current live bytes.
+    pub fn reset_peak(&self) {
+        self.counters.reset_peak()
+    }
+
+    /// Get the usage statistics of this heap, including every partition.
+    ///
+    /// Counters are read one at a time, so the result is not an atomic snapshot.
+    pub fn stats(&self) -> HeapStats {
+        self.counters.stats()
+    }
+}
+
+/// A named partition of an `IsolatedHeap`, created with `IsolatedHeap::partition`.
+///
+/// Cloning a `Partition` is cheap, and the clone refers to the same partition.
+#[derive(Clone)]
+pub struct Partition(Arc<PartitionInner>);
+
+struct PartitionInner {
+    name: String,
+    /// Handles to the partition are created from `proto`, so that they do not share caches with
+    /// the heap's other handles.
+    proto: OwnedElfMalloc<TrackedSource>,
+    heap: Arc<HeapCounters>,
+    counters: Arc<HeapCounters>,
+}
+
+// As with `IsolatedHeap`, `proto` is only read from, by cloning it.
+unsafe impl Send for PartitionInner {}
+unsafe impl Sync for PartitionInner {}
+
+impl Partition {
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    /// Create a new handle allocating from this partition.
+    pub fn handle(&self) -> HeapHandle {
+        HeapHandle {
+            inner: ManuallyDrop::new(OwnedElfMalloc::new(self.0.proto.0.clone())),
+            counters: self.0.heap.clone(),
+            partition: Some(self.clone()),
+        }
+    }
+
+    /// Limit the live bytes of this partition to `bytes`; 0 removes the limit. The heap's own
+    /// limit still applies.
+    pub fn set_limit(&self, bytes: usize) {
+        self.0.counters.set_limit(bytes)
+    }
+
+    /// The current limit of this partition, if any.
+    pub fn limit(&self) -> Option<usize> {
+        self.0.counters.limit()
+    }
+
+    /// Restart `HeapStats::peak_live_bytes` of this partition from its current live bytes.
+    pub fn reset_peak(&self) {
+        self.0.counters.reset_peak()
+    }
+
+    /// Get the usage statistics of this partition.
+    ///
+    /// Counters are read one at a time, so the result is not an atomic snapshot.
+    pub fn stats(&self) -> HeapStats {
+        self.0.counters.stats()
+    }
+}
+
+/// A handle allocating from a specific `IsolatedHeap`.
+///
+/// Like a `DynamicAlloc`, a `HeapHandle` has its own cache and can be moved to another thread.
+/// Handles may outlive the `IsolatedHeap` that created them.
+///
+/// A handle created by `Partition::handle`, and its clones, allocate from that partition. Objects
+/// must be freed through a handle to the same partition.
+pub struct HeapHandle {
+    // Not dropped if the heap is sealed, as returning cached memory writes to sealed pages.
+    inner: ManuallyDrop<OwnedElfMalloc<TrackedSource>>,
+    counters: Arc<HeapCounters>,
+    partition: Option<Partition>,
+}
+
+impl Clone for HeapHandle {
+    fn clone(&self) -> HeapHandle {
+        HeapHandle {
+            inner: ManuallyDrop::new(OwnedElfMalloc::new(self.inner.0.clone())),
+            counters: self.counters.clone(),
+            partition: self.partition.clone(),
+        }
+    }
+}
+
+impl Drop for HeapHandle {
+    fn drop(&mut self) {
+        if !self.counters.sealed.load(Ordering::Acquire) {
+            unsafe { ManuallyDrop::drop(&mut self.inner) };
+        }
+    }
+}
+
+impl HeapHandle {
+    /// Do `self` and `other` allocate from the same heap?
+    pub fn same_heap(&self, other: &HeapHandle) -> bool {
+        &*self.counters as *const HeapCounters == &*other.counters as *const HeapCounters
+    }
+
+    /// The partition this handle allocates from, if any.
+    pub fn partition(&self) -> Option<&Partition> {
+        self.partition.as_ref()
+    }
+}
+
+unsafe impl Alloc for HeapHandle {
+    unsafe fn alloc(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        if self.counters.sealed.load(Ordering::Acquire) {
+            return Err(AllocErr::Unsupported {
+                details: "cannot allocate from a sealed heap",
+            });
+        }
+        let bytes = self.inner.usable_size(&l).1;
+        let partition = self.partition.as_ref().map(|p| &*p.0.counters);
+        if !partition.map_or(true, |p| p.charge(bytes)) {
+            return Err(AllocErr::Exhausted { request: l });
+        }
+        if !self.counters.charge(bytes) {
+            if let Some(p) = partition {
+                p.uncharge(bytes);
+            }
+            return Err(AllocErr::Exhausted { request: l });
+        }
+        let res = self.inner.alloc(l);
+        if res.is_err() {
+            self.counters.uncharge(bytes);
+            if let Some(p) = partition {
+                p.uncharge(bytes);
+            }
+        }
+        if res.is_ok() {
+            self.counters.allocated();
+            if let Some(partition) = partition {
+                partition.allocated();
+            }
+        }
+        res
+    }
+
+    unsafe fn dealloc(&mut self, p: *mut u8, l: Layout) {
+        alloc_assert!(
+            !self.counters.sealed.load(Ordering::Acquire),
+            "cannot free an object in a sealed heap"
+        );
+        let bytes = self.inner.usable_size(&l).1;
+        self.counters.freed(bytes);
+        if let Some(p) = self.partition.as_ref() {
+            p.0.counters.freed(bytes);
+        }
+        self.inner.dealloc(p, l)
+    }
+
+    fn usable_size(&self, l: &Layout) -> (usize, usize) {
+        self.inner.usable_size(l)
+    }
+}
+
+pub use self::global::{DynamicAlloc, HandlePool, SendableAlloc, SharedAlloc, free_all_from,
+                       new_owned_handle, new_owning_handle};
 
 mod global {
     //! This module provides an interface to global instances of allocators in the parent module.
@@ -477,12 +1411,28 @@ mod global {
     //! ensure that allocations and frees are cached correctly even when the allocator is moved to
     //! a different thread.
     //!
+    //! Cloning a `DynamicAlloc` is cheap: the clone shares the global backend, but gets its own
+    //! frontend, whose per-size-class caches are only created when they are first used. For
+    //! thread pools that create workers faster than the workers warm up their caches, a
+    //! `HandlePool` keeps a number of handles with warmed-up caches ready to be handed out.
+    //!
     //! # `SharedAlloc`
     //!
     //! This allocator stores a single frontend per thread in thread-local storage (TLS). It
     //! therefore allows different data-structures to share a single frontend. In experiments with
     //! a custom `Vec` implementation, this leads to serious performance wins.
+    //!
+    //! # `SendableAlloc`
+    //!
+    //! A `DynamicAlloc` can be sent to another thread, but its cache comes along with it: the
+    //! memory it caches was most likely last touched by the thread that created it. A
+    //! `SendableAlloc` is a `DynamicAlloc` that remembers the thread it was last used on. The
+    //! first time it is used on a different thread, it returns its cache to the global pool and
+    //! starts over with a fresh one. Objects allocated before the move remain valid, and can be
+    //! freed from the new thread. A `SendableAlloc` is `Send` but not `Sync`, so containers using
+    //! it (e.g. `AVec<T, SendableAlloc>`) can be moved across threads but not shared.
     use super::*;
+    use super::super::utils::StaticCell;
 
     use std::intrinsics::unlikely;
     use std::sync::mpsc::{channel, Sender};
@@ -490,31 +1440,142 @@ mod global {
     use std::thread;
     use std::cell::UnsafeCell;
 
-    type Source = MmapSource;
+    type Source = TrackedSource;
     type InnerAlloc = ElfMalloc<Source>;
 
     pub type DynamicAlloc = OwnedElfMalloc<Source>;
 
-    /// A global handle that can hand out thread-local handles to the allocator.
-    struct ElfCloner(InnerAlloc);
+    /// A global handle that can hand out thread-local handles to the allocator. The underlying
+    /// allocator is created the first time a handle is requested.
+    struct ElfCloner(StaticCell<InnerAlloc>);
 
     impl ElfCloner {
+        const fn new() -> ElfCloner {
+            ElfCloner(StaticCell::new())
+        }
+
+        fn get(&self) -> &InnerAlloc {
+            self.0.get_or_init(|| builder().build())
+        }
+
         fn new_handle(&self) -> DynamicAlloc {
-            OwnedElfMalloc(self.0.clone())
+            OwnedElfMalloc::new(self.get().clone())
         }
     }
     unsafe impl Sync for ElfCloner {}
 
+    static GLOBAL_HANDLE: ElfCloner = ElfCloner::new();
+
+    /// The configuration of the global allocator, which owning handles share.
+    fn builder() -> ElfMallocBuilder {
+        let mut builder = ElfMallocBuilder::default();
+        builder.page_size(16 << 10);
+        builder
+    }
+
     /// Construct a new `DynamicAlloc`.
     pub fn new_owned_handle() -> DynamicAlloc {
         GLOBAL_HANDLE.new_handle()
     }
 
-    lazy_static! {
-        static ref GLOBAL_HANDLE: ElfCloner = ElfCloner(ElfMallocBuilder::default()
-                                                        .page_size(16 << 10)
-                                                        .build());
+    /// Construct a new `DynamicAlloc` in owning mode. It allocates from a heap of its own rather
+    /// than the global one, and every object allocated through it that is still live when it is
+    /// dropped is freed along with that heap.
+    pub fn new_owning_handle() -> DynamicAlloc {
+        OwnedElfMalloc::owning(&builder())
+    }
+
+    /// A pool of `DynamicAlloc`s whose caches have already been set up (see `ElfMalloc::warm`).
+    ///
+    /// Worker threads `take` a handle when they start, and can `give` it back when they exit so
+    /// that the next worker inherits its caches. The pool can be topped up with `refill`, e.g. from
+    /// a background thread. A `HandlePool` can be shared between threads.
+    pub struct HandlePool {
+        handles: Mutex<Vec<DynamicAlloc>>,
+        capacity: usize,
+        /// The sizes to warm, or `None` for every small size class.
+        sizes: Option<Vec<usize>>,
+    }
+
+    impl HandlePool {
+        /// Create a pool of `capacity` handles, with the caches of every small size class warmed.
+        pub fn new(capacity: usize) -> HandlePool {
+            HandlePool::create(capacity, None)
+        }
+
+        /// Create a pool of `capacity` handles, with the caches for objects of each of `sizes`
+        /// bytes warmed.
+        pub fn with_sizes(capacity: usize, sizes: &[usize]) -> HandlePool {
+            HandlePool::create(capacity, Some(sizes.to_vec()))
+        }
+
+        fn create(capacity: usize, sizes: Option<Vec<usize>>) -> HandlePool {
+            let pool = HandlePool {
+                handles: Mutex::new(Vec::with_capacity(capacity)),
+                capacity: capacity,
+                sizes: sizes,
+            };
+            pool.refill();
+            pool
+        }
+
+        fn warmed(&self) -> DynamicAlloc {
+            let mut handle = new_owned_handle();
+            match self.sizes {
+                Some(ref sizes) => handle.warm(sizes),
+                None => handle.warm_small(),
+            }
+            handle
+        }
+
+        /// Take a handle from the pool. If the pool is empty, a new handle is created and warmed.
+        pub fn take(&self) -> DynamicAlloc {
+            let pooled = self.handles.lock().unwrap().pop();
+            pooled.unwrap_or_else(|| self.warmed())
+        }
+
+        /// Return a handle to the pool, along with its caches. If the pool is full, or the handle
+        /// is in owning mode, it is dropped instead.
+        pub fn give(&self, handle: DynamicAlloc) {
+            if handle.is_owning() {
+                return;
+            }
+            let mut handles = self.handles.lock().unwrap();
+            if handles.len() < self.capacity {
+                handles.push(handle);
+            }
+        }
 
+        /// Create and warm handles until the pool is full.
+        pub fn refill(&self) {
+            // Handles are warmed without holding the lock so that `take` is never blocked on it.
+            while self.len() < self.capacity {
+                let handle = self.warmed();
+                self.give(handle);
+            }
+        }
+
+        /// The number of handles in the pool.
+        pub fn len(&self) -> usize {
+            self.handles.lock().unwrap().len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+    }
+
+    /// Free every live object allocated through `handle`, if it is in owning mode, by releasing
+    /// its heap all at once. See `OwnedElfMalloc::free_all`.
+    ///
+    /// # Safety
+    ///
+    /// None of the freed objects may be used after this call.
+    pub unsafe fn free_all_from(handle: &mut DynamicAlloc) {
+        handle.free_all()
+    }
+
+    lazy_static! {
         /// We still have a crossbeam dependency, which means that we may have to reclaim a
         /// thread's cached memory after it is destroyed. See the comments in `general::global` for
         /// more context on why this is necessary.
@@ -539,7 +1600,7 @@ mod global {
     impl Drop for ElfMallocTLS {
         fn drop(&mut self) {
             unsafe {
-                let _ = BACKUP_CLEAN.lock().unwrap().send(OwnedElfMalloc(
+                let _ = BACKUP_CLEAN.lock().unwrap().send(OwnedElfMalloc::new(
                     ptr::read(&mut self.0),
                 ));
             }
@@ -548,7 +1609,7 @@ mod global {
 
     thread_local! {
         static LOCAL_RUSTMALLOC: UnsafeCell<ElfMallocTLS> =
-            UnsafeCell::new(ElfMallocTLS(GLOBAL_HANDLE.0.clone()));
+            UnsafeCell::new(ElfMallocTLS(GLOBAL_HANDLE.get().clone()));
     }
 
     macro_rules! with_instance {
@@ -576,9 +1637,77 @@ mod global {
     }
 
     /// A ZST for routing allocations through the thread-local handle.
+    ///
+    /// `SharedAlloc::new` is a `const fn`, so a `SharedAlloc` can be stored in a `static`; the
+    /// allocator is initialized the first time it is used.
     #[derive(Clone)]
     pub struct SharedAlloc;
 
+    impl SharedAlloc {
+        pub const fn new() -> SharedAlloc {
+            SharedAlloc
+        }
+    }
+
+    /// A unique address for each thread, used to detect that a `SendableAlloc` has moved.
+    #[thread_local]
+    static THREAD_MARKER: u8 = 0;
+
+    fn thread_marker() -> usize {
+        &THREAD_MARKER as *const u8 as usize
+    }
+
+    /// A `DynamicAlloc` that re-homes its cache when it is moved to another thread. See the
+    /// module documentation for details.
+    pub struct SendableAlloc {
+        inner: DynamicAlloc,
+        home: usize,
+    }
+
+    unsafe impl Send for SendableAlloc {}
+
+    impl SendableAlloc {
+        pub fn new() -> SendableAlloc {
+            SendableAlloc {
+                inner: new_owned_handle(),
+                home: thread_marker(),
+            }
+        }
+
+        #[inline(always)]
+        fn local(&mut self) -> &mut DynamicAlloc {
+            let cur = thread_marker();
+            if unsafe { unlikely(cur != self.home) } {
+                self.rehome(cur);
+            }
+            &mut self.inner
+        }
+
+        #[cold]
+        fn rehome(&mut self, cur: usize) {
+            self.inner = new_owned_handle();
+            self.home = cur;
+        }
+    }
+
+    impl Default for SendableAlloc {
+        fn default() -> SendableAlloc {
+            SendableAlloc::new()
+        }
+    }
+
+    unsafe impl Alloc for SendableAlloc {
+        unsafe fn alloc(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+            self.local().alloc(l)
+        }
+        unsafe fn dealloc(&mut self, p: *mut u8, l: Layout) {
+            self.local().dealloc(p, l)
+        }
+        fn usable_size(&self, l: &Layout) -> (usize, usize) {
+            self.inner.usable_size(l)
+        }
+    }
+
     unsafe impl Alloc for SharedAlloc {
         unsafe fn alloc(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
             with_instance!(r_ptr, r_ptr.alloc(l))
@@ -594,6 +1723,8 @@ mod global {
 
 #[cfg(test)]
 mod tests {
+    extern crate test;
+    use self::test::Bencher;
     use super::*;
     use std::mem;
     use std::ptr;
@@ -649,7 +1780,9 @@ mod tests {
         }
     }
 
+    // Under Miri, the multi-threaded tests are far too slow to be useful.
     #[test]
+    #[cfg_attr(miri, ignore)]
     fn many_threads_many_sizes() {
         let word_size = mem::size_of::<usize>();
         multi_threaded_alloc_test(
@@ -662,6 +1795,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg_attr(miri, ignore)]
     fn large_ws_small_size() {
         multi_threaded_alloc_test(
             (1..(8 << 10))
@@ -671,6 +1805,408 @@ mod tests {
     }
 
     #[test]
+    fn try_alloc_errors() {
+        let mut alloc = ElfMallocBuilder::default().build::<MmapSource>();
+        unsafe {
+            let l = Layout::from_size_align(16 << 20, mmap::page_size()).unwrap();
+            let p = alloc.try_alloc(l.clone()).expect("alloc should not fail");
+            ptr::write_bytes(p, 0xFF, l.size());
+            alloc.dealloc(p, l);
+            // Large objects are only page-aligned, and medium ones aligned to their size class.
+            for &(size, align) in &[(16 << 20, 32 << 20), (64 << 10, 1 << 20)] {
+                let l = Layout::from_size_align(size, align).unwrap();
+                alloc_assert_eq!(
+                    alloc.try_alloc(l.clone()),
+                    Err(ElfAllocError::InvalidLayout {
+                        size: size,
+                        align: align,
+                    })
+                );
+                alloc_assert!(alloc.alloc(l).unwrap_err().is_request_unsupported());
+            }
+        }
+    }
+
+    #[test]
+    fn owning_handle() {
+        let word_size = mem::size_of::<usize>();
+        let layouts: Vec<_> = (1..(4 << 10))
+            .map(|size| Layout::from_size_align(size * 64, word_size).unwrap())
+            .collect();
+        let mut alloc = new_owning_handle();
+        alloc_assert!(alloc.is_owning());
+        unsafe {
+            for _ in 0..4 {
+                let ptrs: Vec<_> = layouts
+                    .iter()
+                    .map(|l| alloc.alloc(l.clone()).expect("alloc should not fail"))
+                    .collect();
+                // free half of the objects individually, and the rest in bulk
+                for (i, (p, l)) in ptrs.into_iter().zip(layouts.iter()).enumerate() {
+                    if i % 2 == 0 {
+                        alloc.dealloc(p, l.clone());
+                    }
+                }
+                let record = Arc::downgrade(alloc.0.record.as_ref().unwrap());
+                alloc_assert!(record.upgrade().unwrap().mapped_bytes() > 0);
+                free_all_from(&mut alloc);
+                // The handle's heap is gone, and all of its memory unmapped with it.
+                alloc_assert!(record.upgrade().is_none());
+            }
+            for l in &layouts {
+                alloc.alloc(l.clone()).expect("alloc should not fail");
+            }
+        }
+        let record = Arc::downgrade(alloc.0.record.as_ref().unwrap());
+        // the remaining objects are freed here
+        mem::drop(alloc);
+        alloc_assert!(record.upgrade().is_none());
+        // Handles to the global heap are not affected.
+        alloc_assert!(!new_owned_handle().is_owning());
+    }
+
+    #[test]
+    fn clone_shares_backend() {
+        let word_size = mem::size_of::<usize>();
+        let layouts: Vec<_> = [8, 64, 1 << 10, 32 << 10]
+            .iter()
+            .map(|&size| Layout::from_size_align(size, word_size).unwrap())
+            .collect();
+        let mut alloc = new_owned_handle();
+        let mut clone = alloc.clone();
+        unsafe {
+            // Objects allocated through one handle can be freed through the other.
+            for l in &layouts {
+                let p = clone.alloc(l.clone()).expect("alloc should not fail");
+                ptr::write_bytes(p, 0xFF, l.size());
+                alloc.dealloc(p, l.clone());
+            }
+        }
+    }
+
+    #[test]
+    fn handle_pool() {
+        let word_size = mem::size_of::<usize>();
+        let pool = HandlePool::with_sizes(2, &[16, 256, 64 << 10]);
+        alloc_assert_eq!(pool.len(), 2);
+        let handles: Vec<_> = (0..3).map(|_| pool.take()).collect();
+        alloc_assert!(pool.is_empty());
+        let threads: Vec<_> = handles
+            .into_iter()
+            .map(|mut alloc| {
+                thread::spawn(move || {
+                    unsafe {
+                        for &size in &[16, 256, 64 << 10] {
+                            let l = Layout::from_size_align(size, word_size).unwrap();
+                            let p = alloc.alloc(l.clone()).expect("alloc should not fail");
+                            ptr::write_bytes(p, 0xFF, size);
+                            alloc.dealloc(p, l);
+                        }
+                    }
+                    alloc
+                })
+            })
+            .collect();
+        for t in threads {
+            pool.give(t.join().expect("threads should not fail"));
+        }
+        // The third handle does not fit.
+        alloc_assert_eq!(pool.len(), 2);
+        pool.give(new_owning_handle());
+        alloc_assert_eq!(pool.len(), 2);
+        let _ = pool.take();
+        pool.refill();
+        alloc_assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn buddy_medium_backend() {
+        let word_size = mem::size_of::<usize>();
+        let mut alloc = ElfMallocBuilder::default()
+            .medium_backend(MediumBackend::Buddy)
+            .build_owned::<MmapSource>();
+        let layouts: Vec<_> = (1..(1 << 10))
+            .map(|size| Layout::from_size_align(size * 4096, word_size).unwrap())
+            .collect();
+        unsafe {
+            let mut ptrs = Vec::new();
+            for l in &layouts {
+                let p = alloc.alloc(l.clone()).expect("alloc should not fail");
+                ptr::write_bytes(p, 0xFF, l.size());
+                ptrs.push(p);
+            }
+            for (ptr, l) in ptrs.into_iter().zip(layouts.into_iter()) {
+                alloc.dealloc(ptr, l);
+            }
+        }
+    }
+
+    #[test]
+    fn size_class_strategies() {
+        let strategies = [
+            SizeClassStrategy::Quantum,
+            SizeClassStrategy::PowersOfTwo,
+            SizeClassStrategy::Geometric,
+        ];
+        for &strategy in &strategies {
+            let mut prev = 0;
+            for size in 1..(8 << 10) {
+                let ix = strategy.class_index(size);
+                let class = strategy.class_size(ix);
+                alloc_assert!(class >= size, "{:?}: size {} in class {}", strategy, size, class);
+                alloc_assert_eq!(strategy.class_index(class), ix);
+                alloc_assert!(ix == prev || ix == prev + 1);
+                prev = ix;
+            }
+        }
+        alloc_assert_eq!(
+            (1..10)
+                .map(|ix| SizeClassStrategy::Geometric.class_size(ix))
+                .collect::<Vec<_>>(),
+            vec![32, 48, 64, 80, 96, 112, 128, 160, 192]
+        );
+
+        let word_size = mem::size_of::<usize>();
+        for &strategy in &strategies {
+            let mut alloc = ElfMallocBuilder::default()
+                .size_classes(strategy)
+                .tiny_class(false)
+                .build_owned::<MmapSource>();
+            unsafe {
+                for size in (1..1024).map(|i| i * 7) {
+                    let l = Layout::from_size_align(size, word_size).unwrap();
+                    let usable = alloc.usable_size(&l).1;
+                    alloc_assert_eq!(usable, strategy.class_size(strategy.class_index(size)));
+                    let p = alloc.alloc(l.clone()).expect("alloc should not fail");
+                    ptr::write_bytes(p, 0xFF, usable);
+                    alloc.dealloc(p, l);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tiny_class() {
+        let word_size = mem::size_of::<usize>();
+        let mut tiny = ElfMallocBuilder::default().build_owned::<MmapSource>();
+        let mut no_tiny = ElfMallocBuilder::default()
+            .tiny_class(false)
+            .build_owned::<MmapSource>();
+        let l = Layout::from_size_align(TINY_SIZE, word_size).unwrap();
+        // Internal fragmentation: a tiny object only uses what it asks for.
+        alloc_assert_eq!(tiny.usable_size(&l).1, TINY_SIZE);
+        alloc_assert_eq!(no_tiny.usable_size(&l).1, 2 * TINY_SIZE);
+        unsafe {
+            let ptrs: Vec<_> = (0..10_000)
+                .map(|i| {
+                    let p = tiny.alloc(l.clone()).unwrap() as *mut usize;
+                    ptr::write(p, i);
+                    p
+                })
+                .collect();
+            // Tiny objects are packed: consecutive objects from a fresh Slag are adjacent.
+            let adjacent = ptrs.windows(2)
+                .filter(|w| (w[1] as usize).wrapping_sub(w[0] as usize) == TINY_SIZE)
+                .count();
+            alloc_assert!(adjacent > ptrs.len() / 2);
+            for (i, &p) in ptrs.iter().enumerate() {
+                alloc_assert_eq!(*p, i);
+                tiny.dealloc(p as *mut u8, l.clone());
+            }
+            // Over-aligned objects never come from the tiny class.
+            let l = Layout::from_size_align(TINY_SIZE, 16).unwrap();
+            let p = tiny.alloc(l.clone()).unwrap();
+            alloc_assert_eq!(p as usize % 16, 0);
+            tiny.dealloc(p, l);
+        }
+    }
+
+    fn bench_tiny_objects(b: &mut Bencher, size: usize, tiny_class: bool) {
+        let mut alloc = ElfMallocBuilder::default()
+            .tiny_class(tiny_class)
+            .build_owned::<MmapSource>();
+        let l = Layout::from_size_align(size, mem::size_of::<usize>()).unwrap();
+        let mut ptrs = Vec::with_capacity(1 << 12);
+        b.iter(|| unsafe {
+            for _ in 0..(1 << 12) {
+                ptrs.push(alloc.alloc(l.clone()).unwrap());
+            }
+            for p in ptrs.drain(..) {
+                alloc.dealloc(p, l.clone());
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_8_byte_tiny_class(b: &mut Bencher) {
+        bench_tiny_objects(b, 8, true);
+    }
+
+    #[bench]
+    fn bench_8_byte_min_class(b: &mut Bencher) {
+        bench_tiny_objects(b, 8, false);
+    }
+
+    #[bench]
+    fn bench_16_byte(b: &mut Bencher) {
+        bench_tiny_objects(b, 16, true);
+    }
+
+    #[bench]
+    fn bench_32_byte(b: &mut Bencher) {
+        bench_tiny_objects(b, 32, true);
+    }
+
+    #[test]
+    fn isolated_heaps() {
+        let word_size = mem::size_of::<usize>();
+        let heap_a = IsolatedHeap::new_default();
+        let heap_b = ElfMallocBuilder::default()
+            .page_size(64 << 10)
+            .build_heap();
+        let (mut a, mut b) = (heap_a.handle(), heap_b.handle());
+        alloc_assert!(a.same_heap(&a.clone()));
+        alloc_assert!(!a.same_heap(&b));
+        let small = Layout::from_size_align(24, word_size).unwrap();
+        let medium = Layout::from_size_align(100 << 10, word_size).unwrap();
+        unsafe {
+            let a_ptrs: Vec<_> = (0..1024).map(|_| a.alloc(small.clone()).unwrap()).collect();
+            let b_ptr = b.alloc(medium.clone()).unwrap();
+            alloc_assert!(a_ptrs.iter().all(|&p| p != b_ptr));
+            alloc_assert_eq!(heap_a.stats().live_objects, 1024);
+            alloc_assert_eq!(heap_a.stats().live_bytes, 1024 * 24);
+            alloc_assert_eq!(
+                heap_b.stats(),
+                HeapStats {
+                    live_bytes: 128 << 10,
+                    peak_live_bytes: 128 << 10,
+                    live_objects: 1,
+                    total_allocs: 1,
+                }
+            );
+            // Handles can be moved to other threads, and can outlive their heap.
+            let mut a2 = a.clone();
+            thread::spawn(move || for p in a_ptrs {
+                a2.dealloc(p, small.clone());
+            }).join()
+                .unwrap();
+            alloc_assert_eq!(heap_a.stats().live_objects, 0);
+            alloc_assert_eq!(heap_a.stats().total_allocs, 1024);
+            mem::drop(heap_b);
+            b.dealloc(b_ptr, medium);
+        }
+    }
+
+    #[test]
+    fn heap_limit() {
+        let heap = IsolatedHeap::new_default();
+        let mut h = heap.handle();
+        let l = Layout::from_size_align(1 << 10, 8).unwrap();
+        heap.set_limit(64 << 10);
+        alloc_assert_eq!(heap.limit(), Some(64 << 10));
+        unsafe {
+            let ptrs: Vec<_> = (0..64).map(|_| h.alloc(l.clone()).unwrap()).collect();
+            alloc_assert!(h.alloc(l.clone()).is_err());
+            alloc_assert_eq!(heap.stats().live_bytes, 64 << 10);
+            alloc_assert_eq!(heap.stats().total_allocs, 64);
+            h.dealloc(ptrs[0], l.clone());
+            alloc_assert_eq!(heap.stats().peak_live_bytes, 64 << 10);
+            heap.reset_peak();
+            alloc_assert_eq!(heap.stats().peak_live_bytes, 63 << 10);
+            let p = h.alloc(l.clone()).unwrap();
+            heap.set_limit(0);
+            let q = h.alloc(l.clone()).unwrap();
+            for p in ptrs.into_iter().skip(1).chain(vec![p, q]) {
+                h.dealloc(p, l.clone());
+            }
+        }
+        alloc_assert_eq!(heap.stats().live_bytes, 0);
+    }
+
+    #[test]
+    fn partitions() {
+        let heap = IsolatedHeap::new_default();
+        let cache = heap.partition("cache");
+        let session = heap.partition("session");
+        alloc_assert_eq!(heap.partition("cache").name(), "cache");
+        alloc_assert_eq!(
+            heap.partitions().iter().map(|p| p.name()).collect::<Vec<_>>(),
+            vec!["cache", "session"]
+        );
+        let (mut c, mut s, mut h) = (cache.handle(), session.handle(), heap.handle());
+        alloc_assert!(c.same_heap(&s));
+        alloc_assert_eq!(c.clone().partition().map(|p| p.name()), Some("cache"));
+        alloc_assert!(h.partition().is_none());
+        let l = Layout::from_size_align(1 << 10, 8).unwrap();
+        cache.set_limit(16 << 10);
+        heap.set_limit(40 << 10);
+        unsafe {
+            let mut ptrs: Vec<_> = (0..16).map(|_| c.alloc(l.clone()).unwrap()).collect();
+            // The partition's limit applies to its own handles only.
+            alloc_assert!(c.alloc(l.clone()).is_err());
+            let s_ptrs: Vec<_> = (0..16).map(|_| s.alloc(l.clone()).unwrap()).collect();
+            ptrs.extend((0..8).map(|_| h.alloc(l.clone()).unwrap()));
+            // The heap's limit applies to every partition.
+            alloc_assert!(s.alloc(l.clone()).is_err());
+            alloc_assert_eq!(cache.stats().live_bytes, 16 << 10);
+            alloc_assert_eq!(session.stats().live_objects, 16);
+            alloc_assert_eq!(heap.stats().live_bytes, 40 << 10);
+            for p in s_ptrs {
+                s.dealloc(p, l.clone());
+            }
+            alloc_assert_eq!(session.stats().live_bytes, 0);
+            alloc_assert_eq!(session.stats().peak_live_bytes, 16 << 10);
+            alloc_assert_eq!(session.stats().total_allocs, 16);
+            for (i, p) in ptrs.into_iter().enumerate() {
+                if i < 16 {
+                    c.dealloc(p, l.clone());
+                } else {
+                    h.dealloc(p, l.clone());
+                }
+            }
+        }
+        alloc_assert_eq!(cache.stats().live_bytes, 0);
+        alloc_assert_eq!(heap.stats().live_bytes, 0);
+    }
+
+    #[cfg(all(target_os = "linux", not(miri)))]
+    #[test]
+    fn sealed_heap() {
+        let heap = ElfMallocBuilder::default().build_sealable_heap();
+        let mut h = heap.handle();
+        let layouts: Vec<_> = [8, 100, 4 << 10, 200 << 10, 16 << 20]
+            .iter()
+            .map(|&size| Layout::from_size_align(size, 8).unwrap())
+            .collect();
+        unsafe {
+            let ptrs: Vec<_> = layouts
+                .iter()
+                .map(|l| {
+                    let p = h.alloc(l.clone()).unwrap();
+                    ptr::write_bytes(p, 0xAB, l.size());
+                    p
+                })
+                .collect();
+            let tmp = h.alloc(layouts[0].clone()).unwrap();
+            h.dealloc(tmp, layouts[0].clone());
+            alloc_assert!(!heap.is_sealed());
+            // Everything the heap has mapped is protected, not only the pages of live objects.
+            let mapped = heap.proto.0.record.as_ref().unwrap().mapped_bytes();
+            let protected = heap.seal();
+            alloc_assert!(heap.is_sealed());
+            alloc_assert!(protected >= mapped);
+            alloc_assert!(protected >= (16 << 20) + (200 << 10));
+            alloc_assert_eq!(heap.seal(), 0);
+            for (&p, l) in ptrs.iter().zip(layouts.iter()) {
+                alloc_assert_eq!(*p.offset(l.size() as isize - 1), 0xAB);
+            }
+            alloc_assert!(h.alloc(layouts[0].clone()).is_err());
+            alloc_assert!(heap.handle().alloc(layouts[1].clone()).is_err());
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
     fn large_ws_large_size() {
         multi_threaded_alloc_test(
             (1..(1 << 8))
@@ -0,0 +1,381 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Dedicated pools for hot allocation sites.
+//!
+//! With the `site-pools` feature, elfmalloc watches the allocations made with an explicit site
+//! (see the `sites` module) and gives the busiest `(site, size)` pairs pools of their own. A
+//! site's pool holds objects of that site that were freed, and serves the site's next allocations
+//! of the same size from them, so that a site that allocates and frees objects at a high rate
+//! keeps reusing the same few objects, which are likely to still be in cache, without going
+//! through its size class.
+//!
+//! Promotion is automatic:
+//!
+//! - Each handle samples one in every `sample_period` allocations with a site, and counts the
+//!   samples for each `(site, size)` pair in a small table of its own. Allocations that are not
+//!   sampled only pay for a decrement.
+//! - When a handle has sampled a pair `promotion_threshold` times, the pair is promoted for the
+//!   whole process, up to `MAX_POOLS` pairs. Promotions are never undone.
+//! - Every handle then keeps a pool for each promoted pair: freeing an object of a promoted site
+//!   and size class through the handle puts it in the handle's pool (up to `POOL_CAPACITY`
+//!   objects), and allocating from the site with the pair's size takes an object from the pool if
+//!   there is one. Pools are returned to their size classes when the handle is destroyed.
+//!
+//! `promoted` lists the promoted pairs, with the number of allocations their pools have served.
+//!
+//! Objects in a pool remain allocated as far as the rest of elfmalloc is concerned: they count
+//! towards quotas, and the per-object work of debugging features (quarantine, zeroing, sanitizer
+//! annotations and memory tagging) is skipped when they go through a pool. They do not count as
+//! live objects of their site in `site_stats`.
+
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use super::sites::{self, SiteId, NO_SITE};
+
+/// The maximum number of promoted `(site, size)` pairs.
+pub const MAX_POOLS: usize = 32;
+
+/// The maximum number of objects in each pool of a handle.
+pub const POOL_CAPACITY: usize = 64;
+
+/// The default number of allocations with a site per sample.
+pub const DEFAULT_SAMPLE_PERIOD: usize = 64;
+
+/// The default number of samples after which a pair is promoted.
+pub const DEFAULT_PROMOTION_THRESHOLD: usize = 32;
+
+/// The number of pairs each handle tracks as candidates for promotion.
+const CANDIDATES: usize = 16;
+
+/// The number of allocations a handle serves from a pool before adding them to `Pool::hits`.
+const FLUSH_HITS: usize = 64;
+
+// These hold the setting plus one, so that 0 (their initial value) means the default.
+static SAMPLE_PERIOD: AtomicUsize = ATOMIC_USIZE_INIT;
+static PROMOTION_THRESHOLD: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn load_setting(setting: &AtomicUsize, default: usize) -> usize {
+    match setting.load(Ordering::Relaxed) {
+        0 => default,
+        n => n - 1,
+    }
+}
+
+/// The number of allocations with a site per sample on each handle.
+pub fn sample_period() -> usize {
+    load_setting(&SAMPLE_PERIOD, DEFAULT_SAMPLE_PERIOD)
+}
+
+/// Sample one in every `period` allocations with a site. 0 stops sampling, so no more pairs are
+/// promoted; pairs that already were keep their pools. Handles pick up a new period after their
+/// next sample.
+pub fn set_sample_period(period: usize) {
+    SAMPLE_PERIOD.store(period.saturating_add(1), Ordering::Relaxed);
+}
+
+/// The number of samples of a pair on one handle after which it is promoted.
+pub fn promotion_threshold() -> usize {
+    load_setting(&PROMOTION_THRESHOLD, DEFAULT_PROMOTION_THRESHOLD)
+}
+
+/// Promote pairs after `samples` samples on one handle.
+pub fn set_promotion_threshold(samples: usize) {
+    PROMOTION_THRESHOLD.store(samples.saturating_add(1), Ordering::Relaxed);
+}
+
+/// A promoted pair. Pools are claimed in order, and `site` is written last (with `Release`), so a
+/// pool whose `site` is not `NO_SITE` is fully initialized.
+struct Pool {
+    site: AtomicUsize,
+    size: AtomicUsize,
+    object_size: AtomicUsize,
+    /// Allocations served from this pool by any handle, as of their last flush.
+    hits: AtomicUsize,
+}
+
+lazy_static! {
+    static ref POOLS: [Pool; MAX_POOLS] = unsafe { ::std::mem::zeroed() };
+}
+
+/// The number of pools claimed, which may be ahead of the number initialized.
+static N_POOLS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The index of the first promoted pair whose pool `f` returns true for.
+fn find<F: Fn(&Pool) -> bool>(f: F) -> Option<usize> {
+    let n = ::std::cmp::min(N_POOLS.load(Ordering::Acquire), MAX_POOLS);
+    for (i, pool) in POOLS[..n].iter().enumerate() {
+        if pool.site.load(Ordering::Acquire) != NO_SITE as usize && f(pool) {
+            return Some(i);
+        }
+    }
+    None
+}
+
+/// Promote `(site, size)`, whose objects are `object_size` bytes, unless it already is or every
+/// pool is taken.
+fn promote(site: SiteId, size: usize, object_size: usize) {
+    let site = site as usize;
+    if find(|p| {
+        p.site.load(Ordering::Relaxed) == site && p.size.load(Ordering::Relaxed) == size
+    }).is_some()
+    {
+        return;
+    }
+    // Two handles may promote the same pair at once, in which case it gets two pools, and the
+    // second is never used.
+    let i = N_POOLS.fetch_add(1, Ordering::AcqRel);
+    if i >= MAX_POOLS {
+        return;
+    }
+    let pool = &POOLS[i];
+    pool.size.store(size, Ordering::Relaxed);
+    pool.object_size.store(object_size, Ordering::Relaxed);
+    pool.site.store(site, Ordering::Release);
+}
+
+/// A promoted pair, as reported by `promoted`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PromotedSite {
+    pub site: SiteId,
+    /// The location of the site, as in `sites::SiteStats`.
+    pub file: &'static str,
+    pub line: u32,
+    pub column: u32,
+    /// The size requested by the site's allocations.
+    pub size: usize,
+    /// The size of the size class its objects come from.
+    pub object_size: usize,
+    /// The number of allocations served from the pair's pools, not counting those that handles
+    /// have not flushed yet.
+    pub hits: usize,
+}
+
+/// Get every promoted pair, in the order in which they were promoted.
+pub fn promoted() -> Vec<PromotedSite> {
+    let n = ::std::cmp::min(N_POOLS.load(Ordering::Acquire), MAX_POOLS);
+    POOLS[..n]
+        .iter()
+        .filter_map(|p| {
+            let site = p.site.load(Ordering::Acquire) as SiteId;
+            if site == NO_SITE {
+                return None;
+            }
+            let (file, line, column) = sites::location(site);
+            Some(PromotedSite {
+                site: site,
+                file: file,
+                line: line,
+                column: column,
+                size: p.size.load(Ordering::Relaxed),
+                object_size: p.object_size.load(Ordering::Relaxed),
+                hits: p.hits.load(Ordering::Relaxed),
+            })
+        })
+        .collect()
+}
+
+#[derive(Copy, Clone)]
+struct Candidate {
+    site: SiteId,
+    size: usize,
+    samples: usize,
+}
+
+/// The free objects a handle keeps for one promoted pair, linked through their first word.
+#[derive(Copy, Clone)]
+struct LocalPool {
+    head: *mut u8,
+    len: usize,
+    /// Allocations served since the last flush to `Pool::hits`.
+    hits: usize,
+}
+
+/// The per-handle state: the sampler, the candidates for promotion, and a pool for each promoted
+/// pair (indexed like `POOLS`).
+pub struct Local {
+    countdown: usize,
+    candidates: [Candidate; CANDIDATES],
+    pools: [LocalPool; MAX_POOLS],
+    /// Set once the handle is being destroyed, after which nothing is pooled.
+    retired: bool,
+}
+
+impl Local {
+    pub fn new() -> Local {
+        Local {
+            countdown: 1,
+            candidates: [Candidate {
+                site: NO_SITE,
+                size: 0,
+                samples: 0,
+            }; CANDIDATES],
+            pools: [LocalPool {
+                head: ptr::null_mut(),
+                len: 0,
+                hits: 0,
+            }; MAX_POOLS],
+            retired: false,
+        }
+    }
+
+    /// Take an object for an allocation of `size` bytes at `site` from this handle's pool for the
+    /// pair, if it is promoted and the pool is not empty.
+    #[inline]
+    pub unsafe fn alloc(&mut self, site: SiteId, size: usize) -> Option<*mut u8> {
+        if N_POOLS.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        let i = match find(|p| {
+            p.site.load(Ordering::Relaxed) == site as usize &&
+                p.size.load(Ordering::Relaxed) == size
+        }) {
+            Some(i) => i,
+            None => return None,
+        };
+        let pool = &mut self.pools[i];
+        if pool.head.is_null() {
+            return None;
+        }
+        let item = pool.head;
+        pool.head = *(item as *mut *mut u8);
+        pool.len -= 1;
+        pool.hits += 1;
+        if pool.hits == FLUSH_HITS {
+            POOLS[i].hits.fetch_add(pool.hits, Ordering::Relaxed);
+            pool.hits = 0;
+        }
+        Some(item)
+    }
+
+    /// The pool that a freed object of `object_size` bytes from `site` should go to, if any.
+    #[inline]
+    pub fn pool_for(&self, site: SiteId, object_size: usize) -> Option<usize> {
+        if self.retired || site == NO_SITE || N_POOLS.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        find(|p| {
+            p.site.load(Ordering::Relaxed) == site as usize &&
+                p.object_size.load(Ordering::Relaxed) == object_size
+        }).and_then(|i| if self.pools[i].len < POOL_CAPACITY {
+            Some(i)
+        } else {
+            None
+        })
+    }
+
+    /// Put `item` in pool `i`, which `pool_for` returned.
+    pub unsafe fn push(&mut self, i: usize, item: *mut u8) {
+        let pool = &mut self.pools[i];
+        *(item as *mut *mut u8) = pool.head;
+        pool.head = item;
+        pool.len += 1;
+    }
+
+    /// Return whether the current allocation with a site should be sampled.
+    #[inline(always)]
+    pub fn sample(&mut self) -> bool {
+        self.countdown -= 1;
+        if self.countdown != 0 {
+            return false;
+        }
+        match sample_period() {
+            0 => {
+                // Check again for a new period after as many allocations as by default.
+                self.countdown = DEFAULT_SAMPLE_PERIOD;
+                false
+            }
+            period => {
+                self.countdown = period;
+                true
+            }
+        }
+    }
+
+    /// Count a sample of an allocation of `size` bytes at `site`, whose object is `object_size`
+    /// bytes, promoting the pair if it has been sampled often enough.
+    #[cold]
+    pub fn record(&mut self, site: SiteId, size: usize, object_size: usize) {
+        // Keep the pairs sampled most often: a new pair replaces the candidate with the fewest
+        // samples, and inherits its count, so that a pair that is sampled steadily eventually
+        // displaces pairs that were only sampled in a burst.
+        let mut ix = 0;
+        for (i, c) in self.candidates.iter().enumerate() {
+            if c.site == site && c.size == size {
+                ix = i;
+                break;
+            }
+            if c.samples < self.candidates[ix].samples {
+                ix = i;
+            }
+        }
+        let c = &mut self.candidates[ix];
+        if c.site != site || c.size != size {
+            c.site = site;
+            c.size = size;
+        }
+        c.samples += 1;
+        if c.samples == promotion_threshold() {
+            promote(site, size, object_size);
+        }
+    }
+
+    /// Stop pooling objects. The handle's objects can then be taken out with `take`.
+    pub fn retire(&mut self) {
+        self.retired = true;
+        for (i, pool) in self.pools.iter_mut().enumerate() {
+            if pool.hits != 0 {
+                POOLS[i].hits.fetch_add(pool.hits, Ordering::Relaxed);
+                pool.hits = 0;
+            }
+        }
+    }
+
+    /// Take an object from any of this handle's pools.
+    pub unsafe fn take(&mut self) -> Option<*mut u8> {
+        for pool in self.pools.iter_mut() {
+            if !pool.head.is_null() {
+                let item = pool.head;
+                pool.head = *(item as *mut *mut u8);
+                pool.len -= 1;
+                return Some(item);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings() {
+        alloc_assert_eq!(load_setting(&ATOMIC_USIZE_INIT, 7), 7);
+        alloc_assert_eq!(load_setting(&AtomicUsize::new(1), 7), 0);
+        alloc_assert_eq!(load_setting(&AtomicUsize::new(4), 7), 3);
+    }
+
+    #[test]
+    fn candidates() {
+        let mut local = Local::new();
+        // A pair sampled in a burst, and then steadily sampled pairs that displace it.
+        for _ in 0..4 {
+            local.record(1, 8, 16);
+        }
+        for round in 0..8 {
+            for site in 2..(CANDIDATES as SiteId + 2) {
+                local.record(site, 24, 32);
+            }
+            if round == 0 {
+                alloc_assert!(local.candidates.iter().any(|c| c.site == 1));
+            }
+        }
+        alloc_assert!(local.candidates.iter().all(|c| c.site != 1));
+        alloc_assert!(local.candidates.iter().all(|c| c.samples >= 8));
+    }
+}
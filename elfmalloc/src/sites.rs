@@ -0,0 +1,251 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Allocation call-site attribution.
+//!
+//! The `alloc_site!` macro expands to a `SiteId` that is unique to the location where it is
+//! invoked. Objects allocated with `general::global::alloc_with_site` (or
+//! `DynamicAllocator::alloc_with_site`, or `ElfMallocGlobal::alloc_with_site`) remember their site
+//! for their entire lifetime, using the same per-object storage as tags. The number of live
+//! bytes, live objects and total allocations for each site can be retrieved with `site_stats`.
+//!
+//! ```rust,ignore
+//! let p = elfmalloc::general::global::alloc_with_site(64, alloc_site!());
+//! ```
+//!
+//! Sites are assigned ids in the order in which they are first reached. Once `MAX_SITES` sites
+//! have been registered, any further sites are attributed to `OVERFLOW_SITE`.
+//!
+//! The current per-site table can be exported with `write_massif` in the format produced by
+//! Valgrind's massif tool, so that it can be viewed with `ms_print` or massif-visualizer. Calling
+//! it just before exit yields a leak report: every byte still live is attributed to its site.
+use std::io::{self, Write};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use super::tags::CounterTable;
+use super::utils::TypedArray;
+
+/// An identifier for an allocation site.
+pub type SiteId = u32;
+
+/// The site of objects that were not allocated with an explicit site. These are not accounted for.
+pub const NO_SITE: SiteId = 0;
+
+/// The site to which allocations are attributed once the site registry is full.
+pub const OVERFLOW_SITE: SiteId = !0;
+
+/// The maximum number of distinct sites.
+pub const MAX_SITES: usize = 4096;
+
+/// A static describing an allocation site. Use `alloc_site!` rather than constructing these
+/// directly.
+#[doc(hidden)]
+pub struct Site {
+    pub file: &'static str,
+    pub line: u32,
+    pub column: u32,
+    /// The id assigned to this site, or zero if it has not been registered yet.
+    pub id: AtomicUsize,
+}
+
+impl Site {
+    /// Get the id of this site, registering it if this is the first call.
+    #[inline]
+    pub fn id(&'static self) -> SiteId {
+        let id = self.id.load(Ordering::Acquire);
+        if id != 0 {
+            return id as SiteId;
+        }
+        self.register()
+    }
+
+    #[cold]
+    fn register(&'static self) -> SiteId {
+        // Id 0 is NO_SITE, so ids start at 1.
+        let mut new = N_REGISTERED.fetch_add(1, Ordering::Relaxed) + 1;
+        if new >= MAX_SITES {
+            new = OVERFLOW_SITE as usize;
+        }
+        match self.id.compare_exchange(0, new, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => {
+                if new != OVERFLOW_SITE as usize {
+                    unsafe {
+                        (*SITES.get(new)).store(self as *const Site as *mut Site, Ordering::Release)
+                    };
+                }
+                new as SiteId
+            }
+            // Another thread registered this site first. The id we took is simply never used.
+            Err(id) => id as SiteId,
+        }
+    }
+}
+
+/// Expands to the `SiteId` of the location at which it is invoked.
+#[macro_export]
+macro_rules! alloc_site {
+    () => {{
+        static SITE: $crate::sites::Site = $crate::sites::Site {
+            file: file!(),
+            line: line!(),
+            column: column!(),
+            id: ::std::sync::atomic::ATOMIC_USIZE_INIT,
+        };
+        SITE.id()
+    }};
+}
+
+static N_REGISTERED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+lazy_static! {
+    static ref SITES: TypedArray<AtomicPtr<Site>> = TypedArray::new(MAX_SITES);
+    static ref SITE_COUNTS: CounterTable = CounterTable::new(MAX_SITES);
+}
+
+/// Record that an object of `bytes` bytes was allocated at `site`.
+pub fn account(site: SiteId, bytes: usize) {
+    if site != NO_SITE {
+        SITE_COUNTS.add(site, bytes as isize, 1);
+    }
+}
+
+/// Record that an object of `bytes` bytes allocated at `site` was freed.
+pub fn unaccount(site: SiteId, bytes: usize) {
+    if site != NO_SITE {
+        SITE_COUNTS.add(site, -(bytes as isize), -1);
+    }
+}
+
+/// Allocation statistics for a single site.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SiteStats {
+    pub id: SiteId,
+    /// The location of the site. For `OVERFLOW_SITE`, this is `"<overflow>"`, 0, 0.
+    pub file: &'static str,
+    pub line: u32,
+    pub column: u32,
+    /// Live bytes, including internal fragmentation.
+    pub live_bytes: usize,
+    pub live_objects: usize,
+    /// The number of objects ever allocated at this site.
+    pub total_allocs: usize,
+}
+
+/// Get the statistics for every site that has allocated at least one object.
+///
+/// Counts are read one site at a time, so the result is not an atomic snapshot.
+pub fn site_stats() -> Vec<SiteStats> {
+    SITE_COUNTS
+        .snapshot(OVERFLOW_SITE)
+        .into_iter()
+        .map(|c| {
+            let (file, line, column) = location(c.key);
+            SiteStats {
+                id: c.key,
+                file: file,
+                line: line,
+                column: column,
+                live_bytes: c.bytes,
+                live_objects: c.live,
+                total_allocs: c.total,
+            }
+        })
+        .collect()
+}
+
+/// The file, line and column of `site`, or `("<overflow>", 0, 0)` for `OVERFLOW_SITE` (and for
+/// ids that have not been assigned to a site).
+pub fn location(site: SiteId) -> (&'static str, u32, u32) {
+    let site = if site == OVERFLOW_SITE || site as usize >= MAX_SITES {
+        ptr::null_mut()
+    } else {
+        unsafe { (*SITES.get(site as usize)).load(Ordering::Acquire) }
+    };
+    if site.is_null() {
+        ("<overflow>", 0, 0)
+    } else {
+        unsafe { ((*site).file, (*site).line, (*site).column) }
+    }
+}
+
+/// Write the current per-site live bytes as a single massif snapshot.
+///
+/// `cmd` is recorded as the profiled command. Each site becomes a child of the root of the heap
+/// tree, largest first; sites without live bytes are omitted.
+pub fn write_massif<W: Write>(w: &mut W, cmd: &str) -> io::Result<()> {
+    write_massif_stats(w, cmd, site_stats())
+}
+
+fn write_massif_stats<W: Write>(w: &mut W, cmd: &str, mut stats: Vec<SiteStats>) -> io::Result<()> {
+    stats.retain(|s| s.live_bytes > 0);
+    stats.sort_by(|a, b| b.live_bytes.cmp(&a.live_bytes));
+    let total = stats.iter().map(|s| s.live_bytes).sum::<usize>();
+    writeln!(w, "desc: elfmalloc site statistics")?;
+    writeln!(w, "cmd: {}", cmd)?;
+    writeln!(w, "time_unit: i")?;
+    writeln!(w, "#-----------")?;
+    writeln!(w, "snapshot=0")?;
+    writeln!(w, "#-----------")?;
+    writeln!(w, "time=0")?;
+    writeln!(w, "mem_heap_B={}", total)?;
+    writeln!(w, "mem_heap_extra_B=0")?;
+    writeln!(w, "mem_stacks_B=0")?;
+    writeln!(w, "heap_tree=detailed")?;
+    writeln!(
+        w,
+        "n{}: {} (heap allocation functions) malloc/new/new[], --alloc-fns, etc.",
+        stats.len(),
+        total
+    )?;
+    for s in &stats {
+        // massif expects a code address; there is none, so the site id stands in for it.
+        writeln!(
+            w,
+            " n0: {} 0x{:X}: {} objects ({}:{}:{})",
+            s.live_bytes,
+            s.id,
+            s.live_objects,
+            s.file,
+            s.line,
+            s.column
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn massif_format() {
+        let site = |id, line, bytes| SiteStats {
+            id: id,
+            file: "src/foo.rs",
+            line: line,
+            column: 5,
+            live_bytes: bytes,
+            live_objects: 2,
+            total_allocs: 3,
+        };
+        let stats = vec![site(1, 10, 64), site(2, 20, 0), site(3, 30, 4096)];
+        let mut out = Vec::new();
+        write_massif_stats(&mut out, "test", stats).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let tree: Vec<&str> = out.lines().skip_while(|l| !l.starts_with("heap_tree")).collect();
+        assert_eq!(
+            tree,
+            vec![
+                "heap_tree=detailed",
+                "n2: 4160 (heap allocation functions) malloc/new/new[], --alloc-fns, etc.",
+                " n0: 4096 0x3: 2 objects (src/foo.rs:30:5)",
+                " n0: 64 0x1: 2 objects (src/foo.rs:10:5)",
+            ]
+        );
+        assert!(out.contains("mem_heap_B=4160\n"));
+    }
+}
@@ -60,13 +60,29 @@
 //!
 //! [1]: https://arxiv.org/abs/1503.09006
 use std::mem;
-use std::sync::atomic::{fence, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
 use super::bagpipe::bag::{Revocable, WeakBag};
 use super::bagpipe::{BagPipe, BagCleanup};
 use super::bagpipe::queue::{FAAQueueLowLevel, RevocableFAAQueue};
-use super::utils::{mmap, LazyInitializable, unlikely};
+use super::utils::{mmap, map_addr, random, with_addr, LazyInitializable, unlikely};
+use super::utils::mmap::MapName;
+use super::conf;
+use super::integrity::{self, Violation};
 use super::alloc_type::AllocType;
 use super::sources::MemorySource;
+use super::unmap_batch::RangeBatch;
+#[cfg(feature = "tags")]
+use super::tags::{Label, N_LABELS};
+#[cfg(feature = "asan")]
+use super::asan;
+#[cfg(feature = "valgrind")]
+use super::valgrind;
+#[cfg(feature = "tsan")]
+use super::tsan;
+#[cfg(feature = "ownership")]
+use super::ownership;
+#[cfg(feature = "contention-stats")]
+use super::stats::contention;
 use std::marker::PhantomData;
 use std::ptr;
 use std::cmp;
@@ -74,20 +90,35 @@ use std::cmp;
 pub type SlagPipe<T> = BagPipe<FAAQueueLowLevel<*mut T>, PageCleanup<T>>;
 pub type RevocablePipe<T> = BagPipe<RevocableFAAQueue<*mut T>, PageCleanup<T>>;
 
+/// Unmaps the pages left in a `BagPipe` when it is dropped, unless they came from a source that
+/// unmaps all of its memory at once (see `MemorySource::unmaps_pages`).
 #[derive(Copy, Clone)]
-pub struct PageCleanup<T>(usize, PhantomData<T>);
+pub struct PageCleanup<T>(usize, bool, PhantomData<T>);
 
 impl<T> PageCleanup<T> {
     pub fn new(page_size: usize) -> PageCleanup<T> {
-        PageCleanup(page_size, PhantomData)
+        PageCleanup::unmapping(page_size, true)
+    }
+
+    /// A `PageCleanup` that unmaps pages of `page_size` bytes only if `unmap` is true, for pages
+    /// whose source may unmap all of its memory at once instead.
+    pub fn unmapping(page_size: usize, unmap: bool) -> PageCleanup<T> {
+        PageCleanup(page_size, unmap, PhantomData)
+    }
+
+    /// A `PageCleanup` for pages of `page_size` bytes taken from `source`.
+    pub fn for_source<M: MemorySource>(page_size: usize, source: &M) -> PageCleanup<T> {
+        PageCleanup::unmapping(page_size, source.unmaps_pages())
     }
 }
 
 impl<T> BagCleanup for PageCleanup<T> {
     type Item = *mut T;
     fn cleanup(&self, it: *mut T) {
-        unsafe {
-            mmap::unmap(it as *mut u8, self.0);
+        if self.1 {
+            unsafe {
+                mmap::unmap(it as *mut u8, self.0);
+            }
         }
     }
 }
@@ -119,13 +150,42 @@ where
 
     /// Get access to the backing memory for the allocator.
     fn backing_memory(&self) -> &Self::Block;
+
+    /// Uncommit all cached pages that are not currently in use.
+    ///
+    /// Returns the number of bytes uncommitted.
+    unsafe fn trim(&mut self) -> usize;
 }
 
 
-pub use self::metadata::{Metadata, compute_metadata};
+pub use self::metadata::{Metadata, compute_metadata, MIN_OBJECT_ALIGN};
 
 mod metadata {
     use super::*;
+
+    /// Objects whose size is a multiple of `MIN_OBJECT_ALIGN` are aligned to `MIN_OBJECT_ALIGN`.
+    ///
+    /// C code expects every object of at least 16 bytes to be suitably aligned for
+    /// `max_align_t`, which is 16 bytes on common 64-bit platforms; SSE code in particular faults
+    /// on misaligned 16-byte loads. This costs up to 8 bytes of padding per `Slag`, which Rust-only
+    /// users can avoid with the `relaxed-align` feature (the `Alloc` trait always passes the
+    /// required alignment explicitly).
+    #[cfg(not(feature = "relaxed-align"))]
+    pub const MIN_OBJECT_ALIGN: usize = 16;
+    #[cfg(feature = "relaxed-align")]
+    pub const MIN_OBJECT_ALIGN: usize = 8;
+
+    /// The alignment of the objects in a `Slag` of objects of `size` bytes.
+    ///
+    /// All power-of-two sizes are aligned to their size; other sizes are aligned to the largest
+    /// power of two dividing them, up to `MIN_OBJECT_ALIGN`.
+    pub fn object_alignment(size: usize) -> usize {
+        if size.is_power_of_two() {
+            size
+        } else {
+            ::std::cmp::min(MIN_OBJECT_ALIGN, 1 << size.trailing_zeros())
+        }
+    }
     /// Metadata about a particular size-class of objects allocated to a particular page size.
     #[derive(Copy, Clone, Debug, Eq, PartialEq)]
     pub struct Metadata {
@@ -209,15 +269,11 @@ mod metadata {
         }
 
         /// Compute the total bytes used for `n_objects` objects each of size `size` bytes represented
-        /// by `gran` bits in the bit-set. This function includes the heuristic that all power-of-two
-        /// sizes are aligned to their size, inserting padding accordingly.
+        /// by `gran` bits in the bit-set. This function includes the padding needed to align the
+        /// objects according to `object_alignment`.
         fn total_bytes(size: usize, gran: usize, n_objects: usize) -> usize {
             let header_size = slag_size();
-            let padding = if size.is_power_of_two() {
-                align_padding(size, n_objects, gran)
-            } else {
-                0
-            };
+            let padding = align_padding(object_alignment(size), n_objects, gran);
             header_size + bitset_bytes(n_objects, gran) + padding + n_objects * size
         }
 
@@ -298,11 +354,7 @@ mod metadata {
             }
             // Get the alignment padding we are using. Note that this is already computed in
             // `total_bytes`, we are just extracting it here.
-            let align_padding = if padded_size.is_power_of_two() {
-                align_padding(padded_size, n_objects, gran)
-            } else {
-                0
-            };
+            let align_padding = align_padding(object_alignment(padded_size), n_objects, gran);
 
             // This is takes all of the space we use in this configuration and subtracts all of
             // the "cruft" that isn't used to actually store an object.
@@ -406,6 +458,19 @@ mod metadata {
             compute_metadata(800, 4096, 0, 0.8, 32 << 10, AllocType::SmallSlag);
             compute_metadata(1025, 4096, 0, 0.8, 32 << 10, AllocType::SmallSlag);
         }
+
+        #[test]
+        fn metadata_alignment() {
+            for size in (1..256).map(|i| i * 8) {
+                let meta = compute_metadata(size, 32 << 10, 0, 0.8, 32 << 10, AllocType::SmallSlag);
+                let align = object_alignment(meta.object_size);
+                alloc_assert_eq!(meta.objects_offset as usize % align, 0, "size {}", size);
+                alloc_assert_eq!(meta.object_size % align, 0, "size {}", size);
+                if size % MIN_OBJECT_ALIGN == 0 {
+                    alloc_assert!(align >= MIN_OBJECT_ALIGN, "size {}", size);
+                }
+            }
+        }
     }
 }
 
@@ -493,28 +558,21 @@ mod ref_count {
 
         /// Decrease the `RefCount` by `n`.
         ///
-        /// This method assumes the reference count is at least `n`. This is only enforced in debug
-        /// builds.
+        /// The reference count must be at least `n`; if it is not, the `Slag` is corrupt, which
+        /// the caller has to check for (see `AllocIter`).
         ///
         /// Returns a tuple whose first element indicates the `RefCount` was previously claimed,
-        /// and the second element is the current value of the reference count itself.
+        /// and the second element is the value of the reference count before the decrement.
         pub fn dec_n(&self, n: usize) -> (bool, usize) {
             let was = self.0.fetch_sub(n, Ordering::Release);
             let claimed = was & MASK == MASK;
-            let result = was & !MASK;
-            alloc_debug_assert!(
-                result >= n,
-                "(dec {:?}; claimed={}), was {}, n={}",
-                self as *const Self,
-                claimed,
-                result,
-                n
-            );
-            (claimed, result)
+            (claimed, was & !MASK)
         }
 
-        // keeping this around for debugging purposes
-        #[allow(dead_code)]
+        /// Load the `RefCount`.
+        ///
+        /// Returns a tuple whose first element indicates the `RefCount` is currently claimed, and
+        /// the second element is the current value of the reference count itself.
         pub fn load(&self) -> (bool, usize) {
             let was = self.0.load(Ordering::Acquire);
             let claimed = was & MASK == MASK;
@@ -546,6 +604,11 @@ pub struct Slag {
     pub rc: RefCount,
     // for BagPipe revocation.
     handle: AtomicUsize,
+    /// Set once the `Slag` is found to be corrupt. See `poison`.
+    poisoned: AtomicBool,
+    /// Lazily-allocated side table holding the `Label`s of each object.
+    #[cfg(feature = "tags")]
+    labels: AtomicPtr<u32>,
 }
 
 #[inline]
@@ -585,9 +648,9 @@ pub struct AllocIter {
     pub cur_word: usize,
     /// A pointer to the next word that will be consumed, or one-past-the-end of the final word.
     pub next_word: *mut Word,
-    /// A pointer to the corresponding `Slag`'s `RefCount`. This allows the iterator to decrement
-    /// the `RefCount` when it consumes a new word.
-    refcnt: *const RefCount,
+    /// A pointer to the corresponding `Slag`. This allows the iterator to decrement its
+    /// `RefCount` when it consumes a new word.
+    slag: *const Slag,
     /// The pointer to the beginning of the corresponding `Slag`'s array of objects.
     object_base: *mut u8,
     /// The size of the objects being allocated.
@@ -596,13 +659,16 @@ pub struct AllocIter {
     remaining_words: usize,
     /// The index of the current word (starts at zero).
     cur_word_index: usize,
+    /// The state of the random number generator used to pick objects, or 0 if objects are
+    /// handed out in address order.
+    rng: u64,
 }
 
 impl AllocIter {
     fn new(
         first_bitset_word: *mut Word,
         bitset_words: usize,
-        refcnt: *const RefCount,
+        slag: *const Slag,
         object_base: *mut u8,
         object_size: usize,
     ) -> AllocIter {
@@ -611,16 +677,35 @@ impl AllocIter {
                 .as_ref()
                 .expect("bitset must point to valid memory")
                 .swap(0, Ordering::Acquire);
-            (*refcnt).dec_n(cur_word.count_ones() as usize);
-            AllocIter {
+            let mut iter = AllocIter {
                 cur_word: cur_word,
                 next_word: first_bitset_word.offset(1),
-                refcnt: refcnt,
+                slag: slag,
                 object_base: object_base,
                 object_size: object_size,
                 remaining_words: (bitset_words - 1),
                 cur_word_index: 0,
-            }
+                rng: if conf::randomize() { random::seed() } else { 0 },
+            };
+            iter.claim_word();
+            iter
+        }
+    }
+
+    /// Take the objects in `cur_word`, which was just swapped out of the bit-set, from the
+    /// reference count.
+    ///
+    /// An object that is marked in the bit-set is always counted as well, as `free` increments the
+    /// reference count before it sets the object's bit. If the reference count is too low, the
+    /// `Slag` is corrupt: it is reported and poisoned, and the iterator is emptied, leaking the
+    /// objects it held.
+    unsafe fn claim_word(&mut self) {
+        let n = self.cur_word.count_ones() as usize;
+        let (_, was) = (*self.slag).rc.dec_n(n);
+        if unlikely(was < n) {
+            (*self.slag).corrupt(ptr::null_mut());
+            self.cur_word = 0;
+            self.remaining_words = 0;
         }
     }
 
@@ -636,9 +721,9 @@ impl AllocIter {
         );
         self.next_word = self.next_word.offset(1);
         self.cur_word = next.swap(0, Ordering::Acquire);
-        (*self.refcnt).dec_n(self.cur_word.count_ones() as usize);
         self.remaining_words -= 1;
         self.cur_word_index += 1;
+        self.claim_word();
     }
 }
 
@@ -648,7 +733,11 @@ impl Iterator for AllocIter {
     fn next(&mut self) -> Option<*mut u8> {
         let word_size = Word::bits();
         loop {
-            let next_bit = self.cur_word.trailing_zeros() as usize;
+            let next_bit = if unsafe { unlikely(self.rng != 0) } {
+                random_set_bit(self.cur_word, random::next(&mut self.rng))
+            } else {
+                self.cur_word.trailing_zeros() as usize
+            };
             unsafe {
                 if unlikely(next_bit == word_size) {
                     if self.remaining_words == 0 {
@@ -670,10 +759,30 @@ impl Iterator for AllocIter {
     }
 }
 
+/// Return the index of a set bit of `word` chosen using the random number `r`, or `Word::bits()`
+/// if `word` is zero.
+///
+/// Randomization is limited to the objects in the current word of the bit-set, as only those have
+/// been claimed by the `AllocIter`.
+fn random_set_bit(word: usize, r: u64) -> usize {
+    let n = word.count_ones() as u64;
+    if n == 0 {
+        return Word::bits();
+    }
+    let mut word = word;
+    for _ in 0..(r % n) {
+        // clear the lowest set bit
+        word &= word - 1;
+    }
+    word.trailing_zeros() as usize
+}
+
 pub enum Transition {
     Null,
     Available,
     Full,
+    /// The object was already marked as free: the `Slag` is corrupt.
+    Corrupt,
 }
 
 macro_rules! or_slag_word {
@@ -703,6 +812,70 @@ impl Slag {
         }
     }
 
+    /// Get the `Slag`'s metadata, or `None` if it has never been initialized.
+    ///
+    /// Unlike `get_metadata`, this may be called on memory that has been handed out by a
+    /// `PageAlloc` but not yet initialized as a `Slag`, whose metadata pointer is null.
+    pub fn try_metadata(&self) -> Option<&Metadata> {
+        unsafe { self.meta.load(Ordering::Acquire).as_ref() }
+    }
+
+    /// Is this `Slag` currently in the *owned* state, i.e. claimed by a thread that allocates
+    /// from it? This is only a snapshot, and may change at any time.
+    pub fn is_owned(&self) -> bool {
+        self.rc.load().0
+    }
+
+    /// Take the `Slag` out of circulation because its bit-set and reference count disagree.
+    ///
+    /// A poisoned `Slag` stays claimed, so that no `free` ever makes it available again, and its
+    /// owner moves on to another `Slag` at the next refresh. Its memory is leaked.
+    pub fn poison(&self) {
+        self.poisoned.store(true, Ordering::Release);
+        self.rc.claim();
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Acquire)
+    }
+
+    /// Report this `Slag` as corrupt, as found when freeing `object` to it (or when refilling from
+    /// it, if `object` is null), and poison it. Under `ViolationPolicy::Abort`, this does not
+    /// return.
+    #[cold]
+    pub fn corrupt(&self, object: *mut u8) {
+        integrity::report(Violation::CorruptSlag {
+            slag: self.as_raw() as *mut u8,
+            object: object,
+        });
+        self.poison();
+    }
+
+    /// The object corresponding to bit `bit` of `word`, which is a word of this `Slag`'s bit-set.
+    pub fn object_at(&self, word: *mut Word, bit: usize, m: &Metadata) -> *mut u8 {
+        let bitset = self.as_raw() as usize + m.bitset_offset as usize;
+        let word_ix = (word as usize - bitset) / mem::size_of::<Word>();
+        let item_ix = word_ix * Word::bits() + bit;
+        unsafe {
+            (self.as_raw() as *mut u8)
+                .offset(m.objects_offset)
+                .offset((item_ix << m.bit_rep_shift) as isize)
+        }
+    }
+
+    /// Is `item`, an object in this `Slag` laid out according to `m`, marked as available in the
+    /// bit-set?
+    ///
+    /// Objects that an owning thread has taken from the bit-set but not yet handed out are not
+    /// marked as available, and neither are objects that have been freed into a thread's cache.
+    pub fn is_available(&self, item: *mut u8, m: &Metadata) -> bool {
+        let (word, word_ix) = Self::get_word(self.as_raw(), item, m);
+        let word = unsafe {
+            &*((self.as_raw() as *mut u8).offset(m.bitset_offset) as *mut Word).offset(word)
+        };
+        word.load(Ordering::Acquire) & (1 << word_ix) != 0
+    }
+
     pub fn as_raw(&self) -> *mut Self {
         self as *const _ as *mut Self
     }
@@ -714,11 +887,13 @@ impl Slag {
     /// the `Slag` data-structures. In order to work in complete generality, the bit-set
     /// initialization is a bit subtle.
     pub unsafe fn init(slag: *mut Self, meta: &Metadata) {
+        slow_path_event!(SLAGS_CREATED);
         let slf = slag.as_mut().expect("null slag");
         slf.set_metadata(meta as *const _ as *mut Metadata);
         ptr::write(&mut slf.ty, meta.ty);
         slf.rc.init(meta.n_objects);
         slf.handle.store(0, Ordering::Relaxed);
+        slf.poisoned.store(false, Ordering::Relaxed);
         // This is scaffolding, we perform a slush_size+bits_per_word-bit rotation to compute the
         // mask for each word in the bitset. See the comment in `compute_metadata` for a more
         // detailed example.
@@ -792,7 +967,7 @@ impl Slag {
     pub fn find(item: *mut u8, alignment: usize) -> *mut Self {
         alloc_debug_assert!(alignment.is_power_of_two());
         alloc_debug_assert!(alignment > 0);
-        ((item as usize) & !(alignment - 1)) as *mut Self
+        map_addr(item, |addr| addr & !(alignment - 1)) as *mut Self
     }
 
     #[inline]
@@ -814,7 +989,8 @@ impl Slag {
     /// Free `item` back to this `Slag`.
     ///
     /// This method assumes `item` is a member of `self` (enforced in debug builds). It also
-    /// computes whether or not this `free` operation triggered a state transition.
+    /// computes whether or not this `free` operation triggered a state transition, or whether
+    /// `item` was already free, in which case the reference count is now wrong.
     pub fn free(&self, item: *mut u8) -> Transition {
         let m = self.get_metadata();
         // must be in-bounds
@@ -828,7 +1004,7 @@ impl Slag {
         // instead of the MSB, with all inc-s and dec-s being by 2. This is more obvious but
         // removing barriers may be vital on non-intel machines.
         let (claimed, was) = self.rc.inc_n(1);
-        unsafe {
+        let before = unsafe {
             // get the start of the bitset
             ((self.as_raw() as *mut u8).offset(m.bitset_offset) as *mut Word)
                 // go to the bitset we want
@@ -838,6 +1014,9 @@ impl Slag {
                 // set the bit in question
                 .fetch_or(1 << word_ix, Ordering::Release)
         };
+        if unsafe { unlikely(before & (1 << word_ix) != 0) } {
+            return Transition::Corrupt;
+        }
         if !claimed {
             if was == m.cutoff_objects - 1 {
                 return Transition::Available;
@@ -850,6 +1029,122 @@ impl Slag {
         Transition::Null
     }
 
+    /// Uncommit all pages of the `Slag` that only contain free objects.
+    ///
+    /// The caller must ensure no other thread can allocate from the `Slag` (e.g. by having removed
+    /// it from an available `BagPipe`). Concurrent remote frees are fine: they only ever set bits
+    /// in the bit-set, so a page that is observed to be free stays free. Pages holding the header
+    /// or the bit-set are never uncommitted. Returns the number of bytes uncommitted.
+    pub unsafe fn uncommit_free_pages(&self, meta: &Metadata) -> usize {
+        let page_size = mmap::page_size();
+        let base = self.as_raw() as usize;
+        let objects = base + meta.objects_offset as usize;
+        let end = base + meta.total_bytes;
+        let bitset = (self.as_raw() as *mut u8).offset(meta.bitset_offset) as *mut Word;
+        let is_free = |obj: usize| {
+            let (word, word_ix) = split_index((obj * meta.object_size) >> meta.bit_rep_shift);
+            (*bitset.offset(word)).load(Ordering::Acquire) & (1 << word_ix) != 0
+        };
+        let mut released = 0;
+        let mut page = (objects + page_size - 1) & !(page_size - 1);
+        while page + page_size <= end {
+            let first = (page - objects) / meta.object_size;
+            if first >= meta.n_objects {
+                // the rest of the slag is padding, which is never touched
+                break;
+            }
+            let last = cmp::min(
+                (page + page_size - 1 - objects) / meta.object_size,
+                meta.n_objects - 1,
+            );
+            if (first..(last + 1)).all(&is_free) {
+                mmap::uncommit(with_addr(self.as_raw() as *mut u8, page), page_size);
+                released += page_size;
+            }
+            page += page_size;
+        }
+        released
+    }
+
+    #[cfg(feature = "tags")]
+    fn label_table_bytes(meta: &Metadata) -> usize {
+        let page_size = mmap::page_size();
+        let bytes = meta.n_objects * mem::size_of::<u32>() * N_LABELS;
+        (bytes + page_size - 1) & !(page_size - 1)
+    }
+
+    /// Get a pointer to the entry for `label` of `item` in `table`.
+    #[cfg(feature = "tags")]
+    fn label_entry(&self, table: *mut u32, item: *mut u8, label: Label, meta: &Metadata) -> *mut u32 {
+        let objects = self.as_raw() as usize + meta.objects_offset as usize;
+        let index = (item as usize - objects) / meta.object_size;
+        unsafe { table.offset((index * N_LABELS + label as usize) as isize) }
+    }
+
+    /// Get the `label` of `item`, which must be an object in this `Slag`.
+    #[cfg(feature = "tags")]
+    pub unsafe fn get_label(&self, item: *mut u8, label: Label) -> u32 {
+        let table = self.labels.load(Ordering::Acquire);
+        if table.is_null() {
+            return 0;
+        }
+        ptr::read(self.label_entry(table, item, label, self.get_metadata()))
+    }
+
+    /// Set the `label` of `item`, which must be an object in this `Slag`.
+    ///
+    /// The label table is mapped the first time a nonzero label is set.
+    #[cfg(feature = "tags")]
+    pub unsafe fn set_label(&self, item: *mut u8, label: Label, val: u32) {
+        let meta = self.get_metadata();
+        let mut table = self.labels.load(Ordering::Acquire);
+        if table.is_null() {
+            if val == 0 {
+                return;
+            }
+            let bytes = Self::label_table_bytes(meta);
+            let new_table = mmap::map(bytes) as *mut u32;
+            table = match self.labels.compare_exchange(
+                ptr::null_mut(),
+                new_table,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => new_table,
+                Err(other) => {
+                    // another thread labeling an object in this slag beat us to it
+                    mmap::unmap(new_table as *mut u8, bytes);
+                    other
+                }
+            };
+        }
+        ptr::write(self.label_entry(table, item, label, meta), val);
+    }
+
+    /// Unmap the label table, if there is one.
+    ///
+    /// This must only be called once all of the `Slag`'s objects are free, and before the `Slag`
+    /// is handed back to a `CoarseAllocator` (which may re-initialize it with different metadata).
+    #[cfg(feature = "tags")]
+    pub unsafe fn release_labels(&self) {
+        let table = self.labels.swap(ptr::null_mut(), Ordering::AcqRel);
+        if !table.is_null() {
+            mmap::unmap(table as *mut u8, Self::label_table_bytes(self.get_metadata()));
+        }
+    }
+
+    /// Zero the `Slag`'s header and bit set.
+    ///
+    /// With `zero-on-free`, this is called when a `Slag` whose objects are all free (and thus
+    /// zeroed) is retired, so that its page only holds zeros when it is re-initialized, possibly
+    /// for a size class whose objects start where this one's bit set was. It has the same
+    /// requirements as `release_labels`, and must be called after it.
+    #[cfg(feature = "zero-on-free")]
+    pub unsafe fn scrub(&self) {
+        let len = self.get_metadata().objects_offset as usize;
+        ptr::write_bytes(self.as_raw() as *mut u8, 0, len);
+    }
+
     /// Initialize an `AllocIter` for allocating out of the `Slag`.
     pub fn refresh(&self, meta: &Metadata) -> AllocIter {
         // offset calls are valid because size_of(u8) is 1
@@ -857,7 +1152,7 @@ impl Slag {
             AllocIter::new(
                 (self.as_raw() as *mut u8).offset(meta.bitset_offset) as *mut Word,
                 meta.n_bitset_words,
-                &self.rc,
+                self,
                 (self.as_raw() as *mut u8).offset(meta.objects_offset),
                 1 << meta.bit_rep_shift,
             )
@@ -908,6 +1203,10 @@ where
     aligned_source: C,
     pages_per: usize,
     ty: AllocType,
+    /// Whether clean pages are faulted in before they are handed out. See `set_prefault`.
+    prefault: bool,
+    /// The name given to fresh pages. See `set_name`.
+    name: MapName,
     _marker: PhantomData<D>,
 }
 
@@ -935,30 +1234,88 @@ impl<C: MemorySource, D: DirtyFn> PageAlloc<C, D> {
         alloc_debug_assert!(page_size.is_power_of_two());
         alloc_debug_assert!(align.is_power_of_two());
         let pages_per = align / page_size;
-        let clean = PageCleanup::new(page_size);
         let creek = C::new(page_size);
         let creek_2 = if pages_per > 1 {
             C::new(align)
         } else {
             creek.clone()
         };
+        Self::from_sources(creek, creek_2, pages_per, target_overhead, pipe_size, ty)
+    }
+
+    /// Create a new `PageAlloc` that gets its pages from `source`.
+    pub fn with_source(source: C, target_overhead: usize, pipe_size: usize, ty: AllocType) -> Self {
+        alloc_debug_assert!(source.page_size().is_power_of_two());
+        Self::from_sources(source.clone(), source, 1, target_overhead, pipe_size, ty)
+    }
+
+    fn from_sources(
+        creek: C,
+        aligned_source: C,
+        pages_per: usize,
+        target_overhead: usize,
+        pipe_size: usize,
+        ty: AllocType,
+    ) -> Self {
+        let clean = PageCleanup::for_source(creek.page_size(), &creek);
         PageAlloc {
             target_overhead: target_overhead,
             creek: creek,
             pages_per: pages_per,
-            aligned_source: creek_2,
+            aligned_source: aligned_source,
             clean: SlagPipe::new_size_cleanup(2, clean),
             dirty: SlagPipe::new_size_cleanup(pipe_size, clean),
             ty: ty,
+            prefault: false,
+            name: MapName::new(None, if ty == AllocType::BigSlag { "medium" } else { "small" }),
             _marker: PhantomData,
         }
     }
 
+    /// Name the mappings of fresh pages `name` (see `mmap::name`). By default, pages for
+    /// `BigSlag`s are named `elfmalloc:medium`, and other pages `elfmalloc:small`.
+    ///
+    /// This only applies to this `PageAlloc` and to clones made after the call.
+    pub fn set_name(&mut self, name: MapName) {
+        self.name = name;
+    }
+
+    /// Fault in every clean page before handing it out, so that the page faults for freshly
+    /// committed memory are taken in `alloc` (usually while a new `Slag` is being set up) rather
+    /// than when objects in the page are first written.
+    ///
+    /// This only applies to this `PageAlloc` and to clones made after the call.
+    pub fn set_prefault(&mut self, prefault: bool) {
+        self.prefault = prefault;
+    }
+
+    /// Fault in at least `bytes` bytes of pages ahead of time, and cache them so that they are
+    /// handed out before any other pages. Returns the number of bytes faulted in.
+    ///
+    /// This is meant to be called during initialization by programs that cannot afford page
+    /// faults later on. The pages are only kept until they are trimmed: `trim`, and freeing pages
+    /// while more than the target overhead is cached, uncommits them again.
+    pub fn prefault(&mut self, bytes: usize) -> usize {
+        let page_size = self.creek.page_size();
+        let mut faulted = 0;
+        while faulted < bytes {
+            let page = match self.clean.try_pop_mut() {
+                Ok(ptr) => ptr,
+                Err(_) => self.refresh_pages(),
+            };
+            unsafe { mmap::prefault(page, page_size) };
+            self.dirty.push_mut(page);
+            faulted += page_size;
+        }
+        faulted
+    }
+
     /// Get more clean pages from the backing memory.
     ///
     /// One of these pages is returned to the caller for allocation. The rest are added to the
     /// clean `BagPipe`.
     fn refresh_pages(&mut self) -> *mut u8 {
+        contention_event!(FRESH_PAGES);
         // If we are using a higher alignment, just allocate a single higher-aligned page. If not,
         // allocate two pages.
         let npages = cmp::max(self.pages_per, 2);
@@ -971,12 +1328,29 @@ impl<C: MemorySource, D: DirtyFn> PageAlloc<C, D> {
         // unnecessary, but refresh_pages is not called in the hot path and the cost of writing
         // additional values is trivial compared with synchronization from the BagPipe. As such, it
         // makes sense to perform this write unconditionally.
-        unsafe { ptr::write(pages as *mut AllocType, self.ty) };
-        let iter = (1..npages).map(|i| unsafe {
-            pages.offset(page_size as isize * (i as isize))
-        });
-        self.clean.bulk_add(iter);
-        pages
+        unsafe {
+            mmap::name(pages, npages * page_size, &self.name);
+            ptr::write(pages as *mut AllocType, self.ty);
+        }
+        #[cfg(feature = "ownership")]
+        ownership::register_slags(pages, npages * page_size, self.ty, page_size);
+        // npages is a power of two, so i -> (mult * i + add) % npages is a permutation of the
+        // pages for any odd mult. By default it is the identity; with randomized placement, the
+        // pages are handed out in a random order.
+        let (mult, add) = if conf::randomize() {
+            let mut rng = random::seed();
+            (random::next(&mut rng) as usize | 1, random::next(&mut rng) as usize)
+        } else {
+            (1, 0)
+        };
+        alloc_debug_assert!(npages.is_power_of_two());
+        let nth = move |i: usize| unsafe {
+            let ix = mult.wrapping_mul(i).wrapping_add(add) & (npages - 1);
+            pages.offset(page_size as isize * (ix as isize))
+        };
+        slow_path_event!(BACKEND_PUSHES);
+        self.clean.bulk_add((1..npages).map(&nth));
+        nth(0)
     }
 }
 
@@ -988,22 +1362,55 @@ impl<C: MemorySource, D: DirtyFn> CoarseAllocator for PageAlloc<C, D> {
     }
 
     unsafe fn alloc(&mut self) -> *mut u8 {
-        if let Ok(ptr) = self.dirty.try_pop_mut() {
-            trace_event!(grabbed_dirty);
-            return ptr;
+        slow_path_event!(BACKEND_POPS);
+        match self.dirty.try_pop_mut() {
+            Ok(ptr) => {
+                trace_event!(grabbed_dirty);
+                #[cfg(feature = "tsan")]
+                tsan::acquire(ptr);
+                return ptr;
+            }
+            Err(_status) => {
+                #[cfg(feature = "contention-stats")]
+                contention::pop_failed(&_status, &contention::PAGES_CONTENDED);
+            }
+        }
+        slow_path_event!(BACKEND_POPS);
+        match self.clean.try_pop_mut() {
+            Ok(ptr) => {
+                trace_event!(grabbed_clean);
+                #[cfg(feature = "tsan")]
+                tsan::acquire(ptr);
+                D::dirty(ptr);
+                if self.prefault {
+                    mmap::prefault(ptr, self.creek.page_size());
+                }
+                return ptr;
+            }
+            Err(_status) => {
+                #[cfg(feature = "contention-stats")]
+                contention::pop_failed(&_status, &contention::PAGES_CONTENDED);
+            }
         }
-        if let Ok(ptr) = self.clean.try_pop_mut() {
-            trace_event!(grabbed_clean);
-            D::dirty(ptr);
-            return ptr;
+        let ptr = self.refresh_pages();
+        if self.prefault {
+            mmap::prefault(ptr, self.creek.page_size());
         }
-        self.refresh_pages()
+        ptr
     }
 
     unsafe fn free(&mut self, ptr: *mut u8, decommit: bool) {
         use self::mmap::uncommit;
         use std::cmp;
+        // The page may be used for a different size class next time.
+        #[cfg(feature = "asan")]
+        asan::unpoison(ptr, self.backing_memory().page_size());
+        #[cfg(feature = "valgrind")]
+        valgrind::make_mem_undefined(ptr, self.backing_memory().page_size());
+        #[cfg(feature = "tsan")]
+        tsan::release(ptr);
         let minor_page_size = mmap::page_size() as isize;
+        slow_path_event!(BACKEND_PUSHES);
         if self.dirty.size_guess() >= self.target_overhead as isize {
             uncommit(ptr, self.backing_memory().page_size());
             self.clean.push_mut(ptr);
@@ -1024,6 +1431,126 @@ impl<C: MemorySource, D: DirtyFn> CoarseAllocator for PageAlloc<C, D> {
             self.dirty.push_mut(ptr);
         }
     }
+
+    unsafe fn trim(&mut self) -> usize {
+        let page_size = self.backing_memory().page_size();
+        let clean = &mut self.clean;
+        // Pages carved out of the same mapping are often adjacent, so they are uncommitted in
+        // batches with one `madvise` call per contiguous run. A page only becomes clean once it
+        // has been uncommitted, since it may be reused as soon as it is in `clean`.
+        let mut release = |base: *mut u8, len: usize| {
+            mmap::uncommit(base, len);
+            for i in 0..len / page_size {
+                clean.push_mut(base.offset((i * page_size) as isize));
+            }
+        };
+        let mut batch = RangeBatch::new();
+        let mut released = 0;
+        while let Ok(ptr) = self.dirty.try_pop_mut() {
+            if batch.push(ptr, page_size) {
+                released += batch.flush(&mut release);
+            }
+        }
+        released + batch.flush(&mut release)
+    }
+}
+
+/// A shared concurrent data-structure for caching large objects.
+///
+/// In the `rust_alloc` module, a single `PageSource` is used as a backing store for several
+/// `PageFrontend`s, each with a separate object size less than or equal to the page size of `M`.
+/// This structure allows for unused pages freed from one size class to be used to service
+/// allocations in another size class. The `bump` module uses it as a source of chunks.
+///
+/// The `PageSource` can be configured to decommit a portion of memory reclaimed from object
+/// classes whose size exceeds a certain threshold.
+#[derive(Clone)]
+pub struct PageSource<M: MemorySource> {
+    cutoff_bytes: usize,
+    target_size: usize,
+    pages: SlagPipe<u8>,
+    source: M,
+    name: MapName,
+}
+
+impl<M: MemorySource> PageSource<M> {
+    pub fn new(
+        cutoff_bytes: usize,
+        target_size: usize,
+        pipe_size: usize,
+        page_size: usize,
+    ) -> PageSource<M> {
+        PageSource::with_source(cutoff_bytes, target_size, pipe_size, M::new(page_size))
+    }
+
+    /// Create a new `PageSource` that gets its pages from `source`.
+    pub fn with_source(
+        cutoff_bytes: usize,
+        target_size: usize,
+        pipe_size: usize,
+        source: M,
+    ) -> PageSource<M> {
+        PageSource {
+            cutoff_bytes: cutoff_bytes,
+            target_size: target_size,
+            pages: SlagPipe::new_size_cleanup(
+                pipe_size,
+                PageCleanup::for_source(source.page_size(), &source),
+            ),
+            source: source,
+            name: MapName::new(None, "medium"),
+        }
+    }
+
+    /// Name the mappings of fresh pages `name` (see `mmap::name`) rather than `elfmalloc:medium`.
+    pub fn set_name(&mut self, name: MapName) {
+        self.name = name;
+    }
+
+    /// The size of the pages handed out by this `PageSource`.
+    pub fn page_size(&self) -> usize {
+        self.source.page_size()
+    }
+
+    /// Whether pages handed out by this `PageSource` may be unmapped individually. See
+    /// `MemorySource::unmaps_pages`.
+    pub fn unmaps_pages(&self) -> bool {
+        self.source.unmaps_pages()
+    }
+
+    pub unsafe fn free(&mut self, p: *mut u8, old_size: usize) {
+        if self.pages.size_guess() >= self.target_size as isize {
+            if self.source.unmaps_pages() {
+                mmap::unmap(p, self.source.page_size());
+                return;
+            }
+            // The page stays mapped until its source is released, but none of it is committed.
+            mmap::uncommit(p, self.source.page_size());
+            self.pages.push_mut(p);
+            return;
+        }
+        if old_size >= self.cutoff_bytes {
+            mmap::uncommit(
+                p.offset(self.cutoff_bytes as isize),
+                self.source.page_size() - self.cutoff_bytes,
+            );
+        }
+        self.pages.push_mut(p);
+    }
+
+    pub unsafe fn alloc(&mut self) -> Option<*mut u8> {
+        self.pages.pop_mut().or_else(|| {
+            let npages = 4;
+            self.source.carve(npages).and_then(|pages| {
+                mmap::name(pages, npages * self.source.page_size(), &self.name);
+                for i in 1..npages {
+                    let offset = (i * self.source.page_size()) as isize;
+                    self.pages.push_mut(pages.offset(offset));
+                }
+                Some(pages)
+            })
+        })
+    }
 }
 
 /// Allocator state wrapping a `Slag`.
@@ -1046,19 +1573,31 @@ impl<CA: CoarseAllocator> Drop for SlagAllocator<CA> {
     fn drop(&mut self) {
         unsafe {
             let slag = self.slag;
+            if (*slag).is_poisoned() {
+                return;
+            }
             let meta = &*self.m;
             let (claimed, was) = (*slag).rc.unclaim();
             if claimed {
                 // we used this slag at some point
                 if was == meta.n_objects {
+                    #[cfg(feature = "tags")]
+                    (*slag).release_labels();
+                    #[cfg(feature = "zero-on-free")]
+                    (*slag).scrub();
                     self.pages.free(slag as *mut u8, false);
                     trace_event!(transition_full);
+                    slow_path_event!(SLAGS_RETIRED);
                 // self.transition_full(slag, meta)
                 } else if was >= meta.cutoff_objects {
                     self.transition_available(slag)
                 }
             } else {
                 // we never allocated from this slag, so just free it back to the page allocator
+                #[cfg(feature = "tags")]
+                (*slag).release_labels();
+                #[cfg(feature = "zero-on-free")]
+                (*slag).scrub();
                 self.pages.free(slag as *mut u8, false);
             }
         }
@@ -1108,7 +1647,10 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
         unsafe {
             Slag::init(first_slag, meta.as_ref().expect("metadata null"));
         };
-        let cleanup = PageCleanup::new(pa.backing_memory().page_size());
+        let cleanup = {
+            let source = pa.backing_memory();
+            PageCleanup::for_source(source.page_size(), source)
+        };
         SlagAllocator {
             m: meta,
             slag: first_slag,
@@ -1122,7 +1664,13 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
     pub unsafe fn refresh(&mut self) -> AllocIter {
         let s_ref = &*self.slag;
         let meta = &*self.m;
-        let (_claimed, was) = s_ref.rc.unclaim();
+        // A poisoned slag keeps its claim (see `Slag::poison`), and is replaced below.
+        let poisoned = s_ref.is_poisoned();
+        let (_claimed, was) = if poisoned {
+            (true, 0)
+        } else {
+            s_ref.rc.unclaim()
+        };
         // We used to have this debug_assert
         //
         // debug_assert!(_claimed, "unclaiming slag on refresh");
@@ -1150,7 +1698,7 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
         // to `full` must successfully revoke the slab from the available bagpipe. But this if
         // condition only evaluates to true if it is impossible to transition the slag to
         // available!
-        if was >= meta.cutoff_objects {
+        if !poisoned && was >= meta.cutoff_objects {
             let _claimed = s_ref.rc.claim();
             alloc_debug_assert!(
                 _claimed,
@@ -1162,29 +1710,76 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
             // first we try and get a slag from the available slagpipe. If it is empty, then we get
             // a fresh page from PageAlloc and initialize it with the current object class's
             // metadata.
-            let next_slab = match self.available.try_pop_mut() {
-                Ok(slab) => {
-                    trace_event!(grabbed_available);
-                    slab
-                }
-                Err(_) => {
-                    let new_raw = self.pages.alloc() as *mut Slag;
-                    if (*new_raw).meta.load(Ordering::Relaxed) != self.m {
-                        Slag::init(new_raw, meta);
+            #[cfg(feature = "contention-stats")]
+            let timer = contention::Timer::start(&contention::SLAG_ACQUISITIONS);
+            slow_path_event!(BACKEND_POPS);
+            let next_slab = loop {
+                match self.available.try_pop_mut() {
+                    Ok(slab) => {
+                        if (*slab).is_poisoned() {
+                            // poisoned before it could be revoked; drop it from the pipe
+                            continue;
+                        }
+                        trace_event!(grabbed_available);
+                        contention_event!(AVAILABLE_HITS);
+                        break slab;
+                    }
+                    Err(_status) => {
+                        #[cfg(feature = "contention-stats")]
+                        contention::pop_failed(&_status, &contention::AVAILABLE_CONTENDED);
+                        let new_raw = self.pages.alloc() as *mut Slag;
+                        if (*new_raw).meta.load(Ordering::Relaxed) != self.m {
+                            Slag::init(new_raw, meta);
+                        }
+                        break new_raw;
                     }
-                    new_raw
                 }
             };
             self.slag = next_slab;
             let s_ref = self.slag.as_mut().expect("s_ref_2"); // let s_ref = &*self.slag;
             let claimed = s_ref.rc.claim();
             alloc_debug_assert!(claimed, "claiming new slag after refresh");
-            s_ref.refresh(meta)
+            let iter = s_ref.refresh(meta);
+            #[cfg(feature = "contention-stats")]
+            timer.finish(
+                &contention::SAMPLED_ACQUISITIONS,
+                &contention::ACQUISITION_NANOS,
+            );
+            iter
+        }
+    }
+
+    /// Refresh `iter` and take an object from it, acquiring a new `Slag` if needed.
+    ///
+    /// A refreshed `Slag` has free objects, unless a concurrent `free` has counted an object but
+    /// not yet marked it in the bit-set, so an `AllocIter` that is still empty after a second
+    /// refresh means the `Slag`'s bit-set and reference count disagree. It is reported and
+    /// poisoned, and the next refresh moves on to another `Slag`.
+    pub unsafe fn refill(&mut self, iter: &mut AllocIter) -> *mut u8 {
+        loop {
+            for _ in 0..2 {
+                *iter = self.refresh();
+                if let Some(ptr) = iter.next() {
+                    return ptr;
+                }
+            }
+            (*self.slag).corrupt(ptr::null_mut());
+        }
+    }
+
+    /// Report `slag` as corrupt (see `Slag::corrupt`), as found when freeing `object` to it, and
+    /// revoke it from the available `BagPipe` in case it is there.
+    #[cold]
+    unsafe fn retire(&mut self, slag: *mut Slag, object: *mut u8) {
+        (*slag).corrupt(object);
+        if RevocablePipe::revoke(&slag) {
+            (*slag).handle.store(0, Ordering::Release);
         }
     }
 
     fn transition_available(&mut self, slag: *mut Slag) {
         trace_event!(transition_available);
+        slow_path_event!(BACKEND_PUSHES);
         self.available.push_mut(slag)
     }
 
@@ -1195,13 +1790,20 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
         if RevocablePipe::revoke(&slag) {
             (*slag).handle.store(0, Ordering::Release);
             trace_event!(transition_full);
+            slow_path_event!(SLAGS_RETIRED);
+            #[cfg(feature = "tags")]
+            (*slag).release_labels();
+            #[cfg(feature = "zero-on-free")]
+            (*slag).scrub();
             self.pages.free(
                 slag as *mut u8,
                 real_size >= self.eager_decommit_threshold,
             )
+        } else {
+            // Caught in a strange race condition (see comments in alloc). We can safely return
+            // without further work.
+            contention_event!(REVOKE_FAILURES);
         }
-        // Otherwise caught in a strange race condition (see comments in alloc). We can
-        // safely return without further work.
     }
 
     pub unsafe fn bulk_free(
@@ -1216,17 +1818,17 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
             return;
         }
         trace_event!(bulk_remote_free);
+        contention_event!(BULK_REMOTE_FREES);
         let s_ref = &*slag;
         let (claimed, was) = s_ref.rc.inc_n(n_ones);
         let before = (*word).fetch_or(mask, Ordering::Release);
-        alloc_debug_assert_eq!(
-            before & mask,
-            0,
-            "\nInvalid mask (obj size {:?}): transitioned\n{:064b} with \n{:064b}",
-            meta.object_size,
-            before,
-            mask,
-        );
+        if unlikely(before & mask != 0) {
+            // at least one of the objects was already free
+            let bit = (before & mask).trailing_zeros() as usize;
+            let object = s_ref.object_at(word, bit, meta);
+            self.retire(slag, object);
+            return;
+        }
         let now = was + n_ones;
         if !claimed {
             if now == meta.n_objects {
@@ -1240,13 +1842,51 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
     /// Perform a "remote" free to the `Slag` containing `item`.
     pub unsafe fn free(&mut self, item: *mut u8) {
         trace_event!(remote_free);
+        contention_event!(REMOTE_FREES);
         let meta = &*self.m;
         let it_slag = Slag::find(item, meta.total_bytes);
         match it_slag.as_ref().expect("found invalid slag").free(item) {
             Transition::Null => return,
             Transition::Available => self.transition_available(it_slag),
             Transition::Full => self.transition_full(it_slag, meta),
+            Transition::Corrupt => self.retire(it_slag, item),
+        }
+    }
+
+    /// Uncommit the free pages of all available `Slag`s.
+    ///
+    /// Each available `Slag` is removed from the available `BagPipe` while its pages are scanned so
+    /// that no other thread can allocate from it in the meantime. Returns the number of bytes
+    /// uncommitted.
+    pub unsafe fn trim_available(&mut self) -> usize {
+        let meta = &*self.m;
+        let mut released = 0;
+        for _ in 0..cmp::max(0, self.available.size_guess()) {
+            let slag = match self.available.try_pop_mut() {
+                Ok(slag) => slag,
+                Err(_) => break,
+            };
+            if (*slag).is_poisoned() {
+                continue;
+            }
+            let (_, n_free) = (*slag).rc.load();
+            if n_free == meta.n_objects {
+                // A free racing with our removal of `slag` failed to revoke it, so we have to
+                // perform the transition to full here.
+                (*slag).handle.store(0, Ordering::Release);
+                trace_event!(transition_full);
+                slow_path_event!(SLAGS_RETIRED);
+                #[cfg(feature = "tags")]
+                (*slag).release_labels();
+                #[cfg(feature = "zero-on-free")]
+                (*slag).scrub();
+                self.pages.free(slag as *mut u8, false);
+                continue;
+            }
+            released += (*slag).uncommit_free_pages(meta);
+            self.available.push_mut(slag);
         }
+        released
     }
 
     /// Test if `it` is an element of the current `Slag`.
@@ -1280,3 +1920,73 @@ impl<CA: CoarseAllocator> Clone for SlagAllocator<CA> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::integrity::ViolationPolicy;
+    use super::super::sources::{MapRecord, MmapSource, TrackedSource};
+    use std::sync::Arc;
+
+    #[test]
+    fn random_set_bit_picks_set_bits() {
+        alloc_assert_eq!(random_set_bit(0, 5), Word::bits());
+        let word = 0b1010_0110;
+        let mut seen = 0;
+        for r in 0..8 {
+            let bit = random_set_bit(word, r);
+            alloc_assert!(word & (1 << bit) != 0);
+            seen |= 1 << bit;
+        }
+        // every set bit can be chosen
+        alloc_assert_eq!(seen, word);
+    }
+
+    #[test]
+    fn double_free_poisons_slag() {
+        let _policy = integrity::lock_policy();
+        integrity::set_violation_policy(ViolationPolicy::Continue);
+        unsafe {
+            let pa: PageAlloc<MmapSource> =
+                PageAlloc::new(1 << 16, 1 << 20, 8, AllocType::SmallSlag);
+            let mut alloc = SlagAllocator::new(1 << 10, 64, 0, 0.6, 1 << 20, pa);
+            let mut iter = alloc.refresh();
+            let p = iter.next().unwrap();
+            let slag = alloc.slag;
+            let before = integrity::violation_stats().corrupt_slags;
+            alloc.free(p);
+            alloc_assert!(!(*slag).is_poisoned());
+            alloc.free(p);
+            alloc_assert!((*slag).is_poisoned());
+            alloc_assert_eq!(integrity::violation_stats().corrupt_slags, before + 1);
+            // the poisoned slag is leaked, and allocation carries on from a fresh one
+            let q = alloc.refill(&mut iter);
+            alloc_assert!(alloc.slag != slag);
+            alloc_assert!(Slag::find(q, (*alloc.m).total_bytes) == alloc.slag);
+        }
+        integrity::set_violation_policy(ViolationPolicy::Abort);
+    }
+
+    #[test]
+    fn tracked_page_source_keeps_pages_mapped() {
+        let page_size = mmap::page_size();
+        let record = Arc::new(MapRecord::new());
+        let source = TrackedSource::with_record(page_size, record.clone());
+        // With a target size of 0, every freed page is over the target.
+        let mut ps = PageSource::with_source(page_size, 0, 8, source);
+        alloc_assert!(!ps.unmaps_pages());
+        unsafe {
+            let p = ps.alloc().unwrap();
+            let mapped = record.mapped_bytes();
+            alloc_assert_eq!(mapped, 4 * page_size);
+            *p = 1;
+            ps.free(p, page_size);
+            // The page is uncommitted rather than unmapped, and stays in the record.
+            alloc_assert_eq!(record.mapped_bytes(), mapped);
+            alloc_assert_eq!(*p, 0);
+        }
+        // Pages left in the pipe are unmapped with the record, not by the pipe's cleanup.
+        mem::drop(ps);
+        alloc_assert_eq!(record.mapped_bytes(), 4 * page_size);
+    }
+}
@@ -6,10 +6,16 @@
 // copied, modified, or distributed except according to those terms.
 
 //! Low-level data-structures for getting more memory from the system.
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, AtomicPtr, Ordering};
 use std::mem;
-use super::utils::{likely, mmap};
+use std::ptr;
+use std::slice;
+use super::utils::{likely, mmap, with_addr};
+#[cfg(feature = "mte")]
+use super::mte;
+#[cfg(feature = "demand-commit")]
+use super::{conf, demand};
 
 /// A generator of chunks of memory providing an `sbrk`-like interface.
 pub trait MemorySource
@@ -25,6 +31,12 @@ where
     /// Currently, there is code (see the `Coalescer` in the `slag` module) that relies on fresh
     /// pages returned from `carve` to be filled with zeros.
     fn carve(&self, npages: usize) -> Option<*mut u8>;
+    /// Whether pages returned by `carve` may be unmapped one at a time once they are no longer
+    /// needed. Sources that unmap all of their memory at once (see `TrackedSource`) return false,
+    /// and their pages are uncommitted instead.
+    fn unmaps_pages(&self) -> bool {
+        true
+    }
 }
 
 /// A `MemorySource` that can tell which pointers lie in a region returned by `carve`.
@@ -52,6 +64,19 @@ impl MemorySource for MmapSource {
     }
 
     fn carve(&self, npages: usize) -> Option<*mut u8> {
+        let res = self.map_pages(npages);
+        #[cfg(feature = "mte")]
+        {
+            if let Some(p) = res {
+                unsafe { mte::enable_pages(p, npages * self.page_size) };
+            }
+        }
+        res
+    }
+}
+
+impl MmapSource {
+    fn map_pages(&self, npages: usize) -> Option<*mut u8> {
         trace!("carve({:?})", npages);
         // faster mod for power-of-2 sizes.
         fn mod_size(x: usize, n: usize) -> usize {
@@ -64,6 +89,10 @@ impl MemorySource for MmapSource {
         if self.page_size <= system_page_size {
             return mmap::fallible_map(npages * self.page_size);
         }
+        #[cfg(miri)]
+        {
+            return mmap::fallible_map_aligned(npages * self.page_size, self.page_size);
+        }
         // We want to return pages aligned to our page size, which is larger than the
         // system page size. As a result, we want to allocate an extra page to guarantee a slice of
         // the memory that is aligned to the larger page size.
@@ -93,6 +122,197 @@ impl MemorySource for MmapSource {
     }
 }
 
+/// The mappings made for a heap, which are all unmapped when the record is dropped.
+///
+/// The base and length of each mapping are kept in an array of their own that is mapped and grown
+/// directly with `mmap`, so that recording a mapping never calls into the global allocator (which
+/// may be the heap doing the mapping). The array is sorted by base address, so that a mapping is
+/// found by binary search when a large object is unmapped; inserting or removing an entry moves
+/// the entries after it with a single `memmove`. Mappings are only added and removed on slow
+/// paths, so the array is guarded by a `Mutex`.
+pub struct MapRecord {
+    maps: Mutex<MapArray>,
+}
+
+struct MapArray {
+    data: *mut (usize, usize),
+    len: usize,
+    cap: usize,
+}
+
+impl MapArray {
+    /// The index of the mapping based at `base`, or the index at which it would be inserted.
+    fn search(&self, base: usize) -> Result<usize, usize> {
+        if self.len == 0 {
+            return Err(0);
+        }
+        let maps = unsafe { slice::from_raw_parts(self.data, self.len) };
+        maps.binary_search_by_key(&base, |&(p, _)| p)
+    }
+}
+
+unsafe impl Send for MapRecord {}
+unsafe impl Sync for MapRecord {}
+
+impl MapRecord {
+    pub fn new() -> MapRecord {
+        MapRecord {
+            maps: Mutex::new(MapArray {
+                data: ptr::null_mut(),
+                len: 0,
+                cap: 0,
+            }),
+        }
+    }
+
+    /// Map `len` bytes, and record the mapping.
+    pub fn map(&self, len: usize) -> Option<*mut u8> {
+        mmap::fallible_map(len).map(|p| {
+            self.add(p, len);
+            p
+        })
+    }
+
+    /// Record the mapping `[p, p + len)`, which is then unmapped along with the rest of the
+    /// record.
+    pub fn add(&self, p: *mut u8, len: usize) {
+        let mut maps = self.maps.lock().unwrap();
+        if maps.len == maps.cap {
+            let new_cap = if maps.cap == 0 {
+                mmap::page_size() / mem::size_of::<(usize, usize)>()
+            } else {
+                maps.cap * 2
+            };
+            let elt_size = mem::size_of::<(usize, usize)>();
+            let new_data = mmap::map(new_cap * elt_size) as *mut (usize, usize);
+            if !maps.data.is_null() {
+                unsafe {
+                    ptr::copy_nonoverlapping(maps.data, new_data, maps.len);
+                    mmap::unmap(maps.data as *mut u8, maps.cap * elt_size);
+                }
+            }
+            maps.data = new_data;
+            maps.cap = new_cap;
+        }
+        let ix = match maps.search(p as usize) {
+            Ok(_) => panic!("recording mapping {:?} twice", p),
+            Err(ix) => ix,
+        };
+        unsafe {
+            let at = maps.data.offset(ix as isize);
+            ptr::copy(at, at.offset(1), maps.len - ix);
+            ptr::write(at, (p as usize, len));
+        }
+        maps.len += 1;
+    }
+
+    /// Unmap `[p, p + len)`, which must have been recorded by `map` or `add`, and remove it from
+    /// the record.
+    pub unsafe fn unmap(&self, p: *mut u8, len: usize) {
+        {
+            let mut maps = self.maps.lock().unwrap();
+            let ix = match maps.search(p as usize) {
+                Ok(ix) => ix,
+                Err(_) => panic!("unmapping a mapping that was not recorded: {:?}", p),
+            };
+            let at = maps.data.offset(ix as isize);
+            ptr::copy(at.offset(1), at, maps.len - ix - 1);
+            maps.len -= 1;
+        }
+        mmap::unmap(p, len);
+    }
+
+    /// Call `f` with the base and length of each recorded mapping, in order of address.
+    pub fn for_each<F: FnMut(*mut u8, usize)>(&self, mut f: F) {
+        let maps = self.maps.lock().unwrap();
+        for i in 0..maps.len {
+            let (p, len) = unsafe { *maps.data.offset(i as isize) };
+            f(p as *mut u8, len);
+        }
+    }
+
+    /// The total length of the recorded mappings.
+    pub fn mapped_bytes(&self) -> usize {
+        let mut total = 0;
+        self.for_each(|_, len| total += len);
+        total
+    }
+}
+
+impl Drop for MapRecord {
+    fn drop(&mut self) {
+        let maps = self.maps.get_mut().unwrap();
+        unsafe {
+            for i in 0..maps.len {
+                let (p, len) = *maps.data.offset(i as isize);
+                #[cfg(feature = "ownership")]
+                super::ownership::unregister(p as *mut u8, len);
+                mmap::unmap(p as *mut u8, len);
+            }
+            if !maps.data.is_null() {
+                mmap::unmap(
+                    maps.data as *mut u8,
+                    maps.cap * mem::size_of::<(usize, usize)>(),
+                );
+            }
+        }
+    }
+}
+
+/// A `MemorySource` that maps memory like `MmapSource`, but can record every mapping it makes in a
+/// `MapRecord` shared with other sources. All of the memory of a heap built from such sources can
+/// then be found (to protect it, say) or released at once, by dropping the record.
+///
+/// A source with a record never unmaps its pages individually. A source created with
+/// `MemorySource::new` has no record, and behaves exactly like an `MmapSource`.
+#[derive(Clone)]
+pub struct TrackedSource {
+    inner: MmapSource,
+    record: Option<Arc<MapRecord>>,
+}
+
+unsafe impl Send for TrackedSource {}
+
+impl TrackedSource {
+    /// Create a source with pages of `page_size` bytes whose mappings are added to `record`.
+    pub fn with_record(page_size: usize, record: Arc<MapRecord>) -> TrackedSource {
+        TrackedSource {
+            inner: MmapSource::new(page_size),
+            record: Some(record),
+        }
+    }
+
+    /// The record of this source's mappings, if it has one.
+    pub fn record(&self) -> Option<&Arc<MapRecord>> {
+        self.record.as_ref()
+    }
+}
+
+impl MemorySource for TrackedSource {
+    fn new(page_size: usize) -> TrackedSource {
+        TrackedSource {
+            inner: MmapSource::new(page_size),
+            record: None,
+        }
+    }
+
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn carve(&self, npages: usize) -> Option<*mut u8> {
+        let res = self.inner.carve(npages);
+        if let (Some(p), Some(record)) = (res, self.record.as_ref()) {
+            record.add(p, npages * self.page_size());
+        }
+        res
+    }
+
+    fn unmaps_pages(&self) -> bool {
+        self.record.is_none()
+    }
+}
+
 /// Base address and size of a memory map.
 ///
 /// This could also just be a `*mut [u8]`, but having two fields is more explicit. We need a new
@@ -102,6 +322,8 @@ struct MapAddr(*mut u8, usize);
 
 impl Drop for MapAddr {
     fn drop(&mut self) {
+        #[cfg(feature = "ownership")]
+        super::ownership::unregister(self.0, self.1);
         unsafe {
             mmap::unmap(self.0, self.1);
         }
@@ -192,6 +414,10 @@ impl MemorySource for Creek {
         alloc_assert!(page_size > mem::size_of::<usize>());
         // first, let's grab some memory;
         let (orig_base, heap_size) = get_heap();
+        #[cfg(feature = "mte")]
+        unsafe {
+            mte::enable_pages(orig_base, heap_size)
+        };
         info!("created heap of size {}", heap_size);
         let orig_addr = orig_base as usize;
         let (slush_addr, real_addr) = {
@@ -211,7 +437,7 @@ impl MemorySource for Creek {
             } else {
                 orig_addr
             };
-            (base as *mut u8, (base + page_size) as *mut u8)
+            (with_addr(orig_base, base), with_addr(orig_base, base + page_size))
         };
         Creek {
             page_size: page_size,
@@ -243,3 +469,235 @@ impl Clone for Creek {
         }
     }
 }
+
+/// A contiguous range of reserved address space with incremental commit.
+///
+/// This exposes the reservation idiom used by `Creek` to code building its own arenas: a large
+/// range is mapped up front with `reserve`, and `commit` then hands out consecutive, page-aligned
+/// chunks from the beginning of it. As with `Creek`, reserving memory does not consume physical
+/// memory; pages are only backed once they are written to. Committed memory can be handed back to
+/// the operating system with `uncommit` or `reset`, and the whole range is unmapped when the
+/// `Region` is dropped.
+///
+/// With the `demand-commit` feature, the range is reserved without access permissions, and pages
+/// are made accessible either by `commit` or, in demand mode, on first access (see the `demand`
+/// module).
+#[derive(Debug)]
+pub struct Region {
+    map_info: MapAddr,
+    /// The number of committed bytes, always a multiple of the system page size.
+    committed: AtomicUsize,
+    /// The slot of the region in the `demand` module's table, if it is committed on demand.
+    #[cfg(feature = "demand-commit")]
+    demand_slot: Option<usize>,
+}
+
+unsafe impl Send for Region {}
+unsafe impl Sync for Region {}
+
+/// Reserve `virtual_bytes` bytes of address space, rounded up to the system page size.
+///
+/// Returns `None` if the address space could not be mapped.
+pub fn reserve(virtual_bytes: usize) -> Option<Region> {
+    let page_size = mmap::page_size();
+    let len = (virtual_bytes + page_size - 1) & !(page_size - 1);
+    #[cfg(not(feature = "demand-commit"))]
+    let base = mmap::fallible_map(len);
+    #[cfg(feature = "demand-commit")]
+    let base = demand::reserve(len);
+    base.map(|base| {
+        Region {
+            map_info: MapAddr(base, len),
+            committed: AtomicUsize::new(0),
+            #[cfg(feature = "demand-commit")]
+            demand_slot: if conf::demand_commit() {
+                demand::register(base)
+            } else {
+                None
+            },
+        }
+    })
+}
+
+impl Region {
+    /// The start of the reserved range.
+    pub fn base(&self) -> *mut u8 {
+        self.map_info.0
+    }
+
+    /// The size of the reserved range in bytes.
+    pub fn len(&self) -> usize {
+        self.map_info.1
+    }
+
+    /// The number of bytes handed out by `commit` so far.
+    pub fn committed(&self) -> usize {
+        self.committed.load(Ordering::Relaxed)
+    }
+
+    /// Is `it` a pointer into the reserved range?
+    pub fn contains(&self, it: *mut u8) -> bool {
+        let it_num = it as usize;
+        let base_num = self.base() as usize;
+        it_num >= base_num && it_num < base_num + self.len()
+    }
+
+    /// Commit the next `bytes` bytes (rounded up to the system page size) and return a pointer to
+    /// them.
+    ///
+    /// This is safe to call from multiple threads; each caller receives a disjoint chunk. Fresh
+    /// chunks are zeroed. Returns `None` if the reservation is exhausted, in which case nothing is
+    /// committed.
+    pub fn commit(&self, bytes: usize) -> Option<*mut u8> {
+        let page_size = mmap::page_size();
+        let bytes = (bytes + page_size - 1) & !(page_size - 1);
+        let mut cur = self.committed.load(Ordering::Relaxed);
+        loop {
+            if self.len() - cur < bytes {
+                return None;
+            }
+            match self.committed.compare_exchange_weak(
+                cur,
+                cur + bytes,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(actual) => cur = actual,
+            }
+        }
+        let res = unsafe { self.base().offset(cur as isize) };
+        #[cfg(feature = "demand-commit")]
+        unsafe {
+            if let Some(slot) = self.demand_slot {
+                demand::extend(slot, res.offset(bytes as isize));
+            } else if !demand::commit(res, bytes) {
+                // Give the range back if nobody has committed past it in the meantime.
+                let _ = self.committed.compare_exchange(
+                    cur + bytes,
+                    cur,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+                return None;
+            }
+        }
+        Some(res)
+    }
+
+    /// Return the physical memory backing `[p, p + len)` to the operating system.
+    ///
+    /// The range stays committed from the `Region`'s point of view: it will read as zeros and is
+    /// backed again when written to.
+    ///
+    /// # Safety
+    ///
+    /// The range must be page-aligned, lie in the committed part of this region, and its contents
+    /// must no longer be needed.
+    pub unsafe fn uncommit(&self, p: *mut u8, len: usize) {
+        alloc_debug_assert!(self.contains(p));
+        alloc_debug_assert!(p as usize + len <= self.base() as usize + self.committed());
+        #[cfg(feature = "demand-commit")]
+        {
+            if self.demand_slot.is_some() {
+                // Make the range fault again, so that it is only charged if it is touched again.
+                return demand::decommit(p, len);
+            }
+        }
+        mmap::uncommit(p, len);
+    }
+
+    /// Uncommit everything and start handing out memory from the beginning of the region again.
+    ///
+    /// Requiring `&mut self` guarantees that there are no concurrent calls to `commit`, but it is
+    /// up to the caller to ensure that no pointers into the region are used afterwards.
+    pub fn reset(&mut self) {
+        let committed = self.committed();
+        #[cfg(feature = "demand-commit")]
+        {
+            if let Some(slot) = self.demand_slot {
+                demand::truncate(slot, self.base());
+            }
+            if committed > 0 {
+                unsafe { demand::decommit(self.base(), committed) };
+            }
+        }
+        #[cfg(not(feature = "demand-commit"))]
+        {
+            if committed > 0 {
+                unsafe { mmap::uncommit(self.base(), committed) };
+            }
+        }
+        self.committed.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "demand-commit")]
+impl Drop for Region {
+    fn drop(&mut self) {
+        // The address space is unmapped when `map_info` is dropped, after this.
+        if let Some(slot) = self.demand_slot {
+            demand::unregister(slot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn region_commit() {
+        let page_size = mmap::page_size();
+        let mut region = reserve(16 * page_size + 1).unwrap();
+        alloc_assert_eq!(region.len(), 17 * page_size);
+        let first = region.commit(1).unwrap();
+        alloc_assert_eq!(first, region.base());
+        let second = region.commit(page_size + 1).unwrap();
+        alloc_assert_eq!(second as usize, first as usize + page_size);
+        alloc_assert_eq!(region.committed(), 3 * page_size);
+        alloc_assert!(region.commit(15 * page_size).is_none());
+        unsafe {
+            *second = 1;
+            region.uncommit(second, page_size);
+            alloc_assert_eq!(*second, 0);
+        }
+        region.reset();
+        alloc_assert_eq!(region.commit(17 * page_size), Some(region.base()));
+    }
+
+    #[test]
+    fn tracked_source_records_mappings() {
+        let page_size = mmap::page_size();
+        let record = Arc::new(MapRecord::new());
+        let source = TrackedSource::with_record(4 * page_size, record.clone());
+        alloc_assert!(!source.unmaps_pages());
+        alloc_assert!(TrackedSource::new(page_size).record().is_none());
+        // Enough mappings to grow the record's array a few times.
+        let mut large = Vec::new();
+        for i in 0..1024 {
+            let p = source.carve(2).unwrap();
+            alloc_assert_eq!(p as usize % (4 * page_size), 0);
+            unsafe { *p = 1 };
+            large.push(record.map((i % 4 + 1) * page_size).unwrap());
+        }
+        let carved = 1024 * 8 * page_size;
+        let mapped = 1024 / 4 * 10 * page_size;
+        alloc_assert_eq!(record.mapped_bytes(), carved + mapped);
+        for (i, p) in large.into_iter().enumerate() {
+            if i % 2 == 0 {
+                unsafe { record.unmap(p, (i % 4 + 1) * page_size) };
+            }
+        }
+        alloc_assert_eq!(record.mapped_bytes(), carved + 1024 / 4 * 6 * page_size);
+        // The record is kept in order of address, whatever order the mappings were made in.
+        let mut prev = 0;
+        record.for_each(|p, _| {
+            alloc_assert!(p as usize > prev);
+            prev = p as usize;
+        });
+        // The rest is unmapped here.
+        mem::drop(source);
+        mem::drop(record);
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! An allocator that sends small and large objects to different allocators.
+//!
+//! `SplitBySize` is built from two `Alloc`s and a threshold: objects of at most `threshold`
+//! bytes are allocated from the first, and larger ones from the second. This makes it possible to
+//! compose allocators that are each good at one thing, e.g. an elfmalloc handle for small objects
+//! and `mmap_alloc::MapAlloc` for big buffers:
+//!
+//! ```rust,ignore
+//! let mut a = SplitBySize::new(64 << 10, SharedAlloc, MapAlloc::default());
+//! ```
+//!
+//! Frees are routed by the size in the `Layout`, so no lookup is needed to find the allocator
+//! that owns an object. This only works if the size passed to `dealloc` is on the same side of
+//! the threshold as the size it was allocated with. The `Alloc` contract allows any size in the
+//! range returned by `usable_size`, so `SplitBySize::usable_size` clamps that range to the
+//! allocator's side of the threshold. `realloc` moves an object from one allocator to the other
+//! when its size crosses the threshold.
+
+use std::cmp;
+use std::ptr;
+use super::alloc::allocator::{Alloc, AllocErr, Layout};
+
+/// An `Alloc` that allocates objects of at most `threshold` bytes from `S` and larger ones from
+/// `L`. See the module documentation.
+pub struct SplitBySize<S: Alloc, L: Alloc> {
+    threshold: usize,
+    small: S,
+    large: L,
+}
+
+impl<S: Alloc, L: Alloc> SplitBySize<S, L> {
+    pub fn new(threshold: usize, small: S, large: L) -> SplitBySize<S, L> {
+        SplitBySize {
+            threshold: threshold,
+            small: small,
+            large: large,
+        }
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub fn small(&self) -> &S {
+        &self.small
+    }
+
+    pub fn small_mut(&mut self) -> &mut S {
+        &mut self.small
+    }
+
+    pub fn large(&self) -> &L {
+        &self.large
+    }
+
+    pub fn large_mut(&mut self) -> &mut L {
+        &mut self.large
+    }
+
+    pub fn into_inner(self) -> (S, L) {
+        (self.small, self.large)
+    }
+
+    fn is_small(&self, l: &Layout) -> bool {
+        l.size() <= self.threshold
+    }
+}
+
+unsafe impl<S: Alloc, L: Alloc> Alloc for SplitBySize<S, L> {
+    unsafe fn alloc(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        if self.is_small(&l) {
+            self.small.alloc(l)
+        } else {
+            self.large.alloc(l)
+        }
+    }
+
+    unsafe fn alloc_zeroed(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        if self.is_small(&l) {
+            self.small.alloc_zeroed(l)
+        } else {
+            self.large.alloc_zeroed(l)
+        }
+    }
+
+    unsafe fn dealloc(&mut self, p: *mut u8, l: Layout) {
+        if self.is_small(&l) {
+            self.small.dealloc(p, l)
+        } else {
+            self.large.dealloc(p, l)
+        }
+    }
+
+    unsafe fn realloc(
+        &mut self,
+        p: *mut u8,
+        old_l: Layout,
+        new_l: Layout,
+    ) -> Result<*mut u8, AllocErr> {
+        match (self.is_small(&old_l), self.is_small(&new_l)) {
+            (true, true) => self.small.realloc(p, old_l, new_l),
+            (false, false) => self.large.realloc(p, old_l, new_l),
+            _ => {
+                // The object changes allocators, so it has to be copied.
+                let new_p = self.alloc(new_l.clone())?;
+                ptr::copy_nonoverlapping(p, new_p, cmp::min(old_l.size(), new_l.size()));
+                self.dealloc(p, old_l);
+                Ok(new_p)
+            }
+        }
+    }
+
+    fn usable_size(&self, l: &Layout) -> (usize, usize) {
+        if self.is_small(l) {
+            let (min, max) = self.small.usable_size(l);
+            (min, cmp::min(max, self.threshold))
+        } else {
+            let (min, max) = self.large.usable_size(l);
+            (cmp::max(min, self.threshold + 1), max)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::heap::Heap;
+    use counting::CountingAlloc;
+    use rust_alloc::SharedAlloc;
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, 8).unwrap()
+    }
+
+    #[test]
+    fn routes_by_size() {
+        let mut a = SplitBySize::new(
+            1 << 10,
+            CountingAlloc::new(SharedAlloc),
+            CountingAlloc::new(Heap),
+        );
+        unsafe {
+            let small = a.alloc(layout(1 << 10)).unwrap();
+            let large = a.alloc_zeroed(layout((1 << 10) + 1)).unwrap();
+            alloc_assert_eq!(a.small().stats().allocs, 1);
+            alloc_assert_eq!(a.large().stats().allocs, 1);
+
+            // Growing the small object past the threshold moves it, contents and all.
+            *small = 42;
+            let moved = a.realloc(small, layout(1 << 10), layout(4 << 10)).unwrap();
+            alloc_assert_eq!(*moved, 42);
+            alloc_assert_eq!(a.small().stats().live_objects, 0);
+            alloc_assert_eq!(a.large().stats().live_objects, 2);
+            let moved = a.realloc(moved, layout(4 << 10), layout(8 << 10)).unwrap();
+            alloc_assert_eq!(a.large().stats().reallocs, 1);
+
+            // Shrinking it below the threshold moves it back.
+            let back = a.realloc(moved, layout(8 << 10), layout(16)).unwrap();
+            alloc_assert_eq!(*back, 42);
+            alloc_assert_eq!(a.small().stats().live_objects, 1);
+
+            a.dealloc(back, layout(16));
+            a.dealloc(large, layout((1 << 10) + 1));
+        }
+        alloc_assert_eq!(a.small().stats().live_objects, 0);
+        alloc_assert_eq!(a.large().stats().live_objects, 0);
+
+        // The usable sizes stay on the object's side of the threshold.
+        alloc_assert!(a.usable_size(&layout(1000)).1 <= 1 << 10);
+        alloc_assert!(a.usable_size(&layout((1 << 10) + 1)).0 > 1 << 10);
+    }
+}
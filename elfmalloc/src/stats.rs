@@ -22,6 +22,84 @@
 //! during thread initialization time because TLS can call `calloc`. This is similar to a problem
 //! encountered in the `general::global` in this crate. `RefCell`'s `try_borrow` gives us a guard
 //! against this recursion.
+//!
+//! ## Contention statistics
+//!
+//! With the `contention-stats` feature, the paths where threads synchronize through the shared
+//! backend (acquiring a `Slag` or a page from a `BagPipe`, freeing objects to a `Slag` owned by
+//! another thread, and draining a cache's remote frees) update process-wide counters, which can
+//! be read with `contention_stats`. They are shared by all handles, including `SharedAlloc` and
+//! the global allocator.
+//!
+//! The counters distinguish operations that found a `BagPipe` empty from those that failed
+//! because of concurrent access (`*_contended`); a high proportion of the latter means that
+//! threads are fighting over the shared structures rather than waiting on memory. Optionally,
+//! one in every `set_contention_sample_period` `Slag` acquisitions and drains is also timed.
+//! None of this is on the fast path, but the counters themselves are shared by every thread, so
+//! the feature should only be enabled while diagnosing a problem.
+//!
+//! ## Layout
+//!
+//! Every process-wide counter written by more than one thread is `CachePadded`, so that counting
+//! an event does not invalidate the cache line holding another counter, or any of the read-mostly
+//! data that the linker happens to place next to it. Read-mostly settings such as the sampling
+//! periods are left unpadded.
+//!
+//! ## Realloc statistics
+//!
+//! With the `realloc-stats` feature, every `realloc` of an existing object records whether it
+//! was satisfied in place (because the object was already large enough, possibly uncommitting the
+//! tail of a large allocation that shrank, or because a large allocation's mapping could be
+//! extended where it is), by moving a large allocation's pages with `mremap`, or by allocating a
+//! new object and copying. They can be read with `realloc_stats`,
+//! and are shared by all handles like the contention counters.
+//!
+//! ## Slow-path statistics
+//!
+//! With the `slow-path-stats` feature, the paths that leave a thread's cache count how often they
+//! run: memory mapped and unmapped by elfmalloc, `Slag`s initialized for a size class and retired
+//! to the page cache, operations on the shared backend, cache drains, and caches freed by decay
+//! (see the `decay` module). They can be read with `slow_path_stats`. Each event is a single
+//! relaxed increment, and none of them happen on the fast path, so a change in how often the slow
+//! path runs shows up here well before it shows up in throughput. The shared backend is
+//! lock-free, so rather than lock acquisitions it counts pushes and pops on its `BagPipe`s, which
+//! are where threads synchronize.
+//!
+//! ## Heap statistics
+//!
+//! With the `stats` feature, every allocation and free through a heap handle (the global
+//! allocator's thread-local handles, `DynamicAllocator` and `SpecializedAllocator`) is counted,
+//! along with the number of bytes requested, and reported by `heap_stats`. Unlike the other
+//! statistics, these are updated on the fast path, so they are designed to cost as little as
+//! possible when enabled and nothing when disabled:
+//!
+//! - Each handle counts its own operations in plain integers (`heap::Local`), and only publishes
+//!   them once every `heap::FLUSH_EVENTS` operations, and when the handle is destroyed. A
+//!   snapshot can therefore miss up to `FLUSH_EVENTS` recent operations per live handle.
+//! - Handles publish to a slot of their own (`heap::Slot`) rather than to shared counters, so
+//!   publishing is a few plain stores to a cache line that no other thread writes. Each slot
+//!   carries a sequence number, which is odd while its handle is publishing.
+//! - `heap_stats` totals the slots without taking a lock, and repeats the total until the sum of
+//!   the sequence numbers stops changing, at which point the total is a snapshot of every handle
+//!   at one instant. A reader never makes a handle wait, so polling the statistics, at any rate,
+//!   adds nothing to the latency of allocations; at worst, a reader that keeps racing with
+//!   publishing handles gives up after a few attempts and returns a total whose counts are
+//!   consistent for each handle.
+//! - All counting goes through the `heap_event!` macro, which expands to nothing without the
+//!   feature, and the counters in each handle are a field that only exists with the feature, so
+//!   a build without it has the same code and the same handle layout as if the counters had never
+//!   been written. `bench_stats` measures the difference between the two builds.
+//!
+//! ## Latency statistics
+//!
+//! With the `latency-stats` feature, one in every `set_latency_sample_period` calls to `alloc` and
+//! `free` on each handle is timed, and the duration is added to a histogram for the operation and
+//! the size class of the object (large objects share a single histogram). The histograms can be
+//! read with `latency_stats` and printed with `dump_latency_stats`. Most calls are served from a
+//! thread's cache in a few nanoseconds, so the interesting part of a histogram is its tail: the
+//! calls that had to acquire a `Slag`, fault in fresh pages, or map a large object. Each handle
+//! counts down to its next sample privately, so operations that are not sampled only pay for a
+//! decrement.
 
 type Num = i64;
 
@@ -74,6 +152,1062 @@ pub mod state {
 
 }
 
+/// Counters describing synchronization in the shared backend. See the module documentation.
+#[cfg(feature = "contention-stats")]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentionStats {
+    /// Times a thread needed a new `Slag` because its current one was exhausted.
+    pub slag_acquisitions: usize,
+    /// `Slag` acquisitions satisfied from the available `BagPipe`.
+    pub available_hits: usize,
+    /// Pops from the available `BagPipe` that failed because of concurrent access. The thread
+    /// falls back to a fresh page, even if there were available `Slag`s.
+    pub available_contended: usize,
+    /// Pops from the clean or dirty page `BagPipe`s that failed because of concurrent access.
+    pub pages_contended: usize,
+    /// Times new memory was carved out of the backing memory source.
+    pub fresh_pages: usize,
+    /// Individual frees to a `Slag` owned by another thread.
+    pub remote_frees: usize,
+    /// Batched frees to a `Slag` owned by another thread.
+    pub bulk_remote_frees: usize,
+    /// Times a cache drained its remote frees back to their `Slag`s.
+    pub drains: usize,
+    /// Objects returned by those drains.
+    pub drained_objects: usize,
+    /// Attempts to remove an empty `Slag` from the available `BagPipe` that lost a race with
+    /// another thread.
+    pub revoke_failures: usize,
+    /// The number of timed `Slag` acquisitions.
+    pub sampled_acquisitions: usize,
+    /// The total time spent in timed `Slag` acquisitions, in nanoseconds.
+    pub acquisition_nanos: usize,
+    /// The number of timed drains.
+    pub sampled_drains: usize,
+    /// The total time spent in timed drains, in nanoseconds.
+    pub drain_nanos: usize,
+}
+
+#[cfg(feature = "contention-stats")]
+impl ContentionStats {
+    /// The mean duration of a timed `Slag` acquisition in nanoseconds, if any were timed.
+    pub fn mean_acquisition_nanos(&self) -> Option<usize> {
+        self.acquisition_nanos.checked_div(self.sampled_acquisitions)
+    }
+
+    /// The mean duration of a timed drain in nanoseconds, if any were timed.
+    pub fn mean_drain_nanos(&self) -> Option<usize> {
+        self.drain_nanos.checked_div(self.sampled_drains)
+    }
+}
+
+#[cfg(feature = "contention-stats")]
+pub mod contention {
+    use super::ContentionStats;
+    use super::super::bagpipe::bag::PopStatus;
+    use super::super::utils::CachePadded;
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use std::time::Instant;
+
+    pub static SLAG_ACQUISITIONS: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static AVAILABLE_HITS: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static AVAILABLE_CONTENDED: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static PAGES_CONTENDED: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static FRESH_PAGES: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static REMOTE_FREES: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static BULK_REMOTE_FREES: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static DRAINS: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static DRAINED_OBJECTS: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static REVOKE_FAILURES: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static SAMPLED_ACQUISITIONS: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static ACQUISITION_NANOS: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static SAMPLED_DRAINS: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static DRAIN_NANOS: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+
+    /// Time one in this many events; 0 disables timing.
+    pub static SAMPLE_PERIOD: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// Count a failed pop from a `BagPipe` in `ctr` if it failed because of contention.
+    #[inline]
+    pub fn pop_failed(status: &PopStatus, ctr: &AtomicUsize) {
+        if let PopStatus::TransientFailure = *status {
+            ctr.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// A timer for an event that may have been chosen for sampling.
+    pub struct Timer(Option<Instant>);
+
+    impl Timer {
+        /// Count an event in `ctr`, and start timing it if it is sampled.
+        #[inline]
+        pub fn start(ctr: &AtomicUsize) -> Timer {
+            let n = ctr.fetch_add(1, Ordering::Relaxed);
+            let period = SAMPLE_PERIOD.load(Ordering::Relaxed);
+            if period != 0 && n % period == 0 {
+                Timer(Some(Instant::now()))
+            } else {
+                Timer(None)
+            }
+        }
+
+        /// Stop the timer, adding to `samples` and `nanos` if the event was sampled.
+        #[inline]
+        pub fn finish(self, samples: &AtomicUsize, nanos: &AtomicUsize) {
+            if let Some(start) = self.0 {
+                let elapsed = start.elapsed();
+                let elapsed = elapsed.as_secs() as usize * 1_000_000_000 +
+                    elapsed.subsec_nanos() as usize;
+                samples.fetch_add(1, Ordering::Relaxed);
+                nanos.fetch_add(elapsed, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn all() -> [&'static AtomicUsize; 14] {
+        [
+            &SLAG_ACQUISITIONS,
+            &AVAILABLE_HITS,
+            &AVAILABLE_CONTENDED,
+            &PAGES_CONTENDED,
+            &FRESH_PAGES,
+            &REMOTE_FREES,
+            &BULK_REMOTE_FREES,
+            &DRAINS,
+            &DRAINED_OBJECTS,
+            &REVOKE_FAILURES,
+            &SAMPLED_ACQUISITIONS,
+            &ACQUISITION_NANOS,
+            &SAMPLED_DRAINS,
+            &DRAIN_NANOS,
+        ]
+    }
+
+    pub fn snapshot() -> ContentionStats {
+        let load = |ctr: &AtomicUsize| ctr.load(Ordering::Relaxed);
+        ContentionStats {
+            slag_acquisitions: load(&SLAG_ACQUISITIONS),
+            available_hits: load(&AVAILABLE_HITS),
+            available_contended: load(&AVAILABLE_CONTENDED),
+            pages_contended: load(&PAGES_CONTENDED),
+            fresh_pages: load(&FRESH_PAGES),
+            remote_frees: load(&REMOTE_FREES),
+            bulk_remote_frees: load(&BULK_REMOTE_FREES),
+            drains: load(&DRAINS),
+            drained_objects: load(&DRAINED_OBJECTS),
+            revoke_failures: load(&REVOKE_FAILURES),
+            sampled_acquisitions: load(&SAMPLED_ACQUISITIONS),
+            acquisition_nanos: load(&ACQUISITION_NANOS),
+            sampled_drains: load(&SAMPLED_DRAINS),
+            drain_nanos: load(&DRAIN_NANOS),
+        }
+    }
+
+    pub fn reset() {
+        for ctr in all().iter() {
+            ctr.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Get the process-wide contention counters.
+///
+/// Counters are read one at a time, so the result is not an atomic snapshot.
+#[cfg(feature = "contention-stats")]
+pub fn contention_stats() -> ContentionStats {
+    contention::snapshot()
+}
+
+/// Reset the process-wide contention counters to zero.
+#[cfg(feature = "contention-stats")]
+pub fn reset_contention_stats() {
+    contention::reset()
+}
+
+/// Time one in every `period` `Slag` acquisitions and cache drains; 0 (the default) disables
+/// timing.
+#[cfg(feature = "contention-stats")]
+pub fn set_contention_sample_period(period: usize) {
+    contention::SAMPLE_PERIOD.store(period, ::std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Counts of how `realloc` calls were satisfied. See the module documentation.
+#[cfg(feature = "realloc-stats")]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReallocStats {
+    /// Calls that returned the object unchanged because it was already large enough.
+    pub fit: usize,
+    /// Calls that shrank a large allocation and uncommitted the pages past its new end (see
+    /// `conf::shrink_threshold`).
+    pub shrunk: usize,
+    /// Calls on large allocations whose mapping was resized in place.
+    pub remapped_in_place: usize,
+    /// Calls on large allocations whose pages were moved to a new address without copying.
+    pub remapped_moved: usize,
+    /// Calls that allocated a new object and copied the old one into it.
+    pub copied: usize,
+    /// The number of bytes copied by those calls.
+    pub copied_bytes: usize,
+}
+
+#[cfg(feature = "realloc-stats")]
+impl ReallocStats {
+    /// The number of calls that returned the original address.
+    pub fn in_place(&self) -> usize {
+        self.fit + self.shrunk + self.remapped_in_place
+    }
+
+    /// The number of calls that were counted.
+    pub fn total(&self) -> usize {
+        self.in_place() + self.remapped_moved + self.copied
+    }
+}
+
+#[cfg(feature = "realloc-stats")]
+pub mod realloc {
+    use super::ReallocStats;
+    use super::super::utils::CachePadded;
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+    pub static FIT: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static SHRUNK: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static REMAPPED_IN_PLACE: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static REMAPPED_MOVED: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static COPIED: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static COPIED_BYTES: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+
+    fn all() -> [&'static AtomicUsize; 6] {
+        [
+            &FIT,
+            &SHRUNK,
+            &REMAPPED_IN_PLACE,
+            &REMAPPED_MOVED,
+            &COPIED,
+            &COPIED_BYTES,
+        ]
+    }
+
+    pub fn snapshot() -> ReallocStats {
+        let load = |ctr: &AtomicUsize| ctr.load(Ordering::Relaxed);
+        ReallocStats {
+            fit: load(&FIT),
+            shrunk: load(&SHRUNK),
+            remapped_in_place: load(&REMAPPED_IN_PLACE),
+            remapped_moved: load(&REMAPPED_MOVED),
+            copied: load(&COPIED),
+            copied_bytes: load(&COPIED_BYTES),
+        }
+    }
+
+    pub fn reset() {
+        for ctr in all().iter() {
+            ctr.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Get the process-wide realloc counters.
+///
+/// Counters are read one at a time, so the result is not an atomic snapshot.
+#[cfg(feature = "realloc-stats")]
+pub fn realloc_stats() -> ReallocStats {
+    realloc::snapshot()
+}
+
+/// Reset the process-wide realloc counters to zero.
+#[cfg(feature = "realloc-stats")]
+pub fn reset_realloc_stats() {
+    realloc::reset()
+}
+
+/// Counts of slow-path events. See the module documentation.
+#[cfg(feature = "slow-path-stats")]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowPathStats {
+    /// Memory mappings created.
+    pub maps: usize,
+    /// The number of bytes in those mappings.
+    pub mapped_bytes: usize,
+    /// Memory mappings (or parts of mappings) removed.
+    pub unmaps: usize,
+    /// The number of bytes unmapped.
+    pub unmapped_bytes: usize,
+    /// `Slag`s initialized for a size class.
+    pub slags_created: usize,
+    /// Empty `Slag`s whose pages were returned to the page cache.
+    pub slags_retired: usize,
+    /// Attempts to pop a `Slag` or a page from one of the shared backend's `BagPipe`s.
+    pub backend_pops: usize,
+    /// Pushes of `Slag`s or pages to the shared backend's `BagPipe`s.
+    pub backend_pushes: usize,
+    /// Times a cache returned a batch of objects to their `Slag`s.
+    pub drains: usize,
+    /// Thread caches freed because they went unused (`cache-decay` feature).
+    pub cache_decays: usize,
+}
+
+#[cfg(feature = "slow-path-stats")]
+pub mod slow_path {
+    use super::SlowPathStats;
+    use super::super::utils::CachePadded;
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+    pub static MAPS: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static MAPPED_BYTES: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static UNMAPS: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static UNMAPPED_BYTES: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static SLAGS_CREATED: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static SLAGS_RETIRED: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static BACKEND_POPS: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static BACKEND_PUSHES: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static DRAINS: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+    pub static CACHE_DECAYS: CachePadded<AtomicUsize> = CachePadded(ATOMIC_USIZE_INIT);
+
+    fn all() -> [&'static AtomicUsize; 10] {
+        [
+            &MAPS,
+            &MAPPED_BYTES,
+            &UNMAPS,
+            &UNMAPPED_BYTES,
+            &SLAGS_CREATED,
+            &SLAGS_RETIRED,
+            &BACKEND_POPS,
+            &BACKEND_PUSHES,
+            &DRAINS,
+            &CACHE_DECAYS,
+        ]
+    }
+
+    pub fn snapshot() -> SlowPathStats {
+        let load = |ctr: &AtomicUsize| ctr.load(Ordering::Relaxed);
+        SlowPathStats {
+            maps: load(&MAPS),
+            mapped_bytes: load(&MAPPED_BYTES),
+            unmaps: load(&UNMAPS),
+            unmapped_bytes: load(&UNMAPPED_BYTES),
+            slags_created: load(&SLAGS_CREATED),
+            slags_retired: load(&SLAGS_RETIRED),
+            backend_pops: load(&BACKEND_POPS),
+            backend_pushes: load(&BACKEND_PUSHES),
+            drains: load(&DRAINS),
+            cache_decays: load(&CACHE_DECAYS),
+        }
+    }
+
+    pub fn reset() {
+        for ctr in all().iter() {
+            ctr.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Get the process-wide slow-path counters.
+///
+/// Counters are read one at a time, so the result is not an atomic snapshot.
+#[cfg(feature = "slow-path-stats")]
+pub fn slow_path_stats() -> SlowPathStats {
+    slow_path::snapshot()
+}
+
+/// Reset the process-wide slow-path counters to zero.
+#[cfg(feature = "slow-path-stats")]
+pub fn reset_slow_path_stats() {
+    slow_path::reset()
+}
+
+/// Counts of the operations on heap handles. See the module documentation.
+#[cfg(feature = "stats")]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Calls to `alloc`, including those that failed.
+    pub allocs: usize,
+    /// Calls to `free`.
+    pub frees: usize,
+    /// The sum of the sizes passed to `alloc`.
+    pub bytes_allocated: usize,
+}
+
+#[cfg(feature = "stats")]
+pub mod heap {
+    use super::HeapStats;
+    use super::super::utils::{mmap, CachePadded};
+    use std::mem;
+    use std::ptr;
+    use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use std::thread;
+
+    /// The number of allocations, or of frees, that a handle counts before publishing its counts.
+    pub const FLUSH_EVENTS: usize = 256;
+
+    /// The number of times `snapshot` collects the counters looking for two identical
+    /// collections before it settles for the last one.
+    const MAX_COLLECTS: usize = 16;
+
+    /// The operations on one handle, published for readers. A slot has a single writer, the
+    /// handle it is claimed by, and is written as a sequence lock: `seq` is odd while the counters
+    /// are being written.
+    pub struct Slot {
+        seq: AtomicUsize,
+        allocs: AtomicUsize,
+        frees: AtomicUsize,
+        bytes_allocated: AtomicUsize,
+        in_use: AtomicBool,
+    }
+
+    impl Slot {
+        /// Add to the counters. Only the handle that claimed the slot may call this.
+        pub fn publish(&self, allocs: usize, frees: usize, bytes_allocated: usize) {
+            let seq = self.seq.load(Ordering::Relaxed);
+            self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+            atomic::fence(Ordering::Release);
+            for &(ctr, n) in &[
+                (&self.allocs, allocs),
+                (&self.frees, frees),
+                (&self.bytes_allocated, bytes_allocated),
+            ]
+            {
+                ctr.store(ctr.load(Ordering::Relaxed).wrapping_add(n), Ordering::Relaxed);
+            }
+            self.seq.store(seq.wrapping_add(2), Ordering::Release);
+        }
+
+        /// Read the counters, waiting out a concurrent `publish`, and return them with the
+        /// sequence number they were read at.
+        fn read(&self) -> (HeapStats, usize) {
+            let mut spins = 0;
+            loop {
+                let seq = self.seq.load(Ordering::Acquire);
+                if seq & 1 == 0 {
+                    let stats = HeapStats {
+                        allocs: self.allocs.load(Ordering::Relaxed),
+                        frees: self.frees.load(Ordering::Relaxed),
+                        bytes_allocated: self.bytes_allocated.load(Ordering::Relaxed),
+                    };
+                    atomic::fence(Ordering::Acquire);
+                    if self.seq.load(Ordering::Relaxed) == seq {
+                        return (stats, seq);
+                    }
+                }
+                // The writer may have been descheduled in the middle of `publish`.
+                spins += 1;
+                if spins % 64 == 0 {
+                    thread::yield_now();
+                }
+            }
+        }
+    }
+
+    /// As many slots as fit in a page, after the cache line holding the link to the next chunk.
+    const CHUNK_SLOTS: usize = 63;
+
+    struct Chunk {
+        next: CachePadded<*mut Chunk>,
+        slots: [CachePadded<Slot>; CHUNK_SLOTS],
+    }
+
+    /// The slots of every handle that has ever published its counts, and the counts at the last
+    /// reset.
+    ///
+    /// Chunks of slots are pushed onto a list and never freed. When a handle is destroyed, its
+    /// slot is released with its counters intact, and the next handle to claim it keeps adding
+    /// to them, so the sum of the slots only ever grows.
+    pub struct Registry {
+        /// The first `Chunk`, or 0.
+        head: AtomicUsize,
+        /// A sequence lock over `base`, which `reset` also uses to exclude other resets.
+        base_seq: AtomicUsize,
+        base: [AtomicUsize; 3],
+    }
+
+    pub static REGISTRY: Registry = Registry {
+        head: ATOMIC_USIZE_INIT,
+        base_seq: ATOMIC_USIZE_INIT,
+        base: [ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT],
+    };
+
+    impl Registry {
+        #[cfg(test)]
+        pub fn new() -> Registry {
+            Registry {
+                head: AtomicUsize::new(0),
+                base_seq: AtomicUsize::new(0),
+                base: [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+            }
+        }
+
+        /// Claim a slot that is not in use by another handle.
+        pub fn claim(&self) -> &Slot {
+            let mut chunk = self.head.load(Ordering::Acquire) as *mut Chunk;
+            while !chunk.is_null() {
+                let c = unsafe { &*chunk };
+                for slot in c.slots.iter() {
+                    if !slot.in_use.load(Ordering::Relaxed) &&
+                        !slot.in_use.swap(true, Ordering::Acquire)
+                    {
+                        return slot;
+                    }
+                }
+                chunk = *c.next;
+            }
+            // Every slot is taken. Map a chunk (fresh mappings are zeroed, so its slots are free
+            // and their counters are zero), claim its first slot and push it onto the list.
+            alloc_debug_assert!(mem::size_of::<Chunk>() <= 4096);
+            let chunk = mmap::map(4096) as *mut Chunk;
+            let c = unsafe { &mut *chunk };
+            c.slots[0].in_use.store(true, Ordering::Relaxed);
+            loop {
+                let head = self.head.load(Ordering::Relaxed);
+                *c.next = head as *mut Chunk;
+                if self.head
+                    .compare_exchange(head, chunk as usize, Ordering::Release, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return &c.slots[0];
+                }
+            }
+        }
+
+        /// Read every slot once. Returns the total of their counters, and the sum of their
+        /// sequence numbers (and that of `base`), which changes if any of them was written.
+        fn collect(&self) -> (HeapStats, usize) {
+            let mut total = HeapStats::default();
+            let mut seqs = 0usize;
+            let mut chunk = self.head.load(Ordering::Acquire) as *mut Chunk;
+            while !chunk.is_null() {
+                let c = unsafe { &*chunk };
+                for slot in c.slots.iter() {
+                    let (stats, seq) = slot.read();
+                    total.allocs = total.allocs.wrapping_add(stats.allocs);
+                    total.frees = total.frees.wrapping_add(stats.frees);
+                    total.bytes_allocated =
+                        total.bytes_allocated.wrapping_add(stats.bytes_allocated);
+                    seqs = seqs.wrapping_add(seq);
+                }
+                chunk = *c.next;
+            }
+            (total, seqs)
+        }
+
+        /// Read the counts at the last reset. Returns them with `base_seq`.
+        fn base(&self) -> (HeapStats, usize) {
+            loop {
+                let seq = self.base_seq.load(Ordering::Acquire);
+                if seq & 1 == 0 {
+                    let base = HeapStats {
+                        allocs: self.base[0].load(Ordering::Relaxed),
+                        frees: self.base[1].load(Ordering::Relaxed),
+                        bytes_allocated: self.base[2].load(Ordering::Relaxed),
+                    };
+                    atomic::fence(Ordering::Acquire);
+                    if self.base_seq.load(Ordering::Relaxed) == seq {
+                        return (base, seq);
+                    }
+                }
+                thread::yield_now();
+            }
+        }
+
+        /// Total the slots, without the counts at the last reset.
+        ///
+        /// Sequence numbers only grow, so if two successive collections have the same sum of
+        /// sequence numbers, nothing was published in between, and the first collection is a
+        /// snapshot of every slot at a single instant. Publishing never waits for a reader, so
+        /// under a steady stream of flushes this may not happen; after `MAX_COLLECTS`
+        /// collections, the last one is used, whose counts are each consistent for one handle
+        /// but may be from slightly different instants for different handles.
+        pub fn snapshot(&self) -> HeapStats {
+            let (mut base, mut base_seq) = self.base();
+            let (mut total, mut seqs) = self.collect();
+            for _ in 1..MAX_COLLECTS {
+                let (next_base, next_base_seq) = self.base();
+                let (next_total, next_seqs) = self.collect();
+                let unchanged = next_seqs == seqs && next_base_seq == base_seq;
+                base = next_base;
+                base_seq = next_base_seq;
+                total = next_total;
+                seqs = next_seqs;
+                if unchanged {
+                    break;
+                }
+            }
+            HeapStats {
+                allocs: total.allocs.wrapping_sub(base.allocs),
+                frees: total.frees.wrapping_sub(base.frees),
+                bytes_allocated: total.bytes_allocated.wrapping_sub(base.bytes_allocated),
+            }
+        }
+
+        /// Make the current totals the new zero.
+        pub fn reset(&self) {
+            let seq = loop {
+                let seq = self.base_seq.load(Ordering::Relaxed);
+                if seq & 1 == 0 &&
+                    self.base_seq
+                        .compare_exchange(seq, seq + 1, Ordering::Acquire, Ordering::Relaxed)
+                        .is_ok()
+                {
+                    break seq;
+                }
+                thread::yield_now();
+            };
+            atomic::fence(Ordering::Release);
+            let (total, _) = self.collect();
+            self.base[0].store(total.allocs, Ordering::Relaxed);
+            self.base[1].store(total.frees, Ordering::Relaxed);
+            self.base[2].store(total.bytes_allocated, Ordering::Relaxed);
+            self.base_seq.store(seq + 2, Ordering::Release);
+        }
+    }
+
+    /// The operations on one handle that have not been published yet, and the slot they are
+    /// published to, which is claimed on the first flush.
+    #[derive(Debug)]
+    pub struct Local {
+        allocs: usize,
+        frees: usize,
+        bytes_allocated: usize,
+        slot: *const Slot,
+    }
+
+    unsafe impl Send for Local {}
+
+    impl Default for Local {
+        fn default() -> Local {
+            Local {
+                allocs: 0,
+                frees: 0,
+                bytes_allocated: 0,
+                slot: ptr::null(),
+            }
+        }
+    }
+
+    impl Local {
+        #[inline(always)]
+        pub fn alloc(&mut self, bytes: usize) {
+            self.allocs += 1;
+            self.bytes_allocated = self.bytes_allocated.wrapping_add(bytes);
+            if self.allocs == FLUSH_EVENTS {
+                self.flush();
+            }
+        }
+
+        #[inline(always)]
+        pub fn free(&mut self) {
+            self.frees += 1;
+            if self.frees == FLUSH_EVENTS {
+                self.flush();
+            }
+        }
+
+        #[cold]
+        pub fn flush(&mut self) {
+            if self.slot.is_null() {
+                self.slot = REGISTRY.claim();
+            }
+            unsafe { &*self.slot }.publish(self.allocs, self.frees, self.bytes_allocated);
+            self.allocs = 0;
+            self.frees = 0;
+            self.bytes_allocated = 0;
+        }
+
+        /// Flush, and release the slot for another handle. Called when the handle is destroyed; a
+        /// handle that is never destroyed keeps its slot.
+        pub fn retire(&mut self) {
+            if self.allocs != 0 || self.frees != 0 {
+                self.flush();
+            }
+            if !self.slot.is_null() {
+                unsafe { &*self.slot }.in_use.store(false, Ordering::Release);
+                self.slot = ptr::null();
+            }
+        }
+    }
+
+    pub fn snapshot() -> HeapStats {
+        REGISTRY.snapshot()
+    }
+
+    pub fn reset() {
+        REGISTRY.reset()
+    }
+}
+
+/// Get the process-wide counts of heap operations. Operations that handles have not flushed yet
+/// are not included (see the module documentation).
+///
+/// The counts are a consistent snapshot (`frees` and `bytes_allocated` are from the same instant
+/// as `allocs`), read without locks and without making handles wait.
+#[cfg(feature = "stats")]
+pub fn heap_stats() -> HeapStats {
+    heap::snapshot()
+}
+
+/// Reset the process-wide counts of heap operations to zero. Operations that handles have not
+/// flushed yet are counted when they are.
+#[cfg(feature = "stats")]
+pub fn reset_heap_stats() {
+    heap::reset()
+}
+
+/// An operation whose latency is recorded by the `latency-stats` feature.
+#[cfg(feature = "latency-stats")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LatencyOp {
+    Alloc,
+    Free,
+}
+
+/// A histogram of the sampled latencies of one operation on one size class.
+///
+/// Durations are recorded in buckets whose width is a quarter of a power of two, so percentiles
+/// are accurate to within 25%.
+#[cfg(feature = "latency-stats")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// The size of the objects in the size class, or `None` for large objects.
+    pub size_class: Option<usize>,
+    pub op: LatencyOp,
+    /// The number of samples in each bucket (see `latency::bucket`).
+    buckets: Vec<usize>,
+}
+
+#[cfg(feature = "latency-stats")]
+impl LatencyHistogram {
+    /// The number of sampled operations.
+    pub fn samples(&self) -> usize {
+        self.buckets.iter().sum()
+    }
+
+    /// An upper bound on the `q`th quantile (between 0 and 1) of the sampled durations, in
+    /// nanoseconds, or `None` if there are no samples.
+    pub fn percentile(&self, q: f64) -> Option<usize> {
+        let samples = self.samples();
+        if samples == 0 {
+            return None;
+        }
+        let rank = ((q * samples as f64).ceil() as usize).max(1).min(samples);
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(latency::bucket_max(i));
+            }
+        }
+        unreachable!()
+    }
+
+    pub fn p50(&self) -> Option<usize> {
+        self.percentile(0.5)
+    }
+
+    pub fn p99(&self) -> Option<usize> {
+        self.percentile(0.99)
+    }
+
+    pub fn p999(&self) -> Option<usize> {
+        self.percentile(0.999)
+    }
+
+    /// An upper bound on the longest sampled duration, in nanoseconds.
+    pub fn max(&self) -> Option<usize> {
+        self.percentile(1.0)
+    }
+}
+
+#[cfg(feature = "latency-stats")]
+pub mod latency {
+    use super::{LatencyHistogram, LatencyOp};
+    use super::super::utils::mmap;
+    use std::mem;
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use std::time::Instant;
+
+    /// The number of buckets in a histogram; durations beyond the last bucket are counted in it.
+    pub const BUCKETS: usize = 128;
+    /// The number of size classes that can be recorded. Samples of further size classes (which
+    /// only custom configurations have) are dropped.
+    const MAX_CLASSES: usize = 64;
+    /// The key of the histograms for large objects.
+    const LARGE: usize = !0;
+    /// How often a `Sampler` checks whether sampling has been enabled while it is disabled.
+    const RECHECK_PERIOD: usize = 4096;
+
+    /// Sample one in this many operations; 0 disables sampling.
+    pub static SAMPLE_PERIOD: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// The histograms of a size class. `key` is 0 until the entry is claimed, and then holds the
+    /// size of the class, or `LARGE`.
+    struct Entry {
+        key: AtomicUsize,
+        buckets: [[AtomicUsize; BUCKETS]; 2],
+    }
+
+    type Table = [Entry; MAX_CLASSES];
+
+    /// The address of the table, or 0 if nothing has been recorded yet. It is mapped on first use
+    /// rather than being a static so that builds with the feature enabled but sampling disabled
+    /// don't pay for it.
+    static TABLE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    fn table(create: bool) -> Option<&'static Table> {
+        let mut addr = TABLE.load(Ordering::Acquire);
+        if addr == 0 {
+            if !create {
+                return None;
+            }
+            let bytes = mem::size_of::<Table>();
+            let fresh = mmap::map(bytes) as usize;
+            addr = match TABLE.compare_exchange(0, fresh, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => fresh,
+                Err(winner) => {
+                    unsafe { mmap::unmap(fresh as *mut u8, bytes) };
+                    winner
+                }
+            };
+        }
+        Some(unsafe { &*(addr as *const Table) })
+    }
+
+    /// The bucket for a duration of `nanos`. Durations below 4ns get a bucket each; after that,
+    /// every power of two is split into 4 buckets.
+    pub fn bucket(nanos: usize) -> usize {
+        if nanos < 4 {
+            return nanos;
+        }
+        let log = mem::size_of::<usize>() * 8 - 1 - nanos.leading_zeros() as usize;
+        let sub = (nanos >> (log - 2)) & 3;
+        ((log - 1) * 4 + sub).min(BUCKETS - 1)
+    }
+
+    /// The longest duration that falls in bucket `i`.
+    pub fn bucket_max(i: usize) -> usize {
+        if i < 4 {
+            return i;
+        }
+        let (log, sub) = (i / 4 + 1, i % 4);
+        ((5 + sub) << (log - 2)) - 1
+    }
+
+    /// Decides which of a handle's operations are sampled.
+    pub struct Sampler {
+        /// The number of operations until the next sample.
+        countdown: usize,
+    }
+
+    impl Sampler {
+        pub fn new() -> Sampler {
+            Sampler { countdown: 1 }
+        }
+
+        /// Return whether the current operation should be timed.
+        #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+        #[inline(always)]
+        pub fn sample(&mut self) -> bool {
+            self.countdown -= 1;
+            if self.countdown != 0 {
+                return false;
+            }
+            self.next_period()
+        }
+
+        #[cold]
+        fn next_period(&mut self) -> bool {
+            match SAMPLE_PERIOD.load(Ordering::Relaxed) {
+                0 => {
+                    self.countdown = RECHECK_PERIOD;
+                    false
+                }
+                period => {
+                    self.countdown = period;
+                    true
+                }
+            }
+        }
+    }
+
+    /// Record that an `op` on an object of size class `size_class` (`None` for large objects)
+    /// took the time since `start`.
+    #[cold]
+    pub fn record(op: LatencyOp, size_class: Option<usize>, start: Instant) {
+        let elapsed = start.elapsed();
+        let nanos = elapsed.as_secs() as usize * 1_000_000_000 + elapsed.subsec_nanos() as usize;
+        let key = size_class.unwrap_or(LARGE);
+        for entry in table(true).unwrap().iter() {
+            let cur = entry.key.load(Ordering::Acquire);
+            if cur == key ||
+                (cur == 0 &&
+                     match entry.key.compare_exchange(0, key, Ordering::AcqRel, Ordering::Acquire) {
+                         Ok(_) => true,
+                         Err(winner) => winner == key,
+                     })
+            {
+                entry.buckets[op as usize][bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+
+    /// Read the histograms that have any samples, ordered by size class (large objects last) and
+    /// operation.
+    pub fn snapshot() -> Vec<LatencyHistogram> {
+        let table = match table(false) {
+            Some(table) => table,
+            None => return Vec::new(),
+        };
+        let mut res = Vec::new();
+        for entry in table.iter() {
+            let key = entry.key.load(Ordering::Acquire);
+            if key == 0 {
+                continue;
+            }
+            for &op in &[LatencyOp::Alloc, LatencyOp::Free] {
+                let hist = LatencyHistogram {
+                    size_class: if key == LARGE { None } else { Some(key) },
+                    op: op,
+                    buckets: entry.buckets[op as usize]
+                        .iter()
+                        .map(|ctr| ctr.load(Ordering::Relaxed))
+                        .collect(),
+                };
+                if hist.samples() != 0 {
+                    res.push(hist);
+                }
+            }
+        }
+        res.sort_by_key(|h| (h.size_class.unwrap_or(LARGE), h.op));
+        res
+    }
+
+    pub fn reset() {
+        if let Some(table) = table(false) {
+            for entry in table.iter() {
+                for ctr in entry.buckets.iter().flat_map(|b| b.iter()) {
+                    ctr.store(0, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+/// Get the process-wide latency histograms that have any samples.
+///
+/// Buckets are read one at a time, so the result is not an atomic snapshot.
+#[cfg(feature = "latency-stats")]
+pub fn latency_stats() -> Vec<LatencyHistogram> {
+    latency::snapshot()
+}
+
+/// Reset the process-wide latency histograms.
+#[cfg(feature = "latency-stats")]
+pub fn reset_latency_stats() {
+    latency::reset()
+}
+
+/// Time one in every `period` calls to `alloc` and `free` on each handle; 0 (the default)
+/// disables timing.
+///
+/// Handles that are already counting down to their next sample pick up the new period after
+/// that sample, and handles with sampling disabled check for a new period every few thousand
+/// operations.
+#[cfg(feature = "latency-stats")]
+pub fn set_latency_sample_period(period: usize) {
+    latency::SAMPLE_PERIOD.store(period, ::std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Write the latency histograms to `w` as a table with the sample count and the p50, p99, p999,
+/// and maximum latencies (in nanoseconds) of each operation and size class.
+#[cfg(feature = "latency-stats")]
+pub fn dump_latency_stats<W: ::std::io::Write>(w: &mut W) -> ::std::io::Result<()> {
+    writeln!(
+        w,
+        "{:>10} {:>5} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "class",
+        "op",
+        "samples",
+        "p50",
+        "p99",
+        "p999",
+        "max"
+    )?;
+    for hist in latency_stats() {
+        let class = match hist.size_class {
+            Some(size) => size.to_string(),
+            None => "large".to_string(),
+        };
+        let op = match hist.op {
+            LatencyOp::Alloc => "alloc",
+            LatencyOp::Free => "free",
+        };
+        let ns = |n: Option<usize>| n.unwrap_or(0);
+        writeln!(
+            w,
+            "{:>10} {:>5} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            class,
+            op,
+            hist.samples(),
+            ns(hist.p50()),
+            ns(hist.p99()),
+            ns(hist.p999()),
+            ns(hist.max())
+        )?;
+    }
+    Ok(())
+}
+
+/// Count an event in one of the counters in `contention`, if the `contention-stats` feature is
+/// enabled.
+macro_rules! contention_event {
+    ($ctr:ident) => {
+        contention_event!($ctr, 1)
+    };
+    ($ctr:ident, $n:expr) => {
+        #[cfg(feature = "contention-stats")]
+        {
+            $crate::stats::contention::$ctr.fetch_add($n, ::std::sync::atomic::Ordering::Relaxed);
+        }
+    };
+}
+
+/// Count an event in one of the counters in `realloc`, if the `realloc-stats` feature is enabled.
+macro_rules! realloc_event {
+    ($ctr:ident) => {
+        realloc_event!($ctr, 1)
+    };
+    ($ctr:ident, $n:expr) => {
+        #[cfg(feature = "realloc-stats")]
+        {
+            $crate::stats::realloc::$ctr.fetch_add($n, ::std::sync::atomic::Ordering::Relaxed);
+        }
+    };
+}
+
+/// Count an event in one of the counters in `slow_path`, if the `slow-path-stats` feature is
+/// enabled.
+macro_rules! slow_path_event {
+    ($ctr:ident) => {
+        slow_path_event!($ctr, 1)
+    };
+    ($ctr:ident, $n:expr) => {
+        #[cfg(feature = "slow-path-stats")]
+        {
+            $crate::stats::slow_path::$ctr.fetch_add($n, ::std::sync::atomic::Ordering::Relaxed);
+        }
+    };
+}
+
+/// Call a method on a handle's `heap::Local` counters, if the `stats` feature is enabled:
+/// `heap_event!(local, alloc, bytes)`, `heap_event!(local, free)` or `heap_event!(local, retire)`.
+/// Without the feature, this expands to nothing, so `local` does not have to exist.
+macro_rules! heap_event {
+    ($local:expr, $op:ident $(, $arg:expr)*) => {
+        #[cfg(feature = "stats")]
+        {
+            $local.$op($($arg),*);
+        }
+    };
+}
+
 macro_rules! trace_event {
     ($fld:tt) => {
         #[cfg(feature = "print_stats")]
@@ -90,3 +1224,210 @@ macro_rules! trace_event {
         }
     };
 }
+
+#[cfg(all(test, feature = "contention-stats"))]
+mod tests {
+    use super::*;
+    use super::super::general::DynamicAllocator;
+    use std::thread;
+
+    #[test]
+    fn contention_counters() {
+        set_contention_sample_period(1);
+        let before = contention_stats();
+        let mut alloc = DynamicAllocator::new();
+        let ptrs: Vec<usize> = (0..100_000)
+            .map(|_| unsafe { alloc.alloc(64) } as usize)
+            .collect();
+        let mut remote = alloc.clone();
+        thread::spawn(move || for p in ptrs {
+            unsafe { remote.free(p as *mut u8) };
+        }).join()
+            .unwrap();
+        let after = contention_stats();
+        alloc_assert!(after.slag_acquisitions > before.slag_acquisitions);
+        alloc_assert!(after.sampled_acquisitions > before.sampled_acquisitions);
+        alloc_assert!(after.remote_frees + after.drained_objects >
+                      before.remote_frees + before.drained_objects);
+    }
+}
+
+#[cfg(all(test, feature = "realloc-stats"))]
+mod realloc_tests {
+    use super::*;
+    use super::super::general::DynamicAllocator;
+
+    #[test]
+    fn realloc_counters() {
+        let mut alloc = DynamicAllocator::new();
+        unsafe {
+            let before = realloc_stats();
+            // Shrinking always fits.
+            let p = alloc.alloc(32);
+            let p = alloc.realloc(p, 16);
+            let after = realloc_stats();
+            alloc_assert!(after.fit > before.fit);
+
+            let before = after;
+            let q = alloc.realloc(p, 4 << 10);
+            let after = realloc_stats();
+            alloc_assert!(after.copied > before.copied);
+            alloc_assert!(after.copied_bytes >= before.copied_bytes + 16);
+            alloc.free(q);
+
+            let before = realloc_stats();
+            let p = alloc.alloc(4 << 20);
+            let p = alloc.realloc(p, 1 << 20);
+            let after = realloc_stats();
+            alloc_assert!(after.shrunk > before.shrunk);
+            alloc.free(p);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "slow-path-stats"))]
+mod slow_path_tests {
+    use super::*;
+    use super::super::general::DynamicAllocator;
+
+    #[test]
+    fn slow_path_counters() {
+        let before = slow_path_stats();
+        let mut alloc = DynamicAllocator::new();
+        unsafe {
+            let ptrs: Vec<_> = (0..100_000).map(|_| alloc.alloc(64)).collect();
+            for p in ptrs {
+                alloc.free(p);
+            }
+            let large = alloc.alloc(4 << 20);
+            alloc.free(large);
+        }
+        let after = slow_path_stats();
+        alloc_assert!(after.maps > before.maps);
+        alloc_assert!(after.mapped_bytes >= before.mapped_bytes + (4 << 20));
+        alloc_assert!(after.unmapped_bytes >= before.unmapped_bytes + (4 << 20));
+        alloc_assert!(after.slags_created > before.slags_created);
+        alloc_assert!(after.backend_pops > before.backend_pops);
+        alloc_assert!(after.drains > before.drains);
+    }
+}
+
+#[cfg(all(test, feature = "stats"))]
+mod heap_tests {
+    use super::*;
+    use super::super::general::DynamicAllocator;
+
+    #[test]
+    fn heap_counters() {
+        let before = heap_stats();
+        {
+            let mut alloc = DynamicAllocator::new();
+            unsafe {
+                let ptrs: Vec<_> = (0..1000).map(|_| alloc.alloc(24)).collect();
+                for p in ptrs {
+                    alloc.free(p);
+                }
+            }
+            // Most of the operations have been flushed, but not necessarily all of them.
+            alloc_assert!(heap_stats().allocs >= before.allocs + 1000 - heap::FLUSH_EVENTS);
+        }
+        // Destroying the handle flushes the rest. Other tests may be running concurrently, so
+        // the counters can only be compared with lower bounds.
+        let after = heap_stats();
+        alloc_assert!(after.allocs >= before.allocs + 1000);
+        alloc_assert!(after.frees >= before.frees + 1000);
+        alloc_assert!(after.bytes_allocated >= before.bytes_allocated + 24 * 1000);
+    }
+
+    #[test]
+    fn consistent_snapshots() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::thread;
+
+        // A registry of our own, so that other tests do not publish to it. Its chunks are never
+        // unmapped, so it is leaked rather than dropped.
+        let registry: &'static heap::Registry =
+            unsafe { &*Box::into_raw(Box::new(heap::Registry::new())) };
+        let done = Arc::new(AtomicBool::new(false));
+        // More writers than fit in a chunk, each publishing one free and 8 bytes per allocation.
+        let writers: Vec<_> = (0..80)
+            .map(|_| {
+                let done = done.clone();
+                thread::spawn(move || {
+                    let slot = registry.claim();
+                    while !done.load(Ordering::Relaxed) {
+                        slot.publish(1, 1, 8);
+                    }
+                })
+            })
+            .collect();
+        let mut last = 0;
+        for _ in 0..1000 {
+            let stats = registry.snapshot();
+            alloc_assert_eq!(stats.frees, stats.allocs);
+            alloc_assert_eq!(stats.bytes_allocated, 8 * stats.allocs);
+            alloc_assert!(stats.allocs >= last);
+            last = stats.allocs;
+        }
+        done.store(true, Ordering::Relaxed);
+        for w in writers {
+            w.join().unwrap();
+        }
+        alloc_assert!(registry.snapshot().allocs > 0);
+        registry.reset();
+        alloc_assert_eq!(registry.snapshot(), HeapStats::default());
+        registry.claim().publish(3, 2, 24);
+        let stats = registry.snapshot();
+        alloc_assert_eq!((stats.allocs, stats.frees, stats.bytes_allocated), (3, 2, 24));
+    }
+}
+
+#[cfg(all(test, feature = "latency-stats"))]
+mod latency_tests {
+    use super::*;
+    use super::super::general::DynamicAllocator;
+
+    #[test]
+    fn buckets() {
+        for nanos in (0..10_000).chain((1..31).map(|i| 1 << i)) {
+            let b = latency::bucket(nanos);
+            alloc_assert!(nanos <= latency::bucket_max(b) || b == latency::BUCKETS - 1);
+            alloc_assert!(b == 0 || nanos > latency::bucket_max(b - 1));
+        }
+    }
+
+    #[test]
+    fn latency_histograms() {
+        set_latency_sample_period(1);
+        let mut alloc = DynamicAllocator::new();
+        unsafe {
+            for &size in &[64, 4 << 20] {
+                let ptrs: Vec<_> = (0..1000).map(|_| alloc.alloc(size)).collect();
+                for p in ptrs {
+                    alloc.free(p);
+                }
+            }
+        }
+        let stats = latency_stats();
+        for &(class, op) in &[
+            (Some(64), LatencyOp::Alloc),
+            (Some(64), LatencyOp::Free),
+            (None, LatencyOp::Alloc),
+            (None, LatencyOp::Free),
+        ]
+        {
+            let hist = stats
+                .iter()
+                .find(|h| h.size_class == class && h.op == op)
+                .expect("missing histogram");
+            alloc_assert!(hist.samples() >= 1000);
+            alloc_assert!(hist.p50() <= hist.p99());
+            alloc_assert!(hist.p99() <= hist.p999());
+            alloc_assert!(hist.p999() <= hist.max());
+        }
+        let mut out = Vec::new();
+        dump_latency_stats(&mut out).unwrap();
+        alloc_assert!(String::from_utf8(out).unwrap().contains("large"));
+    }
+}
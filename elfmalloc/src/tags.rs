@@ -0,0 +1,208 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Per-object ownership tags.
+//!
+//! Objects allocated with `general::global::alloc_tagged` (or `DynamicAllocator::alloc_tagged`)
+//! carry a `Tag` for their entire lifetime. Tags of small and medium objects are stored in a
+//! side table hanging off of each `Slag`, which is mapped lazily the first time an object in that
+//! `Slag` is tagged. Tags of large objects are stored in their header. The number of live bytes
+//! for each tag is aggregated in a global table that can be queried with `bytes_by_tag`, which
+//! allows applications to attribute heap usage to tenants, subsystems, connections, etc.
+//!
+//! The global table is a fixed-size, lock-free hash table. Tags that do not fit in it are
+//! accounted for under `OVERFLOW_TAG`.
+//!
+//! The same per-object storage is used to record allocation sites (see the `sites` module); each
+//! kind of per-object value is called a `Label`.
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use super::utils::TypedArray;
+
+/// A kind of `u32` value stored alongside every object. A value of zero means "unset".
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Label {
+    Tag = 0,
+    Site = 1,
+}
+
+/// The number of `Label` variants.
+pub const N_LABELS: usize = 2;
+
+/// All `Label` variants.
+pub const LABELS: [Label; N_LABELS] = [Label::Tag, Label::Site];
+
+/// Record that an object of `bytes` bytes was given the value `val` for `label`.
+pub fn account_label(label: Label, val: u32, bytes: usize) {
+    match label {
+        Label::Tag => account(val, bytes),
+        #[cfg(feature = "sites")]
+        Label::Site => super::sites::account(val, bytes),
+        #[cfg(not(feature = "sites"))]
+        Label::Site => {}
+    }
+}
+
+/// Record that an object of `bytes` bytes with value `val` for `label` was freed.
+pub fn unaccount_label(label: Label, val: u32, bytes: usize) {
+    match label {
+        Label::Tag => unaccount(val, bytes),
+        #[cfg(feature = "sites")]
+        Label::Site => super::sites::unaccount(val, bytes),
+        #[cfg(not(feature = "sites"))]
+        Label::Site => {}
+    }
+}
+
+/// An identifier attached to an allocated object.
+pub type Tag = u32;
+
+/// The tag of objects that were not allocated with an explicit tag. These are not accounted for.
+pub const UNTAGGED: Tag = 0;
+
+/// The tag under which bytes are accounted when the global table is full.
+pub const OVERFLOW_TAG: Tag = !0;
+
+/// A fixed-capacity table mapping `u32` keys to byte and object counts.
+///
+/// Entries are claimed with a CAS on the key and never removed, so lookups are a simple linear
+/// probe.
+pub struct CounterTable {
+    entries: TypedArray<Entry>,
+    overflow: Entry,
+}
+
+struct Entry {
+    /// The key plus one; zero indicates an unused entry.
+    key: AtomicUsize,
+    bytes: AtomicIsize,
+    count: AtomicIsize,
+    total: AtomicUsize,
+}
+
+/// The counts for a single key in a `CounterTable`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Counts {
+    pub key: u32,
+    /// Live bytes.
+    pub bytes: usize,
+    /// Live objects.
+    pub live: usize,
+    /// Total number of objects ever added.
+    pub total: usize,
+}
+
+// All mutation happens through atomics.
+unsafe impl Sync for CounterTable {}
+unsafe impl Send for CounterTable {}
+
+impl CounterTable {
+    /// Create a new table with room for `size` distinct keys.
+    ///
+    /// The memory for the table is mapped directly (and is thus zeroed), which makes this safe to
+    /// call while servicing a `malloc`.
+    pub fn new(size: usize) -> CounterTable {
+        CounterTable {
+            entries: TypedArray::new(size),
+            overflow: Entry {
+                key: AtomicUsize::new(0),
+                bytes: AtomicIsize::new(0),
+                count: AtomicIsize::new(0),
+                total: AtomicUsize::new(0),
+            },
+        }
+    }
+
+    fn find(&self, key: u32) -> &Entry {
+        let stored = key as usize + 1;
+        let len = self.entries.len();
+        // Fibonacci hashing spreads sequential keys (the common case) across the table.
+        let start = (key as usize).wrapping_mul(0x9E37_79B9) % len;
+        for i in 0..len {
+            let entry = unsafe { &*self.entries.get((start + i) % len) };
+            let cur = entry.key.load(Ordering::Acquire);
+            if cur == stored {
+                return entry;
+            }
+            if cur == 0 {
+                match entry.key.compare_exchange(0, stored, Ordering::AcqRel, Ordering::Acquire) {
+                    Ok(_) => return entry,
+                    Err(other) if other == stored => return entry,
+                    Err(_) => continue,
+                }
+            }
+        }
+        &self.overflow
+    }
+
+    /// Add `bytes` bytes and `count` objects to the counts for `key`.
+    ///
+    /// A positive `count` is also added to the key's total.
+    pub fn add(&self, key: u32, bytes: isize, count: isize) {
+        let entry = self.find(key);
+        entry.bytes.fetch_add(bytes, Ordering::Relaxed);
+        entry.count.fetch_add(count, Ordering::Relaxed);
+        if count > 0 {
+            entry.total.fetch_add(count as usize, Ordering::Relaxed);
+        }
+    }
+
+    /// Get the `Counts` for all keys that have ever been added.
+    ///
+    /// Counts are read one entry at a time, so the result is not an atomic snapshot.
+    pub fn snapshot(&self, overflow_key: u32) -> Vec<Counts> {
+        let mut res = Vec::new();
+        let entries = self.entries.iter().map(|e| unsafe { &*e });
+        for entry in entries.chain(Some(&self.overflow)) {
+            let total = entry.total.load(Ordering::Relaxed);
+            if total == 0 {
+                continue;
+            }
+            let key = if entry as *const Entry == &self.overflow as *const Entry {
+                overflow_key
+            } else {
+                (entry.key.load(Ordering::Acquire) - 1) as u32
+            };
+            res.push(Counts {
+                key: key,
+                bytes: entry.bytes.load(Ordering::Relaxed) as usize,
+                live: entry.count.load(Ordering::Relaxed) as usize,
+                total: total,
+            });
+        }
+        res
+    }
+}
+
+lazy_static! {
+    static ref TAG_BYTES: CounterTable = CounterTable::new(4096);
+}
+
+/// Record that an object of `bytes` bytes was tagged with `tag`.
+pub fn account(tag: Tag, bytes: usize) {
+    if tag != UNTAGGED {
+        TAG_BYTES.add(tag, bytes as isize, 1);
+    }
+}
+
+/// Record that an object of `bytes` bytes tagged with `tag` was freed.
+pub fn unaccount(tag: Tag, bytes: usize) {
+    if tag != UNTAGGED {
+        TAG_BYTES.add(tag, -(bytes as isize), -1);
+    }
+}
+
+/// Get the number of live bytes for each tag with live objects.
+///
+/// Byte counts include internal fragmentation: an object's size is that of its size class.
+pub fn bytes_by_tag() -> Vec<(Tag, usize)> {
+    TAG_BYTES
+        .snapshot(OVERFLOW_TAG)
+        .into_iter()
+        .filter(|c| c.live > 0)
+        .map(|c| (c.key, c.bytes))
+        .collect()
+}
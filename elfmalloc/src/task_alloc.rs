@@ -0,0 +1,228 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Allocator handles for tasks, for use with futures executors such as tokio.
+//!
+//! elfmalloc's thread caches assume that a computation stays on one thread. Tasks on a
+//! work-stealing executor don't: each time a task is polled it may run on a different worker
+//! thread, and all the tasks on a worker share that worker's thread-local cache. A `TaskHandle`
+//! is an allocator handle that belongs to a task instead. It is built on a `SendableAlloc`, so
+//! when the task is polled on a different thread than the last time, the handle returns its cache
+//! to the global pool and starts a fresh one rather than touching memory that was last used on
+//! another CPU.
+//!
+//! Entering a handle with `TaskHandle::enter` makes it the current thread's task handle until the
+//! returned `TaskAlloc` guard is dropped or `exit`ed. While a handle is entered, `TaskLocalAlloc`
+//! (a zero-sized allocator) allocates from it; otherwise `TaskLocalAlloc` uses `SharedAlloc`.
+//! `with_task_alloc` wraps a future so that its handle is entered for every poll:
+//!
+//! ```rust,ignore
+//! let server = with_task_alloc(listener.incoming().for_each(handle_connection));
+//! tokio::run(server);
+//! ```
+//!
+//! # `Send` and `Sync`
+//!
+//! A `TaskHandle` is `Send` but not `Sync`, which is what a task's state needs to be. The
+//! `TaskAlloc` guard is neither: it must be dropped on the thread that created it, before the
+//! task returns from `poll`. `TaskLocalAlloc` is `Send` and `Sync`, since it carries no state, so
+//! collections that use it can be moved between tasks and threads freely.
+//!
+//! All of these share elfmalloc's global backend with `DynamicAlloc` and `SharedAlloc`, so an
+//! object can be freed through any of them, on any thread, whichever handle allocated it. In
+//! particular, objects allocated by a task may outlive it.
+
+use alloc::allocator::{Alloc, AllocErr, Layout};
+use futures::{Future, Poll};
+use std::ptr;
+use super::rust_alloc::{SendableAlloc, SharedAlloc};
+
+/// The handle entered on this thread, or null if there is none.
+#[thread_local]
+static mut CURRENT: *mut SendableAlloc = ptr::null_mut();
+
+/// Whether a `TaskHandle` is entered on the current thread.
+pub fn in_task() -> bool {
+    unsafe { !CURRENT.is_null() }
+}
+
+/// An allocator handle owned by a task. See the module documentation.
+// The handle is boxed so that its address stays the same while it is entered, even though the
+// `TaskAlloc` guard that owns it in the meantime may be moved.
+pub struct TaskHandle(Box<SendableAlloc>);
+
+impl TaskHandle {
+    pub fn new() -> TaskHandle {
+        TaskHandle(Box::new(SendableAlloc::new()))
+    }
+
+    /// Make this the current thread's task handle until the returned guard is dropped or
+    /// `exit`ed, at which point the previous one (if any) is restored.
+    ///
+    /// The guard owns the handle while it is entered, so leaking the guard leaks the handle
+    /// rather than leaving a dangling pointer behind.
+    pub fn enter(self) -> TaskAlloc {
+        let handle = Box::into_raw(self.0);
+        let prev = unsafe { CURRENT };
+        unsafe { CURRENT = handle };
+        TaskAlloc {
+            handle: handle,
+            prev: prev,
+        }
+    }
+}
+
+impl Default for TaskHandle {
+    fn default() -> TaskHandle {
+        TaskHandle::new()
+    }
+}
+
+/// A guard that keeps a `TaskHandle` entered on the current thread. See `TaskHandle::enter`.
+///
+/// The raw pointers make this type neither `Send` nor `Sync`.
+pub struct TaskAlloc {
+    handle: *mut SendableAlloc,
+    prev: *mut SendableAlloc,
+}
+
+impl TaskAlloc {
+    /// Stop using the handle, and get it back.
+    pub fn exit(self) -> TaskHandle {
+        let handle = self.restore();
+        ::std::mem::forget(self);
+        handle
+    }
+
+    fn restore(&self) -> TaskHandle {
+        unsafe {
+            alloc_assert!(
+                CURRENT == self.handle,
+                "TaskAlloc guards must be dropped in the reverse order of creation"
+            );
+            CURRENT = self.prev;
+            TaskHandle(Box::from_raw(self.handle))
+        }
+    }
+}
+
+impl Drop for TaskAlloc {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+/// An allocator that uses the current thread's task handle if there is one, and `SharedAlloc`
+/// otherwise.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct TaskLocalAlloc;
+
+unsafe impl Alloc for TaskLocalAlloc {
+    unsafe fn alloc(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+        if CURRENT.is_null() {
+            SharedAlloc.alloc(l)
+        } else {
+            (*CURRENT).alloc(l)
+        }
+    }
+
+    unsafe fn dealloc(&mut self, p: *mut u8, l: Layout) {
+        if CURRENT.is_null() {
+            SharedAlloc.dealloc(p, l)
+        } else {
+            (*CURRENT).dealloc(p, l)
+        }
+    }
+
+    fn usable_size(&self, l: &Layout) -> (usize, usize) {
+        // Task handles and SharedAlloc are configured identically.
+        SharedAlloc.usable_size(l)
+    }
+}
+
+/// A future that enters its own `TaskHandle` whenever it is polled. See `with_task_alloc`.
+pub struct WithTaskAlloc<F> {
+    future: F,
+    /// `None` only while the future is being polled, or if polling it panicked.
+    handle: Option<TaskHandle>,
+}
+
+/// Wrap `future` so that `TaskLocalAlloc` uses a handle of its own while it is polled.
+pub fn with_task_alloc<F: Future>(future: F) -> WithTaskAlloc<F> {
+    WithTaskAlloc {
+        future: future,
+        handle: Some(TaskHandle::new()),
+    }
+}
+
+impl<F: Future> Future for WithTaskAlloc<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let guard = self.handle
+            .take()
+            .expect("WithTaskAlloc polled after panicking")
+            .enter();
+        let res = self.future.poll();
+        self.handle = Some(guard.exit());
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Async;
+    use futures::future;
+    use std::thread;
+
+    #[test]
+    fn enter_and_exit() {
+        alloc_assert!(!in_task());
+        let outer = TaskHandle::new().enter();
+        alloc_assert!(in_task());
+        let inner = TaskHandle::new().enter();
+        let handle = inner.exit();
+        alloc_assert!(in_task());
+        drop(outer);
+        alloc_assert!(!in_task());
+        drop(handle);
+    }
+
+    #[test]
+    fn task_alloc_follows_task() {
+        let l = Layout::from_size_align(64, 8).unwrap();
+        let mut polls = 0;
+        let mut fut = with_task_alloc(future::poll_fn(move || -> Poll<usize, ()> {
+            alloc_assert!(in_task());
+            let p = unsafe { TaskLocalAlloc.alloc(l.clone()).unwrap() };
+            polls += 1;
+            if polls < 4 {
+                unsafe { TaskLocalAlloc.dealloc(p, l.clone()) };
+                Ok(Async::NotReady)
+            } else {
+                Ok(Async::Ready(p as usize))
+            }
+        }));
+        // Poll the task on a different thread each time, as a work-stealing executor might.
+        for _ in 0..3 {
+            fut = thread::spawn(move || {
+                alloc_assert_eq!(fut.poll(), Ok(Async::NotReady));
+                fut
+            }).join()
+                .unwrap();
+        }
+        let p = match fut.poll() {
+            Ok(Async::Ready(p)) => p as *mut u8,
+            _ => panic!("the task should have completed"),
+        };
+        alloc_assert!(!in_task());
+        // The object outlives the task.
+        unsafe { SharedAlloc.dealloc(p, Layout::from_size_align(64, 8).unwrap()) };
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Statistics about transparent huge page (THP) usage.
+//!
+//! These are gathered by parsing `/proc/self/smaps`, which is too slow to do on any hot path. Only
+//! the mappings that belong to elfmalloc are counted: those named `[anon:elfmalloc:...]` (see the
+//! `name_mappings` option in the `conf` module) and, with the `ownership` feature, those that
+//! overlap a granule of the ownership table. Without either, no mapping can be attributed to the
+//! heap and the statistics are zero. Pair these with `general::global::defrag` to measure its
+//! effect.
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+
+/// Anonymous memory usage of the heap, as reported by the kernel.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThpStats {
+    /// Bytes of resident anonymous memory in the heap's mappings.
+    pub anon_bytes: usize,
+    /// Bytes of resident anonymous memory in the heap's mappings backed by transparent huge pages.
+    pub anon_huge_bytes: usize,
+}
+
+/// Read THP statistics for the heap of the current process.
+pub fn thp_stats() -> io::Result<ThpStats> {
+    let f = File::open("/proc/self/smaps")?;
+    parse_smaps(BufReader::new(f), is_heap_mapping)
+}
+
+/// Does the mapping from `start` to `end` named `name` belong to elfmalloc?
+fn is_heap_mapping(start: usize, end: usize, name: &str) -> bool {
+    if name.starts_with("[anon:elfmalloc:") {
+        return true;
+    }
+    #[cfg(feature = "ownership")]
+    {
+        if let Some((addr, _)) = super::ownership::next_owned(start) {
+            return addr < end;
+        }
+    }
+    let _ = (start, end);
+    false
+}
+
+/// Parse the header line of an smaps entry, `start-end perms offset dev inode [name]`, into the
+/// mapping's address range and name.
+fn parse_header(line: &str) -> Option<(usize, usize, &str)> {
+    let mut fields = line.splitn(6, ' ');
+    let range = fields.next().unwrap_or("");
+    let dash = match range.find('-') {
+        Some(dash) => dash,
+        None => return None,
+    };
+    let start = match usize::from_str_radix(&range[..dash], 16) {
+        Ok(start) => start,
+        Err(_) => return None,
+    };
+    let end = match usize::from_str_radix(&range[dash + 1..], 16) {
+        Ok(end) => end,
+        Err(_) => return None,
+    };
+    // Skip the permissions, offset, device and inode.
+    let name = fields.nth(4).unwrap_or("").trim();
+    Some((start, end, name))
+}
+
+/// Sum the anonymous memory of the smaps entries for which `in_heap` returns true when passed the
+/// entry's address range and name.
+fn parse_smaps<R, F>(r: R, mut in_heap: F) -> io::Result<ThpStats>
+where
+    R: BufRead,
+    F: FnMut(usize, usize, &str) -> bool,
+{
+    fn kb_field(line: &str, name: &str) -> Option<usize> {
+        if !line.starts_with(name) {
+            return None;
+        }
+        line[name.len()..]
+            .trim()
+            .trim_right_matches("kB")
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .map(|kb| kb << 10)
+    }
+
+    let mut stats = ThpStats::default();
+    let mut counting = false;
+    for line in r.lines() {
+        let line = line?;
+        if let Some((start, end, name)) = parse_header(&line) {
+            counting = in_heap(start, end, name);
+        } else if !counting {
+            continue;
+        } else if let Some(bytes) = kb_field(&line, "Anonymous:") {
+            stats.anon_bytes += bytes;
+        } else if let Some(bytes) = kb_field(&line, "AnonHugePages:") {
+            stats.anon_huge_bytes += bytes;
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_smaps_basic() {
+        let smaps = "10000000-10400000 rw-p 00000000 00:00 0    [anon:elfmalloc:small]
+Size:               4096 kB
+Rss:                4096 kB
+Anonymous:          4096 kB
+AnonHugePages:      2048 kB
+10400000-10600000 rw-p 00000000 00:00 0
+Size:               2048 kB
+Anonymous:          2048 kB
+AnonHugePages:      2048 kB
+10600000-10601000 rw-p 00000000 00:00 0    [heap]
+Anonymous:             4 kB
+AnonHugePages:         0 kB
+";
+        let stats = parse_smaps(smaps.as_bytes(), is_heap_mapping).unwrap();
+        assert_eq!(
+            stats,
+            ThpStats {
+                anon_bytes: 4096 << 10,
+                anon_huge_bytes: 2048 << 10,
+            }
+        );
+
+        // Mappings can also be attributed by address, as with the ownership table.
+        let stats = parse_smaps(smaps.as_bytes(), |start, end, _| {
+            start <= 0x1040_0000 && 0x1040_0000 < end
+        }).unwrap();
+        assert_eq!(
+            stats,
+            ThpStats {
+                anon_bytes: 2048 << 10,
+                anon_huge_bytes: 2048 << 10,
+            }
+        );
+        thp_stats().unwrap();
+    }
+}
@@ -0,0 +1,299 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Triage reports for heap violations that abort the process.
+//!
+//! A bare "object was modified after it was freed" says little about what went wrong. Before
+//! `integrity::report` aborts, it prints a report of the memory around the offending pointer to
+//! stderr:
+//!
+//! - The object, its size class and the part of the heap it belongs to.
+//! - With the `ownership` feature, the `Slag` containing it: its layout, whether a thread owns
+//!   it, and whether the object is marked as available in its bit-set.
+//! - For objects written to in the quarantine, which bytes no longer hold the quarantine's fill
+//!   pattern, so that the extent of the stray write is known.
+//! - A hexdump of the object around the modified byte, with the byte marked, and with the
+//!   `ownership` feature, of the ends of the objects on either side of it, which is where a
+//!   buffer overflow usually comes from.
+//! - For a `Slag` whose bit-set and reference count disagree, its reference count, whether a
+//!   thread owns it, and the number and size of its objects.
+//!
+//! The report is written piece by piece with `FDWriter`, without allocating. Memory is only read
+//! if it belongs to elfmalloc: pointers passed to `free` that elfmalloc does not own are described
+//! by their position relative to elfmalloc's memory, but never dereferenced.
+
+use std::cmp;
+use std::fmt::{self, Write};
+use std::mem;
+use alloc_fmt::FDWriter;
+use super::general::global;
+use super::integrity::Violation;
+#[cfg(feature = "ownership")]
+use super::ownership::{self, Granule};
+use super::slag::Slag;
+
+/// The number of bytes per row of a hexdump.
+const ROW: usize = 16;
+/// The number of bytes of the object that are dumped before and after the modified byte.
+const CONTEXT: usize = 64;
+/// The number of bytes dumped from the end of the previous object and the start of the next one.
+#[cfg(feature = "ownership")]
+const NEIGHBOR_BYTES: usize = 32;
+
+/// Write the triage report for `violation` to stderr.
+pub fn print(violation: &Violation) {
+    let _ = write_report(&mut FDWriter(2), violation);
+}
+
+/// Write the triage report for `violation` to `w`. See the module documentation.
+pub fn write_report<W: Write>(w: &mut W, violation: &Violation) -> fmt::Result {
+    writeln!(w, "elfmalloc: heap triage report: {}", violation)?;
+    match *violation {
+        Violation::WriteAfterFree { object, offset } => unsafe {
+            write_after_free(w, object, offset)
+        },
+        Violation::ForeignFree { ptr } => foreign_free(w, ptr),
+        Violation::CorruptSlag { slag, .. } => unsafe { corrupt_slag(w, &*(slag as *mut Slag)) },
+    }
+}
+
+/// Describe the header of a `Slag` whose bit-set and reference count disagree.
+fn corrupt_slag<W: Write>(w: &mut W, slag: &Slag) -> fmt::Result {
+    let (owned, count) = slag.rc.load();
+    write!(
+        w,
+        "  Slag {:?}: reference count {}, {}",
+        slag.as_raw(),
+        count,
+        if owned { "owned by a thread" } else { "not owned" }
+    )?;
+    match slag.try_metadata() {
+        Some(meta) => writeln!(
+            w,
+            ", {} objects of {} bytes",
+            meta.n_objects,
+            meta.object_size
+        ),
+        None => writeln!(w, ", no metadata"),
+    }
+}
+
+unsafe fn write_after_free<W: Write>(w: &mut W, object: *mut u8, offset: usize) -> fmt::Result {
+    let info = match global::lookup(object) {
+        Some(info) => info,
+        None => return writeln!(w, "  {:?} is not an elfmalloc object", object),
+    };
+    let size = info.size_class.unwrap_or(info.usable_size);
+    writeln!(
+        w,
+        "  object {:?}: {} bytes, usable size {}, {:?} heap",
+        object,
+        size,
+        info.usable_size,
+        info.owning_heap
+    )?;
+    #[cfg(feature = "ownership")]
+    write_slag(w, object)?;
+    #[cfg(feature = "quarantine")]
+    write_fill(w, object, size)?;
+
+    #[cfg(feature = "ownership")]
+    {
+        if let Some(prev) = global::object_start(object.offset(-1)) {
+            let prev_size = global::lookup(prev).map_or(0, |i| i.size_class.unwrap_or(0));
+            let n = cmp::min(NEIGHBOR_BYTES, prev_size);
+            writeln!(w, "  end of the previous object {:?}:", prev)?;
+            hexdump(w, object.offset(-(n as isize)), n, None)?;
+        }
+    }
+    let start = offset.saturating_sub(CONTEXT) & !(ROW - 1);
+    let end = cmp::min(size, offset + CONTEXT);
+    writeln!(w, "  object bytes {}..{}:", start, end)?;
+    hexdump(
+        w,
+        object.offset(start as isize),
+        end - start,
+        Some(object.offset(offset as isize) as *const u8),
+    )?;
+    #[cfg(feature = "ownership")]
+    {
+        let next = object.offset(size as isize);
+        if info.size_class.is_some() && global::object_start(next) == Some(next) {
+            writeln!(w, "  start of the next object {:?}:", next)?;
+            hexdump(w, next, NEIGHBOR_BYTES, None)?;
+        }
+    }
+    Ok(())
+}
+
+/// Describe the `Slag` containing `object`, if it is in one.
+#[cfg(feature = "ownership")]
+unsafe fn write_slag<W: Write>(w: &mut W, object: *mut u8) -> fmt::Result {
+    let slag_size = match ownership::granule(object) {
+        Some(Granule::Slags { slag_size, .. }) => slag_size,
+        _ => return Ok(()),
+    };
+    let slag = &*Slag::find(object, slag_size);
+    let meta = match slag.try_metadata() {
+        Some(meta) => meta,
+        None => return writeln!(w, "  Slag {:?} has no metadata", slag.as_raw()),
+    };
+    writeln!(
+        w,
+        "  Slag {:?}: {:?}, {} bytes, {} objects of {} bytes from offset {}; {}; object {}",
+        slag.as_raw(),
+        meta.ty,
+        meta.total_bytes,
+        meta.n_objects,
+        meta.object_size,
+        meta.objects_offset,
+        if slag.is_owned() {
+            "owned by a thread"
+        } else {
+            "not owned"
+        },
+        if slag.is_available(object, meta) {
+            "marked available"
+        } else {
+            "not marked available"
+        }
+    )
+}
+
+/// Report which bytes of the `size`-byte object at `object` no longer hold the quarantine's fill.
+#[cfg(feature = "quarantine")]
+unsafe fn write_fill<W: Write>(w: &mut W, object: *mut u8, size: usize) -> fmt::Result {
+    use super::quarantine::JUNK;
+    let mut changed = 0;
+    let mut first = None;
+    let mut last = 0;
+    for i in 0..size {
+        if *object.offset(i as isize) != JUNK {
+            changed += 1;
+            first = first.or(Some(i));
+            last = i;
+        }
+    }
+    match first {
+        Some(first) => writeln!(
+            w,
+            "  {} of {} bytes differ from the quarantine fill {:#04x}, at offsets {}..={}",
+            changed,
+            size,
+            JUNK,
+            first,
+            last
+        ),
+        None => writeln!(w, "  the object holds the quarantine fill {:#04x} again", JUNK),
+    }
+}
+
+fn foreign_free<W: Write>(w: &mut W, ptr: *mut u8) -> fmt::Result {
+    writeln!(w, "  {:?} is not in elfmalloc's memory, so it was not read", ptr)?;
+    #[cfg(feature = "ownership")]
+    {
+        if let Some((addr, g)) = ownership::next_owned(ptr as usize) {
+            writeln!(
+                w,
+                "  the next elfmalloc memory is {} bytes above it, at {:#x} ({:?})",
+                addr - ptr as usize,
+                addr,
+                g.ty()
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the `len` bytes at `start` as rows of `ROW` bytes, each prefixed with its address. If
+/// `mark` is one of the bytes, its row is followed by a line marking it.
+unsafe fn hexdump<W: Write>(
+    w: &mut W,
+    start: *const u8,
+    len: usize,
+    mark: Option<*const u8>,
+) -> fmt::Result {
+    let width = 2 + 2 * mem::size_of::<usize>();
+    let mut row = 0;
+    while row < len {
+        let base = start.offset(row as isize);
+        let n = cmp::min(ROW, len - row);
+        write!(w, "    {:#0width$x}:", base as usize, width = width)?;
+        for i in 0..ROW {
+            if i < n {
+                write!(w, " {:02x}", *base.offset(i as isize))?;
+            } else {
+                w.write_str("   ")?;
+            }
+        }
+        w.write_str("  |")?;
+        for i in 0..n {
+            let b = *base.offset(i as isize);
+            w.write_char(if b >= 0x20 && b < 0x7f { b as char } else { '.' })?;
+        }
+        w.write_str("|\n")?;
+        if let Some(mark) = mark {
+            if mark >= base && (mark as usize) < base as usize + n {
+                let column = 4 + width + 1 + 3 * (mark as usize - base as usize) + 1;
+                writeln!(w, "{:column$}^^", "", column = column)?;
+            }
+        }
+        row += n;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hexdump_marks_byte() {
+        let bytes: Vec<u8> = (0..20).map(|i| b'a' + i).collect();
+        let mut out = String::new();
+        unsafe {
+            let mark = bytes.as_ptr().offset(17);
+            hexdump(&mut out, bytes.as_ptr(), bytes.len(), Some(mark)).unwrap();
+        }
+        let lines: Vec<&str> = out.lines().collect();
+        alloc_assert_eq!(lines.len(), 3);
+        alloc_assert!(lines[0].ends_with(" 6f 70  |abcdefghijklmnop|"));
+        alloc_assert!(lines[1].contains(" 71 72 73 74 "));
+        alloc_assert!(lines[1].ends_with("|qrst|"));
+        // The mark is under the second byte of the row, "72".
+        let column = lines[2].find('^').unwrap();
+        alloc_assert_eq!(&lines[1][column..column + 2], "72");
+    }
+
+    #[test]
+    fn report_describes_object() {
+        unsafe {
+            let p = global::alloc(100);
+            for i in 0..100 {
+                *p.offset(i) = i as u8;
+            }
+            let mut out = String::new();
+            write_report(&mut out, &Violation::WriteAfterFree { object: p, offset: 70 }).unwrap();
+            alloc_assert!(out.starts_with("elfmalloc: heap triage report: object"));
+            alloc_assert!(out.contains(&format!("  object {:?}: ", p)));
+            alloc_assert!(out.contains("object bytes 0..") && out.contains("^^"));
+            alloc_assert!(out.contains(" 44 45 46 47 "));
+            global::free(p);
+        }
+    }
+
+    #[cfg(feature = "ownership")]
+    #[test]
+    fn report_does_not_read_foreign_memory() {
+        let local = 0usize;
+        let ptr = &local as *const usize as *mut u8;
+        let mut out = String::new();
+        write_report(&mut out, &Violation::ForeignFree { ptr: ptr }).unwrap();
+        alloc_assert!(out.contains("so it was not read"));
+        alloc_assert!(!out.contains('|'));
+    }
+}
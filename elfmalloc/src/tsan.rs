@@ -0,0 +1,35 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! ThreadSanitizer annotations.
+//!
+//! Objects and pages are passed between threads through lock-free structures (remote frees to a
+//! `Slag`'s bit set, `BagPipe`s of pages) whose synchronization ThreadSanitizer cannot always
+//! see. It then reports a race between the last write to an object by the thread that freed it
+//! and the first write by the thread that allocates it next. With the `tsan` feature enabled, we
+//! make the handoff explicit: freeing an object or page is a release on its address, and
+//! allocating it is an acquire on the same address.
+//!
+//! The interface is provided by the TSan runtime, so the feature requires building with
+//! `RUSTFLAGS="-Z sanitizer=thread"`.
+
+extern "C" {
+    fn __tsan_acquire(addr: *mut u8);
+    fn __tsan_release(addr: *mut u8);
+}
+
+/// Annotate that `p` was just allocated, and may have been freed by another thread.
+#[inline]
+pub unsafe fn acquire(p: *mut u8) {
+    __tsan_acquire(p);
+}
+
+/// Annotate that `p` is about to be freed, and may be allocated by another thread.
+#[inline]
+pub unsafe fn release(p: *mut u8) {
+    __tsan_release(p);
+}
@@ -0,0 +1,128 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Batching of `munmap` and `madvise` calls.
+//!
+//! A `RangeBatch` collects address ranges that are to be unmapped or uncommitted, and hands them
+//! out again with adjacent ranges merged, so that memory returned in pieces is returned to the
+//! kernel with one system call per contiguous region. Pages carved out of the same mapping, and
+//! large object mappings created one after another (which the kernel tends to place next to each
+//! other), are usually adjacent.
+//!
+//! `PageAlloc::trim` always uncommits pages in batches. With the `batch-unmap` feature, each heap
+//! also batches the unmapping of freed large objects, and with the `defer_unmap` option (see
+//! `conf`), full batches are unmapped by the background thread rather than by the thread that
+//! freed the last object.
+
+use std::mem;
+
+/// The number of ranges in a batch.
+pub const BATCH_RANGES: usize = 16;
+/// A batch with at least this many bytes is considered full, whatever the number of ranges.
+pub const BATCH_BYTES: usize = 64 << 20;
+
+/// A set of address ranges waiting to be unmapped or uncommitted.
+pub struct RangeBatch {
+    /// The start and length of each range. Only the first `len` entries are used.
+    ranges: [(usize, usize); BATCH_RANGES],
+    len: usize,
+    bytes: usize,
+}
+
+impl RangeBatch {
+    pub fn new() -> RangeBatch {
+        RangeBatch {
+            ranges: [(0, 0); BATCH_RANGES],
+            len: 0,
+            bytes: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Add the `len` bytes at `base` to the batch. Returns `true` if the batch is now full, in
+    /// which case it has to be flushed before anything else is added.
+    pub fn push(&mut self, base: *mut u8, len: usize) -> bool {
+        alloc_debug_assert!(self.len < BATCH_RANGES, "RangeBatch::push on a full batch");
+        self.ranges[self.len] = (base as usize, len);
+        self.len += 1;
+        self.bytes += len;
+        self.len == BATCH_RANGES || self.bytes >= BATCH_BYTES
+    }
+
+    /// Move the contents of the batch into a new one, leaving this one empty.
+    pub fn take(&mut self) -> RangeBatch {
+        mem::replace(self, RangeBatch::new())
+    }
+
+    /// Empty the batch, calling `f` once for every maximal run of adjacent ranges with its start
+    /// and length. Returns the total number of bytes in the batch.
+    pub fn flush<F: FnMut(*mut u8, usize)>(&mut self, mut f: F) -> usize {
+        let ranges = &mut self.ranges[..self.len];
+        ranges.sort_unstable();
+        let mut run: Option<(usize, usize)> = None;
+        for &(base, len) in ranges.iter() {
+            run = match run {
+                Some((start, run_len)) if start + run_len == base => Some((start, run_len + len)),
+                Some((start, run_len)) => {
+                    f(start as *mut u8, run_len);
+                    Some((base, len))
+                }
+                None => Some((base, len)),
+            };
+        }
+        if let Some((start, run_len)) = run {
+            f(start as *mut u8, run_len);
+        }
+        let bytes = self.bytes;
+        self.len = 0;
+        self.bytes = 0;
+        bytes
+    }
+}
+
+impl Default for RangeBatch {
+    fn default() -> RangeBatch {
+        RangeBatch::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_adjacent_ranges() {
+        let mut batch = RangeBatch::new();
+        let page = 4096;
+        // Three adjacent ranges pushed out of order, and one on its own.
+        for &base in &[2 * page, 10 * page, 0, page] {
+            alloc_assert!(!batch.push(base as *mut u8, page));
+        }
+        let mut runs = Vec::new();
+        let bytes = batch.flush(|p, len| runs.push((p as usize, len)));
+        alloc_assert_eq!(bytes, 4 * page);
+        alloc_assert_eq!(runs, vec![(0, 3 * page), (10 * page, page)]);
+        alloc_assert!(batch.is_empty());
+        alloc_assert_eq!(batch.flush(|_, _| panic!("the batch should be empty")), 0);
+    }
+
+    #[test]
+    fn full_batches() {
+        let mut batch = RangeBatch::new();
+        for i in 0..BATCH_RANGES - 1 {
+            alloc_assert!(!batch.push((i * 2 * 4096) as *mut u8, 4096));
+        }
+        alloc_assert!(batch.push(1 << 30, 4096));
+        let full = batch.take();
+        alloc_assert!(batch.is_empty());
+        alloc_assert!(!full.is_empty());
+        alloc_assert!(RangeBatch::new().push(0 as *mut u8, BATCH_BYTES));
+    }
+}
@@ -6,30 +6,67 @@
 // copied, modified, or distributed except according to those terms.
 
 //! Some basic utilities used throughout the allocator code.
+//!
+//! # Pointer provenance
+//!
+//! Pointers carry the provenance of the allocation they were derived from, and casting an integer
+//! back to a pointer loses it. CHERI cannot represent such pointers at all, and Miri rejects them
+//! under `-Zmiri-strict-provenance`. Wherever we compute an address arithmetically (rounding down
+//! to a `Slag` or page boundary, aligning a bump pointer), we therefore derive the result from a
+//! pointer into the same mapping with `with_addr` or `map_addr` rather than casting.
+//!
+//! The exception are pointers that are stored as integers in atomics (the `StaticCell`s, `Creek`
+//! bump pointers, the `ownership` table, and the `bagpipe` queues), which are *exposed*: they are
+//! only ever cast back to the pointer they were created from.
 use std::cmp;
+use std::mem;
 use std::ops::{Deref, DerefMut};
 use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
 pub mod mmap {
+    //! Thin wrappers around the system's memory mapping functions.
+    //!
+    //! Under Miri, which cannot execute foreign functions such as `mmap`, mappings are emulated
+    //! with the global allocator instead. This lets the logic built on top of them (containers,
+    //! size class computations, the slab layer) be checked for undefined behavior. The emulation
+    //! does not support unmapping part of a mapping, and `uncommit` simply zeroes memory.
+    #[cfg(target_os = "linux")]
+    extern crate libc;
+    #[cfg(not(miri))]
     extern crate mmap_alloc;
+    #[cfg(not(miri))]
     extern crate sysconf;
+    #[cfg(not(miri))]
     use self::mmap_alloc::MapAllocBuilder;
     use super::super::alloc::allocator::{Alloc, Layout};
 
+    #[cfg(not(miri))]
     pub fn page_size() -> usize {
         self::sysconf::page::pagesize()
     }
 
+    #[cfg(miri)]
+    pub fn page_size() -> usize {
+        4096
+    }
+
     pub fn map(size: usize) -> *mut u8 {
         fallible_map(size).expect("mmap should not fail")
     }
 
+    #[cfg(not(miri))]
     pub fn fallible_map(size: usize) -> Option<*mut u8> {
         unsafe {
             if let Ok(s) = MapAllocBuilder::default()
                    .exec(true)
                    .build()
                    .alloc(Layout::from_size_align(size, 1).unwrap()) {
+                slow_path_event!(MAPS);
+                slow_path_event!(MAPPED_BYTES, size);
                 Some(s)
             } else {
                 None
@@ -37,18 +74,236 @@ pub mod mmap {
         }
     }
 
+    #[cfg(not(miri))]
     pub unsafe fn unmap(p: *mut u8, len: usize) {
+        slow_path_event!(UNMAPS);
+        slow_path_event!(UNMAPPED_BYTES, len);
         MapAllocBuilder::default().exec(true).build().dealloc(
             p,
             Layout::from_size_align(len, 1).unwrap(),
         )
     }
+    #[cfg(not(miri))]
     pub unsafe fn uncommit(p: *mut u8, len: usize) {
         MapAllocBuilder::default().exec(true).build().uncommit(
             p,
             Layout::from_size_align(len, 1).unwrap(),
         )
     }
+
+    /// Map `size` bytes aligned to `align`, which must be a power of two.
+    ///
+    /// Only used under Miri, where `MmapSource` cannot obtain aligned memory by unmapping the
+    /// unaligned ends of a larger mapping.
+    #[cfg(miri)]
+    pub fn fallible_map_aligned(size: usize, align: usize) -> Option<*mut u8> {
+        use std::cmp;
+        use std::mem;
+        use std::ptr;
+        use super::super::alloc::heap::Heap;
+        // The layout of the allocation is stored just before the returned pointer so that
+        // `unmap` can reconstruct it.
+        let align = cmp::max(align, page_size());
+        alloc_debug_assert!(mem::size_of::<Layout>() <= align);
+        let layout = match Layout::from_size_align(size + align, align) {
+            Some(layout) => layout,
+            None => return None,
+        };
+        unsafe {
+            match Heap.alloc_zeroed(layout.clone()) {
+                Ok(p) => {
+                    slow_path_event!(MAPS);
+                    slow_path_event!(MAPPED_BYTES, size);
+                    let res = p.offset(align as isize);
+                    ptr::write((res as *mut Layout).offset(-1), layout);
+                    Some(res)
+                }
+                Err(_) => None,
+            }
+        }
+    }
+
+    #[cfg(miri)]
+    pub fn fallible_map(size: usize) -> Option<*mut u8> {
+        fallible_map_aligned(size, page_size())
+    }
+
+    #[cfg(miri)]
+    pub unsafe fn unmap(p: *mut u8, _len: usize) {
+        use std::ptr;
+        slow_path_event!(UNMAPS);
+        slow_path_event!(UNMAPPED_BYTES, _len);
+        use super::super::alloc::heap::Heap;
+        let layout = ptr::read((p as *mut Layout).offset(-1));
+        Heap.dealloc(p.offset(-(layout.align() as isize)), layout)
+    }
+
+    #[cfg(miri)]
+    pub unsafe fn uncommit(p: *mut u8, len: usize) {
+        // Uncommitted memory reads as zeros.
+        ::std::ptr::write_bytes(p, 0, len)
+    }
+
+    /// Make `[p, p + len)` read-only. `p` and `len` must be multiples of the page size.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn protect_read_only(p: *mut u8, len: usize) {
+        let ret = libc::mprotect(p as *mut libc::c_void, len, libc::PROT_READ);
+        alloc_assert_eq!(ret, 0, "mprotect failed");
+    }
+
+    /// Fault in the pages of `[p, p + len)` for writing, so that the first accesses to them do not
+    /// take page faults.
+    ///
+    /// On Linux 5.14 and later, this is a single `madvise(MADV_POPULATE_WRITE)` call, which has the
+    /// effect `MAP_POPULATE` would have had on the range. Elsewhere, or if that fails, each page is
+    /// read and written back. The contents of the range are unchanged either way.
+    pub unsafe fn prefault(p: *mut u8, len: usize) {
+        #[cfg(all(target_os = "linux", not(miri)))]
+        {
+            // not exported by the libc crate
+            const MADV_POPULATE_WRITE: libc::c_int = 23;
+            if libc::madvise(p as *mut libc::c_void, len, MADV_POPULATE_WRITE) == 0 {
+                return;
+            }
+        }
+        let page = page_size();
+        let mut offset = 0;
+        while offset < len {
+            let q = p.offset(offset as isize);
+            ::std::ptr::write_volatile(q, ::std::ptr::read_volatile(q));
+            offset += page;
+        }
+    }
+
+    /// The longest name that Linux accepts for a mapping, including the terminating NUL.
+    const MAX_NAME: usize = 80;
+
+    /// A name for anonymous mappings, which `name` attaches to them. It is shown as
+    /// `[anon:<name>]` in `/proc/<pid>/maps` and `/proc/<pid>/smaps`.
+    #[derive(Copy)]
+    pub struct MapName {
+        /// The name, NUL-terminated.
+        buf: [u8; MAX_NAME],
+    }
+
+    impl Clone for MapName {
+        fn clone(&self) -> MapName {
+            *self
+        }
+    }
+
+    impl MapName {
+        /// `elfmalloc:<kind>`, or `elfmalloc:<heap>:<kind>` for a named heap. Characters that the
+        /// kernel does not accept in names are replaced with `_`, and names that are too long are
+        /// truncated.
+        pub fn new(heap: Option<&str>, kind: &str) -> MapName {
+            let mut buf = [0; MAX_NAME];
+            let mut len = 0;
+            {
+                let mut push = |s: &str| for &b in s.as_bytes() {
+                    if len < MAX_NAME - 1 {
+                        buf[len] = match b {
+                            b'\\' | b'`' | b'$' | b'[' | b']' => b'_',
+                            0x20...0x7e => b,
+                            _ => b'_',
+                        };
+                        len += 1;
+                    }
+                };
+                push("elfmalloc:");
+                if let Some(heap) = heap {
+                    push(heap);
+                    push(":");
+                }
+                push(kind);
+            }
+            MapName { buf: buf }
+        }
+
+        /// The name, without the terminating NUL.
+        pub fn as_bytes(&self) -> &[u8] {
+            let len = self.buf.iter().position(|&b| b == 0).unwrap();
+            &self.buf[..len]
+        }
+    }
+
+    /// Attach `name` to the mappings in `[p, p + len)`, which must be page-aligned, so that they
+    /// can be told apart from other anonymous memory when debugging the memory use of a process.
+    ///
+    /// This uses `prctl(PR_SET_VMA_ANON_NAME)`, which is only available on Linux 5.17 and later,
+    /// and only if the kernel was built with `CONFIG_ANON_VMA_NAME`. The first time it fails,
+    /// naming is turned off for the rest of the process. It can also be turned off with the
+    /// `name_mappings` option (see the `conf` module). On other platforms, this does nothing.
+    #[cfg(all(target_os = "linux", not(miri)))]
+    pub unsafe fn name(p: *mut u8, len: usize, name: &MapName) {
+        use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+        use super::super::conf;
+        // not exported by the libc crate
+        const PR_SET_VMA: libc::c_int = 0x53564d41;
+        const PR_SET_VMA_ANON_NAME: libc::c_ulong = 0;
+        static UNSUPPORTED: AtomicBool = ATOMIC_BOOL_INIT;
+        if UNSUPPORTED.load(Ordering::Relaxed) || !conf::name_mappings() {
+            return;
+        }
+        let ret = libc::prctl(
+            PR_SET_VMA,
+            PR_SET_VMA_ANON_NAME,
+            p as libc::c_ulong,
+            len as libc::c_ulong,
+            name.buf.as_ptr() as libc::c_ulong,
+        );
+        if ret != 0 {
+            UNSUPPORTED.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[cfg(not(all(target_os = "linux", not(miri))))]
+    pub unsafe fn name(_p: *mut u8, _len: usize, _name: &MapName) {}
+
+    /// Ask the kernel to back `[p, p + len)` with transparent huge pages.
+    ///
+    /// This uses `MADV_COLLAPSE`, which is only available on Linux 6.1 and later. Any pages in the
+    /// range that are not yet committed will be committed. Returns `false` if the request failed,
+    /// e.g. because the kernel does not support it or THP is disabled.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn collapse(p: *mut u8, len: usize) -> bool {
+        // not exported by the libc crate
+        const MADV_COLLAPSE: libc::c_int = 25;
+        libc::madvise(p as *mut libc::c_void, len, MADV_COLLAPSE) == 0
+    }
+}
+
+pub mod cpu {
+    //! The CPU a thread is running on, for spreading shared structures across CPUs.
+    #[cfg(all(target_os = "linux", not(miri)))]
+    extern crate libc;
+
+    /// A number identifying the CPU the current thread is running on or, where that cannot be
+    /// determined, a hash of the thread's identity.
+    ///
+    /// This is only a hint: the thread may be migrated to another CPU right after the call.
+    #[inline]
+    pub fn current() -> usize {
+        #[cfg(all(target_os = "linux", not(miri)))]
+        {
+            // This goes through the vDSO, so it does not cost a system call.
+            let cpu = unsafe { libc::sched_getcpu() };
+            if cpu >= 0 {
+                return cpu as usize;
+            }
+        }
+        thread_hash()
+    }
+
+    fn thread_hash() -> usize {
+        thread_local! {
+            static ID: u8 = 0;
+        }
+        // The address of a thread-local variable is unique among the running threads. The low
+        // bits are the same for every thread, so they are hashed away.
+        let addr = ID.try_with(|id| id as *const u8 as usize).unwrap_or(0);
+        addr.wrapping_mul(0x9e37_79b9) >> 16
+    }
 }
 
 // we use the unlikely intrinsic if it is available.
@@ -70,6 +325,133 @@ pub unsafe fn likely(b: bool) -> bool {
     b
 }
 
+/// Return a pointer with the provenance of `p` and the address `addr`.
+///
+/// This mirrors the unstable strict-provenance `with_addr`; `addr` must lie in the same mapping as
+/// `p` for the result to be dereferenced.
+#[inline(always)]
+pub fn with_addr<T>(p: *mut T, addr: usize) -> *mut T {
+    let p = p as *mut u8;
+    p.wrapping_offset(addr.wrapping_sub(p as usize) as isize) as *mut T
+}
+
+/// Replace the address of `p` with `f` applied to it, keeping the provenance of `p`.
+#[inline(always)]
+pub fn map_addr<T, F: FnOnce(usize) -> usize>(p: *mut T, f: F) -> *mut T {
+    with_addr(p, f(p as usize))
+}
+
+pub mod random {
+    //! A small, fast pseudo-random number generator for randomized object placement.
+    //!
+    //! This is xorshift64* seeded with splitmix64. It is not cryptographically secure; it only
+    //! has to make placement hard to predict from outside the process.
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+    static SEED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    fn splitmix(mut z: u64) -> u64 {
+        z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Return a fresh non-zero generator state.
+    ///
+    /// The first call derives a process-wide seed from the clock and the address of the stack
+    /// (which is randomized by ASLR); each call then yields a distinct state.
+    pub fn seed() -> u64 {
+        use std::time::{SystemTime, UNIX_EPOCH};
+        if SEED.load(Ordering::Relaxed) == 0 {
+            let local = 0u8;
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() ^ u64::from(d.subsec_nanos()) << 32)
+                .unwrap_or(0);
+            let fresh = splitmix(nanos ^ (&local as *const u8 as u64)) as usize | 1;
+            let _ = SEED.compare_exchange(0, fresh, Ordering::Relaxed, Ordering::Relaxed);
+        }
+        // splitmix64 is a bijection, so distinct counter values give distinct states.
+        match splitmix(SEED.fetch_add(1, Ordering::Relaxed) as u64) {
+            0 => 1,
+            x => x,
+        }
+    }
+
+    /// Advance `state` and return the next pseudo-random number.
+    #[inline]
+    pub fn next(state: &mut u64) -> u64 {
+        let mut x = *state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        *state = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+/// The value of `StaticCell::addr` while a thread is creating the value.
+const STATIC_CELL_BUSY: usize = 1;
+
+/// A `T` that is created the first time it is accessed and never destroyed.
+///
+/// Unlike a `lazy_static`, a `StaticCell` can be constructed in a constant expression (with `new`
+/// on nightly, or by writing out its fields on stable). The value is placed in memory mapped for
+/// the purpose, so creating it does not call into any allocator. This makes it suitable for the
+/// allocator's own global state.
+pub struct StaticCell<T> {
+    /// The address of the value; 0 before it is created, and `STATIC_CELL_BUSY` while it is
+    /// being created.
+    pub addr: AtomicUsize,
+    pub marker: PhantomData<T>,
+}
+
+impl<T> StaticCell<T> {
+    #[cfg(feature = "nightly")]
+    pub const fn new() -> StaticCell<T> {
+        StaticCell {
+            addr: ::std::sync::atomic::ATOMIC_USIZE_INIT,
+            marker: PhantomData,
+        }
+    }
+
+    /// Get the value, calling `init` to create it if this is the first access.
+    ///
+    /// If several threads get here first at the same time, one of them calls `init` and the
+    /// others wait for it to finish.
+    #[inline]
+    pub fn get_or_init<F: FnOnce() -> T>(&self, init: F) -> &T {
+        let addr = self.addr.load(Ordering::Acquire);
+        if unsafe { likely(addr > STATIC_CELL_BUSY) } {
+            return unsafe { &*(addr as *const T) };
+        }
+        self.init(init)
+    }
+
+    #[cold]
+    fn init<F: FnOnce() -> T>(&self, init: F) -> &T {
+        if self.addr
+            .compare_exchange(0, STATIC_CELL_BUSY, Ordering::Acquire, Ordering::Acquire)
+            .is_ok()
+        {
+            unsafe {
+                let val = mmap::map(cmp::max(mem::size_of::<T>(), 1)) as *mut T;
+                ptr::write(val, init());
+                self.addr.store(val as usize, Ordering::Release);
+                return &*val;
+            }
+        }
+        loop {
+            let addr = self.addr.load(Ordering::Acquire);
+            if addr > STATIC_CELL_BUSY {
+                return unsafe { &*(addr as *const T) };
+            }
+            thread::yield_now();
+        }
+    }
+}
+
 /// A `LazyInitializable` type can be constructed from `Params`.
 ///
 /// Types that implement this trate can be wrapped in the `Lazy` construct.
@@ -110,6 +492,11 @@ impl<T: LazyInitializable> Lazy<T> {
             val: UnsafeCell::new(None),
         }
     }
+
+    /// Get a mutable reference to the underlying object only if it has already been initialized.
+    pub fn get_mut_initialized(&mut self) -> Option<&mut T> {
+        unsafe { (*self.val.get()).as_mut() }
+    }
 }
 
 impl<T: LazyInitializable> Deref for Lazy<T> {
@@ -138,6 +525,32 @@ impl<T: LazyInitializable> DerefMut for Lazy<T> {
     }
 }
 
+/// The size of a cache line on the platforms we care about. Adjacent-line prefetching on recent
+/// Intel CPUs effectively doubles this, but 64 bytes is enough to keep two values from being
+/// written in the same line.
+pub const CACHE_LINE: usize = 64;
+
+/// A `T` aligned to, and therefore alone in, a cache line.
+///
+/// Wrap values that are written by many threads (shared counters, the heads of shared queues) so
+/// that writing them does not invalidate the lines that hold neighbouring read-mostly data, or
+/// other threads' counters. The field is public so that a `CachePadded` can initialize a `static`.
+#[repr(align(64))]
+#[derive(Default, Debug)]
+pub struct CachePadded<T>(pub T);
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
 
 /// A low-level dynamic collection of `T` values.
 ///
@@ -155,6 +568,11 @@ pub struct TypedArray<T> {
     mapped: usize,
 }
 
+// Elements are only reached through `get`, which returns a raw pointer, so sharing the array is
+// as safe as sharing its elements. This lets arrays of atomics live in statics.
+unsafe impl<T: Send> Send for TypedArray<T> {}
+unsafe impl<T: Sync> Sync for TypedArray<T> {}
+
 impl<T> TypedArray<T> {
     pub fn new(size: usize) -> TypedArray<T> {
         use std::mem::size_of;
@@ -247,6 +665,13 @@ mod tests {
     }
 
     use super::*;
+
+    #[test]
+    fn cache_padded() {
+        alloc_assert_eq!(mem::align_of::<CachePadded<u8>>(), CACHE_LINE);
+        alloc_assert_eq!(mem::size_of::<[CachePadded<AtomicUsize>; 2]>(), 2 * CACHE_LINE);
+    }
+
     #[test]
     fn basic_functionality() {
         let mut l = Lazy::<DefaultInit<usize>>::new(());
@@ -257,4 +682,29 @@ mod tests {
         alloc_assert_eq!(l_u, 1);
     }
 
+    #[test]
+    fn with_addr_keeps_provenance() {
+        let mut buf = [0u64; 8];
+        let base = buf.as_mut_ptr();
+        let p = with_addr(base, base as usize + 3 * mem::size_of::<u64>());
+        alloc_assert_eq!(p, unsafe { base.offset(3) });
+        unsafe { *p = 7 };
+        alloc_assert_eq!(buf[3], 7);
+        let q = map_addr(p as *mut u8, |addr| addr & !(mem::size_of::<u64>() - 1));
+        alloc_assert_eq!(q, p as *mut u8);
+    }
+
+    #[test]
+    fn map_names() {
+        use self::mmap::MapName;
+        alloc_assert_eq!(MapName::new(None, "small").as_bytes(), b"elfmalloc:small");
+        alloc_assert_eq!(
+            MapName::new(Some("a[b]$\n"), "large").as_bytes(),
+            b"elfmalloc:a_b___:large"
+        );
+        let long = ::std::iter::repeat("x").take(100).collect::<String>();
+        let name = MapName::new(Some(&long), "small");
+        alloc_assert_eq!(name.as_bytes().len(), 79);
+        alloc_assert!(name.as_bytes().starts_with(b"elfmalloc:xxx"));
+    }
 }
@@ -0,0 +1,141 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Valgrind client requests.
+//!
+//! Memcheck only intercepts the system `malloc`; without help, it sees every `Slag` as a single
+//! block of memory that was initialized when it was mapped, and cannot report reads of
+//! uninitialized or freed objects. With the `valgrind` feature enabled, we describe the heap to it
+//! with client requests:
+//!
+//! - Objects allocated from `Slag`s are announced with `MALLOCLIKE_BLOCK` and `FREELIKE_BLOCK`, so
+//!   that memcheck tracks their definedness and reports leaks and invalid accesses in terms of
+//!   them. Large objects are mapped directly, which memcheck already understands.
+//! - Pages are marked undefined when they are returned to a page cache, as they may later be used
+//!   for metadata or for a different size class.
+//! - A `BumpAlloc` is a memcheck *mempool*: its chunks are inaccessible until they are handed out,
+//!   and everything allocated from it is discarded at once by `reset`.
+//!
+//! Client requests are a special instruction sequence that is a no-op when not running under
+//! Valgrind, so leaving the feature enabled costs only a few instructions per call. They are only
+//! implemented for x86-64; on other architectures, the requests do nothing.
+
+const RUNNING_ON_VALGRIND: usize = 0x1001;
+const MALLOCLIKE_BLOCK: usize = 0x1301;
+const FREELIKE_BLOCK: usize = 0x1302;
+const CREATE_MEMPOOL: usize = 0x1303;
+const DESTROY_MEMPOOL: usize = 0x1304;
+const MEMPOOL_ALLOC: usize = 0x1305;
+const MEMPOOL_FREE: usize = 0x1306;
+const RESIZEINPLACE_BLOCK: usize = 0x130b;
+/// The base of the memcheck-specific requests, `VG_USERREQ_TOOL_BASE('M', 'C')`.
+const MEMCHECK_BASE: usize = ((b'M' as usize) << 24) | ((b'C' as usize) << 16);
+const MAKE_MEM_NOACCESS: usize = MEMCHECK_BASE;
+const MAKE_MEM_UNDEFINED: usize = MEMCHECK_BASE + 1;
+const MAKE_MEM_DEFINED: usize = MEMCHECK_BASE + 2;
+
+/// Issue a client request. Returns `default` when not running under Valgrind.
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+unsafe fn request(default: usize, args: [usize; 6]) -> usize {
+    let result;
+    // This is the sequence from valgrind.h: the rotations of rdi add up to 128 bits and so leave
+    // it unchanged, and Valgrind recognizes them as a marker.
+    asm!("rolq $$3, %rdi; rolq $$13, %rdi; rolq $$61, %rdi; rolq $$51, %rdi; xchgq %rbx, %rbx"
+         : "={rdx}"(result)
+         : "{rax}"(args.as_ptr()), "{rdx}"(default)
+         : "cc", "memory"
+         : "volatile");
+    result
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+#[inline(always)]
+unsafe fn request(default: usize, _args: [usize; 6]) -> usize {
+    default
+}
+
+/// Are we running under Valgrind?
+pub fn running_on_valgrind() -> bool {
+    unsafe { request(0, [RUNNING_ON_VALGRIND, 0, 0, 0, 0, 0]) != 0 }
+}
+
+/// Announce that `[p, p + size)` has been allocated.
+#[inline]
+pub unsafe fn malloclike_block(p: *mut u8, size: usize, zeroed: bool) {
+    request(0, [MALLOCLIKE_BLOCK, p as usize, size, 0, zeroed as usize, 0]);
+}
+
+/// Announce that the block at `p` has been freed.
+#[inline]
+pub unsafe fn freelike_block(p: *mut u8) {
+    request(0, [FREELIKE_BLOCK, p as usize, 0, 0, 0, 0]);
+}
+
+/// Announce that the block at `p` has changed size in place.
+#[inline]
+pub unsafe fn resize_in_place(p: *mut u8, old_size: usize, new_size: usize) {
+    request(0, [RESIZEINPLACE_BLOCK, p as usize, old_size, new_size, 0, 0]);
+}
+
+#[inline]
+pub unsafe fn make_mem_noaccess(p: *mut u8, len: usize) {
+    request(0, [MAKE_MEM_NOACCESS, p as usize, len, 0, 0, 0]);
+}
+
+#[inline]
+pub unsafe fn make_mem_undefined(p: *mut u8, len: usize) {
+    request(0, [MAKE_MEM_UNDEFINED, p as usize, len, 0, 0, 0]);
+}
+
+#[inline]
+pub unsafe fn make_mem_defined(p: *mut u8, len: usize) {
+    request(0, [MAKE_MEM_DEFINED, p as usize, len, 0, 0, 0]);
+}
+
+/// Create a mempool identified by the address `pool`.
+pub unsafe fn create_mempool(pool: *const u8) {
+    request(0, [CREATE_MEMPOOL, pool as usize, 0, 0, 0, 0]);
+}
+
+/// Destroy the mempool `pool`, freeing all of its blocks.
+pub unsafe fn destroy_mempool(pool: *const u8) {
+    request(0, [DESTROY_MEMPOOL, pool as usize, 0, 0, 0, 0]);
+}
+
+/// Announce that `[p, p + size)` has been allocated from `pool`.
+#[inline]
+pub unsafe fn mempool_alloc(pool: *const u8, p: *mut u8, size: usize) {
+    request(0, [MEMPOOL_ALLOC, pool as usize, p as usize, size, 0, 0]);
+}
+
+/// Announce that the block at `p` in `pool` has been freed.
+#[inline]
+pub unsafe fn mempool_free(pool: *const u8, p: *mut u8) {
+    request(0, [MEMPOOL_FREE, pool as usize, p as usize, 0, 0, 0]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::general::DynamicAllocator;
+
+    #[test]
+    fn requests_are_noops_natively() {
+        if running_on_valgrind() {
+            return;
+        }
+        let mut alloc = DynamicAllocator::new();
+        unsafe {
+            let p = alloc.alloc(100);
+            *p = 1;
+            let q = alloc.realloc(p, 50);
+            alloc_assert_eq!(*q, 1);
+            alloc.free(q);
+        }
+    }
+}
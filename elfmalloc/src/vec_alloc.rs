@@ -10,20 +10,206 @@
 //! This module contains an `Alloc`-parametric `Vec` implementation based on `RawVec`.
 //! This is currently more of a proof of concept, though it may serve as a starting point
 //! for more robust `Alloc`-parametric collections.
+//!
+//! What an `AVec` does with its spare capacity when it is cleared or truncated is governed by a
+//! `CapacityPolicy`. The default comes from the allocator type (see `DefaultCapacityPolicy`) and
+//! can be overridden per instance with `AVec::set_capacity_policy`. This makes it possible to
+//! compare returning memory at the container level with leaving it to the allocator. The
+//! `Decommit` policy hands the unused tail of the buffer to the allocator's `Uncommit` hook, so it
+//! only returns memory for allocators that know which of their memory may be uncommitted.
+//!
+//! `AVec`s of plain-old-data types (see `Pod`) can be viewed as bytes and built from bytes, which
+//! lets benchmarks model allocate-read-parse loops without a per-element decoding step.
+//!
+//! `AVecWithHeader` stores a header and its elements in a single allocation, like a C struct
+//! ending in a flexible array member. This models messages and packets, where a fixed-size header
+//! and a variable-size payload are allocated and freed together.
+//!
+//! `AVec::try_reserve` and `AVec::try_push` return the allocator's error when the buffer cannot
+//! be grown, rather than aborting the process as `reserve` and `push` do. Together with a limit
+//! on an `IsolatedHeap`, this makes it possible to test how a program copes with running out of
+//! memory.
+//!
+//! `AGapBuffer` is a gap buffer, a sequence with a cursor at which elements are inserted and
+//! deleted, as used by text editors. It models workloads that insert in the middle of a large
+//! sequence, whose allocations are a series of reallocations of medium and large buffers.
+//!
+//! `InlineVec` stores up to a fixed number of elements inline, like `smallvec::SmallVec`, and
+//! spills to a buffer from its `Alloc` when it outgrows them. Comparing it with an `AVec` shows
+//! how much of the cost of many small vectors is the allocator's, and comparing it under
+//! different allocators shows how cheap each makes the spill path.
 
 extern crate smallvec;
-use self::smallvec::VecLike;
-use super::alloc::allocator::Alloc;
+use self::smallvec::{Array, VecLike};
+use super::alloc::allocator::{Alloc, AllocErr, Layout};
 use super::alloc::heap::Heap;
 use super::alloc::raw_vec::RawVec;
 use super::rust_alloc;
-use super::rust_alloc::{DynamicAlloc, SharedAlloc};
+use super::rust_alloc::{DynamicAlloc, SendableAlloc, SharedAlloc};
+use super::utils::{mmap, with_addr};
 
 use std::cmp;
+use std::fmt;
+use std::error::Error;
 use std::iter::{IntoIterator, Extend};
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop};
 use std::ops;
 use std::ptr;
 
+/// What an `AVec` does with capacity that is no longer in use after `clear` or `truncate`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CapacityPolicy {
+    /// Keep the capacity around for future pushes.
+    Keep,
+    /// Reallocate the buffer so that the capacity equals the length.
+    ShrinkToFit,
+    /// Keep the capacity, but ask the allocator to return the pages backing the unused tail of
+    /// the buffer to the operating system (see `Uncommit`). They are transparently recommitted
+    /// when written to. An `AVec` without an `Uncommit` hook keeps the pages, as with `Keep`.
+    Decommit,
+}
+
+/// The `CapacityPolicy` used by `AVec`s with a given allocator unless set explicitly.
+pub trait DefaultCapacityPolicy {
+    fn default_capacity_policy() -> CapacityPolicy {
+        CapacityPolicy::Keep
+    }
+}
+
+impl DefaultCapacityPolicy for DynamicAlloc {}
+impl DefaultCapacityPolicy for SharedAlloc {}
+impl DefaultCapacityPolicy for SendableAlloc {}
+impl DefaultCapacityPolicy for Heap {}
+
+/// The allocator's side of `CapacityPolicy::Decommit`.
+///
+/// Only the allocator knows whether the memory behind one of its objects may be uncommitted: it
+/// may come from the system allocator, be locked into memory, or be read-only. The default
+/// implementation therefore does nothing.
+pub trait Uncommit {
+    /// Uncommit the `len` bytes at `ptr`, a range of whole pages within an object allocated by
+    /// `self`. The pages must read as zeros when they are next accessed.
+    unsafe fn uncommit(&self, _ptr: *mut u8, _len: usize) {}
+}
+
+/// Uncommit pages of an elfmalloc object. elfmalloc maps all of its memory itself, so the pages
+/// can be handed back with `madvise`. Elsewhere than on Linux, uncommitted pages are not
+/// recommitted on access, and with `mte`, recommitted pages would lose the object's tags, so
+/// nothing is done.
+unsafe fn uncommit_object_pages(_ptr: *mut u8, _len: usize) {
+    #[cfg(all(target_os = "linux", not(feature = "mte")))]
+    mmap::uncommit(_ptr, _len);
+}
+
+impl Uncommit for DynamicAlloc {
+    unsafe fn uncommit(&self, ptr: *mut u8, len: usize) {
+        uncommit_object_pages(ptr, len)
+    }
+}
+
+impl Uncommit for SharedAlloc {
+    unsafe fn uncommit(&self, ptr: *mut u8, len: usize) {
+        uncommit_object_pages(ptr, len)
+    }
+}
+
+impl Uncommit for SendableAlloc {
+    unsafe fn uncommit(&self, ptr: *mut u8, len: usize) {
+        uncommit_object_pages(ptr, len)
+    }
+}
+
+// Memory from the system allocator is not ours to uncommit.
+impl Uncommit for Heap {}
+
+/// Types for which every bit pattern is a valid value, and that have no padding.
+///
+/// This is the same contract as `bytemuck`'s `Pod`: such values can be freely converted to and
+/// from bytes. It is implemented for the primitive integer and floating point types and for
+/// arrays of them.
+pub unsafe trait Pod: Copy + 'static {}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => {
+        $(
+            unsafe impl Pod for $t {}
+        )*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, f32, f64);
+
+macro_rules! impl_pod_arrays {
+    ($($n:expr),*) => {
+        $(
+            unsafe impl<T: Pod> Pod for [T; $n] {}
+        )*
+    };
+}
+
+impl_pod_arrays!(1, 2, 3, 4, 5, 6, 7, 8, 16, 32, 64, 128, 256, 512, 1024, 4096);
+
+/// The reason bytes could not be converted to `Pod` values.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PodCastError {
+    /// The number of bytes is not a multiple of the size of the element type.
+    SizeMismatch { len: usize, elem_size: usize },
+    /// The bytes are not aligned for the element type.
+    Misaligned { addr: usize, align: usize },
+}
+
+impl fmt::Display for PodCastError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PodCastError::SizeMismatch { len, elem_size } => {
+                write!(f, "{} bytes do not hold a whole number of {}-byte elements", len, elem_size)
+            }
+            PodCastError::Misaligned { addr, align } => {
+                write!(f, "address {:#x} is not aligned to {} bytes", addr, align)
+            }
+        }
+    }
+}
+
+impl Error for PodCastError {
+    fn description(&self) -> &str {
+        match *self {
+            PodCastError::SizeMismatch { .. } => "size is not a multiple of the element size",
+            PodCastError::Misaligned { .. } => "bytes are not sufficiently aligned",
+        }
+    }
+}
+
+/// The number of `T`s in `len` bytes.
+fn pod_count<T: Pod>(len: usize) -> Result<usize, PodCastError> {
+    let elem_size = mem::size_of::<T>();
+    if elem_size == 0 || len % elem_size != 0 {
+        return Err(PodCastError::SizeMismatch {
+            len: len,
+            elem_size: elem_size,
+        });
+    }
+    Ok(len / elem_size)
+}
+
+/// View `bytes` as a slice of `T`s without copying.
+///
+/// This fails if `bytes` is not aligned for `T` or does not hold a whole number of `T`s. Use
+/// `AVec::from_bytes_in` for bytes of arbitrary alignment.
+pub fn try_cast_slice<T: Pod>(bytes: &[u8]) -> Result<&[T], PodCastError> {
+    let n = pod_count::<T>(bytes.len())?;
+    let addr = bytes.as_ptr() as usize;
+    let align = mem::align_of::<T>();
+    if addr % align != 0 {
+        return Err(PodCastError::Misaligned {
+            addr: addr,
+            align: align,
+        });
+    }
+    Ok(unsafe { ::std::slice::from_raw_parts(bytes.as_ptr() as *const T, n) })
+}
+
 /// A `Vec`-like structure parametric on an `Alloc`. The overall structure here borrows heavily
 /// from the smallvec crate, though our goals here are of course different. One could easily fork
 /// smallvec to achieve a similar aim, but we want to focus on allocation in this setting and
@@ -31,6 +217,9 @@ use std::ptr;
 pub struct AVec<T, A: Alloc> {
     buf: RawVec<T, A>,
     len: usize,
+    policy: CapacityPolicy,
+    /// The allocator's `Uncommit` hook, for `CapacityPolicy::Decommit`.
+    uncommit: Option<unsafe fn(&A, *mut u8, usize)>,
 }
 
 impl<T, A: Alloc> VecLike<T> for AVec<T, A> {
@@ -60,6 +249,33 @@ where
         res.reserve(cap);
         res
     }
+
+    /// Move the elements from index `at` onwards into a new `AVec`, leaving the first `at` in
+    /// `self`, then apply the capacity policy to `self` as `truncate` would.
+    ///
+    /// The new `AVec` allocates from a fresh allocator of the same type (e.g. a new handle for
+    /// `DynamicAlloc`), as `new` does, and inherits the capacity policy of `self`. This lets the
+    /// halves of a divide-and-conquer computation be handed to different threads, each with its
+    /// own handle.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `at > self.len()`.
+    pub fn split_off(&mut self, at: usize) -> Self {
+        alloc_assert!(at <= self.len, "split_off index {} is out of bounds", at);
+        let n = self.len - at;
+        let mut other = Self::with_capacity(n);
+        other.policy = self.policy;
+        other.uncommit = self.uncommit;
+        unsafe {
+            ptr::copy_nonoverlapping(self.get_raw(at), other.buf.ptr(), n);
+        }
+        other.len = n;
+        // The elements past `at` now belong to `other`, so they must not be dropped here.
+        self.len = at;
+        self.truncate(at);
+        other
+    }
 }
 
 impl<T2, T1: PartialEq<T2>, A1: Alloc, A2: Alloc> PartialEq<AVec<T2, A2>> for AVec<T1, A1> {
@@ -95,6 +311,8 @@ impl<T> Default for AVec<T, DynamicAlloc> {
         AVec {
             buf: RawVec::new_in(rust_alloc::new_owned_handle()),
             len: 0,
+            policy: DynamicAlloc::default_capacity_policy(),
+            uncommit: Some(<DynamicAlloc as Uncommit>::uncommit),
         }
     }
 }
@@ -104,6 +322,19 @@ impl<T> Default for AVec<T, SharedAlloc> {
         AVec {
             buf: RawVec::new_in(SharedAlloc),
             len: 0,
+            policy: SharedAlloc::default_capacity_policy(),
+            uncommit: Some(<SharedAlloc as Uncommit>::uncommit),
+        }
+    }
+}
+
+impl<T> Default for AVec<T, SendableAlloc> {
+    fn default() -> AVec<T, SendableAlloc> {
+        AVec {
+            buf: RawVec::new_in(SendableAlloc::new()),
+            len: 0,
+            policy: SendableAlloc::default_capacity_policy(),
+            uncommit: Some(<SendableAlloc as Uncommit>::uncommit),
         }
     }
 }
@@ -113,6 +344,8 @@ impl<T> Default for AVec<T, Heap> {
         AVec {
             buf: RawVec::new(),
             len: 0,
+            policy: Heap::default_capacity_policy(),
+            uncommit: Some(<Heap as Uncommit>::uncommit),
         }
     }
 }
@@ -128,6 +361,19 @@ impl<T, A: Alloc> Drop for AVec<T, A> {
 }
 
 impl<T, A: Alloc> AVec<T, A> {
+    /// Create an empty `AVec` that allocates from `alloc`, with the `Keep` capacity policy.
+    ///
+    /// The `AVec` has no `Uncommit` hook, so `CapacityPolicy::Decommit` keeps its pages; use
+    /// `new_uncommitting_in` for allocators that implement `Uncommit`.
+    pub fn new_in(alloc: A) -> Self {
+        AVec {
+            buf: RawVec::new_in(alloc),
+            len: 0,
+            policy: CapacityPolicy::Keep,
+            uncommit: None,
+        }
+    }
+
     pub fn pop(&mut self) -> Option<T> {
         if self.len == 0 {
             None
@@ -137,10 +383,132 @@ impl<T, A: Alloc> AVec<T, A> {
         }
     }
 
+    pub fn capacity(&self) -> usize {
+        self.buf.cap()
+    }
+
+    pub fn capacity_policy(&self) -> CapacityPolicy {
+        self.policy
+    }
+
+    pub fn set_capacity_policy(&mut self, policy: CapacityPolicy) {
+        self.policy = policy;
+    }
+
+    /// Drop all elements, then apply the capacity policy.
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    /// Drop all elements past the first `len`, then apply the capacity policy.
+    pub fn truncate(&mut self, len: usize) {
+        while self.len > len {
+            self.len -= 1;
+            unsafe {
+                ptr::drop_in_place(self.buf.ptr().offset(self.len as isize));
+            }
+        }
+        match self.policy {
+            CapacityPolicy::Keep => {}
+            CapacityPolicy::ShrinkToFit => self.buf.shrink_to_fit(self.len),
+            CapacityPolicy::Decommit => unsafe { self.decommit_tail() },
+        }
+    }
+
+    /// Have the allocator uncommit every page that lies entirely within the unused part of the
+    /// buffer.
+    unsafe fn decommit_tail(&mut self) {
+        let uncommit = match self.uncommit {
+            Some(uncommit) => uncommit,
+            None => return,
+        };
+        let elt_size = mem::size_of::<T>();
+        if elt_size == 0 {
+            return;
+        }
+        let page_size = mmap::page_size();
+        let start = self.buf.ptr().offset(self.len as isize) as usize;
+        let end = self.buf.ptr().offset(self.buf.cap() as isize) as usize;
+        let start_page = (start + page_size - 1) & !(page_size - 1);
+        let end_page = end & !(page_size - 1);
+        if start_page < end_page {
+            uncommit(
+                self.buf.alloc(),
+                with_addr(self.buf.ptr() as *mut u8, start_page),
+                end_page - start_page,
+            );
+        }
+    }
+
+    /// Move all the elements of `other` to the end of `self`, leaving `other` empty. `other`'s
+    /// capacity policy is then applied to it as `clear` would.
+    ///
+    /// `other` may use a different allocator than `self`.
+    pub fn append<B: Alloc>(&mut self, other: &mut AVec<T, B>) {
+        let n = other.len;
+        self.reserve(n);
+        unsafe {
+            ptr::copy_nonoverlapping(other.buf.ptr(), self.get_raw(self.len), n);
+        }
+        self.len += n;
+        other.len = 0;
+        other.clear();
+    }
+
     pub fn reserve(&mut self, extra_bytes: usize) {
         self.buf.reserve(self.len, extra_bytes);
     }
 
+    /// Make room for at least `extra` more elements, growing the buffer as `reserve` does, but
+    /// return the allocator's error instead of aborting if it cannot be grown. The `AVec` is left
+    /// unchanged on failure.
+    pub fn try_reserve(&mut self, extra: usize) -> Result<(), AllocErr> {
+        let cap = self.buf.cap();
+        if cap.wrapping_sub(self.len) >= extra {
+            return Ok(());
+        }
+        let new_cap = match self.len.checked_add(extra) {
+            Some(needed) => cmp::max(needed, cap * 2),
+            None => return Err(capacity_overflow()),
+        };
+        let new_layout = match Layout::array::<T>(new_cap) {
+            Some(l) => l,
+            None => return Err(capacity_overflow()),
+        };
+        unsafe {
+            let ptr = if cap == 0 {
+                self.buf.alloc_mut().alloc(new_layout)?
+            } else {
+                let old_layout = Layout::array::<T>(cap).unwrap();
+                let old_ptr = self.buf.ptr() as *mut u8;
+                self.buf.alloc_mut().realloc(old_ptr, old_layout, new_layout)?
+            };
+            // The old buffer has been freed or moved by the allocator, so it must not be dropped;
+            // only its allocator is carried over.
+            let alloc = ptr::read(self.buf.alloc());
+            ptr::write(
+                &mut self.buf,
+                RawVec::from_raw_parts_in(ptr as *mut T, new_cap, alloc),
+            );
+        }
+        Ok(())
+    }
+
+    /// Append `val`, or hand it back along with the allocator's error if the buffer is full and
+    /// cannot be grown.
+    pub fn try_push(&mut self, val: T) -> Result<(), (T, AllocErr)> {
+        if self.len == self.buf.cap() {
+            if let Err(e) = self.try_reserve(1) {
+                return Err((val, e));
+            }
+        }
+        unsafe {
+            ptr::write(self.get_raw(self.len), val);
+        }
+        self.len += 1;
+        Ok(())
+    }
+
     pub fn resize(&mut self, new_cap: usize) {
         if new_cap == self.len {
             return;
@@ -169,6 +537,63 @@ impl<T, A: Alloc> AVec<T, A> {
     }
 }
 
+#[cold]
+fn capacity_overflow() -> AllocErr {
+    AllocErr::Unsupported {
+        details: "capacity overflow",
+    }
+}
+
+impl<T, A: Alloc + Uncommit> AVec<T, A> {
+    /// Create an empty `AVec` that allocates from `alloc`, with the `Keep` capacity policy, and
+    /// whose `CapacityPolicy::Decommit` uncommits through `alloc`'s `Uncommit` implementation.
+    pub fn new_uncommitting_in(alloc: A) -> Self {
+        let mut res = Self::new_in(alloc);
+        res.uncommit = Some(<A as Uncommit>::uncommit);
+        res
+    }
+}
+
+impl<T: Pod, A: Alloc> AVec<T, A> {
+    /// Build an `AVec` in `alloc` from the bytes of its elements.
+    ///
+    /// The bytes are copied, so they need not be aligned for `T`; this only fails if they do not
+    /// hold a whole number of `T`s.
+    pub fn from_bytes_in(bytes: &[u8], alloc: A) -> Result<Self, PodCastError> {
+        let n = pod_count::<T>(bytes.len())?;
+        let buf = RawVec::with_capacity_in(n, alloc);
+        unsafe {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), buf.ptr() as *mut u8, bytes.len());
+        }
+        Ok(AVec {
+            buf: buf,
+            len: n,
+            policy: CapacityPolicy::Keep,
+            uncommit: None,
+        })
+    }
+
+    /// View the elements as bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            ::std::slice::from_raw_parts(
+                self.buf.ptr() as *const u8,
+                self.len * mem::size_of::<T>(),
+            )
+        }
+    }
+
+    /// View the elements as mutable bytes, e.g. to read data into them.
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            ::std::slice::from_raw_parts_mut(
+                self.buf.ptr() as *mut u8,
+                self.len * mem::size_of::<T>(),
+            )
+        }
+    }
+}
+
 macro_rules! forward_slice_index_impl {
     ($input:ty, $output:ty) => {
 
@@ -185,12 +610,31 @@ macro_rules! forward_slice_index_impl {
             }
         }
     };
+    (inline $input:ty) => {
+
+        impl<B: Array, A: Alloc> ops::Index<$input> for InlineVec<B, A> {
+            type Output = [B::Item];
+            fn index(&self, ix: $input) -> &[B::Item] {
+                (&**self).index(ix)
+            }
+        }
+
+        impl<B: Array, A: Alloc> ops::IndexMut<$input> for InlineVec<B, A> {
+            fn index_mut(&mut self, ix: $input) -> &mut [B::Item] {
+                (&mut**self).index_mut(ix)
+            }
+        }
+    };
 }
 
 forward_slice_index_impl!(ops::Range<usize>, [T]);
 forward_slice_index_impl!(ops::RangeFrom<usize>, [T]);
 forward_slice_index_impl!(ops::RangeTo<usize>, [T]);
 forward_slice_index_impl!(ops::RangeFull, [T]);
+forward_slice_index_impl!(inline ops::Range<usize>);
+forward_slice_index_impl!(inline ops::RangeFrom<usize>);
+forward_slice_index_impl!(inline ops::RangeTo<usize>);
+forward_slice_index_impl!(inline ops::RangeFull);
 
 impl<T, A: Alloc> ops::Index<usize> for AVec<T, A> {
     type Output = T;
@@ -236,6 +680,653 @@ impl<T, A: Alloc> ops::DerefMut for AVec<T, A> {
     }
 }
 
+/// A header of type `H` followed by a growable array of `T`s, in a single allocation from an
+/// `Alloc`. See the module documentation.
+///
+/// The elements start at the first multiple of `T`'s alignment after the header, and the block
+/// is aligned for both types. Growing the array reallocates the whole block, so references to
+/// the header do not survive a `push` or `reserve`.
+pub struct AVecWithHeader<H, T, A: Alloc> {
+    /// The start of the block, where the header lives.
+    ptr: *mut H,
+    len: usize,
+    cap: usize,
+    alloc: A,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<H: Send, T: Send, A: Alloc + Send> Send for AVecWithHeader<H, T, A> {}
+unsafe impl<H: Sync, T: Sync, A: Alloc + Sync> Sync for AVecWithHeader<H, T, A> {}
+
+impl<H, T, A: Alloc> AVecWithHeader<H, T, A> {
+    /// Allocate a block from `alloc` holding `header` and no elements.
+    pub fn new_in(header: H, alloc: A) -> Self {
+        Self::with_capacity_in(header, 0, alloc)
+    }
+
+    /// Allocate a block from `alloc` holding `header` and room for `cap` elements.
+    pub fn with_capacity_in(header: H, cap: usize, mut alloc: A) -> Self {
+        let cap = if mem::size_of::<T>() == 0 { !0 } else { cap };
+        let layout = Self::layout(cap);
+        unsafe {
+            let ptr = match alloc.alloc(layout) {
+                Ok(ptr) => ptr as *mut H,
+                Err(e) => alloc.oom(e),
+            };
+            ptr::write(ptr, header);
+            AVecWithHeader {
+                ptr: ptr,
+                len: 0,
+                cap: cap,
+                alloc: alloc,
+                _marker: PhantomData,
+            }
+        }
+    }
+
+    /// The offset of the first element from the start of the block.
+    fn offset() -> usize {
+        let align = mem::align_of::<T>();
+        (mem::size_of::<H>() + align - 1) & !(align - 1)
+    }
+
+    /// The layout of a block with room for `cap` elements.
+    fn layout(cap: usize) -> Layout {
+        let elems = if mem::size_of::<T>() == 0 { 0 } else { cap };
+        let size = mem::size_of::<T>()
+            .checked_mul(elems)
+            .and_then(|bytes| bytes.checked_add(Self::offset()))
+            .expect("capacity overflow");
+        let align = cmp::max(mem::align_of::<H>(), mem::align_of::<T>());
+        // Zero-sized blocks cannot be allocated, so always ask for at least one byte.
+        Layout::from_size_align(cmp::max(size, 1), align).unwrap()
+    }
+
+    /// A pointer to the first element.
+    fn elems(&self) -> *mut T {
+        unsafe { (self.ptr as *mut u8).offset(Self::offset() as isize) as *mut T }
+    }
+
+    pub fn header(&self) -> &H {
+        unsafe { &*self.ptr }
+    }
+
+    pub fn header_mut(&mut self) -> &mut H {
+        unsafe { &mut *self.ptr }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// The size of the block holding the header and elements, in bytes.
+    pub fn block_size(&self) -> usize {
+        Self::layout(self.cap).size()
+    }
+
+    /// Make room for at least `extra` more elements, reallocating the block if necessary.
+    pub fn reserve(&mut self, extra: usize) {
+        let needed = self.len.checked_add(extra).expect("capacity overflow");
+        if needed > self.cap {
+            let new_cap = cmp::max(needed, cmp::max(self.cap * 2, 4));
+            self.grow_to(new_cap);
+        }
+    }
+
+    fn grow_to(&mut self, new_cap: usize) {
+        let old = Self::layout(self.cap);
+        let new = Self::layout(new_cap);
+        unsafe {
+            self.ptr = match self.alloc.realloc(self.ptr as *mut u8, old, new) {
+                Ok(ptr) => ptr as *mut H,
+                Err(e) => self.alloc.oom(e),
+            };
+        }
+        self.cap = new_cap;
+    }
+
+    pub fn push(&mut self, val: T) {
+        if self.len == self.cap {
+            self.reserve(1);
+        }
+        unsafe {
+            ptr::write(self.elems().offset(self.len as isize), val);
+        }
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            None
+        } else {
+            self.len -= 1;
+            unsafe { Some(ptr::read(self.elems().offset(self.len as isize))) }
+        }
+    }
+
+    /// Drop all elements past the first `len`. The capacity is unchanged.
+    pub fn truncate(&mut self, len: usize) {
+        while self.len > len {
+            self.len -= 1;
+            unsafe {
+                ptr::drop_in_place(self.elems().offset(self.len as isize));
+            }
+        }
+    }
+
+    /// Drop all elements, keeping the header.
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+}
+
+impl<H, T: Clone, A: Alloc> AVecWithHeader<H, T, A> {
+    /// Allocate a block from `alloc` holding `header` and a copy of `elems`, with no spare
+    /// capacity.
+    pub fn from_slice_in(header: H, elems: &[T], alloc: A) -> Self {
+        let mut res = Self::with_capacity_in(header, elems.len(), alloc);
+        for e in elems {
+            res.push(e.clone());
+        }
+        res
+    }
+}
+
+impl<H, T, A: Alloc> Drop for AVecWithHeader<H, T, A> {
+    fn drop(&mut self) {
+        self.clear();
+        unsafe {
+            ptr::drop_in_place(self.ptr);
+            let layout = Self::layout(self.cap);
+            self.alloc.dealloc(self.ptr as *mut u8, layout);
+        }
+    }
+}
+
+impl<H, T, A: Alloc> ops::Deref for AVecWithHeader<H, T, A> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        unsafe { ::std::slice::from_raw_parts(self.elems(), self.len) }
+    }
+}
+
+impl<H, T, A: Alloc> ops::DerefMut for AVecWithHeader<H, T, A> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { ::std::slice::from_raw_parts_mut(self.elems(), self.len) }
+    }
+}
+
+impl<H, T, A: Alloc> Extend<T> for AVecWithHeader<H, T, A> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iterable: I) {
+        let iter = iterable.into_iter();
+        self.reserve(iter.size_hint().0);
+        for item in iter {
+            self.push(item);
+        }
+    }
+}
+
+impl<H: fmt::Debug, T: fmt::Debug, A: Alloc> fmt::Debug for AVecWithHeader<H, T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AVecWithHeader")
+            .field("header", self.header())
+            .field("elems", &&**self)
+            .finish()
+    }
+}
+
+/// A gap buffer: a sequence with a cursor, which is cheap to insert into and delete from at the
+/// cursor, however far the cursor is from the end.
+///
+/// The elements before the cursor are stored at the start of the buffer and those after it at
+/// the end, with the unused capacity (the gap) in between. Moving the cursor moves the elements
+/// between its old and new positions across the gap. When the gap is used up, the buffer is
+/// reallocated to twice its size and the elements after the cursor are moved to the new end.
+///
+/// This is the data structure behind many text editors. Its allocation profile differs from an
+/// `AVec`'s, which it was added to cover: a document that keeps growing in the middle causes a
+/// series of reallocations of medium and large buffers, each followed by a move of part of the
+/// contents.
+pub struct AGapBuffer<T, A: Alloc> {
+    buf: RawVec<T, A>,
+    /// The number of elements before the gap, which is the position of the cursor.
+    gap_start: usize,
+    /// The number of elements after the gap.
+    tail_len: usize,
+}
+
+impl<T, A: Alloc> AGapBuffer<T, A> {
+    /// Create an empty `AGapBuffer` that allocates from `alloc`.
+    pub fn new_in(alloc: A) -> Self {
+        AGapBuffer {
+            buf: RawVec::new_in(alloc),
+            gap_start: 0,
+            tail_len: 0,
+        }
+    }
+
+    /// Create an empty `AGapBuffer` with room for `cap` elements that allocates from `alloc`.
+    pub fn with_capacity_in(cap: usize, alloc: A) -> Self {
+        AGapBuffer {
+            buf: RawVec::with_capacity_in(cap, alloc),
+            gap_start: 0,
+            tail_len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.gap_start + self.tail_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.cap()
+    }
+
+    /// The position of the cursor: the number of elements before it.
+    pub fn cursor(&self) -> usize {
+        self.gap_start
+    }
+
+    /// The index in the buffer of the first element after the gap.
+    fn gap_end(&self) -> usize {
+        self.buf.cap() - self.tail_len
+    }
+
+    unsafe fn get_raw(&self, ix: usize) -> *mut T {
+        self.buf.ptr().offset(ix as isize)
+    }
+
+    /// Move the cursor to `pos`, so that `pos` elements are before it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pos > self.len()`.
+    pub fn set_cursor(&mut self, pos: usize) {
+        alloc_assert!(pos <= self.len(), "cursor {} is out of bounds", pos);
+        let gap_end = self.gap_end();
+        unsafe {
+            if pos < self.gap_start {
+                let n = self.gap_start - pos;
+                ptr::copy(self.get_raw(pos), self.get_raw(gap_end - n), n);
+                self.tail_len += n;
+            } else {
+                let n = pos - self.gap_start;
+                ptr::copy(self.get_raw(gap_end), self.get_raw(self.gap_start), n);
+                self.tail_len -= n;
+            }
+        }
+        self.gap_start = pos;
+    }
+
+    /// Make room for at least `extra` more elements.
+    pub fn reserve(&mut self, extra: usize) {
+        let old_cap = self.buf.cap();
+        if old_cap - self.len() >= extra {
+            return;
+        }
+        // The allocator preserves the whole old buffer, so the elements after the gap are still
+        // at the end of the old capacity, and are moved to the end of the new one.
+        self.buf.reserve(old_cap, self.len() + extra - old_cap);
+        let new_cap = self.buf.cap();
+        unsafe {
+            ptr::copy(
+                self.get_raw(old_cap - self.tail_len),
+                self.get_raw(new_cap - self.tail_len),
+                self.tail_len,
+            );
+        }
+    }
+
+    /// Insert `val` at the cursor, and move the cursor past it.
+    pub fn insert(&mut self, val: T) {
+        if self.gap_start == self.gap_end() {
+            self.reserve(1);
+        }
+        unsafe {
+            ptr::write(self.get_raw(self.gap_start), val);
+        }
+        self.gap_start += 1;
+    }
+
+    /// Insert `val` so that it is at index `pos`, leaving the cursor after it.
+    pub fn insert_at(&mut self, pos: usize, val: T) {
+        self.set_cursor(pos);
+        self.insert(val);
+    }
+
+    /// Remove and return the element before the cursor, if any, like a backspace.
+    pub fn delete_before(&mut self) -> Option<T> {
+        if self.gap_start == 0 {
+            return None;
+        }
+        self.gap_start -= 1;
+        unsafe { Some(ptr::read(self.get_raw(self.gap_start))) }
+    }
+
+    /// Remove and return the element after the cursor, if any.
+    pub fn delete_after(&mut self) -> Option<T> {
+        if self.tail_len == 0 {
+            return None;
+        }
+        let gap_end = self.gap_end();
+        self.tail_len -= 1;
+        unsafe { Some(ptr::read(self.get_raw(gap_end))) }
+    }
+
+    /// Remove and return the element at index `pos`, leaving the cursor where it was.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pos >= self.len()`.
+    pub fn remove(&mut self, pos: usize) -> T {
+        alloc_assert!(pos < self.len(), "index {} is out of bounds", pos);
+        self.set_cursor(pos);
+        self.delete_after().unwrap()
+    }
+
+    pub fn get(&self, ix: usize) -> Option<&T> {
+        if ix >= self.len() {
+            return None;
+        }
+        let raw = if ix < self.gap_start {
+            ix
+        } else {
+            ix + self.gap_end() - self.gap_start
+        };
+        unsafe { Some(&*self.get_raw(raw)) }
+    }
+
+    /// The elements before and after the cursor.
+    pub fn as_slices(&self) -> (&[T], &[T]) {
+        unsafe {
+            (
+                ::std::slice::from_raw_parts(self.buf.ptr(), self.gap_start),
+                ::std::slice::from_raw_parts(self.get_raw(self.gap_end()), self.tail_len),
+            )
+        }
+    }
+
+    pub fn iter(&self) -> ::std::iter::Chain<::std::slice::Iter<T>, ::std::slice::Iter<T>> {
+        let (before, after) = self.as_slices();
+        before.iter().chain(after.iter())
+    }
+
+    /// Drop all elements. The capacity is unchanged.
+    pub fn clear(&mut self) {
+        while self.delete_before().is_some() {}
+        while self.delete_after().is_some() {}
+    }
+}
+
+impl<T, A: Alloc> Drop for AGapBuffer<T, A> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<T, A: Alloc> ops::Index<usize> for AGapBuffer<T, A> {
+    type Output = T;
+    fn index(&self, ix: usize) -> &T {
+        self.get(ix).expect("index out of bounds")
+    }
+}
+
+impl<T, A: Alloc> Extend<T> for AGapBuffer<T, A> {
+    /// Insert the items at the cursor, in order, leaving the cursor after them.
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iterable: I) {
+        let iter = iterable.into_iter();
+        self.reserve(iter.size_hint().0);
+        for item in iter {
+            self.insert(item);
+        }
+    }
+}
+
+impl<T: fmt::Debug, A: Alloc> fmt::Debug for AGapBuffer<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// A vector that stores up to `B::size()` elements inline and spills to a buffer allocated from
+/// `A` beyond that. `B` is an array type, such as `[u32; 8]`, whose length is the inline capacity
+/// and whose element type is the element type of the vector.
+///
+/// Once spilled, an `InlineVec` stays on the heap until it is dropped, growing like an `AVec`.
+/// The allocator is kept from creation, so a vector that never spills never allocates, but a
+/// `DynamicAlloc` handle is still created for each one.
+pub struct InlineVec<B: Array, A: Alloc> {
+    len: usize,
+    data: InlineData<B, A>,
+}
+
+enum InlineData<B: Array, A: Alloc> {
+    /// The first `len` elements of the array are initialized.
+    Inline(ManuallyDrop<B>, A),
+    Spilled(RawVec<B::Item, A>),
+}
+
+impl<B: Array, A: Alloc> InlineVec<B, A> {
+    /// Create an empty `InlineVec` that spills to memory from `alloc`.
+    pub fn new_in(alloc: A) -> Self {
+        InlineVec {
+            len: 0,
+            data: InlineData::Inline(ManuallyDrop::new(unsafe { mem::uninitialized() }), alloc),
+        }
+    }
+
+    /// The number of elements that fit without spilling.
+    pub fn inline_size() -> usize {
+        B::size()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        match self.data {
+            InlineData::Inline(..) => B::size(),
+            InlineData::Spilled(ref buf) => buf.cap(),
+        }
+    }
+
+    /// Whether the elements are in a buffer from the allocator rather than inline.
+    pub fn spilled(&self) -> bool {
+        match self.data {
+            InlineData::Inline(..) => false,
+            InlineData::Spilled(_) => true,
+        }
+    }
+
+    fn as_ptr(&self) -> *mut B::Item {
+        match self.data {
+            InlineData::Inline(ref arr, _) => arr.ptr() as *mut B::Item,
+            InlineData::Spilled(ref buf) => buf.ptr(),
+        }
+    }
+
+    unsafe fn get_raw(&self, ix: usize) -> *mut B::Item {
+        self.as_ptr().offset(ix as isize)
+    }
+
+    /// Move the elements to a buffer with room for `cap` elements. `self` must not have spilled.
+    fn spill(&mut self, cap: usize) {
+        let buf = match self.data {
+            InlineData::Inline(ref arr, ref alloc) => unsafe {
+                // The allocator moves into the buffer; the old variant is overwritten below
+                // without being dropped.
+                let buf = RawVec::with_capacity_in(cap, ptr::read(alloc));
+                ptr::copy_nonoverlapping(arr.ptr(), buf.ptr(), self.len);
+                buf
+            },
+            InlineData::Spilled(_) => unreachable!(),
+        };
+        unsafe { ptr::write(&mut self.data, InlineData::Spilled(buf)) };
+    }
+
+    /// Make room for at least `extra` more elements, spilling if they do not fit inline.
+    pub fn reserve(&mut self, extra: usize) {
+        if self.capacity() - self.len >= extra {
+            return;
+        }
+        if let InlineData::Spilled(ref mut buf) = self.data {
+            buf.reserve(self.len, extra);
+            return;
+        }
+        let cap = cmp::max(self.len + extra, 2 * B::size());
+        self.spill(cap);
+    }
+
+    pub fn push(&mut self, val: B::Item) {
+        if self.len == self.capacity() {
+            if !self.spilled() {
+                let cap = cmp::max(2 * B::size(), 4);
+                self.spill(cap);
+            } else if let InlineData::Spilled(ref mut buf) = self.data {
+                buf.double();
+            }
+        }
+        unsafe { ptr::write(self.get_raw(self.len), val) };
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<B::Item> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        unsafe { Some(ptr::read(self.get_raw(self.len))) }
+    }
+
+    /// Insert `val` at index `ix`, shifting the elements after it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ix > self.len()`.
+    pub fn insert(&mut self, ix: usize, val: B::Item) {
+        alloc_assert!(ix <= self.len, "insert index {} is out of bounds", ix);
+        self.reserve(1);
+        unsafe {
+            ptr::copy(self.get_raw(ix), self.get_raw(ix + 1), self.len - ix);
+            ptr::write(self.get_raw(ix), val);
+        }
+        self.len += 1;
+    }
+
+    /// Remove and return the element at index `ix`, shifting the elements after it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ix >= self.len()`.
+    pub fn remove(&mut self, ix: usize) -> B::Item {
+        alloc_assert!(ix < self.len, "remove index {} is out of bounds", ix);
+        self.len -= 1;
+        unsafe {
+            let val = ptr::read(self.get_raw(ix));
+            ptr::copy(self.get_raw(ix + 1), self.get_raw(ix), self.len - ix);
+            val
+        }
+    }
+
+    /// Drop the elements from index `len` onwards. The capacity is unchanged.
+    pub fn truncate(&mut self, len: usize) {
+        while self.len > len {
+            self.len -= 1;
+            unsafe { ptr::drop_in_place(self.get_raw(self.len)) };
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+}
+
+impl<B: Array> Default for InlineVec<B, DynamicAlloc> {
+    fn default() -> Self {
+        Self::new_in(rust_alloc::new_owned_handle())
+    }
+}
+
+impl<B: Array> Default for InlineVec<B, SharedAlloc> {
+    fn default() -> Self {
+        Self::new_in(SharedAlloc)
+    }
+}
+
+impl<B: Array> Default for InlineVec<B, Heap> {
+    fn default() -> Self {
+        Self::new_in(Heap)
+    }
+}
+
+impl<B: Array, A: Alloc> Drop for InlineVec<B, A> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<B: Array, A: Alloc> VecLike<B::Item> for InlineVec<B, A> {
+    #[inline]
+    fn push(&mut self, val: B::Item) {
+        InlineVec::push(self, val);
+    }
+}
+
+impl<B: Array, A: Alloc> ops::Index<usize> for InlineVec<B, A> {
+    type Output = B::Item;
+    fn index(&self, ix: usize) -> &B::Item {
+        alloc_assert!(ix < self.len);
+        unsafe { &*self.get_raw(ix) }
+    }
+}
+
+impl<B: Array, A: Alloc> ops::IndexMut<usize> for InlineVec<B, A> {
+    fn index_mut(&mut self, ix: usize) -> &mut B::Item {
+        alloc_assert!(ix < self.len);
+        unsafe { &mut *self.get_raw(ix) }
+    }
+}
+
+impl<B: Array, A: Alloc> ops::Deref for InlineVec<B, A> {
+    type Target = [B::Item];
+    fn deref(&self) -> &[B::Item] {
+        unsafe { ::std::slice::from_raw_parts(self.as_ptr(), self.len) }
+    }
+}
+
+impl<B: Array, A: Alloc> ops::DerefMut for InlineVec<B, A> {
+    fn deref_mut(&mut self) -> &mut [B::Item] {
+        unsafe { ::std::slice::from_raw_parts_mut(self.as_ptr(), self.len) }
+    }
+}
+
+impl<B: Array, A: Alloc> Extend<B::Item> for InlineVec<B, A> {
+    fn extend<I: IntoIterator<Item = B::Item>>(&mut self, iterable: I) {
+        let iter = iterable.into_iter();
+        self.reserve(iter.size_hint().0);
+        for item in iter {
+            self.push(item);
+        }
+    }
+}
+
+impl<B: Array, A: Alloc> fmt::Debug for InlineVec<B, A>
+where
+    B::Item: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -285,6 +1376,411 @@ mod tests {
         alloc_assert_eq!(&*rv, &expect[..]);
     }
 
+    #[test]
+    fn test_capacity_policies() {
+        let _ = env_logger::init();
+        let fill = |policy| {
+            let mut rv = RVec::with_capacity(1 << 16);
+            rv.set_capacity_policy(policy);
+            rv.extend(0..(1 << 16));
+            rv
+        };
+
+        let mut rv = fill(CapacityPolicy::Keep);
+        rv.clear();
+        alloc_assert_eq!(rv.len(), 0);
+        alloc_assert!(rv.capacity() >= 1 << 16);
+
+        let mut rv = fill(CapacityPolicy::ShrinkToFit);
+        rv.truncate(10);
+        alloc_assert_eq!(rv.capacity(), 10);
+        alloc_assert_eq!(&*rv, &(0..10).collect::<Vec<_>>()[..]);
+
+        let mut rv = fill(CapacityPolicy::Decommit);
+        rv.truncate(10);
+        alloc_assert!(rv.capacity() >= 1 << 16);
+        alloc_assert_eq!(&*rv, &(0..10).collect::<Vec<_>>()[..]);
+        rv.extend(10..(1 << 16));
+        alloc_assert_eq!(&*rv, &(0..(1 << 16)).collect::<Vec<_>>()[..]);
+
+
+        // Without an Uncommit hook that releases them, the tail pages keep their contents.
+        let mut hv = AVec::<usize, Heap>::with_capacity(1 << 16);
+        hv.set_capacity_policy(CapacityPolicy::Decommit);
+        hv.extend(0..(1 << 16));
+        hv.truncate(10);
+        alloc_assert_eq!(unsafe { *hv.as_ptr().offset(1 << 15) }, 1 << 15);
+
+        let mut sv = AVec::<usize, SharedAlloc>::new_in(SharedAlloc);
+        sv.set_capacity_policy(CapacityPolicy::Decommit);
+        sv.extend(0..(1 << 16));
+        sv.truncate(10);
+        alloc_assert_eq!(unsafe { *sv.as_ptr().offset(1 << 15) }, 1 << 15);
+    }
+
+    #[test]
+    fn test_split_off_append() {
+        let _ = env_logger::init();
+        let mut rv = RVec::<String>::new();
+        rv.extend((0..100).map(|i| i.to_string()));
+        rv.set_capacity_policy(CapacityPolicy::ShrinkToFit);
+        let mut tail = rv.split_off(60);
+        alloc_assert_eq!(rv.capacity(), 60);
+        alloc_assert_eq!(tail.capacity_policy(), CapacityPolicy::ShrinkToFit);
+        alloc_assert_eq!(tail.len(), 40);
+        alloc_assert_eq!(tail[0], "60");
+        alloc_assert!(rv.split_off(60).is_empty());
+
+        // Vectors with different allocators can be appended to each other.
+        let mut right = AVec::<String, DynamicAlloc>::new();
+        right.append(&mut tail);
+        alloc_assert!(tail.is_empty());
+        alloc_assert_eq!(tail.capacity(), 0);
+        // Splitting at 0 moves everything into a vector with a fresh handle.
+        let mut moved = right.split_off(0);
+        alloc_assert!(right.is_empty());
+        right.append(&mut rv);
+        right.append(&mut moved);
+        alloc_assert!(rv.is_empty() && moved.is_empty());
+        alloc_assert_eq!(right.len(), 100);
+        alloc_assert!(right.iter().enumerate().all(|(i, s)| *s == i.to_string()));
+    }
+
+    #[test]
+    fn test_pod_bytes() {
+        let _ = env_logger::init();
+        let mut rv = RVec::<u32>::new();
+        rv.extend(0..100);
+        let bytes = rv.as_bytes().to_vec();
+        alloc_assert_eq!(bytes.len(), 400);
+
+        // Copying does not care about alignment.
+        let mut shifted = vec![0u8];
+        shifted.extend(bytes.iter().cloned());
+        let copy = RVec::<u32>::from_bytes_in(&shifted[1..], SharedAlloc).unwrap();
+        alloc_assert_eq!(&*copy, &*rv);
+        alloc_assert_eq!(
+            RVec::<u32>::from_bytes_in(&shifted[1..8], SharedAlloc).err(),
+            Some(PodCastError::SizeMismatch {
+                len: 7,
+                elem_size: 4,
+            })
+        );
+
+        // Viewing does.
+        alloc_assert_eq!(try_cast_slice::<u32>(rv.as_bytes()).unwrap(), &*rv);
+        match try_cast_slice::<u32>(&rv.as_bytes()[1..5]) {
+            Err(PodCastError::Misaligned { align: 4, .. }) => {}
+            other => alloc_assert!(false, "expected misalignment, got {:?}", other),
+        }
+
+        let mut rv = RVec::<u16>::from_bytes_in(&[0; 4], SharedAlloc).unwrap();
+        alloc_assert_eq!(&*rv, &[0u16, 0][..]);
+        for b in &mut rv.as_bytes_mut()[2..] {
+            *b = 0xff;
+        }
+        alloc_assert_eq!(&*rv, &[0u16, 0xffff][..]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_send_across_threads() {
+        let _ = env_logger::init();
+        let mut v = AVec::<usize, SendableAlloc>::new();
+        v.extend(0..1000);
+        let v = ::std::thread::spawn(move || {
+            v.extend(1000..2000);
+            v
+        }).join()
+            .unwrap();
+        let expect: Vec<_> = (0..2000).collect();
+        alloc_assert_eq!(&*v, &expect[..]);
+    }
+
+    #[test]
+    fn test_try_push() {
+        use rust_alloc::IsolatedHeap;
+        let _ = env_logger::init();
+        let heap = IsolatedHeap::new_default();
+        heap.set_limit(64 << 10);
+        let mut v = AVec::<usize, _>::new_in(heap.handle());
+        let mut n = 0;
+        let err = loop {
+            match v.try_push(n) {
+                Ok(()) => n += 1,
+                Err((val, e)) => {
+                    alloc_assert_eq!(val, n);
+                    break e;
+                }
+            }
+        };
+        alloc_assert!(!err.is_request_unsupported());
+        alloc_assert!(n * mem::size_of::<usize>() <= 64 << 10);
+        alloc_assert_eq!(&*v, &(0..n).collect::<Vec<_>>()[..]);
+
+        let cap = v.capacity();
+        alloc_assert!(v.try_reserve(!0).unwrap_err().is_request_unsupported());
+        alloc_assert_eq!(v.capacity(), cap);
+
+        heap.set_limit(0);
+        v.try_push(n).unwrap();
+        v.try_reserve(1 << 16).unwrap();
+        alloc_assert!(v.capacity() >= n + 1 + (1 << 16));
+        alloc_assert_eq!(&*v, &(0..n + 1).collect::<Vec<_>>()[..]);
+    }
+
+    #[test]
+    fn test_with_header() {
+        use std::rc::Rc;
+        let _ = env_logger::init();
+
+        // A header that is smaller than the alignment of the elements.
+        let mut v = AVecWithHeader::<u8, u64, SharedAlloc>::new_in(7, SharedAlloc);
+        alloc_assert_eq!(v.capacity(), 0);
+        v.extend(0..1000);
+        *v.header_mut() += 1;
+        alloc_assert_eq!(*v.header(), 8);
+        alloc_assert_eq!(&*v, &(0..1000).collect::<Vec<_>>()[..]);
+        alloc_assert_eq!(v.as_ptr() as usize % mem::align_of::<u64>(), 0);
+        alloc_assert_eq!(v.as_ptr() as usize - v.header() as *const u8 as usize, 8);
+        alloc_assert_eq!(v.pop(), Some(999));
+        v.truncate(10);
+        alloc_assert_eq!(v.len(), 10);
+
+        // The header and every element are dropped exactly once.
+        let rc = Rc::new(());
+        {
+            let mut v = AVecWithHeader::<_, _, SharedAlloc>::from_slice_in(
+                rc.clone(),
+                &[rc.clone(), rc.clone()],
+                SharedAlloc,
+            );
+            alloc_assert_eq!(v.capacity(), 2);
+            alloc_assert_eq!(v.block_size(), 3 * mem::size_of::<Rc<()>>());
+            v.push(rc.clone());
+            alloc_assert_eq!(Rc::strong_count(&rc), 5);
+            drop(v.pop());
+            alloc_assert_eq!(Rc::strong_count(&rc), 4);
+        }
+        alloc_assert_eq!(Rc::strong_count(&rc), 1);
+
+        // Zero-sized headers and elements.
+        let mut v = AVecWithHeader::<(), u32, SharedAlloc>::with_capacity_in((), 4, SharedAlloc);
+        alloc_assert_eq!(v.block_size(), 16);
+        v.push(1);
+        alloc_assert_eq!(v.as_ptr() as usize, v.header() as *const () as usize);
+        let mut v = AVecWithHeader::<u32, (), SharedAlloc>::new_in(1, SharedAlloc);
+        v.extend((0..1000).map(|_| ()));
+        alloc_assert_eq!(v.len(), 1000);
+        alloc_assert_eq!(v.block_size(), 4);
+    }
+
+    /// The header of a message in the `bench_packet_*` benchmarks.
+    struct PacketHeader {
+        _id: u64,
+        _len: u32,
+        _flags: u32,
+    }
+
+    const PACKET_PAYLOAD: usize = 256;
+
+    #[bench]
+    fn bench_packet_with_header_elf(b: &mut Bencher) {
+        let payload = [0xa5u8; PACKET_PAYLOAD];
+        b.iter(|| {
+            let header = PacketHeader {
+                _id: 1,
+                _len: PACKET_PAYLOAD as u32,
+                _flags: 0,
+            };
+            test::black_box(AVecWithHeader::<_, _, SharedAlloc>::from_slice_in(
+                header,
+                &payload[..],
+                SharedAlloc,
+            ))
+        });
+    }
+
+    /// The same messages as `bench_packet_with_header_elf`, with the header and payload in
+    /// separate allocations.
+    #[bench]
+    fn bench_packet_separate_elf(b: &mut Bencher) {
+        let payload = [0xa5u8; PACKET_PAYLOAD];
+        b.iter(|| {
+            let mut header = RVec::with_capacity(1);
+            header.push(PacketHeader {
+                _id: 1,
+                _len: PACKET_PAYLOAD as u32,
+                _flags: 0,
+            });
+            let mut body = RVec::with_capacity(PACKET_PAYLOAD);
+            body.extend(payload.iter().cloned());
+            test::black_box((header, body))
+        });
+    }
+
+    #[test]
+    fn test_gap_buffer() {
+        use std::rc::Rc;
+        let _ = env_logger::init();
+        let mut expected: Vec<usize> = Vec::new();
+        let mut g = AGapBuffer::new_in(SharedAlloc);
+        // Insert at positions all over the buffer, forcing it to grow several times with the
+        // cursor in the middle.
+        for i in 0..2000 {
+            let pos = (i * 7919) % (expected.len() + 1);
+            g.insert_at(pos, i);
+            expected.insert(pos, i);
+        }
+        alloc_assert_eq!(g.iter().cloned().collect::<Vec<_>>(), expected);
+        g.set_cursor(1000);
+        alloc_assert_eq!(g.delete_before(), Some(expected.remove(999)));
+        alloc_assert_eq!(g.delete_after(), Some(expected.remove(999)));
+        alloc_assert_eq!(g.remove(10), expected.remove(10));
+        alloc_assert_eq!(g.cursor(), 10);
+        g.extend(vec![1, 2, 3]);
+        for (i, &x) in [1, 2, 3].iter().enumerate() {
+            expected.insert(10 + i, x);
+        }
+        alloc_assert_eq!(g.len(), expected.len());
+        alloc_assert!((0..g.len()).all(|i| g[i] == expected[i]));
+        alloc_assert_eq!(g.get(g.len()), None);
+        let (before, after) = g.as_slices();
+        alloc_assert_eq!((before.len(), after.len()), (13, expected.len() - 13));
+
+        // Elements on both sides of the gap are dropped exactly once.
+        let rc = Rc::new(());
+        {
+            let mut g = AGapBuffer::with_capacity_in(2, SharedAlloc);
+            for _ in 0..10 {
+                g.insert_at(0, rc.clone());
+            }
+            g.set_cursor(5);
+            drop(g.remove(7));
+            alloc_assert_eq!(Rc::strong_count(&rc), 10);
+        }
+        alloc_assert_eq!(Rc::strong_count(&rc), 1);
+    }
+
+    #[bench]
+    fn bench_editor_gap_buffer_elf(b: &mut Bencher) {
+        bench_editor(b, rust_alloc::new_owned_handle);
+    }
+
+    #[bench]
+    fn bench_editor_gap_buffer_heap(b: &mut Bencher) {
+        bench_editor(b, || Heap);
+    }
+
+    /// Type a 64KiB document, moving the cursor to a pseudo-random position every 32 characters
+    /// and deleting one in every 8 characters typed.
+    fn bench_editor<A: Alloc, F: Fn() -> A>(b: &mut Bencher, alloc: F) {
+        b.iter(|| {
+            let mut doc = AGapBuffer::new_in(alloc());
+            let mut pos = 0usize;
+            for i in 0..(1usize << 16) {
+                if i % 32 == 0 {
+                    pos = pos.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    let len = doc.len();
+                    doc.set_cursor((pos >> 8) % (len + 1));
+                }
+                if i % 8 == 7 {
+                    doc.delete_before();
+                } else {
+                    doc.insert(i as u8);
+                }
+            }
+            test::black_box(doc.len())
+        });
+    }
+
+    #[test]
+    fn test_inline_vec() {
+        use std::rc::Rc;
+        let _ = env_logger::init();
+        let mut v = InlineVec::<[usize; 8], SharedAlloc>::default();
+        let mut expected = Vec::new();
+        for i in 0..8 {
+            v.push(i);
+            expected.push(i);
+        }
+        alloc_assert!(!v.spilled());
+        alloc_assert_eq!(v.capacity(), 8);
+        v.push(8);
+        expected.push(8);
+        alloc_assert!(v.spilled());
+        alloc_assert_eq!(&v[..], &expected[..]);
+        for i in 0..100 {
+            let ix = (i * 7919) % (expected.len() + 1);
+            v.insert(ix, i);
+            expected.insert(ix, i);
+        }
+        alloc_assert_eq!(v.remove(50), expected.remove(50));
+        alloc_assert_eq!(v.pop(), expected.pop());
+        v[3] = 1000;
+        expected[3] = 1000;
+        alloc_assert_eq!(&v[..], &expected[..]);
+
+        // Reserving past the inline capacity spills, and inserting in the middle works inline.
+        let mut v = InlineVec::<[u8; 4], Heap>::default();
+        v.extend(vec![1, 3]);
+        v.insert(1, 2);
+        alloc_assert_eq!(&v[..], &[1, 2, 3]);
+        alloc_assert!(!v.spilled());
+        v.reserve(2);
+        alloc_assert!(v.spilled() && v.capacity() >= 5);
+
+        // Elements are dropped exactly once, whether inline or spilled.
+        let rc = Rc::new(());
+        {
+            let mut inline = InlineVec::<[Rc<()>; 4], SharedAlloc>::default();
+            let mut spilled = InlineVec::<[Rc<()>; 4], SharedAlloc>::default();
+            inline.extend((0..3).map(|_| rc.clone()));
+            spilled.extend((0..10).map(|_| rc.clone()));
+            drop(spilled.remove(0));
+            spilled.truncate(5);
+            alloc_assert_eq!(Rc::strong_count(&rc), 9);
+        }
+        alloc_assert_eq!(Rc::strong_count(&rc), 1);
+    }
+
+    #[bench]
+    fn bench_small_vecs_inline_elf(b: &mut Bencher) {
+        bench_small_vecs::<InlineVec<[usize; 8], DynamicAlloc>>(b);
+    }
+
+    #[bench]
+    fn bench_small_vecs_inline_heap(b: &mut Bencher) {
+        bench_small_vecs::<InlineVec<[usize; 8], Heap>>(b);
+    }
+
+    #[bench]
+    fn bench_small_vecs_avec_elf(b: &mut Bencher) {
+        bench_small_vecs::<AVec<usize, DynamicAlloc>>(b);
+    }
+
+    #[bench]
+    fn bench_small_vecs_avec_heap(b: &mut Bencher) {
+        bench_small_vecs::<AVec<usize, Heap>>(b);
+    }
+
+    /// Build 256 vectors of between 0 and 15 elements, just under half of which outgrow 8 inline
+    /// slots.
+    fn bench_small_vecs<V: VecLike<usize> + Default>(b: &mut Bencher) {
+        b.iter(|| {
+            let vecs: Vec<V> = (0..256)
+                .map(|i| {
+                    let mut vec = V::default();
+                    for x in 0..(i * 7) % 16 {
+                        vec.push(x);
+                    }
+                    vec
+                })
+                .collect();
+            test::black_box(vecs)
+        });
+    }
+
     #[bench]
     fn bench_push_avec_elf(b: &mut Bencher) {
         bench_push::<AVec<usize, DynamicAlloc>>(b);
@@ -0,0 +1,268 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Walking the heap, for conservative garbage collectors and leak scanners.
+//!
+//! `allocations` returns an iterator over the allocated objects in all memory managed by
+//! elfmalloc: the global allocator, the heaps in `rust_alloc`, and the object-specific allocators
+//! in `frontends`. The memory is found through the table kept by the `ownership` module, which is
+//! why this module requires the `ownership` feature. Together with `general::global::lookup` and
+//! `general::global::object_start`, this is what a Boehm-style collector needs from the allocator
+//! underneath it.
+//!
+//! An object in a `Slag` is reported unless it is marked as available in the `Slag`'s bit-set.
+//! Objects that have been freed into a thread's cache, and objects that a thread has taken from
+//! its `Slag` but not yet handed out, are therefore reported as well. A collector can safely scan
+//! such objects, but it must never free an object that it did not allocate itself.
+//!
+//! # Modes
+//!
+//! With `WalkMode::StopTheWorld`, the caller guarantees that no other thread uses elfmalloc while
+//! the iterator is in use, e.g. because they have all been suspended. Large objects are then
+//! reported with the size that was requested for them, which is read from their header.
+//!
+//! With `WalkMode::BestEffort`, other threads may keep allocating and freeing. Objects allocated
+//! or freed during the walk may or may not be reported, and a `Slag` that is reused for another
+//! size class during the walk is skipped from that point on. Since large objects may be unmapped
+//! at any time, their headers are not read, and they are reported with the size of their
+//! mapping instead. In either mode, heaps whose memory is unmapped when they are dropped (such as
+//! owned `rust_alloc` heaps) must not be dropped during the walk.
+
+use super::alloc_type::AllocType;
+use super::general::{global, AllocationInfo, OwningHeap};
+use super::ownership::{self, Granule, GRANULE_SHIFT};
+use super::slag::{Metadata, Slag};
+
+/// How much the caller of `allocations` guarantees about other threads.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WalkMode {
+    /// No other thread uses elfmalloc during the walk.
+    StopTheWorld,
+    /// Other threads may use elfmalloc during the walk, and the results are only approximate.
+    BestEffort,
+}
+
+/// Create an iterator over the allocated objects in elfmalloc's memory, yielding the start of
+/// each object along with information about it.
+///
+/// This is unsafe because it reads elfmalloc's metadata, and the caller must uphold the
+/// guarantees of `mode`.
+pub unsafe fn allocations(mode: WalkMode) -> Allocations {
+    allocations_in(mode, 0, !0)
+}
+
+/// Like `allocations`, but only yield the objects that start in `[base, base + size)`.
+pub unsafe fn allocations_in(mode: WalkMode, base: usize, size: usize) -> Allocations {
+    Allocations {
+        mode: mode,
+        next: base,
+        start: base,
+        end: base.saturating_add(size),
+        slag: None,
+    }
+}
+
+/// An iterator over the allocated objects in elfmalloc's memory. See `allocations`.
+pub struct Allocations {
+    mode: WalkMode,
+    /// The lowest address that has not been walked yet.
+    next: usize,
+    /// Only objects starting in `[start, end)` are yielded.
+    start: usize,
+    end: usize,
+    /// The `Slag` being walked, if any.
+    slag: Option<SlagCursor>,
+}
+
+struct SlagCursor {
+    slag: *mut Slag,
+    meta: *const Metadata,
+    /// The index of the next object to look at.
+    index: usize,
+    owning_heap: OwningHeap,
+}
+
+impl Allocations {
+    /// Return the next allocated object in the current `Slag`, if there is one.
+    unsafe fn next_in_slag(&mut self) -> Option<(*mut u8, AllocationInfo)> {
+        let best_effort = self.mode == WalkMode::BestEffort;
+        let cursor = match self.slag {
+            Some(ref mut cursor) => cursor,
+            None => return None,
+        };
+        let meta = &*cursor.meta;
+        let objects = (cursor.slag as *mut u8).offset(meta.objects_offset);
+        while cursor.index < meta.n_objects {
+            let obj = objects.offset((cursor.index * meta.object_size) as isize);
+            cursor.index += 1;
+            if best_effort &&
+                (*cursor.slag).try_metadata().map(|m| m as *const Metadata) != Some(cursor.meta)
+            {
+                // The Slag has been reused for another size class.
+                break;
+            }
+            if !(*cursor.slag).is_available(obj, meta) {
+                return Some((
+                    obj,
+                    AllocationInfo {
+                        size_class: Some(meta.object_size),
+                        usable_size: meta.object_size,
+                        owning_heap: cursor.owning_heap,
+                    },
+                ));
+            }
+        }
+        None
+    }
+
+    /// Start walking the `Slag` containing `addr`, whose type and size are `ty` and `slag_size`.
+    unsafe fn start_slag(&mut self, addr: usize, ty: AllocType, slag_size: usize) {
+        let slag = Slag::find(addr as *mut u8, slag_size);
+        self.next = slag as usize + slag_size;
+        // The Slag may never have been initialized, or have been used for the other type.
+        self.slag = match (*slag).try_metadata() {
+            Some(meta) if meta.ty == ty => Some(SlagCursor {
+                slag: slag,
+                meta: meta,
+                index: 0,
+                owning_heap: if (*slag).is_owned() {
+                    OwningHeap::Thread
+                } else {
+                    OwningHeap::Shared
+                },
+            }),
+            _ => None,
+        };
+    }
+
+    /// Walk the large object whose mapping contains the granule at `addr`, which is the `index`th
+    /// granule of the mapping.
+    unsafe fn large(&mut self, addr: usize, index: usize) -> Option<(*mut u8, AllocationInfo)> {
+        let granule = 1 << GRANULE_SHIFT;
+        let base = addr - index * granule;
+        let mut end = addr + granule;
+        let mut index = index + 1;
+        while ownership::granule(end as *mut u8) == Some(Granule::Large { index: index }) {
+            end += granule;
+            index += 1;
+        }
+        self.next = end;
+        let start = (base + global::LARGE_OBJECT_OFFSET) as *mut u8;
+        match self.mode {
+            WalkMode::StopTheWorld => global::lookup(start).map(|info| (start, info)),
+            WalkMode::BestEffort => Some((
+                start,
+                AllocationInfo {
+                    size_class: None,
+                    usable_size: end - start as usize,
+                    owning_heap: OwningHeap::Large,
+                },
+            )),
+        }
+    }
+}
+
+impl Iterator for Allocations {
+    type Item = (*mut u8, AllocationInfo);
+
+    fn next(&mut self) -> Option<(*mut u8, AllocationInfo)> {
+        // Objects are found in address order.
+        while let Some((p, info)) = self.next_object() {
+            if p as usize >= self.end {
+                self.slag = None;
+                self.next = self.end;
+                return None;
+            }
+            if p as usize >= self.start {
+                return Some((p, info));
+            }
+        }
+        None
+    }
+}
+
+impl Allocations {
+    fn next_object(&mut self) -> Option<(*mut u8, AllocationInfo)> {
+        unsafe {
+            loop {
+                if let Some(obj) = self.next_in_slag() {
+                    return Some(obj);
+                }
+                self.slag = None;
+                let (addr, g) = match ownership::next_owned(self.next) {
+                    Some((addr, _)) if addr >= self.end => return None,
+                    Some(owned) => owned,
+                    None => return None,
+                };
+                // Slags can be smaller than a granule, in which case `next` may be in the middle
+                // of the granule at `addr`.
+                let addr = if addr < self.next { self.next } else { addr };
+                match g {
+                    Granule::Slags { ty, slag_size } => self.start_slag(addr, ty, slag_size),
+                    Granule::Large { index } => {
+                        if let Some(obj) = self.large(addr, index) {
+                            return Some(obj);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn walk(mode: WalkMode) -> HashMap<usize, AllocationInfo> {
+        unsafe { allocations(mode) }
+            .map(|(p, info)| (p as usize, info))
+            .collect()
+    }
+
+    #[test]
+    fn walk_finds_allocations() {
+        let sizes = [8, 24, 512, 100 << 10, 4 << 20];
+        unsafe {
+            let objs: Vec<_> = sizes.iter().map(|&size| global::alloc(size)).collect();
+            // Other tests allocate concurrently, so only best-effort walks are allowed.
+            let found = walk(WalkMode::BestEffort);
+            for (&obj, &size) in objs.iter().zip(sizes.iter()) {
+                let info = found
+                    .get(&(obj as usize))
+                    .expect("walk should find every live object");
+                alloc_assert!(info.usable_size >= size);
+                alloc_assert_eq!(global::object_start(obj.offset(size as isize / 2)), Some(obj));
+            }
+            let large = objs[objs.len() - 1];
+            for obj in objs {
+                global::free(obj);
+            }
+            alloc_assert!(!walk(WalkMode::BestEffort).contains_key(&(large as usize)));
+        }
+    }
+
+    #[test]
+    fn walk_range() {
+        unsafe {
+            let objs: Vec<_> = (0..16).map(|_| global::alloc(64)).collect();
+            let base = objs.iter().map(|&p| p as usize).min().unwrap();
+            let end = objs.iter().map(|&p| p as usize).max().unwrap();
+            let found: Vec<_> = allocations_in(WalkMode::BestEffort, base, end - base)
+                .map(|(p, _)| p as usize)
+                .collect();
+            alloc_assert!(found.iter().all(|&p| p >= base && p < end));
+            for &p in &objs {
+                alloc_assert!(p as usize == end || found.contains(&(p as usize)));
+            }
+            for p in objs {
+                global::free(p);
+            }
+        }
+    }
+}
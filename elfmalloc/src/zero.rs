@@ -0,0 +1,88 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Zeroing freed memory.
+//!
+//! With the `zero-on-free` feature enabled, every object freed to the global heap, a heap handle
+//! or an `ElfMalloc` is overwritten with zeros before it can be reused or unmapped, so that its
+//! contents do not outlive it. Some compliance regimes require this, and it narrows the window in
+//! which a use-after-free or a heap disclosure bug can leak data. The whole size class is zeroed,
+//! not just the requested size, since the application may have used all of it.
+//!
+//! Freed objects are zeroed with ordinary stores, since they are likely to be in the cache and to
+//! be reused soon. Objects of at least `NONTEMPORAL_THRESHOLD` bytes are zeroed with non-temporal
+//! stores, which bypass the cache: zeroing them with ordinary stores would evict the rest of the
+//! program's working set to make room for memory that is about to be unmapped or cached.
+//!
+//! In the global heap, the header and bit set of a `Slag` are also zeroed when its page is
+//! retired, so every byte of memory that the heap hands out is zero: it was either freshly mapped,
+//! uncommitted, or zeroed when it was last freed. `calloc` and `Alloc::alloc_zeroed` on
+//! `ElfMallocGlobal` rely on this and do not zero objects again, so they cost the same as
+//! `malloc`.
+//!
+//! The overhead is paid on `free`, and is proportional to the size class: a store per word for
+//! small objects, and a pass over memory at bandwidth speed for large ones. Run the `bench_zero`
+//! benchmark with and without the feature to measure it on a given machine. The feature is a
+//! build-time flag rather than an `ELFMALLOC_CONF` option, since the guarantee that `calloc`
+//! relies on only holds if every object has been zeroed since the heap was created.
+
+use std::intrinsics::nontemporal_store;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{fence, Ordering};
+
+/// The size from which objects are zeroed with non-temporal stores.
+pub const NONTEMPORAL_THRESHOLD: usize = 256 << 10;
+
+/// Zero the `size` bytes at `p`, an object that is being freed.
+#[inline]
+pub unsafe fn zero(p: *mut u8, size: usize) {
+    if size >= NONTEMPORAL_THRESHOLD {
+        zero_nontemporal(p, size)
+    } else {
+        ptr::write_bytes(p, 0, size)
+    }
+}
+
+/// Zero the `size` bytes at `p` with non-temporal stores of a word each.
+unsafe fn zero_nontemporal(p: *mut u8, size: usize) {
+    let word = mem::size_of::<usize>();
+    let start = (p as usize + word - 1) & !(word - 1);
+    let end = (p as usize + size) & !(word - 1);
+    if start >= end {
+        return ptr::write_bytes(p, 0, size);
+    }
+    ptr::write_bytes(p, 0, start - p as usize);
+    let mut w = start as *mut usize;
+    while (w as usize) < end {
+        nontemporal_store(w, 0);
+        w = w.offset(1);
+    }
+    ptr::write_bytes(end as *mut u8, 0, p as usize + size - end);
+    // Non-temporal stores are not ordered with respect to other stores (on x86, only a full
+    // fence orders them), and the memory may be handed to another thread next.
+    fence(Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_unaligned() {
+        for &size in &[0, 1, 7, 100, NONTEMPORAL_THRESHOLD - 1, NONTEMPORAL_THRESHOLD + 13] {
+            for offset in 0..mem::size_of::<usize>() {
+                let mut buf = vec![0xFFu8; size + 2 * mem::size_of::<usize>()];
+                unsafe { zero(buf.as_mut_ptr().offset(offset as isize), size) };
+                for (i, &b) in buf.iter().enumerate() {
+                    let inside = i >= offset && i < offset + size;
+                    alloc_assert_eq!(b == 0, inside, "size {} offset {} byte {}", size, offset, i);
+                }
+            }
+        }
+    }
+}
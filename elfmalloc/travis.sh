@@ -13,6 +13,7 @@ set -e
 # Skip elfmalloc tests until we can get them working (still build so we can
 # detect compilation failures).
 travis-cargo --only nightly build
+./asm_check.sh
 exit 0
 
 travis-cargo --only nightly build
@@ -0,0 +1,284 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Support for arbitrary alignments on top of allocators that do not support them.
+
+use core::{cmp, ptr};
+use alloc::allocator::{Alloc, AllocErr, Layout};
+use super::WORD_SIZE;
+
+/// An allocator adaptor that satisfies any alignment on top of an allocator that only guarantees
+/// a fixed, natural alignment.
+///
+/// Requests with an alignment of at most `natural_align` are passed through unchanged. For larger
+/// alignments, `AlignAdaptor` allocates a block from the inner allocator that is larger by the
+/// alignment plus a word, with an alignment of `natural_align`, returns the first suitably
+/// aligned address in it that leaves room for a word in front of it, and stores the address of
+/// the block in that word. `dealloc` reads the address back to free the block. The inner
+/// allocator is therefore never asked for more than `natural_align`.
+///
+/// Like `LayoutHeader`, `AlignAdaptor` implements `Alloc` for `&AlignAdaptor<A>` when `&A`
+/// implements `Alloc`, so that the two can be combined (`LayoutHeader<AlignAdaptor<A>>`) to bind
+/// the C API to such an allocator. It also implements `Alloc` for `AlignAdaptor<A>` when `A` does.
+pub struct AlignAdaptor<A> {
+    inner: A,
+    natural_align: usize,
+}
+
+impl<A> AlignAdaptor<A> {
+    /// Wrap `inner`, which must return memory aligned to at least `natural_align` bytes for any
+    /// request whose alignment does not exceed it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `natural_align` is not a power of two.
+    pub fn new(inner: A, natural_align: usize) -> AlignAdaptor<A> {
+        assert!(natural_align.is_power_of_two(),
+                "natural alignment {} is not a power of two",
+                natural_align);
+        AlignAdaptor {
+            inner: inner,
+            natural_align: natural_align,
+        }
+    }
+
+    pub fn natural_align(&self) -> usize {
+        self.natural_align
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> A {
+        self.inner
+    }
+
+    #[inline]
+    fn is_natural(&self, layout: &Layout) -> bool {
+        layout.align() <= self.natural_align
+    }
+}
+
+/// The layout of the block that holds an over-aligned object with layout `layout`.
+fn block_layout(natural_align: usize, layout: &Layout) -> Option<Layout> {
+    match layout.size().checked_add(layout.align() + WORD_SIZE) {
+        Some(size) => Layout::from_size_align(size, natural_align),
+        None => None,
+    }
+}
+
+/// Allocate an object with layout `layout` from `inner`, which guarantees `natural_align`.
+unsafe fn alloc_aligned<B: Alloc>(inner: &mut B,
+                                  natural_align: usize,
+                                  layout: Layout)
+                                  -> Result<*mut u8, AllocErr> {
+    if layout.align() <= natural_align {
+        return inner.alloc(layout);
+    }
+    let block_layout = match block_layout(natural_align, &layout) {
+        Some(l) => l,
+        None => return Err(AllocErr::Exhausted { request: layout }),
+    };
+    let block = inner.alloc(block_layout)?;
+    let align = layout.align();
+    let obj = (block as usize + WORD_SIZE + align - 1) & !(align - 1);
+    // The word in front of the object is only aligned if the object's alignment is at least a
+    // word.
+    ptr::write_unaligned((obj - WORD_SIZE) as *mut usize, block as usize);
+    Ok(obj as *mut u8)
+}
+
+unsafe fn dealloc_aligned<B: Alloc>(inner: &mut B,
+                                    natural_align: usize,
+                                    ptr: *mut u8,
+                                    layout: Layout) {
+    if layout.align() <= natural_align {
+        return inner.dealloc(ptr, layout);
+    }
+    let block = ptr::read_unaligned((ptr as usize - WORD_SIZE) as *const usize);
+    let block_layout = block_layout(natural_align, &layout).unwrap();
+    inner.dealloc(block as *mut u8, block_layout);
+}
+
+unsafe fn realloc_aligned<B: Alloc>(inner: &mut B,
+                                    natural_align: usize,
+                                    ptr: *mut u8,
+                                    layout: Layout,
+                                    new_layout: Layout)
+                                    -> Result<*mut u8, AllocErr> {
+    if layout.align() <= natural_align && new_layout.align() <= natural_align {
+        return inner.realloc(ptr, layout, new_layout);
+    }
+    // The inner allocator cannot move an over-aligned object, since it does not know where the
+    // object lives in its block.
+    let new_ptr = alloc_aligned(inner, natural_align, new_layout.clone())?;
+    ptr::copy_nonoverlapping(ptr, new_ptr, cmp::min(layout.size(), new_layout.size()));
+    dealloc_aligned(inner, natural_align, ptr, layout);
+    Ok(new_ptr)
+}
+
+unsafe impl<A: Alloc> Alloc for AlignAdaptor<A> {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        alloc_aligned(&mut self.inner, self.natural_align, layout)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        dealloc_aligned(&mut self.inner, self.natural_align, ptr, layout)
+    }
+
+    unsafe fn realloc(&mut self,
+                      ptr: *mut u8,
+                      layout: Layout,
+                      new_layout: Layout)
+                      -> Result<*mut u8, AllocErr> {
+        realloc_aligned(&mut self.inner, self.natural_align, ptr, layout, new_layout)
+    }
+
+    fn usable_size(&self, layout: &Layout) -> (usize, usize) {
+        if self.is_natural(layout) {
+            self.inner.usable_size(layout)
+        } else {
+            (layout.size(), layout.size())
+        }
+    }
+}
+
+unsafe impl<'a, A> Alloc for &'a AlignAdaptor<A>
+    where for<'b> &'b A: Alloc
+{
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        let mut inner = &self.inner;
+        alloc_aligned(&mut inner, self.natural_align, layout)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let mut inner = &self.inner;
+        dealloc_aligned(&mut inner, self.natural_align, ptr, layout)
+    }
+
+    unsafe fn realloc(&mut self,
+                      ptr: *mut u8,
+                      layout: Layout,
+                      new_layout: Layout)
+                      -> Result<*mut u8, AllocErr> {
+        let mut inner = &self.inner;
+        realloc_aligned(&mut inner, self.natural_align, ptr, layout, new_layout)
+    }
+
+    fn usable_size(&self, layout: &Layout) -> (usize, usize) {
+        if self.is_natural(layout) {
+            (&self.inner).usable_size(layout)
+        } else {
+            (layout.size(), layout.size())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::heap::Heap;
+    use core::cell::Cell;
+
+    /// An allocator whose objects are aligned to `align` but never to twice that, so that
+    /// anything relying on more alignment than it guarantees fails.
+    struct Natural {
+        align: usize,
+        live: Cell<usize>,
+    }
+
+    impl Natural {
+        fn outer(&self, layout: &Layout) -> Layout {
+            assert!(layout.align() <= self.align,
+                    "asked for alignment {} from a {}-aligned allocator",
+                    layout.align(),
+                    self.align);
+            Layout::from_size_align(layout.size() + self.align, 2 * self.align).unwrap()
+        }
+    }
+
+    unsafe impl<'a> Alloc for &'a Natural {
+        unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+            let p = Heap.alloc(self.outer(&layout))?;
+            self.live.set(self.live.get() + 1);
+            Ok(p.offset(self.align as isize))
+        }
+
+        unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+            self.live.set(self.live.get() - 1);
+            Heap.dealloc(ptr.offset(-(self.align as isize)), self.outer(&layout));
+        }
+    }
+
+    fn natural(align: usize) -> Natural {
+        Natural {
+            align: align,
+            live: Cell::new(0),
+        }
+    }
+
+    unsafe fn fill(ptr: *mut u8, size: usize, seed: u8) {
+        for i in 0..size {
+            *ptr.offset(i as isize) = seed.wrapping_add(i as u8);
+        }
+    }
+
+    unsafe fn check(ptr: *mut u8, size: usize, seed: u8) {
+        for i in 0..size {
+            assert_eq!(*ptr.offset(i as isize), seed.wrapping_add(i as u8));
+        }
+    }
+
+    /// Allocate, fill, grow, shrink and free objects of every combination of a few sizes and
+    /// alignments through `a`.
+    unsafe fn exercise<A: Alloc>(a: &mut A) {
+        for align_shift in 0..17 {
+            let align = 1 << align_shift;
+            for &size in &[1, 3, align - 1, align, align + 1, 1000] {
+                if size == 0 {
+                    continue;
+                }
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let p = a.alloc(layout.clone()).unwrap();
+                assert_eq!(p as usize % align, 0, "size {} align {}", size, align);
+                fill(p, size, align_shift as u8);
+
+                // Move the object between over-aligned and naturally aligned layouts.
+                let bigger = Layout::from_size_align(size * 2, 1).unwrap();
+                let p = a.realloc(p, layout.clone(), bigger.clone()).unwrap();
+                check(p, size, align_shift as u8);
+                let p = a.realloc(p, bigger, layout.clone()).unwrap();
+                assert_eq!(p as usize % align, 0);
+                check(p, size, align_shift as u8);
+                a.dealloc(p, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn all_alignments() {
+        for &natural_align in &[1, 8, 16, 4096] {
+            let inner = natural(natural_align);
+            unsafe {
+                exercise(&mut AlignAdaptor::new(&inner, natural_align));
+                exercise(&mut &AlignAdaptor::new(natural(natural_align), natural_align));
+            }
+            assert_eq!(inner.live.get(), 0);
+        }
+    }
+
+    #[test]
+    fn overflow() {
+        let inner = natural(8);
+        let mut a = AlignAdaptor::new(&inner, 8);
+        let layout = Layout::from_size_align(!0 - 64, 64).unwrap();
+        unsafe {
+            assert!(a.alloc(layout).is_err());
+        }
+    }
+}
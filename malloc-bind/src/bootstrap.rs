@@ -0,0 +1,502 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Lazy, reentrancy-safe construction of a global allocator.
+//!
+//! When a Rust allocator is installed as the C allocator, the first call to `malloc` can happen
+//! very early - before `main`, from a dynamic loader or from the C runtime's own initialization -
+//! and constructing the allocator may itself call `malloc` (for example, if it reads a
+//! configuration file with `fopen`, or creates a pthread key). A `lazy_static` cannot cope with
+//! this: the nested call finds the value under construction and waits for it forever.
+//!
+//! `LazyMalloc` instead moves through three states:
+//!
+//! ```text
+//! UNINIT --(first call)--> BOOTSTRAPPING --(constructor returns)--> READY
+//! ```
+//!
+//! The first call to any allocation function moves the state from `UNINIT` to `BOOTSTRAPPING` and
+//! runs the constructor. Calls made while the state is `BOOTSTRAPPING` - whether reentrant calls
+//! from the constructor or calls from other threads - are served by `bsalloc`, and the objects
+//! they return are recorded in a small table. Once the constructor returns, the state is `READY`
+//! and all new allocations are made from the constructed allocator. Bootstrap objects remain
+//! valid, and can be passed to `free` and `realloc` as usual; they are recognized using the table
+//! and returned to `bsalloc`.
+//!
+//! Once the state is `READY`, every call is forwarded to the `Malloc` methods of the constructed
+//! allocator, including any it overrides. Only calls made before then, and calls on bootstrap
+//! objects, use the default `Malloc` implementations.
+//!
+//! At most `BOOTSTRAP_OBJECTS` bootstrap objects may be live at once. If the table is full, a
+//! thread allocating during bootstrapping waits for the constructor to finish instead (which will
+//! deadlock if that thread is the one running the constructor).
+
+extern crate bsalloc;
+
+use alloc::allocator::{Alloc, AllocErr, Layout};
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use self::bsalloc::BsAlloc;
+use super::{c_void, size_t, LayoutFinder, Malloc};
+
+const UNINIT: usize = 0;
+const BOOTSTRAPPING: usize = 1;
+const READY: usize = 2;
+
+/// The maximum number of live objects allocated while bootstrapping.
+pub const BOOTSTRAP_OBJECTS: usize = 64;
+
+/// `bsalloc` returns objects aligned to at least a page, but we don't rely on page sizes larger
+/// than this.
+const BOOTSTRAP_MAX_ALIGN: usize = 4096;
+
+/// A global allocator which is constructed on first use. See the module documentation for
+/// details.
+///
+/// Once the inner allocator is constructed, `LazyMalloc`'s `Malloc` methods call those of the inner
+/// allocator, so methods it overrides are used as usual.
+pub struct LazyMalloc<M> {
+    state: AtomicUsize,
+    heap: UnsafeCell<Option<M>>,
+    init: fn() -> M,
+    /// The number of live bootstrap objects. When it is zero, `free` can skip the table.
+    outstanding: AtomicUsize,
+    /// Protects `objects`.
+    lock: AtomicBool,
+    /// The address, size and alignment of each live bootstrap object. Free slots have address 0.
+    objects: UnsafeCell<[(usize, usize, usize); BOOTSTRAP_OBJECTS]>,
+}
+
+unsafe impl<M: Send + Sync> Sync for LazyMalloc<M> {}
+
+impl<M> LazyMalloc<M> {
+    /// Create a new `LazyMalloc` which will be constructed by calling `init`.
+    pub const fn new(init: fn() -> M) -> LazyMalloc<M> {
+        LazyMalloc {
+            state: AtomicUsize::new(UNINIT),
+            heap: UnsafeCell::new(None),
+            init: init,
+            outstanding: AtomicUsize::new(0),
+            lock: AtomicBool::new(false),
+            objects: UnsafeCell::new([(0, 0, 0); BOOTSTRAP_OBJECTS]),
+        }
+    }
+
+    /// Has the inner allocator been constructed?
+    pub fn is_ready(&self) -> bool {
+        self.state.load(Ordering::Acquire) == READY
+    }
+
+    /// The number of live objects which were allocated while bootstrapping.
+    pub fn bootstrap_objects(&self) -> usize {
+        self.outstanding.load(Ordering::Acquire)
+    }
+
+    /// Get the inner allocator, constructing it if this is the first call.
+    ///
+    /// Returns `None` while the allocator is being constructed.
+    fn get(&self) -> Option<&M> {
+        if self.state.load(Ordering::Acquire) != READY {
+            if self.state.compare_exchange(UNINIT, BOOTSTRAPPING, Ordering::AcqRel, Ordering::Acquire)
+                .is_err() {
+                // Either another call is running the constructor, or it finished between our
+                // two loads.
+                if self.state.load(Ordering::Acquire) != READY {
+                    return None;
+                }
+            } else {
+                let heap = (self.init)();
+                unsafe { *self.heap.get() = Some(heap) };
+                self.state.store(READY, Ordering::Release);
+            }
+        }
+        unsafe { (*self.heap.get()).as_ref() }
+    }
+
+    /// Get the inner allocator, waiting for it to be constructed if necessary.
+    fn wait(&self) -> &M {
+        loop {
+            if let Some(heap) = self.get() {
+                return heap;
+            }
+        }
+    }
+
+    fn with_objects<R, F: FnOnce(&mut [(usize, usize, usize); BOOTSTRAP_OBJECTS]) -> R>(&self,
+                                                                                       f: F)
+                                                                                       -> R {
+        while self.lock.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err() {}
+        let res = f(unsafe { &mut *self.objects.get() });
+        self.lock.store(false, Ordering::Release);
+        res
+    }
+
+    /// The inner allocator, if `ptr` belongs to it; `None` if `ptr` is a bootstrap object, or if
+    /// the inner allocator is still being constructed.
+    fn owner(&self, ptr: *mut c_void) -> Option<&M> {
+        if self.bootstrap_layout(ptr as *mut u8).is_some() {
+            None
+        } else {
+            self.get()
+        }
+    }
+
+    /// Look up the layout of `ptr` if it is a bootstrap object.
+    fn bootstrap_layout(&self, ptr: *mut u8) -> Option<Layout> {
+        if self.outstanding.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        self.with_objects(|objects| {
+            objects.iter()
+                .find(|obj| obj.0 == ptr as usize)
+                .map(|obj| super::layout_from_size_align(obj.1, obj.2))
+        })
+    }
+
+    /// Allocate a bootstrap object. Returns `None` if the table is full.
+    unsafe fn bootstrap_alloc(&self, layout: Layout) -> Option<Result<*mut u8, AllocErr>> {
+        if layout.align() > BOOTSTRAP_MAX_ALIGN {
+            return Some(Err(AllocErr::Exhausted { request: layout }));
+        }
+        self.with_objects(|objects| {
+            let slot = match objects.iter_mut().find(|obj| obj.0 == 0) {
+                Some(slot) => slot,
+                None => return None,
+            };
+            let res = (&BsAlloc).alloc(layout.clone());
+            if let Ok(ptr) = res.as_ref() {
+                *slot = (*ptr as usize, layout.size(), layout.align());
+                self.outstanding.fetch_add(1, Ordering::Release);
+            }
+            Some(res)
+        })
+    }
+
+    /// Free `ptr` if it is a bootstrap object, returning whether it was.
+    unsafe fn bootstrap_dealloc(&self, ptr: *mut u8) -> bool {
+        if self.outstanding.load(Ordering::Acquire) == 0 {
+            return false;
+        }
+        let found = self.with_objects(|objects| {
+            objects.iter_mut().find(|obj| obj.0 == ptr as usize).map(|obj| {
+                let layout = super::layout_from_size_align(obj.1, obj.2);
+                *obj = (0, 0, 0);
+                layout
+            })
+        });
+        match found {
+            Some(layout) => {
+                (&BsAlloc).dealloc(ptr, layout);
+                self.outstanding.fetch_sub(1, Ordering::Release);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+unsafe impl<'a, M> Alloc for &'a LazyMalloc<M>
+    where for<'b> &'b M: Alloc
+{
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        let heap = match self.get() {
+            Some(heap) => heap,
+            None => {
+                match self.bootstrap_alloc(layout.clone()) {
+                    Some(res) => return res,
+                    None => self.wait(),
+                }
+            }
+        };
+        let mut heap = heap;
+        (&mut heap).alloc(layout)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        if self.bootstrap_dealloc(ptr) {
+            return;
+        }
+        // Only objects allocated by the inner allocator can get here, so it must exist.
+        let mut heap = self.wait();
+        (&mut heap).dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&mut self,
+                      ptr: *mut u8,
+                      layout: Layout,
+                      new_layout: Layout)
+                      -> Result<*mut u8, AllocErr> {
+        if self.bootstrap_layout(ptr).is_some() {
+            // Move the object out of bootstrap memory.
+            let new_ptr = self.alloc(new_layout.clone())?;
+            ptr::copy_nonoverlapping(ptr,
+                                     new_ptr,
+                                     ::core::cmp::min(layout.size(), new_layout.size()));
+            self.dealloc(ptr, layout);
+            return Ok(new_ptr);
+        }
+        let mut heap = self.wait();
+        (&mut heap).realloc(ptr, layout, new_layout)
+    }
+}
+
+unsafe impl<M: LayoutFinder> LayoutFinder for LazyMalloc<M> {
+    unsafe fn get_layout(&self, ptr: *mut u8) -> Layout {
+        match self.bootstrap_layout(ptr) {
+            Some(layout) => layout,
+            None => self.wait().get_layout(ptr),
+        }
+    }
+
+    unsafe fn insert_layout(&self, ptr: *mut u8, layout: Layout) {
+        if self.bootstrap_layout(ptr).is_none() {
+            self.wait().insert_layout(ptr, layout)
+        }
+    }
+
+    unsafe fn delete_layout(&self, ptr: *mut u8) {
+        if self.bootstrap_layout(ptr).is_none() {
+            self.wait().delete_layout(ptr)
+        }
+    }
+}
+
+/// A view of a `LazyMalloc` whose `Malloc` methods are the default implementations, for calls that
+/// the inner allocator cannot serve.
+struct Bootstrap<'a, M: 'a>(&'a LazyMalloc<M>);
+
+unsafe impl<'a, 'b, M> Alloc for &'b Bootstrap<'a, M>
+    where for<'c> &'c M: Alloc
+{
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        (&mut self.0).alloc(layout)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        (&mut self.0).dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&mut self,
+                      ptr: *mut u8,
+                      layout: Layout,
+                      new_layout: Layout)
+                      -> Result<*mut u8, AllocErr> {
+        (&mut self.0).realloc(ptr, layout, new_layout)
+    }
+}
+
+unsafe impl<'a, M: LayoutFinder> LayoutFinder for Bootstrap<'a, M> {
+    unsafe fn get_layout(&self, ptr: *mut u8) -> Layout {
+        self.0.get_layout(ptr)
+    }
+
+    unsafe fn insert_layout(&self, ptr: *mut u8, layout: Layout) {
+        self.0.insert_layout(ptr, layout)
+    }
+
+    unsafe fn delete_layout(&self, ptr: *mut u8) {
+        self.0.delete_layout(ptr)
+    }
+}
+
+unsafe impl<'a, M: LayoutFinder> Malloc for Bootstrap<'a, M> where for<'b> &'b M: Alloc {}
+
+unsafe impl<M: Malloc> Malloc for LazyMalloc<M>
+    where for<'a> &'a M: Alloc
+{
+    unsafe fn c_malloc(&self, size: size_t) -> *mut c_void {
+        match self.get() {
+            Some(heap) => heap.c_malloc(size),
+            None => Bootstrap(self).c_malloc(size),
+        }
+    }
+
+    unsafe fn c_free(&self, ptr: *mut c_void) {
+        match self.owner(ptr) {
+            Some(heap) => heap.c_free(ptr),
+            None => Bootstrap(self).c_free(ptr),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn c_cfree(&self, ptr: *mut c_void) {
+        match self.owner(ptr) {
+            Some(heap) => heap.c_cfree(ptr),
+            None => Bootstrap(self).c_cfree(ptr),
+        }
+    }
+
+    unsafe fn c_calloc(&self, nmemb: size_t, size: size_t) -> *mut c_void {
+        match self.get() {
+            Some(heap) => heap.c_calloc(nmemb, size),
+            None => Bootstrap(self).c_calloc(nmemb, size),
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    unsafe fn c_valloc(&self, size: size_t) -> *mut c_void {
+        match self.get() {
+            Some(heap) => heap.c_valloc(size),
+            None => Bootstrap(self).c_valloc(size),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn c_pvalloc(&self, size: size_t) -> *mut c_void {
+        match self.get() {
+            Some(heap) => heap.c_pvalloc(size),
+            None => Bootstrap(self).c_pvalloc(size),
+        }
+    }
+
+    unsafe fn c_realloc(&self, ptr: *mut c_void, size: size_t) -> *mut c_void {
+        // A bootstrap object is moved to the inner allocator by `Alloc::realloc`.
+        match self.owner(ptr) {
+            Some(heap) => heap.c_realloc(ptr, size),
+            None => Bootstrap(self).c_realloc(ptr, size),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn c_reallocf(&self, ptr: *mut c_void, size: size_t) -> *mut c_void {
+        match self.owner(ptr) {
+            Some(heap) => heap.c_reallocf(ptr, size),
+            None => Bootstrap(self).c_reallocf(ptr, size),
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    unsafe fn c_posix_memalign(&self,
+                               memptr: *mut *mut c_void,
+                               alignment: size_t,
+                               size: size_t)
+                               -> i32 {
+        match self.get() {
+            Some(heap) => heap.c_posix_memalign(memptr, alignment, size),
+            None => Bootstrap(self).c_posix_memalign(memptr, alignment, size),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn c_memalign(&self, alignment: size_t, size: size_t) -> *mut c_void {
+        match self.get() {
+            Some(heap) => heap.c_memalign(alignment, size),
+            None => Bootstrap(self).c_memalign(alignment, size),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn c_aligned_alloc(&self, alignment: size_t, size: size_t) -> *mut c_void {
+        match self.get() {
+            Some(heap) => heap.c_aligned_alloc(alignment, size),
+            None => Bootstrap(self).c_aligned_alloc(alignment, size),
+        }
+    }
+
+    #[cfg(windows)]
+    #[allow(non_snake_case)]
+    unsafe fn c__aligned_malloc(&self, size: size_t, alignment: size_t) -> *mut c_void {
+        match self.get() {
+            Some(heap) => heap.c__aligned_malloc(size, alignment),
+            None => Bootstrap(self).c__aligned_malloc(size, alignment),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{c_void, LayoutHeader};
+    use core::ptr;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static HEAP: LazyMalloc<LayoutHeader<BsAlloc>> = LazyMalloc::new(init);
+    static REENTRANT_OBJ: AtomicUsize = AtomicUsize::new(0);
+    static INITS: AtomicUsize = AtomicUsize::new(0);
+
+    fn init() -> LayoutHeader<BsAlloc> {
+        INITS.fetch_add(1, Ordering::SeqCst);
+        // Simulate a C library function that allocates while the allocator is being constructed.
+        unsafe {
+            assert!(!HEAP.is_ready());
+            let p = HEAP.c_malloc(100) as *mut u8;
+            assert!(!p.is_null());
+            ptr::write_bytes(p, 0xab, 100);
+            REENTRANT_OBJ.store(p as usize, Ordering::SeqCst);
+        }
+        LayoutHeader(BsAlloc)
+    }
+
+    #[test]
+    fn reentrant_first_call() {
+        unsafe {
+            let p = HEAP.c_malloc(200);
+            assert!(!p.is_null());
+            assert!(HEAP.is_ready());
+            assert_eq!(INITS.load(Ordering::SeqCst), 1);
+            assert_eq!(HEAP.bootstrap_objects(), 1);
+
+            // The bootstrap object survives a move to the real allocator.
+            let q = REENTRANT_OBJ.load(Ordering::SeqCst) as *mut c_void;
+            let q = HEAP.c_realloc(q, 1000) as *mut u8;
+            assert!(!q.is_null());
+            assert_eq!(HEAP.bootstrap_objects(), 0);
+            for i in 0..100 {
+                assert_eq!(*q.offset(i), 0xab);
+            }
+            HEAP.c_free(q as *mut c_void);
+            HEAP.c_free(p);
+            assert_eq!(INITS.load(Ordering::SeqCst), 1);
+        }
+    }
+
+    /// An allocator which counts the calls to its own `c_malloc`.
+    struct Counting(LayoutHeader<BsAlloc>);
+
+    static COUNTING_HEAP: LazyMalloc<Counting> = LazyMalloc::new(counting_init);
+    static COUNTED: AtomicUsize = AtomicUsize::new(0);
+
+    fn counting_init() -> Counting {
+        Counting(LayoutHeader(BsAlloc))
+    }
+
+    unsafe impl<'a> Alloc for &'a Counting {
+        unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+            (&mut &self.0).alloc(layout)
+        }
+
+        unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+            (&mut &self.0).dealloc(ptr, layout)
+        }
+    }
+
+    unsafe impl LayoutFinder for Counting {
+        unsafe fn get_layout(&self, ptr: *mut u8) -> Layout {
+            self.0.get_layout(ptr)
+        }
+    }
+
+    unsafe impl Malloc for Counting {
+        unsafe fn c_malloc(&self, size: size_t) -> *mut c_void {
+            COUNTED.fetch_add(1, Ordering::SeqCst);
+            self.0.c_malloc(size)
+        }
+    }
+
+    #[test]
+    fn forwards_overridden_methods() {
+        unsafe {
+            let p = COUNTING_HEAP.c_malloc(100);
+            assert!(!p.is_null());
+            assert!(COUNTING_HEAP.is_ready());
+            assert_eq!(COUNTED.load(Ordering::SeqCst), 1);
+            COUNTING_HEAP.c_free(p);
+        }
+    }
+}
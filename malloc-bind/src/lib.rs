@@ -19,6 +19,24 @@
 //! `realloc`, etc), but the Rust `Alloc` API requires both size and alignment for these methods,
 //! a mapping must be maintained between allocated objects and those objects' size and alignment.
 //! The `LayoutFinder` trait provides this functionality.
+//!
+//! # Choosing a `LayoutFinder` strategy
+//!
+//! There are two ways to maintain this mapping:
+//!
+//! - If the allocator can already determine the layout of an object from its address (e.g., from
+//!   per-page metadata), it can implement `LayoutFinder::get_layout` directly. This has no memory
+//!   overhead, and is what elfmalloc does.
+//! - Otherwise, the allocator can be wrapped in a `LayoutHeader`, which stores each object's
+//!   layout in a header immediately preceding it. This works with any allocator, but costs at
+//!   least two words per allocation (and up to the alignment of the object for over-aligned
+//!   allocations).
+//!
+//! The strategy is chosen per allocator by picking which type to pass to `define_malloc`.
+//!
+//! The C API lets callers ask for any power-of-two alignment (`posix_memalign`, `memalign`, etc).
+//! An allocator that only guarantees a fixed alignment can be wrapped in an `AlignAdaptor`, which
+//! provides the others on top of it.
 
 // TODO:
 // - Windows:
@@ -41,16 +59,10 @@ extern crate libc;
 extern crate errno;
 #[cfg(any(target_os = "linux", target_os = "macos"))]
 extern crate sysconf;
-// lazy_static's macros are only used in the macros we define, so if no macros are called (which is
-// the case when compiling this crate on its own), then lazy_static's macros (and thus the
-// #[macro_use] attribute) will appear unused.  Due to an issue with clippy
-// (https://rust-lang-nursery.github.io/rust-clippy/master/index.html#useless_attribute), this
-// allow(unused_imports) directive will be seen as useless, so we suppress the useless_attribute
-// warning as well.
-#[cfg_attr(feature = "cargo-clippy", allow(useless_attribute))]
-#[allow(unused_imports)]
-#[macro_use]
-extern crate lazy_static;
+mod align;
+mod bootstrap;
+pub use align::AlignAdaptor;
+pub use bootstrap::{LazyMalloc, BOOTSTRAP_OBJECTS};
 use alloc::allocator::{Alloc, AllocErr, Layout};
 
 // Export these so that they can be used from the macros as $crate::c_void and $crate::size_t.
@@ -116,6 +128,67 @@ pub unsafe trait LayoutFinder {
     unsafe fn delete_layout(&self, _ptr: *mut u8) {}
 }
 
+/// A `LayoutFinder` that stores each object's `Layout` in a header.
+///
+/// `LayoutHeader` wraps an allocator which cannot determine the layout of an object from its
+/// address. Each allocation is extended at the front by a header large enough to hold the size and
+/// alignment of the object, rounded up to the object's alignment so that the object itself stays
+/// aligned. `get_layout` reads the header back, and `insert_layout` and `delete_layout` are no-ops.
+///
+/// Allocators which can look up layouts themselves should implement `LayoutFinder` directly
+/// instead, avoiding the overhead of the header.
+pub struct LayoutHeader<A>(pub A);
+
+impl<A> LayoutHeader<A> {
+    /// The size of the header for an object with alignment `align`.
+    #[inline]
+    fn header_size(align: usize) -> usize {
+        core::cmp::max(align, 2 * WORD_SIZE)
+    }
+
+    /// A pointer to the header words of the object at `ptr`.
+    #[inline]
+    unsafe fn header(ptr: *mut u8) -> *mut [usize; 2] {
+        ptr.offset(-(2 * WORD_SIZE as isize)) as *mut [usize; 2]
+    }
+}
+
+unsafe impl<'a, A> Alloc for &'a LayoutHeader<A>
+    where for<'b> &'b A: Alloc
+{
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        let header_size = LayoutHeader::<A>::header_size(layout.align());
+        let size = match layout.size().checked_add(header_size) {
+            Some(size) => size,
+            None => return Err(AllocErr::Exhausted { request: layout }),
+        };
+        let inner = match Layout::from_size_align(size, layout.align()) {
+            Some(inner) => inner,
+            None => return Err(AllocErr::Exhausted { request: layout }),
+        };
+        let mut a = &self.0;
+        let ptr = (&mut a).alloc(inner)?.offset(header_size as isize);
+        ptr::write(LayoutHeader::<A>::header(ptr), [layout.size(), layout.align()]);
+        Ok(ptr)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let header_size = LayoutHeader::<A>::header_size(layout.align());
+        let inner = layout_from_size_align(layout.size() + header_size, layout.align());
+        let mut a = &self.0;
+        (&mut a).dealloc(ptr.offset(-(header_size as isize)), inner);
+    }
+}
+
+unsafe impl<A> LayoutFinder for LayoutHeader<A> {
+    unsafe fn get_layout(&self, ptr: *mut u8) -> Layout {
+        let header = ptr::read(LayoutHeader::<A>::header(ptr));
+        layout_from_size_align(header[0], header[1])
+    }
+}
+
+unsafe impl<A> Malloc for LayoutHeader<A> where for<'a> &'a A: Alloc {}
+
 // See the posix_memalign manpage on Linux, the malloc manpage on Mac, or
 // https://msdn.microsoft.com/en-us/library/6ewkz86d.aspx on Windows.
 //
@@ -192,7 +265,11 @@ pub unsafe trait Malloc: LayoutFinder
             return ptr::null_mut();
         }
 
-        let size = roundup(size, MIN_ALIGN);
+        let saved = SavedErrno::save();
+        let size = match roundup(size, MIN_ALIGN) {
+            Some(size) => size,
+            None => return enomem(),
+        };
         let layout = layout_from_size_align(size as usize, MIN_ALIGN);
         // TODO: Check _HEAP_MAXREQ on Windows? "malloc sets errno to ENOMEM if a memory allocation
         // fails or if the amount of memory requested exceeds _HEAP_MAXREQ."
@@ -200,6 +277,7 @@ pub unsafe trait Malloc: LayoutFinder
         match (&mut slf).alloc(layout.clone()) {
             Ok(ptr) => {
                 self.insert_layout(ptr, layout);
+                saved.restore();
                 ptr as *mut c_void
             }
             Err(AllocErr::Exhausted { .. }) => {
@@ -222,10 +300,15 @@ pub unsafe trait Malloc: LayoutFinder
             return;
         }
 
+        // POSIX.1-2024: "The free() function shall not modify errno if ptr is a null pointer or a
+        // pointer previously returned as if by malloc() and not yet deallocated." Returning memory
+        // to the operating system may involve system calls that fail harmlessly, so we restore it.
+        let saved = SavedErrno::save();
         let layout = self.get_layout(ptr as *mut u8);
         self.delete_layout(ptr as *mut u8);
         let mut slf = self;
         (&mut slf).dealloc(ptr as *mut u8, layout);
+        saved.restore();
     }
 
     /// The obsolete C `cfree` function (only implemented on Linux).
@@ -255,7 +338,13 @@ pub unsafe trait Malloc: LayoutFinder
             return ptr::null_mut();
         }
 
-        let total_size = roundup(nmemb * size, MIN_ALIGN);
+        let saved = SavedErrno::save();
+        // Linux: "If the multiplication of nmemb and size would result in integer overflow, then
+        // calloc() returns an error."
+        let total_size = match nmemb.checked_mul(size).and_then(|n| roundup(n, MIN_ALIGN)) {
+            Some(size) => size,
+            None => return enomem(),
+        };
         let layout = layout_from_size_align(total_size as usize, MIN_ALIGN);
         // TODO: Check _HEAP_MAXREQ on Windows? "calloc sets errno to ENOMEM if a memory allocation
         // fails or if the amount of memory requested exceeds _HEAP_MAXREQ."
@@ -263,6 +352,7 @@ pub unsafe trait Malloc: LayoutFinder
         match (&mut slf).alloc_zeroed(layout.clone()) {
             Ok(ptr) => {
                 self.insert_layout(ptr, layout);
+                saved.restore();
                 ptr as *mut c_void
             }
             Err(AllocErr::Exhausted { .. }) => {
@@ -291,13 +381,18 @@ pub unsafe trait Malloc: LayoutFinder
             return ptr::null_mut();
         }
 
+        let saved = SavedErrno::save();
         let pagesize = sysconf::page::pagesize();
-        let size = roundup(size, pagesize);
+        let size = match roundup(size, pagesize) {
+            Some(size) => size,
+            None => return enomem(),
+        };
         let layout = layout_from_size_align(size as usize, pagesize);
         let mut slf = self;
         match (&mut slf).alloc(layout.clone()) {
             Ok(ptr) => {
                 self.insert_layout(ptr, layout);
+                saved.restore();
                 ptr as *mut c_void
             }
             Err(AllocErr::Exhausted { .. }) => {
@@ -320,13 +415,18 @@ pub unsafe trait Malloc: LayoutFinder
             return ptr::null_mut();
         }
 
+        let saved = SavedErrno::save();
         let pagesize = sysconf::page::pagesize();
-        let size = roundup(size, pagesize);
+        let size = match roundup(size, pagesize) {
+            Some(size) => size,
+            None => return enomem(),
+        };
         let layout = layout_from_size_align(size as usize, pagesize);
         let mut slf = self;
         match (&mut slf).alloc(layout.clone()) {
             Ok(ptr) => {
                 self.insert_layout(ptr, layout);
+                saved.restore();
                 ptr as *mut c_void
             }
             Err(AllocErr::Exhausted { .. }) => {
@@ -386,7 +486,12 @@ pub unsafe trait Malloc: LayoutFinder
         // just as well because the caller cannot rely on the contents of a newly-allocated object,
         // and thus the new object sharing memory with the old object is fine.
 
-        let size = roundup(size, MIN_ALIGN);
+        let saved = SavedErrno::save();
+        let size = match roundup(size, MIN_ALIGN) {
+            Some(size) => size,
+            // See the comment on the Exhausted case below.
+            None => return enomem(),
+        };
         let layout = self.get_layout(ptr as *mut u8);
         let new_layout = layout_from_size_align(size as usize, MIN_ALIGN);
         let mut slf = self;
@@ -394,6 +499,7 @@ pub unsafe trait Malloc: LayoutFinder
             Ok(ptr) => {
                 self.delete_layout(ptr);
                 self.insert_layout(ptr, new_layout);
+                saved.restore();
                 ptr as *mut c_void
             }
             Err(AllocErr::Exhausted { .. }) => {
@@ -428,7 +534,14 @@ pub unsafe trait Malloc: LayoutFinder
         // sized object is allocated and the original object is freed." See the equivalent comment
         // in realloc for why this is handled automatically.
 
-        let size = roundup(size, MIN_ALIGN);
+        let saved = SavedErrno::save();
+        let size = match roundup(size, MIN_ALIGN) {
+            Some(size) => size,
+            None => {
+                self.c_free(ptr);
+                return enomem();
+            }
+        };
         let layout = self.get_layout(ptr as *mut u8);
         let new_layout = layout_from_size_align(size as usize, MIN_ALIGN);
         let mut slf = self;
@@ -436,6 +549,7 @@ pub unsafe trait Malloc: LayoutFinder
             Ok(ptr) => {
                 self.delete_layout(ptr);
                 self.insert_layout(ptr, new_layout);
+                saved.restore();
                 ptr as *mut c_void
             }
             Err(AllocErr::Exhausted { .. }) => {
@@ -481,7 +595,7 @@ pub unsafe trait Malloc: LayoutFinder
         // The manpage also specifies that the alignment must be a multiple of the word size, but
         // all powers of two greater than or equal to the word size are multiples of the word size,
         // so we omit that check.
-        if alignment <= WORD_SIZE || !alignment.is_power_of_two() {
+        if alignment < WORD_SIZE || !alignment.is_power_of_two() {
             return libc::EINVAL;
         }
 
@@ -494,16 +608,24 @@ pub unsafe trait Malloc: LayoutFinder
         // round up since valid Layouts must have that property. This is safe because this API
         // never takes the memory region size on deallocation, so it's fine that the caller might
         // think they have a smaller memory region than they actually do.
-        let size = roundup(size, alignment);
+        let saved = SavedErrno::save();
+        let size = match roundup(size, alignment) {
+            Some(size) => size,
+            None => return libc::ENOMEM,
+        };
         let layout = layout_from_size_align(size as usize, alignment);
         let mut slf = self;
         match (&mut slf).alloc(layout.clone()) {
             Ok(ptr) => {
                 self.insert_layout(ptr, layout);
+                saved.restore();
                 *memptr = ptr as *mut c_void;
                 0
             }
-            Err(AllocErr::Exhausted { .. }) => libc::ENOMEM,
+            Err(AllocErr::Exhausted { .. }) => {
+                saved.restore();
+                libc::ENOMEM
+            }
             Err(AllocErr::Unsupported { .. }) => core::intrinsics::abort(),
         }
     }
@@ -537,12 +659,17 @@ pub unsafe trait Malloc: LayoutFinder
         // up since valid Layouts must have that property. This is safe because this API never
         // takes the memory region size on deallocation, so it's fine that the caller might think
         // they have a smaller memory region than they actually do.
-        let size = roundup(size, alignment);
+        let saved = SavedErrno::save();
+        let size = match roundup(size, alignment) {
+            Some(size) => size,
+            None => return enomem(),
+        };
         let layout = layout_from_size_align(size as usize, alignment);
         let mut slf = self;
         match (&mut slf).alloc(layout.clone()) {
             Ok(ptr) => {
                 self.insert_layout(ptr, layout);
+                saved.restore();
                 ptr as *mut c_void
             }
             Err(AllocErr::Exhausted { .. }) => {
@@ -600,12 +727,17 @@ pub unsafe trait Malloc: LayoutFinder
         // round up since valid Layouts must have that property. This is safe because this API
         // never takes the memory region size on deallocation, so it's fine that the caller might
         // think they have a smaller memory region than they actually do.
-        let size = roundup(size, alignment);
+        let saved = SavedErrno::save();
+        let size = match roundup(size, alignment) {
+            Some(size) => size,
+            None => return enomem(),
+        };
         let layout = layout_from_size_align(size as usize, alignment);
         let mut slf = self;
         match (&mut slf).alloc(layout.clone()) {
             Ok(ptr) => {
                 self.insert_layout(ptr, layout);
+                saved.restore();
                 ptr as *mut c_void
             }
             Err(AllocErr::Exhausted { .. }) => {
@@ -617,17 +749,48 @@ pub unsafe trait Malloc: LayoutFinder
     }
 }
 
+/// Round `n` up to a multiple of `multiple`, returning `None` on overflow.
 #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
 #[inline(always)]
-fn roundup(n: size_t, multiple: size_t) -> size_t {
+fn roundup(n: size_t, multiple: size_t) -> Option<size_t> {
     if n == 0 {
-        return multiple;
+        return Some(multiple);
     }
     let remainder = n % multiple;
     if remainder == 0 {
-        n
+        Some(n)
     } else {
-        n + multiple - remainder
+        n.checked_add(multiple - remainder)
+    }
+}
+
+/// Set `errno` to `ENOMEM` and return `NULL`.
+#[cold]
+fn enomem() -> *mut c_void {
+    errno::set_errno(errno::Errno(libc::ENOMEM));
+    ptr::null_mut()
+}
+
+/// The value of `errno` on entry to an allocation function.
+///
+/// POSIX leaves the value of `errno` after a successful call unspecified (and requires that `free`
+/// not modify it at all), but plenty of programs inspect `errno` after a sequence of calls without
+/// checking which of them failed, and glibc is careful not to clobber it. The allocator may make
+/// system calls that fail harmlessly (e.g., `madvise` with an unsupported advice value), so the
+/// allocation functions save `errno` on entry and restore it when they succeed.
+struct SavedErrno(errno::Errno);
+
+impl SavedErrno {
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    fn save() -> SavedErrno {
+        SavedErrno(errno::errno())
+    }
+
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    fn restore(self) {
+        errno::set_errno(self.0)
     }
 }
 
@@ -745,26 +908,23 @@ macro_rules! define_malloc {
     )
 }
 
-// This line re-exports the macros from lazy_static so that they'll be available to the code
-// calling define_malloc_lazy_static. This allows define_malloc_lazy_static to be used without the
-// caller needing to know about lazy_static and import its macros themselves.
-//
-// Credit to https://users.rust-lang.org/t/how-to-use-macro-inside-another-macro/12061/2
-#[doc(hidden)]
-pub use lazy_static::*;
-
 /// Define `extern "C"` functions for the C allocation API with a non-constant initializer.
 ///
 /// `define_malloc_lazy_static` is like `define_malloc`, except there is no requirement that the
-/// initialization expression must be constant. Instead, `lazy_static` is used to construct the
-/// global `Malloc` instance.
+/// initialization expression must be constant. Instead, the global instance is a `LazyMalloc`
+/// which evaluates the expression on the first call to any of the functions. Calls made while the
+/// expression is being evaluated (including reentrant calls from the expression itself) are
+/// served by a bootstrap allocator; see `LazyMalloc` for details. Once the allocator is
+/// constructed, the functions call its `Malloc` methods, including any it overrides.
 #[macro_export]
 macro_rules! define_malloc_lazy_static {
     ($alloc_ty:ty, $alloc_new:expr) => (
-        lazy_static!{
-            static ref __HEAP: $alloc_ty = $alloc_new;
+        fn __heap_init() -> $alloc_ty {
+            $alloc_new
         }
 
+        static __HEAP: $crate::LazyMalloc<$alloc_ty> = $crate::LazyMalloc::new(__heap_init);
+
         #[no_mangle]
         pub extern "C" fn malloc(size: $crate::size_t) -> *mut $crate::c_void {
             use $crate::Malloc;
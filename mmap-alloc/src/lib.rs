@@ -40,6 +40,7 @@ extern crate winapi;
 use self::alloc::allocator::{Alloc, AllocErr, CannotReallocInPlace, Excess, Layout};
 use self::object_alloc::{Exhausted, UntypedObjectAlloc};
 use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 #[cfg(any(target_os = "linux", target_os = "macos"))]
 use errno::errno;
@@ -81,6 +82,43 @@ use errno::errno;
 /// In order to avoid this scenario, it is often necessary to explicitly flush the instruction
 /// cache before executing newly-written memory. The mechanism to accomplish this differs by
 /// system.
+///
+/// # Sizes that are not a multiple of the page size
+///
+/// Memory can only be mapped in whole pages, so by default, the size of each request is rounded up
+/// to a multiple of the page size, and the rest of the last page is wasted. `MapAlloc` keeps track
+/// of this waste (see `MapAlloc::rounding_stats`). An allocator built with `strict` enabled
+/// instead rejects such requests, so that a combination of allocators that is meant to route
+/// small objects elsewhere fails loudly rather than silently spending a page on each one.
+///
+/// # Overcommit (Linux)
+///
+/// Linux charges writable private mappings against a commit limit when they are created, unless
+/// `MAP_NORESERVE` is passed. Under the default heuristic overcommit policy, only mappings that
+/// could obviously never be backed are refused, but under strict accounting
+/// (`vm.overcommit_memory = 2`, see `overcommit_mode`), a large sparse mapping fails even if only
+/// a few of its pages will ever be touched. Two options control this:
+///
+/// - `noreserve` passes `MAP_NORESERVE`, so that the mapping is not charged under the heuristic
+///   policy. Strict accounting ignores the flag.
+/// - `reserve_only` maps memory without any access permissions, which is not charged under any
+///   policy. Parts of an object are made usable with `MapAlloc::commit_reserved`, which is when
+///   they are charged, and can fail; `MapAlloc::uncommit` returns them to the reserved state.
+///
+/// `MapAlloc::resident_pages` reports how many pages of an object are actually backed by memory.
+///
+/// # Locked memory
+///
+/// Memory holding secrets such as cryptographic keys should not be written to swap, where it may
+/// outlive the process. An allocator built with `locked` enabled locks its mappings into memory
+/// with `mlock`, excludes them from core dumps on Linux, and overwrites objects with zeros before
+/// unmapping them. Objects are never moved by `mremap`, since that would leave the old pages
+/// unzeroed; `realloc` copies instead.
+///
+/// The amount of memory a process may lock is limited (`RLIMIT_MEMLOCK`, often only 64 KiB for
+/// unprivileged processes). When locking a mapping fails, the allocation still succeeds, but the
+/// memory is not locked, and the failure is counted (see `MapAlloc::lock_failures`), so that a
+/// program can decide for itself whether to carry on.
 pub struct MapAllocBuilder {
     read: bool,
     write: bool,
@@ -91,10 +129,24 @@ pub struct MapAllocBuilder {
     // loading it is efficient
     pagesize: usize,
     obj_size: Option<usize>,
+    strict: bool,
+    // Only supported on Linux
+    noreserve: bool,
+    reserve_only: bool,
+    // Only supported on Linux and Mac
+    locked: bool,
 }
 
 impl MapAllocBuilder {
     pub fn build(&self) -> MapAlloc {
+        assert!(
+            !(self.reserve_only && self.commit),
+            "reserve-only memory cannot be committed on allocation"
+        );
+        assert!(
+            !self.locked || (self.read && self.write && !self.reserve_only),
+            "locked memory must be readable, writable and committed"
+        );
         let obj_size = if let Some(obj_size) = self.obj_size {
             assert_eq!(
                 obj_size % self.pagesize,
@@ -115,6 +167,13 @@ impl MapAllocBuilder {
             perms: perms::get_perm(self.read, self.write, self.exec),
             commit: self.commit,
             obj_size: obj_size,
+            strict: self.strict,
+            noreserve: self.noreserve,
+            reserve_only: self.reserve_only,
+            locked: self.locked,
+            lock_failures: AtomicUsize::new(0),
+            rounded_allocs: AtomicUsize::new(0),
+            rounded_bytes: AtomicUsize::new(0),
         }
     }
 
@@ -188,6 +247,70 @@ impl MapAllocBuilder {
         self.obj_size = Some(obj_size);
         self
     }
+
+    /// Configures whether requests whose size is not a multiple of the page size are rejected.
+    ///
+    /// If `strict` is enabled, `alloc`, `realloc` and the other allocation methods of the `Alloc`
+    /// implementation fail with `AllocErr::Unsupported` for such sizes (in particular, for any
+    /// size smaller than a page), and `grow_in_place` and `shrink_in_place` fail. The default is
+    /// to round the size up. See the "Sizes that are not a multiple of the page size" section of
+    /// the `MapAllocBuilder` documentation.
+    pub fn strict(mut self, strict: bool) -> MapAllocBuilder {
+        self.strict = strict;
+        self
+    }
+
+    /// Configures whether memory is mapped with `MAP_NORESERVE`.
+    ///
+    /// If `noreserve` is enabled, no swap space is reserved for allocated memory, and it is not
+    /// charged against the commit limit under the heuristic overcommit policy. Touching it may
+    /// then fail with `SIGSEGV` if memory runs out. The default is disabled.
+    ///
+    /// See the "Overcommit" section of the `MapAllocBuilder` documentation for more details.
+    ///
+    /// # Platform-specific behavior
+    ///
+    /// `noreserve` is only supported on Linux.
+    #[cfg(target_os = "linux")]
+    pub fn noreserve(mut self, noreserve: bool) -> MapAllocBuilder {
+        self.noreserve = noreserve;
+        self
+    }
+
+    /// Configures whether allocated memory is only reserved.
+    ///
+    /// If `reserve_only` is enabled, memory is mapped without any access permissions and with
+    /// `MAP_NORESERVE`, so that it is not charged against the commit limit under any overcommit
+    /// policy. It cannot be accessed until it has been committed with `MapAlloc::commit_reserved`.
+    /// The default is disabled. `reserve_only` cannot be combined with `commit`.
+    ///
+    /// See the "Overcommit" section of the `MapAllocBuilder` documentation for more details.
+    ///
+    /// # Platform-specific behavior
+    ///
+    /// `reserve_only` is only supported on Linux.
+    #[cfg(target_os = "linux")]
+    pub fn reserve_only(mut self, reserve_only: bool) -> MapAllocBuilder {
+        self.reserve_only = reserve_only;
+        self
+    }
+
+    /// Configures whether allocated memory is locked into physical memory.
+    ///
+    /// If `locked` is enabled, allocated memory is locked with `mlock` so that it is never written
+    /// to swap, and zeroed before it is freed. Locked memory must be readable and writable. The
+    /// default is disabled.
+    ///
+    /// See the "Locked memory" section of the `MapAllocBuilder` documentation for more details.
+    ///
+    /// # Platform-specific behavior
+    ///
+    /// `locked` is only supported on Linux and Mac.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub fn locked(mut self, locked: bool) -> MapAllocBuilder {
+        self.locked = locked;
+        self
+    }
 }
 
 impl Default for MapAllocBuilder {
@@ -199,6 +322,10 @@ impl Default for MapAllocBuilder {
             commit: false,
             pagesize: sysconf::page::pagesize(),
             obj_size: None,
+            strict: false,
+            noreserve: false,
+            reserve_only: false,
+            locked: false,
         }
     }
 }
@@ -213,6 +340,22 @@ pub struct MapAlloc {
     perms: perms::Perm,
     commit: bool,
     obj_size: usize,
+    strict: bool,
+    #[cfg_attr(not(target_os = "linux"), allow(unused))] noreserve: bool,
+    #[cfg_attr(not(target_os = "linux"), allow(unused))] reserve_only: bool,
+    locked: bool,
+    lock_failures: AtomicUsize,
+    rounded_allocs: AtomicUsize,
+    rounded_bytes: AtomicUsize,
+}
+
+/// How much memory a `MapAlloc` has wasted by rounding sizes up to a multiple of the page size.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RoundingStats {
+    /// The number of allocations and reallocations whose size was rounded up.
+    pub allocs: usize,
+    /// The total number of bytes that they were rounded up by.
+    pub wasted_bytes: usize,
 }
 
 impl Default for MapAlloc {
@@ -270,6 +413,195 @@ impl MapAlloc {
         #[cfg(debug_assertions)]
         self.debug_verify_ptr(ptr, layout.clone());
         uncommit(ptr, layout.size());
+        #[cfg(target_os = "linux")]
+        {
+            if self.reserve_only {
+                // Dropping the permissions also drops the charge against the commit limit.
+                protect(ptr, next_multiple(layout.size(), self.pagesize), perms::PROT_NONE);
+            }
+        }
+    }
+
+    /// Commits part of an object allocated by a reserve-only allocator.
+    ///
+    /// `commit_reserved` makes the pages spanning the `size` bytes starting at `ptr` accessible
+    /// with the configured permissions. `ptr` must be page-aligned, and the range must lie within
+    /// an allocated object. This is when the pages are charged against the commit limit, so it
+    /// fails with `AllocErr::Exhausted` if the limit has been reached. It does nothing if this
+    /// allocator is not reserve-only.
+    ///
+    /// See the "Overcommit" section of the `MapAllocBuilder` documentation for more details.
+    ///
+    /// # Platform-specific behavior
+    ///
+    /// `commit_reserved` is only supported on Linux.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn commit_reserved(&self, ptr: *mut u8, size: usize) -> Result<(), AllocErr> {
+        debug_assert_eq!(ptr as usize % self.pagesize, 0);
+        if !self.reserve_only {
+            return Ok(());
+        }
+        let size = next_multiple(size, self.pagesize);
+        // NOTE: Don't inline the call to mprotect; then errno might be called before mprotect.
+        let ret = libc::mprotect(ptr as *mut _, size, self.perms);
+        if ret == 0 {
+            Ok(())
+        } else if errno().0 == libc::ENOMEM {
+            Err(AllocErr::Exhausted {
+                request: Layout::from_size_align(size, self.pagesize).unwrap(),
+            })
+        } else {
+            panic!("mprotect failed: {}", errno())
+        }
+    }
+
+    /// Returns the number of pages spanning the `size` bytes starting at `ptr` that are backed by
+    /// physical memory (that is, resident, rather than never touched, uncommitted, or swapped
+    /// out). `ptr` must be page-aligned.
+    ///
+    /// # Platform-specific behavior
+    ///
+    /// `resident_pages` is only supported on Linux and Mac.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub unsafe fn resident_pages(&self, ptr: *mut u8, size: usize) -> usize {
+        debug_assert_eq!(ptr as usize % self.pagesize, 0);
+        let pages = next_multiple(size, self.pagesize) / self.pagesize;
+        // mincore writes one byte per page, so query in chunks to avoid allocating.
+        let mut vec = [0u8; 256];
+        let mut resident = 0;
+        let mut done = 0;
+        while done < pages {
+            let n = core::cmp::min(pages - done, vec.len());
+            let start = ptr.offset((done * self.pagesize) as isize);
+            // NOTE: Don't inline the call to mincore; then errno might be called before mincore.
+            let ret = libc::mincore(start as *mut _, n * self.pagesize, vec.as_mut_ptr() as *mut _);
+            assert_eq!(ret, 0, "mincore failed: {}", errno());
+            resident += vec[..n].iter().filter(|&&b| b & 1 != 0).count();
+            done += n;
+        }
+        resident
+    }
+
+    /// Returns statistics on the memory wasted by rounding sizes up to a multiple of the page size
+    /// since this `MapAlloc` was built.
+    ///
+    /// Allocations made through the `UntypedObjectAlloc` implementation are never rounded, since
+    /// the object size is a multiple of the page size. An allocator built with `strict` enabled
+    /// never rounds.
+    pub fn rounding_stats(&self) -> RoundingStats {
+        RoundingStats {
+            allocs: self.rounded_allocs.load(Ordering::Relaxed),
+            wasted_bytes: self.rounded_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the number of allocations whose memory could not be locked because the limit on
+    /// locked memory was reached. It is always 0 unless this allocator was built with `locked`
+    /// enabled.
+    ///
+    /// See the "Locked memory" section of the `MapAllocBuilder` documentation for more details.
+    pub fn lock_failures(&self) -> usize {
+        self.lock_failures.load(Ordering::Relaxed)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    unsafe fn lock(&self, ptr: *mut u8, size: usize) {
+        // NOTE: Don't inline the call to mlock; then errno might be called before mlock.
+        let ret = libc::mlock(ptr as *const _, size);
+        if ret != 0 {
+            let err = errno().0;
+            // EAGAIN and ENOMEM are how Linux and Mac report that the limit has been reached, and
+            // EPERM means that the process may not lock any memory at all.
+            if err == libc::EAGAIN || err == libc::ENOMEM || err == libc::EPERM {
+                self.lock_failures.fetch_add(1, Ordering::Relaxed);
+            } else {
+                panic!("mlock failed: {}", errno());
+            }
+        }
+        #[cfg(target_os = "linux")]
+        libc::madvise(ptr as *mut _, size, libc::MADV_DONTDUMP);
+    }
+
+    // locked is not supported on Windows
+    #[cfg(windows)]
+    unsafe fn lock(&self, _ptr: *mut u8, _size: usize) {}
+
+    /// Overwrites a locked object with zeros before it is unmapped.
+    unsafe fn zero_locked(&self, ptr: *mut u8, size: usize) {
+        // Volatile writes, so that the compiler cannot drop them as dead stores.
+        let words = ptr as *mut usize;
+        for i in 0..next_multiple(size, self.pagesize) / core::mem::size_of::<usize>() {
+            ptr::write_volatile(words.offset(i as isize), 0);
+        }
+    }
+
+    /// Moves an object to a new mapping by copying, so that the old one is zeroed when it is freed.
+    #[cfg(target_os = "linux")]
+    unsafe fn realloc_copy(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_layout: Layout,
+    ) -> Result<*mut u8, AllocErr> {
+        use core::cmp;
+        let mut a = self;
+        let new_ptr = Alloc::alloc(&mut a, new_layout.clone())?;
+        ptr::copy_nonoverlapping(ptr, new_ptr, cmp::min(layout.size(), new_layout.size()));
+        Alloc::dealloc(&mut a, ptr, layout);
+        Ok(new_ptr)
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn map(&self, size: usize) -> Option<*mut u8> {
+        use libc::{MAP_NORESERVE, MAP_POPULATE};
+        let mut flags = if self.commit { MAP_POPULATE } else { 0 };
+        if self.noreserve || self.reserve_only {
+            flags |= MAP_NORESERVE;
+        }
+        let perms = if self.reserve_only {
+            perms::PROT_NONE
+        } else {
+            self.perms
+        };
+        let ptr = map_flags(size, perms, flags);
+        if let Some(ptr) = ptr {
+            if self.locked {
+                self.lock(ptr, size);
+            }
+        }
+        ptr
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    unsafe fn map(&self, size: usize) -> Option<*mut u8> {
+        let ptr = map(size, self.perms, self.commit);
+        if let Some(ptr) = ptr {
+            if self.locked {
+                self.lock(ptr, size);
+            }
+        }
+        ptr
+    }
+
+    /// Rejects `layout` if its size is not a multiple of the page size and this allocator is
+    /// strict.
+    fn check_size(&self, layout: &Layout) -> Result<(), AllocErr> {
+        if self.strict && layout.size() % self.pagesize != 0 {
+            Err(AllocErr::invalid_input(
+                "size is not a multiple of the page size",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Records the rounding of the size of `layout`, which has been successfully allocated.
+    fn note_rounding(&self, layout: &Layout) {
+        let waste = next_multiple(layout.size(), self.pagesize) - layout.size();
+        if waste > 0 {
+            self.rounded_allocs.fetch_add(1, Ordering::Relaxed);
+            self.rounded_bytes.fetch_add(waste, Ordering::Relaxed);
+        }
     }
 
     #[cfg(target_os = "linux")]
@@ -281,18 +613,22 @@ impl MapAlloc {
     ) -> Result<(), CannotReallocInPlace> {
         // alignment less than a page is fine because page-aligned objects are also aligned to
         // any alignment less than a page
-        if new_layout.align() > self.pagesize {
+        if new_layout.align() > self.pagesize || self.check_size(&new_layout).is_err() ||
+            self.locked
+        {
             return Err(CannotReallocInPlace);
         }
 
         let old_size = next_multiple(layout.size(), self.pagesize);
         let new_size = next_multiple(new_layout.size(), self.pagesize);
         if old_size == new_size {
+            self.note_rounding(&new_layout);
             return Ok(());
         }
         match remap(ptr, old_size, new_size, true) {
             Some(new_ptr) => {
                 debug_assert_eq!(new_ptr, ptr);
+                self.note_rounding(&new_layout);
                 Ok(())
             }
             None => Err(CannotReallocInPlace),
@@ -323,8 +659,14 @@ unsafe impl<'a> Alloc for &'a MapAlloc {
             ));
         }
 
+        self.check_size(&layout)?;
+
         let size = next_multiple(layout.size(), self.pagesize);
-        map(size, self.perms, self.commit).ok_or(AllocErr::Exhausted { request: layout })
+        let ptr = self.map(size).ok_or(AllocErr::Exhausted {
+            request: layout.clone(),
+        })?;
+        self.note_rounding(&layout);
+        Ok(ptr)
     }
 
     unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
@@ -332,6 +674,9 @@ unsafe impl<'a> Alloc for &'a MapAlloc {
             layout.size() > 0,
             "dealloc: size of layout must be non-zero"
         );
+        if self.locked {
+            self.zero_locked(ptr, layout.size());
+        }
         unmap(ptr, layout.size());
     }
 
@@ -376,14 +721,22 @@ unsafe impl<'a> Alloc for &'a MapAlloc {
             ));
         }
 
+        self.check_size(&new_layout)?;
+
         let old_size = next_multiple(layout.size(), self.pagesize);
         let new_size = next_multiple(new_layout.size(), self.pagesize);
         if old_size == new_size {
+            self.note_rounding(&new_layout);
             return Ok(ptr);
         }
-        remap(ptr, old_size, new_layout.size(), false).ok_or(AllocErr::Exhausted {
-            request: new_layout,
-        })
+        if self.locked {
+            return self.realloc_copy(ptr, layout, new_layout);
+        }
+        let new_ptr = remap(ptr, old_size, new_layout.size(), false).ok_or(AllocErr::Exhausted {
+            request: new_layout.clone(),
+        })?;
+        self.note_rounding(&new_layout);
+        Ok(new_ptr)
     }
 
     #[cfg(any(target_os = "macos", windows))]
@@ -412,10 +765,14 @@ unsafe impl<'a> Alloc for &'a MapAlloc {
             ));
         }
 
+        self.check_size(&new_layout)?;
+
         let old_size = next_multiple(layout.size(), self.pagesize);
         let new_size = next_multiple(new_layout.size(), self.pagesize);
 
+        // shrink_in_place and alloc record the rounding themselves.
         if old_size == new_size {
+            self.note_rounding(&new_layout);
             return Ok(ptr);
         } else if !cfg!(windows) && new_size < old_size {
             // Windows cannot shrink existing mappings.
@@ -513,6 +870,10 @@ unsafe impl<'a> Alloc for &'a MapAlloc {
         debug_assert!(new_layout.size() <= layout.size());
         debug_assert_eq!(new_layout.align(), layout.align());
 
+        if self.check_size(&new_layout).is_err() || self.locked {
+            return Err(CannotReallocInPlace);
+        }
+
         let old_size = next_multiple(layout.size(), self.pagesize);
         let new_size = next_multiple(new_layout.size(), self.pagesize);
         if new_size < old_size {
@@ -520,6 +881,7 @@ unsafe impl<'a> Alloc for &'a MapAlloc {
             let ptr = (ptr as usize + new_size) as *mut u8;
             unmap(ptr, diff);
         }
+        self.note_rounding(&new_layout);
         Ok(())
     }
 }
@@ -610,6 +972,45 @@ unsafe impl UntypedObjectAlloc for MapAlloc {
     }
 }
 
+/// The kernel's policy for committing memory to mappings (`vm.overcommit_memory`).
+#[cfg(target_os = "linux")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OvercommitMode {
+    /// Refuse only mappings that obviously cannot be backed (0, the default).
+    Heuristic,
+    /// Never refuse a mapping (1).
+    Always,
+    /// Refuse mappings that would take the committed memory over the commit limit (2).
+    Never,
+}
+
+/// Returns the kernel's overcommit policy, or `None` if it cannot be read (e.g. because `/proc`
+/// is not mounted).
+///
+/// See the "Overcommit" section of the `MapAllocBuilder` documentation for why this matters.
+#[cfg(target_os = "linux")]
+pub fn overcommit_mode() -> Option<OvercommitMode> {
+    let mut buf = [0u8; 1];
+    unsafe {
+        let path = b"/proc/sys/vm/overcommit_memory\0";
+        let fd = libc::open(path.as_ptr() as *const _, libc::O_RDONLY);
+        if fd < 0 {
+            return None;
+        }
+        let n = libc::read(fd, buf.as_mut_ptr() as *mut _, 1);
+        libc::close(fd);
+        if n != 1 {
+            return None;
+        }
+    }
+    match buf[0] {
+        b'0' => Some(OvercommitMode::Heuristic),
+        b'1' => Some(OvercommitMode::Always),
+        b'2' => Some(OvercommitMode::Never),
+        _ => None,
+    }
+}
+
 fn next_multiple(size: usize, unit: usize) -> usize {
     let remainder = size % unit;
     if remainder == 0 {
@@ -629,14 +1030,19 @@ fn next_multiple(size: usize, unit: usize) -> usize {
 // method.
 
 #[cfg(target_os = "linux")]
+#[cfg_attr(not(test), allow(unused))]
 unsafe fn map(size: usize, perms: i32, commit: bool) -> Option<*mut u8> {
-    use libc::{ENOMEM, MAP_ANONYMOUS, MAP_FAILED, MAP_POPULATE, MAP_PRIVATE};
+    map_flags(size, perms, if commit { libc::MAP_POPULATE } else { 0 })
+}
+
+// flags are passed to mmap in addition to MAP_ANONYMOUS and MAP_PRIVATE
+#[cfg(target_os = "linux")]
+unsafe fn map_flags(size: usize, perms: i32, flags: i32) -> Option<*mut u8> {
+    use libc::{ENOMEM, MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE};
 
     // TODO: Figure out when it's safe to pass MAP_UNINITIALIZED (it's not defined in all
     // versions of libc). Be careful about not invalidating alloc_zeroed.
 
-    let flags = if commit { MAP_POPULATE } else { 0 };
-
     let ptr = libc::mmap(
         ptr::null_mut(),
         size,
@@ -769,7 +1175,6 @@ unsafe fn unmap(ptr: *mut u8, _size: usize) {
     );
 }
 
-#[cfg_attr(target_os = "linux", allow(unused))]
 #[cfg(any(target_os = "linux", target_os = "macos"))]
 unsafe fn protect(ptr: *mut u8, size: usize, perm: perms::Perm) {
     // NOTE: Don't inline the call to mprotect; then errno might be called before mprotect.
@@ -361,6 +361,138 @@ fn test_realloc_large() {
     }
 }
 
+#[test]
+fn test_sub_page_sizes() {
+    unsafe {
+        let page = Layout::from_size_align(pagesize(), 1).unwrap();
+        let small = Layout::from_size_align(100, 1).unwrap();
+        let pages = Layout::from_size_align(2 * pagesize() + 1, 1).unwrap();
+
+        // Check that:
+        // - By default, sizes are rounded up, and the waste is recorded
+        // - Sizes that are a multiple of the page size are not counted
+        let mut alloc = MapAlloc::default();
+        let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, small.clone()).unwrap();
+        test_valid_map_address(ptr);
+        <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, small.clone());
+        let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, page.clone()).unwrap();
+        let ptr = <MapAlloc as Alloc>::realloc(&mut alloc, ptr, page.clone(), pages.clone())
+            .unwrap();
+        <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, pages.clone());
+        assert_eq!(
+            alloc.rounding_stats(),
+            RoundingStats {
+                allocs: 2,
+                wasted_bytes: (pagesize() - 100) + (pagesize() - 1),
+            }
+        );
+
+        // Check that:
+        // - A strict allocator rejects sizes that are not a multiple of the page size, including
+        //   when reallocating
+        // - It still accepts multiples of the page size
+        let mut alloc = MapAllocBuilder::default().strict(true).build();
+        assert!(
+            <MapAlloc as Alloc>::alloc(&mut alloc, small.clone())
+                .unwrap_err()
+                .is_request_unsupported()
+        );
+        let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, page.clone()).unwrap();
+        assert!(
+            <MapAlloc as Alloc>::realloc(&mut alloc, ptr, page.clone(), pages.clone())
+                .unwrap_err()
+                .is_request_unsupported()
+        );
+        <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, page.clone());
+        assert_eq!(alloc.rounding_stats(), RoundingStats::default());
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_reserve_only() {
+    unsafe {
+        let size = 16 * pagesize();
+        let layout = Layout::from_size_align(size, pagesize()).unwrap();
+
+        // Check that:
+        // - A reserve-only allocation is not readable, and no page of it is resident
+        // - Committing part of it makes that part usable
+        // - Uncommitting it releases the pages and makes them inaccessible again
+        let mut alloc = MapAllocBuilder::default().reserve_only(true).build();
+        let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+        test_valid_map_address(ptr);
+        assert_block_perm(ptr, size, PROT_NONE);
+        assert_eq!(alloc.resident_pages(ptr, size), 0);
+        alloc.commit_reserved(ptr, 4 * pagesize()).unwrap();
+        assert_block_perm(ptr, 4 * pagesize(), PROT_READ_WRITE);
+        test_write_read(ptr, 4 * pagesize());
+        assert_eq!(alloc.resident_pages(ptr, size), 4);
+        alloc.uncommit(ptr, layout.clone());
+        assert_eq!(alloc.resident_pages(ptr, size), 0);
+        assert_block_perm(ptr, size, PROT_NONE);
+        <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout.clone());
+
+        // Check that:
+        // - A noreserve allocation is usable right away
+        // - Only the touched pages become resident
+        let mut alloc = MapAllocBuilder::default().noreserve(true).build();
+        let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+        assert_eq!(alloc.resident_pages(ptr, size), 0);
+        test_write_read(ptr, pagesize());
+        assert_eq!(alloc.resident_pages(ptr, size), 1);
+        <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout);
+
+        assert!(overcommit_mode().is_some());
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[test]
+fn test_locked() {
+    unsafe {
+        let layout = Layout::from_size_align(2 * pagesize(), pagesize()).unwrap();
+        let bigger = Layout::from_size_align(3 * pagesize(), pagesize()).unwrap();
+
+        // Check that:
+        // - Locked memory is resident as soon as it is allocated (unless the limit was hit)
+        // - Reallocating a locked object preserves its contents
+        let mut alloc = MapAllocBuilder::default().locked(true).build();
+        let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+        test_valid_map_address(ptr);
+        if alloc.lock_failures() == 0 {
+            assert_eq!(alloc.resident_pages(ptr, layout.size()), 2);
+        }
+        test_write_read(ptr, layout.size());
+        let ptr = <MapAlloc as Alloc>::realloc(&mut alloc, ptr, layout.clone(), bigger.clone())
+            .unwrap();
+        test_read(ptr, layout.size());
+        <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, bigger);
+
+        // Check that:
+        // - When no memory may be locked, allocation still succeeds and the failure is counted
+        //   (root may lock memory regardless of the limit)
+        let mut old = ::libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        assert_eq!(::libc::getrlimit(::libc::RLIMIT_MEMLOCK, &mut old), 0);
+        let zero = ::libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: old.rlim_max,
+        };
+        assert_eq!(::libc::setrlimit(::libc::RLIMIT_MEMLOCK, &zero), 0);
+        let alloc = MapAllocBuilder::default().locked(true).build();
+        let ptr = <&MapAlloc as Alloc>::alloc(&mut &alloc, layout.clone()).unwrap();
+        assert_eq!(::libc::setrlimit(::libc::RLIMIT_MEMLOCK, &old), 0);
+        if ::libc::geteuid() != 0 {
+            assert_eq!(alloc.lock_failures(), 1);
+        }
+        test_write_read(ptr, layout.size());
+        <&MapAlloc as Alloc>::dealloc(&mut &alloc, ptr, layout);
+    }
+}
+
 #[test]
 fn test_commit() {
     unsafe {
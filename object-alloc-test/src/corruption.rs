@@ -789,6 +789,328 @@ impl<C: CorruptionTesterWrapper, O: ObjectAlloc<C>> Tester<C, O> {
     }
 }
 
+/// A builder for multi-threaded corruption tests.
+///
+/// Where `TestBuilder` drives a single allocator from a single thread, a `ConcurrentTestBuilder`
+/// hands a clone of an allocator handle to each of several threads, and objects regularly migrate
+/// between them. This exercises the paths that caches use to recycle objects freed by a thread
+/// other than the one that allocated them. Three patterns are available:
+///
+/// - `steal_heavy`: all threads allocate into and free from a single shared pool of objects, so
+///   most objects are freed by a thread other than the one that allocated them.
+/// - `remote_free`: the threads form a ring, and every object is freed by the next thread in the
+///   ring.
+/// - `thread_churn`: short-lived threads each create a handle, allocate, free some objects and
+///   leave the rest to be freed by later threads, and exit.
+///
+/// Throughout, the same invariants as in the single-threaded test are checked: an object is
+/// never handed out twice, a newly allocated object is either freshly constructed or, if it was
+/// freed before, still valid, and live objects are never corrupted. Once the test has finished
+/// and every handle has been dropped, all freed objects must have been dropped.
+///
+/// The allocator handle must implement `Clone` and `Send`, and clones must share their objects.
+/// Allocators that are not themselves shareable can be wrapped in a `Locked`.
+pub struct ConcurrentTestBuilder<T, O: ObjectAlloc<T> + Clone + Send + 'static> {
+    alloc: O,
+    threads: usize,
+    test_iters: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T, O: ObjectAlloc<T> + Clone + Send + 'static> ConcurrentTestBuilder<T, O> {
+    /// Construct a new `ConcurrentTestBuilder` that tests clones of `alloc`.
+    pub fn new(alloc: O) -> ConcurrentTestBuilder<T, O> {
+        ConcurrentTestBuilder {
+            alloc,
+            threads: 8,
+            test_iters: 100_000,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Configure the number of threads. The default is 8.
+    pub fn threads(mut self, threads: usize) -> ConcurrentTestBuilder<T, O> {
+        assert!(threads > 0);
+        self.threads = threads;
+        self
+    }
+
+    /// Configure the total number of allocations and deallocations, which are split evenly among
+    /// the threads. The default is 100,000.
+    pub fn test_iters(mut self, iters: usize) -> ConcurrentTestBuilder<T, O> {
+        self.test_iters = iters;
+        self
+    }
+}
+
+macro_rules! impl_concurrent_tests {
+    ($tester:ident) => {
+        impl<T: Copy, O> ConcurrentTestBuilder<$tester<T>, O>
+            where T: Send + 'static,
+                  O: ObjectAlloc<$tester<T>> + Clone + Send + 'static
+        {
+            /// Run the `steal_heavy` pattern.
+            pub fn steal_heavy(self) {
+                concurrent::steal_heavy(self.alloc, self.threads, self.test_iters)
+            }
+
+            /// Run the `remote_free` pattern.
+            pub fn remote_free(self) {
+                concurrent::remote_free(self.alloc, self.threads, self.test_iters)
+            }
+
+            /// Run the `thread_churn` pattern.
+            pub fn thread_churn(self) {
+                concurrent::thread_churn(self.alloc, self.threads, self.test_iters)
+            }
+
+            /// Run all patterns, each with a clone of the allocator.
+            pub fn test(self) {
+                concurrent::steal_heavy(self.alloc.clone(), self.threads, self.test_iters);
+                concurrent::remote_free(self.alloc.clone(), self.threads, self.test_iters);
+                concurrent::thread_churn(self.alloc, self.threads, self.test_iters);
+            }
+        }
+    };
+}
+
+impl_concurrent_tests!(CorruptionTesterDefault);
+impl_concurrent_tests!(CorruptionTesterUnsafe);
+
+use std::sync::{Arc, Mutex};
+
+/// An `ObjectAlloc` that can be shared between threads by serializing access with a lock.
+///
+/// This makes single-threaded allocators usable with `ConcurrentTestBuilder`. The wrapped
+/// allocator must not depend on being used from any particular thread.
+pub struct Locked<O>(Arc<Mutex<O>>);
+
+impl<O> Locked<O> {
+    /// Wrap `alloc`, which can then be shared by cloning the returned `Locked`.
+    pub fn new(alloc: O) -> Locked<O> {
+        Locked(Arc::new(Mutex::new(alloc)))
+    }
+}
+
+impl<O> Clone for Locked<O> {
+    fn clone(&self) -> Locked<O> {
+        Locked(self.0.clone())
+    }
+}
+
+// Allocators commonly contain raw pointers, which makes them !Send even though all access to them
+// is serialized here.
+unsafe impl<O> Send for Locked<O> {}
+
+unsafe impl<T, O: ObjectAlloc<T>> ObjectAlloc<T> for Locked<O> {
+    unsafe fn alloc(&mut self) -> Result<*mut T, self::object_alloc::Exhausted> {
+        self.0.lock().unwrap().alloc()
+    }
+
+    unsafe fn dealloc(&mut self, x: *mut T) {
+        self.0.lock().unwrap().dealloc(x)
+    }
+}
+
+mod concurrent {
+    use super::*;
+    use super::rand;
+    use super::rand::Rng;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    /// The state shared by all threads of a test. Objects are stored as `usize`s since raw
+    /// pointers are not `Send`.
+    #[derive(Default)]
+    struct Shared {
+        /// Objects that are currently allocated.
+        live: Mutex<HashSet<usize>>,
+        /// Objects that have been freed and not allocated again since.
+        freed: Mutex<HashSet<usize>>,
+        /// Allocated objects that any thread may free.
+        pool: Mutex<Vec<usize>>,
+    }
+
+    impl Shared {
+        fn alloc<C: CorruptionTesterWrapper, O: ObjectAlloc<C>>(&self, alloc: &mut O) -> usize {
+            let obj = unsafe { alloc.alloc().unwrap() };
+            let addr = obj as usize;
+            assert!(self.live.lock().unwrap().insert(addr),
+                    "object at {:?} allocated while still live",
+                    obj);
+            let recycled = self.freed.lock().unwrap().remove(&addr);
+            // Like in the single-threaded test, only an object that we freed before may still be
+            // constructed, since it may not have been dropped yet.
+            match unsafe { (*obj).state() } {
+                State::New => unsafe { (*obj).update_new(false) },
+                State::Valid if recycled => {}
+                state => {
+                    panic!("newly-allocated object at {:?} (recycled: {}) in unexpected state {:?}",
+                           obj,
+                           recycled,
+                           state);
+                }
+            }
+            addr
+        }
+
+        fn dealloc<C: CorruptionTesterWrapper, O: ObjectAlloc<C>>(&self,
+                                                                  alloc: &mut O,
+                                                                  addr: usize) {
+            let obj = addr as *mut C;
+            assert_eq!(unsafe { (*obj).state() }, State::Valid);
+            assert!(self.live.lock().unwrap().remove(&addr));
+            self.freed.lock().unwrap().insert(addr);
+            unsafe { alloc.dealloc(obj) };
+        }
+
+        /// Pop a random object from the pool.
+        fn steal(&self) -> Option<usize> {
+            let mut pool = self.pool.lock().unwrap();
+            if pool.is_empty() {
+                None
+            } else {
+                let idx = rand::thread_rng().gen_range(0, pool.len());
+                Some(pool.swap_remove(idx))
+            }
+        }
+
+        /// Free everything left in the pool, drop the last handle, and verify that every freed
+        /// object has been dropped.
+        fn finish<C: CorruptionTesterWrapper, O: ObjectAlloc<C>>(&self, mut alloc: O) {
+            while let Some(addr) = self.steal() {
+                self.dealloc(&mut alloc, addr);
+            }
+            assert!(self.live.lock().unwrap().is_empty(), "objects leaked by the test");
+            drop(alloc);
+            for &addr in self.freed.lock().unwrap().iter() {
+                use std::mem;
+                if !mapped::is_mapped_range(addr as *mut u8, mem::size_of::<C>()) {
+                    continue;
+                }
+                match unsafe { (*(addr as *mut C)).state() } {
+                    State::Invalid | State::Dropped => {}
+                    state => {
+                        panic!("freed object at {:#x} in unexpected state: {:?}", addr, state);
+                    }
+                }
+            }
+        }
+    }
+
+    fn join_all(threads: Vec<thread::JoinHandle<()>>) {
+        for t in threads {
+            t.join().expect("test thread panicked");
+        }
+    }
+
+    pub fn steal_heavy<C, O>(alloc: O, threads: usize, iters: usize)
+        where C: CorruptionTesterWrapper + 'static,
+              O: ObjectAlloc<C> + Clone + Send + 'static
+    {
+        let shared = Arc::new(Shared::default());
+        let handles = (0..threads)
+            .map(|_| {
+                let mut alloc = alloc.clone();
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    for _ in 0..(iters / threads) {
+                        let stolen = if rand::random() { shared.steal() } else { None };
+                        match stolen {
+                            Some(addr) => shared.dealloc(&mut alloc, addr),
+                            None => {
+                                let addr = shared.alloc(&mut alloc);
+                                shared.pool.lock().unwrap().push(addr);
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        join_all(handles);
+        shared.finish(alloc);
+    }
+
+    pub fn remote_free<C, O>(alloc: O, threads: usize, iters: usize)
+        where C: CorruptionTesterWrapper + 'static,
+              O: ObjectAlloc<C> + Clone + Send + 'static
+    {
+        const BATCH: usize = 32;
+        let shared = Arc::new(Shared::default());
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..threads)
+            .map(|_| channel::<Vec<usize>>())
+            .unzip();
+        let handles = receivers
+            .into_iter()
+            .enumerate()
+            .map(|(i, recv)| {
+                let mut alloc = alloc.clone();
+                let shared = shared.clone();
+                // Thread i sends its objects to thread i + 1.
+                let send = senders[(i + 1) % threads].clone();
+                thread::spawn(move || {
+                    let free_batch = |alloc: &mut O, batch: Vec<usize>| for addr in batch {
+                        shared.dealloc(alloc, addr);
+                    };
+                    for _ in 0..(iters / threads / BATCH) {
+                        let batch: Vec<usize> = (0..BATCH).map(|_| shared.alloc(&mut alloc)).collect();
+                        send.send(batch).unwrap();
+                        while let Ok(batch) = recv.try_recv() {
+                            free_batch(&mut alloc, batch);
+                        }
+                    }
+                    // Our successor stops receiving once all of its senders are gone.
+                    drop(send);
+                    for batch in recv.iter() {
+                        free_batch(&mut alloc, batch);
+                    }
+                })
+            })
+            .collect();
+        drop(senders);
+        join_all(handles);
+        shared.finish(alloc);
+    }
+
+    pub fn thread_churn<C, O>(alloc: O, threads: usize, iters: usize)
+        where C: CorruptionTesterWrapper + 'static,
+              O: ObjectAlloc<C> + Clone + Send + 'static
+    {
+        const ROUNDS: usize = 16;
+        let shared = Arc::new(Shared::default());
+        let per_thread = iters / ROUNDS / threads;
+        for _ in 0..ROUNDS {
+            let handles = (0..threads)
+                .map(|_| {
+                    let mut alloc = alloc.clone();
+                    let shared = shared.clone();
+                    thread::spawn(move || {
+                        // Free objects left behind by threads that have already exited.
+                        for _ in 0..(per_thread / 2) {
+                            match shared.steal() {
+                                Some(addr) => shared.dealloc(&mut alloc, addr),
+                                None => break,
+                            }
+                        }
+                        let mine: Vec<usize> =
+                            (0..(per_thread / 2)).map(|_| shared.alloc(&mut alloc)).collect();
+                        for (i, addr) in mine.into_iter().enumerate() {
+                            if i % 2 == 0 {
+                                shared.dealloc(&mut alloc, addr);
+                            } else {
+                                shared.pool.lock().unwrap().push(addr);
+                            }
+                        }
+                        // The handle is dropped with the thread.
+                    })
+                })
+                .collect();
+            join_all(handles);
+        }
+        shared.finish(alloc);
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     extern crate core;
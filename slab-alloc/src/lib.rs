@@ -35,7 +35,8 @@
 //   (rather than always prioritizing space usage)
 // - Would it be worth it to special-case 64-byte allocations to ensure that they are 64-byte
 //   aligned so that each object gets its own cache line? It should be sufficient to artificially
-//   override the align parameter to be 64 bytes when the size is 64 bytes.
+//   override the align parameter to be 64 bytes when the size is 64 bytes. (Users can already opt
+//   into this with SlabAllocBuilder::cache_aligned.)
 // - Add a feature flag to disable using aligned slabs for backing sizes larger than a page (to
 //   enable benchmarking aligned vs large slabs)
 // - Consider improvements to the slab size selection algorithm. For example, might we be willing
@@ -60,6 +61,8 @@ mod aligned;
 mod backing;
 mod init;
 mod large;
+#[cfg(all(feature = "std", target_pointer_width = "64"))]
+pub mod node_pool;
 mod ptr_map;
 mod stack;
 #[cfg(test)]
@@ -106,6 +109,9 @@ lazy_static!{
 const WORKING_PERIOD_SECONDS: u64 = 15;
 const OBJECTS_PER_SLAB: usize = 8;
 
+/// The cache line size assumed by `cache_aligned`.
+pub const CACHE_LINE_SIZE: usize = 64;
+
 /// A typed slab allocator.
 pub struct SlabAlloc<T, I: InitSystem, B: BackingAlloc> {
     alloc: PrivateSlabAlloc<I, B>,
@@ -127,6 +133,33 @@ enum PrivateUntypedSlabAlloc<I: InitSystem, B: BackingAlloc> {
     Large(SizedSlabAlloc<I, large::System<B::Large>>),
 }
 
+/// A typed slab allocator in which every object occupies its own cache line(s).
+///
+/// Objects allocated from a `CacheAligned` pool never share a cache line with other objects, so
+/// concurrent data structures can use them without padding their types by hand to avoid false
+/// sharing. A `CacheAligned` pool is obtained with `CacheAligned::new` or
+/// `SlabAllocBuilder::build_cache_aligned`.
+pub struct CacheAligned<T, I: InitSystem, B: BackingAlloc>(SlabAlloc<T, I, B>);
+
+#[cfg(feature = "std")]
+impl<T: Default> CacheAligned<T, DefaultInitSystem<T>, HeapBackingAlloc> {
+    /// Constructs a heap-backed pool that places each object on a single cache line (or more, if
+    /// `T` is larger than a cache line) and initializes objects with `T::default`.
+    pub fn new() -> CacheAligned<T, DefaultInitSystem<T>, HeapBackingAlloc> {
+        SlabAllocBuilder::default().build_cache_aligned(1)
+    }
+}
+
+unsafe impl<T, I: InitSystem, B: BackingAlloc> ObjectAlloc<T> for CacheAligned<T, I, B> {
+    unsafe fn alloc(&mut self) -> Result<*mut T, Exhausted> {
+        ObjectAlloc::alloc(&mut self.0)
+    }
+
+    unsafe fn dealloc(&mut self, x: *mut T) {
+        ObjectAlloc::dealloc(&mut self.0, x)
+    }
+}
+
 /// A builder for `SlabAlloc`s.
 pub struct SlabAllocBuilder<T, I: InitSystem> {
     init: I,
@@ -153,6 +186,26 @@ impl<T, I: InitSystem> SlabAllocBuilder<T, I> {
         self
     }
 
+    /// Places each object on its own `lines` cache lines.
+    ///
+    /// Objects are padded to a multiple of `CACHE_LINE_SIZE` bytes (and to at least `lines` cache
+    /// lines) and aligned to a cache line boundary, so that no two objects share a cache line.
+    /// This prevents false sharing between objects that are used by different threads. Using two
+    /// lines also defeats adjacent-line prefetching on some processors.
+    pub fn cache_aligned(mut self, lines: usize) -> SlabAllocBuilder<T, I> {
+        assert!(lines > 0);
+        self.layout = util::misc::cache_aligned(self.layout, lines);
+        self
+    }
+
+    /// Builds a `CacheAligned` pool whose memory is backed by the heap.
+    ///
+    /// This is equivalent to calling `cache_aligned(lines)` and then `build`.
+    #[cfg(feature = "std")]
+    pub fn build_cache_aligned(self, lines: usize) -> CacheAligned<T, I, HeapBackingAlloc> {
+        CacheAligned(self.cache_aligned(lines).build())
+    }
+
     /// Builds a `SlabAlloc` whose memory is backed by the heap.
     #[cfg(feature = "std")]
     pub fn build(self) -> SlabAlloc<T, I, HeapBackingAlloc> {
@@ -347,6 +400,13 @@ impl<I: InitSystem> UntypedSlabAllocBuilder<I> {
         self
     }
 
+    /// Places each object on its own `lines` cache lines. See `SlabAllocBuilder::cache_aligned`.
+    pub fn cache_aligned(mut self, lines: usize) -> UntypedSlabAllocBuilder<I> {
+        assert!(lines > 0);
+        self.layout = util::misc::cache_aligned(self.layout, lines);
+        self
+    }
+
     /// Builds an `UntypedSlabAlloc` whose memory is backed by the heap.
     #[cfg(feature = "std")]
     pub fn build(self) -> UntypedSlabAlloc<I, HeapBackingAlloc> {
@@ -0,0 +1,366 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE-APACHE file) or
+// the MIT license (the LICENSE-MIT file) at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A pool of nodes for intrusive lock-free lists and queues.
+//!
+//! Lock-free structures built from linked nodes (Treiber stacks, Michael-Scott queues, and so on)
+//! are prone to the ABA problem: a thread reads a pointer to node `A`, other threads remove `A`,
+//! free it, and reuse its memory for a new node that ends up where `A` was, and the first
+//! thread's compare-and-swap succeeds even though the structure has changed underneath it.
+//!
+//! A `NodePool` solves this the usual way, with tagged pointers and type-stable memory:
+//!
+//! - Every `Node` carries a generation counter, which `NodePool::free` increments. A `Tagged`
+//!   pointer to a node stores the low `TAG_BITS` bits of the node's generation in the high bits of
+//!   the pointer, so a pointer read before a node was recycled never compares equal to one read
+//!   after. A compare-and-swap on an `AtomicTagged` can only be fooled if the node was recycled a
+//!   multiple of `2^TAG_BITS` times between the load and the compare-and-swap.
+//! - Freed nodes are kept on a free list inside the pool, and only returned to the underlying
+//!   `ObjectAlloc` when the pool is dropped. A thread holding a stale pointer can therefore always
+//!   read the node it points to (which is what a failed compare-and-swap needs), as long as the
+//!   pool is alive.
+//!
+//! The pool gets new nodes from any `ObjectAlloc<Node<T>>`, with a lock taken only when its free
+//! list is empty. The free list is itself a Treiber stack of tagged pointers. Pointers keep their
+//! tag in the bits above a 48-bit address, so this module is only available on 64-bit platforms.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+use core::ops::Deref;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use object_alloc::{Exhausted, ObjectAlloc};
+use init::NopInitSystem;
+use backing::heap::HeapBackingAlloc;
+use {SlabAlloc, SlabAllocBuilder};
+
+/// The number of bits of a node's generation stored in a `Tagged` pointer.
+pub const TAG_BITS: usize = 16;
+const ADDR_BITS: usize = 64 - TAG_BITS;
+const ADDR_MASK: usize = (1 << ADDR_BITS) - 1;
+const TAG_MASK: usize = (1 << TAG_BITS) - 1;
+
+/// A node allocated from a `NodePool`, holding a `T`.
+///
+/// Besides the value, a node has a link, `next`, for the list or queue that currently holds it,
+/// and a generation counter. Nodes are only ever accessed through shared references, so the
+/// value must use interior mutability for anything that changes while the node is in a
+/// structure.
+pub struct Node<T> {
+    next: AtomicTagged<T>,
+    generation: AtomicUsize,
+    /// The next node obtained from the pool's allocator, so that the pool can return every node
+    /// when it is dropped. Written once, under the pool's lock.
+    chain: UnsafeCell<*mut Node<T>>,
+    /// Only initialized while the node is allocated.
+    value: ManuallyDrop<T>,
+}
+
+impl<T> Node<T> {
+    /// The link to the next node in the structure holding this node.
+    ///
+    /// The link is null when the node is allocated. The pool uses it for its free list while the
+    /// node is free, so a structure must not rely on its value after freeing the node.
+    pub fn next(&self) -> &AtomicTagged<T> {
+        &self.next
+    }
+
+    /// The number of times the node has been freed.
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Deref for Node<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+/// A pointer to a `Node`, tagged with the generation of the node at the time the pointer was
+/// created. See the module documentation.
+pub struct Tagged<T> {
+    bits: usize,
+    _marker: PhantomData<*mut Node<T>>,
+}
+
+impl<T> Tagged<T> {
+    fn from_bits(bits: usize) -> Tagged<T> {
+        Tagged {
+            bits: bits,
+            _marker: PhantomData,
+        }
+    }
+
+    /// A tagged pointer to `node`, with the node's current generation as its tag.
+    ///
+    /// # Safety
+    /// `node` must be a node allocated by a `NodePool` that has not been dropped.
+    pub unsafe fn current(node: *mut Node<T>) -> Tagged<T> {
+        Tagged::new(node, (*node).generation())
+    }
+
+    fn new(node: *mut Node<T>, generation: usize) -> Tagged<T> {
+        debug_assert_eq!(node as usize & !ADDR_MASK, 0);
+        Tagged::from_bits(node as usize | ((generation & TAG_MASK) << ADDR_BITS))
+    }
+
+    pub fn null() -> Tagged<T> {
+        Tagged::from_bits(0)
+    }
+
+    pub fn is_null(self) -> bool {
+        self.ptr().is_null()
+    }
+
+    pub fn ptr(self) -> *mut Node<T> {
+        (self.bits & ADDR_MASK) as *mut Node<T>
+    }
+
+    pub fn tag(self) -> usize {
+        self.bits >> ADDR_BITS
+    }
+
+    /// Whether the node has not been freed since this pointer was created. A stale pointer may
+    /// appear current if the node was recycled a multiple of `2^TAG_BITS` times.
+    ///
+    /// # Safety
+    /// The pointer must not be null, and the pool that allocated the node must not have been
+    /// dropped.
+    pub unsafe fn is_current(self) -> bool {
+        (*self.ptr()).generation() & TAG_MASK == self.tag()
+    }
+
+    /// Dereference the pointer, returning `None` if it is null. The node may have been freed and
+    /// reallocated since the pointer was created (see `is_current`), but the memory remains a
+    /// valid `Node<T>`.
+    ///
+    /// # Safety
+    /// The pool that allocated the node must outlive the returned reference, and the value may
+    /// only be read if the node is allocated.
+    pub unsafe fn as_ref<'a>(self) -> Option<&'a Node<T>> {
+        self.ptr().as_ref()
+    }
+}
+
+impl<T> Clone for Tagged<T> {
+    fn clone(&self) -> Tagged<T> {
+        *self
+    }
+}
+
+impl<T> Copy for Tagged<T> {}
+
+impl<T> PartialEq for Tagged<T> {
+    fn eq(&self, other: &Tagged<T>) -> bool {
+        self.bits == other.bits
+    }
+}
+
+impl<T> Eq for Tagged<T> {}
+
+impl<T> fmt::Debug for Tagged<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tagged({:?}, {})", self.ptr(), self.tag())
+    }
+}
+
+/// An atomic `Tagged` pointer, for the links and heads of lock-free structures.
+pub struct AtomicTagged<T> {
+    bits: AtomicUsize,
+    _marker: PhantomData<*mut Node<T>>,
+}
+
+unsafe impl<T: Send> Send for AtomicTagged<T> {}
+unsafe impl<T: Send> Sync for AtomicTagged<T> {}
+
+impl<T> AtomicTagged<T> {
+    pub fn new(ptr: Tagged<T>) -> AtomicTagged<T> {
+        AtomicTagged {
+            bits: AtomicUsize::new(ptr.bits),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn null() -> AtomicTagged<T> {
+        AtomicTagged::new(Tagged::null())
+    }
+
+    pub fn load(&self, order: Ordering) -> Tagged<T> {
+        Tagged::from_bits(self.bits.load(order))
+    }
+
+    pub fn store(&self, ptr: Tagged<T>, order: Ordering) {
+        self.bits.store(ptr.bits, order)
+    }
+
+    pub fn swap(&self, ptr: Tagged<T>, order: Ordering) -> Tagged<T> {
+        Tagged::from_bits(self.bits.swap(ptr.bits, order))
+    }
+
+    /// Store `new` if the current value is `current`, comparing both the address and the tag.
+    /// Returns the previous value, which is `current` on success.
+    pub fn compare_exchange(
+        &self,
+        current: Tagged<T>,
+        new: Tagged<T>,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<Tagged<T>, Tagged<T>> {
+        self.bits
+            .compare_exchange(current.bits, new.bits, success, failure)
+            .map(Tagged::from_bits)
+            .map_err(Tagged::from_bits)
+    }
+}
+
+impl<T> Default for AtomicTagged<T> {
+    fn default() -> AtomicTagged<T> {
+        AtomicTagged::null()
+    }
+}
+
+impl<T> fmt::Debug for AtomicTagged<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AtomicTagged({:?})", self.load(Ordering::SeqCst))
+    }
+}
+
+/// A thread-safe pool of `Node<T>`s. See the module documentation.
+pub struct NodePool<T, O: ObjectAlloc<Node<T>>> {
+    /// The head of the free list.
+    free: AtomicTagged<T>,
+    /// The allocator, and the most recently allocated node, which heads the chain of every node
+    /// obtained from it.
+    alloc: Mutex<(O, *mut Node<T>)>,
+    nodes: AtomicUsize,
+}
+
+unsafe impl<T: Send, O: ObjectAlloc<Node<T>> + Send> Send for NodePool<T, O> {}
+unsafe impl<T: Send, O: ObjectAlloc<Node<T>> + Send> Sync for NodePool<T, O> {}
+
+impl<T> Default for NodePool<T, SlabAlloc<Node<T>, NopInitSystem, HeapBackingAlloc>> {
+    /// A pool backed by a heap-backed `SlabAlloc`.
+    fn default() -> NodePool<T, SlabAlloc<Node<T>, NopInitSystem, HeapBackingAlloc>> {
+        // The pool initializes every field of a node it gets from the allocator, and `Node<T>`
+        // has no drop glue, so the allocator does not need to initialize nodes.
+        NodePool::new(unsafe { SlabAllocBuilder::no_initialize() }.build())
+    }
+}
+
+impl<T, O: ObjectAlloc<Node<T>>> NodePool<T, O> {
+    /// Create a pool that gets nodes from `alloc`.
+    ///
+    /// The pool overwrites every field of the nodes `alloc` returns, so it does not matter how
+    /// `alloc` initializes them.
+    pub fn new(alloc: O) -> NodePool<T, O> {
+        NodePool {
+            free: AtomicTagged::null(),
+            alloc: Mutex::new((alloc, ptr::null_mut())),
+            nodes: AtomicUsize::new(0),
+        }
+    }
+
+    /// The number of nodes the pool has obtained from its allocator, whether allocated or free.
+    pub fn nodes(&self) -> usize {
+        self.nodes.load(Ordering::Relaxed)
+    }
+
+    /// Allocate a node holding `value`, returning a pointer tagged with its current generation.
+    /// The node's `next` link is null.
+    pub fn alloc(&self, value: T) -> Result<Tagged<T>, Exhausted> {
+        let node = match self.pop() {
+            Some(node) => node,
+            None => self.alloc_fresh()?,
+        };
+        unsafe {
+            (*node).next.store(Tagged::null(), Ordering::Relaxed);
+            ptr::write(&mut (*node).value, ManuallyDrop::new(value));
+            Ok(Tagged::current(node))
+        }
+    }
+
+    /// Free `node`, returning its value. Its generation is incremented, so that pointers to it
+    /// created before now are no longer current.
+    ///
+    /// # Safety
+    /// `node` must have been allocated by this pool and not freed since. Other threads may still
+    /// hold pointers to it, but must not read its value.
+    pub unsafe fn free(&self, node: Tagged<T>) -> T {
+        let node = node.ptr();
+        let value = ManuallyDrop::into_inner(ptr::read(&(*node).value));
+        (*node).generation.fetch_add(1, Ordering::AcqRel);
+        self.push(node);
+        value
+    }
+
+    fn pop(&self) -> Option<*mut Node<T>> {
+        let mut head = self.free.load(Ordering::Acquire);
+        loop {
+            let node = match unsafe { head.as_ref() } {
+                Some(node) => node,
+                None => return None,
+            };
+            // If another thread pops `node` first, this reads a link that may be stale, but the
+            // tag on `head` makes the compare-and-swap fail.
+            let next = node.next.load(Ordering::Acquire);
+            match self.free.compare_exchange(head, next, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Some(head.ptr()),
+                Err(cur) => head = cur,
+            }
+        }
+    }
+
+    fn push(&self, node: *mut Node<T>) {
+        let tagged = unsafe { Tagged::current(node) };
+        let mut head = self.free.load(Ordering::Acquire);
+        loop {
+            unsafe { (*node).next.store(head, Ordering::Relaxed) };
+            match self.free.compare_exchange(head, tagged, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return,
+                Err(cur) => head = cur,
+            }
+        }
+    }
+
+    #[cold]
+    fn alloc_fresh(&self) -> Result<*mut Node<T>, Exhausted> {
+        let mut guard = self.alloc.lock().unwrap();
+        let (ref mut alloc, ref mut chain) = *guard;
+        let node = unsafe { alloc.alloc()? };
+        unsafe {
+            ptr::write(&mut (*node).next, AtomicTagged::null());
+            ptr::write(&mut (*node).generation, AtomicUsize::new(0));
+            ptr::write(&mut (*node).chain, UnsafeCell::new(*chain));
+        }
+        *chain = node;
+        self.nodes.fetch_add(1, Ordering::Relaxed);
+        Ok(node)
+    }
+}
+
+impl<T, O: ObjectAlloc<Node<T>>> Drop for NodePool<T, O> {
+    /// Return every node to the allocator. The values of nodes that are still allocated are not
+    /// dropped.
+    fn drop(&mut self) {
+        let (ref mut alloc, chain) = *self.alloc.get_mut().unwrap();
+        let mut node = chain;
+        while !node.is_null() {
+            unsafe {
+                let next = *(*node).chain.get();
+                alloc.dealloc(node);
+                node = next;
+            }
+        }
+    }
+}
@@ -137,6 +137,143 @@ call_for_all_types_prefix!(make_test_memory_corruption, test_memory_corruption);
 call_for_all_types_prefix!(make_test_quickcheck_memory_corruption,
                            quickcheck_memory_corruption);
 
+fn test_concurrent_memory_corruption<T: Copy + Send + 'static>() {
+    use self::object_alloc_test::corruption::{ConcurrentTestBuilder, CorruptionTesterDefault,
+                                              Locked};
+    use std::env;
+    let default = 100_000;
+    let iters = match env::var("SLAB_TEST_ITERS") {
+        Ok(val) => val.parse().unwrap_or(default),
+        Err(_) => default,
+    };
+    // A SlabAlloc is not shareable on its own, so the threads take turns using a single one.
+    // This still exercises objects being freed by a different thread than the one that
+    // allocated them.
+    let mut alloc = SlabAllocBuilder::default()
+        .build_backing(leaky_get_aligned, leaky_get_large) as
+                    SlabAlloc<_, _, LeakyBackingAlloc>;
+    infer_allocator_type::<CorruptionTesterDefault<T>>(&mut alloc);
+    ConcurrentTestBuilder::new(Locked::new(alloc))
+        .test_iters(iters)
+        .test();
+}
+
+#[test]
+fn test_concurrent_memory_corruption_small() {
+    use self::object_alloc_test::types::Byte16;
+    test_concurrent_memory_corruption::<Byte16>();
+}
+
+#[test]
+fn test_concurrent_memory_corruption_large() {
+    use self::object_alloc_test::types::Byte1024;
+    test_concurrent_memory_corruption::<Byte1024>();
+}
+
+#[test]
+fn test_cache_aligned() {
+    use CacheAligned;
+    use CACHE_LINE_SIZE;
+    let mut alloc = CacheAligned::<u64, _, _>::new();
+    let mut ptrs: Vec<usize> = (0..1024).map(|_| unsafe { alloc.alloc().unwrap() as usize }).collect();
+    ptrs.sort();
+    for w in ptrs.windows(2) {
+        assert_eq!(w[0] % CACHE_LINE_SIZE, 0);
+        assert!(w[1] - w[0] >= CACHE_LINE_SIZE);
+    }
+    for p in ptrs {
+        unsafe { alloc.dealloc(p as *mut u64) };
+    }
+
+    let mut alloc = SlabAllocBuilder::default().build_cache_aligned(2);
+    infer_allocator_type::<[u8; 72]>(&mut alloc);
+    let (a, b) = unsafe { (alloc.alloc().unwrap(), alloc.alloc().unwrap()) };
+    assert_eq!(a as usize % CACHE_LINE_SIZE, 0);
+    assert!((a as isize - b as isize).abs() as usize >= 2 * CACHE_LINE_SIZE);
+    unsafe {
+        alloc.dealloc(a);
+        alloc.dealloc(b);
+    }
+}
+
+#[test]
+#[cfg(target_pointer_width = "64")]
+fn test_node_pool_aba() {
+    use node_pool::{AtomicTagged, NodePool, Tagged};
+    use std::sync::atomic::Ordering::SeqCst;
+    let pool = NodePool::default();
+    let a = pool.alloc(1usize).unwrap();
+    let head = AtomicTagged::new(a);
+    // Recycle the node, as another thread might between a load and a compare-and-swap.
+    let stale = head.load(SeqCst);
+    assert_eq!(unsafe { pool.free(a) }, 1);
+    let b = pool.alloc(2).unwrap();
+    head.store(b, SeqCst);
+    assert_eq!(b.ptr(), a.ptr());
+    assert_ne!(b, stale);
+    assert!(unsafe { b.is_current() && !stale.is_current() });
+    assert!(head.compare_exchange(stale, Tagged::null(), SeqCst, SeqCst).is_err());
+    assert_eq!(unsafe { b.as_ref() }.map(|node| **node), Some(2));
+    assert_eq!(pool.nodes(), 1);
+    assert_eq!(unsafe { pool.free(b) }, 2);
+}
+
+#[test]
+#[cfg(target_pointer_width = "64")]
+fn test_node_pool_concurrent() {
+    use node_pool::{AtomicTagged, NodePool};
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed};
+    use std::thread;
+    const THREADS: usize = 8;
+    const ITERS: usize = 100_000;
+    // A Treiber stack whose nodes are recycled as soon as they are popped, which makes ABA all
+    // but certain without tags.
+    let pool = Arc::new(NodePool::default());
+    let stack = Arc::new(AtomicTagged::null());
+    let threads: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let (pool, stack) = (pool.clone(), stack.clone());
+            thread::spawn(move || {
+                let mut sum = 0;
+                for i in 0..ITERS {
+                    let node = pool.alloc(t * ITERS + i).unwrap();
+                    let mut head = stack.load(Acquire);
+                    loop {
+                        unsafe { node.as_ref().unwrap() }.next().store(head, Relaxed);
+                        match stack.compare_exchange(head, node, AcqRel, Acquire) {
+                            Ok(_) => break,
+                            Err(cur) => head = cur,
+                        }
+                    }
+                    let mut head = stack.load(Acquire);
+                    let popped = loop {
+                        let next = match unsafe { head.as_ref() } {
+                            Some(node) => node.next().load(Acquire),
+                            None => {
+                                head = stack.load(Acquire);
+                                continue;
+                            }
+                        };
+                        match stack.compare_exchange(head, next, AcqRel, Acquire) {
+                            Ok(_) => break head,
+                            Err(cur) => head = cur,
+                        }
+                    };
+                    sum += unsafe { pool.free(popped) };
+                }
+                sum
+            })
+        })
+        .collect();
+    let sum: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+    let n = THREADS * ITERS;
+    assert_eq!(sum, n * (n - 1) / 2);
+    assert!(stack.load(Relaxed).is_null());
+    // Each thread has at most one node out of the free list at a time.
+    assert!(pool.nodes() <= THREADS);
+}
+
 #[cfg_attr(not(feature = "build-ignored-tests"), allow(unused))]
 fn bench_alloc_no_free<T: Default>(b: &mut Bencher) {
     let mut alloc = SlabAllocBuilder::default().build();
@@ -481,6 +481,16 @@ pub mod misc {
     extern crate alloc;
     use self::alloc::allocator::Layout;
 
+    /// Pad and align `layout` so that objects occupy `lines` whole cache lines (or more, if they
+    /// are larger than that) and start on a cache line boundary.
+    pub fn cache_aligned(layout: Layout, lines: usize) -> Layout {
+        use ::CACHE_LINE_SIZE;
+        let size = ::core::cmp::max(layout.size(), lines * CACHE_LINE_SIZE);
+        let size = (size + CACHE_LINE_SIZE - 1) & !(CACHE_LINE_SIZE - 1);
+        let align = ::core::cmp::max(layout.align(), CACHE_LINE_SIZE);
+        Layout::from_size_align(size, align).unwrap()
+    }
+
     pub fn satisfy_min_align(layout: Layout, min_align: usize) -> Layout {
         if min_align <= layout.align() {
             layout
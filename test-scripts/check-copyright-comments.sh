@@ -65,6 +65,7 @@ check_comments '.rs' '//' || EXIT=1
 check_comments '.md' '<!--' || EXIT=1
 check_comments '.toml' '#' || EXIT=1
 check_comments '.yml' '#' || EXIT=1
+check_comments '.py' '#' || EXIT=1
 # In shell scripts, the copyright comment should start on line 3
 check_comments_line '.sh' '#' 3 || EXIT=1
 exit "$EXIT"